@@ -0,0 +1,28 @@
+//! Feed arbitrary bytes to `Framed`+`BincodeCodec`, read by arbitrary
+//! chunks: frames received must be the ones decoded from the whole input.
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use rpccaps::rpc::codec::{BincodeCodec, Framed};
+use rpccaps_fuzz::{ChunkedReader, collect, decode_all};
+
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    data: Vec<u8>,
+    splits: Vec<u8>,
+    capacity: u8,
+}
+
+fuzz_target!(|input: Input| {
+    let reader = ChunkedReader::new(&input.data, &input.splits);
+    let capacity = (input.capacity as usize).max(1);
+    let mut framed = Framed::with_capacity(reader, BincodeCodec::<Vec<u8>>::new(), capacity);
+
+    let max_polls = (input.data.len() + 1) * (input.splits.len() + 2);
+    let items = collect(&mut framed, max_polls);
+    let expected = decode_all(BincodeCodec::<Vec<u8>>::new(), &input.data);
+    assert!(items.len() <= expected.len());
+    assert_eq!(items[..], expected[..items.len()]);
+});
@@ -0,0 +1,41 @@
+//! Send arbitrary values through `Framed`+`BincodeCodec` over a writer
+//! accepting partial writes, then read them back by arbitrary chunks.
+#![no_main]
+use arbitrary::Arbitrary;
+use futures::prelude::*;
+use libfuzzer_sys::fuzz_target;
+use serde::{Deserialize, Serialize};
+
+use rpccaps::rpc::codec::{BincodeCodec, Framed};
+use rpccaps_fuzz::{ChunkedReader, ChunkedWriter, collect, run};
+
+
+#[derive(Arbitrary, Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum Message {
+    Empty,
+    Call(u64, String),
+    Data { id: u32, payload: Vec<u8>, code: Option<i32> },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    values: Vec<Message>,
+    write_splits: Vec<u8>,
+    read_splits: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut sink = Framed::new(ChunkedWriter::new(&input.write_splits),
+                               BincodeCodec::<Message>::new());
+    for value in input.values.iter() {
+        let sent = run(sink.send(value.clone()), 1 << 16)
+            .expect("sending value did not complete");
+        assert!(sent.is_ok());
+    }
+
+    let data = sink.into_inner().data;
+    let reader = ChunkedReader::new(&data, &input.read_splits);
+    let mut stream = Framed::new(reader, BincodeCodec::<Message>::new());
+    let max_polls = (data.len() + 1) * (input.read_splits.len() + 2);
+    assert_eq!(collect(&mut stream, max_polls), input.values);
+});
@@ -0,0 +1,139 @@
+//! Utilities shared by fuzz targets: reader and writer splitting I/O
+//! according to a fuzzed pattern, and manual polling of `Framed`.
+use std::pin::Pin;
+
+use bytes::BytesMut;
+use futures::io::{self, AsyncRead, AsyncWrite};
+use futures::prelude::*;
+use futures::task::{noop_waker_ref, Context, Poll};
+
+use rpccaps::rpc::codec::Decoder;
+
+
+/// Return next chunk size from split pattern, `None` meaning that I/O is
+/// pending.
+fn next_split(splits: &[u8], index: &mut usize) -> Option<usize> {
+    if splits.is_empty() {
+        return Some(usize::MAX);
+    }
+    let split = splits[*index % splits.len()];
+    *index += 1;
+    match split {
+        0 => None,
+        split => Some(split as usize),
+    }
+}
+
+
+/// Reader returning data by chunks whose sizes are given by `splits`.
+pub struct ChunkedReader<'a> {
+    data: &'a [u8],
+    splits: &'a [u8],
+    index: usize,
+}
+
+impl<'a> ChunkedReader<'a> {
+    pub fn new(data: &'a [u8], splits: &'a [u8]) -> Self {
+        Self { data, splits, index: 0 }
+    }
+}
+
+impl<'a> AsyncRead for ChunkedReader<'a> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = &mut *self;
+        match next_split(this.splits, &mut this.index) {
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            Some(split) => {
+                let size = split.min(buf.len()).min(this.data.len());
+                buf[..size].copy_from_slice(&this.data[..size]);
+                this.data = &this.data[size..];
+                Poll::Ready(Ok(size))
+            }
+        }
+    }
+}
+
+
+/// Writer accepting data by chunks whose sizes are given by `splits`.
+pub struct ChunkedWriter<'a> {
+    pub data: Vec<u8>,
+    splits: &'a [u8],
+    index: usize,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    pub fn new(splits: &'a [u8]) -> Self {
+        Self { data: Vec::new(), splits, index: 0 }
+    }
+}
+
+impl<'a> AsyncWrite for ChunkedWriter<'a> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = &mut *self;
+        match next_split(this.splits, &mut this.index) {
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            Some(split) => {
+                let size = split.min(buf.len());
+                this.data.extend_from_slice(&buf[..size]);
+                Poll::Ready(Ok(size))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+/// Poll stream up to `max_polls` times, returning the received items.
+///
+/// `Framed` can return `Poll::Pending` without registering a wake-up when a
+/// frame is incomplete, so stream is polled manually instead of using an
+/// executor.
+pub fn collect<S: Stream+Unpin>(stream: &mut S, max_polls: usize) -> Vec<S::Item> {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut items = Vec::new();
+    for _ in 0..max_polls {
+        match stream.poll_next_unpin(&mut cx) {
+            Poll::Ready(Some(item)) => items.push(item),
+            Poll::Ready(None) => break,
+            Poll::Pending => (),
+        }
+    }
+    items
+}
+
+/// Poll future up to `max_polls` times, returning its output if ready.
+pub fn run<F: Future+Unpin>(mut future: F, max_polls: usize) -> Option<F::Output> {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    (0..max_polls).find_map(|_| match future.poll_unpin(&mut cx) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    })
+}
+
+/// Decode all frames of `data` at once using provided codec, as reference
+/// for the framing layer.
+pub fn decode_all<C: Decoder>(mut codec: C, data: &[u8]) -> Vec<C::Item> {
+    let mut buffer = BytesMut::from(data);
+    let mut items = Vec::new();
+    while let Ok(Some(item)) = codec.decode(&mut buffer) {
+        items.push(item);
+    }
+    items
+}
@@ -0,0 +1,259 @@
+//! Command-line utility managing keys, certificates and references.
+//!
+//! References are handled as bincode serialized `Reference<u64, Dalek>`,
+//! keys as raw ed25519 keypair bytes, and public keys as hex strings.
+//! Private keys are only readable by their owner (mode `0600`).
+use std::{convert::TryFrom, env, fs, io::Write, process};
+
+use rpccaps::{ErrorKind, Result};
+use rpccaps::data::{Authorization, Capability, Reference, SignMethod, tls};
+use rpccaps::data::signature::{Dalek, dalek::{Keypair, PublicKey}};
+use rpccaps::data::validate::Validate;
+
+
+type Ref = Reference<u64, Dalek>;
+
+const USAGE: &str = "\
+Usage: rpccaps-cli <command> [args...]
+
+Commands:
+    keygen <key_out>                        generate a new identity keypair
+    pubkey <key>                            print identity's public key
+    cert <cert_out> <key_out> [subjects..]  generate a self-signed certificate (DER)
+    mint <issuer_key> <subject> <id> <actions> <share> <max_share> <ref_out>
+                                            create a new reference
+    sign <ref> <signer_key> <subject> <actions> <share> <ref_out>
+                                            delegate reference to subject
+    shrink <ref> <signer_key> <subject> <ref_out>
+                                            shorten reference's chain up to subject
+    validate <ref> <subject>                validate reference for subject
+    inspect <ref>                           print reference's content
+    inspect-cap <blob>                      print a serialized capability
+
+Public keys are hex-encoded, capability bits are integers (0b, 0x prefixes allowed).";
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(value: &str) -> Result<Vec<u8>> {
+    if !value.is_ascii() {
+        return ErrorKind::InvalidInput.err("invalid hex string");
+    }
+    if !value.len().is_multiple_of(2) {
+        return ErrorKind::InvalidInput.err("invalid hex string length");
+    }
+    (0..value.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i+2], 16)
+                    .or(ErrorKind::InvalidInput.err("invalid hex string")))
+        .collect()
+}
+
+fn parse_int(value: &str) -> Result<u64> {
+    let (value, radix) = match value {
+        v if v.starts_with("0b") => (&v[2..], 2),
+        v if v.starts_with("0x") => (&v[2..], 16),
+        v => (v, 10),
+    };
+    u64::from_str_radix(value, radix)
+        .or(ErrorKind::InvalidInput.err(format!("invalid integer: {}", value)))
+}
+
+fn parse_u32(value: &str) -> Result<u32> {
+    u32::try_from(parse_int(value)?)
+        .or(ErrorKind::InvalidInput.err(format!("integer out of range: {}", value)))
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).or_else(|err| ErrorKind::File.err(format!("{}: {}", path, err)))
+}
+
+fn write(path: &str, data: &[u8]) -> Result<()> {
+    fs::write(path, data).or_else(|err| ErrorKind::File.err(format!("{}: {}", path, err)))
+}
+
+/// Write private key to `path`, only readable by its owner. The key is
+/// written to a temporary file which replaces the target, so that an
+/// existing file never exposes it with its former permissions.
+fn write_secret(path: &str, data: &[u8]) -> Result<()> {
+    let temp = format!("{}.{}.tmp", path, process::id());
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(&temp)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path))
+        .or_else(|err| {
+            let _ = fs::remove_file(&temp);
+            ErrorKind::File.err(format!("{}: {}", path, err))
+        })
+}
+
+fn read_key(path: &str) -> Result<Keypair> {
+    Dalek::signer(&read(path)?).or(ErrorKind::InvalidData.err("invalid keypair"))
+}
+
+fn read_ref(path: &str) -> Result<Ref> {
+    bincode::deserialize(&read(path)?).or(ErrorKind::InvalidData.err("invalid reference"))
+}
+
+fn write_ref(path: &str, reference: &Ref) -> Result<()> {
+    bincode::serialize(reference).or(ErrorKind::Codec.err("can not serialize reference"))
+        .and_then(|buf| write(path, &buf))
+}
+
+fn public_key(value: &str) -> Result<PublicKey> {
+    PublicKey::from_bytes(&from_hex(value)?).or(ErrorKind::InvalidInput.err("invalid public key"))
+}
+
+fn capability(actions: &str, share: &str) -> Result<Capability> {
+    Ok(Capability::new(parse_int(actions)?, parse_int(share)?))
+}
+
+fn print_capability(prefix: &str, capability: &Capability) {
+    println!("{}actions: {:#066b}", prefix, capability.actions);
+    println!("{}share:   {:#066b}", prefix, capability.share);
+}
+
+
+fn run(args: &[String]) -> Result<()> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["keygen", key_out] => {
+            let keypair = Dalek::generate().or(ErrorKind::Internal.err("can not generate key"))?;
+            write_secret(key_out, &keypair.to_bytes())?;
+            println!("{}", to_hex(keypair.public.as_bytes()));
+        },
+        ["pubkey", key] => println!("{}", to_hex(read_key(key)?.public.as_bytes())),
+        ["cert", cert_out, key_out, subjects @ ..] => {
+            let subjects = match subjects.len() {
+                0 => vec![String::from("localhost")],
+                _ => subjects.iter().map(|s| s.to_string()).collect(),
+            };
+            let (certs, key) = tls::new_cert(subjects)?;
+            write(cert_out, &certs[0].0)?;
+            write_secret(key_out, &key.0)?;
+        },
+        ["mint", issuer_key, subject, id, actions, share, max_share, ref_out] => {
+            let auth = Authorization::new(capability(actions, share)?, public_key(subject)?);
+            let reference = Ref::new(parse_int(id)?, &read_key(issuer_key)?,
+                                     parse_u32(max_share)?, auth)
+                .or_else(|err| ErrorKind::InvalidInput.err(format!("{:?}", err)))?;
+            write_ref(ref_out, &reference)?;
+        },
+        ["sign", reference, signer_key, subject, actions, share, ref_out] => {
+            let mut reference = read_ref(reference)?;
+            let auth = Authorization::new(capability(actions, share)?, public_key(subject)?);
+            reference.sign(&read_key(signer_key)?, auth)
+                .or_else(|err| ErrorKind::InvalidInput.err(format!("{:?}", err)))?;
+            write_ref(ref_out, &reference)?;
+        },
+        ["shrink", reference, signer_key, subject, ref_out] => {
+            let reference = read_ref(reference)?
+                .shrink(&read_key(signer_key)?, &public_key(subject)?)
+                .ok_or(ErrorKind::InvalidInput.error("can not shrink reference"))?;
+            write_ref(ref_out, &reference)?;
+        },
+        ["validate", reference, subject] => {
+            read_ref(reference)?.validate(&public_key(subject)?)
+                .or_else(|err| ErrorKind::InvalidData.err(format!("invalid reference: {:?}", err)))?;
+            println!("valid");
+        },
+        ["inspect", reference] => {
+            let reference = read_ref(reference)?;
+            println!("id: {}", reference.id());
+            println!("issuer: {}", to_hex(reference.issuer().as_bytes()));
+            println!("max_share: {}", reference.max_share());
+            for (i, cert) in reference.certs().iter().enumerate() {
+                println!("cert #{}:", i);
+                println!("    subject: {}", to_hex(cert.auth.subject.as_bytes()));
+                print_capability("    ", &cert.auth.capability);
+                if let Some(not_before) = cert.auth.not_before {
+                    println!("    not_before: {}", not_before);
+                }
+                if let Some(expires) = cert.auth.expires {
+                    println!("    expires: {}", expires);
+                }
+            }
+        },
+        ["inspect-cap", blob] => {
+            let capability: Capability = bincode::deserialize(&read(blob)?)
+                .or(ErrorKind::InvalidData.err("invalid capability"))?;
+            print_capability("", &capability);
+            if !capability.is_valid() {
+                println!("warning: shared actions are not a subset of allowed actions");
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+    Ok(())
+}
+
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("rpccaps-test-cli-{}", name)).to_str().unwrap().to_string()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(from_hex("00ff10").unwrap(), vec![0x00, 0xff, 0x10]);
+        assert_eq!(from_hex("0").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(from_hex("zz").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(from_hex("é0").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(parse_int("0b101").unwrap(), 5);
+        assert_eq!(parse_int("0x1f").unwrap(), 31);
+        assert_eq!(parse_int("12").unwrap(), 12);
+        assert_eq!(parse_u32("4294967295").unwrap(), u32::MAX);
+        assert_eq!(parse_u32("4294967296").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_mint_validate() {
+        let (issuer, subject, reference) = (temp_path("issuer.key"), temp_path("subject.key"),
+                                            temp_path("ref.bin"));
+        run(&args(&["keygen", &issuer])).unwrap();
+        run(&args(&["keygen", &subject])).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&issuer).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let subject_key = to_hex(read_key(&subject).unwrap().public.as_bytes());
+        run(&args(&["mint", &issuer, &subject_key, "1", "0b11", "0b01", "2", &reference])).unwrap();
+        run(&args(&["validate", &reference, &subject_key])).unwrap();
+        assert_eq!(read_ref(&reference).unwrap().certs()[0].auth.capability, Capability::new(0b11, 0b01));
+
+        let issuer_key = to_hex(read_key(&issuer).unwrap().public.as_bytes());
+        assert!(run(&args(&["validate", &reference, &issuer_key])).is_err());
+        assert!(run(&args(&["mint", &issuer, &subject_key, "1", "1", "0", "4294967296", &reference])).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Content addressing of objects granted through references.
+//!
+//! An object's id is derived from the hash of its issuing reference's root
+//! (id, issuer, max share and first certificate). Thus ids of dynamically
+//! granted objects are collision-free, and any peer holding the reference
+//! or one of its delegations computes the same id.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use serde::{Serialize,Deserialize};
+use super::bytes::Bytes;
+use super::canonical;
+use super::clock::{Clock, SystemClock};
+use super::hash::{DefaultHasher, Hasher};
+use super::reference::{Certificate, Error, Reference};
+use super::signature::SignMethod;
+
+
+/// Prefix of hashed data, separating object ids from other hashes.
+const ADDRESS_PREFIX: &[u8] = b"rpccaps-object:";
+
+
+/// Hashed data of a reference's root.
+#[derive(Serialize)]
+#[serde(bound="Id: Serialize, Sign: SignMethod")]
+struct AddressData<'a, Id, Sign: SignMethod> {
+    id: &'a Id,
+    issuer: &'a [u8],
+    max_share: u32,
+    cert: &'a Certificate<Sign>,
+}
+
+
+/// Object id derived from a reference.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct ObjectId(pub [u8;32]);
+
+impl ObjectId {
+    /// Derive object id from provided reference.
+    pub fn from_reference<Id,Sign>(reference: &Reference<Id,Sign>) -> Result<Self,Error>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        let cert = reference.certs().first().ok_or(Error::Empty)?;
+        let data = AddressData {
+            id: reference.id(),
+            issuer: reference.issuer().as_bytes(),
+            max_share: reference.max_share(),
+            cert,
+        };
+        let data = canonical::serialize(&data).map_err(Error::Serialize)?;
+
+        let mut hasher = DefaultHasher::default();
+        hasher.update(ADDRESS_PREFIX);
+        hasher.update(&data);
+        Ok(Self(hasher.finalize()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+
+impl<Id,Sign> Reference<Id,Sign>
+    where Id: Clone+Serialize, Sign: SignMethod
+{
+    /// Return id of the object granted by this reference.
+    pub fn object_id(&self) -> Result<ObjectId,Error> {
+        ObjectId::from_reference(self)
+    }
+}
+
+
+/// Objects registered by their content-addressed id.
+pub struct Registry<T> {
+    objects: RwLock<BTreeMap<ObjectId, T>>,
+}
+
+impl<T: Clone> Registry<T> {
+    pub fn new() -> Self {
+        Self { objects: RwLock::new(BTreeMap::new()) }
+    }
+
+    /// Register object granted by reference, returning its id. Return
+    /// None if there already is an object for this id.
+    pub fn insert<Id,Sign>(&self, reference: &Reference<Id,Sign>, object: T)
+        -> Result<Option<ObjectId>,Error>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        let id = reference.object_id()?;
+        let mut objects = self.objects.write().unwrap();
+        if objects.contains_key(&id) {
+            return Ok(None);
+        }
+        objects.insert(id, object);
+        Ok(Some(id))
+    }
+
+    /// Return object by id.
+    pub fn get(&self, id: &ObjectId) -> Option<T> {
+        self.objects.read().unwrap().get(id).cloned()
+    }
+
+    /// Return object granted by reference (or one of its delegations),
+    /// once the reference is validated for `subject` at system's time.
+    pub fn resolve<Id,Sign>(&self, reference: &Reference<Id,Sign>, subject: &Sign::Verifier)
+        -> Result<Option<T>,Error>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        self.resolve_with(reference, subject, &SystemClock)
+    }
+
+    /// Return object granted by reference, validating it for `subject`
+    /// using clock's time.
+    pub fn resolve_with<Id,Sign,C>(&self, reference: &Reference<Id,Sign>, subject: &Sign::Verifier,
+                                   clock: &C)
+        -> Result<Option<T>,Error>
+        where Id: Clone+Serialize, Sign: SignMethod, C: Clock
+    {
+        reference.validate_with(subject, clock)?;
+        Ok(self.get(&reference.object_id()?))
+    }
+
+    /// Remove object by id, returning it.
+    pub fn remove(&self, id: &ObjectId) -> Option<T> {
+        self.objects.write().unwrap().remove(id)
+    }
+
+    /// Count of registered objects.
+    pub fn len(&self) -> usize {
+        self.objects.read().unwrap().len()
+    }
+
+    /// Return true if there is no registered object.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Capability;
+    use crate::data::signature::Dalek;
+    use crate::data::testing::Chain;
+
+    #[test]
+    fn test_object_id() {
+        let cap = Capability::new(0b1111, 0b1111);
+        let mut chain = Chain::<Dalek>::new(0, 0, 8, cap.clone());
+        let id = chain.object_id().unwrap();
+        assert_eq!(id, chain.reference.clone().object_id().unwrap());
+        assert_eq!(id.to_string().len(), 64);
+
+        // delegations share root's id
+        chain.sign(1, cap.clone()).unwrap();
+        assert_eq!(chain.object_id().unwrap(), id);
+
+        let other = Chain::<Dalek>::new(10, 0, 8, cap);
+        assert_ne!(other.object_id().unwrap(), id);
+    }
+
+    #[test]
+    fn test_registry() {
+        let cap = Capability::new(0b1111, 0b1111);
+        let mut chain = Chain::<Dalek>::new(0, 0, 8, cap.clone());
+        let registry = Registry::new();
+
+        let id = registry.insert(&chain.reference, "object").unwrap().unwrap();
+        assert_eq!(registry.insert(&chain.reference, "other").unwrap(), None);
+        assert_eq!(registry.get(&id), Some("object"));
+
+        chain.sign(1, cap).unwrap();
+        let subject = chain.verifier(2);
+        assert_eq!(registry.resolve(&chain.reference, &subject).unwrap(), Some("object"));
+        assert!(matches!(registry.resolve(&chain.reference, &chain.verifier(1)), Err(Error::Subject)));
+
+        // tampered chain is refused
+        let mut data = bincode::serialize(&chain.reference).unwrap();
+        let index = data.len() - 64;
+        data[index] ^= 0xff;
+        let tampered: Reference<u64,Dalek> = bincode::deserialize(&data).unwrap();
+        assert!(matches!(registry.resolve(&tampered, &subject), Err(Error::Signature(_))));
+        assert_eq!(registry.remove(&id), Some("object"));
+        assert!(registry.is_empty());
+    }
+}
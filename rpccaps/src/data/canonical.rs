@@ -0,0 +1,119 @@
+//! Canonical serialization of signed data.
+//!
+//! Signatures are computed over serialized data, whose encoding is pinned
+//! here instead of relying on bincode's defaults, so that upgrading bincode
+//! or changing the wire codec can not silently invalidate signatures.
+//!
+//! Profile's version 1 is:
+//! - a leading version byte;
+//! - bincode 1.x encoding with fixed-size little-endian integers, `u64`
+//!   lengths, and enum variants' index as `u32`;
+//! - struct fields and enum variants in their declaration order: reordering
+//!   them in signed structures is a breaking change.
+use bincode::Options;
+use serde::{Serialize,Deserialize};
+
+use super::depth::{self, DepthLimit};
+
+
+/// Version of the canonical profile.
+pub const VERSION: u8 = 1;
+
+
+/// Bincode options of the profile.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .with_no_limit()
+        .reject_trailing_bytes()
+}
+
+/// Serialize value using the canonical profile.
+pub fn serialize<T: ?Sized+Serialize>(value: &T) -> bincode::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    serialize_into(&mut buf, value)?;
+    Ok(buf)
+}
+
+/// Serialize value using the canonical profile, appending it to `buf`.
+pub fn serialize_into<T: ?Sized+Serialize>(buf: &mut Vec<u8>, value: &T) -> bincode::Result<()> {
+    buf.push(VERSION);
+    options().serialize_into(buf, value)
+}
+
+/// Deserialize value serialized using the canonical profile, nested at
+/// most `depth::DEFAULT_MAX_DEPTH` deep.
+pub fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
+    deserialize_with_depth(bytes, depth::DEFAULT_MAX_DEPTH)
+}
+
+/// Deserialize value serialized using the canonical profile, nested at
+/// most `max_depth` deep.
+pub fn deserialize_with_depth<'a, T: Deserialize<'a>>(bytes: &'a [u8], max_depth: usize)
+    -> bincode::Result<T>
+{
+    match bytes.split_first() {
+        Some((&VERSION, data)) => {
+            let limit = DepthLimit::new(max_depth);
+            options().deserialize_seed(&limit, data).map_err(|err| match limit.exceeded() {
+                Some(exceeded) => Box::new(bincode::ErrorKind::Io(exceeded.into())),
+                None => err,
+            })
+        },
+        Some((version, _)) => Err(Box::new(bincode::ErrorKind::Custom(
+            format!("unsupported canonical profile version {}", version)))),
+        None => Err(Box::new(bincode::ErrorKind::Custom(String::from("empty data")))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::data::{Authorization, Capability};
+    use crate::data::reference::CertData;
+    use crate::data::signature::Dalek;
+
+    #[test]
+    fn test_canonical() {
+        let value = (1u8, 0x0203u16, Some(4u64), vec![5u8, 6], String::from("a"));
+        let data = serialize(&value).unwrap();
+        assert_eq!(data, [
+            1,                          // version
+            1, 3, 2,                    // u8, u16
+            1, 4, 0, 0, 0, 0, 0, 0, 0,  // Some(u64)
+            2, 0, 0, 0, 0, 0, 0, 0, 5, 6,
+            1, 0, 0, 0, 0, 0, 0, 0, b'a',
+        ]);
+        assert_eq!(deserialize::<(u8, u16, Option<u64>, Vec<u8>, String)>(&data).unwrap(), value);
+        // tuple and option are nested values
+        assert!(deserialize_with_depth::<(u8, u16, Option<u64>, Vec<u8>, String)>(&data, 2).is_ok());
+        assert!(deserialize_with_depth::<(u8, u16, Option<u64>, Vec<u8>, String)>(&data, 1).is_err());
+
+        let mut data = data;
+        assert!(deserialize::<(u8, u16, Option<u64>, Vec<u8>, String)>(&data[..data.len()-1]).is_err());
+        data[0] = 2;
+        assert!(deserialize::<(u8, u16, Option<u64>, Vec<u8>, String)>(&data).is_err());
+    }
+
+    #[test]
+    fn test_canonical_cert_data() {
+        // pinned digest: any change of the signed data's encoding breaks it
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let issuer = Keypair { secret, public };
+        let auth = Authorization::<Dalek>::new(Capability::new(0b11, 0b01), issuer.public)
+                        .with_validity(Some(10), Some(20));
+        let data = CertData::Reference(auth, 42u32, issuer.public, 2);
+
+        let data = serialize(&data).unwrap();
+        let digest = Sha256::digest(&data).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        // version, variant, authorization, id, issuer, max share
+        assert_eq!(data.len(), 1 + 4 + (16 + 40 + 9 + 9) + 4 + 40 + 4);
+        assert_eq!(digest, "7a8d4c3162f85d7b2b501fb0b57071e8a71794d71a5425ee4ccdac88f5ae18f9");
+    }
+}
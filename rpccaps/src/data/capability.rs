@@ -1,4 +1,5 @@
 use std::cmp::PartialEq;
+use std::fmt;
 use std::ops::{BitAnd,BitAndAssign};
 
 use serde::{Serialize,Deserialize};
@@ -6,17 +7,35 @@ use serde::{Serialize,Deserialize};
 /// A Capability are the allowed operations (and thoses that can be shared)
 /// over a reference's Authorization.
 ///
+/// Actions' bits can be named with `Capability::with_names()`, such as
+/// with the ones of a service (see `Service::action_names()`), for `Debug`
+/// formatting; names are not sent on the wire. Capabilities are built from
+/// actions' constants with `Actions`, or the `capability!` macro:
+///
+/// ```
+/// use rpccaps::capability;
+///
+/// const READ: u64 = 0b01;
+/// const WRITE: u64 = 0b10;
+///
+/// let cap = capability!{ READ | WRITE, share: READ }.with_names(&["read", "write"]);
+/// assert_eq!(format!("{:?}", cap), "Capability { actions: read | write, share: read }");
+/// ```
+///
 /// # Operators
 /// - `BitAnd`: equivalent to `Capability::subset()`
 /// - `BitAndAssign`: inplace equivalent to `Capability::subset_inplace()`
 ///
 
-#[derive(Serialize,Deserialize,PartialEq,Clone,Debug)]
+#[derive(Serialize,Deserialize,Clone)]
 pub struct Capability {
     /// Allowed actions as a bits field.
     pub actions: u64,
     /// Shareable operations as a bits field.
     pub share: u64,
+    /// Names of actions' bits, by bit index.
+    #[serde(skip)]
+    names: Option<&'static [&'static str]>,
 }
 
 
@@ -24,18 +43,36 @@ impl Capability {
     /// Create a capability ensuring valid fields.
     pub fn new(actions: u64, share: u64) -> Self {
         let (actions, share) = (actions, (share & actions));
-        Self { actions, share }
+        Self { actions, share, names: None }
     }
 
     /// Create an empty capability.
     pub fn empty() -> Self {
-        Self { actions: 0, share: 0 }
+        Self { actions: 0, share: 0, names: None }
+    }
+
+    /// Name actions' bits, by bit index. Empty names are formatted as
+    /// unnamed bits.
+    pub fn with_names(mut self, names: &'static [&'static str]) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    /// Return names of actions' bits, if any.
+    pub fn names(&self) -> Option<&'static [&'static str]> {
+        self.names
+    }
+
+    /// Return bit of action named `name`.
+    pub fn action(&self, name: &str) -> Option<u64> {
+        let index = self.names?.iter().position(|n| !n.is_empty() && *n == name)?;
+        Some(1 << index)
     }
 
     /// Create new capability as subset of `self`.
     pub fn subset(&self, actions: u64, share: u64) -> Self {
         let (actions, share) = (actions, (share & actions));
-        Self { actions: self.share & actions, share: self.share & share }
+        Self { actions: self.share & actions, share: self.share & share, names: self.names }
     }
 
     /// Make `self` as subset of itself.
@@ -78,6 +115,98 @@ impl Capability {
 }
 
 
+impl PartialEq for Capability {
+    fn eq(&self, other: &Self) -> bool {
+        self.actions == other.actions && self.share == other.share
+    }
+}
+
+
+impl fmt::Debug for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.names {
+            None => f.debug_struct("Capability").field("actions", &self.actions)
+                     .field("share", &self.share).finish(),
+            Some(names) => f.debug_struct("Capability").field("actions", &NamedBits(self.actions, names))
+                            .field("share", &NamedBits(self.share, names)).finish(),
+        }
+    }
+}
+
+/// Bits formatted by their names, `bitN` when unnamed.
+struct NamedBits(u64, &'static [&'static str]);
+
+impl fmt::Debug for NamedBits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let NamedBits(bits, names) = *self;
+        if bits == 0 {
+            return f.write_str("0")
+        }
+        let set = (0..64).filter(|index| bits & (1 << index) != 0);
+        for (n, index) in set.enumerate() {
+            if n > 0 {
+                f.write_str(" | ")?;
+            }
+            match names.get(index) {
+                Some(name) if !name.is_empty() => f.write_str(name)?,
+                _ => write!(f, "bit{}", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Builder of a `Capability` from actions' constants.
+#[derive(Clone,Debug,Default)]
+pub struct Actions {
+    actions: u64,
+    share: u64,
+    names: Option<&'static [&'static str]>,
+}
+
+impl Actions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow provided actions.
+    pub fn allow(mut self, actions: u64) -> Self {
+        self.actions |= actions;
+        self
+    }
+
+    /// Allow and share provided actions.
+    pub fn share(mut self, share: u64) -> Self {
+        self.actions |= share;
+        self.share |= share;
+        self
+    }
+
+    /// Name actions' bits, by bit index.
+    pub fn names(mut self, names: &'static [&'static str]) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    /// Return built capability.
+    pub fn build(self) -> Capability {
+        Capability { actions: self.actions, share: self.share, names: self.names }
+    }
+}
+
+
+/// Build a `Capability` from allowed actions, optionally followed by shared
+/// ones: `capability!{ READ | WRITE, share: READ }`. Shared actions are
+/// also allowed.
+#[macro_export]
+macro_rules! capability {
+    ($actions:expr $(, share: $share:expr)? $(,)?) => {
+        $crate::data::capability::Actions::new().allow($actions)$(.share($share))?.build()
+    };
+}
+
+
 impl BitAnd for Capability {
     type Output = Self;
 
@@ -94,6 +223,281 @@ impl BitAndAssign for Capability {
 }
 
 
+/// Allowed and shareable actions carried by authorizations (see
+/// `reference::Authorization`), and presented by callers of services (see
+/// `Service::Capability`). Actions are identified by their index, lower
+/// than `ACTIONS`.
+pub trait CapabilitySet: Clone+PartialEq {
+    /// Maximum number of actions.
+    const ACTIONS: usize;
+
+    /// Create a capability allowing every action, sharing none.
+    fn all() -> Self;
+
+    /// Create a capability allowing no action.
+    fn empty() -> Self;
+
+    /// Create a capability allowing the action of provided index, or an
+    /// empty one if it is not lower than `ACTIONS`.
+    fn of_action(index: usize) -> Self;
+
+    /// Return true if the action of provided index is allowed.
+    fn allows(&self, index: usize) -> bool;
+
+    /// Return allowed actions as a bits field, by words of 64 actions.
+    fn action_bits(&self) -> Vec<u64>;
+
+    /// Return true if `self` is a subset of `cap`.
+    fn is_subset(&self, cap: &Self) -> bool;
+
+    /// Verify that shareable actions are allowed ones.
+    fn is_valid(&self) -> bool;
+
+    /// Return true if no action is allowed.
+    fn is_empty(&self) -> bool;
+
+    /// Return the largest capability that can be delegated: shareable
+    /// actions, which can be shared again.
+    fn delegable(&self) -> Self;
+
+    /// Return actions allowed and shareable by both `self` and `cap`.
+    fn intersection(&self, cap: &Self) -> Self;
+}
+
+impl CapabilitySet for Capability {
+    const ACTIONS: usize = 64;
+
+    fn all() -> Self {
+        Self::new(u64::MAX, 0)
+    }
+
+    fn empty() -> Self {
+        Capability::empty()
+    }
+
+    fn of_action(index: usize) -> Self {
+        Self::new(1u64.checked_shl(index as u32).unwrap_or(0), 0)
+    }
+
+    fn allows(&self, index: usize) -> bool {
+        index < Self::ACTIONS && self.is_allowed(1 << index)
+    }
+
+    fn action_bits(&self) -> Vec<u64> {
+        vec![self.actions]
+    }
+
+    fn is_subset(&self, cap: &Self) -> bool {
+        Capability::is_subset(self, cap)
+    }
+
+    fn is_valid(&self) -> bool {
+        Capability::is_valid(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        Capability::is_empty(self)
+    }
+
+    fn delegable(&self) -> Self {
+        Self { actions: self.share, share: self.share, names: self.names }
+    }
+
+    fn intersection(&self, cap: &Self) -> Self {
+        Self { actions: self.actions & cap.actions, share: self.share & cap.share,
+               names: self.names.or(cap.names) }
+    }
+}
+
+
+/// Capability of `64 * N` actions, for services exceeding the 64 actions
+/// of a `Capability` (see `#[service(capability_type=...)]`). Actions are
+/// identified by their index, and have the same subset and share
+/// semantics.
+#[derive(Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Debug)]
+pub struct WideCapability<const N: usize> {
+    /// Allowed actions as a bits field, by words of 64 actions.
+    #[serde(with="words")]
+    pub actions: [u64; N],
+    /// Shareable actions as a bits field, by words of 64 actions.
+    #[serde(with="words")]
+    pub share: [u64; N],
+}
+
+impl<const N: usize> WideCapability<N> {
+    /// Maximum number of actions.
+    pub const ACTIONS: usize = 64 * N;
+
+    /// Create a capability ensuring valid fields.
+    pub fn new(actions: [u64; N], share: [u64; N]) -> Self {
+        Self { actions, share: std::array::from_fn(|i| share[i] & actions[i]) }
+    }
+
+    /// Create an empty capability.
+    pub fn empty() -> Self {
+        Self { actions: [0; N], share: [0; N] }
+    }
+
+    /// Create a capability from allowed and shared actions' indexes.
+    /// Shared actions are also allowed.
+    ///
+    /// # Panics
+    /// If an index is not lower than `Self::ACTIONS`.
+    pub fn from_indexes(actions: &[usize], share: &[usize]) -> Self {
+        let mut cap = Self::empty();
+        for &index in actions {
+            cap.actions[index / 64] |= 1 << (index % 64);
+        }
+        for &index in share {
+            cap.actions[index / 64] |= 1 << (index % 64);
+            cap.share[index / 64] |= 1 << (index % 64);
+        }
+        cap
+    }
+
+    /// Create new capability as subset of `self`.
+    pub fn subset(&self, actions: &[u64; N], share: &[u64; N]) -> Self {
+        Self {
+            actions: std::array::from_fn(|i| self.share[i] & actions[i]),
+            share: std::array::from_fn(|i| self.share[i] & share[i] & actions[i]),
+        }
+    }
+
+    /// Return true if action of provided index is allowed.
+    pub fn is_allowed(&self, index: usize) -> bool {
+        index < Self::ACTIONS && self.actions[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Return true if action of provided index can be shared.
+    pub fn is_shareable(&self, index: usize) -> bool {
+        index < Self::ACTIONS && self.share[index / 64] & (1 << (index % 64)) != 0
+    }
+}
+
+impl<const N: usize> CapabilitySet for WideCapability<N> {
+    const ACTIONS: usize = 64 * N;
+
+    fn all() -> Self {
+        Self::new([u64::MAX; N], [0; N])
+    }
+
+    fn empty() -> Self {
+        WideCapability::empty()
+    }
+
+    fn of_action(index: usize) -> Self {
+        match index < Self::ACTIONS {
+            true => Self::from_indexes(&[index], &[]),
+            false => Self::empty(),
+        }
+    }
+
+    fn allows(&self, index: usize) -> bool {
+        self.is_allowed(index)
+    }
+
+    fn action_bits(&self) -> Vec<u64> {
+        self.actions.to_vec()
+    }
+
+    fn is_subset(&self, cap: &Self) -> bool {
+        (0..N).all(|i| {
+            self.actions[i] & !(cap.share[i] & cap.actions[i]) == 0 &&
+            self.share[i] & !cap.share[i] == 0
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        (0..N).all(|i| self.share[i] & !self.actions[i] == 0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.actions.iter().chain(self.share.iter()).all(|word| *word == 0)
+    }
+
+    fn delegable(&self) -> Self {
+        Self { actions: self.share, share: self.share }
+    }
+
+    fn intersection(&self, cap: &Self) -> Self {
+        Self {
+            actions: std::array::from_fn(|i| self.actions[i] & cap.actions[i]),
+            share: std::array::from_fn(|i| self.share[i] & cap.share[i]),
+        }
+    }
+}
+
+impl<const N: usize> From<Capability> for WideCapability<N> {
+    /// Capability's actions are the first 64 ones.
+    fn from(cap: Capability) -> Self {
+        let mut wide = Self::empty();
+        if N > 0 {
+            wide.actions[0] = cap.actions;
+            wide.share[0] = cap.share;
+        }
+        wide
+    }
+}
+
+impl<const N: usize> BitAnd for WideCapability<N> {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.subset(&rhs.actions, &rhs.share)
+    }
+}
+
+impl<const N: usize> BitAndAssign for WideCapability<N> {
+    fn bitand_assign(&mut self, rhs: Self) {
+        *self = self.subset(&rhs.actions, &rhs.share)
+    }
+}
+
+
+/// (De)serialization of words arrays as tuples, serde only implementing it
+/// for arrays up to 32 items.
+mod words {
+    use std::fmt;
+
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{SerializeTuple, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(words: &[u64; N], serializer: S)
+        -> Result<S::Ok, S::Error>
+    {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for word in words {
+            tuple.serialize_element(word)?;
+        }
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D)
+        -> Result<[u64; N], D::Error>
+    {
+        struct WordsVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for WordsVisitor<N> {
+            type Value = [u64; N];
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an array of {} words", N)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut words = [0u64; N];
+                for (index, word) in words.iter_mut().enumerate() {
+                    *word = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &self))?;
+                }
+                Ok(words)
+            }
+        }
+
+        deserializer.deserialize_tuple(N, WordsVisitor::<N>)
+    }
+}
+
+
 
 #[cfg(test)]
 mod tests {
@@ -123,5 +527,70 @@ mod tests {
         assert!(!b.is_subset(&a));
     }
 
+    #[test]
+    fn test_wide_capability() {
+        type Wide = WideCapability<2>;
+
+        let a = Wide::from_indexes(&[1, 2, 70, 100], &[1, 70]);
+        assert!(a.is_valid());
+        assert!(a.is_allowed(100) && !a.is_shareable(100) && a.is_shareable(70));
+        assert!(!a.is_allowed(128));
+        assert_eq!(Wide::new([0b11, 0b11], [0b10, 0b111]), Wide::new([0b11, 0b11], [0b10, 0b11]));
+
+        // same semantics as `Capability` on each word
+        let b = a.subset(&[0b0110, 1 << 6 | 1 << 36], &[0b10, 0]);
+        assert_eq!(b, Wide::from_indexes(&[70], &[1]));
+        assert!(b.is_subset(&a));
+        assert!(!a.is_subset(&b));
+        assert!(!Wide::from_indexes(&[100], &[]).is_subset(&a));
+        assert!(Wide::empty().is_subset(&a) && Wide::empty().is_empty());
+        assert!(Wide::all().allows(127) && Wide::all().delegable().is_empty());
+        assert_eq!(Wide::of_action(100), Wide::from_indexes(&[100], &[]));
+        assert_eq!(Wide::of_action(128), Wide::empty());
+        assert_eq!(Capability::of_action(3), Capability::new(0b1000, 0));
+        assert_eq!(Capability::of_action(64), Capability::empty());
+        assert!(Capability::all().allows(63) && !Capability::all().allows(64));
+
+        let cap = Capability::new(0b1011, 0b0010);
+        let wide = Wide::from(cap.clone());
+        assert_eq!(wide, Wide::new([0b1011, 0], [0b0010, 0]));
+        assert_eq!(wide.is_subset(&a), cap.is_subset(&Capability::new(a.actions[0], a.share[0])));
+
+        let data = bincode::serialize(&a).unwrap();
+        assert_eq!(data.len(), 32);
+        assert_eq!(bincode::deserialize::<Wide>(&data).unwrap(), a);
+        assert!(bincode::deserialize::<Wide>(&data[..24]).is_err());
+    }
+
+    #[test]
+    fn test_actions() {
+        const READ: u64 = 0b001;
+        const WRITE: u64 = 0b010;
+        const ADMIN: u64 = 0b100;
+
+        let cap = capability!{ READ | WRITE, share: READ };
+        assert_eq!(cap, Capability::new(0b011, 0b001));
+        assert_eq!(capability!{ READ, share: ADMIN }, Capability::new(0b101, 0b100));
+        assert_eq!(Actions::new().allow(WRITE).build(), Capability::new(0b010, 0));
+        assert_eq!(format!("{:?}", cap), "Capability { actions: 3, share: 1 }");
+
+        let names = &["read", "write"];
+        let cap = cap.with_names(names);
+        assert_eq!(cap.action("write"), Some(WRITE));
+        assert_eq!(cap.action("admin"), None);
+        let sub = cap.subset(READ, 0);
+        assert_eq!(format!("{:?}", sub), "Capability { actions: read, share: 0 }");
+        let cap = Actions::new().names(names).allow(ADMIN).share(WRITE).build();
+        assert_eq!(format!("{:?}", cap), "Capability { actions: write | bit2, share: write }");
+        let cap = cap.with_names(&["", "write", ""]);
+        assert_eq!(format!("{:?}", cap), "Capability { actions: write | bit2, share: write }");
+
+        // names are not sent on the wire
+        let data = bincode::serialize(&cap).unwrap();
+        assert_eq!(data, bincode::serialize(&(0b110u64, 0b010u64)).unwrap());
+        let cap: Capability = bincode::deserialize(&data).unwrap();
+        assert_eq!(cap.names(), None);
+        assert_eq!(cap, Capability::new(0b110, 0b010));
+    }
 }
 
@@ -0,0 +1,98 @@
+//! Provide time sources used by time-dependent features (expiration,
+//! nonce windows, timeouts, quotas).
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+/// A source of time.
+pub trait Clock: Send+Sync {
+    /// Current time as duration since UNIX epoch.
+    fn now(&self) -> Duration;
+
+    /// Current time as seconds since UNIX epoch.
+    fn timestamp(&self) -> u64 {
+        self.now().as_secs()
+    }
+}
+
+
+/// Clock using system's time.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+
+/// Clock whose time is manually set, used for deterministic tests.
+#[derive(Debug,Default)]
+pub struct MockClock {
+    /// Time since UNIX epoch in milliseconds.
+    now: AtomicU64,
+}
+
+impl MockClock {
+    /// Create a new clock at provided time.
+    pub fn new(now: Duration) -> Self {
+        Self { now: AtomicU64::new(now.as_millis() as u64) }
+    }
+
+    /// Set clock's time.
+    pub fn set(&self, now: Duration) {
+        self.now.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Move clock forward by provided duration.
+    pub fn advance(&self, duration: Duration) {
+        self.now.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(self.now.load(Ordering::Relaxed))
+    }
+}
+
+
+impl<C: Clock+?Sized> Clock for Arc<C> {
+    fn now(&self) -> Duration {
+        self.as_ref().now()
+    }
+}
+
+impl<C: Clock+?Sized> Clock for &C {
+    fn now(&self) -> Duration {
+        (*self).now()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(Duration::from_secs(10));
+        assert_eq!(clock.timestamp(), 10);
+
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now(), Duration::from_millis(11500));
+
+        clock.set(Duration::from_secs(2));
+        assert_eq!(clock.timestamp(), 2);
+    }
+
+    #[test]
+    fn test_shared_clock() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let shared: Arc<dyn Clock> = clock.clone();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(shared.timestamp(), 15);
+    }
+}
@@ -0,0 +1,375 @@
+//! Deserialization with a maximum nesting depth.
+//!
+//! Recursive types (such as chains of certificates or nested enums) are
+//! deserialized recursively: a hostile payload nesting values deep enough
+//! blows the stack of the task decoding it. `DepthLimit` deserializes values
+//! through a deserializer counting nested compound values (sequences, maps,
+//! enums, options and newtypes), failing with `DepthExceeded` once they are
+//! nested deeper than its maximum:
+//!
+//! ```ignore
+//! let mut deserializer = serde_json::Deserializer::from_slice(&data);
+//! let message: Message = depth::deserialize(&mut deserializer, DEFAULT_MAX_DEPTH)?;
+//! ```
+//!
+//! Codecs (see `rpc::codec`) and canonical deserialization of signed data
+//! (see `canonical`), thus references and authentication messages, are
+//! limited to `DEFAULT_MAX_DEPTH` by default.
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, EnumAccess, MapAccess,
+                SeqAccess, VariantAccess, Visitor};
+
+use crate::ErrorKind;
+
+
+/// Default maximum nesting depth of deserialized values.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+
+/// Deserialized value is nested deeper than the maximum depth.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct DepthExceeded {
+    pub max_depth: usize,
+}
+
+impl fmt::Display for DepthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "value nested deeper than the maximum depth of {}", self.max_depth)
+    }
+}
+
+impl std::error::Error for DepthExceeded {}
+
+impl From<DepthExceeded> for std::io::Error {
+    fn from(err: DepthExceeded) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl From<DepthExceeded> for crate::Error {
+    fn from(err: DepthExceeded) -> Self {
+        ErrorKind::InvalidData.error(err.to_string())
+    }
+}
+
+
+/// Current depth of a deserialization.
+struct State {
+    depth: Cell<usize>,
+    max_depth: usize,
+    exceeded: Cell<bool>,
+}
+
+impl State {
+    /// Enter a nested value, failing if it is too deep.
+    fn enter<E: de::Error>(&self) -> Result<(), E> {
+        let depth = self.depth.get() + 1;
+        if depth > self.max_depth {
+            self.exceeded.set(true);
+            return Err(E::custom(DepthExceeded { max_depth: self.max_depth }))
+        }
+        self.depth.set(depth);
+        Ok(())
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Call `func` inside a nested value.
+    fn nested<T, E: de::Error>(&self, func: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.enter()?;
+        let result = func();
+        self.leave();
+        result
+    }
+}
+
+
+/// Seed deserializing values of type `T` nested at most `max_depth` deep.
+pub struct DepthLimit<T> {
+    state: State,
+    phantom: PhantomData<T>,
+}
+
+impl<T> DepthLimit<T> {
+    pub fn new(max_depth: usize) -> Self {
+        Self { state: State { depth: Cell::new(0), max_depth, exceeded: Cell::new(false) },
+               phantom: PhantomData }
+    }
+
+    /// Return the error if the maximum depth has been exceeded.
+    pub fn exceeded(&self) -> Option<DepthExceeded> {
+        match self.state.exceeded.get() {
+            true => Some(DepthExceeded { max_depth: self.state.max_depth }),
+            false => None,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for &DepthLimit<T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+        T::deserialize(Wrap { inner: deserializer, state: &self.state })
+    }
+}
+
+
+/// Deserialize value from `deserializer`, nested at most `max_depth` deep.
+pub fn deserialize<'de, T, D>(deserializer: D, max_depth: usize) -> Result<T, DepthError<D::Error>>
+    where T: Deserialize<'de>, D: Deserializer<'de>
+{
+    let limit = DepthLimit::new(max_depth);
+    (&limit).deserialize(deserializer).map_err(|err| match limit.exceeded() {
+        Some(exceeded) => DepthError::Exceeded(exceeded),
+        None => DepthError::Other(err),
+    })
+}
+
+
+/// Error of a deserialization with a maximum depth.
+#[derive(Debug)]
+pub enum DepthError<E> {
+    Exceeded(DepthExceeded),
+    /// Error of the deserializer.
+    Other(E),
+}
+
+impl<E: Into<std::io::Error>> From<DepthError<E>> for std::io::Error {
+    fn from(err: DepthError<E>) -> Self {
+        match err {
+            DepthError::Exceeded(err) => err.into(),
+            DepthError::Other(err) => err.into(),
+        }
+    }
+}
+
+
+/// Deserializer, visitor, or access of values, counting their depth.
+struct Wrap<'s, T> {
+    inner: T,
+    state: &'s State,
+}
+
+impl<'s, T> Wrap<'s, T> {
+    fn wrap<U>(&self, inner: U) -> Wrap<'s, U> {
+        Wrap { inner, state: self.state }
+    }
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Self::Error> {
+            let visitor = self.wrap(visitor);
+            self.inner.$method($($arg,)* visitor)
+        })*
+    };
+}
+
+impl<'s, 'de, D: Deserializer<'de>> Deserializer<'de> for Wrap<'s, D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any(); deserialize_bool(); deserialize_char();
+        deserialize_i8(); deserialize_i16(); deserialize_i32(); deserialize_i64(); deserialize_i128();
+        deserialize_u8(); deserialize_u16(); deserialize_u32(); deserialize_u64(); deserialize_u128();
+        deserialize_f32(); deserialize_f64();
+        deserialize_str(); deserialize_string(); deserialize_bytes(); deserialize_byte_buf();
+        deserialize_option(); deserialize_unit(); deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq(); deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map(); deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier(); deserialize_ignored_any();
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty);)*) => {
+        $(fn $method<E: de::Error>(self, value: $ty) -> Result<Self::Value, E> {
+            self.inner.$method(value)
+        })*
+    };
+}
+
+impl<'s, 'de, V: Visitor<'de>> Visitor<'de> for Wrap<'s, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(f)
+    }
+
+    forward_visit! {
+        visit_bool(bool); visit_char(char);
+        visit_i8(i8); visit_i16(i16); visit_i32(i32); visit_i64(i64); visit_i128(i128);
+        visit_u8(u8); visit_u16(u16); visit_u32(u32); visit_u64(u64); visit_u128(u128);
+        visit_f32(f32); visit_f64(f64);
+        visit_str(&str); visit_borrowed_str(&'de str); visit_string(String);
+        visit_bytes(&[u8]); visit_borrowed_bytes(&'de [u8]); visit_byte_buf(Vec<u8>);
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let (deserializer, Wrap { inner, state }) = (self.wrap(deserializer), self);
+        state.nested(|| inner.visit_some(deserializer))
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let (deserializer, Wrap { inner, state }) = (self.wrap(deserializer), self);
+        state.nested(|| inner.visit_newtype_struct(deserializer))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let (seq, Wrap { inner, state }) = (self.wrap(seq), self);
+        state.nested(|| inner.visit_seq(seq))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let (map, Wrap { inner, state }) = (self.wrap(map), self);
+        state.nested(|| inner.visit_map(map))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let (data, Wrap { inner, state }) = (self.wrap(data), self);
+        state.nested(|| inner.visit_enum(data))
+    }
+}
+
+impl<'s, 'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for Wrap<'s, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let deserializer = self.wrap(deserializer);
+        self.inner.deserialize(deserializer)
+    }
+}
+
+impl<'s, 'de, A: SeqAccess<'de>> SeqAccess<'de> for Wrap<'s, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, A::Error> {
+        let seed = self.wrap(seed);
+        self.inner.next_element_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'s, 'de, A: MapAccess<'de>> MapAccess<'de> for Wrap<'s, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error> {
+        let seed = self.wrap(seed);
+        self.inner.next_key_seed(seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        let seed = self.wrap(seed);
+        self.inner.next_value_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'s, 'de, A: EnumAccess<'de>> EnumAccess<'de> for Wrap<'s, A> {
+    type Error = A::Error;
+    type Variant = Wrap<'s, A::Variant>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), A::Error> {
+        let (seed, state) = (self.wrap(seed), self.state);
+        self.inner.variant_seed(seed).map(|(value, variant)| (value, Wrap { inner: variant, state }))
+    }
+}
+
+impl<'s, 'de, A: VariantAccess<'de>> VariantAccess<'de> for Wrap<'s, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.inner.unit_variant()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, A::Error> {
+        let seed = self.wrap(seed);
+        self.inner.newtype_variant_seed(seed)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        let visitor = self.wrap(visitor);
+        self.inner.tuple_variant(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, A::Error>
+    {
+        let visitor = self.wrap(visitor);
+        self.inner.struct_variant(fields, visitor)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize,Deserialize,Debug,PartialEq)]
+    enum Tree {
+        Leaf(u32),
+        Node(Vec<Tree>),
+    }
+
+    fn nested(depth: usize) -> Tree {
+        (0..depth).fold(Tree::Leaf(1), |tree, _| Tree::Node(vec![tree]))
+    }
+
+    fn decode(data: &[u8], max_depth: usize) -> Result<Tree, DepthError<bincode::Error>> {
+        let mut deserializer = bincode::Deserializer::from_slice(data, bincode::DefaultOptions::new());
+        deserialize(&mut deserializer, max_depth)
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        // each node nests an enum and a sequence
+        let data = bincode::DefaultOptions::new().serialize(&nested(10)).unwrap();
+        assert_eq!(decode(&data, 21).unwrap(), nested(10));
+        assert!(matches!(decode(&data, 20), Err(DepthError::Exceeded(DepthExceeded { max_depth: 20 }))));
+        assert!(matches!(decode(&data[..data.len()-1], 21), Err(DepthError::Other(_))));
+
+        // hostile payload: nodes of a single child, without any leaf
+        let node = bincode::DefaultOptions::new().serialize(&Tree::Node(vec![])).unwrap();
+        let mut data = Vec::new();
+        for _ in 0..100_000 {
+            data.extend_from_slice(&node[..node.len()-1]);
+            data.push(1);
+        }
+        let limit = DepthLimit::<Tree>::new(DEFAULT_MAX_DEPTH);
+        assert!(bincode::DefaultOptions::new().deserialize_seed(&limit, &data).is_err());
+        assert_eq!(limit.exceeded(), Some(DepthExceeded { max_depth: DEFAULT_MAX_DEPTH }));
+
+        let err = std::io::Error::from(limit.exceeded().unwrap());
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(crate::Error::from(limit.exceeded().unwrap()).kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,115 @@
+//! Hash function of fingerprints, content-addressed ids and receipts.
+//!
+//! The crate hashes data through `DefaultHasher` only, so that deployments
+//! with specific requirements can swap the algorithm in a single place.
+//! Hashes of a deployment's peers must be computed with the same
+//! algorithm: changing it changes objects' ids and identities'
+//! fingerprints, and invalidates existing receipts.
+//!
+//! SHA-256 is used by default, BLAKE3 with the `blake3-hash` feature.
+//! Other algorithms are provided by implementing `Hasher` for them, with
+//! 32 bytes outputs.
+use sha2::Digest;
+
+
+/// Output of a hasher.
+pub type Hash = [u8; 32];
+
+
+/// Incremental hash function.
+pub trait Hasher: Default {
+    /// Algorithm's name.
+    const NAME: &'static str;
+
+    /// Hash provided data.
+    fn update(&mut self, data: &[u8]);
+
+    /// Return hash of updated data.
+    fn finalize(self) -> Hash;
+
+    /// Return hash of provided data.
+    fn digest(data: &[u8]) -> Hash {
+        let mut hasher = Self::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+
+/// SHA-256 hasher.
+#[derive(Clone,Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Hasher for Sha256 {
+    const NAME: &'static str = "sha256";
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> Hash {
+        self.0.finalize().into()
+    }
+}
+
+
+/// BLAKE3 hasher.
+#[cfg(feature="blake3-hash")]
+#[derive(Clone,Default)]
+pub struct Blake3(blake3::Hasher);
+
+#[cfg(feature="blake3-hash")]
+impl Hasher for Blake3 {
+    const NAME: &'static str = "blake3";
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> Hash {
+        self.0.finalize().into()
+    }
+}
+
+
+/// Hasher used by the crate.
+#[cfg(not(feature="blake3-hash"))]
+pub type DefaultHasher = Sha256;
+
+/// Hasher used by the crate.
+#[cfg(feature="blake3-hash")]
+pub type DefaultHasher = Blake3;
+
+/// Return hash of provided data, using the default hasher.
+pub fn digest(data: &[u8]) -> Hash {
+    DefaultHasher::digest(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hash: Hash) -> String {
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(Sha256::digest(b"abc")),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let mut hasher = DefaultHasher::default();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(hasher.finalize(), digest(b"abc"));
+    }
+
+    #[cfg(feature="blake3-hash")]
+    #[test]
+    fn test_blake3() {
+        assert_eq!(hex(Blake3::digest(b"abc")),
+                   "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+        assert_eq!(DefaultHasher::NAME, "blake3");
+    }
+}
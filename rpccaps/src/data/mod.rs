@@ -1,12 +1,23 @@
+pub mod address;
 pub mod bytes;
+pub mod canonical;
 pub mod capability;
+pub mod clock;
+pub mod depth;
+pub mod hash;
+pub mod presentation;
 pub mod reference;
 pub mod signature;
+#[cfg(any(test, feature="testing"))]
+pub mod testing;
 pub mod validate;
 pub mod tls;
 
 
-pub use capability::Capability;
+pub use address::ObjectId;
+pub use capability::{Capability, CapabilitySet, WideCapability};
+pub use clock::{Clock,SystemClock};
+pub use presentation::{Presentation, ReferenceBundle};
 pub use reference::{Authorization,Reference};
 pub use self::signature::SignMethod;
 
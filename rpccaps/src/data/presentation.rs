@@ -0,0 +1,271 @@
+use serde::{Serialize,Deserialize,de::DeserializeOwned};
+use signature::{Signer,Verifier};
+
+use super::bytes;
+use super::canonical;
+use super::reference::{Error,Reference,TOKEN_VERSION};
+use super::signature as sign;
+use super::validate::Validate;
+
+
+/// Channel binding material of a connection.
+pub type ChannelBinding = [u8;32];
+
+
+/// Data signed by the presenter of a reference.
+#[derive(Serialize)]
+struct PresentationData<'a, R> {
+    binding: &'a [u8],
+    reference: &'a R,
+}
+
+
+/// Data signed by the presenter of a reference bundle.
+#[derive(Serialize)]
+struct BundleData<'a, R> {
+    binding: &'a [u8],
+    references: &'a [R],
+}
+
+
+/// A Presentation is a reference along with a proof-of-possession of its
+/// last subject's key.
+///
+/// The proof is a signature over the reference and the channel binding of
+/// the connection it is presented on. Thus, a presentation relayed or
+/// replayed over another connection fails validation.
+#[derive(Serialize,Deserialize,Clone)]
+pub struct Presentation<Id,Sign>
+    where Id: Clone, Sign: sign::SignMethod
+{
+    #[serde(bound="Id: Serialize+for<'d> Deserialize<'d>, Sign: sign::SignMethod+Serialize+for<'d> Deserialize<'d>")]
+    reference: Reference<Id,Sign>,
+    #[serde(with="bytes")]
+    signature: sign::Signature,
+}
+
+
+impl<Id,Sign> Presentation<Id,Sign>
+    where Id: Clone+Serialize, Sign: sign::SignMethod+Serialize
+{
+    /// Create a new presentation of `reference` for the provided channel
+    /// binding. `signer` must be the reference's last subject.
+    pub fn new(reference: Reference<Id,Sign>, signer: &Sign::Signer, binding: &ChannelBinding)
+        -> Result<Self,Error>
+    {
+        match (reference.last(), Sign::verifier(signer)) {
+            (None, _) => return Err(Error::Empty),
+            (Some(cert), Ok(verifier)) if &cert.auth.subject == verifier => (),
+            _ => return Err(Error::Subject),
+        }
+
+        let signature = Self::signed_data(&reference, binding)
+            .and_then(|buf| signer.try_sign(&buf).map_err(Error::Signature))?;
+        Ok(Self { reference, signature })
+    }
+
+    /// Return presented reference.
+    pub fn reference(&self) -> &Reference<Id,Sign> {
+        &self.reference
+    }
+
+    /// Return presented reference, consuming self.
+    pub fn into_reference(self) -> Reference<Id,Sign> {
+        self.reference
+    }
+
+    /// Return serialized data to sign.
+    fn signed_data(reference: &Reference<Id,Sign>, binding: &[u8]) -> Result<Vec<u8>,Error> {
+        canonical::serialize(&PresentationData { binding, reference })
+            .map_err(Error::Serialize)
+    }
+}
+
+
+impl<Id,Sign> Presentation<Id,Sign>
+    where Id: Clone+Serialize+DeserializeOwned, Sign: sign::SignMethod+Serialize+DeserializeOwned
+{
+    /// Return presentation as a text-safe token, as `Reference::to_token()`.
+    pub fn to_token(&self) -> Result<String,Error> {
+        let mut data = vec![TOKEN_VERSION];
+        canonical::serialize_into(&mut data, self).map_err(Error::Serialize)?;
+        Ok(base64::encode_config(&data, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Read presentation from a token returned by `to_token()`. The
+    /// presentation still has to be validated.
+    pub fn from_token(token: &str) -> Result<Self,Error> {
+        let data = base64::decode_config(token.trim(), base64::URL_SAFE_NO_PAD)
+                           .or(Err(Error::Token))?;
+        match data.split_first() {
+            Some((&TOKEN_VERSION, data)) => canonical::deserialize(data).map_err(Error::Serialize),
+            _ => Err(Error::Token),
+        }
+    }
+}
+
+
+/// Validate presentation against a channel binding: reference must be valid
+/// for its last subject, who must have signed the presentation.
+impl<Id,Sign> Validate for Presentation<Id,Sign>
+    where Id: Clone+Serialize, Sign: sign::SignMethod+Serialize
+{
+    type Error = Error;
+    type Context = ChannelBinding;
+
+    fn validate(&self, binding: &Self::Context) -> Result<(),Self::Error> {
+        let subject = match self.reference.last() {
+            Some(cert) => &cert.auth.subject,
+            None => return Err(Error::Empty),
+        };
+        self.reference.validate(subject)?;
+
+        let buf = Self::signed_data(&self.reference, binding)?;
+        subject.verify(&buf, &self.signature)
+               .map_err(Error::Signature)
+    }
+}
+
+
+/// A ReferenceBundle presents multiple references at once, with a single
+/// proof-of-possession covering all of them.
+///
+/// All references must have the same last subject, who signs the bundle
+/// along with the connection's channel binding (as for `Presentation`).
+#[derive(Serialize,Deserialize,Clone)]
+pub struct ReferenceBundle<Id,Sign>
+    where Id: Clone, Sign: sign::SignMethod
+{
+    #[serde(bound="Id: Serialize+for<'d> Deserialize<'d>, Sign: sign::SignMethod+Serialize+for<'d> Deserialize<'d>")]
+    references: Vec<Reference<Id,Sign>>,
+    #[serde(with="bytes")]
+    signature: sign::Signature,
+}
+
+
+impl<Id,Sign> ReferenceBundle<Id,Sign>
+    where Id: Clone+Serialize, Sign: sign::SignMethod+Serialize
+{
+    /// Create a new bundle of `references` for the provided channel binding.
+    /// `signer` must be the last subject of all references.
+    pub fn new(references: Vec<Reference<Id,Sign>>, signer: &Sign::Signer, binding: &ChannelBinding)
+        -> Result<Self,Error>
+    {
+        let verifier = Sign::verifier(signer).or(Err(Error::Subject))?;
+        match Self::subject(&references)? {
+            subject if subject == verifier => (),
+            _ => return Err(Error::Subject),
+        }
+
+        let signature = Self::signed_data(&references, binding)
+            .and_then(|buf| signer.try_sign(&buf).map_err(Error::Signature))?;
+        Ok(Self { references, signature })
+    }
+
+    /// Return presented references.
+    pub fn references(&self) -> &[Reference<Id,Sign>] {
+        &self.references
+    }
+
+    /// Return presented references, consuming self.
+    pub fn into_references(self) -> Vec<Reference<Id,Sign>> {
+        self.references
+    }
+
+    /// Return the last subject shared by all references.
+    fn subject(references: &[Reference<Id,Sign>]) -> Result<&Sign::Verifier,Error> {
+        let mut subjects = references.iter().map(|r| r.last().map(|cert| &cert.auth.subject));
+        let subject = match subjects.next() {
+            Some(Some(subject)) => subject,
+            _ => return Err(Error::Empty),
+        };
+        match subjects.all(|s| s == Some(subject)) {
+            true => Ok(subject),
+            false => Err(Error::Subject),
+        }
+    }
+
+    /// Return serialized data to sign.
+    fn signed_data(references: &[Reference<Id,Sign>], binding: &[u8]) -> Result<Vec<u8>,Error> {
+        canonical::serialize(&BundleData { binding, references })
+            .map_err(Error::Serialize)
+    }
+}
+
+
+/// Validate bundle against a channel binding: all references must be valid
+/// for their shared last subject, who must have signed the bundle.
+impl<Id,Sign> Validate for ReferenceBundle<Id,Sign>
+    where Id: Clone+Serialize, Sign: sign::SignMethod+Serialize
+{
+    type Error = Error;
+    type Context = ChannelBinding;
+
+    fn validate(&self, binding: &Self::Context) -> Result<(),Self::Error> {
+        let subject = Self::subject(&self.references)?;
+        for reference in self.references.iter() {
+            reference.validate(subject)?;
+        }
+
+        let buf = Self::signed_data(&self.references, binding)?;
+        subject.verify(&buf, &self.signature)
+               .map_err(Error::Signature)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::expect;
+    use super::super::capability::Capability;
+    use super::super::reference::Authorization;
+    use super::super::signature::Dalek;
+    use super::super::testing::Chain;
+    use super::*;
+
+    #[test]
+    fn test_presentation() {
+        let cap = Capability::new(0b1111, 0b1111);
+        let chain = Chain::<Dalek>::new(0, 0, 64, cap);
+        let binding = [1u8;32];
+
+        let presentation = Presentation::new(chain.reference.clone(), &chain.signer(1), &binding)
+                                .unwrap();
+        expect!(presentation.validate(&binding), Ok(_));
+        expect!(presentation.validate(&[2u8;32]), Err(Error::Signature(_)));
+
+        let token = presentation.to_token().unwrap();
+        let presentation = Presentation::<u64,Dalek>::from_token(&token).unwrap();
+        expect!(presentation.validate(&binding), Ok(_));
+        assert!(Presentation::<u64,Dalek>::from_token(&chain.to_token().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_presentation_err_subject() {
+        let cap = Capability::new(0b1111, 0b1111);
+        let chain = Chain::<Dalek>::new(0, 0, 64, cap);
+
+        match Presentation::new(chain.reference.clone(), &chain.signer(2), &[0u8;32]) {
+            Err(Error::Subject) => (),
+            _ => panic!("presentation signed by another subject than reference's one"),
+        }
+    }
+
+    #[test]
+    fn test_bundle() {
+        let cap = Capability::new(0b1111, 0b1111);
+        let chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
+        let auth = Authorization::new(cap, chain.verifier(1));
+        let other = Reference::<u64,Dalek>::new(1u64, &chain.issuer(), 64, auth).unwrap();
+        let references = vec![chain.reference.clone(), other];
+        let binding = [1u8;32];
+
+        let bundle = ReferenceBundle::new(references.clone(), &chain.signer(1), &binding).unwrap();
+        expect!(bundle.validate(&binding), Ok(_));
+        expect!(bundle.validate(&[2u8;32]), Err(Error::Signature(_)));
+        assert!(matches!(ReferenceBundle::new(references, &chain.signer(2), &binding),
+                         Err(Error::Subject)));
+        assert!(matches!(ReferenceBundle::<u64,Dalek>::new(Vec::new(), &chain.signer(1), &binding),
+                         Err(Error::Empty)));
+    }
+}
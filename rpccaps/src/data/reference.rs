@@ -1,85 +1,123 @@
 use std::fmt;
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use bincode;
-use serde::{Serialize,Deserialize};
+use serde::{Serialize,Deserialize,de::DeserializeOwned};
 use signature::{Signer,Verifier};
 
 use super::bytes::{self as bytes};
+use super::canonical;
 use super::validate::Validate;
-use super::capability::Capability;
+use super::capability::{Capability, CapabilitySet};
+use super::clock::{Clock,SystemClock};
 use super::signature as sign;
 
 
 #[derive(Debug)]
 pub enum Error {
     Empty, Capability, Issuer, Subject, MaxShare,
+    /// Authorization's validity is not within its issuer's one.
+    Validity,
+    /// Authorization is expired or not yet valid.
+    Expired,
     Serialize(bincode::Error),
     Signature(sign::Error),
+    /// Token is malformed or of an unsupported version.
+    Token,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        f.write_str(&format!("{}", self))
+        match self {
+            Self::Empty => f.write_str("reference has no certificate"),
+            Self::Capability => f.write_str("capability is not a subset of the issuer's shareable one"),
+            Self::Issuer => f.write_str("issuer is not the subject of the last certificate"),
+            Self::Subject => f.write_str("subject is not the one of the last certificate"),
+            Self::MaxShare => f.write_str("maximum count of delegations is reached"),
+            Self::Validity => f.write_str("validity is not within the one of the issuer's authorization"),
+            Self::Expired => f.write_str("authorization is expired or not yet valid"),
+            Self::Serialize(err) => write!(f, "can not serialize certificate: {}", err),
+            Self::Signature(err) => write!(f, "invalid signature: {}", err),
+            Self::Token => f.write_str("malformed token"),
+        }
     }
 }
 
 
+/// Version of references' token format.
+pub const TOKEN_VERSION: u8 = 1;
+
 
 /// A Reference is the combination of an object reference (as id) and authorizations chain.
 ///
-/// It implements various utilities to sign and validate it.
+/// It implements various utilities to sign and validate it. Authorizations'
+/// capability is a `Capability` by default, or any other `CapabilitySet`
+/// such as a `WideCapability`.
 #[derive(Serialize,Deserialize,PartialEq,Clone)]
-pub struct Reference<Id,Sign>
+pub struct Reference<Id,Sign,Cap=Capability>
     where Id: Clone, Sign: sign::SignMethod
 {
     id: Id,
     #[serde(with="bytes")]
     issuer: Sign::Verifier,
     max_share: u32,
-    certs: Vec<Certificate<Sign>>,
+    certs: Vec<Certificate<Sign,Cap>>,
     phantom: PhantomData<Sign>,
 }
 
 
+/// Delegation of a reference's chain, as yielded by `Reference::chain()`:
+/// issuer, subject, delegated capability, and whether the certificate is
+/// valid: signed by the issuer, and within the previous one.
+pub type Hop<'a,Sign,Cap> = (&'a <Sign as sign::SignMethod>::Verifier, &'a <Sign as sign::SignMethod>::Verifier,
+                             &'a Cap, bool);
+
+
 #[derive(Serialize,Deserialize,PartialEq,Clone)]
-pub struct Certificate<Sign>
+pub struct Certificate<Sign,Cap=Capability>
     where Sign: sign::SignMethod
 {
-    #[serde(bound="Sign: sign::SignMethod")]
-    pub auth: Authorization<Sign>,
+    #[serde(bound(serialize="Sign: sign::SignMethod, Cap: Serialize",
+                  deserialize="Sign: sign::SignMethod, Cap: Deserialize<'de>"))]
+    pub auth: Authorization<Sign,Cap>,
     #[serde(with="bytes")]
     pub signature: sign::Signature,
 }
 
 
 #[derive(Serialize,Deserialize,PartialEq,Clone)]
-pub struct Authorization<Sign>
+pub struct Authorization<Sign,Cap=Capability>
     where Sign: sign::SignMethod
 {
-    pub capability: Capability,
+    pub capability: Cap,
     #[serde(with="bytes")]
     pub subject: Sign::Verifier,
-    // TODO: timeout, FIXME: capability namespaces
+    /// Timestamp (in seconds) before which authorization is not valid.
+    pub not_before: Option<u64>,
+    /// Timestamp (in seconds) from which authorization is expired.
+    pub expires: Option<u64>,
+    // FIXME: capability namespaces
 }
 
 
+/// Data signed by a certificate, serialized using `canonical` profile.
 #[derive(Serialize,Deserialize,PartialEq,Clone)]
-pub enum CertData<Id, Sign>
+pub enum CertData<Id, Sign, Cap=Capability>
     where Sign: sign::SignMethod
 {
-    #[serde(bound(serialize="Sign: sign::SignMethod, Id: Serialize"))]
-    Reference(Authorization<Sign>, Id, #[serde(with="bytes")] Sign::Verifier, u32),
-    #[serde(bound(serialize="Sign: sign::SignMethod, Id: Serialize"))]
-    Signature(Authorization<Sign>, #[serde(with="bytes")] sign::Signature),
+    #[serde(bound(serialize="Sign: sign::SignMethod, Id: Serialize, Cap: Serialize"))]
+    Reference(Authorization<Sign,Cap>, Id, #[serde(with="bytes")] Sign::Verifier, u32),
+    #[serde(bound(serialize="Sign: sign::SignMethod, Id: Serialize, Cap: Serialize"))]
+    Signature(Authorization<Sign,Cap>, #[serde(with="bytes")] sign::Signature),
 }
 
 
-impl<Id,Sign> Reference<Id,Sign>
-    where Id: Clone+Serialize, Sign: sign::SignMethod
+impl<Id,Sign,Cap> Reference<Id,Sign,Cap>
+    where Id: Clone+Serialize, Sign: sign::SignMethod, Cap: CapabilitySet+Serialize
 {
     /// Create a new reference, signing it with the provided keys.
-    pub fn new(id: Id, issuer: &Sign::Signer, max_share: u32, auth: Authorization<Sign>)
+    pub fn new(id: Id, issuer: &Sign::Signer, max_share: u32, auth: Authorization<Sign,Cap>)
         -> Result<Self,Error>
     {
         match Sign::verifier(&issuer) {
@@ -105,21 +143,53 @@ impl<Id,Sign> Reference<Id,Sign>
         &self.issuer
     }
 
+    /// Return maximum count of delegations.
+    pub fn max_share(&self) -> u32 {
+        self.max_share
+    }
+
     /// Return authorizations of the reference.
-    pub fn certs(&self) -> &Vec<Certificate<Sign>> {
+    pub fn certs(&self) -> &Vec<Certificate<Sign,Cap>> {
         &self.certs
     }
 
     /// Return last certificate
-    pub fn last(&self) -> Option<&Certificate<Sign>> {
+    pub fn last(&self) -> Option<&Certificate<Sign,Cap>> {
         self.certs.last()
     }
 
+    /// Return count of delegations, 0 for a reference that has not been
+    /// delegated.
+    pub fn depth(&self) -> usize {
+        self.certs.len().saturating_sub(1)
+    }
+
+    /// Iterate over the delegations of the chain, from the reference's
+    /// issuer to its last subject. Unlike validation, the walk does not
+    /// stop at the first invalid certificate, so that a chain can be
+    /// audited as a whole.
+    pub fn chain(&self) -> impl Iterator<Item=Hop<'_,Sign,Cap>> {
+        let issuers = std::iter::once(&self.issuer).chain(self.certs.iter().map(|cert| &cert.auth.subject));
+        self.certs.iter().enumerate().zip(issuers).map(move |((index, cert), issuer)| {
+            let previous = index.checked_sub(1).map(|index| &self.certs[index]);
+            let valid = self.verify_cert(&mut Vec::new(), issuer, cert, previous).is_ok();
+            (issuer, &cert.auth.subject, &cert.auth.capability, valid)
+        })
+    }
+
+    /// Return capability actually granted to the last subject: each
+    /// delegation only grants what the previous one can share.
+    pub fn effective_capability(&self) -> Option<Cap> {
+        let mut certs = self.certs.iter();
+        let first = certs.next()?.auth.capability.clone();
+        Some(certs.fold(first, |capability, cert| capability.delegable().intersection(&cert.auth.capability)))
+    }
+
     /// Return cert data for provided signer, authorization and last
     /// certificate. Return Error on data validation fails.
-    fn cert_data(&self, issuer: &Sign::Verifier, auth: Authorization<Sign>,
-                 last: Option<&Certificate<Sign>>)
-        -> Result<CertData<Id,Sign>,Error>
+    fn cert_data(&self, issuer: &Sign::Verifier, auth: Authorization<Sign,Cap>,
+                 last: Option<&Certificate<Sign,Cap>>)
+        -> Result<CertData<Id,Sign,Cap>,Error>
     {
        match last {
             None => Ok(CertData::Reference(auth, self.id.clone(), self.issuer.clone(), self.max_share)),
@@ -132,13 +202,17 @@ impl<Id,Sign> Reference<Id,Sign>
                 if issuer != &last.auth.subject {
                     return Err(Error::Issuer);
                 }
+                // test: auth validity must be within last auth's one
+                if !auth.is_within(&last.auth) {
+                    return Err(Error::Validity);
+                }
                 Ok(CertData::Signature(auth, last.signature))
             }
         }
     }
 
     /// Add a new signature to the reference.
-    pub fn sign(&mut self, issuer: &Sign::Signer, auth: Authorization<Sign>) -> Result<(), Error> {
+    pub fn sign(&mut self, issuer: &Sign::Signer, auth: Authorization<Sign,Cap>) -> Result<(), Error> {
         if self.certs.len() >= (self.max_share as usize)+1 {
             return Err(Error::MaxShare);
         }
@@ -146,14 +220,10 @@ impl<Id,Sign> Reference<Id,Sign>
         let cert_data = self.cert_data(&Sign::verifier(&issuer).unwrap(), auth.clone(),
                                        self.certs.last());
         match cert_data {
-            Ok(cert_data) => bincode::serialize(&cert_data)
-                .or_else(|err| Err(Error::Serialize(err)))
-                .and_then(|buf| issuer.try_sign(&buf)
-                                      .or_else(|err| Err(Error::Signature(err))))
-                .and_then(|signature| {
-                    self.certs.push(Certificate { auth, signature });
-                    Ok(())
-                }),
+            Ok(cert_data) => canonical::serialize(&cert_data)
+                .map_err(Error::Serialize)
+                .and_then(|buf| issuer.try_sign(&buf).map_err(Error::Signature))
+                .map(|signature| self.certs.push(Certificate { auth, signature })),
             Err(err) => Err(err),
         }
     }
@@ -170,6 +240,17 @@ impl<Id,Sign> Reference<Id,Sign>
             }))
     }
 
+    /// Start the delegation of the reference to `subject`, signed by the
+    /// subject of its last certificate:
+    ///
+    /// ```ignore
+    /// reference.delegate_to(subject).with_capability(cap)
+    ///          .expires_in(Duration::from_secs(3600)).sign(&signer)?;
+    /// ```
+    pub fn delegate_to(&mut self, subject: Sign::Verifier) -> Delegation<'_,Id,Sign,Cap> {
+        Delegation { reference: self, subject, capability: None, not_before: None, expires: None }
+    }
+
     /// Shorten the authorizations' chain for the provided subject, signing it in
     /// a new reference.
     pub fn shrink(&self, signer: &Sign::Signer, subject: &Sign::Verifier) -> Option<Self> {
@@ -184,14 +265,108 @@ impl<Id,Sign> Reference<Id,Sign>
     }
 }
 
-/// Validation is tested agains't last user's public-key
-impl<Id,Sign> Validate for Reference<Id,Sign>
-    where Id: Clone+Serialize, Sign: sign::SignMethod
+/// Delegation of a reference to a subject, built by `Reference::delegate_to`.
+///
+/// Capability defaults to the actions delegable by the issuer, sharing
+/// none of them, and validity to the issuer's authorization's one.
+pub struct Delegation<'a,Id,Sign,Cap=Capability>
+    where Id: Clone, Sign: sign::SignMethod
 {
-    type Error = Error;
-    type Context = Sign::Verifier;
+    reference: &'a mut Reference<Id,Sign,Cap>,
+    subject: Sign::Verifier,
+    capability: Option<Cap>,
+    not_before: Option<u64>,
+    expires: Option<u64>,
+}
 
-    fn validate(&self, subject: &Self::Context) -> Result<(),Self::Error> {
+impl<'a,Id,Sign,Cap> Delegation<'a,Id,Sign,Cap>
+    where Id: Clone+Serialize, Sign: sign::SignMethod, Cap: CapabilitySet+Serialize
+{
+    /// Delegate provided capability.
+    pub fn with_capability(mut self, capability: Cap) -> Self {
+        self.capability = Some(capability);
+        self
+    }
+
+    /// Set timestamp (in seconds) before which delegation is not valid.
+    pub fn not_before(mut self, timestamp: u64) -> Self {
+        self.not_before = Some(timestamp);
+        self
+    }
+
+    /// Set timestamp (in seconds) from which delegation is expired.
+    pub fn expires_at(mut self, timestamp: u64) -> Self {
+        self.expires = Some(timestamp);
+        self
+    }
+
+    /// Expire delegation after provided duration from now.
+    pub fn expires_in(self, duration: Duration) -> Self {
+        self.expires_in_with(duration, &SystemClock)
+    }
+
+    /// Expire delegation after provided duration from the time of `clock`.
+    pub fn expires_in_with<C: Clock>(self, duration: Duration, clock: &C) -> Self {
+        let expires = (clock.now() + duration).as_secs();
+        self.expires_at(expires)
+    }
+
+    /// Return delegated authorization, checking it against the issuer's
+    /// one.
+    pub fn authorization(&self, issuer: &Sign::Verifier) -> Result<Authorization<Sign,Cap>, Error> {
+        let last = match self.reference.last() {
+            Some(last) => last,
+            None => return Err(Error::Empty),
+        };
+        if self.reference.certs.len() > self.reference.max_share as usize {
+            return Err(Error::MaxShare);
+        }
+        if issuer != &last.auth.subject {
+            return Err(Error::Issuer);
+        }
+        let capability = self.capability.clone().unwrap_or_else(
+            || last.auth.capability.delegable().intersection(&Cap::all()));
+        if !capability.is_subset(&last.auth.capability) {
+            return Err(Error::Capability);
+        }
+        let auth = Authorization {
+            capability, subject: self.subject.clone(),
+            not_before: self.not_before.or(last.auth.not_before),
+            expires: self.expires.or(last.auth.expires),
+        };
+        match auth.is_within(&last.auth) {
+            true => Ok(auth),
+            false => Err(Error::Validity),
+        }
+    }
+
+    /// Sign delegation with issuer's keys, adding it to the reference.
+    pub fn sign(self, signer: &Sign::Signer) -> Result<(), Error> {
+        let issuer = Sign::verifier(signer).or(Err(Error::Issuer))?;
+        let auth = self.authorization(issuer)?;
+        self.reference.sign(signer, auth)
+    }
+}
+
+impl<Id,Sign,Cap> Reference<Id,Sign,Cap>
+    where Id: Clone+Serialize, Sign: sign::SignMethod, Cap: CapabilitySet+Serialize
+{
+    /// Validate reference for provided subject at `now` (timestamp in
+    /// seconds): all authorizations of the chain must be valid at this time.
+    pub fn validate_at(&self, subject: &Sign::Verifier, now: u64) -> Result<(),Error> {
+        if self.certs.iter().any(|cert| !cert.auth.is_valid_at(now)) {
+            return Err(Error::Expired);
+        }
+        self.validate_chain(subject)
+    }
+
+    /// Validate reference for provided subject using clock's time.
+    pub fn validate_with<C: Clock>(&self, subject: &Sign::Verifier, clock: &C) -> Result<(),Error> {
+        self.validate_at(subject, clock.timestamp())
+    }
+
+    /// Validate chain of certificates, regardless of time.
+    fn validate_chain(&self, subject: &Sign::Verifier) -> Result<(),Error> {
         // Max share count
         if self.certs.len() > (self.max_share as usize)+1 {
             return Err(Error::MaxShare);
@@ -209,195 +384,345 @@ impl<Id,Sign> Validate for Reference<Id,Sign>
         // Check certificates
         let mut buf = Vec::new();
         let mut issuer = &self.issuer;
-        let mut last: Option<&Certificate<Sign>> = None;
+        let mut last: Option<&Certificate<Sign,Cap>> = None;
 
         for cert in self.certs.iter() {
-            match self.cert_data(issuer, cert.auth.clone(), last) {
-                Ok(cert_data) => {
-                    buf.clear();
-                    if let Err(err) = bincode::serialize_into(&mut buf, &cert_data) {
-                        return Err(Error::Serialize(err))
-                    }
-
-                    if let Err(err) = issuer.verify(&buf, &cert.signature) {
-                        return Err(Error::Signature(err))
-                    }
-
-                    issuer = &cert.auth.subject;
-                    last = Some(&cert);
-                },
-                Err(err) => return Err(err),
-            };
+            self.verify_cert(&mut buf, issuer, cert, last)?;
+            issuer = &cert.auth.subject;
+            last = Some(cert);
         }
         Ok(())
     }
-}
-
 
+    /// Verify that certificate, following `last` one, is signed by `issuer`
+    /// and within the last one, using `buf` to serialize its data.
+    fn verify_cert(&self, buf: &mut Vec<u8>, issuer: &Sign::Verifier, cert: &Certificate<Sign,Cap>,
+                   last: Option<&Certificate<Sign,Cap>>) -> Result<(),Error>
+    {
+        let cert_data = self.cert_data(issuer, cert.auth.clone(), last)?;
+        buf.clear();
+        canonical::serialize_into(buf, &cert_data).map_err(Error::Serialize)?;
+        issuer.verify(buf, &cert.signature).map_err(Error::Signature)
+    }
+}
 
-impl<Sign> Authorization<Sign>
-    where Sign: sign::SignMethod
+impl<Id,Sign,Cap> Reference<Id,Sign,Cap>
+    where Id: Clone+Serialize+DeserializeOwned, Sign: sign::SignMethod+Serialize+DeserializeOwned,
+          Cap: CapabilitySet+Serialize+DeserializeOwned
 {
-    pub fn new(capability: Capability, subject: Sign::Verifier) -> Self {
-        Self { capability, subject }
+    /// Return reference as a text-safe token (for HTTP headers, QR codes,
+    /// configuration files...): `TOKEN_VERSION` followed by its canonical
+    /// serialization, encoded in unpadded base64url.
+    pub fn to_token(&self) -> Result<String, Error> {
+        let mut data = vec![TOKEN_VERSION];
+        canonical::serialize_into(&mut data, self).map_err(Error::Serialize)?;
+        Ok(base64::encode_config(&data, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Read reference from a token returned by `to_token()`. The reference
+    /// still has to be validated.
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        let data = base64::decode_config(token.trim(), base64::URL_SAFE_NO_PAD)
+                           .or(Err(Error::Token))?;
+        match data.split_first() {
+            Some((&TOKEN_VERSION, data)) => canonical::deserialize(data).map_err(Error::Serialize),
+            _ => Err(Error::Token),
+        }
     }
 }
 
+/// Validation is tested agains't last user's public-key, at system's time.
+impl<Id,Sign,Cap> Validate for Reference<Id,Sign,Cap>
+    where Id: Clone+Serialize, Sign: sign::SignMethod, Cap: CapabilitySet+Serialize
+{
+    type Error = Error;
+    type Context = Sign::Verifier;
 
-#[cfg(test)]
-pub mod tests {
-    use std::ops::{Deref,DerefMut};
+    fn validate(&self, subject: &Self::Context) -> Result<(),Self::Error> {
+        self.validate_with(subject, &SystemClock)
+    }
+}
 
-    use crate::expect;
-    use super::super::signature::{Dalek,SignMethod};
-    use super::*;
 
 
-    pub struct TestReference<Sign: SignMethod> {
-        pub signers: Vec<Sign::Signer>,
-        pub public_keys: Vec<Sign::Verifier>,
-        pub reference: Reference<u64,Sign>,
+impl<Sign,Cap> Authorization<Sign,Cap>
+    where Sign: sign::SignMethod
+{
+    pub fn new(capability: Cap, subject: Sign::Verifier) -> Self {
+        Self { capability, subject, not_before: None, expires: None }
     }
 
-    impl<Sign: SignMethod> Deref for TestReference<Sign> {
-        type Target = Reference<u64,Sign>;
-
-        fn deref(&self) -> &Self::Target {
-            &self.reference
-        }
+    /// Set validity period (timestamps in seconds).
+    pub fn with_validity(mut self, not_before: Option<u64>, expires: Option<u64>) -> Self {
+        self.not_before = not_before;
+        self.expires = expires;
+        self
     }
 
-    impl<Sign: SignMethod> DerefMut for TestReference<Sign> {
-        fn deref_mut(&mut self) -> &mut Self::Target {
-            &mut self.reference
-        }
+    /// Return true if authorization is valid at provided timestamp.
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.not_before.is_none_or(|t| t <= now) && self.expires.is_none_or(|t| now < t)
     }
 
-    impl TestReference<Dalek> {
-        pub fn new(max_share: u32, cap: Capability) -> Self {
-            let signers = (0..10)
-                .map(|_| Dalek::generate().unwrap())
-                .collect::<Vec<_>>();
-            let public_keys = signers.iter().map(|s| s.public)
-                .collect::<Vec<_>>();
-
-            let auth = Authorization::new(cap, public_keys[1].clone());
-            let reference = Reference::<u64,Dalek>::new(0u64, &signers[0], max_share, auth)
-                                .expect("can not create reference");
-
-            Self { signers, public_keys, reference }
-        }
-
-        pub fn sign(&mut self, signer: usize, capability: Capability) -> Result<(),Error>
-        {
-            if signer+1 >= self.signers.len() {
-                panic!("signer invalid")
-            }
+    /// Return true if validity period is within the provided one's.
+    pub fn is_within(&self, auth: &Self) -> bool {
+        let not_before = match (self.not_before, auth.not_before) {
+            (_, None) => true,
+            (Some(a), Some(b)) => a >= b,
+            (None, Some(_)) => false,
+        };
+        let expires = match (self.expires, auth.expires) {
+            (_, None) => true,
+            (Some(a), Some(b)) => a <= b,
+            (None, Some(_)) => false,
+        };
+        not_before && expires
+    }
+}
 
-            let auth = Authorization::new(capability, self.public_keys[signer+1].clone());
-            self.reference.sign(&self.signers[signer], auth)
-        }
 
-        pub fn sign_n(&mut self, last: Option<usize>, mut capability: Capability) -> Result<(), (usize,Error)> {
-            let last = last.unwrap_or_else(|| self.signers.len()-1);
-            for i in 1..last {
-                capability.actions >>= 1;
-                if let Err(err) = self.sign(i, capability.clone()) {
-                    return Err((i, err));
-                }
-            }
-            Ok(())
-        }
+#[cfg(test)]
+mod tests {
+    use crate::expect;
+    use super::super::signature::{Dalek,SignMethod};
+    use super::super::testing::Chain;
+    use super::super::clock::MockClock;
+    use super::*;
 
-        pub fn validate(&self, subject: Option<usize>) -> Result<(), Error> {
-            let subject = subject.unwrap_or_else(|| self.public_keys.len()-1);
-            self.reference.validate(&self.public_keys[subject])
+    /// Delegate `count` times from the holder, halving actions at each
+    /// delegation.
+    fn delegate_shrinking(chain: &mut Chain<Dalek>, count: usize, mut capability: Capability)
+        -> Result<(), (usize, Error)>
+    {
+        for i in 0..count {
+            capability.actions >>= 1;
+            chain.delegate(capability.clone()).map_err(|err| (i, err))?;
         }
+        Ok(())
     }
 
     #[test]
     fn test_sign_ok() {
         let cap = Capability::new(0b11111111, 0b11111111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        expect!(test.sign_n(None, cap), Ok(_));
-        expect!(test.validate(None), Ok(_));
+        expect!(delegate_shrinking(&mut chain, 8, cap), Ok(_));
+        expect!(chain.validate(), Ok(_));
+    }
+
+    #[test]
+    fn test_token() {
+        let cap = Capability::new(0b11111111, 0b11111111);
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
+        expect!(delegate_shrinking(&mut chain, 8, cap), Ok(_));
+
+        let token = chain.to_token().unwrap();
+        assert!(token.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_'));
+        let reference = Reference::<u64,Dalek>::from_token(&token).unwrap();
+        assert_eq!(reference.to_token().unwrap(), token);
+        expect!(reference.validate(&chain.verifier(9)), Ok(_));
+
+        let mut data = base64::decode_config(&token, base64::URL_SAFE_NO_PAD).unwrap();
+        data[0] = TOKEN_VERSION + 1;
+        let token = base64::encode_config(&data, base64::URL_SAFE_NO_PAD);
+        assert!(matches!(Reference::<u64,Dalek>::from_token(&token), Err(Error::Token)));
+        assert!(matches!(Reference::<u64,Dalek>::from_token("not a token"), Err(Error::Token)));
+        assert!(Reference::<u64,Dalek>::from_token(&token[..token.len()-4]).is_err());
     }
 
     #[test]
     fn test_sign_err() {
         let cap = Capability::new(0b11111111, 0b00000000);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        expect!(test.sign(1, cap.clone()), Err(Error::Capability));
-        expect!(test.sign(2, cap.clone()), Err(Error::Capability));
+        expect!(chain.sign(1, cap.clone()), Err(Error::Capability));
+        expect!(chain.sign(2, cap.clone()), Err(Error::Capability));
     }
 
     #[test]
     fn test_sign_max_share() {
         let cap = Capability::new(0b11111111, 0b11111111);
-        let mut test = TestReference::<Dalek>::new(0, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 0, cap.clone());
+
+        expect!(delegate_shrinking(&mut chain, 8, cap), Err((_, Error::MaxShare)));
+        // TODO expect!(chain.validate(), Ok(_));
+    }
 
-        expect!(test.sign_n(None, cap), Err((_, Error::MaxShare)));
-        // TODO expect!(test.validate(None), Ok(_));
+    #[test]
+    fn test_delegate_to() {
+        let cap = Capability::new(0b1111, 0b0011);
+        let mut chain = Chain::<Dalek>::new(0, 0, 1, cap.clone());
+        let (signer, subject, other) = (chain.signer(1), chain.verifier(2), chain.signer(2));
+
+        // checked before signing
+        expect!(chain.reference.delegate_to(subject).sign(&other), Err(Error::Issuer));
+        expect!(chain.reference.delegate_to(subject).with_capability(cap.clone()).sign(&signer),
+                Err(Error::Capability));
+        chain.reference.certs[0].auth.expires = Some(1000);
+        expect!(chain.reference.delegate_to(subject).expires_at(2000).sign(&signer),
+                Err(Error::Validity));
+        assert_eq!(chain.certs.len(), 1);
+
+        let clock = MockClock::new(Duration::from_secs(100));
+        expect!(chain.reference.delegate_to(subject).expires_in_with(Duration::from_secs(901), &clock)
+                     .sign(&signer), Err(Error::Validity));
+        assert_eq!(chain.certs.len(), 1);
+
+        // defaults to delegable actions, sharing none, and issuer's validity
+        chain.reference.delegate_to(subject).not_before(10).sign(&signer).unwrap();
+        let auth = &chain.last().unwrap().auth;
+        assert_eq!(auth.capability, Capability::new(0b0011, 0));
+        assert_eq!((auth.not_before, auth.expires), (Some(10), Some(1000)));
+        chain.reference.certs.pop();
+
+        chain.reference.delegate_to(subject).expires_in_with(Duration::from_secs(900), &clock)
+             .sign(&signer).unwrap();
+        assert_eq!(chain.last().unwrap().auth.expires, Some(1000));
+
+        let subject = chain.verifier(3);
+        expect!(chain.reference.delegate_to(subject).sign(&other), Err(Error::MaxShare));
+        assert_eq!(Error::MaxShare.to_string(), "maximum count of delegations is reached");
+    }
+
+    #[test]
+    fn test_chain() {
+        let cap = Capability::new(0b11111111, 0b00111111);
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
+        assert_eq!(chain.depth(), 0);
+        chain.sign(1, Capability::new(0b00111100, 0b00001100)).unwrap();
+        chain.sign(2, Capability::new(0b00001100, 0b00000100)).unwrap();
+        assert_eq!(chain.depth(), 2);
+        assert_eq!(chain.effective_capability(), Some(Capability::new(0b00001100, 0b00000100)));
+
+        // poisoned signature is reported
+        chain.reference.certs[2].signature = chain.reference.certs[1].signature;
+        let hops = chain.chain()
+                        .map(|(issuer, subject, capability, valid)| (*issuer, *subject, capability.clone(), valid))
+                        .collect::<Vec<_>>();
+        assert_eq!(hops.len(), 3);
+        assert_eq!((hops[0].0, hops[0].1), (chain.issuer().public, chain.verifier(1)));
+        assert_eq!((hops[2].0, hops[2].1), (chain.verifier(2), chain.verifier(3)));
+        assert_eq!(hops.iter().map(|hop| hop.3).collect::<Vec<_>>(), [true, true, false]);
+        assert_eq!(hops[1].2, Capability::new(0b00111100, 0b00001100));
+
+        // actions that could not be shared are not granted
+        chain.reference.certs[2].auth.capability = Capability::new(0b00111111, 0b00001111);
+        assert_eq!(chain.effective_capability(), Some(Capability::new(0b00001100, 0b00001100)));
+        assert!(!chain.chain().nth(2).unwrap().3);
     }
 
     #[test]
     fn test_validate_err_auth() {
         let cap = Capability::new(0b11111111, 0b11111111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        expect!(test.sign_n(None, cap), Ok(_));
-        expect!(test.validate(None), Ok(_));
+        expect!(delegate_shrinking(&mut chain, 8, cap), Ok(_));
+        expect!(chain.validate(), Ok(_));
 
-        let auth = test.certs.remove(5);
-        expect!(test.validate(Some(test.certs.len())), Err(_));
+        let auth = chain.certs.remove(5);
+        expect!(chain.reference.validate(&chain.verifier(chain.certs.len())), Err(_));
 
-        test.certs.push(auth);
-        expect!(test.validate(None), Err(_));
+        chain.certs.push(auth);
+        expect!(chain.validate(), Err(_));
     }
 
     #[test]
     fn test_validate_err_subject() {
         let cap = Capability::new(0b11111111, 0b00001111);
-        let test = TestReference::<Dalek>::new(64, cap.clone());
+        let chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        expect!(test.validate(Some(2)), Err(Error::Subject));
+        expect!(chain.reference.validate(&chain.verifier(2)), Err(Error::Subject));
     }
 
     #[test]
     fn test_validate_err_cap() {
         let cap = Capability::new(0b11111111, 0b00001111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        test.sign(1, cap.subset(cap.actions >> 1, cap.share)).unwrap();
-        test.reference.certs.get_mut(1).unwrap().auth.capability.actions = cap.actions;
-        expect!(test.validate(Some(2)), Err(Error::Capability));
+        chain.sign(1, cap.subset(cap.actions >> 1, cap.share)).unwrap();
+        chain.reference.certs.get_mut(1).unwrap().auth.capability.actions = cap.actions;
+        expect!(chain.validate(), Err(Error::Capability));
     }
 
     #[test]
     fn test_validate_err_sign() {
         let cap = Capability::new(0b11111111, 0b00001111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        test.sign(1, cap.subset(cap.actions >> 1, cap.share)).unwrap();
+        chain.sign(1, cap.subset(cap.actions >> 1, cap.share)).unwrap();
 
         // signature poisoning
-        let sig = test.reference.certs.get(0).unwrap().signature.clone();
-        test.reference.certs.get_mut(1).unwrap().signature = sig;
+        let sig = chain.reference.certs.first().unwrap().signature;
+        chain.reference.certs.get_mut(1).unwrap().signature = sig;
 
-        expect!(test.validate(Some(2)), Err(Error::Signature(_)));
+        expect!(chain.validate(), Err(Error::Signature(_)));
+    }
+
+    #[test]
+    fn test_wide_capability() {
+        use super::super::capability::WideCapability;
+        type Wide = WideCapability<2>;
+
+        let signers = (0..3).map(|_| Dalek::generate().unwrap()).collect::<Vec<_>>();
+        let cap = Wide::from_indexes(&[3, 90, 120], &[90, 120]);
+        let auth = Authorization::new(cap, signers[1].public);
+        let mut reference = Reference::<u64,Dalek,Wide>::new(0u64, &signers[0], 4, auth).unwrap();
+
+        let auth = Authorization::new(Wide::from_indexes(&[3, 90], &[]), signers[2].public);
+        expect!(reference.sign(&signers[1], auth), Err(Error::Capability));
+        let auth = Authorization::new(Wide::from_indexes(&[120], &[90]), signers[2].public);
+        expect!(reference.sign(&signers[1], auth), Ok(_));
+        expect!(reference.validate(&signers[2].public), Ok(_));
+
+        let token = reference.to_token().unwrap();
+        let reference = Reference::<u64,Dalek,Wide>::from_token(&token).unwrap();
+        assert!(reference.last().unwrap().auth.capability.is_allowed(90));
+        expect!(reference.validate(&signers[2].public), Ok(_));
+    }
+
+    #[test]
+    fn test_validity() {
+        let cap = Capability::new(0b11111111, 0b11111111);
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
+        chain.reference.certs.clear();
+        let auth = Authorization::new(cap.clone(), chain.verifier(1))
+                        .with_validity(Some(100), Some(200));
+        chain.reference.sign(&chain.issuer(), auth).unwrap();
+
+        // delegation can not outlive its issuer's authorization
+        let signer = chain.signer(1);
+        let auth = Authorization::new(cap.clone(), chain.verifier(2));
+        expect!(chain.reference.sign(&signer, auth), Err(Error::Validity));
+        let auth = Authorization::new(cap.clone(), chain.verifier(2))
+                        .with_validity(Some(150), Some(250));
+        expect!(chain.reference.sign(&signer, auth), Err(Error::Validity));
+        let auth = Authorization::new(cap.clone(), chain.verifier(2))
+                        .with_validity(None, Some(150));
+        expect!(chain.reference.sign(&signer, auth), Err(Error::Validity));
+        let auth = Authorization::new(cap, chain.verifier(2))
+                        .with_validity(Some(120), Some(150));
+        expect!(chain.reference.sign(&signer, auth), Ok(_));
+
+        let subject = &chain.verifier(2);
+        expect!(chain.validate_at(subject, 99), Err(Error::Expired));
+        expect!(chain.validate_at(subject, 110), Err(Error::Expired));
+        expect!(chain.validate_at(subject, 120), Ok(_));
+        expect!(chain.validate_at(subject, 150), Err(Error::Expired));
+
+        // validity is signed
+        chain.reference.certs[1].auth.expires = Some(200);
+        expect!(chain.validate_at(subject, 120), Err(Error::Signature(_)));
     }
 
     #[test]
     fn test_subset() {
         let cap = Capability::new(0b11111111, 0b11111111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        test.sign_n(None, cap).unwrap();
+        delegate_shrinking(&mut chain, 8, cap).unwrap();
 
-        let subject = test.public_keys[4];
-        let subset = test.reference.subset(&subject).unwrap();
+        let subject = chain.verifier(4);
+        let subset = chain.reference.subset(&subject).unwrap();
         if subject != subset.certs.last().unwrap().auth.subject {
             panic!("subject in reference and its subset are different")
         }
@@ -408,12 +733,12 @@ pub mod tests {
     #[test]
     fn test_shrink() {
         let cap = Capability::new(0b11111111, 0b11111111);
-        let mut test = TestReference::<Dalek>::new(64, cap.clone());
+        let mut chain = Chain::<Dalek>::new(0, 0, 64, cap.clone());
 
-        test.sign_n(None, cap).unwrap();
+        delegate_shrinking(&mut chain, 8, cap).unwrap();
 
-        let (signer, subject) = (&test.signers[2], &test.public_keys[6]);
-        let subset = test.reference.shrink(signer, subject).unwrap();
+        let (signer, subject) = (&chain.signer(2), &chain.verifier(6));
+        let subset = chain.reference.shrink(signer, subject).unwrap();
         let last = subset.certs.last().unwrap();
 
         if &last.auth.subject != subject {
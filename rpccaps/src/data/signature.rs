@@ -1,4 +1,5 @@
 use std::convert::TryFrom;
+use std::fmt;
 
 use signature;
 use serde::{Serialize,Deserialize};
@@ -6,7 +7,61 @@ use serde::{Serialize,Deserialize};
 use super::bytes;
 
 pub use signature::Error;
-pub use ed25519::Signature;
+
+
+/// Signature of 64 bytes, as produced by ed25519 and secp256k1 (compact
+/// ECDSA) signers. It is checked by the verifier of its method.
+///
+/// It replaces the re-exported `ed25519::Signature`, from and into which it
+/// converts, keeping the same encoding.
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub struct Signature([u8; Signature::BYTE_SIZE]);
+
+impl Signature {
+    pub const BYTE_SIZE: usize = 64;
+
+    pub fn new(bytes: [u8; Self::BYTE_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::BYTE_SIZE] {
+        self.0
+    }
+}
+
+impl AsRef<[u8]> for Signature {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl signature::Signature for Signature {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        <[u8; Self::BYTE_SIZE]>::try_from(bytes).map(Self).or(Err(Error::new()))
+    }
+}
+
+impl From<ed25519::Signature> for Signature {
+    fn from(signature: ed25519::Signature) -> Self {
+        Self(signature.to_bytes())
+    }
+}
+
+impl TryFrom<Signature> for ed25519::Signature {
+    type Error = Error;
+
+    fn try_from(signature: Signature) -> Result<Self, Error> {
+        ed25519::Signature::from_bytes(&signature.0)
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature(")?;
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+        write!(f, ")")
+    }
+}
 
 
 pub trait Verifier : signature::Verifier<Signature>+PartialEq+Clone+bytes::Bytes {
@@ -28,7 +83,7 @@ pub trait SignMethod : Clone {
 
 impl bytes::Bytes for Signature {
     fn from_bytes<B: AsRef<[u8]>>(b: B) -> Option<Self> {
-        <Self as TryFrom<&[u8]>>::try_from(b.as_ref()).ok()
+        <[u8; Self::BYTE_SIZE]>::try_from(b.as_ref()).map(Self).ok()
     }
 
     fn as_bytes(&self) -> &[u8] {
@@ -48,6 +103,20 @@ pub mod dalek {
     impl super::Signer for Keypair {}
     impl super::Verifier for PublicKey {}
 
+    impl signature::Signer<Signature> for Keypair {
+        fn try_sign(&self, msg: &[u8]) -> Result<Signature, Error> {
+            let signature: ed25519::Signature = signature::Signer::try_sign(self, msg)?;
+            Ok(signature.into())
+        }
+    }
+
+    impl signature::Verifier<Signature> for PublicKey {
+        fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), Error> {
+            let signature = ed25519::Signature::try_from(*signature)?;
+            signature::Verifier::verify(self, msg, &signature)
+        }
+    }
+
     impl super::SignMethod for Dalek {
         type Signer = Keypair;
         type Verifier = PublicKey;
@@ -79,3 +148,176 @@ pub mod dalek {
 pub use dalek::Dalek;
 
 
+#[cfg(feature="secp256k1")]
+pub mod secp256k1 {
+    //! secp256k1 ECDSA signatures, over the SHA-256 digest of messages.
+    //! Public keys are encoded as compressed SEC1 points, signatures as
+    //! their compact (`r || s`) form, normalized to low `s`.
+    use k256::ecdsa::{self, SigningKey, VerifyingKey};
+    use rand_core::{OsRng, RngCore};
+    use super::*;
+
+    /// Size of an encoded public key.
+    pub const PUBLIC_KEY_SIZE: usize = 33;
+
+    #[derive(Serialize,Deserialize,Clone)]
+    pub struct Secp256k1;
+
+    /// Public key, along with its encoding.
+    #[derive(Clone)]
+    pub struct PublicKey {
+        key: VerifyingKey,
+        bytes: [u8; PUBLIC_KEY_SIZE],
+    }
+
+    impl PublicKey {
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+            let key = VerifyingKey::from_sec1_bytes(bytes)?;
+            Ok(Self::from(key))
+        }
+    }
+
+    impl From<VerifyingKey> for PublicKey {
+        fn from(key: VerifyingKey) -> Self {
+            let mut bytes = [0u8; PUBLIC_KEY_SIZE];
+            bytes.copy_from_slice(&key.to_bytes());
+            Self { key, bytes }
+        }
+    }
+
+    impl PartialEq for PublicKey {
+        fn eq(&self, other: &Self) -> bool {
+            self.bytes == other.bytes
+        }
+    }
+
+    impl fmt::Debug for PublicKey {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "PublicKey(")?;
+            self.bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+            write!(f, ")")
+        }
+    }
+
+    /// Secret key along with its public key.
+    pub struct Keypair {
+        pub secret: SigningKey,
+        pub public: PublicKey,
+    }
+
+    impl Keypair {
+        /// Create keypair from secret key's 32 bytes.
+        pub fn from_bytes(secret: &[u8]) -> Result<Self, Error> {
+            let secret = SigningKey::from_bytes(secret)?;
+            let public = PublicKey::from(secret.verifying_key());
+            Ok(Self { secret, public })
+        }
+    }
+
+    impl super::Signer for Keypair {}
+    impl super::Verifier for PublicKey {}
+
+    impl signature::Signer<Signature> for Keypair {
+        fn try_sign(&self, msg: &[u8]) -> Result<Signature, Error> {
+            let signature: ecdsa::Signature = self.secret.try_sign(msg)?;
+            <Signature as signature::Signature>::from_bytes(signature.as_ref())
+        }
+    }
+
+    impl signature::Verifier<Signature> for PublicKey {
+        fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), Error> {
+            let signature = ecdsa::Signature::try_from(signature.as_ref())?;
+            self.key.verify(msg, &signature)
+        }
+    }
+
+    impl super::SignMethod for Secp256k1 {
+        type Signer = Keypair;
+        type Verifier = PublicKey;
+
+        fn generate() -> Result<Self::Signer, Error> {
+            let mut secret = [0u8; 32];
+            // out of range secrets are negligibly likely
+            loop {
+                OsRng.fill_bytes(&mut secret);
+                if let Ok(keypair) = Keypair::from_bytes(&secret) {
+                    return Ok(keypair);
+                }
+            }
+        }
+
+        fn signer(secret: &[u8]) -> Result<Self::Signer, Error> {
+            Keypair::from_bytes(secret)
+        }
+
+        fn verifier(signer: &Self::Signer) -> Result<&Self::Verifier, Error> {
+            Ok(&signer.public)
+        }
+    }
+
+    impl bytes::Bytes for PublicKey {
+        fn from_bytes<B: AsRef<[u8]>>(b: B) -> Option<Self> {
+            PublicKey::from_bytes(b.as_ref()).ok()
+        }
+
+        fn as_bytes(&self) -> &[u8] {
+            &self.bytes
+        }
+    }
+}
+
+#[cfg(feature="secp256k1")]
+pub use secp256k1::Secp256k1;
+
+
+#[cfg(test)]
+mod tests {
+    use signature::{Signer as _, Verifier as _};
+
+    use super::*;
+
+    fn test_method<Sign: SignMethod>() {
+        let (signer, other) = (Sign::generate().unwrap(), Sign::generate().unwrap());
+        let verifier = Sign::verifier(&signer).unwrap();
+        let signature = signer.try_sign(b"message").unwrap();
+        assert!(verifier.verify(b"message", &signature).is_ok());
+        assert!(verifier.verify(b"other message", &signature).is_err());
+        assert!(Sign::verifier(&other).unwrap().verify(b"message", &signature).is_err());
+
+        let encoded = bytes::Bytes::as_bytes(verifier);
+        assert!(<Sign::Verifier as bytes::Bytes>::from_bytes(encoded) == Some(verifier.clone()));
+        let signature = <Signature as bytes::Bytes>::from_bytes(bytes::Bytes::as_bytes(&signature)).unwrap();
+        assert!(verifier.verify(b"message", &signature).is_ok());
+    }
+
+    #[test]
+    fn test_dalek() {
+        test_method::<Dalek>();
+
+        let keypair = Dalek::generate().unwrap();
+        let signature: ed25519::Signature = keypair.try_sign(b"message").unwrap();
+        assert_eq!(ed25519::Signature::try_from(Signature::from(signature)).unwrap(), signature);
+    }
+
+    #[cfg(feature="secp256k1")]
+    #[test]
+    fn test_secp256k1() {
+        use crate::data::{Authorization, Capability, Reference};
+        use crate::data::validate::Validate;
+
+        test_method::<Secp256k1>();
+
+        let signer = Secp256k1::signer(&[7u8; 32]).unwrap();
+        assert_eq!(bytes::Bytes::as_bytes(&signer.public).len(), secp256k1::PUBLIC_KEY_SIZE);
+        assert!(Secp256k1::signer(&[0u8; 32]).is_err());
+
+        // references signed by secp256k1 keys
+        let subject = Secp256k1::generate().unwrap();
+        let auth = Authorization::new(Capability::new(0b11, 0b01), subject.public.clone());
+        let reference = Reference::<u64, Secp256k1>::new(0, &signer, 4, auth).unwrap();
+        let reference = Reference::<u64, Secp256k1>::from_token(&reference.to_token().unwrap()).unwrap();
+        assert!(reference.validate(&subject.public).is_ok());
+    }
+}
+
+
@@ -0,0 +1,161 @@
+//! Deterministic keys, references and capability chains for tests.
+//!
+//! **Not for production**: keys are derived from public seeds, thus anyone
+//! knowing a seed has its secret key. This module is only built for the
+//! crate's tests and with the `testing` feature.
+//!
+//! ```ignore
+//! let mut chain = Chain::<Dalek>::new(1, 0u64, 8, Capability::new(0b111, 0b111));
+//! chain.delegate(Capability::new(0b011, 0b001))?;
+//! chain.validate()?;
+//! ```
+use std::ops::{Deref, DerefMut};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use serde::Serialize;
+
+use super::capability::Capability;
+use super::hash::{DefaultHasher, Hasher};
+use super::reference::{Authorization, Error, Reference};
+use super::signature::{Dalek, SignMethod};
+use super::validate::Validate;
+
+
+/// Sign methods whose signers can be derived from a seed.
+pub trait Seeded: SignMethod {
+    /// Return signer derived from provided secret seed.
+    fn from_seed(seed: &[u8; 32]) -> Self::Signer;
+}
+
+impl Seeded for Dalek {
+    fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let secret = SecretKey::from_bytes(seed).expect("seed is 32 bytes");
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+}
+
+
+/// Return signer derived from provided seed. Distinct seeds give distinct
+/// signers.
+pub fn signer<Sign: Seeded>(seed: u64) -> Sign::Signer {
+    let mut hasher = DefaultHasher::default();
+    hasher.update(b"rpccaps::data::testing");
+    hasher.update(&seed.to_le_bytes());
+    Sign::from_seed(&hasher.finalize())
+}
+
+/// Return `count` signers derived from consecutive seeds starting at `seed`.
+pub fn signers<Sign: Seeded>(seed: u64, count: usize) -> Vec<Sign::Signer> {
+    (seed..seed + count as u64).map(signer::<Sign>).collect()
+}
+
+/// Return verifier of signer derived from provided seed.
+pub fn verifier<Sign: Seeded>(seed: u64) -> Sign::Verifier {
+    Sign::verifier(&signer::<Sign>(seed)).expect("signer has a verifier").clone()
+}
+
+
+/// Reference whose signers are derived from consecutive seeds: the one at
+/// index 0 is the issuer, the one at index `i` the subject of the `i`-th
+/// authorization. A chain is thus the same across runs.
+pub struct Chain<Sign: SignMethod, Id: Clone=u64> {
+    /// Seed of the issuer's signer.
+    pub seed: u64,
+    pub reference: Reference<Id, Sign>,
+}
+
+impl<Sign: Seeded, Id: Clone+Serialize> Chain<Sign, Id> {
+    /// Create reference to object `id`, issued by signer of `seed` to the
+    /// signer of `seed + 1` with provided capability.
+    pub fn new(seed: u64, id: Id, max_share: u32, capability: Capability) -> Self {
+        let auth = Authorization::new(capability, verifier::<Sign>(seed + 1));
+        let reference = Reference::new(id, &signer::<Sign>(seed), max_share, auth)
+                                  .expect("can not create reference");
+        Self { seed, reference }
+    }
+
+    /// Signer at provided index.
+    pub fn signer(&self, index: usize) -> Sign::Signer {
+        signer::<Sign>(self.seed + index as u64)
+    }
+
+    /// Verifier of the signer at provided index.
+    pub fn verifier(&self, index: usize) -> Sign::Verifier {
+        verifier::<Sign>(self.seed + index as u64)
+    }
+
+    /// Delegate capability from signer at `index` to the next one.
+    pub fn sign(&mut self, index: usize, capability: Capability) -> Result<(), Error> {
+        let auth = Authorization::new(capability, self.verifier(index + 1));
+        self.reference.sign(&self.signer(index), auth)
+    }
+
+    /// Delegate capability from current holder to the next signer, which
+    /// becomes the holder.
+    pub fn delegate(&mut self, capability: Capability) -> Result<(), Error> {
+        self.sign(self.reference.depth() + 1, capability)
+    }
+
+    /// Delegate capability `count` times, returning the count of
+    /// delegations made before an error.
+    pub fn delegate_n(&mut self, count: usize, capability: Capability) -> Result<(), (usize, Error)> {
+        for i in 0..count {
+            self.delegate(capability.clone()).map_err(|err| (i, err))?;
+        }
+        Ok(())
+    }
+
+    /// Signer issuing the reference.
+    pub fn issuer(&self) -> Sign::Signer {
+        self.signer(0)
+    }
+
+    /// Signer holding the reference, subject of its last authorization.
+    pub fn holder(&self) -> Sign::Signer {
+        self.signer(self.reference.depth() + 1)
+    }
+
+    /// Validate reference for its holder.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.reference.validate(&self.verifier(self.reference.depth() + 1))
+    }
+}
+
+impl<Sign: SignMethod, Id: Clone> Deref for Chain<Sign, Id> {
+    type Target = Reference<Id, Sign>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reference
+    }
+}
+
+impl<Sign: SignMethod, Id: Clone> DerefMut for Chain<Sign, Id> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.reference
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain() {
+        assert_eq!(verifier::<Dalek>(3), verifier::<Dalek>(3));
+        assert!(verifier::<Dalek>(3) != verifier::<Dalek>(4));
+
+        let cap = Capability::new(0b111, 0b111);
+        let mut chain = Chain::<Dalek>::new(7, 0, 2, cap.clone());
+        assert!(chain.verifier(1) == verifier::<Dalek>(8));
+        chain.delegate_n(2, cap.clone()).unwrap();
+        assert!(chain.validate().is_ok());
+        assert!(matches!(chain.delegate(cap), Err(Error::MaxShare)));
+
+        // same seed, same chain
+        let other = Chain::<Dalek>::new(7, 0, 2, Capability::new(0b111, 0b111));
+        let subset = chain.subset(&chain.verifier(1)).unwrap();
+        assert_eq!(bincode::serialize(&subset).unwrap(), bincode::serialize(&*other).unwrap());
+    }
+}
@@ -1,32 +1,51 @@
 use std::{
+    fmt,
     fs, io::ErrorKind as IoErrorKind,
+    net::IpAddr,
     path::PathBuf,
+    sync::Arc,
 };
+use x509_parser::extensions::GeneralName;
+use zeroize::{Zeroize, Zeroizing};
+
 use crate::{ErrorKind,Result};
 
 
+/// Parse private key from der or pem (PKCS #8 or PKCS #1) data.
+pub fn private_key_from_bytes(key: &[u8], der: bool)
+    -> Result<rustls::PrivateKey>
+{
+    if der {
+        return Ok(rustls::PrivateKey(key.to_vec()));
+    }
+
+    let mut pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)
+            .or(ErrorKind::InvalidData.err("invalid PKCS #8 key"))?;
+    let mut rsa = match pkcs8.is_empty() {
+        true => rustls_pemfile::rsa_private_keys(&mut &*key)
+                    .or(ErrorKind::InvalidData.err("invalid PKCS #1 key"))?,
+        false => Vec::new(),
+    };
+    let key = match (pkcs8.is_empty(), rsa.is_empty()) {
+        (false, _) => pkcs8.swap_remove(0),
+        (true, false) => rsa.swap_remove(0),
+        (true, true) => return ErrorKind::InvalidData.err("malformed PKCS #1 private key"),
+    };
+    // other keys of the file are not used
+    pkcs8.iter_mut().chain(rsa.iter_mut()).for_each(|k| k.zeroize());
+    Ok(rustls::PrivateKey(key))
+}
+
+
 /// Read private key from der or pem file.
 pub fn private_key_from_file(key_path: &PathBuf)
     -> Result<rustls::PrivateKey>
 {
     match fs::read(key_path) {
-        Ok(key) => match key_path.extension() {
-            Some(x) if x == "der" => Ok(rustls::PrivateKey(key)),
-            _ => {
-                let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)
-                        .or(ErrorKind::InvalidData.err("invalid PKCS #8 key"))?;
-                match pkcs8.into_iter().next() {
-                    Some(x) => Ok(rustls::PrivateKey(x)),
-                    None => {
-                        let rsa = rustls_pemfile::rsa_private_keys(&mut &*key)
-                            .or(ErrorKind::InvalidData.err("invalid PKCS #1 key"))?;
-                        match rsa.into_iter().next() {
-                            Some(x) => Ok(rustls::PrivateKey(x)),
-                            None => ErrorKind::InvalidData.err("malformed PKCS #1 private key"),
-                        }
-                    }
-                }
-            }
+        Ok(key) => {
+            let key = Zeroizing::new(key);
+            let der = matches!(key_path.extension(), Some(x) if x == "der");
+            private_key_from_bytes(&key, der)
         },
         Err(err) if err.kind() == IoErrorKind::NotFound
             => ErrorKind::NotFound.err("private key file not found"),
@@ -35,6 +54,99 @@ pub fn private_key_from_file(key_path: &PathBuf)
 }
 
 
+/// Callback providing private key's data.
+pub type KeyCallback = Arc<dyn Send+Sync+Fn() -> Result<Zeroizing<Vec<u8>>>>;
+
+/// Source of a private key, allowing keys to be injected by secret managers
+/// without being written on disk. Key data is expected as PEM, or as DER
+/// when it is not.
+///
+/// Data read from the source is zeroized once parsed. The parsed key is
+/// handed to rustls, which does not zeroize it.
+#[derive(Clone)]
+pub enum KeySource {
+    /// Read key from file.
+    File(PathBuf),
+    /// Read PEM key from environment variable. The variable is left
+    /// untouched.
+    Env(String),
+    /// Read key from file descriptor (see `FdKey`).
+    #[cfg(unix)]
+    Fd(FdKey),
+    /// Get key's data from callback.
+    Callback(KeyCallback),
+}
+
+impl KeySource {
+    /// Read key from file descriptor `fd`, whose ownership is given to the
+    /// source.
+    #[cfg(unix)]
+    pub fn fd(fd: std::os::unix::io::RawFd) -> Self {
+        Self::Fd(FdKey::new(fd))
+    }
+
+    /// Load private key from source.
+    pub fn load(&self) -> Result<rustls::PrivateKey> {
+        let data = match self {
+            Self::File(path) => return private_key_from_file(path),
+            Self::Env(name) => match std::env::var(name) {
+                Ok(value) => Zeroizing::new(value.into_bytes()),
+                Err(_) => return ErrorKind::NotFound.err(format!("missing private key variable {}", name)),
+            },
+            #[cfg(unix)]
+            Self::Fd(fd) => fd.read()?,
+            Self::Callback(callback) => callback()?,
+        };
+        private_key_from_bytes(&data, !data.starts_with(b"-----BEGIN"))
+    }
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            #[cfg(unix)]
+            Self::Fd(_) => f.write_str("Fd"),
+            Self::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+
+/// Private key read from a file descriptor, owned by the source. The
+/// descriptor is read until its end and closed on first load, its data
+/// being dropped once parsed: reloading is not supported, next loads of the
+/// source or its clones fail. The descriptor is closed when the last clone
+/// is dropped if it has never been loaded.
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct FdKey {
+    file: Arc<std::sync::Mutex<Option<fs::File>>>,
+}
+
+#[cfg(unix)]
+impl FdKey {
+    pub fn new(fd: std::os::unix::io::RawFd) -> Self {
+        use std::os::unix::io::FromRawFd;
+        // safety: ownership of the descriptor is given to the source.
+        let file = unsafe { fs::File::from_raw_fd(fd) };
+        Self { file: Arc::new(std::sync::Mutex::new(Some(file))) }
+    }
+
+    /// Return key's data, reading and closing the descriptor. Fail if it
+    /// has already been read.
+    fn read(&self) -> Result<Zeroizing<Vec<u8>>> {
+        use std::io::Read;
+        let file = self.file.lock().unwrap_or_else(|err| err.into_inner()).take();
+        let mut file = file.ok_or(ErrorKind::NotFound.error("private key descriptor already read"))?;
+        let mut data = Zeroizing::new(Vec::new());
+        file.read_to_end(&mut data).or_else(|err| ErrorKind::File.err(err.to_string()))?;
+        Ok(data)
+    }
+}
+
+
 /// Return certificates from der or pem file.
 pub fn cert_from_file(cert_path: &PathBuf)
     -> Result<Vec<rustls::Certificate>>
@@ -55,6 +167,92 @@ pub fn cert_from_file(cert_path: &PathBuf)
 }
 
 
+/// Write certificates and private key to files, as der or pem according to
+/// their extension. Private key's file is only readable by its owner.
+pub fn write_cert(certs: &[rustls::Certificate], key: &rustls::PrivateKey,
+                  cert_path: &PathBuf, key_path: &PathBuf) -> Result<()>
+{
+    let cert = match cert_path.extension() {
+        Some(x) if x == "der" => match certs {
+            [cert] => cert.0.clone(),
+            _ => return ErrorKind::InvalidInput.err("der file can only hold one certificate"),
+        },
+        _ => pem::encode_many(&certs.iter().map(|cert| pem::Pem {
+            tag: String::from("CERTIFICATE"), contents: cert.0.clone()
+        }).collect::<Vec<_>>()).into_bytes(),
+    };
+    let key = match key_path.extension() {
+        Some(x) if x == "der" => Zeroizing::new(key.0.clone()),
+        _ => Zeroizing::new(pem::encode(&pem::Pem {
+            tag: String::from("PRIVATE KEY"), contents: key.0.clone()
+        }).into_bytes()),
+    };
+
+    write_file(key_path, &key, true).or_else(|err| ErrorKind::File.err(err.to_string()))?;
+    write_file(cert_path, &cert, false).or_else(|err| ErrorKind::File.err(err.to_string()))
+}
+
+/// Write data to a temporary file then rename it to `path`, so that a
+/// crash never leaves a partially written file. When `private`, the file
+/// is only readable by its owner, even if `path` already existed with
+/// wider permissions.
+fn write_file(path: &PathBuf, data: &[u8], private: bool) -> std::io::Result<()> {
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    let result = options.open(&tmp_path).and_then(|mut file| {
+        // mode only applies on creation
+        #[cfg(unix)]
+        if private {
+            file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        }
+        std::io::Write::write_all(&mut file, data)?;
+        file.sync_all()
+    }).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+
+/// Server certificate verifier only accepting pinned certificates, whatever
+/// their issuer, validity period or names. Handshake's signature is still
+/// verified against the presented certificate.
+pub struct PinnedCertVerifier {
+    certs: Vec<rustls::Certificate>,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(certs: Vec<rustls::Certificate>) -> Self {
+        Self { certs }
+    }
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(&self, end_entity: &rustls::Certificate,
+                          _intermediates: &[rustls::Certificate],
+                          _server_name: &rustls::ServerName,
+                          _scts: &mut dyn Iterator<Item=&[u8]>,
+                          _ocsp_response: &[u8], _now: std::time::SystemTime)
+        -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error>
+    {
+        match self.certs.contains(end_entity) {
+            true => Ok(rustls::client::ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificateData(
+                String::from("certificate is not pinned"))),
+        }
+    }
+}
+
+
 /// Generate a new certificate and private key
 pub fn new_cert(subjects: Vec<String>)
     -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)>
@@ -69,3 +267,220 @@ pub fn new_cert(subjects: Vec<String>)
     Ok((vec![rustls::Certificate(cert)], rustls::PrivateKey(key)))
 }
 
+
+
+/// Problem found on certificate and private key material.
+#[derive(Clone,Debug,PartialEq)]
+pub enum CertIssue {
+    /// No certificate is provided.
+    Missing,
+    /// Certificate or key can not be loaded.
+    Unreadable(String),
+    /// Certificate can not be parsed.
+    InvalidFormat(String),
+    /// Private key is not supported or invalid.
+    InvalidKey,
+    /// Certificate expired at provided timestamp.
+    Expired(i64),
+    /// Certificate is not valid before provided timestamp.
+    NotYetValid(i64),
+    /// Private key does not match certificate's public key.
+    KeyMismatch,
+    /// Subject name is not in certificate's alternative names.
+    MissingSubject(String),
+}
+
+impl fmt::Display for CertIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "no certificate provided"),
+            Self::Unreadable(err) => write!(f, "can not load certificate or key: {}", err),
+            Self::InvalidFormat(err) => write!(f, "invalid certificate format: {}", err),
+            Self::InvalidKey => write!(f, "invalid or unsupported private key"),
+            Self::Expired(at) => write!(f, "certificate expired (at timestamp {})", at),
+            Self::NotYetValid(at) => write!(f, "certificate not valid before timestamp {}", at),
+            Self::KeyMismatch => write!(f, "private key does not match certificate"),
+            Self::MissingSubject(name) => write!(f, "subject {} is missing from certificate", name),
+        }
+    }
+}
+
+
+/// Report of certificate and private key validation.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct CertReport {
+    pub issues: Vec<CertIssue>,
+}
+
+impl CertReport {
+    /// Return true if no issue has been found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for CertReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Check end-entity certificate (the first one) against private key,
+/// subject names and provided time (as seconds since UNIX epoch).
+pub fn check_cert(certs: &[rustls::Certificate], key: &rustls::PrivateKey,
+                  subjects: &[String], now: u64) -> CertReport
+{
+    let mut report = CertReport::default();
+    let cert = match certs.first() {
+        Some(cert) => cert,
+        None => {
+            report.issues.push(CertIssue::Missing);
+            return report;
+        }
+    };
+    let cert = match x509_parser::parse_x509_certificate(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(err) => {
+            report.issues.push(CertIssue::InvalidFormat(err.to_string()));
+            return report;
+        }
+    };
+
+    let (now, validity) = (now as i64, cert.validity());
+    if now < validity.not_before.timestamp() {
+        report.issues.push(CertIssue::NotYetValid(validity.not_before.timestamp()));
+    }
+    if now > validity.not_after.timestamp() {
+        report.issues.push(CertIssue::Expired(validity.not_after.timestamp()));
+    }
+
+    // rcgen only handles PKCS #8 keys: others are only checked by rustls
+    match rcgen::KeyPair::from_der(&key.0) {
+        Ok(key) if key.public_key_raw() != &*cert.public_key().subject_public_key.data =>
+            report.issues.push(CertIssue::KeyMismatch),
+        Ok(_) => (),
+        Err(_) if rustls::sign::any_supported_type(key).is_ok() => (),
+        Err(_) => report.issues.push(CertIssue::InvalidKey),
+    }
+
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.clone(),
+        _ => Vec::new(),
+    };
+    for subject in subjects {
+        let ip = subject.parse::<IpAddr>().ok();
+        let found = names.iter().any(|name| match (name, ip) {
+            (GeneralName::DNSName(name), _) => name.eq_ignore_ascii_case(subject),
+            (GeneralName::IPAddress(addr), Some(IpAddr::V4(ip))) => *addr == ip.octets(),
+            (GeneralName::IPAddress(addr), Some(IpAddr::V6(ip))) => *addr == ip.octets(),
+            _ => false,
+        });
+        if !found {
+            report.issues.push(CertIssue::MissingSubject(subject.clone()));
+        }
+    }
+    report
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_cert() {
+        let subjects = vec![String::from("localhost"), String::from("127.0.0.1")];
+        let (certs, key) = new_cert(subjects.clone()).unwrap();
+        let now = 1_700_000_000;
+        assert!(check_cert(&certs, &key, &subjects, now).is_ok());
+
+        let report = check_cert(&certs, &key, &[String::from("example.com")], now);
+        assert_eq!(report.issues, vec![CertIssue::MissingSubject(String::from("example.com"))]);
+
+        let (_, other_key) = new_cert(subjects.clone()).unwrap();
+        let report = check_cert(&certs, &other_key, &subjects, now);
+        assert_eq!(report.issues, vec![CertIssue::KeyMismatch]);
+
+        let report = check_cert(&certs, &rustls::PrivateKey(vec![1,2,3]), &subjects, now);
+        assert_eq!(report.issues, vec![CertIssue::InvalidKey]);
+
+        assert!(matches!(check_cert(&certs, &key, &subjects, 0).issues[..],
+                         [CertIssue::NotYetValid(_)]));
+        assert!(matches!(check_cert(&certs, &key, &subjects, u64::MAX >> 2).issues[..],
+                         [CertIssue::Expired(_)]));
+
+        let report = check_cert(&[rustls::Certificate(vec![0;8])], &key, &subjects, now);
+        assert!(matches!(report.issues[..], [CertIssue::InvalidFormat(_)]));
+        assert_eq!(check_cert(&[], &key, &subjects, now).issues, vec![CertIssue::Missing]);
+    }
+
+    #[test]
+    fn test_key_source() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let (pem, der) = (cert.serialize_private_key_pem(), cert.serialize_private_key_der());
+
+        std::env::set_var("RPCCAPS_TEST_KEY", &pem);
+        assert_eq!(KeySource::Env(String::from("RPCCAPS_TEST_KEY")).load().unwrap().0, der);
+        assert_eq!(KeySource::Env(String::from("RPCCAPS_TEST_NO_KEY")).load().unwrap_err().kind(),
+                   ErrorKind::NotFound);
+
+        let data = der.clone();
+        let source = KeySource::Callback(Arc::new(move || Ok(Zeroizing::new(data.clone()))));
+        assert_eq!(source.load().unwrap().0, der);
+
+        let source = KeySource::Callback(Arc::new(|| Ok(Zeroizing::new(b"-----BEGIN nothing".to_vec()))));
+        assert_eq!(source.load().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // descriptor is read and closed on first load
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::IntoRawFd;
+            let path = std::env::temp_dir().join("rpccaps-test-fd-key.pem");
+            fs::write(&path, &pem).unwrap();
+            let source = KeySource::fd(fs::File::open(&path).unwrap().into_raw_fd());
+            fs::remove_file(&path).unwrap();
+            assert_eq!(source.load().unwrap().0, der);
+            // data is not kept for reloads
+            assert_eq!(source.clone().load().unwrap_err().kind(), ErrorKind::NotFound);
+            assert_eq!(source.load().unwrap_err().kind(), ErrorKind::NotFound);
+
+            // descriptor of a source never loaded is closed once dropped
+            let (reader, mut writer) = std::os::unix::net::UnixStream::pair().unwrap();
+            let source = KeySource::fd(reader.into_raw_fd());
+            drop(source.clone());
+            drop(source);
+            assert!(std::io::Write::write_all(&mut writer, b"key").is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_cert() {
+        let (certs, key) = new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = std::env::temp_dir().join("rpccaps-test-write-cert.pem");
+        let key_path = std::env::temp_dir().join("rpccaps-test-write-key.pem");
+        fs::write(&key_path, b"previous key").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        write_cert(&certs, &key, &cert_path, &key_path).unwrap();
+        assert_eq!(cert_from_file(&cert_path).unwrap(), certs);
+        assert_eq!(private_key_from_file(&key_path).unwrap(), key);
+        // existing key file is no longer readable by others
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
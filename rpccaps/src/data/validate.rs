@@ -1,6 +1,7 @@
 use std::fmt::Display;
 use std::ops::{Deref,DerefMut};
 
+use async_trait::async_trait;
 use serde::{Serialize,Deserialize,Serializer,Deserializer};
 
 
@@ -13,8 +14,19 @@ pub trait Validate: Sized {
 }
 
 
+/// Add data validation requiring I/O (revocation lookups, clock queries,
+/// store reads, etc.) after deserialization for a struct.
+#[async_trait]
+pub trait AsyncValidate: Sized+Send+Sync {
+    type Error: Display;
+    type Context: Send+Sync;
+
+    async fn validate(&self, context: &Self::Context) -> Result<(),Self::Error>;
+}
+
+
 /// Wrapper around struct used to add validation at deserialization
-pub struct Unsafe<T: Validate>(T);
+pub struct Unsafe<T>(T);
 
 impl<T: Validate> Unsafe<T> {
     pub fn validate(self, context: &T::Context) -> Result<T,T::Error> {
@@ -22,8 +34,14 @@ impl<T: Validate> Unsafe<T> {
     }
 }
 
+impl<T: AsyncValidate> Unsafe<T> {
+    pub async fn validate_async(self, context: &T::Context) -> Result<T,T::Error> {
+        AsyncValidate::validate(&self.0, context).await.map(|_| self.0)
+    }
+}
+
 
-impl<T: Validate> Deref for Unsafe<T> {
+impl<T> Deref for Unsafe<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -31,14 +49,14 @@ impl<T: Validate> Deref for Unsafe<T> {
     }
 }
 
-impl<T: Validate> DerefMut for Unsafe<T> {
+impl<T> DerefMut for Unsafe<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
 
-impl<T: Validate+Serialize> Serialize for Unsafe<T> {
+impl<T: Serialize> Serialize for Unsafe<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer,
     {
@@ -46,7 +64,7 @@ impl<T: Validate+Serialize> Serialize for Unsafe<T> {
     }
 }
 
-impl<'de, T: Validate+Deserialize<'de>> Deserialize<'de> for Unsafe<T> {
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Unsafe<T> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: Deserializer<'de>,
     {
@@ -8,6 +8,10 @@ pub enum ErrorKind {
 	NotFound,
 	Codec,
 	LimitReached,
+	Forbidden,
+	Timeout,
+	Cancelled,
+	Unavailable,
 	InvalidData,
 	InvalidInput,
 	IO,
@@ -4,10 +4,21 @@
 
 pub mod error;
 pub mod data;
+pub mod prelude;
 pub mod rpc;
 pub mod services;
 
 pub use error::{ErrorKind,Error,Result};
+pub use rpccaps_derive::service;
+
+/// Crates used by the code `#[service]` generates, so that services' crates
+/// do not need to depend on them.
+#[doc(hidden)]
+pub mod __private {
+    pub use async_trait;
+    pub use futures;
+    pub use serde;
+}
 
 
 pub mod tests {
@@ -0,0 +1,61 @@
+//! Traits and types needed by most users, under stable paths, along with
+//! the `service` attribute macro:
+//!
+//! ```ignore
+//! use rpccaps::prelude::*;
+//!
+//! #[service]
+//! impl Storage { ... }
+//! ```
+//!
+//! The crate's `Error` and `Result` are not part of it, since they would
+//! shadow std's ones, which generated services use.
+pub use crate::ErrorKind;
+pub use crate::data::{Capability, Reference, SignMethod};
+pub use crate::rpc::call::{CallError, ClientOptions};
+pub use crate::rpc::codec::{Bincode, BincodeCodec, CodecFactory, Decoder, Encoder, Framed};
+pub use crate::rpc::dispatch::HandlerOptions;
+pub use crate::rpc::service::Service;
+pub use crate::rpc::transport::Transport;
+pub use crate::service;
+
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use crate as rpccaps;
+    use crate::rpc::transport::MPSCTransport;
+    use super::*;
+
+    pub mod counter {
+        use super::*;
+
+        pub struct Service(pub u32);
+
+        #[service]
+        impl Service {
+            pub fn incr(&mut self) -> u32 {
+                self.0 += 1;
+                self.0
+            }
+        }
+    }
+
+    #[test]
+    fn test_prelude() {
+        let (server_transport, client_transport) =
+            MPSCTransport::<counter::Response, counter::Request>::bi(8);
+        let client_fut = async move {
+            let mut client = counter::Client::new(client_transport);
+            assert_eq!(client.incr().await, Ok(1));
+        };
+        let server_fut = async move {
+            let (s, r) = server_transport.split();
+            counter::Service(0).serve(Transport::new(s, r)).await;
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+}
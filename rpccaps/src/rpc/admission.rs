@@ -0,0 +1,520 @@
+//! Fair admission of streams across connections.
+//!
+//! Streams are admitted while in-flight streams are under both the global
+//! limit and their connection's one. Otherwise they wait, and released
+//! slots are handed out to waiting connections in a round-robin manner, so
+//! that a single noisy connection can not monopolize the server. Queued
+//! streams (in-flight and waiting) can be watched in order to signal
+//! backpressure.
+//!
+//! Connections themselves are limited per remote IP by `IpConnections`, and
+//! new streams of a connection can be rate limited by a `TokenBucket`, or
+//! per remote IP by `IpRates` when each connection carries a single stream.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use serde::{Deserialize,Serialize};
+
+use crate::{ErrorKind, Result};
+use crate::data::{Clock, SystemClock};
+use super::backpressure::Watch;
+use super::reaper::Reap;
+
+
+/// Connection identifier.
+pub type ConnectionId = usize;
+
+
+#[derive(Default)]
+struct Connection {
+    in_flight: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    connections: BTreeMap<ConnectionId, Connection>,
+    /// Connections having waiters, in round-robin order.
+    queue: VecDeque<ConnectionId>,
+}
+
+
+/// Admission of streams, limiting in-flight streams globally and per
+/// connection.
+pub struct Admission {
+    /// Maximum in-flight streams.
+    max_streams: usize,
+    /// Maximum in-flight streams per connection.
+    connection_streams: usize,
+    state: Mutex<State>,
+    /// Backpressure watch of queued streams.
+    watch: Option<Watch>,
+}
+
+impl Admission {
+    pub fn new(max_streams: usize, connection_streams: usize) -> Self {
+        Self { max_streams: max_streams.max(1), connection_streams: connection_streams.max(1),
+               state: Mutex::new(State::default()), watch: None }
+    }
+
+    /// Watch count of queued streams, in-flight and waiting, notifying
+    /// backpressure.
+    pub fn with_watch(mut self, watch: Watch) -> Self {
+        self.watch = Some(watch);
+        self
+    }
+
+    /// Return true if backpressure is raised.
+    pub fn is_pressured(&self) -> bool {
+        self.watch.as_ref().is_some_and(Watch::is_raised)
+    }
+
+    /// Count of in-flight streams.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Count of in-flight streams of provided connection.
+    pub fn connection_in_flight(&self, connection: ConnectionId) -> usize {
+        self.state.lock().unwrap().connections.get(&connection).map_or(0, |c| c.in_flight)
+    }
+
+    /// Wait for a stream of provided connection to be admitted. Stream is
+    /// in-flight until the returned permit is dropped.
+    pub async fn acquire(self: &Arc<Self>, connection: ConnectionId) -> Result<Permit> {
+        let receiver = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            let global = state.in_flight < self.max_streams;
+            let conn = state.connections.entry(connection).or_default();
+            // connections having waiters are only admitted in turn
+            if global && conn.in_flight < self.connection_streams && conn.waiters.is_empty() {
+                conn.in_flight += 1;
+                state.in_flight += 1;
+                self.watch(state);
+                return Ok(Permit::new(self.clone(), connection));
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            conn.waiters.push_back(sender);
+            if conn.waiters.len() == 1 {
+                state.queue.push_back(connection);
+            }
+            self.watch(state);
+            receiver
+        };
+        receiver.await.or(ErrorKind::Internal.err("admission has been dropped"))
+    }
+
+    /// Release stream's slot, admitting waiting streams.
+    fn release(self: &Arc<Self>, connection: ConnectionId) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.in_flight -= 1;
+        if let Some(conn) = state.connections.get_mut(&connection) {
+            conn.in_flight -= 1;
+            if conn.in_flight == 0 && conn.waiters.is_empty() {
+                state.connections.remove(&connection);
+            }
+        }
+        self.admit(state);
+        self.watch(state);
+    }
+
+    /// Update backpressure watch with count of queued streams.
+    fn watch(&self, state: &State) {
+        if let Some(ref watch) = self.watch {
+            let waiting: usize = state.connections.values().map(|c| c.waiters.len()).sum();
+            watch.update(state.in_flight + waiting);
+        }
+    }
+
+    /// Admit waiting streams in round-robin order while there are slots.
+    fn admit(self: &Arc<Self>, state: &mut State) {
+        let mut skipped = 0;
+        while state.in_flight < self.max_streams && skipped < state.queue.len() {
+            let id = state.queue.pop_front().unwrap();
+            let conn = state.connections.get_mut(&id).unwrap();
+            if conn.in_flight >= self.connection_streams {
+                state.queue.push_back(id);
+                skipped += 1;
+                continue;
+            }
+
+            // skip waiters that are gone
+            while let Some(waiter) = conn.waiters.pop_front() {
+                match waiter.send(Permit::new(self.clone(), id)) {
+                    Ok(_) => {
+                        conn.in_flight += 1;
+                        state.in_flight += 1;
+                        break;
+                    },
+                    Err(permit) => permit.forget(),
+                }
+            }
+
+            match (conn.waiters.is_empty(), conn.in_flight) {
+                (false, _) => state.queue.push_back(id),
+                (true, 0) => { state.connections.remove(&id); },
+                _ => (),
+            }
+            skipped = 0;
+        }
+    }
+}
+
+
+/// Admitted stream's slot, released on drop.
+pub struct Permit {
+    admission: Option<Arc<Admission>>,
+    connection: ConnectionId,
+}
+
+impl Permit {
+    fn new(admission: Arc<Admission>, connection: ConnectionId) -> Self {
+        Self { admission: Some(admission), connection }
+    }
+
+    /// Return permit's connection.
+    pub fn connection(&self) -> ConnectionId {
+        self.connection
+    }
+
+    /// Drop permit whose slot has not been counted.
+    fn forget(mut self) {
+        self.admission = None;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(admission) = self.admission.take() {
+            admission.release(self.connection);
+        }
+    }
+}
+
+
+/// Count of open connections per remote IP, limited to a maximum.
+pub struct IpConnections {
+    max: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpConnections {
+    pub fn new(max: usize) -> Self {
+        Self { max: max.max(1), counts: Mutex::new(HashMap::new()) }
+    }
+
+    /// Count of open connections from provided IP.
+    pub fn count(&self, ip: &IpAddr) -> usize {
+        self.counts.lock().unwrap().get(ip).copied().unwrap_or(0)
+    }
+
+    /// Count a new connection from provided IP, returning `None` when the
+    /// maximum is reached. Connection is counted until the returned permit
+    /// is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= self.max {
+            return None
+        }
+        *count += 1;
+        Some(IpPermit { connections: self.clone(), ip })
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(ip);
+            }
+        }
+    }
+}
+
+
+/// Counted connection, released on drop.
+pub struct IpPermit {
+    connections: Arc<IpConnections>,
+    ip: IpAddr,
+}
+
+impl IpPermit {
+    /// Connection's remote IP.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        self.connections.release(&self.ip);
+    }
+}
+
+
+/// Rate limit of a token bucket.
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub struct RateLimit {
+    /// Tokens added per second.
+    pub rate: f64,
+    /// Maximum tokens, i.e. how many can be taken at once.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self { rate, burst }
+    }
+}
+
+
+/// Token bucket, refilled at limit's rate up to its burst.
+pub struct TokenBucket<C: Clock=SystemClock> {
+    limit: RateLimit,
+    tokens: f64,
+    last: Duration,
+    clock: C,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self::with_clock(limit, SystemClock)
+    }
+}
+
+impl<C: Clock> TokenBucket<C> {
+    /// Return new bucket, initially full.
+    pub fn with_clock(limit: RateLimit, clock: C) -> Self {
+        let last = clock.now();
+        Self { limit, tokens: limit.burst as f64, last, clock }
+    }
+
+    /// Available tokens.
+    pub fn tokens(&mut self) -> f64 {
+        self.refill();
+        self.tokens
+    }
+
+    /// Take a token, returning false if there is none.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        match self.tokens >= 1.0 {
+            true => {
+                self.tokens -= 1.0;
+                true
+            },
+            false => false,
+        }
+    }
+
+    /// Duration until a token is available.
+    pub fn retry_after(&mut self) -> Duration {
+        self.refill();
+        match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::try_from_secs_f64((1.0 - self.tokens) / self.limit.rate)
+                        .unwrap_or(Duration::MAX),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.last = now;
+    }
+}
+
+
+/// Token buckets of remote IPs, rate limiting their new streams.
+pub struct IpRates<C: Clock+Clone=SystemClock> {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket<C>>>,
+    clock: C,
+}
+
+impl IpRates {
+    pub fn new(limit: RateLimit) -> Self {
+        Self::with_clock(limit, SystemClock)
+    }
+}
+
+impl<C: Clock+Clone> IpRates<C> {
+    pub fn with_clock(limit: RateLimit, clock: C) -> Self {
+        Self { limit, buckets: Mutex::new(HashMap::new()), clock }
+    }
+
+    /// Take a token of provided IP, returning false if there is none.
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.entry(ip).or_insert_with(|| TokenBucket::with_clock(self.limit, self.clock.clone()))
+               .try_acquire()
+    }
+}
+
+impl<C: Clock+Clone> Reap for IpRates<C> {
+    fn name(&self) -> &str {
+        "ip_rates"
+    }
+
+    /// Remove refilled buckets, which are recreated full when needed.
+    fn reap(&self, _now: Duration) -> usize {
+        let mut buckets = self.buckets.lock().unwrap();
+        let len = buckets.len();
+        let burst = self.limit.burst as f64;
+        buckets.retain(|_, bucket| bucket.tokens() < burst);
+        len - buckets.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use futures::executor::LocalPool;
+    use futures::task::Poll;
+
+    use super::*;
+
+    #[test]
+    fn test_admission() {
+        let admission = Arc::new(Admission::new(2, 2));
+        LocalPool::new().run_until(async {
+            let a1 = admission.acquire(1).await.unwrap();
+            let a2 = admission.acquire(1).await.unwrap();
+
+            let mut a3 = admission.acquire(1).boxed_local();
+            let mut a4 = admission.acquire(1).boxed_local();
+            let mut b1 = admission.acquire(2).boxed_local();
+            assert!(futures::poll!(&mut a3).is_pending());
+            assert!(futures::poll!(&mut a4).is_pending());
+            assert!(futures::poll!(&mut b1).is_pending());
+
+            // round-robin among waiting connections
+            drop(a1);
+            let a3 = a3.await.unwrap();
+            assert!(futures::poll!(&mut a4).is_pending());
+            drop(a2);
+            let b1 = b1.await.unwrap();
+            assert_eq!((b1.connection(), admission.connection_in_flight(2)), (2, 1));
+            assert!(futures::poll!(&mut a4).is_pending());
+
+            // cancelled waiters are skipped
+            drop(a4);
+            drop(a3);
+            assert_eq!(admission.in_flight(), 1);
+            drop(b1);
+            assert_eq!(admission.in_flight(), 0);
+            assert!(admission.state.lock().unwrap().connections.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_admission_backpressure() {
+        use super::super::backpressure::{Pressure, Watermarks};
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let notified_ = notified.clone();
+        let watch = Watch::new(Watermarks::new(3, 1),
+                               move |pressure| notified_.lock().unwrap().push(pressure));
+        let admission = Arc::new(Admission::new(2, 2).with_watch(watch));
+        LocalPool::new().run_until(async {
+            let a1 = admission.acquire(1).await.unwrap();
+            let a2 = admission.acquire(1).await.unwrap();
+            assert!(!admission.is_pressured());
+            // waiting streams are queued too
+            let mut a3 = admission.acquire(1).boxed_local();
+            assert!(futures::poll!(&mut a3).is_pending());
+            assert!(admission.is_pressured());
+
+            drop(a1);
+            let a3 = a3.await.unwrap();
+            assert!(admission.is_pressured());
+            drop(a2);
+            assert!(!admission.is_pressured());
+            drop(a3);
+            assert_eq!(*notified.lock().unwrap(), vec![Pressure::Raised, Pressure::Relieved]);
+        });
+    }
+
+    #[test]
+    fn test_admission_connection_limit() {
+        let admission = Arc::new(Admission::new(4, 1));
+        LocalPool::new().run_until(async {
+            let a1 = admission.acquire(1).await.unwrap();
+            let mut a2 = admission.acquire(1).boxed_local();
+            assert!(futures::poll!(&mut a2).is_pending());
+
+            // other connections are not blocked by a busy one
+            let b1 = admission.acquire(2).await.unwrap();
+            drop(a1);
+            match futures::poll!(&mut a2) {
+                Poll::Ready(Ok(permit)) => assert_eq!(permit.connection(), 1),
+                _ => panic!("stream expected to be admitted"),
+            }
+            drop(b1);
+        });
+    }
+
+    #[test]
+    fn test_ip_connections() {
+        let connections = Arc::new(IpConnections::new(2));
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let a1 = connections.acquire(a).unwrap();
+        let a2 = connections.acquire(a).unwrap();
+        assert!(connections.acquire(a).is_none());
+        let b1 = connections.acquire(b).unwrap();
+        assert_eq!((connections.count(&a), connections.count(&b)), (2, 1));
+
+        drop(a1);
+        let _a3 = connections.acquire(a).unwrap();
+        drop((a2, b1));
+        assert_eq!((connections.count(&a), connections.count(&b)), (1, 0));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        use crate::data::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let mut bucket = TokenBucket::with_clock(RateLimit::new(2.0, 3), clock.clone());
+        assert!((0..3).all(|_| bucket.try_acquire()));
+        assert!(!bucket.try_acquire());
+        assert_eq!(bucket.retry_after(), Duration::from_millis(500));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+
+        // refilled up to burst
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(bucket.tokens(), 3.0);
+    }
+
+    #[test]
+    fn test_ip_rates() {
+        use crate::data::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let rates = IpRates::with_clock(RateLimit::new(1.0, 2), clock.clone());
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(rates.try_acquire(a) && rates.try_acquire(a));
+        assert!(!rates.try_acquire(a));
+        assert!(rates.try_acquire(b));
+
+        // only refilled buckets are reclaimed
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(rates.reap(clock.now()), 1);
+        assert!(rates.try_acquire(a));
+        assert!(!rates.try_acquire(a));
+    }
+}
@@ -0,0 +1,165 @@
+//! Backpressure signaled to the application.
+//!
+//! Queues (a server's admission of streams, a sender's write buffer) are
+//! watched against `Watermarks`: pressure is raised once a queue's level
+//! reaches the high mark, and relieved once it falls back to the low one.
+//! Transitions are reported to a callback, which usually forwards them as
+//! `ServerEvent::Backpressure` or `ClientEvent::Backpressure`, so that
+//! applications can shed load upstream before calls time out.
+//!
+//! Senders also expose their room through `Capacity`, polled by
+//! `Transport::poll_capacity()`.
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use serde::{Deserialize,Serialize};
+
+use crate::{ErrorKind, Result};
+
+
+/// Backpressure transition.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Pressure {
+    /// Level reached the high-water mark.
+    Raised,
+    /// Level fell back to the low-water mark.
+    Relieved,
+}
+
+
+/// Levels at which pressure is raised and relieved.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Watermarks {
+    pub high: usize,
+    pub low: usize,
+}
+
+impl Watermarks {
+    /// Return new watermarks, `low` being kept under `high`.
+    pub fn new(high: usize, low: usize) -> Self {
+        let high = high.max(1);
+        Self { high, low: low.min(high - 1) }
+    }
+}
+
+
+/// Watch a queue's level against watermarks, notifying pressure
+/// transitions.
+pub struct Watch {
+    pub marks: Watermarks,
+    raised: AtomicBool,
+    notify: Box<dyn Fn(Pressure)+Send+Sync>,
+}
+
+impl Watch {
+    pub fn new<F>(marks: Watermarks, notify: F) -> Self
+        where F: 'static+Fn(Pressure)+Send+Sync
+    {
+        Self { marks, raised: AtomicBool::new(false), notify: Box::new(notify) }
+    }
+
+    /// Return true if pressure is raised.
+    pub fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    /// Update queue's level, notifying and returning transition if any.
+    pub fn update(&self, level: usize) -> Option<Pressure> {
+        let (from, pressure) = match level {
+            level if level >= self.marks.high => (false, Pressure::Raised),
+            level if level <= self.marks.low => (true, Pressure::Relieved),
+            _ => return None,
+        };
+        self.raised.compare_exchange(from, !from, Ordering::AcqRel, Ordering::Relaxed).ok()?;
+        (self.notify)(pressure);
+        Some(pressure)
+    }
+}
+
+
+/// Sender reporting how much can be sent before it is not ready.
+pub trait Capacity {
+    /// Poll for room: pending while the sender is full, then the count of
+    /// bytes or items that can be sent without waiting (at least one).
+    fn poll_capacity(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<usize>>;
+}
+
+impl<T> Capacity for mpsc::Sender<T> {
+    /// Channel's remaining room is not known: one item once ready.
+    fn poll_capacity(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        match futures::ready!(self.get_mut().poll_ready(cx)) {
+            Ok(_) => Poll::Ready(Ok(1)),
+            Err(_) => Poll::Ready(ErrorKind::IO.err("channel is closed")),
+        }
+    }
+}
+
+impl<T: Capacity+Unpin+?Sized> Capacity for Box<T> {
+    fn poll_capacity(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        Pin::new(&mut **self.get_mut()).poll_capacity(cx)
+    }
+}
+
+
+/// Future returned by `capacity()`.
+pub struct CapacityFuture<'a, S: ?Sized>(&'a mut S);
+
+impl<S: Capacity+Unpin+?Sized> Future for CapacityFuture<'_, S> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.get_mut().0).poll_capacity(cx)
+    }
+}
+
+/// Wait for sender to have room, returning its capacity.
+pub fn capacity<S: Capacity+Unpin+?Sized>(sender: &mut S) -> CapacityFuture<'_, S> {
+    CapacityFuture(sender)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_watch() {
+        assert_eq!(Watermarks::new(4, 8), Watermarks { high: 4, low: 3 });
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let notified_ = notified.clone();
+        let watch = Watch::new(Watermarks::new(4, 1),
+                               move |pressure| notified_.lock().unwrap().push(pressure));
+        assert_eq!(watch.update(3), None);
+        assert_eq!(watch.update(4), Some(Pressure::Raised));
+        assert_eq!(watch.update(5), None);
+        // hysteresis between watermarks
+        assert_eq!(watch.update(2), None);
+        assert!(watch.is_raised());
+        assert_eq!(watch.update(1), Some(Pressure::Relieved));
+        assert_eq!(watch.update(0), None);
+        assert_eq!(*notified.lock().unwrap(), vec![Pressure::Raised, Pressure::Relieved]);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let (mut sender, mut receiver) = mpsc::channel::<u32>(0);
+        block_on(async {
+            assert_eq!(capacity(&mut sender).await.unwrap(), 1);
+            sender.try_send(1).unwrap();
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            assert!(Pin::new(&mut sender).poll_capacity(&mut cx).is_pending());
+            receiver.next().await;
+            assert_eq!(capacity(&mut sender).await.unwrap(), 1);
+            drop(receiver);
+            assert!(capacity(&mut sender).await.is_err());
+        });
+    }
+}
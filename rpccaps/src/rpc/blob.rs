@@ -0,0 +1,174 @@
+//! Stream file-backed blobs from memory-mapped files, in chunks, so that
+//! serving large content keeps memory usage flat.
+//!
+//! Blob framing is a little-endian `u64` content size, followed by the raw
+//! content.
+use std::{fs::File, path::Path};
+
+use futures::io::{AsyncRead,AsyncReadExt,AsyncWrite,AsyncWriteExt};
+use memmap2::Mmap;
+
+use crate::{ErrorKind,Result};
+
+
+/// Default size of chunks written to the stream.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+
+/// Memory-mapped file content.
+///
+/// Mapped content must not be modified by other processes while the blob
+/// is alive, since the mapping is not protected against concurrent writes.
+pub struct MappedBlob {
+    /// Mapping, None for empty files (which can not be mapped).
+    mmap: Option<Mmap>,
+}
+
+impl MappedBlob {
+    /// Map file at provided path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::from_file(&file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound
+                => ErrorKind::NotFound.err("blob file not found"),
+            Err(err) => ErrorKind::File.err(err.to_string()),
+        }
+    }
+
+    /// Map provided file.
+    pub fn from_file(file: &File) -> Result<Self> {
+        let len = file.metadata().or_else(|err| ErrorKind::File.err(err.to_string()))?.len();
+        if len == 0 {
+            return Ok(Self { mmap: None });
+        }
+
+        // Safety: see struct's documentation.
+        match unsafe { Mmap::map(file) } {
+            Ok(mmap) => Ok(Self { mmap: Some(mmap) }),
+            Err(err) => ErrorKind::File.err(err.to_string()),
+        }
+    }
+
+    /// Content size in bytes.
+    pub fn len(&self) -> u64 {
+        self.as_bytes().len() as u64
+    }
+
+    /// Return true if blob is empty.
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_none()
+    }
+
+    /// Mapped content.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.mmap {
+            Some(ref mmap) => mmap.as_ref(),
+            None => &[],
+        }
+    }
+
+    /// Write blob to `writer` (e.g. a `quinn::SendStream`) by chunks of
+    /// `chunk_size` bytes. Return the count of content bytes written.
+    pub async fn send<W>(&self, writer: &mut W, chunk_size: usize) -> Result<u64>
+        where W: AsyncWrite+Unpin
+    {
+        let write_err = |err: std::io::Error| ErrorKind::IO.err(err.to_string());
+        writer.write_all(&self.len().to_le_bytes()).await.or_else(write_err)?;
+        for chunk in self.as_bytes().chunks(chunk_size.max(1)) {
+            writer.write_all(chunk).await.or_else(write_err)?;
+        }
+        writer.flush().await.or_else(write_err)?;
+        Ok(self.len())
+    }
+}
+
+
+/// Read a blob sent by `MappedBlob::send` from `reader`, writing its content
+/// into `writer` by chunks. Fails with `LimitReached` when announced size is
+/// bigger than `max_size`. Return the count of content bytes read.
+pub async fn recv_blob<R,W>(reader: &mut R, writer: &mut W, max_size: Option<u64>)
+    -> Result<u64>
+    where R: AsyncRead+Unpin, W: AsyncWrite+Unpin
+{
+    let io_err = |err: std::io::Error| ErrorKind::IO.err(err.to_string());
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await.or_else(io_err)?;
+
+    let size = u64::from_le_bytes(header);
+    if let Some(max_size) = max_size {
+        if size > max_size {
+            return ErrorKind::LimitReached.err("blob exceeds maximum size")
+        }
+    }
+
+    let mut buffer = vec![0u8; DEFAULT_CHUNK_SIZE.min(size as usize)];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = buffer.len().min(remaining as usize);
+        reader.read_exact(&mut buffer[..len]).await.or_else(io_err)?;
+        writer.write_all(&buffer[..len]).await.or_else(io_err)?;
+        remaining -= len as u64;
+    }
+    writer.flush().await.or_else(io_err)?;
+    Ok(size)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use futures::executor::LocalPool;
+    use futures::io::Cursor;
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_send_recv() {
+        let content = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let path = temp_file("rpccaps_test_blob_send_recv", &content);
+        let blob = MappedBlob::open(&path).unwrap();
+        assert_eq!(blob.len(), content.len() as u64);
+
+        LocalPool::new().run_until(async {
+            let mut stream = Cursor::new(Vec::new());
+            blob.send(&mut stream, 4096).await.unwrap();
+
+            stream.set_position(0);
+            let mut out = Cursor::new(Vec::new());
+            assert_eq!(recv_blob(&mut stream, &mut out, None).await.unwrap(), content.len() as u64);
+            assert_eq!(out.into_inner(), content);
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_recv_max_size() {
+        let path = temp_file("rpccaps_test_blob_max_size", &[1u8; 128]);
+        let blob = MappedBlob::open(&path).unwrap();
+
+        LocalPool::new().run_until(async {
+            let mut stream = Cursor::new(Vec::new());
+            blob.send(&mut stream, DEFAULT_CHUNK_SIZE).await.unwrap();
+
+            stream.set_position(0);
+            let mut out = Cursor::new(Vec::new());
+            assert_eq!(recv_blob(&mut stream, &mut out, Some(64)).await.unwrap_err().kind(),
+                       ErrorKind::LimitReached);
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_empty() {
+        let path = temp_file("rpccaps_test_blob_empty", &[]);
+        let blob = MappedBlob::open(&path).unwrap();
+        assert!(blob.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Deserialization of collections with a maximum length.
+//!
+//! Methods' arguments declared with `#[rpc(max_len=N)]` are deserialized
+//! using `max_len`: collections longer than `N` items are rejected before
+//! their allocation, based on the length announced by the format (as
+//! bincode does), or as soon as the `N+1`-th item is read otherwise.
+//!
+//! ```ignore
+//! #[service]
+//! impl Service {
+//!     fn put(&mut self, #[rpc(max_len=1024)] values: Vec<u32>) { ... }
+//! }
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+
+
+/// Maximum count of items preallocated, whatever the announced length.
+const MAX_PREALLOC: usize = 4096;
+
+
+/// Value that can be deserialized with a maximum length.
+pub trait Bounded<'de>: Sized {
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>;
+}
+
+
+/// Deserialize value, rejecting collections longer than `N` items. It is
+/// used as serde's `deserialize_with` for `#[rpc(max_len=N)]` arguments.
+pub fn max_len<'de, D, T, const N: usize>(deserializer: D) -> Result<T, D::Error>
+    where D: Deserializer<'de>, T: Bounded<'de>
+{
+    T::deserialize_bounded(deserializer, N)
+}
+
+
+/// Check announced length, returning capacity to preallocate.
+fn capacity<E: de::Error>(hint: Option<usize>, max_len: usize) -> Result<usize, E> {
+    match hint {
+        Some(len) if len > max_len => Err(E::invalid_length(len, &MaxLen(max_len))),
+        Some(len) => Ok(len.min(MAX_PREALLOC)),
+        None => Ok(0),
+    }
+}
+
+/// Expected length, for error messages.
+struct MaxLen(usize);
+
+impl de::Expected for MaxLen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at most {} items", self.0)
+    }
+}
+
+
+struct SeqVisitor<T>(usize, PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for SeqVisitor<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sequence of at most {} items", self.0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::with_capacity(capacity(seq.size_hint(), self.0)?);
+        while let Some(value) = seq.next_element()? {
+            if values.len() == self.0 {
+                return Err(de::Error::invalid_length(values.len() + 1, &MaxLen(self.0)));
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Bounded<'de> for Vec<T> {
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>
+    {
+        deserializer.deserialize_seq(SeqVisitor(max_len, PhantomData))
+    }
+}
+
+
+/// Map collection built by `MapVisitor`.
+trait Map<K, V> {
+    fn with_capacity(capacity: usize) -> Self;
+    fn insert(&mut self, key: K, value: V);
+}
+
+impl<K: Eq+Hash, V, S: BuildHasher+Default> Map<K, V> for HashMap<K, V, S> {
+    fn with_capacity(capacity: usize) -> Self {
+        HashMap::with_capacity_and_hasher(capacity, S::default())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        HashMap::insert(self, key, value);
+    }
+}
+
+impl<K: Ord, V> Map<K, V> for BTreeMap<K, V> {
+    fn with_capacity(_capacity: usize) -> Self {
+        BTreeMap::new()
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        BTreeMap::insert(self, key, value);
+    }
+}
+
+
+struct MapVisitor<M, K, V>(usize, PhantomData<(M, K, V)>);
+
+impl<'de, M, K, V> Visitor<'de> for MapVisitor<M, K, V>
+    where M: Map<K, V>, K: Deserialize<'de>, V: Deserialize<'de>
+{
+    type Value = M;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a map of at most {} entries", self.0)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut map = M::with_capacity(capacity(access.size_hint(), self.0)?);
+        let mut len = 0;
+        while let Some((key, value)) = access.next_entry()? {
+            if len == self.0 {
+                return Err(de::Error::invalid_length(len + 1, &MaxLen(self.0)));
+            }
+            map.insert(key, value);
+            len += 1;
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, K, V, S> Bounded<'de> for HashMap<K, V, S>
+    where K: Deserialize<'de>+Eq+Hash, V: Deserialize<'de>, S: BuildHasher+Default
+{
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>
+    {
+        deserializer.deserialize_map(MapVisitor::<Self, K, V>(max_len, PhantomData))
+    }
+}
+
+impl<'de, K, V> Bounded<'de> for BTreeMap<K, V>
+    where K: Deserialize<'de>+Ord, V: Deserialize<'de>
+{
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>
+    {
+        deserializer.deserialize_map(MapVisitor::<Self, K, V>(max_len, PhantomData))
+    }
+}
+
+
+struct StringVisitor(usize);
+
+impl<'de> Visitor<'de> for StringVisitor {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string of at most {} bytes", self.0)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+        match value.len() > self.0 {
+            true => Err(E::invalid_length(value.len(), &MaxLen(self.0))),
+            false => Ok(value.to_string()),
+        }
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<String, E> {
+        match value.len() > self.0 {
+            true => Err(E::invalid_length(value.len(), &MaxLen(self.0))),
+            false => Ok(value),
+        }
+    }
+}
+
+/// Strings' length is counted in bytes. Unlike other collections, they may
+/// be allocated by the deserializer before being checked.
+impl<'de> Bounded<'de> for String {
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>
+    {
+        deserializer.deserialize_string(StringVisitor(max_len))
+    }
+}
+
+
+struct OptionVisitor<T>(usize, PhantomData<T>);
+
+impl<'de, T: Bounded<'de>> Visitor<'de> for OptionVisitor<T> {
+    type Value = Option<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an option")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        T::deserialize_bounded(deserializer, self.0).map(Some)
+    }
+}
+
+/// Maximum length applies to the optional value.
+impl<'de, T: Bounded<'de>> Bounded<'de> for Option<T> {
+    fn deserialize_bounded<D: Deserializer<'de>>(deserializer: D, max_len: usize)
+        -> Result<Self, D::Error>
+    {
+        deserializer.deserialize_option(OptionVisitor(max_len, PhantomData))
+    }
+}
+
+
+
+/// Value deserialized with a maximum length of `N` items, for fields whose
+/// deserialization can't use `max_len` (see `rpc::stable`).
+pub struct Limited<T, const N: usize>(pub T);
+
+impl<'de, T: Bounded<'de>, const N: usize> Deserialize<'de> for Limited<T, N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize_bounded(deserializer, N).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::executor::block_on;
+    use futures::prelude::*;
+
+    use super::*;
+    use crate as rpccaps;
+    use crate::rpc::codec::{BincodeCodec, Decoder, Encoder, Framed};
+    use crate::rpc::service::Service as _;
+    use rpccaps_derive::*;
+
+    mod collections_service {
+        use super::*;
+
+        pub struct Service;
+
+        #[service]
+        impl Service {
+            fn values(&mut self, #[rpc(max_len=4)] values: Vec<u32>, offset: u32) -> u32 {
+                values.iter().sum::<u32>() + offset
+            }
+
+            fn tags(&mut self, #[rpc(max_len=2)] tags: Option<Vec<String>>) -> usize {
+                tags.map_or(0, |tags| tags.len())
+            }
+
+            fn entries(&mut self, #[rpc(max_len=2)] entries: HashMap<String, u32>,
+                       names: Option<String>) -> u32 {
+                entries.values().sum::<u32>() + names.map_or(0, |n| n.len() as u32)
+            }
+        }
+    }
+
+    use collections_service::{Request, Response};
+
+    fn encode(request: Request) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        BincodeCodec::new().encode(request, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn decode(mut buffer: BytesMut) -> Option<Request> {
+        BincodeCodec::<Request>::new().decode(&mut buffer).ok().flatten()
+    }
+
+    #[test]
+    fn test_max_len() {
+        assert!(decode(encode(Request::Values(vec![1, 2, 3, 4], 0))).is_some());
+        assert!(decode(encode(Request::Values(vec![1, 2, 3, 4, 5], 0))).is_none());
+        assert!(decode(encode(Request::Tags(None))).is_some());
+        assert!(decode(encode(Request::Tags(Some(vec![String::new(); 3])))).is_none());
+
+        let entries = (0..3).map(|i| (i.to_string(), i)).collect::<HashMap<_,_>>();
+        assert!(decode(encode(Request::Entries(entries.clone(), None))).is_none());
+        // unbounded arguments are not limited
+        let name = Some("a".repeat(64));
+        let entries = entries.into_iter().take(2).collect();
+        assert!(decode(encode(Request::Entries(entries, name))).is_some());
+
+        // announced length is checked before reading items
+        let mut buffer = encode(Request::Values(vec![], 0));
+        let header = u64::MAX.to_le_bytes();
+        buffer[12..20].copy_from_slice(&header);
+        assert!(decode(buffer).is_none());
+    }
+
+    #[test]
+    fn test_max_len_serve() {
+        let requests = [
+            encode(Request::Values(vec![1, 2], 3)),
+            encode(Request::Tags(Some(vec![String::from("a")]))),
+            encode(Request::Values(vec![1; 5], 0)),
+            encode(Request::Tags(None)),
+        ].concat();
+
+        let mut output = Vec::new();
+        block_on(collections_service::Service.serve_stream(
+            (&mut output, futures::io::Cursor::new(requests)),
+            BincodeCodec::new(), BincodeCodec::new()));
+
+        // serving stops at the oversized request
+        let responses = Framed::new(futures::io::Cursor::new(output),
+                                    BincodeCodec::<Response>::new());
+        let responses = block_on(responses.collect::<Vec<_>>());
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(responses[0], Response::Values(6)));
+        assert!(matches!(responses[1], Response::Tags(1)));
+    }
+}
@@ -0,0 +1,260 @@
+//! Execution time budgets of callers' identities.
+//!
+//! Capability bits tell which methods a caller may call, not how much of
+//! the server it may use: a legitimate holder can still monopolize it.
+//! `Budgets` accounts the time spent executing the handlers dispatched for
+//! each identity, against a budget renewed every period. Only time spent
+//! polling handlers' futures is counted, not the time they wait for I/O.
+//!
+//! When `ServerConfig::identity_budget` is set, the server meters streams
+//! of authenticated peers (identified by their certificate's fingerprint):
+//! once an identity's budget is exhausted, its new streams are reset with
+//! a `Rejection::BudgetExhausted` until the period is over. Time is
+//! accounted as it is spent, so that concurrent streams share the budget;
+//! running streams are aborted once it is exhausted, as cancelled calls
+//! are (see `rpc::cancel`). Accounting is exposed by `Server::budgets`.
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Clock, SystemClock};
+use super::enforce::Fingerprint;
+use super::reaper::Reap;
+
+
+/// Execution time allowed to an identity over a period.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub struct TimeBudget {
+    /// Execution time allowed per period.
+    pub budget: Duration,
+    /// Period after which budget is renewed.
+    pub period: Duration,
+}
+
+impl TimeBudget {
+    pub fn new(budget: Duration, period: Duration) -> Self {
+        Self { budget, period }
+    }
+}
+
+
+/// Accounting of an identity's execution time.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct BudgetUsage {
+    /// Start of the current period (since UNIX epoch).
+    pub period_start: Duration,
+    /// Execution time used during the current period.
+    pub used: Duration,
+    /// Execution time used since accounting started.
+    pub total: Duration,
+    /// Count of metered calls since accounting started.
+    pub calls: u64,
+}
+
+impl BudgetUsage {
+    /// Start a new period if the current one is over at `now`.
+    fn renew(&mut self, limit: &TimeBudget, now: Duration) {
+        if now >= self.period_start + limit.period {
+            self.period_start = now;
+            self.used = Duration::ZERO;
+        }
+    }
+}
+
+
+/// Execution time accounting per identity.
+pub struct Budgets<C: Clock=SystemClock> {
+    limit: TimeBudget,
+    clock: C,
+    usages: Mutex<BTreeMap<Fingerprint, BudgetUsage>>,
+}
+
+impl Budgets {
+    pub fn new(limit: TimeBudget) -> Self {
+        Self::with_clock(limit, SystemClock)
+    }
+}
+
+impl<C: Clock> Budgets<C> {
+    /// Create budgets whose periods are measured with `clock`.
+    pub fn with_clock(limit: TimeBudget, clock: C) -> Self {
+        Self { limit, clock, usages: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn limit(&self) -> &TimeBudget {
+        &self.limit
+    }
+
+    /// Return None when identity has budget left, or the duration until it
+    /// is renewed.
+    pub fn check(&self, identity: &Fingerprint) -> Option<Duration> {
+        let now = self.clock.now();
+        let mut usages = self.usages.lock().unwrap();
+        let usage = usages.get_mut(identity)?;
+        usage.renew(&self.limit, now);
+        match usage.used >= self.limit.budget {
+            true => Some(usage.period_start + self.limit.period - now),
+            false => None,
+        }
+    }
+
+    /// Account a call's execution time to identity.
+    pub fn record(&self, identity: Fingerprint, elapsed: Duration) {
+        self.account(identity, elapsed, 1);
+    }
+
+    /// Account execution time and count of calls to identity, returning
+    /// true if its budget is exhausted.
+    fn account(&self, identity: Fingerprint, elapsed: Duration, calls: u64) -> bool {
+        let now = self.clock.now();
+        let mut usages = self.usages.lock().unwrap();
+        let usage = usages.entry(identity)
+                          .or_insert_with(|| BudgetUsage { period_start: now, ..Default::default() });
+        usage.renew(&self.limit, now);
+        usage.used += elapsed;
+        usage.total += elapsed;
+        usage.calls += calls;
+        usage.used >= self.limit.budget
+    }
+
+    /// Return identity's accounting.
+    pub fn usage(&self, identity: &Fingerprint) -> Option<BudgetUsage> {
+        self.usages.lock().unwrap().get(identity).copied()
+    }
+
+    /// Return accounting of all identities.
+    pub fn snapshot(&self) -> BTreeMap<Fingerprint, BudgetUsage> {
+        self.usages.lock().unwrap().clone()
+    }
+
+    /// Meter the execution time of future as a call of identity,
+    /// accounting it after each poll. The future is aborted once the
+    /// identity's budget is exhausted.
+    pub fn metered<F: Future>(self: &Arc<Self>, identity: Fingerprint, fut: F) -> Metered<F, C> {
+        self.account(identity, Duration::ZERO, 1);
+        Metered { fut: Box::pin(fut), budgets: self.clone(), identity }
+    }
+}
+
+impl<C: Clock> Reap for Budgets<C> {
+    fn name(&self) -> &str {
+        "budgets"
+    }
+
+    /// Remove identities whose period is over: they are idle since then.
+    fn reap(&self, now: Duration) -> usize {
+        let mut usages = self.usages.lock().unwrap();
+        let len = usages.len();
+        usages.retain(|_, usage| now < usage.period_start + self.limit.period);
+        len - usages.len()
+    }
+}
+
+
+/// Future whose polling time is accounted to an identity. Resolves to
+/// None when aborted because identity's budget is exhausted.
+pub struct Metered<F: Future, C: Clock=SystemClock> {
+    fut: Pin<Box<F>>,
+    budgets: Arc<Budgets<C>>,
+    identity: Fingerprint,
+}
+
+impl<F: Future, C: Clock> Future for Metered<F, C> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let start = Instant::now();
+        let poll = self.fut.as_mut().poll(cx);
+        let exhausted = self.budgets.account(self.identity, start.elapsed(), 0);
+        match poll {
+            Poll::Ready(output) => Poll::Ready(Some(output)),
+            Poll::Pending if exhausted => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::data::clock::MockClock;
+    use super::*;
+
+    fn budgets() -> (Arc<Budgets<Arc<MockClock>>>, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let limit = TimeBudget::new(Duration::from_millis(10), Duration::from_secs(1));
+        (Arc::new(Budgets::with_clock(limit, clock.clone())), clock)
+    }
+
+    #[test]
+    fn test_budgets() {
+        let (budgets, clock) = budgets();
+        let (alice, bob) = ([1u8; 32], [2u8; 32]);
+        assert_eq!(budgets.check(&alice), None);
+
+        budgets.record(alice, Duration::from_millis(6));
+        assert_eq!(budgets.check(&alice), None);
+        budgets.record(alice, Duration::from_millis(6));
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(budgets.check(&alice), Some(Duration::from_millis(600)));
+        assert_eq!(budgets.check(&bob), None);
+
+        // budget is renewed with the period, not the total
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(budgets.check(&alice), None);
+        let usage = budgets.usage(&alice).unwrap();
+        assert_eq!((usage.used, usage.total, usage.calls), (Duration::ZERO, Duration::from_millis(12), 2));
+
+        // idle identities are reaped
+        clock.advance(Duration::from_millis(500));
+        budgets.record(bob, Duration::from_millis(1));
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(budgets.reap(clock.now()), 1);
+        assert_eq!(budgets.snapshot().keys().collect::<Vec<_>>(), vec![&bob]);
+    }
+
+    #[test]
+    fn test_metered() {
+        let (budgets, _) = budgets();
+        let identity = [1u8; 32];
+        let mut yielded = false;
+        let output = futures::executor::block_on(budgets.metered(identity, async move {
+            std::thread::sleep(Duration::from_millis(5));
+            // time between polls is not counted
+            future::poll_fn(|cx| match std::mem::replace(&mut yielded, true) {
+                true => Poll::Ready(()),
+                false => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                },
+            }).await;
+            std::thread::sleep(Duration::from_millis(5));
+            1
+        }));
+        assert_eq!(output, Some(1));
+        let usage = budgets.usage(&identity).unwrap();
+        assert!(usage.used >= Duration::from_millis(10));
+        assert_eq!(usage.calls, 1);
+        assert!(budgets.check(&identity).is_some());
+    }
+
+    #[test]
+    fn test_metered_exhausted() {
+        let (budgets, _) = budgets();
+        let identity = [1u8; 32];
+        // time is accounted while running, not once complete
+        let mut pending = budgets.metered(identity, future::pending::<()>());
+        futures::executor::block_on(async {
+            assert!(futures::poll!(&mut pending).is_pending());
+            budgets.record(identity, Duration::from_millis(10));
+            // running future is aborted at its next poll
+            assert_eq!(pending.await, None);
+        });
+        assert_eq!(budgets.usage(&identity).unwrap().calls, 2);
+    }
+}
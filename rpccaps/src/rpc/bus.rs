@@ -0,0 +1,156 @@
+//! In-process bus of typed events between services of a server.
+//!
+//! Unlike the network events of `ServerEvents`, the bus carries application
+//! events from one service to others running in the same server: each event
+//! type is a topic, published and subscribed by type. The server's bus is
+//! registered among its dependencies, thus resolved with `Dep<EventBus>`:
+//!
+//! ```ignore
+//! // auth service announces new identities...
+//! server.add_context_builder(AUTH, |Dep(bus): Dep<EventBus>| auth::Service::new(bus), options)?;
+//! // ...and quota service listens to them.
+//! let mut identities = server.bus.subscribe::<NewIdentity>(32);
+//! ```
+//!
+//! As for server events, events are published without waiting: when a
+//! subscriber's queue is full, the event is dropped for this subscriber.
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+
+use super::reaper::Reap;
+
+
+/// Subscribers of an event type.
+struct Topic<E> {
+    subscribers: Mutex<Vec<mpsc::Sender<E>>>,
+}
+
+/// Topic of any event type.
+trait AnyTopic: Send+Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    /// Remove closed subscribers, returning their count.
+    fn reap(&self) -> usize;
+}
+
+impl<E: Send+'static> AnyTopic for Topic<E> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn reap(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let len = subscribers.len();
+        subscribers.retain(|sender| !sender.is_closed());
+        len - subscribers.len()
+    }
+}
+
+
+/// Bus of events published and subscribed by type.
+#[derive(Default)]
+pub struct EventBus {
+    topics: RwLock<HashMap<TypeId, Box<dyn AnyTopic>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to events of type `E`, returning stream of events. At most
+    /// `capacity` events are queued.
+    pub fn subscribe<E: Clone+Send+'static>(&self, capacity: usize) -> mpsc::Receiver<E> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let mut topics = self.topics.write().unwrap();
+        let topic = topics.entry(TypeId::of::<E>())
+                          .or_insert_with(|| Box::new(Topic::<E> { subscribers: Mutex::new(Vec::new()) }));
+        topic.as_any().downcast_ref::<Topic<E>>().unwrap()
+             .subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Return true if there are subscribers to events of type `E`.
+    pub fn has_subscribers<E: Send+'static>(&self) -> bool {
+        self.with_topic(|topic: &Topic<E>| !topic.subscribers.lock().unwrap().is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Publish event to its type's subscribers, removing closed ones.
+    /// Return the count of subscribers it was queued for.
+    pub fn publish<E: Clone+Send+'static>(&self, event: E) -> usize {
+        self.with_topic(|topic: &Topic<E>| {
+            let mut queued = 0;
+            topic.subscribers.lock().unwrap().retain_mut(|sender| match sender.try_send(event.clone()) {
+                Ok(_) => {
+                    queued += 1;
+                    true
+                },
+                Err(err) => !err.is_disconnected(),
+            });
+            queued
+        }).unwrap_or(0)
+    }
+
+    /// Call `func` with topic of events `E`, if any.
+    fn with_topic<E: Send+'static, T>(&self, func: impl FnOnce(&Topic<E>) -> T) -> Option<T> {
+        let topics = self.topics.read().unwrap();
+        topics.get(&TypeId::of::<E>())
+              .and_then(|topic| topic.as_any().downcast_ref::<Topic<E>>())
+              .map(func)
+    }
+}
+
+impl Reap for EventBus {
+    fn name(&self) -> &str {
+        "bus"
+    }
+
+    /// Remove closed subscribers.
+    fn reap(&self, _now: Duration) -> usize {
+        self.topics.read().unwrap().values().map(|topic| topic.reap()).sum()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use futures::executor::LocalPool;
+
+    use super::*;
+
+    #[derive(Clone,Debug,PartialEq)]
+    struct NewIdentity([u8; 32]);
+
+    #[test]
+    fn test_bus() {
+        let bus = EventBus::new();
+        assert_eq!(bus.publish(NewIdentity([0; 32])), 0);
+
+        let mut identities = bus.subscribe::<NewIdentity>(0);
+        let mut names = bus.subscribe::<String>(0);
+        assert!(bus.has_subscribers::<NewIdentity>() && !bus.has_subscribers::<u32>());
+        drop(bus.subscribe::<NewIdentity>(1));
+        assert_eq!(bus.reap(Duration::ZERO), 1);
+
+        LocalPool::new().run_until(async {
+            assert_eq!(bus.publish(NewIdentity([1; 32])), 1);
+            assert_eq!(bus.publish(String::from("alice")), 1);
+            assert_eq!(identities.next().await, Some(NewIdentity([1; 32])));
+            assert_eq!(names.next().await.as_deref(), Some("alice"));
+
+            // full queues drop events, closed ones are removed
+            drop(names);
+            assert_eq!(bus.publish(NewIdentity([2; 32])), 1);
+            assert_eq!(bus.publish(NewIdentity([3; 32])), 0);
+            assert_eq!(identities.next().await, Some(NewIdentity([2; 32])));
+            assert_eq!(bus.publish(String::from("bob")), 0);
+            assert!(!bus.has_subscribers::<String>());
+        });
+    }
+}
@@ -0,0 +1,187 @@
+//! Options and errors of generated clients' calls.
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+
+use super::message::{Error, SlowDown};
+
+
+/// Options of a generated client, provided to `Client::with_options`.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct ClientOptions {
+    /// Maximum duration waiting for a call's response, forever when `None`.
+    ///
+    /// Timeouts are driven by tokio's timer, thus requiring clients to run
+    /// in a tokio runtime when set.
+    pub request_timeout: Option<Duration>,
+    /// Send the trace context of each call ahead of its request (see
+    /// `rpc::trace`). Servers of protocol versions before `version::TRACE`
+    /// can't decode it.
+    pub trace: bool,
+}
+
+impl ClientOptions {
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+}
+
+
+/// Error returned by a generated client's calls.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum CallError {
+    /// No response has been received before client's request timeout.
+    Timeout,
+    /// Transport has been closed or failed, or an unexpected response has
+    /// been received.
+    Failed,
+    /// Server asked to wait `retry_after` before calling the method again.
+    /// Next call of the method waits for it.
+    SlowDown { retry_after: Duration },
+    /// Server failed to handle the call.
+    Server(Error),
+    /// A previous call has been abandoned before its last response was
+    /// received, which is left in the transport: the client can't tell it
+    /// from the responses of next calls, and must be created again over a
+    /// new transport.
+    Desynced,
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "call timed out"),
+            Self::Failed => write!(f, "call failed"),
+            Self::SlowDown { retry_after } =>
+                write!(f, "call slowed down, retry after {:?}", retry_after),
+            Self::Server(err) => write!(f, "call failed on server: {}", err),
+            Self::Desynced => write!(f, "client is out of sync with its transport"),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+
+/// Await `fut`, returning `CallError::Timeout` if it does not complete
+/// before `timeout`.
+pub async fn with_timeout<F: Future>(timeout: Option<Duration>, fut: F)
+    -> Result<F::Output, CallError>
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.or(Err(CallError::Timeout)),
+        None => Ok(fut.await),
+    }
+}
+
+
+/// Backoff requested by the server, per method.
+#[derive(Clone,Debug,Default)]
+pub struct Backoff {
+    until: BTreeMap<&'static str, Instant>,
+}
+
+impl Backoff {
+    /// Record server's slow down of `method`, returning the call's error.
+    pub fn slow_down(&mut self, method: &'static str, slow_down: SlowDown) -> CallError {
+        let SlowDown { retry_after } = slow_down;
+        if let Some(until) = Instant::now().checked_add(retry_after) {
+            self.until.insert(method, until);
+        }
+        CallError::SlowDown { retry_after }
+    }
+
+    /// Remaining duration before `method` can be called again.
+    pub fn remaining(&self, method: &str) -> Option<Duration> {
+        let until = self.until.get(method)?;
+        until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())
+    }
+
+    /// Wait until `method` can be called again. This requires a tokio
+    /// runtime when the method has been slowed down.
+    pub async fn wait(&mut self, method: &str) {
+        if let Some(remaining) = self.remaining(method) {
+            tokio::time::sleep(remaining).await;
+        }
+        self.until.remove(method);
+    }
+}
+
+
+/// Call of an unordered method whose response has not been received yet,
+/// as returned by generated clients' `start_*` methods. The call is
+/// cancelled when dropped before its response is received.
+///
+/// Cancellation requires the server to be of `rpc::version::CANCEL` or
+/// later. Responses of other (e.g. cancelled) calls received meanwhile are
+/// skipped.
+pub struct Pending<'a, T, Req, Resp, Out>
+    where T: Sink<Req>+Unpin
+{
+    transport: &'a mut T,
+    call_id: u64,
+    timeout: Option<Duration>,
+    /// Return the request cancelling a call.
+    cancel: fn(u64) -> Req,
+    /// Return the result of a call from a response, None if the response
+    /// is another call's one.
+    output: fn(u64, Resp) -> Option<Result<Out, CallError>>,
+    done: bool,
+}
+
+impl<'a, T, Req, Resp, Out> Pending<'a, T, Req, Resp, Out>
+    where T: Stream<Item=Resp>+Sink<Req>+Unpin
+{
+    pub fn new(transport: &'a mut T, call_id: u64, timeout: Option<Duration>,
+               cancel: fn(u64) -> Req, output: fn(u64, Resp) -> Option<Result<Out, CallError>>)
+        -> Self
+    {
+        Self { transport, call_id, timeout, cancel, output, done: false }
+    }
+
+    /// Id of the call.
+    pub fn call_id(&self) -> u64 {
+        self.call_id
+    }
+
+    /// Wait for call's response, until request timeout. The call is
+    /// cancelled when it times out.
+    pub async fn response(mut self) -> Result<Out, CallError> {
+        let (transport, call_id, output) = (&mut *self.transport, self.call_id, self.output);
+        let result = with_timeout(self.timeout, async move {
+            while let Some(resp) = transport.next().await {
+                if let Some(result) = output(call_id, resp) {
+                    return result
+                }
+            }
+            Err(CallError::Failed)
+        }).await;
+        self.done = !matches!(result, Err(CallError::Timeout));
+        result.and_then(|result| result)
+    }
+
+    /// Cancel the call: server aborts its dispatch.
+    pub async fn cancel(mut self) -> Result<(), CallError> {
+        self.done = true;
+        self.transport.send((self.cancel)(self.call_id)).await.or(Err(CallError::Failed))
+    }
+}
+
+impl<'a, T, Req, Resp, Out> Drop for Pending<'a, T, Req, Resp, Out>
+    where T: Sink<Req>+Unpin
+{
+    /// Cancel call if it is not done. Cancellation is not sent if the
+    /// transport is not ready.
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.transport.send((self.cancel)(self.call_id)).now_or_never();
+        }
+    }
+}
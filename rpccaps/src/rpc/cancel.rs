@@ -0,0 +1,147 @@
+//! Cancellation safety of services.
+//!
+//! A call's dispatch future can be dropped before completion: when the
+//! client cancels an unordered call, or when a handler registered with
+//! `HandlerOptions::timeout` exceeds it. Dropping it at an `.await` point
+//! leaves the service in whatever state it was at this point, e.g. with
+//! half of a transfer applied, a lock file left behind, or an entry
+//! removed from a map but not yet inserted into another one.
+//!
+//! A service is cancellation-safe when this can't happen: each `.await`
+//! of its methods is a point at which stopping is harmless, such as
+//! stateless methods or ones mutating state only after their last
+//! `.await`. Services assert it with `#[service(cancellation_safe)]` (or
+//! by returning true from `Service::is_cancellation_safe()`), or by being
+//! wrapped into a `CancellationSafe`:
+//!
+//! ```ignore
+//! dispatch.add_builder(0, Box::new(|_| CancellationSafe::new(echo::Service)),
+//!                      HandlerOptions { timeout: Some(timeout), ..Default::default() })?;
+//! ```
+//!
+//! Cancelled calls of other services run to completion, their responses
+//! being replaced by a `message::Error::Cancelled`. Their timed out calls
+//! are handled following `HandlerOptions::unsafe_timeout`: run to
+//! completion on a detached tokio task by default, dropped as the ones of
+//! cancellation-safe services (`UnsafeTimeout::Drop`), or their
+//! registration with a timeout is refused (`UnsafeTimeout::Refuse`).
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Service asserted cancellation-safe, whose calls can be aborted.
+#[derive(Clone)]
+pub struct CancellationSafe<S: Service>(S);
+
+impl<S: Service> CancellationSafe<S> {
+    pub fn new(inner: S) -> Self {
+        Self(inner)
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl<S: Service> Deref for CancellationSafe<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S: Service> DerefMut for CancellationSafe<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.0
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for CancellationSafe<S>
+    where S::Response: 'static
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.0.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.0.capability()
+    }
+
+    fn is_cancellation_safe() -> bool {
+        true
+    }
+
+    super::service::forward_requests!(@requests S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        self.0.dispatch(request).await
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        self.0.dispatch_streaming(request).await
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        self.0.dispatch_incoming(request, requests).await
+    }
+}
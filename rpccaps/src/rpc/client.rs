@@ -0,0 +1,916 @@
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{ErrorKind, Result};
+use crate::data::ObjectId;
+use crate::data::presentation::ChannelBinding;
+use super::backpressure::{Pressure, Watch, Watermarks};
+use super::call::CallError;
+use super::codec::{BincodeCodec, CodecLimits, Framed};
+use super::config::{Balance, ClientConfig};
+use super::context;
+use super::handshake;
+use super::message::Control;
+use super::pipeline::Pipeline;
+use super::protocol::{Checked, Frame, Peer};
+use super::schema;
+use super::service::Service;
+use super::trace::{self, Instrument};
+use super::transport::Transport;
+
+
+/// Transport of a service's stream, to be wrapped by its generated `Client`.
+pub type ClientTransport<Req, Resp> = Transport<Framed<quinn::SendStream, BincodeCodec<Req>>,
+                                                Framed<quinn::RecvStream, BincodeCodec<Resp>>>;
+
+/// Transport of a service's stream, checking messages against the protocol.
+pub type CheckedTransport<Sv> = Checked<ClientTransport<<Sv as Service>::Request, <Sv as Service>::Response>,
+                                        <Sv as Service>::Response, <Sv as Service>::Request>;
+
+
+/// Stream counted among its client's pending streams until dropped.
+struct Pending {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Pending {
+    fn new(pending: Arc<AtomicUsize>) -> Self {
+        pending.fetch_add(1, Ordering::Relaxed);
+        Self { pending }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Event pushed by the server to the client.
+#[derive(Clone,Debug,PartialEq)]
+pub enum ClientEvent {
+    /// Reference to this object has been revoked.
+    Revoked(ObjectId),
+    /// Send buffer of a stream crossed a watermark of
+    /// `Client::with_backpressure()`.
+    Backpressure(quinn::StreamId, Pressure),
+}
+
+
+/// Revoked references and events' subscribers.
+#[derive(Default)]
+struct ControlState {
+    revoked: BTreeSet<ObjectId>,
+    subscribers: Vec<mpsc::Sender<ClientEvent>>,
+    /// Connection has been closed or lost.
+    closed: bool,
+}
+
+impl ControlState {
+    fn handle(&mut self, message: Control) {
+        let event = match message {
+            Control::Revoked(id) => {
+                self.revoked.insert(id);
+                ClientEvent::Revoked(id)
+            },
+        };
+        self.emit(event);
+    }
+
+    fn emit(&mut self, event: ClientEvent) {
+        self.subscribers.retain_mut(|sender| {
+            !matches!(sender.try_send(event.clone()), Err(err) if err.is_disconnected())
+        });
+    }
+}
+
+
+/// Client connected to a server over QUIC, opening a stream for each
+/// service it uses.
+///
+/// Control messages pushed by the server (such as revocations) are handled
+/// in background, and provided to subscribers as events, along with the
+/// backpressure of streams' send buffers.
+pub struct Client {
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    control: Arc<Mutex<ControlState>>,
+    /// Watermarks of opened streams' send buffers.
+    backpressure: Option<Watermarks>,
+    /// Count of opened streams not yet dropped.
+    pending: Arc<AtomicUsize>,
+    /// Limits of opened streams' messages.
+    limits: CodecLimits,
+    /// Maximum duration of services' schema validation.
+    handshake_timeout: Option<Duration>,
+}
+
+impl Client {
+    /// Connect to server at provided address, using `server_name` for its
+    /// certificate validation.
+    pub async fn connect(config: &ClientConfig, address: SocketAddr, server_name: &str)
+        -> Result<Self>
+    {
+        let bind = match address {
+            SocketAddr::V4(_) => SocketAddr::from(([0,0,0,0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16;8], 0)),
+        };
+        let mut endpoint = quinn::Endpoint::client(bind)
+                .or(ErrorKind::Endpoint.err("can't init endpoint"))?;
+        endpoint.set_default_client_config(config.get_client_config()?);
+
+        let connecting = endpoint.connect(address, server_name)
+                .or_else(|err| ErrorKind::Endpoint.err(err.to_string()))?;
+        let quinn::NewConnection { connection, uni_streams, .. } = connecting.await
+                .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        Ok(Self::new(endpoint, connection, uni_streams)
+               .with_limits(config.connection_config.codec_limits)
+               .with_handshake_timeout(config.connection_config.handshake_timeout))
+    }
+
+    /// Client over an established connection, receiving control messages
+    /// from `uni_streams`.
+    pub fn new(endpoint: quinn::Endpoint, connection: quinn::Connection,
+               uni_streams: quinn::IncomingUniStreams) -> Self
+    {
+        let control = Arc::new(Mutex::new(ControlState::default()));
+        let span = trace::connection(connection.remote_address(), connection.stable_id());
+        tokio::spawn(Self::receive_control(uni_streams, control.clone()).instrument(span));
+        Self { endpoint, connection, control, backpressure: None, pending: Default::default(),
+               limits: CodecLimits::default(), handshake_timeout: None }
+    }
+
+    /// Enforce provided limits on messages of streams opened from now on.
+    pub fn with_limits(mut self, limits: CodecLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Fail opening services whose schema is not received within
+    /// `timeout` (see `service()`).
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Emit `ClientEvent::Backpressure` when the send buffer of a stream
+    /// opened from now on crosses provided watermarks (in bytes).
+    pub fn with_backpressure(mut self, marks: Watermarks) -> Self {
+        self.backpressure = Some(marks);
+        self
+    }
+
+    /// Read control messages pushed by the server.
+    async fn receive_control(mut streams: quinn::IncomingUniStreams,
+                             control: Arc<Mutex<ControlState>>)
+    {
+        while let Some(Ok(stream)) = streams.next().await {
+            let mut messages = Framed::new(stream, BincodeCodec::<Control>::new());
+            while let Some(message) = messages.next().await {
+                control.lock().unwrap().handle(message);
+            }
+        }
+        control.lock().unwrap().closed = true;
+    }
+
+    /// Subscribe to events pushed by the server. At most `capacity` events
+    /// are queued.
+    pub fn subscribe(&self, capacity: usize) -> mpsc::Receiver<ClientEvent> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.control.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// Return true if server revoked reference to the object `id`.
+    pub fn is_revoked(&self, id: &ObjectId) -> bool {
+        self.control.lock().unwrap().revoked.contains(id)
+    }
+
+    /// Return client's endpoint.
+    pub fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+
+    /// Return underlying connection.
+    pub fn connection(&self) -> &quinn::Connection {
+        &self.connection
+    }
+
+    /// Return channel binding material derived from the TLS session, as
+    /// the server gets it from its `Context`.
+    pub fn channel_binding(&self) -> Result<ChannelBinding> {
+        context::channel_binding(&self.connection)
+    }
+
+    /// Open a new stream to the service registered at `id` on the server,
+    /// returning transport of its requests and responses.
+    pub async fn open<Id, Req, Resp>(&self, id: Id) -> Result<ClientTransport<Req, Resp>>
+        where Id: Serialize, Req: Serialize, Resp: DeserializeOwned
+    {
+        let (mut sender, receiver) = self.connection.open_bi().await
+                .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+
+        sender.write_all(&handshake::header(id)?).await
+                .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+
+        let stream = sender.id();
+        let mut sender = Framed::new(sender, BincodeCodec::with_limits(self.limits))
+                             .with_guard(Pending::new(self.pending.clone()));
+        if let Some(marks) = self.backpressure {
+            let control = self.control.clone();
+            sender = sender.with_watch(Watch::new(marks, move |pressure| {
+                control.lock().unwrap().emit(ClientEvent::Backpressure(stream, pressure))
+            }));
+        }
+        Ok(Transport::new(sender, Framed::new(receiver, BincodeCodec::with_limits(self.limits))))
+    }
+
+    /// Open a new stream to service `Sv` registered at `id`. Its messages
+    /// are checked against the protocol: responses stream ends when the
+    /// server violates it.
+    ///
+    /// The server's schema of the service is validated against `Sv`'s one
+    /// (see `rpc::schema`), failing on mismatch.
+    pub async fn service<Sv, Id>(&self, id: Id) -> Result<CheckedTransport<Sv>>
+        where Sv: Service, Id: Serialize,
+              Sv::Request: Serialize, Sv::Response: DeserializeOwned
+    {
+        let transport = self.open(id).await?;
+        let mut transport = Checked::new(transport, Peer::Client, Sv::response_frame, Sv::request_frame);
+        schema::validate::<Sv, _>(&mut transport, self.handshake_timeout).await?;
+        Ok(transport)
+    }
+
+    /// Return a new pipeline composing calls over this client's connection.
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    /// Count of opened streams whose transport is not yet dropped.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Return connection's current round-trip time estimate.
+    pub fn rtt(&self) -> Duration {
+        self.connection.rtt()
+    }
+
+    /// Return true once connection has been closed or lost.
+    pub fn is_closed(&self) -> bool {
+        self.control.lock().unwrap().closed
+    }
+
+    /// Close connection.
+    pub fn close(&self) {
+        self.control.lock().unwrap().closed = true;
+        self.connection.close(0u32.into(), b"");
+    }
+}
+
+
+/// Options of a `Pool`.
+#[derive(Clone,Copy,Debug)]
+pub struct PoolOptions {
+    /// Connections per server.
+    pub connections: usize,
+    /// Delay before reconnecting a lost connection, doubled after each
+    /// failed attempt.
+    pub min_backoff: Duration,
+    /// Maximum delay between reconnection attempts.
+    pub max_backoff: Duration,
+    /// Maximum times an idempotent call is re-issued.
+    pub retries: u32,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self { connections: 2, min_backoff: Duration::from_millis(100),
+               max_backoff: Duration::from_secs(10), retries: 3 }
+    }
+}
+
+
+/// Pooled connection.
+struct Slot {
+    address: SocketAddr,
+    server_name: String,
+    client: Option<Arc<Client>>,
+    /// Consecutive failed connection attempts.
+    failures: u32,
+    /// No connection is attempted before it.
+    retry_at: Option<Instant>,
+    /// Set by the task claiming the slot's connection attempt.
+    connecting: bool,
+}
+
+impl Slot {
+    /// Return open client, if any.
+    fn client(&mut self) -> Option<Arc<Client>> {
+        match self.client {
+            Some(ref client) if !client.is_closed() => Some(client.clone()),
+            _ => {
+                self.client = None;
+                None
+            }
+        }
+    }
+}
+
+
+/// Connections to the servers of a `ClientConfig`, spreading streams
+/// across them following its `balance` policy.
+///
+/// Lost connections are re-established on demand, with an exponential
+/// backoff between failed attempts: meanwhile, streams are opened on the
+/// other connections. Idempotent calls can be re-issued over a new stream
+/// when they fail (`Pool::idempotent()`).
+///
+/// Connections are established and awaited by tokio, thus requiring the
+/// pool to run in a tokio runtime.
+pub struct Pool {
+    config: ClientConfig,
+    options: PoolOptions,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// Return pool of `options.connections` connections to each of
+    /// `config.servers`. Fail when no connection can be established.
+    pub async fn connect(config: ClientConfig, options: PoolOptions) -> Result<Self> {
+        let slots = config.servers.iter().flat_map(|(address, server_name)| {
+            (0..options.connections.max(1)).map(move |_| Mutex::new(Slot {
+                address: *address, server_name: server_name.clone(), client: None,
+                failures: 0, retry_at: None, connecting: true,
+            }))
+        }).collect();
+        let pool = Self { config, options, slots, next: AtomicUsize::new(0) };
+        let mut connected = 0;
+        for index in 0..pool.slots.len() {
+            if pool.reconnect(index).await.is_some() {
+                connected += 1;
+            }
+        }
+        match connected {
+            0 => ErrorKind::IO.err("can not connect to any server"),
+            _ => Ok(pool),
+        }
+    }
+
+    /// Count of open connections.
+    pub fn connected(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.lock().unwrap().client().is_some()).count()
+    }
+
+    /// Return the open connection picked by the balance policy (ties
+    /// being served in turn), reconnecting lost ones whose backoff
+    /// elapsed. When all of them are backing off, wait for the first one
+    /// to be retried.
+    pub async fn client(&self) -> Result<Arc<Client>> {
+        let count = self.slots.len();
+        if count == 0 {
+            return ErrorKind::IO.err("pool has no server")
+        }
+        for _ in 0..=self.options.retries {
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let mut retry_at: Option<Instant> = None;
+            let mut best: Option<(u128, Arc<Client>)> = None;
+            for index in (start..start + count).map(|i| i % count) {
+                let due = {
+                    let mut slot = self.slots[index].lock().unwrap();
+                    if let Some(client) = slot.client() {
+                        match self.cost(&client) {
+                            0 => return Ok(client),
+                            cost if best.as_ref().is_none_or(|(c, _)| cost < *c) =>
+                                best = Some((cost, client)),
+                            _ => {},
+                        }
+                        continue
+                    }
+                    match slot.retry_at {
+                        Some(at) if at > Instant::now() => {
+                            retry_at = Some(retry_at.map_or(at, |r| r.min(at)));
+                            false
+                        },
+                        // claimed under the same lock as checked
+                        _ if !slot.connecting => {
+                            slot.connecting = true;
+                            true
+                        },
+                        _ => false,
+                    }
+                };
+                if due {
+                    if let Some(client) = self.reconnect(index).await {
+                        return Ok(client)
+                    }
+                }
+            }
+            if let Some((_, client)) = best {
+                return Ok(client)
+            }
+            match retry_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => break,
+            }
+        }
+        ErrorKind::IO.err("no connection available")
+    }
+
+    /// Cost of opening a stream on `client`, by the balance policy.
+    fn cost(&self, client: &Client) -> u128 {
+        match self.config.balance {
+            Balance::RoundRobin => 0,
+            Balance::LeastPending => client.pending() as u128,
+            Balance::Latency => client.rtt().as_micros() * (client.pending() as u128 + 1),
+        }
+    }
+
+    /// Connect slot at `index`, scheduling its next attempt on failure.
+    /// The caller must have claimed the slot (setting `connecting`).
+    async fn reconnect(&self, index: usize) -> Option<Arc<Client>> {
+        let (address, server_name) = {
+            let slot = self.slots[index].lock().unwrap();
+            (slot.address, slot.server_name.clone())
+        };
+        let client = Client::connect(&self.config, address, &server_name).await.ok().map(Arc::new);
+
+        let mut slot = self.slots[index].lock().unwrap();
+        slot.connecting = false;
+        match client {
+            Some(ref client) => {
+                slot.client = Some(client.clone());
+                slot.failures = 0;
+                slot.retry_at = None;
+            },
+            None => {
+                slot.retry_at = Some(Instant::now() + self.backoff(slot.failures));
+                slot.failures += 1;
+            },
+        }
+        client
+    }
+
+    /// Delay after `failures` consecutive failed attempts.
+    fn backoff(&self, failures: u32) -> Duration {
+        self.options.min_backoff.saturating_mul(1 << failures.min(16)).min(self.options.max_backoff)
+    }
+
+    /// Open a new stream to the service registered at `id`, on the next
+    /// connection able to open it.
+    pub async fn open<Id, Req, Resp>(&self, id: Id) -> Result<ClientTransport<Req, Resp>>
+        where Id: Serialize+Clone, Req: Serialize, Resp: DeserializeOwned
+    {
+        for _ in 0..self.slots.len() {
+            let client = self.client().await?;
+            match client.open(id.clone()).await {
+                Ok(transport) => return Ok(transport),
+                // connection is lost
+                Err(err) if err.kind() == ErrorKind::IO => client.close(),
+                Err(err) => return Err(err),
+            }
+        }
+        ErrorKind::IO.err("no connection available")
+    }
+
+    /// Open a new stream to service `Sv` registered at `id`, checking its
+    /// messages against the protocol and validating its schema.
+    pub async fn service<Sv, Id>(&self, id: Id) -> Result<CheckedTransport<Sv>>
+        where Sv: Service, Id: Serialize+Clone,
+              Sv::Request: Serialize, Sv::Response: DeserializeOwned
+    {
+        let transport = self.open(id).await?;
+        let mut transport = Checked::new(transport, Peer::Client, Sv::response_frame, Sv::request_frame);
+        let timeout = self.config.connection_config.handshake_timeout;
+        schema::validate::<Sv, _>(&mut transport, timeout).await?;
+        Ok(transport)
+    }
+
+    /// Run `call` over a new stream to service `Sv`, re-issuing it on
+    /// another stream (reconnecting if needed) while the stream fails, up
+    /// to `options.retries` times, waiting a backoff in between.
+    ///
+    /// As the server may have handled the call before the failure, it is
+    /// only re-issued when all of its requests are idempotent (see
+    /// `Service::is_idempotent()`), and not when it was denied.
+    pub async fn idempotent<Sv, Id, F, Fut, T>(&self, id: Id, mut call: F)
+        -> std::result::Result<T, CallError>
+        where Sv: Service, Id: Serialize+Clone,
+              Sv::Request: Serialize, Sv::Response: DeserializeOwned,
+              F: FnMut(Attempt<Sv>) -> Fut,
+              Fut: Future<Output=std::result::Result<T, CallError>>
+    {
+        let mut attempts = 0;
+        loop {
+            let retriable = Arc::new(AtomicBool::new(true));
+            let result = match self.service::<Sv, Id>(id.clone()).await {
+                Ok(transport) => call(Attempt { transport, retriable: retriable.clone() }).await,
+                Err(_) => Err(CallError::Failed),
+            };
+            match result {
+                Err(CallError::Failed) if attempts < self.options.retries
+                                          && retriable.load(Ordering::Relaxed) => {
+                    tokio::time::sleep(self.backoff(attempts)).await;
+                    attempts += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+
+/// Transport of an attempt of `Pool::idempotent()`, recording whether the
+/// call can be re-issued: it can't once a non-idempotent request was sent
+/// or a request was denied.
+pub struct Attempt<Sv: Service> {
+    transport: CheckedTransport<Sv>,
+    retriable: Arc<AtomicBool>,
+}
+
+impl<Sv: Service> Stream for Attempt<Sv>
+    where Sv::Response: DeserializeOwned
+{
+    type Item = Sv::Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let item = futures::ready!(self.transport.poll_next_unpin(cx));
+        if let Some(Frame::Denied) = item.as_ref().and_then(Sv::response_frame) {
+            self.retriable.store(false, Ordering::Relaxed);
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl<Sv: Service> Sink<Sv::Request> for Attempt<Sv>
+    where Sv::Request: Serialize
+{
+    type Error = <CheckedTransport<Sv> as Sink<Sv::Request>>::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Sv::Request) -> std::result::Result<(), Self::Error> {
+        if !Sv::is_idempotent(&item) {
+            self.retriable.store(false, Ordering::Relaxed);
+        }
+        self.transport.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_close_unpin(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use super::super::config::ServerConfig;
+    use super::super::context::{Context, DefaultContext};
+    use super::super::dispatch::HandlerOptions;
+    use super::super::handshake::{CodecId, Handshake};
+    use super::super::message::Rejection;
+    use super::super::server::Server;
+    use super::super::service::tests::{simple_service, simple_service_2};
+    use crate::data::tls;
+
+    #[test]
+    fn test_client() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = env::temp_dir().join("rpccaps-test-client-cert.der");
+        fs::write(&cert_path, &certs[0].0).unwrap();
+
+        let mut server_config = ServerConfig::default();
+        server_config.connection_config.cert_data = Some((certs, key));
+        server_config.connection_config.alpn_protocols = vec![b"rpccaps/test".to_vec()];
+        let mut client_config = ClientConfig::default();
+        client_config.root_certs.push(cert_path);
+        client_config.connection_config.alpn_protocols = vec![b"rpccaps/test".to_vec()];
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut server = Server::<u32>::new(server_config);
+            server.dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                                        HandlerOptions::default()).unwrap();
+            // builders get peer's information from context
+            let peer_info = Arc::new(std::sync::Mutex::new(None));
+            let peer_info_ = peer_info.clone();
+            server.dispatch.add_builder(1, Box::new(move |context: Arc<DefaultContext>| {
+                *peer_info_.lock().unwrap() = Some(context.peer_info());
+                simple_service::Service::new()
+            }), HandlerOptions::default()).unwrap();
+            // anonymous peers are not allowed to pinned ids
+            server.dispatch.pin(2, [[0u8; 32]]);
+            server.dispatch.add_builder(3, Box::new(|_| simple_service_2::Service::new()),
+                                        HandlerOptions::default()).unwrap();
+            let revocations = server.revocations.clone();
+            let (endpoint, incoming) = server.get_endpoint(SocketAddr::from(([127,0,0,1], 0))).unwrap();
+            let address = endpoint.local_addr().unwrap();
+            tokio::spawn(async move { server.dispatch_incoming(endpoint, incoming).await });
+
+            let client = Client::connect(&client_config, address, "localhost").await.unwrap();
+            let transport = client.service::<simple_service::Service, u32>(0).await.unwrap();
+            let mut service = simple_service::Client::new(transport);
+            assert_eq!(service.validate_schema().await, Ok(()));
+            assert_eq!(service.add(13).await, Ok(13));
+            assert_eq!(service.sub(1).await, Ok(12));
+
+            // schema is validated when opening the stream
+            let err = client.service::<simple_service::Service, u32>(3).await.err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(client.service::<simple_service_2::Service, u32>(3).await.is_ok());
+
+            let transport = client.service::<simple_service::Service, u32>(1).await.unwrap();
+            assert_eq!(simple_service::Client::new(transport).add(1).await, Ok(1));
+            let peer_info = peer_info.lock().unwrap().take().unwrap();
+            assert_eq!(peer_info.address.ip(), address.ip());
+            assert_eq!(peer_info.alpn_protocol.as_deref(), Some(&b"rpccaps/test"[..]));
+            assert_eq!(peer_info.server_name.as_deref(), Some("localhost"));
+            assert!(!peer_info.is_authenticated());
+
+            // revocations are pushed to connections holding the reference
+            let mut events = client.subscribe(4);
+            let (held, other) = (ObjectId([1; 32]), ObjectId([2; 32]));
+            let connection = revocations.connections()[0];
+            revocations.hold(connection, held).unwrap();
+            assert_eq!(revocations.revoke(other, None), 0);
+            assert_eq!(revocations.revoke(held, None), 1);
+            assert_eq!(events.next().await, Some(ClientEvent::Revoked(held)));
+            assert!(client.is_revoked(&held) && !client.is_revoked(&other));
+
+            // streams of an unsupported version are rejected
+            let (mut sender, mut receiver) = client.connection().open_bi().await.unwrap();
+            let handshake = Handshake { version: 0, ..Handshake::new(CodecId::Bincode) };
+            sender.write_all(&handshake.encode()).await.unwrap();
+            match receiver.read(&mut [0u8; 1]).await {
+                Err(quinn::ReadError::Reset(code)) =>
+                    assert_eq!(Rejection::from_code(code.into_inner()), Some(Rejection::UnsupportedVersion)),
+                _ => panic!("stream must be reset"),
+            }
+
+            let (mut sender, mut receiver) = client.connection().open_bi().await.unwrap();
+            sender.write_all(&handshake::header(2u32).unwrap()).await.unwrap();
+            match receiver.read(&mut [0u8; 1]).await {
+                Err(quinn::ReadError::Reset(code)) =>
+                    assert_eq!(Rejection::from_code(code.into_inner()), Some(Rejection::Forbidden)),
+                _ => panic!("stream must be reset"),
+            }
+            client.close();
+        });
+    }
+
+    #[test]
+    fn test_pool() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = env::temp_dir().join("rpccaps-test-pool-cert.der");
+        fs::write(&cert_path, &certs[0].0).unwrap();
+
+        let client_config = |servers: &Vec<(SocketAddr, String)>, balance| {
+            let mut config = ClientConfig::default();
+            config.root_certs.push(cert_path.clone());
+            config.servers = servers.clone();
+            config.balance = balance;
+            config
+        };
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async move {
+            // replicas of a service
+            let mut servers = Vec::new();
+            for _ in 0..2 {
+                let mut server_config = ServerConfig::default();
+                server_config.connection_config.cert_data = Some((certs.clone(), key.clone()));
+                let mut server = Server::<u32>::new(server_config);
+                server.dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                                            HandlerOptions::default()).unwrap();
+                let (endpoint, incoming) = server.get_endpoint(SocketAddr::from(([127,0,0,1], 0))).unwrap();
+                servers.push((endpoint.local_addr().unwrap(), String::from("localhost")));
+                tokio::spawn(async move { server.dispatch_incoming(endpoint, incoming).await });
+            }
+
+            let options = PoolOptions { connections: 1, ..Default::default() };
+            let pool = Pool::connect(client_config(&servers, Balance::RoundRobin), options).await.unwrap();
+            assert_eq!(pool.connected(), 2);
+            let (a, b) = (pool.client().await.unwrap(), pool.client().await.unwrap());
+            assert!(!Arc::ptr_eq(&a, &b));
+            assert_ne!(a.connection().remote_address(), b.connection().remote_address());
+
+            // lost connection is re-established
+            a.close();
+            assert_eq!(pool.connected(), 1);
+            let transport = pool.service::<simple_service::Service, u32>(0).await.unwrap();
+            assert_eq!(simple_service::Client::new(transport).add(2).await, Ok(2));
+            pool.client().await.unwrap();
+            assert_eq!(pool.connected(), 2);
+
+            // idempotent calls are re-issued on failure
+            let mut attempts = 0;
+            let result = pool.idempotent::<simple_service::Service, _, _, _, _>(0u32, |transport| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    match attempt {
+                        1 => Err(CallError::Failed),
+                        _ => simple_service::Client::new(transport).add(3).await,
+                    }
+                }
+            }).await;
+            assert_eq!((result, attempts), (Ok(3), 2));
+
+            // ...only when all of their requests are idempotent
+            let retries = options.retries as usize;
+            for (idempotent, expected) in [(true, retries + 1), (false, 1)].iter().copied() {
+                let mut attempts = 0;
+                let result = pool.idempotent::<simple_service::Service, _, _, _, _>(0u32, |transport| {
+                    attempts += 1;
+                    async move {
+                        let mut client = simple_service::Client::new(transport);
+                        match idempotent {
+                            true => client.get().await?,
+                            false => client.add(0).await?,
+                        };
+                        Err::<(), _>(CallError::Failed)
+                    }
+                }).await;
+                assert_eq!((result, attempts), (Err(CallError::Failed), expected));
+            }
+
+            // streams go to the connection with fewest pending ones
+            let pool = Pool::connect(client_config(&servers, Balance::LeastPending), options).await.unwrap();
+            let (a, b) = (pool.client().await.unwrap(), pool.client().await.unwrap());
+            let transport = pool.service::<simple_service::Service, u32>(0).await.unwrap();
+            assert_eq!(a.pending() + b.pending(), 1);
+            for _ in 0..4 {
+                assert_eq!(pool.client().await.unwrap().pending(), 0);
+            }
+            drop(transport);
+            assert_eq!(a.pending() + b.pending(), 0);
+        });
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = env::temp_dir().join("rpccaps-test-mutual-tls-cert.der");
+        fs::write(&cert_path, &certs[0].0).unwrap();
+        let (client_certs, client_key) = tls::new_cert(vec![String::from("client")]).unwrap();
+
+        let mut server_config = ServerConfig::default();
+        server_config.connection_config.cert_data = Some((certs, key));
+        server_config.connection_config.with_no_client_auth = false;
+        server_config.client_roots.add(&client_certs[0]).unwrap();
+
+        let mut client_config = ClientConfig::default();
+        client_config.root_certs.push(cert_path.clone());
+        client_config.connection_config.with_no_client_auth = false;
+        client_config.connection_config.cert_data = Some((client_certs.clone(), client_key));
+
+        let mut anonymous_config = ClientConfig::default();
+        anonymous_config.root_certs.push(cert_path);
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut server = Server::<u32>::new(server_config);
+            let identity = Arc::new(std::sync::Mutex::new(None));
+            let identity_ = identity.clone();
+            server.dispatch.add_builder(0, Box::new(move |context: Arc<DefaultContext>| {
+                *identity_.lock().unwrap() = context.peer_certificate();
+                simple_service::Service::new()
+            }), HandlerOptions::default()).unwrap();
+            let (endpoint, incoming) = server.get_endpoint(SocketAddr::from(([127,0,0,1], 0))).unwrap();
+            let address = endpoint.local_addr().unwrap();
+            tokio::spawn(async move { server.dispatch_incoming(endpoint, incoming).await });
+
+            // builders get client's verified identity from context
+            let client = Client::connect(&client_config, address, "localhost").await.unwrap();
+            let transport = client.service::<simple_service::Service, u32>(0).await.unwrap();
+            assert_eq!(simple_service::Client::new(transport).add(1).await, Ok(1));
+            assert_eq!(identity.lock().unwrap().take(), Some(client_certs[0].clone()));
+            client.close();
+
+            // clients without certificate are rejected
+            let call = async {
+                let client = Client::connect(&anonymous_config, address, "localhost").await?;
+                let transport = client.service::<simple_service::Service, u32>(0).await?;
+                simple_service::Client::new(transport).add(1).await
+                    .or(ErrorKind::IO.err("call failed"))
+            };
+            let result = tokio::time::timeout(std::time::Duration::from_secs(5), call).await;
+            assert!(matches!(result, Ok(Err(_))));
+            assert!(identity.lock().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_identity_budget() {
+        use super::super::budget::TimeBudget;
+        use super::super::events::ServerEvent;
+
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = env::temp_dir().join("rpccaps-test-identity-budget-cert.der");
+        fs::write(&cert_path, &certs[0].0).unwrap();
+        let (client_certs, client_key) = tls::new_cert(vec![String::from("client")]).unwrap();
+
+        let mut server_config = ServerConfig::default();
+        server_config.connection_config.cert_data = Some((certs, key));
+        server_config.connection_config.with_no_client_auth = false;
+        server_config.client_roots.add(&client_certs[0]).unwrap();
+        server_config.identity_budget = Some(TimeBudget::new(std::time::Duration::from_millis(10),
+                                                             std::time::Duration::from_secs(60)));
+
+        let mut client_config = ClientConfig::default();
+        client_config.root_certs.push(cert_path);
+        client_config.connection_config.with_no_client_auth = false;
+        client_config.connection_config.cert_data = Some((client_certs, client_key));
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut server = Server::<u32>::new(server_config);
+            server.dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                                        HandlerOptions::default()).unwrap();
+            // long-lived stream, using more than its budget
+            server.dispatch.add_with(1, Box::new(|_| Box::pin(async {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                future::pending::<()>().await
+            })), HandlerOptions::default()).unwrap();
+            let mut events = server.events.subscribe(16);
+            let (endpoint, incoming) = server.get_endpoint(SocketAddr::from(([127,0,0,1], 0))).unwrap();
+            let address = endpoint.local_addr().unwrap();
+            tokio::spawn(async move { server.dispatch_incoming(endpoint, incoming).await });
+
+            let client = Client::connect(&client_config, address, "localhost").await.unwrap();
+            let _transport = client.open::<u32, simple_service::Request, simple_service::Response>(1)
+                                   .await.unwrap();
+            // running stream is aborted before completion
+            let exhausted = async {
+                while let Some(event) = events.next().await {
+                    if matches!(event, ServerEvent::BudgetExhausted(..)) {
+                        return true;
+                    }
+                }
+                false
+            };
+            assert!(tokio::time::timeout(std::time::Duration::from_secs(5), exhausted).await.unwrap());
+
+            // further streams are refused
+            let call = async {
+                let transport = client.service::<simple_service::Service, u32>(0).await?;
+                simple_service::Client::new(transport).add(1).await
+                    .or(ErrorKind::IO.err("call failed"))
+            };
+            let result = tokio::time::timeout(std::time::Duration::from_secs(5), call).await;
+            assert!(matches!(result, Ok(Err(_))));
+        });
+    }
+
+    #[test]
+    fn test_pinned_certs() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let cert_path = env::temp_dir().join("rpccaps-test-pinned-cert.der");
+        fs::write(&cert_path, &certs[0].0).unwrap();
+        let (other, _) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let other_path = env::temp_dir().join("rpccaps-test-pinned-other.der");
+        fs::write(&other_path, &other[0].0).unwrap();
+
+        let mut server_config = ServerConfig::default();
+        server_config.connection_config.cert_data = Some((certs, key));
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let mut server = Server::<u32>::new(server_config);
+            let (endpoint, incoming) = server.get_endpoint(SocketAddr::from(([127,0,0,1], 0))).unwrap();
+            let address = endpoint.local_addr().unwrap();
+            tokio::spawn(async move { server.dispatch_incoming(endpoint, incoming).await });
+
+            // pinned certificate is accepted whatever the server name
+            let mut client_config = ClientConfig::default();
+            client_config.pinned_certs.push(cert_path);
+            let client = Client::connect(&client_config, address, "example.com").await.unwrap();
+            client.close();
+
+            let mut client_config = ClientConfig::default();
+            client_config.pinned_certs.push(other_path);
+            assert!(Client::connect(&client_config, address, "localhost").await.is_err());
+        });
+    }
+}
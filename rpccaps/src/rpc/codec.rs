@@ -1,48 +1,205 @@
 use std::{
+    any::Any,
+    fmt::Display,
 	marker::PhantomData,
     pin::Pin,
 };
 
-use bytes::BytesMut;
+use bytes::{Buf, BufMut, BytesMut};
 use futures::io::{AsyncRead,AsyncWrite};
 use futures::prelude::*;
 use futures::task::{Context,Poll};
 
-use bincode;
+use bincode::{self, Options};
 use serde::{Deserialize,Serialize};
 pub use tokio_util::codec::{Decoder,Encoder};
 
 use crate::{ErrorKind,Error};
+use crate::data::depth::{self, DepthLimit};
+use super::backpressure::{Capacity, Watch};
+use super::leak::{self, Kind};
+use super::trace;
 
 
-/// FramedRead/Write compatible with futures::io's AsyncRead/Write
+/// FramedRead/Write compatible with futures::io's AsyncRead/Write.
+///
+/// Encoded items are buffered before being written. Once buffered data
+/// reaches the high-water mark, the sink is not ready until it has been
+/// written under this mark. The buffer's level can also be watched against
+/// watermarks, signaling backpressure to the application.
+///
+/// Data is read by chunks, whose size can be adapted to the stream within
+/// bounds: it grows when reads fill it or frames exceed it, cutting
+/// syscalls for big frames, and shrinks after consecutive small reads,
+/// releasing read buffer's memory.
 pub struct Framed<T,C>
 {
     inner: T,
     codec: C,
     chunk_size: usize,
+    /// Bounds of the chunk size, when adaptive.
+    chunk_bounds: Option<(usize, usize)>,
+    /// Count of consecutive reads under a quarter of the chunk size.
+    small_reads: u32,
     buffer: BytesMut,
+    write_buffer: BytesMut,
+    high_water: usize,
+    /// Backpressure watch of the write buffer.
+    watch: Option<Watch>,
+    /// Value kept until the framed is dropped.
+    guard: Option<Box<dyn Any+Send+Sync>>,
+    /// Buffers tracked as a frame, labelled by the inner stream's type.
+    _tracked: leak::Tracked,
 }
 
 
+/// Default high-water mark of the write buffer.
+pub const HIGH_WATER: usize = 64 * 1024;
+
+/// Default bounds of adaptive chunk sizes.
+pub const MIN_CHUNK_SIZE: usize = 128;
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Count of consecutive small reads after which chunk size is shrunk.
+const SHRINK_READS: u32 = 8;
+
+
 impl<T,C> Framed<T,C>
 {
+    /// Framed whose chunk size adapts within default bounds.
     pub fn new(inner: T, codec: C) -> Self {
-        Self::with_capacity(inner, codec, 128)
+        Self::with_capacity(inner, codec, MIN_CHUNK_SIZE)
+            .with_chunk_bounds(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
     }
 
+    /// Framed reading fixed-size chunks of `capacity` bytes (e.g. a single
+    /// byte in order not to consume data following a frame).
     pub fn with_capacity(inner: T, codec: C, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         let buffer = BytesMut::with_capacity(capacity);
-        Self { inner, codec, chunk_size: capacity, buffer }
+        Self { inner, codec, chunk_size: capacity, chunk_bounds: None, small_reads: 0, buffer,
+               write_buffer: BytesMut::new(), high_water: HIGH_WATER, watch: None, guard: None,
+               _tracked: leak::track(Kind::Frame, std::any::type_name::<T>()) }
+    }
+
+    /// Adapt chunk size within provided bounds.
+    pub fn with_chunk_bounds(mut self, min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        self.chunk_size = self.chunk_size.clamp(min, max);
+        self.chunk_bounds = Some((min, max));
+        self
+    }
+
+    /// Size of the chunks currently read.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Set high-water mark of the write buffer.
+    pub fn with_high_water(mut self, high_water: usize) -> Self {
+        self.high_water = high_water.max(1);
+        self
+    }
+
+    /// Watch write buffer's level (in bytes), notifying backpressure.
+    pub fn with_watch(mut self, watch: Watch) -> Self {
+        self.watch = Some(watch);
+        self
+    }
+
+    /// Keep `guard` until the framed is dropped (e.g. counting it among
+    /// its client's open streams).
+    pub fn with_guard(mut self, guard: impl Any+Send+Sync) -> Self {
+        self.guard = Some(Box::new(guard));
+        self
     }
 
     pub fn capacity(&self) -> usize {
         self.buffer.capacity()
     }
 
+    /// Size of encoded data not written yet.
+    pub fn buffered(&self) -> usize {
+        self.write_buffer.len()
+    }
+
     pub fn into_inner(self) -> T {
         self.inner
     }
+
+    /// Update backpressure watch with write buffer's level.
+    fn watch(&self) {
+        if let Some(ref watch) = self.watch {
+            watch.update(self.write_buffer.len());
+        }
+    }
+
+    /// Adapt chunk size to a read of `read` bytes.
+    fn tune_read(&mut self, read: usize) {
+        let (min, max) = match self.chunk_bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        if read >= self.chunk_size {
+            self.chunk_size = (self.chunk_size * 2).min(max);
+            self.small_reads = 0;
+        } else if read <= self.chunk_size / 4 {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_READS {
+                self.chunk_size = (self.chunk_size / 2).max(min);
+                self.small_reads = 0;
+                // release memory of big frames once consumed
+                if self.buffer.is_empty() && self.buffer.capacity() > 2 * self.chunk_size {
+                    self.buffer = BytesMut::with_capacity(self.chunk_size);
+                }
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+
+    /// Adapt chunk size to a decoded frame of `size` bytes, so that next
+    /// frames of this size are read at once.
+    fn tune_frame(&mut self, size: usize) {
+        if let Some((_, max)) = self.chunk_bounds {
+            if size > self.chunk_size {
+                self.chunk_size = size.next_power_of_two().min(max);
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite+Unpin, C> Framed<T,C> {
+    /// Write buffered data until its size is under `size`. Partial writes
+    /// are consumed from the buffer, and inner writer registers the task's
+    /// waker when it is not ready.
+    fn poll_write_until(&mut self, cx: &mut Context<'_>, size: usize)
+        -> Poll<Result<(), Error>>
+    {
+        while self.write_buffer.len() > size {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(ErrorKind::IO.err("can not write buffer")),
+                Poll::Ready(Ok(n)) => {
+                    self.write_buffer.advance(n);
+                    self.watch();
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(ErrorKind::IO.err(err.to_string())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite+Unpin, C: Unpin> Capacity for Framed<T,C> {
+    /// Room of the write buffer under the high-water mark, in bytes.
+    fn poll_capacity(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let high_water = this.high_water;
+        futures::ready!(this.poll_write_until(cx, high_water - 1))?;
+        Poll::Ready(Ok(high_water - this.write_buffer.len()))
+    }
 }
 
 impl<T,C> Stream for Framed<T,C>
@@ -53,34 +210,41 @@ impl<T,C> Stream for Framed<T,C>
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>
     {
-        let mut this = self.as_mut();
-        let buffer_size = this.buffer.len();
+        let this = self.as_mut().get_mut();
+        loop {
+            let buffered = this.buffer.len();
+            match this.codec.decode(&mut this.buffer) {
+                Ok(Some(item)) => {
+                    this.tune_frame(buffered - this.buffer.len());
+                    return Poll::Ready(Some(item))
+                },
+                Ok(None) => (),
+                Err(_) => return Poll::Ready(None),
+            }
 
-        if this.buffer.len() + this.chunk_size < this.buffer.capacity() {
-            let len = this.buffer.len() + this.chunk_size;
-            this.buffer.resize(len, 0);
+            // read into buffer's free space, keeping only read bytes
+            let buffer_size = this.buffer.len();
+            this.buffer.resize(buffer_size + this.chunk_size, 0);
+            let poll = Pin::new(&mut this.inner)
+                            .poll_read(cx, &mut this.buffer[buffer_size..]);
+            match poll {
+                Poll::Ready(Ok(size)) => {
+                    this.buffer.truncate(buffer_size+size);
+                    if size == 0 {
+                        return Poll::Ready(None);
+                    }
+                    this.tune_read(size);
+                },
+                Poll::Ready(Err(_)) => {
+                    this.buffer.truncate(buffer_size);
+                    return Poll::Ready(None);
+                },
+                Poll::Pending => {
+                    this.buffer.truncate(buffer_size);
+                    return Poll::Pending;
+                },
+            }
         }
-
-        let mut buffer = BytesMut::new();
-        std::mem::swap(&mut buffer, &mut this.buffer);
-
-        let poll = Pin::new(&mut this.inner)
-                        .poll_read(cx, &mut buffer[buffer_size..]);
-        let r = match poll {
-            Poll::Ready(Ok(size)) => {
-                buffer.resize(buffer_size+size, 0);
-                match this.codec.decode(&mut buffer) {
-                    Ok(Some(item)) => Poll::Ready(Some(item)),
-                    Ok(None) => Poll::Pending,
-                    Err(_) => Poll::Ready(None),
-                }
-            },
-            Poll::Ready(Err(_)) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
-        };
-
-        std::mem::swap(&mut buffer, &mut this.buffer);
-        r
     }
 }
 
@@ -93,46 +257,38 @@ impl<T,C,I> Sink<I> for Framed<T,C>
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>)
         -> Poll<Result<(), Self::Error>>
     {
-        Poll::Ready(Ok(()))
+        let this = self.get_mut();
+        let high_water = this.high_water;
+        this.poll_write_until(cx, high_water - 1)
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: I)
+    fn start_send(self: Pin<&mut Self>, item: I)
         -> Result<(), Self::Error>
     {
-        let mut this = self.as_mut();
-        let mut buffer = BytesMut::new();
-        std::mem::swap(&mut buffer, &mut this.buffer);
-
-        let r = this.codec.encode(item, &mut buffer)
-            		.or_else(|_| ErrorKind::Codec.err("encoding error"));
-        std::mem::swap(&mut buffer, &mut this.buffer);
-        r
+        let this = self.get_mut();
+        this.codec.encode(item, &mut this.write_buffer)
+            .or_else(|_| ErrorKind::Codec.err("encoding error"))?;
+        this.watch();
+        Ok(())
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>)
         -> Poll<Result<(), Self::Error>>
     {
-        let mut this = self.as_mut();
-        let mut buffer = BytesMut::new();
-        std::mem::swap(&mut buffer, &mut this.buffer);
-
-        let r = match Pin::new(&mut this.inner).poll_write(cx, &mut buffer) {
-            Poll::Ready(Ok(size)) => match this.buffer.split_at(size).0.len() {
-                x if x > 0 => Poll::Pending,
-                _ => Poll::Ready(Ok(())),
-            },
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_until(cx, 0))?;
+        match Pin::new(&mut this.inner).poll_flush(cx) {
             Poll::Ready(Err(err)) => Poll::Ready(ErrorKind::IO.err(err.to_string())),
+            Poll::Ready(Ok(_)) => Poll::Ready(Ok(())),
             Poll::Pending => Poll::Pending,
-        };
-
-        std::mem::swap(&mut buffer, &mut this.buffer);
-        r
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
         -> Poll<Result<(), Self::Error>>
     {
-        let mut this = self.as_mut();
+        futures::ready!(Sink::<I>::poll_flush(self.as_mut(), cx))?;
+        let this = self.get_mut();
         match Pin::new(&mut this.inner).poll_close(cx) {
             Poll::Ready(Err(err)) => Poll::Ready(ErrorKind::IO.err(err.to_string())),
             Poll::Ready(Ok(_)) => Poll::Ready(Ok(())),
@@ -142,12 +298,161 @@ impl<T,C,I> Sink<I> for Framed<T,C>
 }
 
 
+/// Build encoder and decoder of a service's stream, for responses of type
+/// `O` and requests of type `I`. It is implemented for functions returning
+/// an `(encoder, decoder)` tuple.
+pub trait CodecFactory<O, I>: Send+Sync {
+    type Encoder: Encoder<O, Error=Self::EncoderError>+Send+Unpin;
+    type EncoderError: Send+Unpin;
+    type Decoder: Decoder<Item=I>+Send+Unpin;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder);
+}
+
+impl<O, I, F, E, D> CodecFactory<O, I> for F
+    where F: Fn() -> (E, D)+Send+Sync,
+          E: Encoder<O>+Send+Unpin, E::Error: Send+Unpin,
+          D: Decoder<Item=I>+Send+Unpin
+{
+    type Encoder = E;
+    type EncoderError = E::Error;
+    type Decoder = D;
+
+    fn codec(&self) -> (E, D) {
+        self()
+    }
+}
+
+
+/// Bincode codec factory.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Bincode;
+
+impl<O, I> CodecFactory<O, I> for Bincode
+    where O: Serialize+Send+Unpin, for<'de> I: Deserialize<'de>+Send+Unpin
+{
+    type Encoder = BincodeCodec<O>;
+    type EncoderError = bincode::Error;
+    type Decoder = BincodeCodec<I>;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder) {
+        (BincodeCodec::new(), BincodeCodec::new())
+    }
+}
+
+
+/// Estimation of messages' encoded size, used to pre-allocate buffers and
+/// check size limits without encoding them. It is implemented by generated
+/// services' messages. `BincodeCodec` does not need it: items are
+/// serialized once, their frame header being written afterwards.
+pub trait EncodedSize {
+    /// Size of the message encoded by `BincodeCodec`, frame header
+    /// excluded, if it can be computed. Messages of variable size may be
+    /// serialized to compute it.
+    fn encoded_size_hint(&self) -> Option<usize>;
+}
+
+/// Return size of the value once serialized with bincode.
+pub fn serialized_size<T: Serialize+?Sized>(value: &T) -> Option<usize> {
+    bincode::serialized_size(value).ok().map(|size| size as usize)
+}
+
+
+/// Deserialize value encoded by `bincode::serialize`, nested at most
+/// `max_depth` deep (see `data::depth`).
+pub fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8], max_depth: usize) -> bincode::Result<T> {
+    // same options as `bincode::deserialize`
+    let limit = DepthLimit::new(max_depth);
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes()
+        .deserialize_seed(&limit, bytes)
+        .map_err(|err| match limit.exceeded() {
+            Some(exceeded) => Box::new(bincode::ErrorKind::Io(exceeded.into())),
+            None => err,
+        })
+}
+
+
+/// Limits of the items encoded and decoded by `BincodeCodec`, as set by
+/// servers' and clients' `ConnectionConfig::codec_limits`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct CodecLimits {
+    /// Maximum size of encoded and decoded items, frame header excluded.
+    pub max_size: Option<usize>,
+    /// Maximum nesting depth of decoded items.
+    pub max_depth: usize,
+}
+
+impl Default for CodecLimits {
+    fn default() -> Self {
+        Self { max_size: None, max_depth: depth::DEFAULT_MAX_DEPTH }
+    }
+}
+
+impl<O, I> CodecFactory<O, I> for CodecLimits
+    where O: Serialize+Send+Unpin, for<'de> I: Deserialize<'de>+Send+Unpin
+{
+    type Encoder = BincodeCodec<O>;
+    type EncoderError = bincode::Error;
+    type Decoder = BincodeCodec<I>;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder) {
+        (BincodeCodec::with_limits(*self), BincodeCodec::with_limits(*self))
+    }
+}
+
+
+/// Size of the frame header of `BincodeCodec`, holding the payload's size.
+const HEADER_SIZE: usize = 8;
+
 /// Implement tokio codec for Bincode.
-pub struct BincodeCodec<T>(PhantomData<T>);
+///
+/// Decoded items are nested at most `depth::DEFAULT_MAX_DEPTH` deep by
+/// default (see `data::depth`).
+pub struct BincodeCodec<T> {
+    /// Maximum size of encoded and decoded items.
+    max_size: Option<usize>,
+    /// Maximum nesting depth of decoded items.
+    max_depth: usize,
+    phantom: PhantomData<T>,
+}
 
 impl<T> BincodeCodec<T> {
     pub fn new() -> Self {
-        Self(PhantomData)
+        Self { max_size: None, max_depth: depth::DEFAULT_MAX_DEPTH, phantom: PhantomData }
+    }
+
+    /// Codec failing on items bigger than `max_size` once encoded. Frames
+    /// are refused from their header, before their payload is received.
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self { max_size: Some(max_size), ..Self::new() }
+    }
+
+    /// Codec enforcing provided limits.
+    pub fn with_limits(limits: CodecLimits) -> Self {
+        Self { max_size: limits.max_size, max_depth: limits.max_depth, ..Self::new() }
+    }
+
+    /// Fail to decode items nested deeper than `max_depth`, with an
+    /// `InvalidData` IO error.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn max_size(&self) -> Option<usize> {
+        self.max_size
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Fail if size is over the maximum.
+    fn check_size(&self, size: usize) -> Result<(), bincode::Error> {
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(Box::new(bincode::ErrorKind::SizeLimit)),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -179,14 +484,21 @@ impl<T> Encoder<T> for BincodeCodec<T>
     type Error = bincode::Error;
 
     fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let size = bincode::serialized_size(&item)? as u64;
-        let header_size = bincode::serialized_size(&size)? as usize;
-
+        // item is serialized once, its header being written afterwards
         let index = dst.len();
-        dst.resize(index + header_size + size as usize, 0);
-        let mut buf = &mut dst.as_mut()[index..];
-        bincode::serialize_into(&mut buf, &size)?;
-        bincode::serialize_into(&mut buf, &item)
+        dst.resize(index + HEADER_SIZE, 0);
+        let size = bincode::serialize_into((&mut *dst).writer(), &item)
+            .and_then(|_| {
+                let size = dst.len() - index - HEADER_SIZE;
+                self.check_size(size).map(|_| size as u64)
+            });
+        traced::<T,_,_>("encode", match size {
+            Ok(size) => bincode::serialize_into(&mut dst[index..index + HEADER_SIZE], &size),
+            Err(err) => {
+                dst.truncate(index);
+                Err(err)
+            },
+        })
     }
 }
 
@@ -198,22 +510,245 @@ impl<T> Decoder for BincodeCodec<T>
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>
     {
-        let size = 0u64;
-        let header_size = bincode::serialized_size(&size)? as usize;
+        let header_size = HEADER_SIZE;
         if src.len() < header_size {
             return Ok(None);
         }
 
-        let buf = src.split_to(header_size);
-        match bincode::deserialize(buf.as_ref()) {
-            Err(err) => return Err(err),
-            Ok(size) if src.len() < size => return Ok(None),
-            Ok(size) => {
-                let buf = src.split_to(size);
-                bincode::deserialize::<Self::Item>(buf.as_ref())
-                    .and_then(|item| Ok(Some(item)))
-            }
+        // header is only consumed once the whole frame is available
+        let size: usize = traced::<T,_,_>("decode", bincode::deserialize(&src[..header_size])
+                                          .and_then(|size| self.check_size(size).map(|_| size)))?;
+        if src.len() - header_size < size {
+            return Ok(None);
         }
+        src.advance(header_size);
+        let buf = src.split_to(size);
+        traced::<T,_,_>("decode", deserialize(buf.as_ref(), self.max_depth).map(Some))
+    }
+}
+
+
+/// Emit error event of `operation` failing on an item of type `T` (see
+/// `trace::codec_error`).
+fn traced<T, R, E: Display>(operation: &'static str, result: Result<R, E>) -> Result<R, E> {
+    if let Err(ref err) = result {
+        trace::codec_error(operation, std::any::type_name::<T>(), err);
+    }
+    result
+}
+
+
+/// Size of frames' header, holding payload's size as a little-endian
+/// `u64`. This is the same framing as `BincodeCodec`'s.
+#[cfg(any(feature="json", feature="cbor"))]
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Append frame of provided payload to `dst`.
+#[cfg(any(feature="json", feature="cbor"))]
+fn write_frame(payload: &[u8], dst: &mut BytesMut) {
+    dst.reserve(FRAME_HEADER_SIZE + payload.len());
+    dst.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    dst.extend_from_slice(payload);
+}
+
+/// Split a frame's payload from `src`, once it has been fully received.
+#[cfg(any(feature="json", feature="cbor"))]
+fn read_frame(src: &mut BytesMut) -> Option<BytesMut> {
+    if src.len() < FRAME_HEADER_SIZE {
+        return None;
+    }
+
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    header.copy_from_slice(&src[..FRAME_HEADER_SIZE]);
+    let size = u64::from_le_bytes(header) as usize;
+    if src.len() - FRAME_HEADER_SIZE < size {
+        return None;
+    }
+    src.advance(FRAME_HEADER_SIZE);
+    Some(src.split_to(size))
+}
+
+
+/// Implement tokio codec for JSON, using the same framing as `BincodeCodec`.
+/// Decoded items are nested at most `depth::DEFAULT_MAX_DEPTH` deep by
+/// default; serde_json limits them to 128 in any case.
+#[cfg(feature="json")]
+pub struct JsonCodec<T> {
+    max_depth: usize,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(feature="json")]
+impl<T> JsonCodec<T> {
+    pub fn new() -> Self {
+        Self { max_depth: depth::DEFAULT_MAX_DEPTH, phantom: PhantomData }
+    }
+
+    /// Fail to decode items nested deeper than `max_depth`, with an
+    /// `InvalidData` IO error.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Return item's JSON payload, without framing.
+    pub fn encode_payload(&self, item: &T) -> Result<Vec<u8>, std::io::Error>
+        where T: Serialize
+    {
+        Ok(serde_json::to_vec(item)?)
+    }
+
+    /// Read item from a JSON payload, without framing.
+    pub fn decode_payload(&self, payload: &[u8]) -> Result<T, std::io::Error>
+        where for<'de> T: Deserialize<'de>
+    {
+        let mut deserializer = serde_json::Deserializer::from_slice(payload);
+        let item = depth::deserialize(&mut deserializer, self.max_depth)?;
+        deserializer.end()?;
+        Ok(item)
+    }
+}
+
+#[cfg(feature="json")]
+impl<T> Default for JsonCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature="json")]
+impl<T> Encoder<T> for JsonCodec<T>
+    where T: Serialize
+{
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let payload = traced::<T,_,_>("encode", self.encode_payload(&item))?;
+        write_frame(&payload, dst);
+        Ok(())
+    }
+}
+
+#[cfg(feature="json")]
+impl<T> Decoder for JsonCodec<T>
+    where for<'de> T: Deserialize<'de>
+{
+    type Item = T;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match read_frame(src) {
+            Some(buf) => traced::<T,_,_>("decode", self.decode_payload(buf.as_ref())).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// JSON codec factory.
+#[cfg(feature="json")]
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Json;
+
+#[cfg(feature="json")]
+impl<O, I> CodecFactory<O, I> for Json
+    where O: Serialize+Send+Unpin, for<'de> I: Deserialize<'de>+Send+Unpin
+{
+    type Encoder = JsonCodec<O>;
+    type EncoderError = std::io::Error;
+    type Decoder = JsonCodec<I>;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder) {
+        (JsonCodec::new(), JsonCodec::new())
+    }
+}
+
+
+/// Implement tokio codec for CBOR, using the same framing as `BincodeCodec`.
+/// Decoded items are nested at most `depth::DEFAULT_MAX_DEPTH` deep by
+/// default.
+#[cfg(feature="cbor")]
+pub struct CborCodec<T> {
+    max_depth: usize,
+    phantom: PhantomData<T>,
+}
+
+#[cfg(feature="cbor")]
+impl<T> CborCodec<T> {
+    pub fn new() -> Self {
+        Self { max_depth: depth::DEFAULT_MAX_DEPTH, phantom: PhantomData }
+    }
+
+    /// Fail to decode items nested deeper than `max_depth`, with an
+    /// `InvalidData` IO error.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+}
+
+#[cfg(feature="cbor")]
+impl<T> Default for CborCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature="cbor")]
+impl<T> Encoder<T> for CborCodec<T>
+    where T: Serialize
+{
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut payload = Vec::new();
+        traced::<T,_,_>("encode", ciborium::ser::into_writer(&item, &mut payload).map_err(|err| match err {
+            ciborium::ser::Error::Io(err) => err,
+            err => std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()),
+        }))?;
+        write_frame(&payload, dst);
+        Ok(())
+    }
+}
+
+#[cfg(feature="cbor")]
+impl<T> Decoder for CborCodec<T>
+    where for<'de> T: Deserialize<'de>
+{
+    type Item = T;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match read_frame(src) {
+            Some(buf) => traced::<T,_,_>("decode",
+                ciborium::de::from_reader_with_recursion_limit(buf.as_ref(), self.max_depth)
+                .map(Some).map_err(|err| match err {
+                    ciborium::de::Error::Io(err) => err,
+                    ciborium::de::Error::RecursionLimitExceeded =>
+                        depth::DepthExceeded { max_depth: self.max_depth }.into(),
+                    err => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", err)),
+                })),
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// CBOR codec factory.
+#[cfg(feature="cbor")]
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Cbor;
+
+#[cfg(feature="cbor")]
+impl<O, I> CodecFactory<O, I> for Cbor
+    where O: Serialize+Send+Unpin, for<'de> I: Deserialize<'de>+Send+Unpin
+{
+    type Encoder = CborCodec<O>;
+    type EncoderError = std::io::Error;
+    type Decoder = CborCodec<I>;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder) {
+        (CborCodec::new(), CborCodec::new())
     }
 }
 
@@ -244,6 +779,62 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_max_size() {
+        let value = String::from("nothing flight like a bird");
+        let mut buffer = BytesMut::new();
+        let mut codec = BincodeCodec::<String>::with_max_size(16);
+        assert!(codec.encode(value.clone(), &mut buffer).is_err());
+
+        // frame is refused from its header
+        BincodeCodec::new().encode(value, &mut buffer).unwrap();
+        let mut header = buffer.split_to(8);
+        assert!(codec.decode(&mut header).is_err());
+        assert!(codec.encode(String::from("a bird"), &mut buffer).is_ok());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let value = vec![vec![vec![1u32]]];
+        let mut buffer = BytesMut::new();
+        BincodeCodec::new().encode(value.clone(), &mut buffer).unwrap();
+        let mut codec = BincodeCodec::<Vec<Vec<Vec<u32>>>>::new().with_max_depth(2);
+        match *codec.decode(&mut buffer.clone()).unwrap_err() {
+            bincode::ErrorKind::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+            err => panic!("unexpected error {:?}", err),
+        }
+        let mut codec = codec.with_max_depth(3);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(value.clone()));
+
+        let limits = CodecLimits { max_depth: 2, ..CodecLimits::default() };
+        let mut codec = BincodeCodec::<Vec<Vec<Vec<u32>>>>::with_limits(limits);
+        BincodeCodec::new().encode(value.clone(), &mut buffer).unwrap();
+        assert!(codec.decode(&mut buffer).is_err());
+
+        let data = bincode::serialize(&value).unwrap();
+        assert!(deserialize::<Vec<Vec<Vec<u32>>>>(&data, 2).is_err());
+        assert_eq!(deserialize::<Vec<Vec<Vec<u32>>>>(&data, 3).unwrap(), value);
+
+        #[cfg(feature="json")]
+        for max_depth in [2, 3] {
+            let mut buffer = BytesMut::new();
+            JsonCodec::new().encode(value.clone(), &mut buffer).unwrap();
+            let result = JsonCodec::<Vec<Vec<Vec<u32>>>>::new().with_max_depth(max_depth).decode(&mut buffer);
+            match max_depth {
+                2 => assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData),
+                _ => assert_eq!(result.unwrap(), Some(value.clone())),
+            }
+        }
+
+        #[cfg(feature="cbor")]
+        {
+            let mut buffer = BytesMut::new();
+            CborCodec::new().encode(value.clone(), &mut buffer).unwrap();
+            let err = CborCodec::<Vec<Vec<Vec<u32>>>>::new().with_max_depth(2).decode(&mut buffer).unwrap_err();
+            assert!(err.get_ref().is_some_and(|err| err.is::<depth::DepthExceeded>()));
+        }
+    }
+
     #[test]
     fn test_encode_decode_complete() {
         let mut case = TestCase::new(String::from("nothing flight like a bird"));
@@ -265,12 +856,284 @@ mod tests {
         case.encode();
 
         // test decoding incomplete
-        let mut buffer = case.buffer.split_off(case.buffer.len() / 2);
-        match case.codec.decode(&mut buffer) {
+        let rest = case.buffer.split_off(case.buffer.len() / 2);
+        match case.codec.decode(&mut case.buffer) {
             Ok(None) => (),
             Err(err) => panic!("decoding error: {}", err),
             Ok(Some(_)) => panic!("got frame while it should return None"),
         }
+
+        // frame's header is kept until the frame is complete
+        case.buffer.unsplit(rest);
+        assert_eq!(case.codec.decode(&mut case.buffer).unwrap(), Some(case.value));
+        assert!(case.buffer.is_empty());
     }
-}
 
+    #[test]
+    fn test_framed_single_byte() {
+        futures::executor::block_on(async {
+            let mut data = BytesMut::new();
+            BincodeCodec::new().encode(13u32, &mut data).unwrap();
+            data.extend_from_slice(b"following");
+
+            // frame is read without consuming following data
+            let mut stream = Framed::with_capacity(futures::io::Cursor::new(data.to_vec()),
+                                                   BincodeCodec::<u32>::new(), 1);
+            assert_eq!(stream.next().await, Some(13));
+            let mut reader = stream.into_inner();
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"following");
+        })
+    }
+
+    #[test]
+    fn test_framed() {
+        futures::executor::block_on(async {
+            let values = vec![String::from("nothing flight"), String::from("like a bird")];
+            let mut sink = Framed::with_capacity(Vec::new(), BincodeCodec::new(), 4);
+            for value in values.iter() {
+                sink.send(value.clone()).await.unwrap();
+            }
+
+            let reader = futures::io::Cursor::new(sink.into_inner());
+            let stream = Framed::with_capacity(reader, BincodeCodec::<String>::new(), 4);
+            assert_eq!(stream.collect::<Vec<_>>().await, values);
+        })
+    }
+
+    /// Reader returning at most `max` bytes per read.
+    struct SlowReader {
+        data: futures::io::Cursor<Vec<u8>>,
+        max: usize,
+    }
+
+    impl AsyncRead for SlowReader {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+            -> Poll<std::io::Result<usize>>
+        {
+            let max = buf.len().min(self.max);
+            Pin::new(&mut self.data).poll_read(cx, &mut buf[..max])
+        }
+    }
+
+    /// Encode values as a stream's data.
+    fn encoded(values: &[String]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        for value in values {
+            BincodeCodec::new().encode(value.clone(), &mut buffer).unwrap();
+        }
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn test_framed_chunk_size() {
+        futures::executor::block_on(async {
+            // big frames are read at once
+            let values = vec!["a bird".repeat(2000); 4];
+            let mut stream = Framed::new(futures::io::Cursor::new(encoded(&values)),
+                                         BincodeCodec::<String>::new());
+            assert_eq!(stream.chunk_size(), MIN_CHUNK_SIZE);
+            assert_eq!((&mut stream).collect::<Vec<_>>().await, values);
+            assert!((16384..=MAX_CHUNK_SIZE).contains(&stream.chunk_size()));
+
+            // small reads shrink chunks down to the minimum
+            let values = (0..32).map(|i| i.to_string()).collect::<Vec<_>>();
+            let reader = SlowReader { data: futures::io::Cursor::new(encoded(&values)), max: 4 };
+            let mut stream = Framed::new(reader, BincodeCodec::<String>::new())
+                                 .with_chunk_bounds(32, 1024);
+            assert_eq!((&mut stream).collect::<Vec<_>>().await, values);
+            assert_eq!(stream.chunk_size(), 32);
+
+            // fixed chunk size
+            let values = vec!["a bird".repeat(100); 2];
+            let mut stream = Framed::with_capacity(futures::io::Cursor::new(encoded(&values)),
+                                                   BincodeCodec::<String>::new(), 4);
+            assert_eq!((&mut stream).collect::<Vec<_>>().await, values);
+            assert_eq!(stream.chunk_size(), 4);
+        })
+    }
+
+    #[cfg(feature="json")]
+    #[test]
+    fn test_json_codec() {
+        let value = vec![String::from("nothing flight"), String::from("like a bird")];
+        let (mut bincode, mut json) = (BytesMut::new(), BytesMut::new());
+        BincodeCodec::new().encode(value.clone(), &mut bincode).unwrap();
+        JsonCodec::new().encode(value.clone(), &mut json).unwrap();
+
+        // same framing as bincode
+        assert_eq!(json[..8], (json.len() as u64 - 8).to_le_bytes());
+        assert_eq!(bincode[..8], (bincode.len() as u64 - 8).to_le_bytes());
+        assert_eq!(&json[8..], br#"["nothing flight","like a bird"]"#);
+
+        let mut codec = JsonCodec::<Vec<String>>::new();
+        let mut partial = json.split_to(json.len() - 1);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(json);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(value));
+        assert!(partial.is_empty());
+
+        let mut invalid = BytesMut::new();
+        write_frame(b"{", &mut invalid);
+        assert!(codec.decode(&mut invalid).is_err());
+    }
+
+    #[cfg(feature="cbor")]
+    #[test]
+    fn test_cbor_codec() {
+        let value = (String::from("nothing flight"), 12u32);
+        let mut buffer = BytesMut::new();
+        CborCodec::new().encode(value.clone(), &mut buffer).unwrap();
+        // array(2), text(14), ..., unsigned(12)
+        assert_eq!(buffer[..8], (buffer.len() as u64 - 8).to_le_bytes());
+        assert_eq!(&buffer[8..10], &[0x82, 0x6e]);
+        assert_eq!(buffer[buffer.len()-1], 0x0c);
+
+        let mut codec = CborCodec::<(String, u32)>::new();
+        let mut partial = buffer.split_to(buffer.len() - 1);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some(value));
+
+        let mut invalid = BytesMut::new();
+        write_frame(&[0xff], &mut invalid);
+        assert!(codec.decode(&mut invalid).is_err());
+    }
+
+    #[cfg(feature="cbor")]
+    #[test]
+    fn test_cbor_framed() {
+        futures::executor::block_on(async {
+            let values = vec![String::from("nothing flight"), String::from("like a bird")];
+            let mut sink = Framed::with_capacity(Vec::new(), CborCodec::new(), 4);
+            for value in values.iter() {
+                sink.send(value.clone()).await.unwrap();
+            }
+
+            let reader = futures::io::Cursor::new(sink.into_inner());
+            let stream = Framed::with_capacity(reader, CborCodec::<String>::new(), 4);
+            assert_eq!(stream.collect::<Vec<_>>().await, values);
+        })
+    }
+
+    /// Writer accepting at most `max` bytes per write, and not ready on
+    /// every other call (or always, when `stalled`).
+    struct SlowWriter {
+        data: Vec<u8>,
+        max: usize,
+        ready: bool,
+        stalled: bool,
+    }
+
+    impl SlowWriter {
+        fn new(max: usize) -> Self {
+            Self { data: Vec::new(), max, ready: false, stalled: false }
+        }
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+            -> Poll<std::io::Result<usize>>
+        {
+            self.ready = !self.ready;
+            if self.stalled || !self.ready {
+                if !self.stalled {
+                    cx.waker().wake_by_ref();
+                }
+                return Poll::Pending;
+            }
+            let size = buf.len().min(self.max);
+            self.data.extend_from_slice(&buf[..size]);
+            Poll::Ready(Ok(size))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_framed_partial_writes() {
+        futures::executor::block_on(async {
+            let values = (0..64).map(|i| "a bird".repeat(i)).collect::<Vec<_>>();
+            let mut sink = Framed::new(SlowWriter::new(7), BincodeCodec::new()).with_high_water(32);
+            for value in values.iter() {
+                sink.feed(value.clone()).await.unwrap();
+                assert!(sink.buffered() < 32 + 16 + value.len());
+            }
+            sink.close().await.unwrap();
+            assert_eq!(sink.buffered(), 0);
+
+            let reader = futures::io::Cursor::new(sink.into_inner().data);
+            let stream = Framed::new(reader, BincodeCodec::<String>::new());
+            assert_eq!(stream.collect::<Vec<_>>().await, values);
+        })
+    }
+
+    #[test]
+    fn test_framed_high_water() {
+        let mut writer = SlowWriter::new(1024);
+        writer.stalled = true;
+        let mut sink = Framed::new(writer, BincodeCodec::new()).with_high_water(16);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sink = Pin::new(&mut sink);
+        assert!(Sink::<String>::poll_ready(sink.as_mut(), &mut cx).is_ready());
+        sink.as_mut().start_send(String::from("nothing flight like a bird")).unwrap();
+        assert!(Sink::<String>::poll_ready(sink.as_mut(), &mut cx).is_pending());
+        assert!(Sink::<String>::poll_flush(sink.as_mut(), &mut cx).is_pending());
+        assert_eq!(sink.buffered(), 16 + 26);
+
+        sink.inner.stalled = false;
+        while Sink::<String>::poll_flush(sink.as_mut(), &mut cx).is_pending() {}
+        assert_eq!(sink.buffered(), 0);
+        assert_eq!(sink.inner.data.len(), 16 + 26);
+    }
+
+    #[test]
+    fn test_framed_backpressure() {
+        use std::sync::{Arc, Mutex};
+        use super::super::backpressure::{Pressure, Watermarks};
+
+        let mut writer = SlowWriter::new(1024);
+        writer.stalled = true;
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let notified_ = notified.clone();
+        let watch = Watch::new(Watermarks::new(40, 0),
+                               move |pressure| notified_.lock().unwrap().push(pressure));
+        let mut sink = Framed::new(writer, BincodeCodec::new()).with_high_water(64).with_watch(watch);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sink = Pin::new(&mut sink);
+        assert!(matches!(sink.as_mut().poll_capacity(&mut cx), Poll::Ready(Ok(64))));
+        sink.as_mut().start_send(String::from("a bird")).unwrap();
+        assert!(matches!(sink.as_mut().poll_capacity(&mut cx), Poll::Ready(Ok(42))));
+        assert!(notified.lock().unwrap().is_empty());
+        sink.as_mut().start_send(String::from("nothing flight like a bird")).unwrap();
+        assert_eq!(*notified.lock().unwrap(), vec![Pressure::Raised]);
+
+        sink.inner.stalled = false;
+        while Sink::<String>::poll_flush(sink.as_mut(), &mut cx).is_pending() {}
+        assert_eq!(*notified.lock().unwrap(), vec![Pressure::Raised, Pressure::Relieved]);
+    }
+
+    #[cfg(feature="leak-detection")]
+    #[test]
+    fn test_tracked() {
+        struct Tracked;
+        let count = || leak::report().entries.iter()
+            .filter(|entry| entry.kind == Kind::Frame && entry.label.ends_with("::Tracked")).count();
+
+        let framed = Framed::new(Tracked, BincodeCodec::<String>::new());
+        assert_eq!(count(), 1);
+        drop(framed);
+        assert_eq!(count(), 0);
+    }
+}
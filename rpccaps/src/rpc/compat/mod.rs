@@ -0,0 +1,5 @@
+//! Adapters between rpccaps services and other services' frameworks.
+#[cfg(feature="tower")]
+pub mod tower;
+#[cfg(feature="tarpc-compat")]
+pub mod tarpc;
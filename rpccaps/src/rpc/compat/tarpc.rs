@@ -0,0 +1,177 @@
+//! Bridging of tarpc service definitions, easing the migration of existing
+//! tarpc codebases.
+//!
+//! Services generated by `#[tarpc::service]` keep their request and
+//! response enums (both serde-serializable), which are carried as is over
+//! rpccaps transports and codecs:
+//! - on the server side, `Served` wraps tarpc's `Serve` implementation
+//!   (`ServeWorld` for a `World` service) into a rpccaps `Service`, to be
+//!   registered as any other service;
+//! - on the client side, `Bridged` sends requests over a service's stream
+//!   (e.g. `Client::service()`), standing in for tarpc's generated client.
+//!
+//! ```ignore
+//! let serve = WorldServer.serve();
+//! server.dispatch.add_builder(0, Box::new(move |_| Served::from_serve(serve.clone())),
+//!                             HandlerOptions::default())?;
+//!
+//! let mut world = Bridged::new(client.open(0).await?);
+//! let response = world.call(WorldRequest::Hello { name }).await?;
+//! ```
+//!
+//! Messages must be serializable, which requires tarpc's `serde1` feature.
+//! Bridged services are plain request-response ones, without methods'
+//! capability bits: tarpc calls are kept as is until services are ported
+//! to `#[service]`.
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::future::BoxFuture;
+use tarpc::context;
+use tarpc::server::Serve;
+
+use crate::rpc::call::{with_timeout, CallError, ClientOptions};
+use crate::rpc::service::Service;
+
+
+/// Service serving requests with a tarpc `Serve` implementation.
+pub struct Served<Req, Resp> {
+    serve: Box<dyn Fn(Req) -> BoxFuture<'static, Resp>+Send+Sync>,
+}
+
+impl<Req, Resp> Served<Req, Resp> {
+    /// Serve requests with `serve`, usually calling tarpc's
+    /// `Serve::serve()` on a clone of the generated `Serve{Service}`.
+    pub fn new<F, Fut>(serve: F) -> Self
+        where F: 'static+Fn(Req) -> Fut+Send+Sync,
+              Fut: 'static+Future<Output=Resp>+Send,
+    {
+        Self { serve: Box::new(move |request| serve(request).boxed()) }
+    }
+
+    /// Serve requests with a tarpc `Serve` implementation, called with a
+    /// new tarpc context for each request.
+    pub fn from_serve<S>(serve: S) -> Self
+        where S: 'static+Serve<Req, Resp=Resp>+Clone+Send+Sync,
+              S::Fut: 'static+Send,
+    {
+        Self::new(move |request| serve.clone().serve(context::current(), request))
+    }
+}
+
+impl<Req, Resp> Unpin for Served<Req, Resp> {}
+
+#[async_trait]
+impl<Req, Resp> Service for Served<Req, Resp>
+    where Req: 'static+Send+Sync+Unpin, Resp: 'static+Send+Sync+Unpin
+{
+    type Request = Req;
+    type Response = Resp;
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    async fn dispatch(&mut self, request: Req) -> Option<Resp> {
+        Some((self.serve)(request).await)
+    }
+}
+
+
+/// Client calling a tarpc service over a rpccaps transport.
+pub struct Bridged<T, Req, Resp> {
+    transport: T,
+    options: ClientOptions,
+    /// False once a call timed out: its response may still come.
+    synced: bool,
+    phantom: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<T, Req, Resp> Bridged<T, Req, Resp>
+    where T: Stream<Item=Resp>+Sink<Req>+Unpin
+{
+    pub fn new(transport: T) -> Self {
+        Self::with_options(transport, ClientOptions::default())
+    }
+
+    pub fn with_options(transport: T, options: ClientOptions) -> Self {
+        Self { transport, options, synced: true, phantom: PhantomData }
+    }
+
+    /// Send request, returning its response. Once a call timed out,
+    /// further calls fail with `CallError::Desynced`.
+    pub async fn call(&mut self, request: Req) -> Result<Resp, CallError> {
+        if !self.synced {
+            return Err(CallError::Desynced);
+        }
+        self.transport.send(request).await.or(Err(CallError::Failed))?;
+        self.synced = false;
+        let response = with_timeout(self.options.request_timeout, self.transport.next()).await?;
+        self.synced = true;
+        response.ok_or(CallError::Failed)
+    }
+
+    /// Return inner transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future::join;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    #[tarpc::service]
+    trait World {
+        async fn hello(name: String) -> String;
+    }
+
+    #[derive(Clone)]
+    struct HelloServer;
+
+    #[tarpc::server]
+    impl World for HelloServer {
+        async fn hello(self, _: context::Context, name: String) -> String {
+            format!("Hello, {}!", name)
+        }
+    }
+
+    #[test]
+    fn test_bridged() {
+        let (server_transport, client_transport) = MPSCTransport::<WorldResponse, WorldRequest>::bi(8);
+
+        let client_fut = async move {
+            let mut world = Bridged::new(client_transport);
+            match world.call(WorldRequest::Hello { name: "world".into() }).await {
+                Ok(WorldResponse::Hello(response)) => assert_eq!(response, "Hello, world!"),
+                response => panic!("unexpected response: {:?}", response),
+            }
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            Served::from_serve(HelloServer.serve()).serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_bridged_timeout() {
+        let (_server_transport, client_transport) = MPSCTransport::<WorldResponse, WorldRequest>::bi(8);
+        let options = ClientOptions::default().with_request_timeout(Duration::from_millis(10));
+        let mut world = Bridged::with_options(client_transport, options);
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let hello = || WorldRequest::Hello { name: "world".into() };
+            assert!(matches!(world.call(hello()).await, Err(CallError::Timeout)));
+            assert!(matches!(world.call(hello()).await, Err(CallError::Desynced)));
+        });
+    }
+}
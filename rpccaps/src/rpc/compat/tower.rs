@@ -0,0 +1,295 @@
+//! Adapters between rpccaps and tower services, so that tower middleware
+//! stacks (rate limiting, retries, load shedding, etc.) can be reused with
+//! rpccaps services:
+//! - `IntoTower` implements `tower::Service` on top of a rpccaps service,
+//!   responding with its `dispatch()` output;
+//! - `FromTower` serves a tower stack on top of an `IntoTower` adapter,
+//!   whose rpccaps service `S` describes the messages (methods, frames,
+//!   implicit responses) and provides callers' capability.
+//!
+//! Both combined wrap a service into a tower stack, then serve it:
+//!
+//! ```ignore
+//! let service = IntoTower::new(storage::Service::new());
+//! let stack = ServiceBuilder::new().concurrency_limit(4).service(service.clone());
+//! FromTower::new(stack, &service).serve(transport).await;
+//! ```
+//!
+//! Only calls dispatched by `dispatch()` go through tower: streaming
+//! methods are not supported.
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::future::BoxFuture;
+use futures::lock::Mutex;
+
+use crate::{ErrorKind, Result};
+use crate::rpc::message::Error;
+use crate::rpc::protocol::{Call, Frame, Reply};
+use crate::rpc::service::Service;
+
+
+/// Tower service dispatching requests to a rpccaps service. Calls are
+/// dispatched one at a time, since dispatch requires exclusive access to
+/// the service; the adapter can be cloned, sharing the service.
+pub struct IntoTower<S: Service> {
+    inner: Arc<Mutex<S>>,
+    /// Service's capability, as of its last dispatch.
+    capability: Arc<RwLock<S::Capability>>,
+}
+
+impl<S: Service> IntoTower<S> {
+    pub fn new(inner: S) -> Self {
+        let capability = Arc::new(RwLock::new(inner.capability()));
+        Self { inner: Arc::new(Mutex::new(inner)), capability }
+    }
+
+    /// Return service's capability. It is updated once calls are
+    /// dispatched, since they can change it (e.g. authentication).
+    pub fn capability(&self) -> S::Capability {
+        self.capability.read().unwrap().clone()
+    }
+}
+
+impl<S: Service> Clone for IntoTower<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), capability: self.capability.clone() }
+    }
+}
+
+impl<S> tower_service::Service<S::Request> for IntoTower<S>
+    where S: 'static+Service
+{
+    /// Response of the request, if any (e.g. none for notifications).
+    type Response = Option<S::Response>;
+    type Error = crate::Error;
+    type Future = BoxFuture<'static, Result<Option<S::Response>>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: S::Request) -> Self::Future {
+        let (inner, capability) = (self.inner.clone(), self.capability.clone());
+        async move {
+            let mut service = inner.lock().await;
+            if !service.is_alive() {
+                return ErrorKind::Other.err("service is not alive")
+            }
+            let response = service.dispatch(request).await;
+            *capability.write().unwrap() = service.capability();
+            Ok(response)
+        }.boxed()
+    }
+}
+
+
+/// Service dispatching requests to a tower service handling the messages
+/// of `S`. Requests failing with an error are answered with a
+/// `message::Error::Internal`. Callers' capability is the one of the
+/// `IntoTower` adapter's service.
+pub struct FromTower<T, S: Service> {
+    inner: T,
+    capability: Arc<RwLock<S::Capability>>,
+    phantom: PhantomData<fn() -> S>,
+}
+
+impl<T, S> FromTower<T, S>
+    where S: Service, T: tower_service::Service<S::Request, Response=Option<S::Response>>
+{
+    /// Serve tower stack `inner`, built on top of `service`.
+    pub fn new(inner: T, service: &IntoTower<S>) -> Self {
+        Self { inner, capability: service.capability.clone(), phantom: PhantomData }
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, S> FromTower<T, S>
+    where S: Service
+{
+    /// Response to a request failing with `error`. Notifications are not
+    /// answered, since the client does not read their response.
+    fn failed(request: Option<Frame>, call_id: Option<u64>, error: impl Display) -> Option<S::Response> {
+        match request {
+            Some(Frame::Request(Call { reply: Reply::None, .. })) => None,
+            _ => S::error(call_id, Error::Internal(error.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> Service for FromTower<T, S>
+    where S: Service,
+          T: tower_service::Service<S::Request, Response=Option<S::Response>>+Send+Sync+Unpin,
+          T::Future: Send, T::Error: Display
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.capability.read().unwrap().clone()
+    }
+
+    super::super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let (frame, call_id) = (S::request_frame(&request), S::call_id(&request));
+        if let Err(err) = future::poll_fn(|cx| self.inner.poll_ready(cx)).await {
+            return Self::failed(frame, call_id, err)
+        }
+        match self.inner.call(request).await {
+            Ok(response) => response,
+            Err(err) => Self::failed(frame, call_id, err),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use futures::executor::LocalPool;
+    use futures::future::join;
+    use tower_service::Service as _;
+
+    use super::*;
+    use crate::data::Capability;
+    use crate::rpc::call::CallError;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    /// Tower middleware failing calls once `max` calls have been made.
+    struct Limit<T> {
+        inner: T,
+        calls: Arc<AtomicU32>,
+        max: u32,
+    }
+
+    impl<T, Req> tower_service::Service<Req> for Limit<T>
+        where T: tower_service::Service<Req, Error=crate::Error>, T::Response: 'static+Send,
+              T::Future: 'static+Send
+    {
+        type Response = T::Response;
+        type Error = crate::Error;
+        type Future = BoxFuture<'static, Result<T::Response>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: Req) -> Self::Future {
+            match self.calls.fetch_add(1, Ordering::Relaxed) < self.max {
+                true => self.inner.call(request).boxed(),
+                false => future::ready(ErrorKind::LimitReached.err("too many calls")).boxed(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_into_tower() {
+        let mut service = IntoTower::new(simple_service::Service::new());
+        LocalPool::new().run_until(async {
+            let response = service.call(simple_service::Request::Add(3)).await.unwrap();
+            assert!(matches!(response, Some(simple_service::Response::Add(3))));
+            let response = service.clone().call(simple_service::Request::Get()).await.unwrap();
+            assert!(matches!(response, Some(simple_service::Response::Get(3))));
+        });
+    }
+
+    #[test]
+    fn test_from_tower_capability() {
+        use crate::data::{signature::Dalek, testing};
+        use crate::services::auth::{Auth, AuthOptions};
+
+        let service = IntoTower::new(simple_service::Service::new());
+        assert!(FromTower::new(service.clone(), &service).capability() == Capability::new(u64::MAX, 0));
+
+        // unauthenticated peers have no capability
+        let issuer = testing::signer::<Dalek>(0).public;
+        let auth = Auth::<_, Dalek>::new(simple_service::Service::new(), issuer, [0u8; 32],
+                                         AuthOptions::default());
+        let service = IntoTower::new(auth);
+        assert!(FromTower::new(service.clone(), &service).capability().is_empty());
+    }
+
+    #[test]
+    fn test_from_tower() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let (server_transport, client_transport) =
+            MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service::Client::new(client_transport);
+            assert_eq!(client.add(4).await, Ok(4));
+            assert_eq!(client.add(1).await, Ok(5));
+            assert_eq!(client.get().await, Err(CallError::Server(
+                Error::Internal(ErrorKind::LimitReached.error("too many calls").to_string()))));
+        };
+        let server_fut = {
+            let calls = calls.clone();
+            async move {
+                let (s,r) = server_transport.split();
+                let service = IntoTower::new(simple_service::Service::new());
+                let stack = Limit { inner: service.clone(), calls, max: 2 };
+                let mut service = FromTower::new(stack, &service);
+                service.serve(Transport::new(s, r)).await;
+            }
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+}
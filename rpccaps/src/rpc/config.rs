@@ -1,5 +1,6 @@
 use std::{
     convert::TryInto,
+    net::SocketAddr,
     path::PathBuf,
     sync::Arc,
     time::Duration,
@@ -8,8 +9,47 @@ use std::{
 use serde::{Deserialize,Serialize};
 use crate::{
     ErrorKind, Result,
-    data::tls,
+    data::{tls, Clock, SystemClock},
 };
+use super::admission::RateLimit;
+use super::backpressure::Watermarks;
+use super::budget::TimeBudget;
+use super::codec::CodecLimits;
+use super::filter::AddressFilter;
+use super::version;
+
+
+/// Transport of connections.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum TransportKind {
+    /// QUIC, multiplexing streams over a connection.
+    #[default]
+    Quic,
+    /// Plain TCP, with a connection per stream (see `transport::tcp`).
+    Tcp,
+    /// TCP secured by TLS, with a connection per stream.
+    TcpTls,
+}
+
+impl TransportKind {
+    /// Return true if transport is carried by TCP.
+    pub fn is_tcp(&self) -> bool {
+        !matches!(self, Self::Quic)
+    }
+}
+
+/// Policy by which a `client::Pool` picks the connection of new streams.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum Balance {
+    /// Each connection in turn.
+    #[default]
+    RoundRobin,
+    /// Connection with the fewest pending streams.
+    LeastPending,
+    /// Connection with the lowest round-trip time, weighted by its pending
+    /// streams.
+    Latency,
+}
 
 
 /// Connection configuration
@@ -18,16 +58,40 @@ pub struct ConnectionConfig {
     pub cert_data: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
     /// Endpoint's certificate and private key's file path
     pub cert_path: Option<(PathBuf, PathBuf)>,
+    /// Endpoint's private key source, used instead of `cert_path`'s key
+    /// path when provided. A file descriptor source can only be loaded
+    /// once (see `tls::FdKey`).
+    pub private_key: Option<tls::KeySource>,
     /// Endpoint's certificate subjects' names
     pub cert_subjects: Vec<String>,
-    /// If true, create cert when missing
+    /// If true, create cert when missing. When `cert_path` is set, created
+    /// cert and key are written there, to be reused on next startup.
     pub create_cert: bool,
+    /// If true, generate an ephemeral certificate when the configured one is
+    /// invalid, instead of failing. Only intended for development.
+    pub dev_mode: bool,
     /// Maximum concurrent bidirectional streams per peer
     pub concurrent_streams: u32,
     /// Maximum connection idle timeout
     pub idle_timeout: Duration,
-    /// Wether client must authenticate
+    /// Maximum duration of a stream's header (handshake and service's id)
+    /// and, over TCP, of the TLS handshake. Peers exceeding it are
+    /// disconnected. Unlimited if None.
+    pub handshake_timeout: Option<Duration>,
+    /// Wether client must authenticate. Servers then require clients to
+    /// present a certificate issued by one of their `client_roots`.
     pub with_no_client_auth: bool,
+    /// Application protocols negotiated by ALPN, in order of preference.
+    /// Not negotiated when empty. Defaults to the supported protocol
+    /// versions (see `version::alpn_protocols`).
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Transport of connections. Servers and clients over TCP are the
+    /// ones of `transport::tcp`, whose `AnyServer` and `AnyClient` select
+    /// the configured one.
+    pub transport: TransportKind,
+    /// Limits of the messages exchanged on streams, enforced by their
+    /// codec: frames over `max_size` are refused from their header.
+    pub codec_limits: CodecLimits,
 }
 
 
@@ -41,10 +105,35 @@ pub struct ServerConfig {
     pub migration: bool,
     /// Enable stateless retries
     pub stateless_retry: bool,
+    /// Filter incoming connections by remote address, before handshake.
+    pub address_filter: AddressFilter,
+    /// Interval of expired state's cleanup. Disabled if None.
+    pub reap_interval: Option<Duration>,
+    /// Maximum in-flight streams among all connections. Once reached,
+    /// streams are admitted in turn across connections.
+    pub max_streams: usize,
+    /// Maximum in-flight streams of a single connection.
+    pub connection_streams: usize,
+    /// Watermarks of queued streams (in-flight and waiting for admission)
+    /// at which `ServerEvent::Backpressure` is emitted.
+    pub backpressure: Option<Watermarks>,
+    /// Maximum open connections per remote IP. Connections above it are
+    /// closed once established, with a `Rejection::TooManyConnections`.
+    pub ip_connections: Option<usize>,
+    /// Rate limit of new streams per connection, or per remote IP over
+    /// TCP. Streams above it are reset with a `Rejection::RateLimited`.
+    pub stream_rate: Option<RateLimit>,
+    /// Execution time budget of each authenticated peer's identity. Streams
+    /// above it are reset with a `Rejection::BudgetExhausted`.
+    pub identity_budget: Option<TimeBudget>,
+    /// Certificate authorities allowed to issue clients' certificates, when
+    /// client authentication is required.
+    pub client_roots: rustls::RootCertStore,
 }
 
 
 /// Client configuration
+#[derive(Default)]
 pub struct ClientConfig {
     /// Connection configuration
     pub connection_config: ConnectionConfig,
@@ -52,10 +141,18 @@ pub struct ClientConfig {
     pub system_certs: bool,
     /// Provide certificate authorities from provided files
     pub root_certs: Vec<PathBuf>,
+    /// Only accept server certificates read from provided files, instead
+    /// of verifying them against certificate authorities (e.g. self-signed
+    /// certificates of peer-to-peer deployments).
+    pub pinned_certs: Vec<PathBuf>,
+    /// Servers connected by a `client::Pool`, as addresses and names for
+    /// their certificate validation (e.g. replicas of a stateless service).
+    pub servers: Vec<(SocketAddr, String)>,
+    /// How calls are spread across `servers`.
+    pub balance: Balance,
 }
 
 
-
 impl ConnectionConfig {
     /// Initialize ``quinn::Transport`` based on self's parameters.
     pub fn set_transport_config(&self, transport: &mut quinn::TransportConfig) {
@@ -65,17 +162,27 @@ impl ConnectionConfig {
     }
 
     /// Get certificate and private key based on self's parameters.
+    /// `private_key` requires `cert_path`, holding the certificate.
     pub fn get_cert(&self, create_cert: bool)
         -> Result<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>>
     {
+        if self.private_key.is_some() && (self.cert_data.is_some() || self.cert_path.is_none()) {
+            return ErrorKind::Config.err("private key source requires cert_path, without cert_data");
+        }
         match self.cert_data {
             Some((ref cert, ref key)) => Ok(Some((cert.clone(), key.clone()))),
             None => match self.cert_path {
+                Some((ref cert_path, ref key_path)) if create_cert && self.is_cert_missing() => {
+                    let (cert, key) = tls::new_cert(self.cert_subjects.clone())?;
+                    tls::write_cert(&cert, &key, cert_path, key_path)?;
+                    Ok(Some((cert, key)))
+                },
                 Some((ref cert_path, ref key_path)) => {
                     let cert = tls::cert_from_file(cert_path)?;
-                    let key = tls::private_key_from_file(key_path)?;
-
-                    // TODO: write cert
+                    let key = match self.private_key {
+                        Some(ref source) => source.load()?,
+                        None => tls::private_key_from_file(key_path)?,
+                    };
                     Ok(Some((cert, key)))
                 },
                 None if create_cert => tls::new_cert(self.cert_subjects.clone())
@@ -84,6 +191,54 @@ impl ConnectionConfig {
             }
         }
     }
+
+    /// Return true if no certificate is configured, or if it has not been
+    /// created yet at `cert_path`. Certificates are not created when the
+    /// private key has another source.
+    pub fn is_cert_missing(&self) -> bool {
+        match (&self.cert_data, &self.cert_path) {
+            (Some(_), _) => false,
+            (None, Some((cert_path, _))) => self.private_key.is_none() && !cert_path.exists(),
+            (None, None) => self.private_key.is_none(),
+        }
+    }
+
+    /// Check configured certificate and private key, returning report of
+    /// the issues found.
+    pub fn check_cert(&self) -> tls::CertReport {
+        if self.create_cert && self.is_cert_missing() {
+            return tls::CertReport::default()
+        }
+        self.report(&self.get_cert(false))
+    }
+
+    /// Return report of loaded certificate and private key.
+    fn report(&self, cert: &Result<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>>)
+        -> tls::CertReport
+    {
+        match cert {
+            Ok(Some((certs, key))) =>
+                tls::check_cert(certs, key, &self.cert_subjects, SystemClock.timestamp()),
+            Ok(None) => tls::CertReport { issues: vec![tls::CertIssue::Missing] },
+            Err(err) => tls::CertReport { issues: vec![tls::CertIssue::Unreadable(err.to_string())] },
+        }
+    }
+
+    /// Get certificate and private key after checking them. When issues are
+    /// found, return an error describing them or, in dev mode, an ephemeral
+    /// certificate. Certificate and key are only loaded once.
+    pub fn get_checked_cert(&self)
+        -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)>
+    {
+        let cert = self.get_cert(self.create_cert && self.is_cert_missing());
+        let report = self.report(&cert);
+        match (report.is_ok(), self.dev_mode, cert) {
+            (_, _, Err(err)) if err.kind() == ErrorKind::Config => Err(err),
+            (true, _, Ok(Some(cert))) => Ok(cert),
+            (false, true, _) => tls::new_cert(self.cert_subjects.clone()),
+            (_, _, _) => ErrorKind::Certificate.err(report.to_string()),
+        }
+    }
 }
 
 impl Default for ConnectionConfig {
@@ -91,20 +246,34 @@ impl Default for ConnectionConfig {
         Self {
             cert_data: None,
             cert_path: None,
+            private_key: None,
             cert_subjects: vec![String::from("localhost")],
             create_cert: true,
+            dev_mode: false,
             concurrent_streams: 32,
             idle_timeout: Duration::from_secs(10),
+            handshake_timeout: Some(Duration::from_secs(10)),
             with_no_client_auth: true,
+            alpn_protocols: version::alpn_protocols(),
+            transport: TransportKind::default(),
+            codec_limits: CodecLimits::default(),
         }
     }
 }
 
 
 impl ServerConfig {
+    /// Return true if clients are required to authenticate.
+    pub fn client_auth(&self) -> bool {
+        !self.connection_config.with_no_client_auth
+    }
+
     /// Return quinn server configuration.
     pub fn get_server_config(&self) -> Result<quinn::ServerConfig>
     {
+        if self.connection_config.transport.is_tcp() {
+            return ErrorKind::Config.err("server is configured for TCP transport");
+        }
         let crypto = self.get_tls_config()?;
         let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
         server_config.concurrent_connections(self.concurrent_connections)
@@ -115,21 +284,32 @@ impl ServerConfig {
         Ok(server_config)
     }
 
+    /// Allow clients' certificates issued by authorities read from
+    /// provided file.
+    pub fn add_client_roots(&mut self, path: &PathBuf) -> Result<()> {
+        for cert in tls::cert_from_file(path)? {
+            self.client_roots.add(&cert)
+                .or(ErrorKind::Certificate.err("invalid client authority certificate"))?;
+        }
+        Ok(())
+    }
+
     /// Initialize ``rustls::ConfigBuilder`` based on self's parameters.
     pub fn get_tls_config(&self) -> Result<rustls::ServerConfig>
     {
-        let certs_key = match self.connection_config.get_cert(self.connection_config.create_cert)? {
-            Some(certs_key) => certs_key,
-            None => return ErrorKind::ValueError.err("no certificate specified"),
-        };
+        let certs_key = self.connection_config.get_checked_cert()?;
         let builder = rustls::ServerConfig::builder().with_safe_defaults();
-        /*match self.connection_config.with_no_client_auth {
-            true => */  /*,
-            false => Ok(builder.with_single_cert(certs_key.0, certs_key.1)),
-        }*/
-        builder.with_no_client_auth()
-               .with_single_cert(certs_key.0, certs_key.1)
-               .or(ErrorKind::Certificate.err("invalid certificate at init client config"))
+        let builder = match self.client_auth() {
+            true if self.client_roots.is_empty() => return ErrorKind::Config.err(
+                "client authentication requires at least one client root certificate"),
+            true => builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(self.client_roots.clone())),
+            false => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs_key.0, certs_key.1)
+                                .or(ErrorKind::Certificate.err("invalid certificate at init server config"))?;
+        config.alpn_protocols = self.connection_config.alpn_protocols.clone();
+        Ok(config)
     }
 }
 
@@ -140,6 +320,15 @@ impl Default for ServerConfig {
             concurrent_connections: 32,
             stateless_retry: false,
             migration: false,
+            address_filter: AddressFilter::default(),
+            reap_interval: Some(Duration::from_secs(60)),
+            max_streams: 1024,
+            connection_streams: 64,
+            backpressure: None,
+            ip_connections: None,
+            stream_rate: None,
+            identity_budget: None,
+            client_roots: rustls::RootCertStore::empty(),
         }
     }
 }
@@ -149,46 +338,68 @@ impl ClientConfig {
     /// Return quinn client configuration.
     pub fn get_client_config(&self) -> Result<quinn::ClientConfig>
     {
+        if self.connection_config.transport.is_tcp() {
+            return ErrorKind::Config.err("client is configured for TCP transport");
+        }
         let crypto = self.get_tls_config()?;
         let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
         let ref mut transport = Arc::get_mut(&mut client_config.transport).unwrap();
         self.connection_config.set_transport_config(transport);
+        // accept server's control stream
+        transport.max_concurrent_uni_streams(1_u8.into());
         Ok(client_config)
     }
 
-    /// Initialize ``rustls::ConfigBuilder`` based on self's parameters.
-    pub fn get_tls_config(&self) -> Result<rustls::ClientConfig>
-    {
-        let certs_key = self.connection_config.get_cert(self.connection_config.create_cert)?;
+    /// Return certificate authorities read from `root_certs`, along with
+    /// platform's trusted ones when `system_certs` is set.
+    pub fn get_root_certs(&self) -> Result<rustls::RootCertStore> {
         let mut roots = rustls::RootCertStore::empty();
-
-        for cert_path in self.root_certs.iter() {
-            for ref mut cert in tls::cert_from_file(cert_path)? {
-                roots.add(cert)
-                     .or(ErrorKind::Certificate.err("invalid authority certificate"));
+        if self.system_certs {
+            let certs = rustls_native_certs::load_native_certs()
+                .or_else(|err| ErrorKind::Certificate.err(
+                    format!("can't load system certificates: {}", err)))?;
+            // platform stores may hold certificates webpki can't parse
+            let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+            if let (0, invalid) = roots.add_parsable_certificates(&certs) {
+                if invalid > 0 {
+                    return ErrorKind::Certificate.err("no valid system certificate");
+                }
             }
         }
-
-        let builder = rustls::ClientConfig::builder()
-                                .with_safe_defaults()
-                                .with_root_certificates(roots);
-        // TODO: errors handling
-        match (self.connection_config.with_no_client_auth, certs_key) {
-            (true, Some((certs, key))) => Ok(builder.with_single_cert(certs, key).unwrap()),
-            (true, None) => ErrorKind::ValueError.err(
-                "missing certificate while specifying `with_no_client_auth`"),
-            (false, _) => Ok(builder.with_no_client_auth()),
+        for cert_path in self.root_certs.iter() {
+            for cert in tls::cert_from_file(cert_path)? {
+                roots.add(&cert)
+                     .or(ErrorKind::Certificate.err("invalid authority certificate"))?;
+            }
         }
+        Ok(roots)
     }
-}
 
-impl Default for ClientConfig {
-    fn default() -> Self {
-        Self {
-            connection_config: ConnectionConfig::default(),
-            system_certs: false,
-            root_certs: Vec::new(),
-        }
+    /// Initialize ``rustls::ConfigBuilder`` based on self's parameters.
+    pub fn get_tls_config(&self) -> Result<rustls::ClientConfig>
+    {
+        let certs_key = self.connection_config.get_cert(self.connection_config.create_cert)?;
+        let builder = rustls::ClientConfig::builder().with_safe_defaults();
+        let verifier: Arc<dyn rustls::client::ServerCertVerifier> = match self.pinned_certs.is_empty() {
+            true => Arc::new(rustls::client::WebPkiVerifier::new(self.get_root_certs()?, None)),
+            false => {
+                let mut pinned = Vec::new();
+                for cert_path in self.pinned_certs.iter() {
+                    pinned.extend(tls::cert_from_file(cert_path)?);
+                }
+                Arc::new(tls::PinnedCertVerifier::new(pinned))
+            },
+        };
+        let builder = builder.with_custom_certificate_verifier(verifier);
+        let mut config = match (self.connection_config.with_no_client_auth, certs_key) {
+            (false, Some((certs, key))) => builder.with_single_cert(certs, key)
+                .or(ErrorKind::Certificate.err("invalid certificate at init client config"))?,
+            (false, None) => return ErrorKind::ValueError.err(
+                "missing certificate while client authentication is required"),
+            (true, _) => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.connection_config.alpn_protocols.clone();
+        Ok(config)
     }
 }
 
@@ -205,6 +416,87 @@ pub mod tests {
         let quinn_config = config.get_server_config().unwrap();
     }
 
+    #[test]
+    fn test_checked_cert() {
+        let mut config = ConnectionConfig::default();
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        config.cert_data = Some((certs, key));
+        assert!(config.check_cert().is_ok());
+
+        config.cert_subjects = vec![String::from("example.com")];
+        let err = config.get_checked_cert().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Certificate);
+
+        config.dev_mode = true;
+        let (certs, _) = config.get_checked_cert().unwrap();
+        assert_ne!(Some(&certs), config.cert_data.as_ref().map(|(c, _)| c));
+
+        config.cert_data = None;
+        config.create_cert = false;
+        assert_eq!(config.check_cert().issues, vec![tls::CertIssue::Missing]);
+
+        // private key source without certificate path is a misconfiguration
+        config.create_cert = true;
+        config.private_key = Some(tls::KeySource::Env(String::from("RPCCAPS_TEST_CONFIG_KEY")));
+        assert!(!config.is_cert_missing());
+        assert_eq!(config.get_checked_cert().unwrap_err().kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn test_client_auth_config() {
+        let mut config = ServerConfig::default();
+        config.connection_config.with_no_client_auth = false;
+        assert_eq!(config.get_tls_config().unwrap_err().kind(), ErrorKind::Config);
+
+        let (certs, _) = tls::new_cert(vec![String::from("client")]).unwrap();
+        config.client_roots.add(&certs[0]).unwrap();
+        assert!(config.get_tls_config().is_ok());
+
+        let mut config = ClientConfig::default();
+        config.connection_config.with_no_client_auth = false;
+        config.connection_config.create_cert = false;
+        assert_eq!(config.get_tls_config().unwrap_err().kind(), ErrorKind::ValueError);
+    }
+
+    #[test]
+    fn test_persisted_cert() {
+        for ext in ["pem", "der"] {
+            let cert_path = std::env::temp_dir().join(format!("rpccaps-test-persisted-cert.{}", ext));
+            let key_path = std::env::temp_dir().join(format!("rpccaps-test-persisted-key.{}", ext));
+            let _ = std::fs::remove_file(&cert_path);
+
+            let config = ConnectionConfig {
+                cert_path: Some((cert_path.clone(), key_path.clone())),
+                ..Default::default()
+            };
+            assert!(config.is_cert_missing());
+            let (certs, key) = config.get_checked_cert().unwrap();
+            assert!(!config.is_cert_missing());
+
+            // written cert is reused
+            assert_eq!(config.get_checked_cert().unwrap(), (certs.clone(), key.clone()));
+            assert_eq!(tls::cert_from_file(&cert_path).unwrap(), certs);
+            assert_eq!(tls::private_key_from_file(&key_path).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn test_root_certs() {
+        let (certs, key) = tls::new_cert(vec![String::from("authority")]).unwrap();
+        let cert_path = std::env::temp_dir().join("rpccaps-test-root.der");
+        let key_path = std::env::temp_dir().join("rpccaps-test-root-key.der");
+        tls::write_cert(&certs, &key, &cert_path, &key_path).unwrap();
+
+        let mut config = ClientConfig { root_certs: vec![cert_path], ..Default::default() };
+        let roots = config.get_root_certs().unwrap();
+        assert_eq!(roots.len(), 1);
+
+        // system certificates are loaded alongside provided ones
+        config.system_certs = true;
+        assert!(config.get_root_certs().unwrap().len() >= roots.len());
+        config.get_tls_config().unwrap();
+    }
+
     #[test]
     fn test_default_client_config() {
         let config = ClientConfig::default();
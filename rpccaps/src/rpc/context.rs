@@ -0,0 +1,286 @@
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{ErrorKind, Result};
+use crate::data::{presentation::{ChannelBinding,Presentation,ReferenceBundle}, signature::SignMethod, validate::Validate};
+use crate::data::Reference;
+use super::deps::Dependencies;
+use super::revocation::Revocations;
+use super::version;
+
+
+/// Label used to derive channel binding from TLS exporter.
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-rpccaps-channel-binding";
+
+
+/// Return channel binding material derived from connection's TLS session.
+pub fn channel_binding(connection: &quinn::Connection) -> Result<ChannelBinding> {
+    let mut binding = [0u8;32];
+    connection.export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, b"")
+              .or(ErrorKind::Internal.err("can not export channel binding"))?;
+    Ok(binding)
+}
+
+
+/// Connection context shared among services dispatched on a same connection.
+pub trait Context: Send+Sync {
+    /// Create context from a newly established connection.
+    fn from_connection(endpoint: quinn::Endpoint, connection: quinn::Connection) -> Self;
+
+    /// Return endpoint that accepted the connection.
+    fn endpoint(&self) -> &quinn::Endpoint;
+
+    /// Return underlying connection.
+    fn connection(&self) -> &quinn::Connection;
+
+    /// Attach dependencies registered on the server. By default, they are
+    /// not kept.
+    fn with_dependencies(self, _dependencies: Arc<Dependencies>) -> Self where Self: Sized {
+        self
+    }
+
+    /// Return dependencies registered on the server, if kept.
+    fn dependencies(&self) -> Option<&Arc<Dependencies>> {
+        None
+    }
+
+    /// Return channel binding material derived from the TLS session. Both
+    /// peers of a connection get the same value, while it differs from
+    /// one connection to another.
+    fn channel_binding(&self) -> Result<ChannelBinding> {
+        channel_binding(self.connection())
+    }
+
+    /// Validate a presentation made by the peer over this connection.
+    fn validate_presentation<Id,Sign>(&self, presentation: &Presentation<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod+Serialize
+    {
+        let binding = self.channel_binding()?;
+        presentation.validate(&binding)
+                    .or(ErrorKind::InvalidData.err("invalid presentation"))?;
+        self.hold(presentation.reference())
+    }
+
+    /// Validate a reference bundle made by the peer over this connection.
+    fn validate_bundle<Id,Sign>(&self, bundle: &ReferenceBundle<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod+Serialize
+    {
+        let binding = self.channel_binding()?;
+        bundle.validate(&binding)
+              .or(ErrorKind::InvalidData.err("invalid reference bundle"))?;
+        bundle.references().iter().try_for_each(|reference| self.hold(reference))
+    }
+
+    /// Record that the peer holds provided reference, so that its
+    /// revocation is pushed to this connection. Fail if it has been
+    /// revoked. Nothing is recorded without the server's `Revocations`
+    /// among dependencies.
+    fn hold<Id,Sign>(&self, reference: &Reference<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        let revocations = match self.dependencies().and_then(|deps| deps.get::<Revocations>()) {
+            Some(revocations) => revocations,
+            None => return Ok(()),
+        };
+        let id = reference.object_id().or(ErrorKind::InvalidData.err("invalid reference"))?;
+        revocations.hold(self.connection().stable_id(), id)
+    }
+
+    /// Return certificates chain presented by the peer, if any.
+    fn peer_certs(&self) -> Option<Vec<rustls::Certificate>> {
+        self.connection().peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+            .map(|certs| *certs)
+    }
+
+    /// Return peer's end-entity certificate, identifying a client verified
+    /// against server's `client_roots`.
+    fn peer_certificate(&self) -> Option<rustls::Certificate> {
+        self.peer_certs().and_then(|certs| certs.into_iter().next())
+    }
+
+    /// Return peer's address.
+    fn remote_address(&self) -> SocketAddr {
+        self.connection().remote_address()
+    }
+
+    /// Return application protocol negotiated by ALPN, if any.
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.handshake_data().and_then(|data| data.protocol)
+    }
+
+    /// Return peer's protocol version: the one of the handshake of the
+    /// stream being dispatched, or as negotiated by ALPN. It falls back to
+    /// `version::peer_version()` otherwise.
+    fn protocol_version(&self) -> u16 {
+        version::stream_version()
+            .or_else(|| self.alpn_protocol().and_then(|protocol| version::from_alpn(&protocol)))
+            .unwrap_or_else(version::peer_version)
+    }
+
+    /// Return server name requested by the peer (SNI), if any.
+    fn server_name(&self) -> Option<String> {
+        self.handshake_data().and_then(|data| data.server_name)
+    }
+
+    /// Return TLS handshake's data, once completed.
+    fn handshake_data(&self) -> Option<quinn::crypto::rustls::HandshakeData> {
+        self.connection().handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .map(|data| *data)
+    }
+
+    /// Return peer's information, as used for authorization decisions.
+    fn peer_info(&self) -> PeerInfo {
+        let handshake = self.handshake_data();
+        PeerInfo {
+            address: self.remote_address(),
+            alpn_protocol: handshake.as_ref().and_then(|data| data.protocol.clone()),
+            server_name: handshake.and_then(|data| data.server_name),
+            certs: self.peer_certs(),
+        }
+    }
+}
+
+
+/// Peer's connection information.
+#[derive(Clone,Debug,PartialEq)]
+pub struct PeerInfo {
+    /// Peer's address.
+    pub address: SocketAddr,
+    /// Application protocol negotiated by ALPN.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Server name requested by the peer.
+    pub server_name: Option<String>,
+    /// Certificates chain presented by the peer, when client
+    /// authentication is enabled.
+    pub certs: Option<Vec<rustls::Certificate>>,
+}
+
+impl PeerInfo {
+    /// Return true if the peer presented certificates.
+    pub fn is_authenticated(&self) -> bool {
+        self.certs.is_some()
+    }
+}
+
+
+/// Context of a connection whose peer presented a verified identity.
+pub struct AuthenticatedContext<C> {
+    context: Arc<C>,
+    identity: Vec<rustls::Certificate>,
+}
+
+impl<C: Context> AuthenticatedContext<C> {
+    /// Create authenticated context, failing when the peer has no identity.
+    pub fn new(context: Arc<C>) -> Result<Self> {
+        let identity = context.peer_certs()
+                              .ok_or(ErrorKind::NotFound.error("peer has no identity"))?;
+        Ok(Self { context, identity })
+    }
+
+    /// Return underlying context.
+    pub fn context(&self) -> &Arc<C> {
+        &self.context
+    }
+
+    /// Return certificates chain presented by the peer.
+    pub fn identity(&self) -> &[rustls::Certificate] {
+        &self.identity
+    }
+}
+
+impl<C> Clone for AuthenticatedContext<C> {
+    fn clone(&self) -> Self {
+        Self { context: self.context.clone(), identity: self.identity.clone() }
+    }
+}
+
+impl<C> Deref for AuthenticatedContext<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.context
+    }
+}
+
+
+/// Connection context, distinguishing anonymous peers from authenticated
+/// ones.
+pub enum TypedContext<C> {
+    Anonymous(Arc<C>),
+    Authenticated(AuthenticatedContext<C>),
+}
+
+impl<C: Context> TypedContext<C> {
+    pub fn new(context: Arc<C>) -> Self {
+        match context.peer_certs() {
+            Some(identity) => Self::Authenticated(AuthenticatedContext { context, identity }),
+            None => Self::Anonymous(context),
+        }
+    }
+
+    /// Return underlying context.
+    pub fn context(&self) -> &Arc<C> {
+        match self {
+            Self::Anonymous(context) => context,
+            Self::Authenticated(context) => context.context(),
+        }
+    }
+
+    /// Return true if the peer is authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self, Self::Authenticated(_))
+    }
+
+    /// Return authenticated context, if any.
+    pub fn authenticated(&self) -> Option<&AuthenticatedContext<C>> {
+        match self {
+            Self::Authenticated(context) => Some(context),
+            Self::Anonymous(_) => None,
+        }
+    }
+}
+
+impl<C> Clone for TypedContext<C> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Anonymous(context) => Self::Anonymous(context.clone()),
+            Self::Authenticated(context) => Self::Authenticated(context.clone()),
+        }
+    }
+}
+
+
+/// Default context, only keeping connection information.
+pub struct DefaultContext {
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    dependencies: Option<Arc<Dependencies>>,
+}
+
+impl Context for DefaultContext {
+    fn from_connection(endpoint: quinn::Endpoint, connection: quinn::Connection) -> Self {
+        Self { endpoint, connection, dependencies: None }
+    }
+
+    fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+
+    fn connection(&self) -> &quinn::Connection {
+        &self.connection
+    }
+
+    fn with_dependencies(mut self, dependencies: Arc<Dependencies>) -> Self {
+        self.dependencies = Some(dependencies);
+        self
+    }
+
+    fn dependencies(&self) -> Option<&Arc<Dependencies>> {
+        self.dependencies.as_ref()
+    }
+}
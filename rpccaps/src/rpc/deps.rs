@@ -0,0 +1,94 @@
+//! Typed container of dependencies shared with services.
+//!
+//! The server's host registers shared dependencies (database pools, key
+//! stores, configuration...) at startup, and service builders resolve them
+//! by type from the connection context, using the `Dep<T>` extractor:
+//!
+//! ```ignore
+//! server.dependencies.insert(pool);
+//! server.add_context_builder(0, |Dep(pool): Dep<Pool>| Service::new(pool),
+//!                            HandlerOptions::default())?;
+//! ```
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+
+/// Dependencies, registered and resolved by type.
+#[derive(Default)]
+pub struct Dependencies {
+    values: RwLock<HashMap<TypeId, Arc<dyn Any+Send+Sync>>>,
+}
+
+impl Dependencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a dependency, replacing and returning the previous one of
+    /// the same type.
+    pub fn insert<T: Any+Send+Sync>(&self, value: T) -> Option<Arc<T>> {
+        self.insert_arc(Arc::new(value))
+    }
+
+    /// Register a dependency already shared by the host.
+    pub fn insert_arc<T: Any+Send+Sync>(&self, value: Arc<T>) -> Option<Arc<T>> {
+        self.values.write().unwrap().insert(TypeId::of::<T>(), value)
+            .and_then(|value| value.downcast().ok())
+    }
+
+    /// Resolve dependency of type `T`.
+    pub fn get<T: Any+Send+Sync>(&self) -> Option<Arc<T>> {
+        self.values.read().unwrap().get(&TypeId::of::<T>())
+            .and_then(|value| value.clone().downcast().ok())
+    }
+
+    /// Return true if a dependency of type `T` is registered.
+    pub fn contains<T: Any+Send+Sync>(&self) -> bool {
+        self.values.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Remove dependency of type `T`, returning it.
+    pub fn remove<T: Any+Send+Sync>(&self) -> Option<Arc<T>> {
+        self.values.write().unwrap().remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+    }
+
+    /// Count of registered dependencies.
+    pub fn len(&self) -> usize {
+        self.values.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug,PartialEq)]
+    struct Pool(u32);
+
+    #[test]
+    fn test_dependencies() {
+        let deps = Dependencies::new();
+        assert!(deps.get::<Pool>().is_none());
+        assert!(deps.insert(Pool(1)).is_none());
+        deps.insert(String::from("config"));
+
+        assert_eq!(deps.get::<Pool>().as_deref(), Some(&Pool(1)));
+        assert_eq!(deps.insert(Pool(2)).as_deref(), Some(&Pool(1)));
+        assert_eq!(deps.get::<Pool>().as_deref(), Some(&Pool(2)));
+        assert!(deps.contains::<String>());
+        assert_eq!(deps.len(), 2);
+
+        let shared = Arc::new(Pool(3));
+        deps.insert_arc(shared.clone());
+        assert!(Arc::ptr_eq(&deps.get::<Pool>().unwrap(), &shared));
+        assert_eq!(deps.remove::<Pool>().as_deref(), Some(&Pool(3)));
+        assert!(!deps.contains::<Pool>());
+    }
+}
@@ -1,15 +1,36 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::marker::PhantomData;
-use std::sync::{RwLock, atomic::{AtomicU32, Ordering}};
+use std::sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicU32, Ordering}};
 use std::pin::Pin;
+use std::time::Duration;
 
+#[cfg(not(feature="rwlock-dispatch"))]
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use futures::channel::oneshot;
 use futures::prelude::*;
 use serde::{Deserialize,Serialize};
 use futures::io::{AsyncRead,AsyncWrite};
 
 use crate::{ErrorKind, Result};
-use super::codec::{BincodeCodec,Decoder,Framed};
+use crate::data::{Clock, SystemClock};
+use super::admission::RateLimit;
+use super::codec::{CodecFactory,CodecLimits,Decoder,Framed};
+use super::enforce::Fingerprint;
+use super::handshake::{Handshake, WireCodec};
+use super::leak;
+use super::message::Rejection;
+#[cfg(feature="metrics")]
+use super::metrics::Collector;
+use super::reaper::Reap;
+use super::schema::Schema;
 use super::service::Service;
+use super::throttle::{Throttle, Throttled};
+use super::trace::{self, Instrument};
+use super::transport::Transport;
+use super::version::{self, Downgraded};
+
+use probe::Probe;
 
 
 pub type HandlerFn<D> = Box<dyn Send+Sync+Unpin+Fn(D) -> Pin<Box<dyn Future<Output=()>+Send>>>;
@@ -20,6 +41,412 @@ pub struct Handler<D> {
     pub func: HandlerFn<D>,
     /// If true, remove handler after call.
     pub once: bool,
+    /// Priority of the handler's streams.
+    pub priority: Option<i32>,
+    /// Time (since UNIX epoch) after which handler is removed.
+    pub expires: Option<Duration>,
+    /// Maximum duration of handler's calls.
+    pub timeout: Option<Duration>,
+    /// If true, timed out calls run to completion on a detached task
+    /// instead of being dropped.
+    pub detach: bool,
+    /// Description of the service, for handlers registered as services.
+    pub info: Option<ServiceInfo>,
+    /// Draining state and running calls of the handler.
+    pub drain: Arc<Drain>,
+}
+
+impl<D> Handler<D> {
+    /// Return true if handler is expired at provided time.
+    pub fn is_expired(&self, now: Duration) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+
+/// Draining state of a handler (see `Dispatch::drain`): once draining, new
+/// calls are refused while running ones finish.
+#[derive(Debug,Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    running: AtomicU32,
+    /// Senders notified once running calls are done.
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl Drain {
+    /// Return true if handler is draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Return count of handler's running calls.
+    pub fn running(&self) -> u32 {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Mark handler as draining, returning a future resolved once its
+    /// running calls are done.
+    pub fn start(&self) -> impl Future<Output=()>+Send+'static {
+        self.draining.store(true, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        // running calls are checked while holding waiters, so that the
+        // last one can't finish unnoticed
+        let mut waiters = self.waiters.lock().unwrap();
+        match self.running() {
+            0 => { sender.send(()).ok(); },
+            _ => waiters.push(sender),
+        }
+        receiver.map(|_| ())
+    }
+
+    /// Register a new call, unless handler is draining.
+    fn enter(self: &Arc<Self>) -> Option<Running> {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let running = Running(self.clone());
+        match self.is_draining() {
+            true => None,
+            false => Some(running),
+        }
+    }
+}
+
+/// Running call of a handler, notifying its draining once dropped.
+struct Running(Arc<Drain>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let drain = &self.0;
+        if drain.running.fetch_sub(1, Ordering::SeqCst) == 1 && drain.is_draining() {
+            for waiter in drain.waiters.lock().unwrap().drain(..) {
+                waiter.send(()).ok();
+            }
+        }
+    }
+}
+
+
+/// Description of a registered service.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ServiceInfo {
+    /// Service's metadata.
+    pub metas: &'static [(&'static str, &'static str)],
+    /// Service's methods and the index of the action they require.
+    pub methods: &'static [(&'static str, usize)],
+    /// Service's schema, if generated by `#[service]`.
+    pub schema: Option<Schema>,
+    /// Whether service's calls can be aborted (see `rpc::cancel`).
+    pub cancellation_safe: bool,
+}
+
+impl ServiceInfo {
+    /// Return description of service `Sv`.
+    pub fn of<Sv: Service>() -> Self {
+        Self { metas: Sv::metas(), methods: Sv::methods(), schema: Sv::schema(),
+               cancellation_safe: Sv::is_cancellation_safe() }
+    }
+}
+
+
+/// Options of handlers' registration.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct HandlerOptions {
+    /// If true, remove handler after call.
+    pub once: bool,
+    /// Priority of the streams dispatched to the handler (e.g. high for
+    /// control services, low for bulk transfers). Streams with higher
+    /// priority are sent first on congested connections.
+    pub priority: Option<i32>,
+    /// Maximum duration of services' construction by async builders.
+    pub build_timeout: Option<Duration>,
+    /// Duration after which handler is removed (e.g. for once handlers that
+    /// are never called). Expired handlers are not dispatched to, and
+    /// removed by the `Reaper`.
+    pub ttl: Option<Duration>,
+    /// Maximum duration of a call to the handler, after which its future is
+    /// dropped (closing the dispatched stream). This requires a tokio runtime.
+    ///
+    /// Timed out calls of services that are not cancellation-safe (see
+    /// `rpc::cancel`) are handled following `unsafe_timeout`.
+    pub timeout: Option<Duration>,
+    /// Handling of timed out calls of services that are not
+    /// cancellation-safe, detached by default.
+    pub unsafe_timeout: UnsafeTimeout,
+    /// Maximum rate of requests to the services built by the handler, among
+    /// all their instances. Requests above it are answered with a slow down
+    /// (see `throttle`).
+    pub max_rate: Option<RateLimit>,
+}
+
+
+/// Handling of the timed out calls of services that are not
+/// cancellation-safe (see `rpc::cancel`).
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum UnsafeTimeout {
+    /// Drop their future, as for cancellation-safe services, which may
+    /// leave them in an inconsistent state.
+    Drop,
+    /// Run them to completion on a detached tokio task, while the dispatch
+    /// returns a timeout error.
+    #[default]
+    Detach,
+    /// Refuse the registration of such services with a timeout.
+    Refuse,
+}
+
+
+/// Fingerprints of the peers' identities allowed to a pinned id (see
+/// `Dispatch::pin`).
+pub type Identities = BTreeSet<Fingerprint>;
+
+
+/// Running dispatch task, substracted from dispatcher's count when dropped
+/// (including when the task is cancelled).
+struct Task(Arc<AtomicU32>);
+
+impl Task {
+    fn new(count: &Arc<AtomicU32>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Sender whose priority can be set.
+pub trait Prioritize {
+    /// Set sender's priority.
+    fn set_priority(&self, priority: i32) -> Result<()>;
+}
+
+#[cfg(feature="network")]
+impl Prioritize for quinn::SendStream {
+    fn set_priority(&self, priority: i32) -> Result<()> {
+        quinn::SendStream::set_priority(self, priority)
+            .or(ErrorKind::IO.err("can not set stream priority"))
+    }
+}
+
+/// Sender of a stream notifying the peer of its rejection, or of its
+/// admission for transports whose streams can't be reset.
+#[async_trait]
+pub trait Reject {
+    /// Reset stream with provided rejection.
+    async fn reject(&mut self, rejection: Rejection);
+
+    /// Notify the peer that the stream is dispatched. By default, nothing
+    /// is sent.
+    async fn admit(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature="network")]
+#[async_trait]
+impl Reject for quinn::SendStream {
+    async fn reject(&mut self, rejection: Rejection) {
+        self.reset(rejection.code().into()).ok();
+    }
+}
+
+
+/// Function resetting a service returned to its pool.
+type ResetFn<Sv> = Box<dyn Fn(&mut Sv)+Send+Sync>;
+
+/// Pool of pre-constructed service instances, for services that are
+/// expensive to build. Instances are leased to incoming streams and
+/// returned to the pool once the stream is closed, unless their
+/// `is_alive()` returns false. Returned instances are reset by the pool's
+/// reset function (if any), so that no state leaks from a stream to the
+/// next one.
+pub struct ServicePool<Sv> {
+    builder: Box<dyn Fn() -> Sv+Send+Sync>,
+    reset: Option<ResetFn<Sv>>,
+    services: Mutex<Vec<Sv>>,
+    capacity: usize,
+}
+
+impl<Sv> ServicePool<Sv> {
+    /// Create pool of `capacity` instances built using provided function.
+    pub fn new<F>(capacity: usize, builder: F) -> Self
+        where F: 'static+Fn() -> Sv+Send+Sync
+    {
+        let services = (0..capacity).map(|_| builder()).collect();
+        Self { builder: Box::new(builder), reset: None, services: Mutex::new(services), capacity }
+    }
+
+    /// Reset instances with provided function when they are returned.
+    pub fn with_reset<F>(mut self, reset: F) -> Self
+        where F: 'static+Fn(&mut Sv)+Send+Sync
+    {
+        self.reset = Some(Box::new(reset));
+        self
+    }
+
+    /// Maximum count of idle instances.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Count of idle instances.
+    pub fn len(&self) -> usize {
+        self.services.lock().unwrap().len()
+    }
+
+    /// Return true if there is no idle instance.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take an idle instance, building a new one if there is none.
+    pub fn lease(&self) -> Sv {
+        let service = self.services.lock().unwrap().pop();
+        service.unwrap_or_else(|| (self.builder)())
+    }
+
+    /// Return instance to the pool, resetting it. It is dropped if it is not
+    /// alive or if pool is full.
+    pub fn release(&self, mut service: Sv)
+        where Sv: Service
+    {
+        if !service.is_alive() || self.len() >= self.capacity {
+            return
+        }
+        if let Some(reset) = self.reset.as_ref() {
+            reset(&mut service);
+        }
+        let mut services = self.services.lock().unwrap();
+        if services.len() < self.capacity {
+            services.push(service);
+        }
+    }
+}
+
+
+/// Handlers by id.
+///
+/// The map is immutable and swapped on updates (copy-on-write), so that
+/// reading it never blocks, whatever the registration churn is. With the
+/// `rwlock-dispatch` feature, handlers are kept behind a `RwLock` instead.
+#[cfg(not(feature="rwlock-dispatch"))]
+pub struct Handlers<Id,D>(ArcSwap<BTreeMap<Id, Arc<Handler<D>>>>);
+
+#[cfg(feature="rwlock-dispatch")]
+pub struct Handlers<Id,D>(RwLock<BTreeMap<Id, Arc<Handler<D>>>>);
+
+#[cfg(not(feature="rwlock-dispatch"))]
+impl<Id: std::cmp::Ord+Clone, D> Handlers<Id,D> {
+    pub fn new() -> Self {
+        Self(ArcSwap::from_pointee(BTreeMap::new()))
+    }
+
+    /// Return handler registered at id.
+    pub fn get(&self, id: &Id) -> Result<Option<Arc<Handler<D>>>> {
+        Ok(self.0.load().get(id).cloned())
+    }
+
+    /// Return registered handlers, ordered by id.
+    pub fn entries(&self) -> Vec<(Id, Arc<Handler<D>>)> {
+        self.0.load().iter().map(|(id, handler)| (id.clone(), handler.clone())).collect()
+    }
+
+    /// Insert handler, failing if there already is one for this id.
+    pub fn insert(&self, id: Id, handler: Handler<D>) -> Result<()> {
+        let (handler, mut exists) = (Arc::new(handler), false);
+        self.0.rcu(|handlers| {
+            let mut handlers = BTreeMap::clone(handlers);
+            exists = handlers.contains_key(&id);
+            if !exists {
+                handlers.insert(id.clone(), handler.clone());
+            }
+            handlers
+        });
+        match exists {
+            false => Ok(()),
+            true => ErrorKind::NotFound.err("handler already exists for this id"),
+        }
+    }
+
+    /// Remove handler by id.
+    pub fn remove(&self, id: &Id) {
+        self.0.rcu(|handlers| {
+            let mut handlers = BTreeMap::clone(handlers);
+            handlers.remove(id);
+            handlers
+        });
+    }
+
+    /// Only keep handlers for which `func` returns true, returning count
+    /// of removed ones.
+    pub fn retain(&self, func: impl Fn(&Handler<D>) -> bool) -> usize {
+        let mut removed = 0;
+        self.0.rcu(|handlers| {
+            let mut handlers = BTreeMap::clone(handlers);
+            let len = handlers.len();
+            handlers.retain(|_, handler| func(handler));
+            removed = len - handlers.len();
+            handlers
+        });
+        removed
+    }
+}
+
+#[cfg(feature="rwlock-dispatch")]
+impl<Id: std::cmp::Ord+Clone, D> Handlers<Id,D> {
+    pub fn new() -> Self {
+        Self(RwLock::new(BTreeMap::new()))
+    }
+
+    /// Return handler registered at id.
+    pub fn get(&self, id: &Id) -> Result<Option<Arc<Handler<D>>>> {
+        match self.0.read() {
+            Ok(handlers) => Ok(handlers.get(id).cloned()),
+            Err(_) => ErrorKind::Internal.err("can not read handlers"),
+        }
+    }
+
+    /// Return registered handlers, ordered by id.
+    pub fn entries(&self) -> Vec<(Id, Arc<Handler<D>>)> {
+        self.0.read().unwrap().iter().map(|(id, handler)| (id.clone(), handler.clone())).collect()
+    }
+
+    /// Insert handler, failing if there already is one for this id.
+    pub fn insert(&self, id: Id, handler: Handler<D>) -> Result<()> {
+        match self.0.write() {
+            Ok(handlers) if handlers.contains_key(&id) =>
+                ErrorKind::NotFound.err("handler already exists for this id"),
+            Ok(mut handlers) => {
+                handlers.insert(id, Arc::new(handler));
+                Ok(())
+            },
+            _ => ErrorKind::Internal.err("can not lock-write handlers"),
+        }
+    }
+
+    /// Remove handler by id.
+    pub fn remove(&self, id: &Id) {
+        self.0.write().unwrap().remove(id);
+    }
+
+    /// Only keep handlers for which `func` returns true, returning count
+    /// of removed ones.
+    pub fn retain(&self, func: impl Fn(&Handler<D>) -> bool) -> usize {
+        let mut handlers = self.0.write().unwrap();
+        let len = handlers.len();
+        handlers.retain(|_, handler| func(handler));
+        len - handlers.len()
+    }
+}
+
+impl<Id: std::cmp::Ord+Clone, D> Default for Handlers<Id,D> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 
@@ -27,112 +454,508 @@ pub struct Handler<D> {
 pub struct Dispatch<Id,D>
     where Id: std::cmp::Ord
 {
-    pub handlers: RwLock<BTreeMap<Id, Handler<D>>>,
-    pub count: AtomicU32,
+    pub handlers: Handlers<Id,D>,
+    /// Identities allowed to pinned ids.
+    pins: RwLock<BTreeMap<Id, Arc<Identities>>>,
+    pub count: Arc<AtomicU32>,
     pub max_count: Option<u32>,
+    /// Limits of the messages of services registered with bincode.
+    pub limits: CodecLimits,
+    /// Maximum duration of a stream's header: handshake and handler's id.
+    pub header_timeout: Option<Duration>,
+    /// Collector of registered services' metrics.
+    #[cfg(feature="metrics")]
+    collector: RwLock<Option<Arc<Collector>>>,
     phantom: PhantomData<()>,
 }
 
 impl<Id,D> Dispatch<Id,D>
-    where Id: std::cmp::Ord+Send+Sync,
+    where Id: std::cmp::Ord+Clone+Send+Sync,
           D: Send+Sync
 {
     pub fn new(max_count: Option<u32>) -> Self {
-        Self { handlers: RwLock::new(BTreeMap::new()),
-               count: AtomicU32::new(0),
-               max_count, phantom: PhantomData }
+        Self { handlers: Handlers::new(), pins: Default::default(),
+               count: Arc::new(AtomicU32::new(0)),
+               max_count, limits: CodecLimits::default(), header_timeout: None,
+               #[cfg(feature="metrics")]
+               collector: RwLock::new(None),
+               phantom: PhantomData }
+    }
+
+    /// Enforce provided limits on messages of services registered
+    /// afterwards with bincode.
+    pub fn with_limits(mut self, limits: CodecLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Fail streams whose header is not received within `timeout`, so that
+    /// idle peers do not hold resources before being dispatched. This
+    /// requires a tokio runtime.
+    pub fn with_header_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.header_timeout = timeout;
+        self
     }
 
     /// Register handler at id. If ``once`` is true, then handler is called once
     /// then removed.
     pub fn add(&self, id: Id, func: HandlerFn<D>, once: bool) -> Result<()>
     {
-        let handler = Handler { func, once };
-        match self.handlers.write() {
-            Ok(mut handlers) => match handlers.insert(id, handler) {
-                None => Ok(()),
-                Some(_) => ErrorKind::NotFound.err("handler already exists for this id"),
-            },
-            _ => ErrorKind::Internal.err("can not lock-write handlers"),
+        self.add_with(id, func, HandlerOptions { once, ..Default::default() })
+    }
+
+    /// Register handler at id with provided options.
+    pub fn add_with(&self, id: Id, func: HandlerFn<D>, options: HandlerOptions) -> Result<()>
+    {
+        self.add_handler(id, func, options, None)
+    }
+
+    /// Register handler serving the described service at id.
+    pub fn add_service_with(&self, id: Id, func: HandlerFn<D>, options: HandlerOptions,
+                            info: ServiceInfo) -> Result<()>
+    {
+        self.add_handler(id, func, options, Some(info))
+    }
+
+    fn add_handler(&self, id: Id, func: HandlerFn<D>, options: HandlerOptions,
+                   info: Option<ServiceInfo>) -> Result<()>
+    {
+        let unsafe_timeout = options.timeout.is_some()
+                             && matches!(info, Some(ServiceInfo { cancellation_safe: false, .. }));
+        if unsafe_timeout && options.unsafe_timeout == UnsafeTimeout::Refuse {
+            return ErrorKind::Config.err("service with timeout must be cancellation-safe")
         }
+        let expires = options.ttl.map(|ttl| SystemClock.now() + ttl);
+        let detach = unsafe_timeout && options.unsafe_timeout == UnsafeTimeout::Detach;
+        let handler = Handler { func, once: options.once, priority: options.priority, expires,
+                                timeout: options.timeout, detach, info, drain: Default::default() };
+        self.handlers.insert(id, handler)
+    }
+
+    /// Return registered services' ids and descriptions, omitting expired
+    /// handlers and the ones not registered as services.
+    pub fn services(&self) -> Vec<(Id, ServiceInfo)> {
+        let now = SystemClock.now();
+        self.handlers.entries().into_iter()
+            .filter(|(_, handler)| !handler.is_expired(now))
+            .filter_map(|(id, handler)| Some((id, handler.info.clone()?)))
+            .collect()
+    }
+
+    /// Return priority of handler's streams, if any.
+    pub fn priority(&self, id: &Id) -> Option<i32> {
+        self.handlers.get(id).ok()??.priority
     }
 
     /// Remove handler by id.
     pub fn remove(&self, id: &Id) {
-        self.handlers.write().unwrap().remove(&id);
+        self.handlers.remove(id);
     }
 
-    /// Call dispatch registered at id with provided data.
+    /// Drain handler registered at id: new calls to it fail with an
+    /// `ErrorKind::Unavailable` error (streams are reset with a
+    /// `Rejection::Unavailable`), while running ones finish. Return a future
+    /// resolved once they are done, after which the handler can be removed
+    /// and replaced (e.g. by a new version of the service).
+    pub fn drain(&self, id: &Id) -> Result<impl Future<Output=()>+Send+'static> {
+        match self.handlers.get(id)? {
+            Some(handler) => Ok(handler.drain.start()),
+            None => ErrorKind::NotFound.err("handler not found"),
+        }
+    }
+
+    /// Return true if handler registered at id is draining.
+    pub fn is_draining(&self, id: &Id) -> bool {
+        matches!(self.handlers.get(id), Ok(Some(handler)) if handler.drain.is_draining())
+    }
+
+    /// Call dispatch registered at id with provided data, for an anonymous
+    /// peer.
     pub async fn dispatch(&self, id: Id, data: D) -> Result<()> {
+        self.dispatch_as(id, data, None).await
+    }
+
+    /// Call dispatch registered at id with provided data, for the peer of
+    /// provided identity. Fail if id is pinned to other identities.
+    pub async fn dispatch_as(&self, id: Id, data: D, identity: Option<&Fingerprint>) -> Result<()> {
+        if !self.allows(&id, identity) {
+            return ErrorKind::Forbidden.err("peer's identity is not allowed to handler")
+        }
         if let Some(max_count) = self.max_count {
             if self.count.load(Ordering::Relaxed) >= max_count {
                 return ErrorKind::LimitReached.err("maximum tasks count reached")
             }
         }
-        self.count.fetch_add(1, Ordering::Relaxed);
+        let task = Task::new(&self.count);
 
         // we need to keep handlers reading out of future awaiting in order
         // to avoid deadlock/latency among dispatch tasks (e.g. when
         // unregistering once handlers.
-        let (fut, once) = {
-            match self.handlers.read() {
-                Ok(handlers) => match handlers.get(&id) {
-                    None => return ErrorKind::NotFound.err("handler not found"),
-                    Some(handler) => ((handler.func)(data), handler.once)
-                },
-                Err(_) => return ErrorKind::Internal.err("can not read handlers"),
-            }
+        let (fut, once, timeout, detach, running) = match self.handlers.get(&id)? {
+            Some(handler) if handler.is_expired(SystemClock.now()) =>
+                return ErrorKind::NotFound.err("handler expired"),
+            None => return ErrorKind::NotFound.err("handler not found"),
+            Some(handler) => {
+                let running = handler.drain.enter()
+                    .ok_or_else(|| ErrorKind::Unavailable.error("handler is draining"))?;
+                let fut = leak::track_future("dispatch", (handler.func)(data));
+                (fut, handler.once, handler.timeout, handler.detach, running)
+            },
         };
 
-        fut.await;
+        let result = match (timeout, detach) {
+            // task keeps running once timed out, counted and holding the
+            // handler's drain until it completes
+            (Some(timeout), true) => {
+                let fut = async move {
+                    let (_task, _running) = (task, running);
+                    fut.await
+                };
+                match tokio::time::timeout(timeout, tokio::spawn(fut)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(err)) => ErrorKind::Internal.err(err.to_string()),
+                    Err(_) => ErrorKind::Timeout.err("handler timed out"),
+                }
+            },
+            (Some(timeout), false) => tokio::time::timeout(timeout, fut).await
+                                        .or_else(|_| ErrorKind::Timeout.err("handler timed out")),
+            (None, _) => {
+                fut.await;
+                Ok(())
+            },
+        };
 
         if once {
             self.remove(&id);
         }
+        result
+    }
+}
 
-        // FIXME: handling task cancelation, count may not be substracted
-        self.count.fetch_sub(1, Ordering::Relaxed);
-        Ok(())
+
+impl<Id: std::cmp::Ord, D> Dispatch<Id,D> {
+    /// Pin id to peers of provided identities (see `enforce::fingerprint`):
+    /// streams of other peers, and of anonymous ones, are reset with a
+    /// `Rejection::Forbidden`, so that sensitive services stay out of reach
+    /// even to holders of a leaked capability. Servers identify peers by
+    /// their client certificate, verified by mTLS; services authenticating
+    /// their peers in-band are pinned with `services::auth::Auth::with_identities`.
+    ///
+    /// Pins are kept whatever handler is registered at id, so that it can
+    /// be pinned before its registration and stays so when replaced.
+    pub fn pin(&self, id: Id, identities: impl IntoIterator<Item=Fingerprint>) {
+        let identities = Arc::new(identities.into_iter().collect());
+        self.pins.write().unwrap().insert(id, identities);
+    }
+
+    /// Remove pin of id.
+    pub fn unpin(&self, id: &Id) {
+        self.pins.write().unwrap().remove(id);
+    }
+
+    /// Return identities allowed to id, if pinned.
+    pub fn pinned(&self, id: &Id) -> Option<Arc<Identities>> {
+        self.pins.read().unwrap().get(id).cloned()
+    }
+
+    /// Return true if peer of provided identity can be dispatched to id.
+    /// Anonymous peers are not allowed to pinned ids.
+    pub fn allows(&self, id: &Id, identity: Option<&Fingerprint>) -> bool {
+        match (self.pins.read().unwrap().get(id), identity) {
+            (None, _) => true,
+            (Some(identities), Some(identity)) => identities.contains(identity),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Measure calls of services registered afterwards by builder or pool
+    /// (under their `name` meta, or their type's name), and count bytes of
+    /// their messages, into provided collector.
+    #[cfg(feature="metrics")]
+    pub fn collect(&self, collector: Arc<Collector>) {
+        *self.collector.write().unwrap() = Some(collector);
+    }
+
+    /// Return probe of service `Sv`'s streams.
+    #[cfg(feature="metrics")]
+    pub(super) fn probe<Sv: Service>(&self) -> Probe {
+        Probe::of::<Sv>(self.collector.read().unwrap().clone())
+    }
+
+    /// Generic as with metrics, so that callers do not depend on the feature.
+    #[cfg(not(feature="metrics"))]
+    #[allow(clippy::extra_unused_type_parameters)]
+    pub(super) fn probe<Sv: Service>(&self) -> Probe {
+        Probe
+    }
+}
+
+
+/// Metrics probe of a registered service's streams: when the dispatch
+/// collects metrics, calls are measured and messages' bytes counted.
+/// Otherwise, services and codecs are left as is.
+#[cfg(feature="metrics")]
+mod probe {
+    use std::sync::Arc;
+
+    use super::super::metrics::{Collector, Counted, Measured, Metrics};
+    use super::super::service::Service;
+
+    pub type Probed<Sv> = Measured<Sv>;
+
+    #[derive(Clone)]
+    pub struct Probe {
+        collector: Option<Arc<Collector>>,
+        metrics: Arc<Metrics>,
+    }
+
+    impl Probe {
+        pub fn of<Sv: Service>(collector: Option<Arc<Collector>>) -> Self {
+            let metrics = match collector {
+                Some(ref collector) => {
+                    let name = Sv::metas().iter().find(|(key, _)| *key == "name")
+                                  .map_or_else(std::any::type_name::<Sv>, |(_, name)| name);
+                    collector.service::<Sv>(name)
+                },
+                None => Arc::new(Metrics::new()),
+            };
+            Self { collector, metrics }
+        }
+
+        pub fn service<Sv: Service>(&self, service: Sv) -> Probed<Sv> {
+            Measured::new(service, self.metrics.clone())
+        }
+
+        pub fn release<Sv: Service>(service: Probed<Sv>) -> Sv {
+            service.into_inner()
+        }
+
+        pub fn codec<F>(&self, factory: F) -> Counted<F> {
+            Counted::new(factory, self.collector.clone())
+        }
+    }
+}
+
+#[cfg(not(feature="metrics"))]
+mod probe {
+    use super::super::service::Service;
+
+    pub type Probed<Sv> = Sv;
+
+    #[derive(Clone,Copy)]
+    pub struct Probe;
+
+    impl Probe {
+        pub fn service<Sv: Service>(&self, service: Sv) -> Probed<Sv> {
+            service
+        }
+
+        pub fn release<Sv: Service>(service: Probed<Sv>) -> Sv {
+            service
+        }
+
+        pub fn codec<F>(&self, factory: F) -> F {
+            factory
+        }
+    }
+}
+
+
+impl<Id,D> Reap for Dispatch<Id,D>
+    where Id: std::cmp::Ord+Clone+Send+Sync,
+          D: Send+Sync
+{
+    fn name(&self) -> &str {
+        "handlers"
+    }
+
+    fn reap(&self, now: Duration) -> usize {
+        self.handlers.retain(|handler| !handler.is_expired(now))
     }
 }
 
 
 /// Implement Dispatch with ``(AsyncWrite, AsyncRead, data)`` as ``Data``.
 impl<Id,S,R,D> Dispatch<Id,(S,R,D)>
-    where for<'de> Id: std::cmp::Ord+Send+Sync+Deserialize<'de>,
+    where for<'de> Id: std::cmp::Ord+Clone+Send+Sync+Deserialize<'de>,
           S: 'static+AsyncWrite+Unpin+Sync+Send,
           R: 'static+AsyncRead+Unpin+Sync+Send,
           D: 'static+Sync+Send,
 {
-    /// Register a service using factory function.
-    /// FIXME: generic codec
-    pub fn add_builder<F,Sv>(&self, id: Id, builder: Box<F>, once: bool)
+    /// Register a service using factory function, and bincode codec.
+    pub fn add_builder<F,Sv>(&self, id: Id, builder: Box<F>, options: HandlerOptions)
             -> Result<()>
         where F: 'static+Send+Sync+Unpin+Fn(D)->Sv,
               Sv: 'static+Send+Sync+Service,
               for <'de> Sv::Request: Deserialize<'de>, Sv::Response: Serialize
     {
+        self.add_builder_with_codec(id, builder, self.limits, options)
+    }
+
+    /// Register a service using factory function, whose streams' messages
+    /// are encoded and decoded by codecs built with `codec`.
+    pub fn add_builder_with_codec<F,Sv,Cd>(&self, id: Id, builder: Box<F>, codec: Cd,
+                                           options: HandlerOptions)
+            -> Result<()>
+        where F: 'static+Send+Sync+Unpin+Fn(D)->Sv,
+              Sv: 'static+Send+Sync+Service,
+              Cd: 'static+Unpin+CodecFactory<Sv::Response, Sv::Request>
+    {
+        let (throttle, probe) = (options.max_rate.map(Throttle::new), self.probe::<Sv>());
+        let codec = probe.codec(codec);
         let handler = Box::new(move |(sender, receiver, data)| {
-            let (encoder, decoder) = (BincodeCodec::new(), BincodeCodec::new());
-            builder(data).serve_stream((sender, receiver), encoder, decoder)
+            let (encoder, decoder) = codec.codec();
+            let (service, version) = (probe.service(builder(data)), version::peer_version());
+            match throttle {
+                Some(ref throttle) => Downgraded::new(Throttled::new(service, throttle.clone()), version)
+                                        .serve_stream((sender, receiver), encoder, decoder),
+                None => Downgraded::new(service, version)
+                            .serve_stream((sender, receiver), encoder, decoder),
+            }
         });
-        self.add(id, handler, once)
+        self.add_service_with(id, handler, options, ServiceInfo::of::<Sv>())
     }
 
-    /// Dispatch ``(sender, receiver, data)`` to service. Uses provided
-    /// codec ``C`` to decode handler's Id.
-    pub async fn dispatch_stream<C>(&self, (sender, receiver, data): (S,R,D))
+    /// Register a pool of services, leasing an instance to each stream
+    /// instead of constructing one.
+    pub fn add_pool<Sv>(&self, id: Id, pool: Arc<ServicePool<Sv>>, options: HandlerOptions)
             -> Result<()>
-        where C: Default+Decoder<Item=Id>+Unpin
+        where Sv: 'static+Send+Sync+Service,
+              for <'de> Sv::Request: Deserialize<'de>, Sv::Response: Serialize
     {
-        let mut codec = Framed::new(receiver, C::default());
-        let id = match codec.next().await {
-            Some(id) => id,
-            _ => return ErrorKind::InvalidData.err("can not read/decode handler's id"),
+        let (throttle, probe) = (options.max_rate.map(Throttle::new), self.probe::<Sv>());
+        let codec = Arc::new(probe.codec(self.limits));
+        let handler = Box::new(move |(sender, receiver, _)| {
+            let (pool, throttle, codec) = (pool.clone(), throttle.clone(), codec.clone());
+            let (service, version) = (probe.service(pool.lease()), version::peer_version());
+            Box::pin(async move {
+                let (encoder, decoder) = codec.codec();
+                let transport = Transport::new(Framed::new(sender, encoder),
+                                               Framed::new(receiver, decoder));
+                let service = match throttle {
+                    Some(throttle) => {
+                        let mut service = Downgraded::new(Throttled::new(service, throttle), version);
+                        service.serve(transport).await;
+                        service.into_inner().into_inner()
+                    },
+                    None => {
+                        let mut service = Downgraded::new(service, version);
+                        service.serve(transport).await;
+                        service.into_inner()
+                    },
+                };
+                pool.release(Probe::release(service));
+            }) as Pin<Box<dyn Future<Output=()>+Send>>
+        });
+        self.add_service_with(id, handler, options, ServiceInfo::of::<Sv>())
+    }
+
+    /// Register a service using async factory function, which can perform
+    /// I/O before the service is served. Stream is closed when construction
+    /// fails or exceeds `options.build_timeout` (which requires a tokio
+    /// runtime).
+    pub fn add_async_builder<F,Fut,Sv>(&self, id: Id, builder: Box<F>, options: HandlerOptions)
+            -> Result<()>
+        where F: 'static+Send+Sync+Unpin+Fn(D)->Fut,
+              Fut: 'static+Send+Future<Output=Result<Sv>>,
+              Sv: 'static+Send+Sync+Service,
+              for <'de> Sv::Request: Deserialize<'de>, Sv::Response: Serialize
+    {
+        let (timeout, probe) = (options.build_timeout, self.probe::<Sv>());
+        let (throttle, probed) = (options.max_rate.map(Throttle::new), Arc::new((probe.codec(self.limits), probe)));
+        let handler = Box::new(move |(sender, receiver, data)| {
+            let (build, version) = (builder(data), version::peer_version());
+            let (throttle, probed) = (throttle.clone(), probed.clone());
+            Box::pin(async move {
+                let (codec, probe) = &*probed;
+                let service = match timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, build).await {
+                        Ok(service) => service,
+                        Err(_) => return,
+                    },
+                    None => build.await,
+                };
+                let (encoder, decoder) = codec.codec();
+                match (service.map(|service| probe.service(service)), throttle) {
+                    (Ok(service), Some(throttle)) => Downgraded::new(Throttled::new(service, throttle), version)
+                        .serve_stream((sender, receiver), encoder, decoder).await,
+                    (Ok(service), None) => Downgraded::new(service, version)
+                        .serve_stream((sender, receiver), encoder, decoder).await,
+                    (Err(_), _) => (),
+                }
+            }) as Pin<Box<dyn Future<Output=()>+Send>>
+        });
+        self.add_service_with(id, handler, options, ServiceInfo::of::<Sv>())
+    }
+
+    /// Dispatch ``(sender, receiver, data)`` to service. The stream starts
+    /// with a handshake (see `handshake`), whose codec must be ``C``, used
+    /// to decode handler's Id: streams of an invalid one are rejected.
+    /// Sender's priority is set to the handler's one.
+    pub async fn dispatch_stream<C>(&self, data: (S,R,D)) -> Result<()>
+        where C: Default+Decoder<Item=Id>+WireCodec+Unpin,
+              S: Prioritize+Reject, Id: trace::SpanField
+    {
+        self.dispatch_stream_as::<C>(data, None).await
+    }
+
+    /// Dispatch ``(sender, receiver, data)`` to service, as `dispatch_stream`,
+    /// for the peer of provided identity.
+    pub async fn dispatch_stream_as<C>(&self, (mut sender, receiver, data): (S,R,D),
+                                       identity: Option<&Fingerprint>)
+            -> Result<()>
+        where C: Default+Decoder<Item=Id>+WireCodec+Unpin,
+              S: Prioritize+Reject, Id: trace::SpanField
+    {
+        let header = Self::read_header::<C>(receiver);
+        let header = match self.header_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, header).await.unwrap_or_else(|_|
+                Err((None, ErrorKind::Timeout.error("stream's header not received in time")))),
+            None => header.await,
+        };
+        let (handshake, id, receiver) = match header {
+            Ok(header) => header,
+            Err((rejection, err)) => {
+                if let Some(rejection) = rejection {
+                    sender.reject(rejection).await;
+                }
+                return Err(err)
+            },
         };
 
-        let receiver = codec.into_inner();
-        self.dispatch(id, (sender, receiver, data)).await
+        if !self.allows(&id, identity) {
+            sender.reject(Rejection::Forbidden).await;
+            return ErrorKind::Forbidden.err("peer's identity is not allowed to handler")
+        }
+        if self.is_draining(&id) {
+            sender.reject(Rejection::Unavailable).await;
+            return ErrorKind::Unavailable.err("handler is draining")
+        }
+        if let Some(priority) = self.priority(&id) {
+            sender.set_priority(priority)?;
+        }
+        sender.admit().await?;
+
+        // builders downgrade their services to the peer's version
+        let span = trace::stream(&id);
+        let dispatch = self.dispatch_as(id, (sender, receiver, data), identity).instrument(span);
+        version::with_peer_version(handshake.version, dispatch).await
+    }
+
+    /// Read stream's handshake and handler's id, returning them with the
+    /// receiver of the following messages. Return the rejection to send
+    /// back to the peer along with the error.
+    async fn read_header<C>(mut receiver: R)
+            -> std::result::Result<(Handshake, Id, R), (Option<Rejection>, crate::Error)>
+        where C: Default+Decoder<Item=Id>+WireCodec+Unpin
+    {
+        let handshake = Handshake::accept(&mut receiver, C::ID).await?;
+        // read byte per byte in order not to consume data following the id
+        let mut codec = Framed::with_capacity(receiver, C::default(), 1);
+        match codec.next().await {
+            Some(id) => Ok((handshake, id, codec.into_inner())),
+            None => Err((None, ErrorKind::InvalidData.error("can not read/decode handler's id"))),
+        }
     }
 
 }
@@ -144,6 +967,7 @@ pub mod tests {
     use futures::executor::LocalPool;
 
     use super::*;
+    use super::super::codec::BincodeCodec;
 
     pub struct TestDispatch {
         pub result: Arc<RwLock<i64>>,
@@ -208,10 +1032,10 @@ pub mod tests {
     fn test_dispatch() {
         LocalPool::new().run_until(async {
             let test = TestDispatch::new(None);
-            test.dispatch(&"add", (2,3)).await.unwrap();
+            test.dispatch("add", (2,3)).await.unwrap();
             assert_eq!(test.result(), 5);
 
-            test.dispatch(&"sub", (3,1)).await.unwrap();
+            test.dispatch("sub", (3,1)).await.unwrap();
             assert_eq!(test.result(), 2);
         })
     }
@@ -220,13 +1044,429 @@ pub mod tests {
     fn test_dispatch_once() {
         LocalPool::new().run_until(async {
             let test = TestDispatch::new(None);
-            test.dispatch(&"add_once",(2,3)).await.unwrap();
+            test.dispatch("add_once",(2,3)).await.unwrap();
             assert_eq!(test.result(), 5);
-            assert_eq!(test.dispatch(&"add_once",(2,3)).await.unwrap_err().kind(),
+            assert_eq!(test.dispatch("add_once",(2,3)).await.unwrap_err().kind(),
                        ErrorKind::NotFound);
         })
     }
 
+    #[test]
+    fn test_ttl() {
+        let test = TestDispatch::new(None);
+        let options = HandlerOptions { once: true, ttl: Some(Duration::from_secs(60)),
+                                       ..Default::default() };
+        test.add_with("ttl", Box::new(|_| Box::pin(async {})), options).unwrap();
+
+        let now = SystemClock.now();
+        assert_eq!(test.reap(now), 0);
+        assert_eq!(test.reap(now + Duration::from_secs(60)), 1);
+        assert!(test.handlers.get(&"ttl").unwrap().is_none());
+        assert!(test.handlers.get(&"add").unwrap().is_some());
+
+        let options = HandlerOptions { ttl: Some(Duration::ZERO), ..Default::default() };
+        test.add_with("expired", Box::new(|_| Box::pin(async {})), options).unwrap();
+        LocalPool::new().run_until(async {
+            assert_eq!(test.dispatch("expired", (0, 0)).await.unwrap_err().kind(),
+                       ErrorKind::NotFound);
+        });
+    }
+
+    #[test]
+    fn test_count() {
+        let test = TestDispatch::new(Some(1));
+        LocalPool::new().run_until(async {
+            test.dispatch("add", (1, 2)).await.unwrap();
+            assert_eq!(test.dispatch("unknown", (1, 2)).await.unwrap_err().kind(),
+                       ErrorKind::NotFound);
+            // cancelled task
+            test.add("pending", Box::new(|_| Box::pin(future::pending())), true).unwrap();
+            let mut fut = test.dispatch("pending", (0, 0)).boxed();
+            assert!(futures::poll!(&mut fut).is_pending());
+            assert_eq!(test.count.load(Ordering::Relaxed), 1);
+            drop(fut);
+        });
+        assert_eq!(test.count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let test = TestDispatch::new(None);
+        let (sender, receiver) = oneshot::channel::<()>();
+        let receiver = Mutex::new(Some(receiver));
+        test.add("running", Box::new(move |_| {
+            let receiver = receiver.lock().unwrap().take();
+            Box::pin(async move {
+                if let Some(receiver) = receiver {
+                    receiver.await.ok();
+                }
+            })
+        }), false).unwrap();
+
+        LocalPool::new().run_until(async {
+            let mut running = test.dispatch("running", (0, 0)).boxed();
+            assert!(futures::poll!(&mut running).is_pending());
+
+            let mut drained = test.drain(&"running").unwrap().boxed();
+            assert!(test.is_draining(&"running") && !test.is_draining(&"add"));
+            assert!(futures::poll!(&mut drained).is_pending());
+            assert_eq!(test.dispatch("running", (0, 0)).await.unwrap_err().kind(),
+                       ErrorKind::Unavailable);
+
+            // running calls finish
+            sender.send(()).unwrap();
+            assert_eq!(running.await, Ok(()));
+            drained.await;
+            assert!(test.drain(&"unknown").is_err());
+            test.drain(&"add").unwrap().await;
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        let test = TestDispatch::new(None);
+        let (sender, mut receiver) = futures::channel::oneshot::channel::<()>();
+        let sender = Mutex::new(Some(sender));
+        let options = HandlerOptions { timeout: Some(Duration::from_millis(10)), ..Default::default() };
+        test.add_with("pending", Box::new(move |_| {
+            // sender is dropped with handler's future
+            let sender = sender.lock().unwrap().take();
+            Box::pin(async move {
+                let _sender = sender;
+                future::pending::<()>().await
+            })
+        }), options).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            assert_eq!(test.dispatch("pending", (0, 0)).await.unwrap_err().kind(),
+                       ErrorKind::Timeout);
+            assert_eq!(receiver.try_recv(), Err(futures::channel::oneshot::Canceled));
+            assert_eq!(test.count.load(Ordering::Relaxed), 0);
+            // handlers returning in time
+            test.add_with("ready", Box::new(|_| Box::pin(async {})), options).unwrap();
+            test.dispatch("ready", (0, 0)).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_timeout_unsafe() {
+        use super::super::cancel::CancellationSafe;
+        use super::super::service::tests::{simple_service, simple_service_2};
+
+        let test = TestDispatch::new(None);
+        let options = HandlerOptions { timeout: Some(Duration::from_millis(10)), ..Default::default() };
+        let info = ServiceInfo::of::<simple_service::Service>();
+        assert!(!info.cancellation_safe);
+        let drop = HandlerOptions { unsafe_timeout: UnsafeTimeout::Drop, ..options };
+        test.add_service_with("dropped", Box::new(|_| Box::pin(future::pending())), drop,
+                              info.clone()).unwrap();
+        let refuse = HandlerOptions { unsafe_timeout: UnsafeTimeout::Refuse, ..options };
+        assert_eq!(test.add_service_with("unsafe", Box::new(|_| Box::pin(async {})), refuse,
+                                         info.clone()).unwrap_err().kind(),
+                   ErrorKind::Config);
+        test.add_service_with("safe", Box::new(|_| Box::pin(async {})), refuse,
+                              ServiceInfo::of::<CancellationSafe<simple_service::Service>>()).unwrap();
+        test.add_service_with("safe_2", Box::new(|_| Box::pin(async {})), refuse,
+                              ServiceInfo::of::<simple_service_2::Service>()).unwrap();
+
+        // by default, detached calls run to completion, still counted and
+        // draining
+        let (sender, receiver) = futures::channel::oneshot::channel::<()>();
+        let sender = Mutex::new(Some(sender));
+        test.add_service_with("unsafe", Box::new(move |_| {
+            let sender = sender.lock().unwrap().take();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                sender.unwrap().send(()).unwrap();
+            })
+        }), options, info).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            assert_eq!(test.dispatch("dropped", (0, 0)).await.unwrap_err().kind(),
+                       ErrorKind::Timeout);
+            assert_eq!(test.count.load(Ordering::Relaxed), 0);
+
+            assert_eq!(test.dispatch("unsafe", (0, 0)).await.unwrap_err().kind(),
+                       ErrorKind::Timeout);
+            assert_eq!(test.count.load(Ordering::Relaxed), 1);
+            let mut drained = test.drain(&"unsafe").unwrap().boxed();
+            assert!(futures::poll!(&mut drained).is_pending());
+            assert_eq!(receiver.await, Ok(()));
+            drained.await;
+            assert_eq!(test.count.load(Ordering::Relaxed), 0);
+        });
+    }
+
+    #[test]
+    fn test_priority() {
+        let test = TestDispatch::new(None);
+        let options = HandlerOptions { priority: Some(-1), ..Default::default() };
+        test.add_with("bulk", Box::new(|_| Box::pin(async {})), options).unwrap();
+        assert_eq!(test.priority(&"bulk"), Some(-1));
+        assert_eq!(test.priority(&"add"), None);
+        assert_eq!(test.priority(&"unknown"), None);
+    }
+
+    #[test]
+    fn test_identities() {
+        let test = TestDispatch::new(None);
+        let (admin, other) = ([1u8; 32], [2u8; 32]);
+        test.pin("admin", [admin]);
+        let res = test.result.clone();
+        test.add_with("admin", Box::new(move |(a,_)| {
+            *res.write().unwrap() = a;
+            Box::pin(async {})
+        }), HandlerOptions::default()).unwrap();
+        assert_eq!(test.pinned(&"admin").unwrap().len(), 1);
+
+        LocalPool::new().run_until(async {
+            assert_eq!(test.dispatch("admin", (1, 0)).await.unwrap_err().kind(),
+                       ErrorKind::Forbidden);
+            assert_eq!(test.dispatch_as("admin", (2, 0), Some(&other)).await.unwrap_err().kind(),
+                       ErrorKind::Forbidden);
+            assert_eq!(test.result(), 0);
+            test.dispatch_as("admin", (3, 0), Some(&admin)).await.unwrap();
+            assert_eq!(test.result(), 3);
+            // unrestricted handlers
+            test.dispatch_as("add", (2, 2), Some(&other)).await.unwrap();
+            assert_eq!(test.result(), 4);
+
+            test.unpin(&"admin");
+            test.dispatch("admin", (5, 0)).await.unwrap();
+            assert_eq!(test.result(), 5);
+        });
+    }
+
+    /// Writer whose data is kept after it is dropped.
+    #[derive(Clone,Default)]
+    pub struct SharedWriter(pub Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl AsyncWrite for SharedWriter {
+        fn poll_write(self: Pin<&mut Self>, _: &mut futures::task::Context, buf: &[u8])
+            -> futures::task::Poll<std::io::Result<usize>>
+        {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            futures::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut futures::task::Context)
+            -> futures::task::Poll<std::io::Result<()>>
+        {
+            futures::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut futures::task::Context)
+            -> futures::task::Poll<std::io::Result<()>>
+        {
+            futures::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_async_builder() {
+        use bytes::BytesMut;
+        use super::super::codec::Encoder;
+        use super::super::service::tests::simple_service;
+
+        let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, u64)>::new(None);
+        let options = HandlerOptions { build_timeout: Some(Duration::from_millis(50)),
+                                       ..Default::default() };
+        dispatch.add_async_builder(0, Box::new(|delay: u64| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok(simple_service::Service::new())
+        }), options).unwrap();
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            for (delay, served) in [(0, true), (200, false)] {
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(0, (writer.clone(), reader, delay)).await.unwrap();
+                assert_eq!(!writer.0.lock().unwrap().is_empty(), served);
+            }
+        });
+    }
+
+    #[test]
+    fn test_builder_codec() {
+        use bytes::BytesMut;
+        use super::super::codec::Encoder;
+        use super::super::service::tests::simple_service;
+
+        /// Bincode codec prefixing responses with a marker byte.
+        struct Marked(BincodeCodec<simple_service::Response>);
+
+        impl Encoder<simple_service::Response> for Marked {
+            type Error = bincode::Error;
+
+            fn encode(&mut self, item: simple_service::Response, dst: &mut BytesMut)
+                -> std::result::Result<(), Self::Error>
+            {
+                dst.extend_from_slice(&[0xff]);
+                self.0.encode(item, dst)
+            }
+        }
+
+        let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>::new(None);
+        dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                             HandlerOptions::default()).unwrap();
+        dispatch.add_builder_with_codec(1, Box::new(|_| simple_service::Service::new()),
+                                        || (Marked(BincodeCodec::new()), BincodeCodec::new()),
+                                        HandlerOptions::default()).unwrap();
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+        let mut response = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Response::Add(3), &mut response).unwrap();
+
+        LocalPool::new().run_until(async {
+            for (id, marked) in [(0, false), (1, true)] {
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(id, (writer.clone(), reader, ())).await.unwrap();
+
+                let expected = match marked {
+                    true => [&[0xff], response.as_ref()].concat(),
+                    false => response.to_vec(),
+                };
+                assert_eq!(*writer.0.lock().unwrap(), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_builder_limits() {
+        use bytes::BytesMut;
+        use super::super::codec::Encoder;
+        use super::super::service::tests::simple_service;
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+
+        LocalPool::new().run_until(async {
+            // requests over the limit are refused
+            for (max_size, served) in [(None, true), (Some(4), false)] {
+                let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>::new(None)
+                    .with_limits(CodecLimits { max_size, ..CodecLimits::default() });
+                dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                                     HandlerOptions::default()).unwrap();
+
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(0, (writer.clone(), reader, ())).await.unwrap();
+                assert_eq!(!writer.0.lock().unwrap().is_empty(), served);
+            }
+        });
+    }
+
+    #[test]
+    fn test_builder_downgrade() {
+        use bytes::BytesMut;
+        use super::super::admission::RateLimit;
+        use super::super::codec::Encoder;
+        use super::super::service::tests::simple_service;
+
+        let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>::new(None);
+        let options = HandlerOptions { max_rate: Some(RateLimit::new(0.1, 1)), ..Default::default() };
+        dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()), options).unwrap();
+        let pool = Arc::new(ServicePool::new(1, simple_service::Service::new));
+        dispatch.add_pool(1, pool, options).unwrap();
+
+        let mut request = BytesMut::new();
+        for _ in 0..2 {
+            BincodeCodec::new().encode(simple_service::Request::Add(1), &mut request).unwrap();
+        }
+        LocalPool::new().run_until(async {
+            // second call is throttled: peers without slow down responses
+            // are denied instead
+            for id in [0, 1] {
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                let dispatch = dispatch.dispatch(id, (writer.clone(), reader, ()));
+                version::with_peer_version(version::SLOW_DOWN - 1, dispatch).await.unwrap();
+
+                let mut written = BytesMut::from(writer.0.lock().unwrap().as_slice());
+                let mut decoder = BincodeCodec::<simple_service::Response>::new();
+                assert!(matches!(decoder.decode(&mut written), Ok(Some(simple_service::Response::Add(1)))));
+                assert!(matches!(decoder.decode(&mut written), Ok(Some(simple_service::Response::__Denied(..)))));
+            }
+        });
+    }
+
+    #[test]
+    fn test_pool() {
+        use bytes::BytesMut;
+        use std::sync::atomic::AtomicUsize;
+        use super::super::codec::Encoder;
+        use super::super::service::tests::simple_service;
+
+        let built = Arc::new(AtomicUsize::new(0));
+        let built_ = built.clone();
+        let pool = Arc::new(ServicePool::new(1, move || {
+            built_.fetch_add(1, Ordering::Relaxed);
+            simple_service::Service::new()
+        }).with_reset(|service| service.clear()));
+        assert_eq!((pool.len(), built.load(Ordering::Relaxed)), (1, 1));
+
+        let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>::new(None);
+        dispatch.add_pool(0, pool.clone(), HandlerOptions::default()).unwrap();
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+
+        LocalPool::new().run_until(async {
+            // same instance is served to both streams, reset in between
+            for _ in 0..2 {
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(0, (writer.clone(), reader, ())).await.unwrap();
+
+                let mut response = BytesMut::new();
+                BincodeCodec::new().encode(simple_service::Response::Add(3), &mut response).unwrap();
+                assert_eq!(*writer.0.lock().unwrap(), response.to_vec());
+            }
+        });
+        assert_eq!((pool.len(), built.load(Ordering::Relaxed)), (1, 1));
+
+        // exceeding instances are built then dropped
+        let (a, b) = (pool.lease(), pool.lease());
+        pool.release(a);
+        pool.release(b);
+        assert_eq!((pool.len(), built.load(Ordering::Relaxed)), (1, 2));
+    }
+
+    #[test]
+    fn test_pool_dead() {
+        /// Service closed once it has been served.
+        struct Closing(bool);
+
+        #[async_trait::async_trait]
+        impl Service for Closing {
+            type Request = ();
+            type Response = ();
+
+            fn is_alive(&self) -> bool {
+                !self.0
+            }
+
+            async fn dispatch(&mut self, _request: ()) -> Option<()> {
+                self.0 = true;
+                None
+            }
+        }
+
+        let pool = ServicePool::new(1, || Closing(false));
+        let mut service = pool.lease();
+        assert!(pool.is_empty());
+        futures::executor::block_on(service.dispatch(()));
+        pool.release(service);
+        assert!(pool.is_empty());
+    }
+
     // TODO:
     // - test max_count
     // - test dispatch_transport
@@ -236,9 +1476,9 @@ pub mod tests {
     fn test_dispatch_max_count() {
         LocalPool::new().run_until(async {
             let test = TestDispatch::new(Some(2));
-            let fut_0 = test.dispatch(&"add", (2,3));
-            let fut_1 = test.dispatch(&"add", (5,7));
-            let fut_2 = test.dispatch(&"sub", (13,12));
+            let fut_0 = test.dispatch("add", (2,3));
+            let fut_1 = test.dispatch("add", (5,7));
+            let fut_2 = test.dispatch("sub", (13,12));
 
             assert_eq!(fut_2.await.unwrap_err(), Error::TooManyTasks);
         })
@@ -0,0 +1,332 @@
+//! Enforcement of callers' capability over services' methods.
+//!
+//! `Enforced` wraps a service and denies requests to methods whose action
+//! is not allowed by the caller's effective capability.
+//! Each denial produces a `Denial` record, emitted to server events, so that
+//! authorization failures can be audited and debugged. The caller only gets
+//! a redacted reason, when enabled.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use crate::data::{hash, CapabilitySet, ObjectId};
+use super::events::{ServerEvent, ServerEvents};
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Fingerprint of a caller's identity (hash of its public key or
+/// certificate).
+pub type Fingerprint = hash::Hash;
+
+/// Return fingerprint of identity's bytes.
+pub fn fingerprint(identity: &[u8]) -> Fingerprint {
+    hash::digest(identity)
+}
+
+
+/// Origin of requests served by an enforced service.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Origin {
+    /// Caller's identity fingerprint.
+    pub identity: Option<Fingerprint>,
+    /// Id of the service as registered on the dispatcher.
+    pub service: String,
+    /// Id of the object granted by the caller's reference.
+    pub reference: Option<ObjectId>,
+}
+
+impl Origin {
+    pub fn new(service: impl ToString) -> Self {
+        Self { service: service.to_string(), ..Self::default() }
+    }
+
+    /// Set identity's fingerprint from its bytes.
+    pub fn with_identity(mut self, identity: &[u8]) -> Self {
+        self.identity = Some(fingerprint(identity));
+        self
+    }
+
+    /// Set reference's object id.
+    pub fn with_reference(mut self, reference: ObjectId) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+}
+
+
+/// Record of a denied request.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Denial {
+    /// Origin of the request.
+    pub origin: Origin,
+    /// Called method's name.
+    pub method: &'static str,
+    /// Actions required by the method, as a bits field by words of 64
+    /// actions (see `CapabilitySet::action_bits`).
+    pub required: Vec<u64>,
+    /// Actions allowed by the caller's capability, restricted by the
+    /// service's mask, as a bits field of the same words.
+    pub presented: Vec<u64>,
+}
+
+impl Denial {
+    /// Reason returned to the caller, not disclosing identity nor presented
+    /// capability.
+    pub fn redacted(&self) -> String {
+        format!("method `{}` is not allowed", self.method)
+    }
+}
+
+
+/// Service denying requests to methods not allowed by caller's capability.
+pub struct Enforced<S: Service> {
+    inner: S,
+    origin: Origin,
+    events: Option<Arc<ServerEvents>>,
+    /// Return redacted denial reason to the caller.
+    reveal: bool,
+    /// Actions allowed whatever caller's capability, all if None.
+    mask: Option<S::Capability>,
+}
+
+impl<S: Service> Enforced<S> {
+    pub fn new(inner: S, origin: Origin) -> Self {
+        Self { inner, origin, events: None, reveal: false, mask: None }
+    }
+
+    /// Emit denial records to provided events.
+    pub fn with_events(mut self, events: Arc<ServerEvents>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Return a redacted reason to the caller when its request is denied.
+    pub fn with_reason(mut self, reveal: bool) -> Self {
+        self.reveal = reveal;
+        self
+    }
+
+    /// Restrict allowed methods to actions of `mask`, in addition to
+    /// caller's capability.
+    pub fn with_mask(mut self, mask: S::Capability) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Check request against caller's capability, returning denial when
+    /// it is not allowed. Streamed items are checked as their method's
+    /// call (see `Service::method_of()`).
+    pub fn check(&self, request: &S::Request) -> Option<Denial> {
+        let (method, required) = *S::method_of(request)
+                                     .and_then(|index| S::methods().get(index))?;
+        let mut presented = self.inner.capability();
+        if let Some(ref mask) = self.mask {
+            presented = presented.intersection(mask);
+        }
+        match presented.allows(required) {
+            true => None,
+            false => Some(Denial { origin: self.origin.clone(), method,
+                                   required: S::Capability::of_action(required).action_bits(),
+                                   presented: presented.action_bits() }),
+        }
+    }
+
+    /// Record denial of request, returning the response to send.
+    fn deny(&self, request: &S::Request, denial: Denial) -> Option<S::Response> {
+        let reason = match self.reveal {
+            true => denial.redacted(),
+            false => String::new(),
+        };
+        if let Some(ref events) = self.events {
+            events.emit(ServerEvent::RequestDenied(denial));
+        }
+        S::denied(S::call_id(request), reason)
+    }
+}
+
+impl<S: Service+Clone> Clone for Enforced<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), origin: self.origin.clone(),
+               events: self.events.clone(), reveal: self.reveal, mask: self.mask.clone() }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for Enforced<S>
+    where S::Response: 'static
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        match self.check(&request) {
+            Some(denial) => self.deny(&request, denial),
+            None => self.inner.dispatch(request).await,
+        }
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        match self.check(&request) {
+            Some(denial) => Ok(stream::iter(self.deny(&request, denial)).boxed()),
+            None => self.inner.dispatch_streaming(request).await,
+        }
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        match self.check(&request) {
+            Some(denial) => Ok(stream::iter(self.deny(&request, denial)).boxed()),
+            None => self.inner.dispatch_incoming(request, requests).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+
+    use super::*;
+    use crate as rpccaps;
+    use crate::data::Capability;
+    use rpccaps_derive::*;
+
+    mod limited_service {
+        use super::*;
+
+        pub struct Service {
+            pub cap: Capability,
+        }
+
+        #[service]
+        #[rpc(capability="self.cap")]
+        impl Service {
+            fn read(&mut self) -> u32 {
+                1
+            }
+
+            fn write(&mut self, _value: u32) -> bool {
+                true
+            }
+        }
+    }
+
+    use limited_service::{Request, Response};
+
+    #[test]
+    fn test_enforced() {
+        let events = Arc::new(ServerEvents::new());
+        let mut receiver = events.subscribe(4);
+        let origin = Origin::new("limited").with_identity(b"alice");
+        let service = limited_service::Service { cap: Capability::new(0b01, 0) };
+        let mut service = Enforced::new(service, origin.clone()).with_events(events);
+
+        LocalPool::new().run_until(async {
+            assert!(matches!(service.dispatch(Request::Read()).await, Some(Response::Read(1))));
+            assert!(matches!(service.dispatch(Request::Write(2)).await,
+                             Some(Response::__Denied(_, reason)) if reason.is_empty()));
+
+            let denial = match receiver.next().await {
+                Some(ServerEvent::RequestDenied(denial)) => denial,
+                event => panic!("unexpected event {:?}", event),
+            };
+            assert_eq!(denial, Denial { origin, method: "write", required: vec![0b10],
+                                        presented: vec![0b01] });
+            assert_ne!(denial.origin.identity, Origin::new("").with_identity(b"bob").identity);
+
+            // capabilities are never denied, reason can be revealed
+            let mut service = service.with_reason(true);
+            assert!(matches!(service.dispatch(Request::__Capabilities).await,
+                             Some(Response::__Capabilities(_))));
+            match service.dispatch(Request::Write(2)).await {
+                Some(Response::__Denied(_, reason)) => assert_eq!(reason, "method `write` is not allowed"),
+                _ => panic!("request expected to be denied"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_enforced_streamed() {
+        use crate::rpc::service::tests::incoming_service::{self, Request, Response};
+
+        // streamed items are checked as their method's call
+        assert_eq!(Request::SumChunk(1).required_capability(), Request::Sum(0).required_capability());
+        let service = incoming_service::Service { total: 0 };
+        let mut service = Enforced::new(service, Origin::new("incoming")).with_mask(Capability::empty());
+        LocalPool::new().run_until(async {
+            for request in [Request::SumChunk(1), Request::SumEnd] {
+                assert!(matches!(service.dispatch(request).await, Some(Response::__Denied(..))));
+            }
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! Provide server lifecycle events to subscribers.
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+
+use crate::Error;
+use super::backpressure::Pressure;
+use super::enforce::{Denial, Fingerprint};
+use super::reaper::Reap;
+
+
+/// Event happening on server.
+#[derive(Clone,Debug,PartialEq)]
+pub enum ServerEvent {
+    /// A new connection has been established.
+    ConnectionOpened(SocketAddr),
+    /// Incoming connection has been rejected by address filter or per-IP
+    /// connections limit.
+    ConnectionRejected(SocketAddr),
+    /// Connection has been closed.
+    ConnectionClosed(SocketAddr),
+    /// A stream has been accepted on connection and is being dispatched.
+    StreamDispatched(SocketAddr),
+    /// Stream dispatch or its handler failed.
+    HandlerError(SocketAddr, Error),
+    /// A limit (including stream rate) has been reached, and stream was
+    /// rejected.
+    LimitReached(SocketAddr),
+    /// A request has been denied by capability enforcement.
+    RequestDenied(Denial),
+    /// Peer's identity has exhausted its execution time budget, and stream
+    /// was rejected.
+    BudgetExhausted(SocketAddr, Fingerprint),
+    /// Queued streams crossed a watermark of `ServerConfig::backpressure`.
+    Backpressure(Pressure),
+}
+
+
+/// Broadcast server events to subscribers.
+///
+/// Events are sent without waiting: when a subscriber's queue is full, the
+/// event is dropped for this subscriber.
+pub struct ServerEvents {
+    subscribers: Mutex<Vec<mpsc::Sender<ServerEvent>>>,
+}
+
+impl ServerEvents {
+    pub fn new() -> Self {
+        Self { subscribers: Mutex::new(Vec::new()) }
+    }
+
+    /// Subscribe to events, returning stream of events. At most `capacity`
+    /// events are queued.
+    pub fn subscribe(&self, capacity: usize) -> mpsc::Receiver<ServerEvent> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Return true if there are subscribers.
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    /// Send event to subscribers, removing closed ones.
+    pub fn emit(&self, event: ServerEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|sender| !matches!(sender.try_send(event.clone()),
+                                                  Err(err) if err.is_disconnected()));
+    }
+}
+
+impl Reap for ServerEvents {
+    fn name(&self) -> &str {
+        "subscribers"
+    }
+
+    /// Remove closed subscribers.
+    fn reap(&self, _now: Duration) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let len = subscribers.len();
+        subscribers.retain(|sender| !sender.is_closed());
+        len - subscribers.len()
+    }
+}
+
+impl Default for ServerEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use futures::prelude::*;
+    use futures::executor::LocalPool;
+
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let addr = SocketAddr::from_str("127.0.0.1:4433").unwrap();
+        let events = ServerEvents::new();
+        let mut receiver = events.subscribe(1);
+        let dropped = events.subscribe(1);
+        drop(dropped);
+
+        events.emit(ServerEvent::ConnectionOpened(addr));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+
+        drop(events.subscribe(1));
+        assert_eq!(events.reap(Duration::ZERO), 1);
+
+        LocalPool::new().run_until(async {
+            assert_eq!(receiver.next().await, Some(ServerEvent::ConnectionOpened(addr)));
+            events.emit(ServerEvent::LimitReached(addr));
+            assert_eq!(receiver.next().await, Some(ServerEvent::LimitReached(addr)));
+        });
+    }
+}
@@ -0,0 +1,305 @@
+//! Extract typed values from connection context for service builders.
+//!
+//! Instead of receiving the whole `Arc<C>` context, builders registered
+//! with `Dispatch::add_context_builder` declare the values they need as
+//! arguments, which are extracted from the context when a stream is
+//! dispatched:
+//!
+//! ```ignore
+//! dispatch.add_context_builder(0, |RemoteAddr(addr): RemoteAddr| Service::new(addr),
+//!                              HandlerOptions::default())?;
+//! ```
+//!
+//! Dependencies registered on the server are resolved by type with `Dep<T>`.
+//!
+//! Builders taking an `AuthenticatedContext` (or `PeerIdentity`) require
+//! an authenticated peer: `Server::add_context_builder` refuses them when
+//! client authentication is not configured.
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::io::{AsyncRead,AsyncWrite};
+use serde::{Deserialize,Serialize};
+
+use crate::{ErrorKind, Result};
+use super::codec::{BincodeCodec, CodecFactory, Framed};
+use super::context::{AuthenticatedContext, Context, PeerInfo, TypedContext};
+use super::deps::Dependencies;
+use super::dispatch::{Dispatch, HandlerOptions, ServiceInfo};
+use super::message::Error;
+use super::service::Service;
+use super::throttle::{Throttle, Throttled};
+use super::version::Downgraded;
+
+
+/// Value that can be extracted from connection context.
+pub trait FromContext<C>: Sized {
+    /// Whether extraction requires the peer to be authenticated.
+    const AUTHENTICATED: bool = false;
+
+    fn from_context(context: &Arc<C>) -> Result<Self>;
+}
+
+impl<C> FromContext<C> for Arc<C> {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(context.clone())
+    }
+}
+
+/// Peer's address.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+impl<C: Context> FromContext<C> for RemoteAddr {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(Self(context.remote_address()))
+    }
+}
+
+impl<C: Context> FromContext<C> for PeerInfo {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(context.peer_info())
+    }
+}
+
+/// Certificates chain presented by the peer. Extraction fails when the
+/// peer did not authenticate.
+#[derive(Clone,Debug,PartialEq)]
+pub struct PeerIdentity(pub Vec<rustls::Certificate>);
+
+impl<C: Context> FromContext<C> for PeerIdentity {
+    const AUTHENTICATED: bool = true;
+
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        context.peer_certs().map(Self)
+               .ok_or(ErrorKind::NotFound.error("peer has no identity"))
+    }
+}
+
+/// Handle to the connection, shared by streams of a same session.
+#[derive(Clone,Debug)]
+pub struct Session(pub quinn::Connection);
+
+impl<C: Context> FromContext<C> for Session {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(Self(context.connection().clone()))
+    }
+}
+
+/// Dependency registered on the server, resolved by type. Extraction fails
+/// when it is not registered.
+#[derive(Debug,PartialEq)]
+pub struct Dep<T>(pub Arc<T>);
+
+impl<T> Clone for Dep<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C: Context, T: Send+Sync+'static> FromContext<C> for Dep<T> {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        context.dependencies().and_then(|deps| deps.get::<T>()).map(Self)
+               .ok_or_else(|| ErrorKind::NotFound.error(
+                   format!("dependency `{}` is not registered", std::any::type_name::<T>())))
+    }
+}
+
+/// All dependencies registered on the server.
+impl<C: Context> FromContext<C> for Arc<Dependencies> {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        context.dependencies().cloned()
+               .ok_or(ErrorKind::NotFound.error("context has no dependencies"))
+    }
+}
+
+impl<C: Context> FromContext<C> for AuthenticatedContext<C> {
+    const AUTHENTICATED: bool = true;
+
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        AuthenticatedContext::new(context.clone())
+    }
+}
+
+impl<C: Context> FromContext<C> for TypedContext<C> {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(TypedContext::new(context.clone()))
+    }
+}
+
+impl<C, T: FromContext<C>> FromContext<C> for Option<T> {
+    fn from_context(context: &Arc<C>) -> Result<Self> {
+        Ok(T::from_context(context).ok())
+    }
+}
+
+
+/// Service factory whose arguments are extracted from context.
+pub trait Builder<C, Args>: Send+Sync {
+    type Service: Service;
+
+    /// Whether building requires the peer to be authenticated.
+    const AUTHENTICATED: bool;
+
+    /// Extract arguments and build service.
+    fn build(&self, context: &Arc<C>) -> Result<Self::Service>;
+}
+
+macro_rules! impl_builder {
+    ($($arg:ident),*) => {
+        impl<C, F, Sv, $($arg,)*> Builder<C, ($($arg,)*)> for F
+            where F: Send+Sync+Fn($($arg),*) -> Sv,
+                  Sv: Service,
+                  $($arg: FromContext<C>,)*
+        {
+            type Service = Sv;
+
+            const AUTHENTICATED: bool = false $(|| $arg::AUTHENTICATED)*;
+
+            #[allow(unused_variables)]
+            fn build(&self, context: &Arc<C>) -> Result<Sv> {
+                Ok(self($($arg::from_context(context)?),*))
+            }
+        }
+    }
+}
+
+impl_builder!();
+impl_builder!(A);
+impl_builder!(A, B);
+impl_builder!(A, B, D);
+impl_builder!(A, B, D, E);
+
+
+impl<Id,S,R,C> Dispatch<Id,(S,R,Arc<C>)>
+    where for<'de> Id: std::cmp::Ord+Clone+Send+Sync+Deserialize<'de>,
+          S: 'static+AsyncWrite+Unpin+Sync+Send,
+          R: 'static+AsyncRead+Unpin+Sync+Send,
+          C: 'static+Context,
+{
+    /// Register a service using factory function whose arguments are
+    /// extracted from the connection context. When extraction fails, the
+    /// client gets a `message::Error::Unavailable` before the stream is
+    /// closed.
+    pub fn add_context_builder<B,Args>(&self, id: Id, builder: B, options: HandlerOptions)
+            -> Result<()>
+        where B: 'static+Builder<C,Args>+Unpin,
+              B::Service: 'static,
+              for <'de> <B::Service as Service>::Request: Deserialize<'de>,
+              <B::Service as Service>::Response: Serialize
+    {
+        let (throttle, probe) = (options.max_rate.map(Throttle::new), self.probe::<B::Service>());
+        let codec = probe.codec(self.limits);
+        let handler = Box::new(move |(sender, receiver, context): (S, R, Arc<C>)| {
+            let (encoder, decoder) = codec.codec();
+            let version = context.protocol_version();
+            match (builder.build(&context).map(|service| probe.service(service)), throttle.as_ref()) {
+                (Ok(service), Some(throttle)) =>
+                    Downgraded::new(Throttled::new(service, throttle.clone()), version)
+                        .serve_stream((sender, receiver), encoder, decoder),
+                (Ok(service), None) => Downgraded::new(service, version)
+                    .serve_stream((sender, receiver), encoder, decoder),
+                (Err(_), _) => Box::pin(unavailable::<B::Service,_>(sender)),
+            }
+        });
+        self.add_service_with(id, handler, options, ServiceInfo::of::<B::Service>())
+    }
+}
+
+
+/// Send `message::Error::Unavailable` to the client of a service that
+/// could not be built, then close the stream. The cause is not disclosed.
+async fn unavailable<Sv,S>(sender: S)
+    where Sv: Service, Sv::Response: Serialize, S: AsyncWrite+Unpin
+{
+    let mut sink = Framed::new(sender, BincodeCodec::new());
+    if let Some(response) = Sv::error(None, Error::Unavailable) {
+        sink.send(response).await.ok();
+    }
+    sink.close().await.ok();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use super::super::context::DefaultContext;
+    use super::super::server::IncomingStream;
+    use super::super::service::tests::{simple_service,simple_service_2};
+
+    #[test]
+    fn test_add_context_builder() {
+        let dispatch = Dispatch::<u32, IncomingStream<DefaultContext>>::new(None);
+        let options = HandlerOptions::default();
+        dispatch.add_context_builder(0, simple_service::Service::new, options).unwrap();
+        dispatch.add_context_builder(1, |_: RemoteAddr, _: Option<PeerIdentity>| {
+            simple_service_2::Service::new()
+        }, options).unwrap();
+        dispatch.add_context_builder(2, |_: Arc<DefaultContext>, _: Session| {
+            simple_service::Service::new()
+        }, options).unwrap();
+        dispatch.add_context_builder(3, |_: Dep<String>, _: Arc<Dependencies>| {
+            simple_service::Service::new()
+        }, options).unwrap();
+
+        assert!(dispatch.add_context_builder(0, simple_service::Service::new, options).is_err());
+        assert!(dispatch.handlers.get(&2).unwrap().is_some());
+    }
+
+    fn requires_auth<B: Builder<DefaultContext, Args>, Args>(_: B) -> bool {
+        B::AUTHENTICATED
+    }
+
+    #[test]
+    fn test_builder_authenticated() {
+        use super::super::context::TypedContext;
+
+        assert!(!requires_auth(simple_service::Service::new));
+        assert!(!requires_auth(|_: RemoteAddr, _: Option<PeerIdentity>| simple_service::Service::new()));
+        assert!(!requires_auth(|_: TypedContext<DefaultContext>| simple_service::Service::new()));
+        assert!(requires_auth(|_: RemoteAddr, _: PeerIdentity| simple_service::Service::new()));
+        assert!(requires_auth(|_: AuthenticatedContext<DefaultContext>| simple_service::Service::new()));
+    }
+
+    /// Context whose name is extracted by `Name`.
+    struct NamedContext(Option<&'static str>);
+
+    struct Name(&'static str);
+
+    impl FromContext<NamedContext> for Name {
+        fn from_context(context: &Arc<NamedContext>) -> Result<Self> {
+            context.0.map(Name).ok_or(ErrorKind::NotFound.error("context has no name"))
+        }
+    }
+
+    #[test]
+    fn test_builder_extract() {
+        let extracted = Arc::new(Mutex::new(Vec::new()));
+        let record = extracted.clone();
+        let builder = move |Name(name): Name, context: Arc<NamedContext>, other: Option<Name>| {
+            record.lock().unwrap().push((name, context.0, other.map(|Name(name)| name)));
+            simple_service::Service::new()
+        };
+
+        assert!(builder.build(&Arc::new(NamedContext(Some("a")))).is_ok());
+        assert_eq!(builder.build(&Arc::new(NamedContext(None))).err().unwrap().kind(),
+                   ErrorKind::NotFound);
+        assert_eq!(*extracted.lock().unwrap(), [("a", Some("a"), Some("a"))]);
+    }
+
+    #[test]
+    fn test_unavailable() {
+        let mut buffer = Vec::new();
+        block_on(unavailable::<simple_service::Service,_>(&mut buffer));
+
+        let mut buffer = bytes::BytesMut::from(&buffer[..]);
+        let response = BincodeCodec::<simple_service::Response>::new().decode(&mut buffer).unwrap();
+        assert!(matches!(response, Some(simple_service::Response::__Error(None, Error::Unavailable))));
+    }
+}
@@ -0,0 +1,144 @@
+//! Filter remote peers by address.
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+};
+
+use crate::{ErrorKind, Error, Result};
+
+
+/// An IP subnet in CIDR notation (e.g. `10.0.0.0/8`, `fd00::/8`).
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Create a new subnet, failing when prefix is too long for address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        match prefix <= max {
+            true => Ok(Self { addr, prefix }),
+            false => ErrorKind::InvalidInput.err("CIDR prefix is too long"),
+        }
+    }
+
+    /// Return true if address belongs to the subnet. IPv4-mapped IPv6
+    /// addresses are handled as IPv4 ones.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) =>
+                Self::matches(&net.octets(), &addr.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(addr)) =>
+                Self::matches(&net.octets(), &addr.octets(), self.prefix),
+            _ => false,
+        }
+    }
+
+    /// Return true if the `prefix` first bits of `a` and `b` are equal.
+    fn matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+        let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+        if a[..bytes] != b[..bytes] {
+            return false;
+        }
+        match bits {
+            0 => true,
+            _ => {
+                let mask = 0xffu8 << (8 - bits);
+                a[bytes] & mask == b[bytes] & mask
+            }
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .or(ErrorKind::InvalidInput.err("invalid CIDR address"))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>()
+                .or(ErrorKind::InvalidInput.err("invalid CIDR prefix"))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+
+/// Allow and deny lists of subnets.
+///
+/// An address is accepted when it does not belong to a denied subnet, and
+/// belongs to an allowed subnet if any is specified.
+#[derive(Clone,Debug,Default)]
+pub struct AddressFilter {
+    /// Allowed subnets. When empty, all subnets are allowed.
+    pub allow: Vec<Cidr>,
+    /// Denied subnets.
+    pub deny: Vec<Cidr>,
+}
+
+impl AddressFilter {
+    /// Return true if address is accepted.
+    pub fn is_allowed(&self, addr: &IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(addr)) &&
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        IpAddr::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::from_str("10.1.0.0/15").unwrap();
+        assert!(cidr.contains(&ip("10.0.0.1")));
+        assert!(cidr.contains(&ip("10.1.255.255")));
+        assert!(!cidr.contains(&ip("10.2.0.1")));
+        assert!(cidr.contains(&ip("::ffff:10.0.0.1")));
+
+        let cidr = Cidr::from_str("fd00::/8").unwrap();
+        assert!(cidr.contains(&ip("fd12::1")));
+        assert!(!cidr.contains(&ip("fe80::1")));
+        assert!(!cidr.contains(&ip("10.0.0.1")));
+
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(&ip("1.2.3.4")));
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = AddressFilter::default();
+        assert!(filter.is_allowed(&ip("1.2.3.4")));
+
+        filter.allow.push(Cidr::from_str("10.0.0.0/8").unwrap());
+        filter.deny.push(Cidr::from_str("10.0.0.0/24").unwrap());
+        assert!(!filter.is_allowed(&ip("1.2.3.4")));
+        assert!(!filter.is_allowed(&ip("10.0.0.1")));
+        assert!(filter.is_allowed(&ip("10.0.1.1")));
+    }
+}
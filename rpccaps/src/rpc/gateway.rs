@@ -0,0 +1,582 @@
+//! HTTP/JSON gateway exposing services to HTTP clients.
+//!
+//! A `POST /{service}/{method}` request whose body is the JSON array of the
+//! method's arguments is translated into the corresponding service's
+//! `Request` variant. The response body is the JSON value returned by the
+//! method (`null` when the method returns nothing).
+//!
+//! Unordered methods take their call id as first argument.
+//!
+//! Requests are authenticated from their headers by the gateway's
+//! authenticator, then dispatched through `Enforced`: methods not allowed
+//! by the caller's capability are answered with `403 Forbidden`. Without
+//! authenticator, all requests are refused with `401 Unauthorized`.
+//!
+//! `with_presentations()` authenticates callers by a presentation of
+//! their reference (see `Presentation::to_token()`) sent as
+//! `Authorization: Presentation <token>`. It proves possession of the
+//! reference's last subject key, signing the request's binding (see
+//! `request_binding()`) of its `Rpccaps-Timestamp` and `Rpccaps-Nonce`
+//! headers: requests out of the allowed clock skew are refused, as the
+//! ones reusing a nonce. References are never accepted as bearer tokens.
+//!
+//! The gateway is only served over TLS (see `Gateway::listen()`).
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::prelude::*;
+use hyper::{Body, HeaderMap, Method, StatusCode};
+use hyper::body::HttpBody;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::net;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{ErrorKind, Error, Result};
+use crate::data::{bytes::Bytes, hash, Capability, Clock, ObjectId, SignMethod, SystemClock};
+use crate::data::presentation::{ChannelBinding, Presentation};
+use crate::data::validate::Validate;
+use super::codec::JsonCodec;
+use super::enforce::{self, Enforced, Fingerprint, Origin};
+use super::events::{ServerEvent, ServerEvents};
+use super::service::Service;
+
+
+/// Default maximum size of requests' body.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1 << 20;
+
+/// Header of the request's timestamp, as seconds since UNIX epoch.
+pub const TIMESTAMP_HEADER: &str = "rpccaps-timestamp";
+
+/// Header of the request's nonce.
+pub const NONCE_HEADER: &str = "rpccaps-nonce";
+
+/// Prefix of requests' binding.
+pub const REQUEST_BINDING_PREFIX: &[u8] = b"rpccaps-gateway-request";
+
+/// Accepted lengths of requests' nonce.
+const NONCE_LENGTHS: std::ops::RangeInclusive<usize> = 16..=128;
+
+/// Delay before accepting connections again after the listener failed.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+
+/// Return binding of a request with provided timestamp and nonce, signed
+/// by the presentation of the caller's reference.
+pub fn request_binding(timestamp: u64, nonce: &str) -> ChannelBinding {
+    hash::digest(&[REQUEST_BINDING_PREFIX, &timestamp.to_le_bytes(), nonce.as_bytes()].concat())
+}
+
+
+/// Nonces of accepted requests, kept until their timestamp is out of the
+/// allowed skew.
+struct Nonces(Mutex<HashMap<String, u64>>);
+
+impl Nonces {
+    /// Record nonce, kept until `expires`. Return false if it is already
+    /// recorded.
+    fn insert(&self, nonce: &str, expires: u64, now: u64) -> bool {
+        let mut nonces = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        nonces.retain(|_, expires| *expires >= now);
+        match nonces.contains_key(nonce) {
+            true => false,
+            false => nonces.insert(nonce.to_string(), expires).is_none(),
+        }
+    }
+}
+
+
+/// Caller of a request, as authenticated by the gateway.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Caller {
+    /// Caller's identity fingerprint.
+    pub identity: Option<Fingerprint>,
+    /// Id of the object granted by the caller's reference.
+    pub reference: Option<ObjectId>,
+    /// Caller's capability, restricting the methods it can call.
+    pub capability: Capability,
+}
+
+impl Caller {
+    pub fn new(capability: Capability) -> Self {
+        Self { identity: None, reference: None, capability }
+    }
+
+    fn origin(&self, service: &str) -> Origin {
+        Origin { identity: self.identity, service: service.to_string(), reference: self.reference }
+    }
+}
+
+
+pub type GatewayFn = Box<dyn Send+Sync+Fn(&Caller, &str, Value) -> Pin<Box<dyn Future<Output=Result<Value>>+Send>>>;
+
+/// Return caller authenticated from request's headers.
+pub type Authenticator = Box<dyn Send+Sync+Fn(&HeaderMap) -> Result<Caller>>;
+
+
+/// Gateway dispatching HTTP requests to services by name.
+pub struct Gateway {
+    services: BTreeMap<String, GatewayFn>,
+    authenticator: Option<Authenticator>,
+    events: Option<Arc<ServerEvents>>,
+    max_body_size: usize,
+}
+
+
+/// Return camel-cased version of provided method name, as used for
+/// `Request` variants.
+fn to_camel(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = true;
+    for c in name.chars() {
+        match c {
+            '_' => upper = true,
+            c if upper => {
+                out.extend(c.to_uppercase());
+                upper = false;
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+
+impl Gateway {
+    pub fn new() -> Self {
+        Self { services: BTreeMap::new(), authenticator: None, events: None,
+               max_body_size: DEFAULT_MAX_BODY_SIZE }
+    }
+
+    /// Authenticate requests' callers with provided function.
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Authenticate callers by the presentation token of their reference,
+    /// given in the `Authorization` header, proving possession of its last
+    /// subject's key for the request's binding. References must be issued
+    /// by `issuer` and valid at system's time; callers get their effective
+    /// capability. Requests whose timestamp differs from system's time by
+    /// more than `max_skew`, or reusing a nonce, are refused.
+    pub fn with_presentations<Id,Sign>(self, issuer: Sign::Verifier, max_skew: Duration) -> Self
+        where Id: 'static+Clone+Serialize+DeserializeOwned,
+              Sign: 'static+SignMethod+Serialize+DeserializeOwned,
+              Sign::Verifier: Send+Sync
+    {
+        let nonces = Nonces(Mutex::new(HashMap::new()));
+        let max_skew = max_skew.as_secs();
+        self.with_authenticator(Box::new(move |headers| {
+            let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+            let token = header(hyper::header::AUTHORIZATION.as_str())
+                            .and_then(|value| value.strip_prefix("Presentation "))
+                            .ok_or_else(|| ErrorKind::Forbidden.error("missing presentation"))?;
+            let timestamp = header(TIMESTAMP_HEADER).and_then(|value| value.parse::<u64>().ok())
+                                .ok_or_else(|| ErrorKind::Forbidden.error("missing request timestamp"))?;
+            let nonce = header(NONCE_HEADER).filter(|nonce| NONCE_LENGTHS.contains(&nonce.len()))
+                            .ok_or_else(|| ErrorKind::Forbidden.error("missing request nonce"))?;
+            let now = SystemClock.timestamp();
+            if timestamp.abs_diff(now) > max_skew {
+                return ErrorKind::Forbidden.err("request timestamp is out of allowed skew");
+            }
+
+            let presentation = Presentation::<Id,Sign>::from_token(token)
+                                   .or_else(|err| ErrorKind::Forbidden.err(err.to_string()))?;
+            let reference = presentation.reference();
+            if reference.issuer() != &issuer {
+                return ErrorKind::Forbidden.err("reference is not issued by gateway's issuer");
+            }
+            let subject = match reference.last() {
+                Some(cert) => cert.auth.subject.clone(),
+                None => return ErrorKind::Forbidden.err("empty reference"),
+            };
+            presentation.validate(&request_binding(timestamp, nonce))
+                        .or_else(|err| ErrorKind::Forbidden.err(err.to_string()))?;
+            // nonces are only recorded for valid presentations, kept as long
+            // as their request's timestamp is accepted
+            if !nonces.insert(nonce, timestamp + max_skew, now) {
+                return ErrorKind::Forbidden.err("request nonce is already used");
+            }
+            Ok(Caller {
+                identity: Some(enforce::fingerprint(subject.as_bytes())),
+                reference: reference.object_id().ok(),
+                capability: reference.effective_capability()
+                                     .ok_or_else(|| ErrorKind::Forbidden.error("empty reference"))?,
+            })
+        }))
+    }
+
+    /// Emit denial records of enforced calls to provided events.
+    pub fn with_events(mut self, events: Arc<ServerEvents>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Refuse requests whose body is larger than `max_body_size` bytes.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Register a service under `name` using factory function. A new
+    /// service instance is built for each call, enforced by the caller's
+    /// capability.
+    pub fn add_builder<F,Sv>(&mut self, name: impl Into<String>, builder: Box<F>) -> Result<()>
+        where F: 'static+Send+Sync+Fn()->Sv,
+              Sv: 'static+Send+Sync+Service,
+              Sv::Request: DeserializeOwned, Sv::Response: Serialize,
+              Sv::Capability: From<Capability>
+    {
+        let name = name.into();
+        let (service_name, events) = (name.clone(), self.events.clone());
+        let func: GatewayFn = Box::new(move |caller, method, args| {
+            let request = Self::request::<Sv>(method, args);
+            let mut service = Enforced::new(builder(), caller.origin(&service_name))
+                                  .with_mask(caller.capability.clone().into());
+            let events = events.clone();
+            Box::pin(async move {
+                let request = request?;
+                if let Some(denial) = service.check(&request) {
+                    let reason = denial.redacted();
+                    if let Some(events) = events {
+                        events.emit(ServerEvent::RequestDenied(denial));
+                    }
+                    return ErrorKind::Forbidden.err(reason);
+                }
+                match service.dispatch(request).await {
+                    Some(response) => Self::response::<Sv>(response),
+                    None => Ok(Value::Null),
+                }
+            })
+        });
+
+        match self.services.insert(name, func) {
+            None => Ok(()),
+            Some(_) => ErrorKind::KeyError.err("service already exists for this name"),
+        }
+    }
+
+    /// Return request for provided method and JSON arguments.
+    fn request<Sv>(method: &str, args: Value) -> Result<Sv::Request>
+        where Sv: Service, Sv::Request: DeserializeOwned
+    {
+        if !Sv::methods().iter().any(|(name, _)| *name == method) {
+            return ErrorKind::NotFound.err("method not found");
+        }
+
+        let args = match args {
+            Value::Array(mut args) if args.len() == 1 => args.remove(0),
+            Value::Array(args) => Value::Array(args),
+            _ => return ErrorKind::InvalidInput.err("arguments must be a JSON array"),
+        };
+
+        // Value's deserializer does not accept empty tuple variants, which
+        // is the case for methods without arguments.
+        let mut request = serde_json::Map::new();
+        request.insert(to_camel(method), args);
+        JsonCodec::new().encode_payload(&Value::Object(request))
+            .and_then(|payload| JsonCodec::new().decode_payload(&payload))
+            .or_else(|err| ErrorKind::InvalidInput.err(err.to_string()))
+    }
+
+    /// Return JSON value for provided response.
+    fn response<Sv>(response: Sv::Response) -> Result<Value>
+        where Sv: Service, Sv::Response: Serialize
+    {
+        let payload = JsonCodec::new().encode_payload(&response);
+        match payload.and_then(|payload| JsonCodec::<Value>::new().decode_payload(&payload)) {
+            Ok(Value::Object(map)) => Ok(map.into_iter().next().map(|(_, v)| v)
+                                            .unwrap_or(Value::Null)),
+            Ok(_) => Ok(Value::Null),
+            Err(err) => ErrorKind::Codec.err(err.to_string()),
+        }
+    }
+
+    /// Call service's method with provided JSON arguments, on behalf of
+    /// `caller`.
+    pub async fn call(&self, caller: &Caller, service: &str, method: &str, args: Value) -> Result<Value> {
+        match self.services.get(service) {
+            Some(func) => func(caller, method, args).await,
+            None => ErrorKind::NotFound.err("service not found"),
+        }
+    }
+
+    /// Read request's body, failing when it exceeds the maximum size.
+    async fn read_body(&self, mut body: Body) -> Result<Vec<u8>> {
+        if body.size_hint().lower() > self.max_body_size as u64 {
+            return ErrorKind::LimitReached.err("request body is too large");
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+            if data.len() + chunk.len() > self.max_body_size {
+                return ErrorKind::LimitReached.err("request body is too large");
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// Handle an HTTP request.
+    pub async fn handle(&self, request: hyper::Request<Body>) -> hyper::Response<Body> {
+        let path = request.uri().path().trim_matches('/').to_string();
+        let (service, method) = match (request.method(), path.split_once('/')) {
+            (&Method::POST, Some(target)) => target,
+            (&Method::POST, None) =>
+                return Self::http_error(ErrorKind::NotFound.error("path must be /service/method")),
+            _ => return Self::http_response(StatusCode::METHOD_NOT_ALLOWED, &Value::Null),
+        };
+
+        let caller = match self.authenticator {
+            Some(ref authenticate) => authenticate(request.headers()),
+            None => ErrorKind::Forbidden.err("gateway has no authenticator"),
+        };
+        let caller = match caller {
+            Ok(caller) => caller,
+            Err(err) => return Self::http_response(StatusCode::UNAUTHORIZED,
+                                                   &json!({ "error": err.to_string() })),
+        };
+
+        let result = match self.read_body(request.into_body()).await {
+            Ok(body) => match JsonCodec::new().decode_payload(&body) {
+                Ok(args) => self.call(&caller, service, method, args).await,
+                Err(err) => ErrorKind::InvalidInput.err(err.to_string()),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(value) => Self::http_response(StatusCode::OK, &value),
+            Err(err) => Self::http_error(err),
+        }
+    }
+
+    fn http_error(err: Error) -> hyper::Response<Body> {
+        let status = match err.kind() {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::LimitReached => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::InvalidInput|ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::http_response(status, &json!({ "error": err.to_string() }))
+    }
+
+    fn http_response(status: StatusCode, value: &Value) -> hyper::Response<Body> {
+        let mut response = hyper::Response::new(Body::from(value.to_string()));
+        *response.status_mut() = status;
+        response.headers_mut().insert(hyper::header::CONTENT_TYPE,
+                                      hyper::header::HeaderValue::from_static("application/json"));
+        response
+    }
+
+    /// Serve HTTP requests over TLS at provided address. Must be run on a
+    /// tokio runtime.
+    pub async fn listen(self: Arc<Self>, address: SocketAddr, tls: Arc<rustls::ServerConfig>)
+        -> Result<()>
+    {
+        let listener = net::TcpListener::bind(address).await
+                            .or_else(|err| ErrorKind::Endpoint.err(err.to_string()))?;
+        let acceptor = TlsAcceptor::from(tls);
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue
+                },
+            };
+            let (gateway, acceptor) = (self.clone(), acceptor.clone());
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let service = hyper::service::service_fn(move |request| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(request).await) }
+                });
+                hyper::server::conn::Http::new().http1_only(true)
+                    .serve_connection(stream, service).await.ok();
+            });
+        }
+    }
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+
+    use super::*;
+    use super::super::service::tests::simple_service;
+    use crate::data::signature::Dalek;
+    use crate::data::testing::Chain;
+
+    fn get_gateway() -> Gateway {
+        let mut gateway = Gateway::new();
+        gateway.add_builder("simple", Box::new(simple_service::Service::new)).unwrap();
+        gateway
+    }
+
+    fn post(path: &str, body: &'static str) -> hyper::Request<Body> {
+        hyper::Request::post(path).body(Body::from(body)).unwrap()
+    }
+
+    #[test]
+    fn test_to_camel() {
+        assert_eq!(to_camel("add"), "Add");
+        assert_eq!(to_camel("echo_unordered"), "EchoUnordered");
+    }
+
+    #[test]
+    fn test_call() {
+        let gateway = get_gateway();
+        let caller = Caller::new(Capability::new(u64::MAX, 0));
+        LocalPool::new().run_until(async {
+            assert_eq!(gateway.call(&caller, "simple", "add", json!([13])).await, Ok(json!(13)));
+            assert_eq!(gateway.call(&caller, "simple", "clear", json!([])).await, Ok(Value::Null));
+            assert_eq!(gateway.call(&caller, "simple", "mul", json!([2])).await.unwrap_err().kind(),
+                       ErrorKind::NotFound);
+            assert_eq!(gateway.call(&caller, "other", "add", json!([2])).await.unwrap_err().kind(),
+                       ErrorKind::NotFound);
+            assert_eq!(gateway.call(&caller, "simple", "add", json!(["a"])).await.unwrap_err().kind(),
+                       ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn test_call_denied() {
+        let events = Arc::new(ServerEvents::new());
+        let mut receiver = events.subscribe(4);
+        let mut gateway = Gateway::new().with_events(events);
+        gateway.add_builder("simple", Box::new(simple_service::Service::new)).unwrap();
+
+        // only `clear` is allowed
+        let caller = Caller::new(Capability::new(0b0001, 0));
+        LocalPool::new().run_until(async {
+            assert_eq!(gateway.call(&caller, "simple", "clear", json!([])).await, Ok(Value::Null));
+            assert_eq!(gateway.call(&caller, "simple", "add", json!([1])).await.unwrap_err().kind(),
+                       ErrorKind::Forbidden);
+            match receiver.next().await {
+                Some(ServerEvent::RequestDenied(denial)) => {
+                    assert_eq!(denial.method, "add");
+                    assert_eq!(denial.origin.service, "simple");
+                },
+                _ => panic!("denial not emitted"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_handle() {
+        let gateway = get_gateway().with_authenticator(Box::new(|_| Ok(Caller::new(Capability::new(u64::MAX, 0)))));
+        LocalPool::new().run_until(async {
+            let response = gateway.handle(post("/simple/add", "[7]")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"7");
+
+            let request = hyper::Request::get("/simple/add").body(Body::empty()).unwrap();
+            assert_eq!(gateway.handle(request).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+            let response = gateway.handle(post("/simple/nope", "[]")).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_handle_unauthorized() {
+        let gateway = get_gateway();
+        LocalPool::new().run_until(async {
+            let response = gateway.handle(post("/simple/add", "[7]")).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn test_handle_body_size() {
+        let gateway = get_gateway().with_max_body_size(4)
+            .with_authenticator(Box::new(|_| Ok(Caller::new(Capability::new(u64::MAX, 0)))));
+        LocalPool::new().run_until(async {
+            assert_eq!(gateway.handle(post("/simple/add", "[7]")).await.status(), StatusCode::OK);
+            assert_eq!(gateway.handle(post("/simple/add", "[    7]")).await.status(),
+                       StatusCode::PAYLOAD_TOO_LARGE);
+
+            let (mut sender, body) = Body::channel();
+            let request = hyper::Request::post("/simple/add").body(body).unwrap();
+            let send = async move {
+                sender.send_data("[  ".into()).await.unwrap();
+                sender.send_data("7]".into()).await.unwrap();
+            };
+            let (response, _) = future::join(gateway.handle(request), send).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+
+    #[test]
+    fn test_handle_presentations() {
+        // simple_service's `add` capability bit
+        let chain = Chain::<Dalek>::new(1, 0u64, 1, Capability::new(0b0010, 0));
+        let gateway = get_gateway().with_presentations::<u64, Dalek>(chain.verifier(0), Duration::from_secs(30));
+        let other = Chain::<Dalek>::new(10, 0u64, 1, Capability::new(0b0010, 0));
+        let now = SystemClock.timestamp();
+        let token = |chain: &Chain<Dalek>, timestamp, nonce| {
+            let binding = request_binding(timestamp, nonce);
+            let presentation = Presentation::new(chain.reference.clone(), &chain.holder(), &binding).unwrap();
+            format!("Presentation {}", presentation.to_token().unwrap())
+        };
+
+        LocalPool::new().run_until(async {
+            let request = |path, token: String, timestamp: u64, nonce: &str|
+                hyper::Request::post(path).header(hyper::header::AUTHORIZATION, token)
+                    .header(TIMESTAMP_HEADER, timestamp.to_string()).header(NONCE_HEADER, nonce)
+                    .body(Body::from("[7]")).unwrap();
+            let status = |request| async { gateway.handle(request).await.status() };
+
+            let nonce = "0123456789abcdef";
+            assert_eq!(status(request("/simple/add", token(&chain, now, nonce), now, nonce)).await,
+                       StatusCode::OK);
+            // nonces can not be reused
+            assert_eq!(status(request("/simple/add", token(&chain, now, nonce), now, nonce)).await,
+                       StatusCode::UNAUTHORIZED);
+            let nonce = "0123456789abcdeg";
+            assert_eq!(status(request("/simple/sub", token(&chain, now, nonce), now, nonce)).await,
+                       StatusCode::FORBIDDEN);
+
+            // presentation is bound to the request's timestamp and nonce
+            let nonce = "0123456789abcdeh";
+            let token_ = token(&chain, now, nonce);
+            assert_eq!(status(request("/simple/add", token_.clone(), now + 1, nonce)).await,
+                       StatusCode::UNAUTHORIZED);
+            assert_eq!(status(request("/simple/add", token_, now, "0123456789abcdei")).await,
+                       StatusCode::UNAUTHORIZED);
+            let old = now - 60;
+            assert_eq!(status(request("/simple/add", token(&chain, old, nonce), old, nonce)).await,
+                       StatusCode::UNAUTHORIZED);
+            assert_eq!(status(request("/simple/add", token(&chain, now, "short"), now, "short")).await,
+                       StatusCode::UNAUTHORIZED);
+
+            // references are not accepted as bearer tokens, nor from other issuers
+            let bearer = format!("Bearer {}", chain.to_token().unwrap());
+            assert_eq!(status(request("/simple/add", bearer, now, nonce)).await, StatusCode::UNAUTHORIZED);
+            assert_eq!(status(request("/simple/add", token(&other, now, nonce), now, nonce)).await,
+                       StatusCode::UNAUTHORIZED);
+            assert_eq!(status(request("/simple/add", token(&chain, now, nonce), now, nonce)).await,
+                       StatusCode::OK);
+        });
+    }
+}
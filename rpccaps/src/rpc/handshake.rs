@@ -0,0 +1,190 @@
+//! Handshake frame opening a stream.
+//!
+//! Clients start each stream with a fixed-size handshake, followed by the
+//! id of the service they call: magic bytes identifying the protocol, the
+//! client's protocol version (see `version`), and the codec of the stream.
+//! The server validates it before routing the stream, so that peers of an
+//! unsupported version or codec are rejected, as streams reset with a
+//! `Rejection`, instead of failing on garbage decode errors.
+//!
+//! Layout (little endian): `MAGIC`, version as `u16`, codec id as `u8`.
+use futures::io::{AsyncRead, AsyncReadExt};
+
+use crate::{ErrorKind, Result};
+use super::codec::BincodeCodec;
+#[cfg(feature="cbor")]
+use super::codec::CborCodec;
+#[cfg(feature="json")]
+use super::codec::JsonCodec;
+use super::message::Rejection;
+use super::version;
+
+
+/// Magic bytes starting a stream.
+pub const MAGIC: [u8; 4] = *b"rpcc";
+
+/// Size of an encoded handshake.
+pub const SIZE: usize = 7;
+
+
+/// Codec of a stream's messages.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CodecId {
+    Bincode,
+    Json,
+    Cbor,
+}
+
+impl CodecId {
+    /// Id sent on the wire.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::Bincode => 0,
+            Self::Json => 1,
+            Self::Cbor => 2,
+        }
+    }
+
+    /// Codec of provided id.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Bincode),
+            1 => Some(Self::Json),
+            2 => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+}
+
+
+/// Codec identified in handshakes.
+pub trait WireCodec {
+    const ID: CodecId;
+}
+
+impl<T> WireCodec for BincodeCodec<T> {
+    const ID: CodecId = CodecId::Bincode;
+}
+
+#[cfg(feature="json")]
+impl<T> WireCodec for JsonCodec<T> {
+    const ID: CodecId = CodecId::Json;
+}
+
+#[cfg(feature="cbor")]
+impl<T> WireCodec for CborCodec<T> {
+    const ID: CodecId = CodecId::Cbor;
+}
+
+
+/// Handshake of a stream.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Handshake {
+    /// Client's protocol version.
+    pub version: u16,
+    /// Codec of the stream.
+    pub codec: CodecId,
+}
+
+impl Handshake {
+    /// Handshake of the current protocol version.
+    pub fn new(codec: CodecId) -> Self {
+        Self { version: version::PROTOCOL_VERSION, codec }
+    }
+
+    /// Return encoded handshake.
+    pub fn encode(&self) -> [u8; SIZE] {
+        let mut data = [0u8; SIZE];
+        data[..4].copy_from_slice(&MAGIC);
+        data[4..6].copy_from_slice(&self.version.to_le_bytes());
+        data[6] = self.codec.to_byte();
+        data
+    }
+
+    /// Decode handshake, failing with the rejection of invalid ones.
+    pub fn decode(data: &[u8; SIZE]) -> std::result::Result<Self, Rejection> {
+        if data[..4] != MAGIC {
+            return Err(Rejection::InvalidHandshake)
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        let codec = CodecId::from_byte(data[6]).ok_or(Rejection::InvalidHandshake)?;
+        Ok(Self { version, codec })
+    }
+
+    /// Validate handshake against what the server supports: its version
+    /// and codec `codec`.
+    pub fn validate(&self, codec: CodecId) -> std::result::Result<(), Rejection> {
+        match (version::is_supported(self.version), self.codec == codec) {
+            (false, _) => Err(Rejection::UnsupportedVersion),
+            (_, false) => Err(Rejection::InvalidHandshake),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read handshake from `reader`, validating it. Return the rejection
+    /// to send back to the client along with the error.
+    pub async fn accept<R: AsyncRead+Unpin>(reader: &mut R, codec: CodecId)
+        -> std::result::Result<Self, (Option<Rejection>, crate::Error)>
+    {
+        let mut data = [0u8; SIZE];
+        reader.read_exact(&mut data).await
+              .map_err(|_| (None, ErrorKind::IO.error("can not read handshake")))?;
+        let handshake = Self::decode(&data)
+            .map_err(|rejection| (Some(rejection), ErrorKind::InvalidData.error("invalid handshake")))?;
+        handshake.validate(codec).map_err(|rejection| {
+            let reason = match rejection {
+                Rejection::UnsupportedVersion => format!(
+                    "unsupported protocol version {} (supported: {} to {})", handshake.version,
+                    version::MIN_PROTOCOL_VERSION, version::PROTOCOL_VERSION),
+                _ => format!("unsupported codec {:?} (expected {:?})", handshake.codec, codec),
+            };
+            (Some(rejection), ErrorKind::InvalidData.error(reason))
+        })?;
+        Ok(handshake)
+    }
+}
+
+
+/// Return encoded handshake followed by service's `id` encoded with
+/// bincode: the header of a client's stream.
+pub fn header<Id: serde::Serialize>(id: Id) -> Result<Vec<u8>> {
+    use super::codec::Encoder;
+
+    let mut buffer = bytes::BytesMut::new();
+    buffer.extend_from_slice(&Handshake::new(CodecId::Bincode).encode());
+    BincodeCodec::new().encode(id, &mut buffer)
+        .or(ErrorKind::Codec.err("can not encode service's id"))?;
+    Ok(buffer.to_vec())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let handshake = Handshake::new(CodecId::Bincode);
+        let data = handshake.encode();
+        assert_eq!(&data[..4], b"rpcc");
+        assert_eq!(Handshake::decode(&data), Ok(handshake));
+
+        let accept = |data: Vec<u8>| block_on(async move {
+            let mut reader = futures::io::Cursor::new(data);
+            Handshake::accept(&mut reader, CodecId::Bincode).await.map_err(|(rejection, _)| rejection)
+        });
+        assert_eq!(accept(data.to_vec()), Ok(handshake));
+        assert_eq!(accept(data[..3].to_vec()), Err(None));
+        assert_eq!(accept(b"GET / HTTP/1.1".to_vec()), Err(Some(Rejection::InvalidHandshake)));
+
+        let old = Handshake { version: version::MIN_PROTOCOL_VERSION - 1, ..handshake };
+        assert_eq!(accept(old.encode().to_vec()), Err(Some(Rejection::UnsupportedVersion)));
+        let json = Handshake::new(CodecId::Json);
+        assert_eq!(accept(json.encode().to_vec()), Err(Some(Rejection::InvalidHandshake)));
+        let mut data = data;
+        data[6] = 0xff;
+        assert_eq!(Handshake::decode(&data), Err(Rejection::InvalidHandshake));
+    }
+}
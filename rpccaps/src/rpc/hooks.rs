@@ -0,0 +1,228 @@
+//! Post-process services' responses before they are sent (redaction,
+//! payload compression, etc.).
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Process a response before it is sent, returning the response to send (if
+/// any). Caller's capability is provided as it was when the request has been
+/// dispatched.
+pub trait ResponseHook<S: Service>: Send+Sync {
+    fn process(&self, capability: &S::Capability, response: S::Response) -> Option<S::Response>;
+}
+
+impl<S, F> ResponseHook<S> for F
+    where S: Service, F: Send+Sync+Fn(&S::Capability, S::Response) -> Option<S::Response>
+{
+    fn process(&self, capability: &S::Capability, response: S::Response) -> Option<S::Response> {
+        self(capability, response)
+    }
+}
+
+
+/// Service running response hooks on the responses of the inner service.
+///
+/// Hooks are run in their registration order, between `dispatch()` and the
+/// sink. Streamed responses are processed one by one as they are produced.
+/// Hooks are shared among clones, so they can be configured once at
+/// registration and reused by built services.
+pub struct Hooked<S: Service> {
+    inner: S,
+    hooks: Vec<Arc<dyn ResponseHook<S>>>,
+}
+
+impl<S: Service> Hooked<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, hooks: Vec::new() }
+    }
+
+    /// Append a hook.
+    pub fn hook(mut self, hook: impl ResponseHook<S>+'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Append hooks shared with other services.
+    pub fn with_hooks(mut self, hooks: &[Arc<dyn ResponseHook<S>>]) -> Self {
+        self.hooks.extend(hooks.iter().cloned());
+        self
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Run hooks over response.
+    fn process(&self, response: S::Response) -> Option<S::Response> {
+        process(&self.hooks, &self.inner.capability(), response)
+    }
+}
+
+impl<S: Service+'static> Hooked<S> {
+    /// Return responses' stream processed by hooks.
+    fn process_stream(&self, responses: BoxStream<'static, S::Response>)
+        -> BoxStream<'static, S::Response>
+    {
+        let (hooks, capability) = (self.hooks.clone(), self.inner.capability());
+        responses.filter_map(move |resp| future::ready(process(&hooks, &capability, resp)))
+                 .boxed()
+    }
+}
+
+/// Run hooks over response.
+fn process<S: Service>(hooks: &[Arc<dyn ResponseHook<S>>], capability: &S::Capability,
+                       response: S::Response) -> Option<S::Response>
+{
+    hooks.iter().try_fold(response, |resp, hook| hook.process(capability, resp))
+}
+
+impl<S: Service+Clone> Clone for Hooked<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), hooks: self.hooks.clone() }
+    }
+}
+
+#[async_trait]
+impl<S: Service+'static> Service for Hooked<S>
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let response = self.inner.dispatch(request).await?;
+        self.process(response)
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let responses = self.inner.dispatch_streaming(request).await?;
+        Ok(self.process_stream(responses))
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let responses = self.inner.dispatch_incoming(request, requests).await?;
+        Ok(self.process_stream(responses))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+
+    use super::*;
+    use crate::data::Capability;
+    use super::super::service::tests::simple_service::{Service as Simple, Request, Response};
+
+    #[test]
+    fn test_hooks() {
+        let redact: Arc<dyn ResponseHook<Simple>> = Arc::new(|_: &Capability, resp| match resp {
+            Response::Add(_) => Some(Response::Add(0)),
+            resp => Some(resp),
+        });
+        let mut service = Hooked::new(Simple::new())
+            .hook(|_: &Capability, resp| match resp {
+                Response::Clear => None,
+                resp => Some(resp),
+            })
+            .with_hooks(&[redact]);
+
+        LocalPool::new().run_until(async {
+            assert!(matches!(service.dispatch(Request::Add(13)).await, Some(Response::Add(0))));
+            assert!(matches!(service.dispatch(Request::Sub(1)).await, Some(Response::Sub(12))));
+            assert!(service.dispatch(Request::Clear()).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_hooks_streaming() {
+        use super::super::service::tests::streaming_service::{self, Request, Response};
+        let mut service = Hooked::new(streaming_service::Service { start: 0 })
+            .hook(|_: &Capability, resp| match resp {
+                Response::CountChunk(item) if item % 2 == 1 => None,
+                resp => Some(resp),
+            });
+
+        // responses are processed as they are produced
+        LocalPool::new().run_until(async {
+            let responses = match service.dispatch_streaming(Request::Count(u32::MAX)).await {
+                Ok(responses) => responses,
+                Err(_) => panic!("request is not streaming"),
+            };
+            let items = responses.take(3).map(|resp| match resp {
+                Response::CountChunk(item) => item,
+                _ => panic!("unexpected response"),
+            }).collect::<Vec<_>>().await;
+            assert_eq!(items, vec![0, 2, 4]);
+        });
+    }
+}
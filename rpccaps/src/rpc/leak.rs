@@ -0,0 +1,201 @@
+//! Leak detection, enabled by the `leak-detection` feature.
+//!
+//! Long-running servers leak resources through entries that are never
+//! released: dispatched futures that never complete, streams' framed
+//! buffers kept by a forgotten handle, sessions kept by services that are
+//! never dropped. Counters tell that something leaks, not what. With the
+//! feature, such resources are tracked from their creation until they are
+//! dropped, along with their creation's backtrace; `report()` returns the
+//! outstanding ones, which `services::leaks` serves on demand:
+//!
+//! ```ignore
+//! let report = leak::report().older_than(Duration::from_secs(600));
+//! eprintln!("{}", report.to_text());
+//! ```
+//!
+//! Capturing backtraces is expensive: the feature is meant for debugging.
+//! Without it, tracking is a no-op and reports are empty.
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+pub use tracker::*;
+
+
+/// Kind of tracked resource.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Kind {
+    /// Dispatched future.
+    Task,
+    /// Stream's framed buffers.
+    Frame,
+    /// Session entry.
+    Session,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Task => "task",
+            Self::Frame => "frame",
+            Self::Session => "session",
+        }
+    }
+}
+
+
+/// Outstanding tracked resource.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct Entry {
+    pub kind: Kind,
+    pub label: String,
+    /// Time elapsed since its creation.
+    pub age: Duration,
+    /// Backtrace of its creation.
+    pub backtrace: String,
+}
+
+/// Outstanding resources, oldest first.
+#[derive(Serialize,Deserialize,Clone,Debug,Default,PartialEq)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+}
+
+impl Report {
+    /// Return count of entries of provided kind.
+    pub fn count(&self, kind: Kind) -> usize {
+        self.entries.iter().filter(|entry| entry.kind == kind).count()
+    }
+
+    /// Only keep entries older than `age`: leaked resources are the ones
+    /// that outlive what they are used for.
+    pub fn older_than(mut self, age: Duration) -> Self {
+        self.entries.retain(|entry| entry.age >= age);
+        self
+    }
+
+    /// Return report as text, with entries' backtraces.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for kind in [Kind::Task, Kind::Frame, Kind::Session].iter() {
+            writeln!(text, "{}: {} outstanding", kind.name(), self.count(*kind)).unwrap();
+        }
+        for entry in self.entries.iter() {
+            writeln!(text, "\n{} `{}`, created {:?} ago:\n{}", entry.kind.name(), entry.label,
+                     entry.age, entry.backtrace).unwrap();
+        }
+        text
+    }
+}
+
+
+/// Return future tracked as a task until it is dropped, whether it
+/// completed or not.
+pub fn track_future<F: Future>(label: &str, future: F) -> impl Future<Output=F::Output> {
+    let tracked = track(Kind::Task, label);
+    async move {
+        let _tracked = tracked;
+        future.await
+    }
+}
+
+
+#[cfg(feature="leak-detection")]
+mod tracker {
+    use std::backtrace::Backtrace;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Instant;
+
+    use super::{Entry, Kind, Report};
+
+    struct Record {
+        kind: Kind,
+        label: String,
+        created: Instant,
+        backtrace: Backtrace,
+    }
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    static RECORDS: Mutex<BTreeMap<u64, Record>> = Mutex::new(BTreeMap::new());
+
+    /// Tracked resource, released when dropped.
+    #[derive(Debug)]
+    pub struct Tracked(u64);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            RECORDS.lock().unwrap().remove(&self.0);
+        }
+    }
+
+    /// Track a resource until the returned value is dropped.
+    pub fn track(kind: Kind, label: &str) -> Tracked {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let record = Record { kind, label: label.to_string(), created: Instant::now(),
+                              backtrace: Backtrace::force_capture() };
+        RECORDS.lock().unwrap().insert(id, record);
+        Tracked(id)
+    }
+
+    /// Return report of outstanding resources.
+    pub fn report() -> Report {
+        let records = RECORDS.lock().unwrap();
+        let entries = records.values().map(|record| Entry {
+            kind: record.kind, label: record.label.clone(), age: record.created.elapsed(),
+            backtrace: record.backtrace.to_string(),
+        }).collect();
+        Report { entries }
+    }
+}
+
+
+#[cfg(not(feature="leak-detection"))]
+mod tracker {
+    use super::{Kind, Report};
+
+    /// Disabled tracking.
+    #[derive(Debug)]
+    pub struct Tracked;
+
+    pub fn track(_kind: Kind, _label: &str) -> Tracked {
+        Tracked
+    }
+
+    pub fn report() -> Report {
+        Report::default()
+    }
+}
+
+
+#[cfg(all(test, feature="leak-detection"))]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn count(label: &str) -> usize {
+        report().entries.iter().filter(|entry| entry.label == label).count()
+    }
+
+    #[test]
+    fn test_track() {
+        let session = track(Kind::Session, "test-track");
+        let future = track_future("test-track", async { 1 });
+        assert_eq!(count("test-track"), 2);
+
+        let entry = report().entries.into_iter().find(|entry| entry.kind == Kind::Session
+                                                              && entry.label == "test-track").unwrap();
+        assert!(entry.backtrace.contains("test_track"));
+        let report = Report { entries: vec![entry] };
+        assert!(report.to_text().starts_with("task: 0 outstanding\nframe: 0 outstanding\nsession: 1 outstanding\n"));
+        assert_eq!(report.older_than(Duration::from_secs(60)).entries.len(), 0);
+
+        assert_eq!(block_on(future), 1);
+        drop(session);
+        assert_eq!(count("test-track"), 0);
+    }
+}
@@ -0,0 +1,274 @@
+//! Declarative registration of services.
+//!
+//! A `Manifest` describes which builders are registered under which ids,
+//! with which options. Builders are referred to by name, as registered on
+//! `Builders` by the host. Server composition can thus be driven from
+//! configuration files, and tested as data:
+//!
+//! ```ignore
+//! let mut builders = Builders::new();
+//! builders.add("storage", |_| storage::Service::new())?;
+//!
+//! let manifest: Manifest<u32> = serde_json::from_str(&config)?;
+//! manifest.apply(&server.dispatch, &builders)?;
+//! ```
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::time::Duration;
+
+use futures::io::{AsyncRead,AsyncWrite};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{ErrorKind, Result};
+use crate::data::Capability;
+use super::admission::RateLimit;
+use super::dispatch::{Dispatch, HandlerOptions, UnsafeTimeout};
+use super::enforce::{Enforced, Fingerprint, Origin};
+use super::service::Service;
+
+
+/// Registration options of a manifest's entry.
+#[derive(Serialize,Deserialize,Clone,Debug,Default,PartialEq)]
+#[serde(default)]
+pub struct EntryOptions {
+    /// If true, remove handler after call.
+    pub once: bool,
+    /// Priority of the handler's streams.
+    pub priority: Option<i32>,
+    /// Maximum duration of services' construction by async builders.
+    pub build_timeout: Option<Duration>,
+    /// Duration after which handler is removed.
+    pub ttl: Option<Duration>,
+    /// Maximum duration of a call to the handler.
+    pub timeout: Option<Duration>,
+    /// Handling of timed out calls of services that are not
+    /// cancellation-safe, detached by default.
+    pub unsafe_timeout: UnsafeTimeout,
+    /// Maximum rate of requests to the entry's services.
+    pub max_rate: Option<RateLimit>,
+    /// Actions of the methods callers are allowed to call, in addition to
+    /// their capability, as a bits field of services' first 64 actions.
+    /// Requests to other methods are denied.
+    pub capability: Option<u64>,
+    /// Fingerprints of the peers' identities the entry's id is pinned to
+    /// (see `Dispatch::pin`).
+    pub identities: Option<BTreeSet<Fingerprint>>,
+}
+
+impl From<&EntryOptions> for HandlerOptions {
+    fn from(options: &EntryOptions) -> Self {
+        Self { once: options.once, priority: options.priority, build_timeout: options.build_timeout,
+               ttl: options.ttl, timeout: options.timeout, unsafe_timeout: options.unsafe_timeout,
+               max_rate: options.max_rate }
+    }
+}
+
+
+/// Service registration.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct Entry<Id> {
+    /// Id of the handler.
+    pub id: Id,
+    /// Name of the builder, as registered on `Builders`.
+    pub builder: String,
+    #[serde(default)]
+    pub options: EntryOptions,
+}
+
+
+/// Services' registrations, applied to a dispatcher.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct Manifest<Id> {
+    pub services: Vec<Entry<Id>>,
+}
+
+impl<Id: Ord+Clone> Manifest<Id> {
+    /// Check that entries' builders exist and ids are unique.
+    pub fn validate<S,R,D>(&self, builders: &Builders<Id,S,R,D>) -> Result<()> {
+        let mut ids = BTreeSet::new();
+        for entry in self.services.iter() {
+            if !builders.contains(&entry.builder) {
+                return ErrorKind::NotFound.err(format!("no builder named `{}`", entry.builder))
+            }
+            if !ids.insert(&entry.id) {
+                return ErrorKind::KeyError.err("duplicate service id in manifest")
+            }
+        }
+        Ok(())
+    }
+
+    /// Register entries' services to dispatcher, pinning their ids when
+    /// restricted to identities. The manifest is validated first, so that
+    /// nothing is registered when it is invalid.
+    pub fn apply<S,R,D>(&self, dispatch: &Dispatch<Id,(S,R,D)>, builders: &Builders<Id,S,R,D>)
+        -> Result<()>
+    {
+        self.validate(builders)?;
+        for entry in self.services.iter() {
+            if let Some(ref identities) = entry.options.identities {
+                dispatch.pin(entry.id.clone(), identities.iter().copied());
+            }
+            builders.register(&entry.builder, dispatch, entry.id.clone(), &entry.options)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Register a builder on a dispatcher, with provided id and options.
+type Register<Id,S,R,D> = Box<dyn Fn(&Dispatch<Id,(S,R,D)>, Id, &EntryOptions) -> Result<()>+Send+Sync>;
+
+/// Services' builders referred to by manifests.
+pub struct Builders<Id: Ord,S,R,D> {
+    builders: BTreeMap<String, Register<Id,S,R,D>>,
+}
+
+impl<Id: Ord,S,R,D> Default for Builders<Id,S,R,D> {
+    fn default() -> Self {
+        Self { builders: BTreeMap::new() }
+    }
+}
+
+impl<Id: Ord,S,R,D> Builders<Id,S,R,D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return true if a builder is registered with this name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.builders.contains_key(name)
+    }
+
+    /// Registered builders' names.
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.builders.keys().map(String::as_str)
+    }
+
+    /// Register builder named `name` to dispatcher.
+    pub fn register(&self, name: &str, dispatch: &Dispatch<Id,(S,R,D)>, id: Id,
+                    options: &EntryOptions) -> Result<()>
+    {
+        match self.builders.get(name) {
+            Some(register) => register(dispatch, id, options),
+            None => ErrorKind::NotFound.err(format!("no builder named `{}`", name)),
+        }
+    }
+}
+
+impl<Id,S,R,D> Builders<Id,S,R,D>
+    where for<'de> Id: Ord+Clone+Send+Sync+Debug+Deserialize<'de>,
+          S: 'static+AsyncWrite+Unpin+Sync+Send,
+          R: 'static+AsyncRead+Unpin+Sync+Send,
+          D: 'static+Sync+Send,
+{
+    /// Add a builder, failing if there already is one with this name.
+    pub fn add<F,Sv>(&mut self, name: impl Into<String>, builder: F) -> Result<()>
+        where F: 'static+Clone+Send+Sync+Unpin+Fn(D)->Sv,
+              Sv: 'static+Send+Sync+Service,
+              Sv::Request: DeserializeOwned, Sv::Response: 'static+Serialize,
+              Sv::Capability: From<Capability>
+    {
+        let name = name.into();
+        if self.builders.contains_key(&name) {
+            return ErrorKind::KeyError.err(format!("builder `{}` already exists", name))
+        }
+        let register = move |dispatch: &Dispatch<Id,(S,R,D)>, id: Id, options: &EntryOptions| {
+            let builder = builder.clone();
+            match options.capability {
+                Some(mask) => {
+                    let origin = Origin::new(format!("{:?}", id));
+                    dispatch.add_builder(id, Box::new(move |data| {
+                        Enforced::new(builder(data), origin.clone()).with_mask(Capability::new(mask, mask).into())
+                    }), options.into())
+                },
+                None => dispatch.add_builder(id, Box::new(builder), options.into()),
+            }
+        };
+        self.builders.insert(name, Box::new(register));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::executor::LocalPool;
+
+    use super::*;
+    use super::super::codec::{BincodeCodec, Decoder, Encoder};
+    use super::super::dispatch::tests::SharedWriter;
+    use super::super::service::tests::simple_service;
+
+    type TestDispatch = Dispatch<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>;
+
+    fn builders() -> Builders<u32, SharedWriter, futures::io::Cursor<Vec<u8>>, ()> {
+        let mut builders = Builders::new();
+        builders.add("simple", |_| simple_service::Service::new()).unwrap();
+        builders
+    }
+
+    fn entry(id: u32, builder: &str, options: EntryOptions) -> Entry<u32> {
+        Entry { id, builder: builder.into(), options }
+    }
+
+    #[test]
+    fn test_apply() {
+        let add_bit = 1 << simple_service::Service::methods().iter()
+                             .find(|(name, _)| *name == "add").unwrap().1;
+        // unsafe timed out calls are detached unless set otherwise
+        assert_eq!(HandlerOptions::from(&EntryOptions::default()).unsafe_timeout, UnsafeTimeout::Detach);
+
+        let manifest = Manifest { services: vec![
+            entry(0, "simple", EntryOptions { priority: Some(2), ..Default::default() }),
+            entry(1, "simple", EntryOptions { capability: Some(!add_bit), ..Default::default() }),
+        ]};
+        // manifests are data
+        let encoded = bincode::serialize(&manifest).unwrap();
+        assert_eq!(bincode::deserialize::<Manifest<u32>>(&encoded).unwrap(), manifest);
+
+        let dispatch = TestDispatch::new(None);
+        manifest.apply(&dispatch, &builders()).unwrap();
+        assert_eq!(dispatch.priority(&0), Some(2));
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+        LocalPool::new().run_until(async {
+            for (id, allowed) in [(0, true), (1, false)] {
+                let writer = SharedWriter::default();
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(id, (writer.clone(), reader, ())).await.unwrap();
+
+                let mut response = BytesMut::from(writer.0.lock().unwrap().as_slice());
+                let response = BincodeCodec::<simple_service::Response>::new()
+                                    .decode(&mut response).unwrap();
+                match (response, allowed) {
+                    (Some(simple_service::Response::Add(3)), true) => (),
+                    (Some(simple_service::Response::__Denied(..)), false) => (),
+                    _ => panic!("unexpected response for service {}", id),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_validate() {
+        let dispatch = TestDispatch::new(None);
+        let manifest = Manifest { services: vec![
+            entry(0, "simple", EntryOptions::default()),
+            entry(1, "unknown", EntryOptions::default()),
+        ]};
+        assert_eq!(manifest.apply(&dispatch, &builders()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(dispatch.handlers.get(&0).unwrap().is_none());
+
+        let manifest = Manifest { services: vec![
+            entry(0, "simple", EntryOptions::default()),
+            entry(0, "simple", EntryOptions { once: true, ..Default::default() }),
+        ]};
+        assert_eq!(manifest.validate(&builders()).unwrap_err().kind(), ErrorKind::KeyError);
+
+        let mut builders = builders();
+        assert_eq!(builders.add("simple", |_| simple_service::Service::new()).unwrap_err().kind(),
+                   ErrorKind::KeyError);
+    }
+}
@@ -0,0 +1,173 @@
+//! Messages exchanged on the wire besides services' requests and responses.
+use std::fmt;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned,Deserialize,Serialize};
+
+use crate::data::ObjectId;
+
+
+/// Error sent on the wire by a service, converted from and into the errors
+/// of services declared with `#[rpc(error="...")]`.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct RemoteError {
+    /// Error code, defined by the service.
+    pub code: u32,
+    /// Error description.
+    pub message: String,
+}
+
+impl RemoteError {
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RemoteError({}): {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+
+/// Error sent by the server instead of a request's response, when it
+/// failed to handle the request.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq,Eq)]
+pub enum Error {
+    /// Request does not call any of service's methods.
+    ActionNotFound,
+    /// Request has been dispatched, but no response has been returned.
+    Internal(String),
+    /// Call of this id has been cancelled by the client.
+    Cancelled(u64),
+    /// Service could not be built for the stream (e.g. a value could not
+    /// be extracted from the connection's context).
+    Unavailable,
+    /// Error returned by a fallible method (returning a `Result`), encoded
+    /// with bincode (see `Error::method()`).
+    Method(Vec<u8>),
+}
+
+impl Error {
+    /// Error of a method that returned `Err(error)`.
+    pub fn method<E: Serialize>(error: &E) -> Self {
+        Self::Method(bincode::serialize(error).unwrap_or_default())
+    }
+
+    /// Decode error of a fallible method, if it is one.
+    pub fn method_error<E: DeserializeOwned>(&self) -> Option<E> {
+        match self {
+            Self::Method(error) => bincode::deserialize(error).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ActionNotFound => write!(f, "action not found"),
+            Self::Internal(reason) => write!(f, "internal error: {}", reason),
+            Self::Cancelled(call_id) => write!(f, "call {} cancelled", call_id),
+            Self::Unavailable => write!(f, "service unavailable"),
+            Self::Method(_) => write!(f, "method failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+
+/// Response sent instead of the one of a request exceeding its service's
+/// rate, asking the client to wait before calling the method again.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+pub struct SlowDown {
+    /// Duration to wait before the next call.
+    pub retry_after: Duration,
+}
+
+
+/// Control message pushed by the server on a connection.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Control {
+    /// Reference to this object has been revoked.
+    Revoked(ObjectId),
+}
+
+
+/// Reason of a connection or stream refused by the server, sent to the peer
+/// as QUIC application error code along with its reason.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Rejection {
+    /// Too many connections from peer's address.
+    TooManyConnections,
+    /// Too many new streams on the connection.
+    RateLimited,
+    /// Peer's identity has exhausted its execution time budget.
+    BudgetExhausted,
+    /// Stream's handshake is of an unsupported protocol version.
+    UnsupportedVersion,
+    /// Stream's handshake is malformed or of an unsupported codec.
+    InvalidHandshake,
+    /// Stream's service is draining (see `Dispatch::drain`).
+    Unavailable,
+    /// Stream's service is pinned to other identities than the peer's
+    /// (see `Dispatch::pin`).
+    Forbidden,
+}
+
+impl Rejection {
+    /// Error code sent on the wire.
+    pub fn code(self) -> u32 {
+        match self {
+            Self::TooManyConnections => 1,
+            Self::RateLimited => 2,
+            Self::BudgetExhausted => 3,
+            Self::UnsupportedVersion => 4,
+            Self::InvalidHandshake => 5,
+            Self::Unavailable => 6,
+            Self::Forbidden => 7,
+        }
+    }
+
+    /// Rejection of provided error code.
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            1 => Some(Self::TooManyConnections),
+            2 => Some(Self::RateLimited),
+            3 => Some(Self::BudgetExhausted),
+            4 => Some(Self::UnsupportedVersion),
+            5 => Some(Self::InvalidHandshake),
+            6 => Some(Self::Unavailable),
+            7 => Some(Self::Forbidden),
+            _ => None,
+        }
+    }
+
+    /// Error of the peer whose stream or connection is rejected.
+    pub fn error(self) -> crate::Error {
+        let kind = match self {
+            Self::TooManyConnections | Self::RateLimited | Self::BudgetExhausted =>
+                crate::ErrorKind::LimitReached,
+            Self::UnsupportedVersion | Self::InvalidHandshake => crate::ErrorKind::InvalidData,
+            Self::Unavailable => crate::ErrorKind::Unavailable,
+            Self::Forbidden => crate::ErrorKind::Forbidden,
+        };
+        kind.error(self.reason())
+    }
+
+    /// Human-readable reason.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::TooManyConnections => "too many connections",
+            Self::RateLimited => "stream rate limit exceeded",
+            Self::BudgetExhausted => "execution time budget exhausted",
+            Self::UnsupportedVersion => "unsupported protocol version",
+            Self::InvalidHandshake => "invalid stream handshake",
+            Self::Unavailable => "service is unavailable",
+            Self::Forbidden => "peer's identity is not allowed",
+        }
+    }
+}
@@ -0,0 +1,773 @@
+//! Per-method latency and error metrics of services.
+//!
+//! `Measured` wraps a service, recording the latency of each call into an
+//! exponential histogram, keyed by the index of the called method (as
+//! emitted by `#[service]`). Streamed responses are measured until their
+//! stream is complete or dropped.
+//!
+//! A server's metrics are gathered by a `Collector`: measured services by
+//! name, bytes going through `Counted` codecs, open connections, in-flight
+//! dispatches and identities' execution time budgets. Once collected by a
+//! `Dispatch` (see `Dispatch::collect`), services registered by builder or
+//! pool are measured and their codecs counted. Its `MetricsSnapshot` can
+//! be rendered in Prometheus' text format, and served by
+//! `services::metrics::Exporter`.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use crate::data::Clock;
+use super::budget::{BudgetUsage, Budgets};
+use super::codec::{CodecFactory, Decoder, Encoder};
+use super::dispatch::Dispatch;
+use super::enforce::Fingerprint;
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Count of histogram's buckets.
+pub const BUCKETS: usize = 32;
+
+
+/// Latency histogram with exponential buckets: bucket `i` counts values
+/// lower than `2^i` microseconds, the last one counting all greater values.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self { buckets: [0; BUCKETS], count: 0, sum: Duration::ZERO }
+    }
+
+    /// Upper bound of bucket at provided index.
+    pub fn bucket_bound(index: usize) -> Duration {
+        Duration::from_micros(1u64 << index.min(BUCKETS - 1))
+    }
+
+    /// Record a value.
+    pub fn record(&mut self, value: Duration) {
+        let micros = value.as_micros().min(u64::MAX as u128) as u64;
+        let index = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[index.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Count of values by bucket.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Count of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of recorded values.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Mean of recorded values.
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(self.sum / count as u32),
+        }
+    }
+
+    /// Upper bound of the quantile `q` (in `[0, 1]`) of recorded values.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets.iter().position(|count| {
+            seen += count;
+            seen >= rank
+        }).map(Self::bucket_bound)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Metrics of a single method.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct MethodMetrics {
+    /// Count of calls.
+    pub calls: u64,
+    /// Count of calls whose response is an error.
+    pub errors: u64,
+    /// Calls' latency.
+    pub latency: Histogram,
+}
+
+impl MethodMetrics {
+    /// Ratio of calls responding an error.
+    pub fn error_rate(&self) -> f64 {
+        match self.calls {
+            0 => 0.0,
+            calls => self.errors as f64 / calls as f64,
+        }
+    }
+}
+
+
+/// Metrics of a service's methods, by method index. They can be shared
+/// among services of a same kind.
+#[derive(Debug,Default)]
+pub struct Metrics {
+    methods: Mutex<BTreeMap<usize, MethodMetrics>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a call of method at `index`.
+    pub fn record(&self, index: usize, latency: Duration, error: bool) {
+        let mut methods = self.methods.lock().unwrap();
+        let method = methods.entry(index).or_default();
+        method.calls += 1;
+        method.errors += error as u64;
+        method.latency.record(latency);
+    }
+
+    /// Return metrics of method at `index`.
+    pub fn method(&self, index: usize) -> Option<MethodMetrics> {
+        self.methods.lock().unwrap().get(&index).cloned()
+    }
+
+    /// Return metrics of all called methods, by index.
+    pub fn snapshot(&self) -> BTreeMap<usize, MethodMetrics> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    /// Return metrics of called methods of service `S`, by name.
+    pub fn named<S: Service>(&self) -> Vec<(&'static str, MethodMetrics)> {
+        let methods = S::methods();
+        self.snapshot().into_iter()
+            .filter_map(|(index, metrics)| methods.get(index).map(|(name, _)| (*name, metrics)))
+            .collect()
+    }
+}
+
+
+/// Record call of a streaming method once dropped.
+struct Call {
+    metrics: Arc<Metrics>,
+    index: usize,
+    start: Instant,
+    error: bool,
+}
+
+impl Call {
+    fn observe(&mut self, error: bool) {
+        self.error |= error;
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        self.metrics.record(self.index, self.start.elapsed(), self.error);
+    }
+}
+
+
+/// Service recording metrics of the inner service's calls.
+pub struct Measured<S: Service> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S: Service> Measured<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    /// Recorded metrics.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Measure stream of responses until it is dropped.
+    fn measure(&self, index: Option<usize>, start: Instant,
+               responses: BoxStream<'static, S::Response>)
+        -> BoxStream<'static, S::Response>
+        where S::Response: 'static
+    {
+        let mut call = match index {
+            Some(index) => Call { metrics: self.metrics.clone(), index, start, error: false },
+            None => return responses,
+        };
+        responses.map(move |response| {
+            call.observe(S::is_error(&response));
+            response
+        }).boxed()
+    }
+}
+
+impl<S: Service+Clone> Clone for Measured<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), metrics: self.metrics.clone() }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for Measured<S>
+    where S::Response: 'static
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let (index, start) = (S::method_index(&request), Instant::now());
+        let response = self.inner.dispatch(request).await;
+        if let Some(index) = index {
+            let error = response.as_ref().is_some_and(S::is_error);
+            self.metrics.record(index, start.elapsed(), error);
+        }
+        response
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let (index, start) = (S::method_index(&request), Instant::now());
+        let responses = self.inner.dispatch_streaming(request).await?;
+        Ok(self.measure(index, start, responses))
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let (index, start) = (S::method_index(&request), Instant::now());
+        let responses = self.inner.dispatch_incoming(request, requests).await?;
+        Ok(self.measure(index, start, responses))
+    }
+}
+
+
+/// Gauge read at snapshot, returning None once its target is gone.
+type Gauge = Box<dyn Fn() -> Option<u64>+Send+Sync>;
+
+/// Budgets' accounting read at snapshot, returning None once they are gone.
+type BudgetsRead = Box<dyn Fn() -> Option<BTreeMap<Fingerprint, BudgetUsage>>+Send+Sync>;
+
+/// Metrics of a named service.
+struct ServiceMetrics {
+    methods: &'static [(&'static str, usize)],
+    metrics: Arc<Metrics>,
+}
+
+
+/// Collect metrics of a server: calls of named services, bytes encoded and
+/// decoded by codecs, open connections, identities' budgets, and gauges
+/// read at snapshot.
+#[derive(Default)]
+pub struct Collector {
+    services: Mutex<BTreeMap<String, ServiceMetrics>>,
+    gauges: Mutex<BTreeMap<String, Gauge>>,
+    budgets: Mutex<Option<BudgetsRead>>,
+    encoded: AtomicU64,
+    decoded: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return metrics of service `S` registered under provided name,
+    /// shared by all services measured under this name.
+    pub fn service<S: Service>(&self, name: &str) -> Arc<Metrics> {
+        let mut services = self.services.lock().unwrap();
+        services.entry(name.to_string())
+                .or_insert_with(|| ServiceMetrics { methods: S::methods(), metrics: Arc::new(Metrics::new()) })
+                .metrics.clone()
+    }
+
+    /// Measure calls of service under provided name.
+    pub fn measure<S: Service>(&self, name: &str, service: S) -> Measured<S> {
+        Measured::new(service, self.service::<S>(name))
+    }
+
+    /// Count bytes going through codecs built by provided factory.
+    pub fn codec<F>(self: &Arc<Self>, factory: F) -> Counted<F> {
+        Counted::new(factory, Some(self.clone()))
+    }
+
+    /// Add gauge read at snapshot, replacing the one of the same name.
+    /// The gauge is removed once it returns None.
+    pub fn gauge<F>(&self, name: &str, read: F)
+        where F: 'static+Fn() -> Option<u64>+Send+Sync
+    {
+        self.gauges.lock().unwrap().insert(name.to_string(), Box::new(read));
+    }
+
+    /// Add gauge `dispatch_in_flight` of the streams being dispatched,
+    /// without keeping the dispatcher alive.
+    pub fn watch_dispatch<Id, D>(&self, dispatch: &Arc<Dispatch<Id, D>>)
+        where Id: 'static+Ord+Send+Sync, D: 'static
+    {
+        let dispatch = Arc::downgrade(dispatch);
+        self.gauge("dispatch_in_flight", move || {
+            Weak::upgrade(&dispatch).map(|dispatch| dispatch.count.load(Ordering::Relaxed) as u64)
+        });
+    }
+
+    /// Export accounting of provided budgets, without keeping them alive.
+    pub fn watch_budgets<C>(&self, budgets: &Arc<Budgets<C>>)
+        where C: 'static+Clock+Send+Sync
+    {
+        let budgets = Arc::downgrade(budgets);
+        *self.budgets.lock().unwrap() = Some(Box::new(move || {
+            Weak::upgrade(&budgets).map(|budgets| budgets.snapshot())
+        }));
+    }
+
+    /// Count an open connection until returned guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Count of open connections.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Count of bytes encoded by counted codecs.
+    pub fn encoded(&self) -> u64 {
+        self.encoded.load(Ordering::Relaxed)
+    }
+
+    /// Count of bytes decoded by counted codecs.
+    pub fn decoded(&self) -> u64 {
+        self.decoded.load(Ordering::Relaxed)
+    }
+
+    /// Return current metrics, removing gauges whose target is gone.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let services = self.services.lock().unwrap().iter().map(|(name, service)| {
+            let methods = service.metrics.snapshot().into_iter()
+                .filter_map(|(index, metrics)| service.methods.get(index).map(|(name, _)| (*name, metrics)))
+                .collect();
+            (name.clone(), methods)
+        }).collect();
+
+        let mut gauges = BTreeMap::new();
+        self.gauges.lock().unwrap().retain(|name, read| match read() {
+            Some(value) => { gauges.insert(name.clone(), value); true },
+            None => false,
+        });
+        let mut budgets = self.budgets.lock().unwrap();
+        let usages = budgets.as_ref().and_then(|read| read());
+        if usages.is_none() {
+            *budgets = None;
+        }
+        MetricsSnapshot { services, gauges, encoded: self.encoded(), decoded: self.decoded(),
+                          connections: self.connections(), budgets: usages.unwrap_or_default() }
+    }
+}
+
+
+/// Open connection counted by a `Collector`.
+pub struct ConnectionGuard(Arc<Collector>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Codec (or codec factory) counting bytes it encodes and decodes into its
+/// collector, if any.
+pub struct Counted<C> {
+    inner: C,
+    collector: Option<Arc<Collector>>,
+}
+
+impl<C> Counted<C> {
+    pub fn new(inner: C, collector: Option<Arc<Collector>>) -> Self {
+        Self { inner, collector }
+    }
+
+    fn count(&self, counter: fn(&Collector) -> &AtomicU64, bytes: usize) {
+        if let Some(ref collector) = self.collector {
+            counter(collector).fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<I, C: Encoder<I>> Encoder<I> for Counted<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = dst.len();
+        self.inner.encode(item, dst)?;
+        self.count(|collector| &collector.encoded, dst.len().saturating_sub(len));
+        Ok(())
+    }
+}
+
+impl<C: Decoder> Decoder for Counted<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = src.len();
+        let item = self.inner.decode(src)?;
+        self.count(|collector| &collector.decoded, len.saturating_sub(src.len()));
+        Ok(item)
+    }
+}
+
+impl<O, I, F: CodecFactory<O, I>> CodecFactory<O, I> for Counted<F> {
+    type Encoder = Counted<F::Encoder>;
+    type EncoderError = F::EncoderError;
+    type Decoder = Counted<F::Decoder>;
+
+    fn codec(&self) -> (Self::Encoder, Self::Decoder) {
+        let (encoder, decoder) = self.inner.codec();
+        (Counted::new(encoder, self.collector.clone()), Counted::new(decoder, self.collector.clone()))
+    }
+}
+
+
+/// Metrics collected at a point in time.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct MetricsSnapshot {
+    /// Metrics of called methods, by service and method name.
+    pub services: BTreeMap<String, BTreeMap<&'static str, MethodMetrics>>,
+    /// Gauges' values, by name.
+    pub gauges: BTreeMap<String, u64>,
+    /// Bytes encoded by counted codecs.
+    pub encoded: u64,
+    /// Bytes decoded by counted codecs.
+    pub decoded: u64,
+    /// Open connections.
+    pub connections: u64,
+    /// Accounting of budgeted identities, by fingerprint.
+    pub budgets: BTreeMap<Fingerprint, BudgetUsage>,
+}
+
+impl MetricsSnapshot {
+    /// Metrics of a service's method.
+    pub fn method(&self, service: &str, method: &str) -> Option<&MethodMetrics> {
+        self.services.get(service)?.get(method)
+    }
+
+    /// Total count of calls.
+    pub fn calls(&self) -> u64 {
+        self.services.values().flat_map(|methods| methods.values()).map(|m| m.calls).sum()
+    }
+
+    /// Render metrics in Prometheus' text exposition format. Latency
+    /// buckets are exposed in seconds; gauges are prefixed by `rpccaps_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let methods = || self.services.iter().flat_map(|(service, methods)| {
+            methods.iter().map(move |(method, metrics)| {
+                (format!("service=\"{}\",method=\"{}\"", escape(service), escape(method)), metrics)
+            })
+        });
+
+        out.push_str("# TYPE rpccaps_requests_total counter\n");
+        for (labels, metrics) in methods() {
+            writeln!(out, "rpccaps_requests_total{{{}}} {}", labels, metrics.calls).unwrap();
+        }
+        out.push_str("# TYPE rpccaps_request_errors_total counter\n");
+        for (labels, metrics) in methods() {
+            writeln!(out, "rpccaps_request_errors_total{{{}}} {}", labels, metrics.errors).unwrap();
+        }
+        out.push_str("# TYPE rpccaps_request_duration_seconds histogram\n");
+        for (labels, metrics) in methods() {
+            let latency = &metrics.latency;
+            let mut count = 0;
+            for (index, bucket) in latency.buckets()[..BUCKETS - 1].iter().enumerate() {
+                count += bucket;
+                writeln!(out, "rpccaps_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels,
+                         Histogram::bucket_bound(index).as_secs_f64(), count).unwrap();
+            }
+            writeln!(out, "rpccaps_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels,
+                     latency.count()).unwrap();
+            writeln!(out, "rpccaps_request_duration_seconds_sum{{{}}} {}", labels,
+                     latency.sum().as_secs_f64()).unwrap();
+            writeln!(out, "rpccaps_request_duration_seconds_count{{{}}} {}", labels,
+                     latency.count()).unwrap();
+        }
+
+        for (name, kind, value) in [("encoded_bytes_total", "counter", self.encoded),
+                                    ("decoded_bytes_total", "counter", self.decoded),
+                                    ("connections", "gauge", self.connections)] {
+            writeln!(out, "# TYPE rpccaps_{} {}\nrpccaps_{} {}", name, kind, name, value).unwrap();
+        }
+        let budgets = || self.budgets.iter().map(|(identity, usage)| {
+            let identity: String = identity.iter().map(|byte| format!("{:02x}", byte)).collect();
+            (identity, usage)
+        });
+        out.push_str("# TYPE rpccaps_budget_used_seconds gauge\n");
+        for (identity, usage) in budgets() {
+            writeln!(out, "rpccaps_budget_used_seconds{{identity=\"{}\"}} {}", identity,
+                     usage.used.as_secs_f64()).unwrap();
+        }
+        out.push_str("# TYPE rpccaps_budget_seconds_total counter\n");
+        for (identity, usage) in budgets() {
+            writeln!(out, "rpccaps_budget_seconds_total{{identity=\"{}\"}} {}", identity,
+                     usage.total.as_secs_f64()).unwrap();
+        }
+        out.push_str("# TYPE rpccaps_budget_calls_total counter\n");
+        for (identity, usage) in budgets() {
+            writeln!(out, "rpccaps_budget_calls_total{{identity=\"{}\"}} {}", identity,
+                     usage.calls).unwrap();
+        }
+        for (name, value) in self.gauges.iter() {
+            let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                                   .collect();
+            writeln!(out, "# TYPE rpccaps_{} gauge\nrpccaps_{} {}", name, name, value).unwrap();
+        }
+        out
+    }
+}
+
+/// Escape label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+
+    use super::*;
+    use super::super::codec::Bincode;
+    use super::super::service::tests::{error_service, simple_service, streaming_service};
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+        for micros in [0, 1, 3, 100, 100, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(&histogram.buckets()[..4], &[1, 1, 1, 0]);
+        assert_eq!(histogram.buckets()[7], 2);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(histogram.quantile(0.9), Some(Duration::from_micros(8192)));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(5_204_000 / 6)));
+
+        histogram.record(Duration::from_secs(1 << 20));
+        assert_eq!(histogram.buckets()[BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn test_measured() {
+        let metrics = Arc::new(Metrics::new());
+        LocalPool::new().run_until(async {
+            let mut service = Measured::new(simple_service::Service::new(), metrics.clone());
+            service.dispatch(simple_service::Request::Add(2)).await;
+            service.dispatch(simple_service::Request::Add(3)).await;
+            service.dispatch(simple_service::Request::Get()).await;
+            service.dispatch(simple_service::Request::__Capabilities).await;
+
+            let mut service = Measured::new(error_service::Service, Arc::new(Metrics::new()));
+            service.dispatch(error_service::Request::Div(4, 2)).await;
+            service.dispatch(error_service::Request::Div(4, 0)).await;
+            let div = service.metrics().method(0).unwrap();
+            assert_eq!((div.calls, div.errors, div.error_rate()), (2, 1, 0.5));
+
+            let streaming = Arc::new(Metrics::new());
+            let mut service = Measured::new(streaming_service::Service { start: 0 },
+                                            streaming.clone());
+            let responses = service.dispatch_streaming(streaming_service::Request::Count(3))
+                                   .await.ok().unwrap();
+            assert_eq!(streaming.method(0), None);
+            assert_eq!(responses.count().await, 4);
+            assert_eq!(streaming.method(0).unwrap().calls, 1);
+        });
+
+        // `__Capabilities` is not a method
+        assert_eq!(metrics.snapshot().values().map(|m| m.calls).sum::<u64>(), 3);
+        let named = metrics.named::<simple_service::Service>().into_iter()
+                           .map(|(name, m)| (name, m.calls)).collect::<Vec<_>>();
+        assert_eq!(named, vec![("add", 2), ("get", 1)]);
+    }
+
+    #[test]
+    fn test_collector() {
+        let collector = Arc::new(Collector::new());
+        LocalPool::new().run_until(async {
+            let mut service = collector.measure("simple", simple_service::Service::new());
+            service.dispatch(simple_service::Request::Add(2)).await;
+            let mut service = collector.measure("simple", simple_service::Service::new());
+            service.dispatch(simple_service::Request::Add(3)).await;
+        });
+
+        let factory = collector.codec(Bincode);
+        let (mut encoder, mut decoder) = CodecFactory::<u64, u64>::codec(&factory);
+        let mut buffer = BytesMut::new();
+        encoder.encode(7u64, &mut buffer).unwrap();
+        let size = buffer.len() as u64;
+        assert_eq!(decoder.decode(&mut buffer).unwrap(), Some(7));
+        assert_eq!((collector.encoded(), collector.decoded()), (size, size));
+
+        let guard = collector.connection();
+        let value = Arc::new(AtomicU64::new(3));
+        collector.gauge("value", {
+            let value = Arc::downgrade(&value);
+            move || value.upgrade().map(|value| value.load(Ordering::Relaxed))
+        });
+
+        let snapshot = collector.snapshot();
+        assert_eq!(snapshot.method("simple", "add").map(|m| m.calls), Some(2));
+        assert_eq!((snapshot.calls(), snapshot.connections), (2, 1));
+        assert_eq!(snapshot.gauges.get("value"), Some(&3));
+        let text = snapshot.to_prometheus();
+        assert!(text.contains("rpccaps_requests_total{service=\"simple\",method=\"add\"} 2\n"));
+        assert!(text.contains("rpccaps_request_duration_seconds_bucket{service=\"simple\",method=\"add\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains(&format!("rpccaps_encoded_bytes_total {}\n", size)));
+        assert!(text.contains("rpccaps_value 3\n"));
+
+        // gauges are removed once their target is gone
+        drop((guard, value));
+        let snapshot = collector.snapshot();
+        assert_eq!((snapshot.connections, snapshot.gauges.len()), (0, 0));
+    }
+
+    #[test]
+    fn test_collector_budgets() {
+        use super::super::budget::TimeBudget;
+
+        let collector = Collector::new();
+        let budgets = Arc::new(Budgets::new(TimeBudget::new(Duration::from_secs(1), Duration::from_secs(60))));
+        collector.watch_budgets(&budgets);
+        budgets.record([0xab; 32], Duration::from_millis(250));
+
+        let snapshot = collector.snapshot();
+        assert_eq!(snapshot.budgets.get(&[0xab; 32]).map(|usage| usage.calls), Some(1));
+        let text = snapshot.to_prometheus();
+        let identity = "ab".repeat(32);
+        assert!(text.contains(&format!("rpccaps_budget_used_seconds{{identity=\"{}\"}} 0.25\n", identity)));
+        assert!(text.contains(&format!("rpccaps_budget_calls_total{{identity=\"{}\"}} 1\n", identity)));
+
+        drop(budgets);
+        assert!(collector.snapshot().budgets.is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_collect() {
+        use super::super::codec::BincodeCodec;
+        use super::super::dispatch::{HandlerOptions, tests::SharedWriter};
+
+        let mut request = BytesMut::new();
+        BincodeCodec::new().encode(simple_service::Request::Add(3), &mut request).unwrap();
+
+        let collector = Arc::new(Collector::new());
+        let dispatch = Dispatch::<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>::new(None);
+        dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()), HandlerOptions::default())
+                .unwrap();
+        dispatch.collect(collector.clone());
+        dispatch.add_builder(1, Box::new(|_| simple_service::Service::new()), HandlerOptions::default())
+                .unwrap();
+
+        // only services registered once collected are measured
+        let writer = SharedWriter::default();
+        LocalPool::new().run_until(async {
+            for id in 0..2 {
+                let reader = futures::io::Cursor::new(request.to_vec());
+                dispatch.dispatch(id, (writer.clone(), reader, ())).await.unwrap();
+            }
+        });
+        let snapshot = collector.snapshot();
+        let name = std::any::type_name::<simple_service::Service>();
+        assert_eq!(snapshot.method(name, "add").map(|m| m.calls), Some(1));
+        assert_eq!(snapshot.decoded, request.len() as u64);
+        assert_eq!(snapshot.encoded as usize * 2, writer.0.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,333 @@
+//! Middleware run around services' dispatch (logging, metrics, access
+//! checks, etc.), without editing the services themselves.
+//!
+//! A `Layered` service runs its middleware's `before_dispatch` on each
+//! method's call, denying the request when it fails, then
+//! `after_dispatch` once the inner service returned the response. Layers
+//! compose, the last one added being run first:
+//!
+//! ```ignore
+//! let service = Layered::new(storage::Service::new(), Logging).layer(Quotas::new());
+//! ```
+//!
+//! Requests are cloned before their dispatch, so that `after_dispatch`
+//! gets them along with their response. For streaming calls, it is run on
+//! each response of the stream.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use crate::Result;
+use super::message::Error;
+use super::protocol::{Call, Frame, Reply};
+use super::service::Service;
+
+
+/// Return name of the method called by request, if any.
+pub fn method_name<S: Service>(request: &S::Request) -> Option<&'static str> {
+    S::method_index(request).and_then(|index| S::methods().get(index)).map(|(name, _)| *name)
+}
+
+
+/// Middleware run around a service's dispatch.
+pub trait ServiceMiddleware<S: Service>: Send+Sync {
+    /// Check request before it is dispatched: the request is denied with
+    /// the error as reason when it fails.
+    fn before_dispatch(&self, _request: &S::Request) -> Result<()> {
+        Ok(())
+    }
+
+    /// Observe the response returned by the dispatch of a call's request,
+    /// or each response streamed by streaming calls.
+    fn after_dispatch(&self, _request: &S::Request, _response: &Option<S::Response>) {}
+}
+
+
+/// Service running middleware around the dispatch of the inner one.
+pub struct Layered<S: Service, M> {
+    inner: S,
+    /// Shared with the streams of streaming calls.
+    middleware: Arc<M>,
+}
+
+impl<S: Service, M: ServiceMiddleware<S>> Layered<S, M> {
+    pub fn new(inner: S, middleware: M) -> Self {
+        Self { inner, middleware: Arc::new(middleware) }
+    }
+
+    /// Add a layer of middleware around this one.
+    pub fn layer<N>(self, middleware: N) -> Layered<Self, N>
+        where N: ServiceMiddleware<Self>, M: 'static, S::Request: 'static+Clone+Send, S::Response: 'static
+    {
+        Layered::new(self, middleware)
+    }
+}
+
+impl<S: Service, M> Layered<S, M> {
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Request's call, as classified by `request_frame()`. Since requests
+    /// go through the dispatch methods in turn, each one only runs the
+    /// middleware on the calls it handles, so that it is run once.
+    fn call(request: &S::Request) -> Option<Call> {
+        match S::request_frame(request) {
+            Some(Frame::Request(call)) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+impl<S: Service, M: ServiceMiddleware<S>> Layered<S, M> {
+    /// Run middleware after the dispatch of request on each of the streamed
+    /// responses.
+    fn observe(&self, request: S::Request, responses: BoxStream<'static, S::Response>)
+        -> BoxStream<'static, S::Response>
+        where M: 'static, S::Request: 'static+Send, S::Response: 'static
+    {
+        let middleware = self.middleware.clone();
+        responses.map(move |response| {
+            let response = Some(response);
+            middleware.after_dispatch(&request, &response);
+            response
+        }).filter_map(future::ready).boxed()
+    }
+
+    /// Run middleware before the dispatch of request, returning the
+    /// response to send instead of dispatching it when it fails.
+    /// Notifications are dropped, since the client does not read their
+    /// response.
+    pub fn check(&self, request: &S::Request) -> Option<Option<S::Response>> {
+        S::method_index(request)?;
+        let err = self.middleware.before_dispatch(request).err()?;
+        match Self::call(request) {
+            Some(Call { reply: Reply::None, .. }) => Some(None),
+            _ => Some(S::denied(S::call_id(request), err.to_string())),
+        }
+    }
+}
+
+impl<S: Service+Clone, M> Clone for Layered<S, M> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), middleware: self.middleware.clone() }
+    }
+}
+
+#[async_trait]
+impl<S, M> Service for Layered<S, M>
+    where S: Service, S::Request: 'static+Clone+Send, S::Response: 'static, M: 'static+ServiceMiddleware<S>
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let call = Self::call(&request);
+        if matches!(call, Some(Call { incoming: true, .. } | Call { reply: Reply::Stream, .. })) {
+            return self.inner.dispatch(request).await
+        }
+        if let Some(response) = self.check(&request) {
+            return response
+        }
+        let response = self.inner.dispatch(request.clone()).await;
+        self.middleware.after_dispatch(&request, &response);
+        response
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> std::result::Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: false, reply: Reply::Stream })) {
+            return self.inner.dispatch_streaming(request).await
+        }
+        match self.check(&request) {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => {
+                let responses = self.inner.dispatch_streaming(request.clone()).await?;
+                Ok(self.observe(request, responses))
+            },
+        }
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> std::result::Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: true, .. })) {
+            return self.inner.dispatch_incoming(request, requests).await
+        }
+        match self.check(&request) {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => {
+                let responses = self.inner.dispatch_incoming(request.clone(), requests).await?;
+                Ok(self.observe(request, responses))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::ErrorKind;
+    use crate::rpc::call::CallError;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    /// Record dispatched methods, refusing to add more than `max`.
+    #[derive(Clone)]
+    struct Recorder {
+        max: u32,
+        calls: Arc<Mutex<Vec<(&'static str, bool)>>>,
+    }
+
+    impl ServiceMiddleware<simple_service::Service> for Recorder {
+        fn before_dispatch(&self, request: &simple_service::Request) -> Result<()> {
+            match request {
+                simple_service::Request::Add(a) if *a > self.max =>
+                    ErrorKind::LimitReached.err("value is too large"),
+                _ => Ok(()),
+            }
+        }
+
+        fn after_dispatch(&self, request: &simple_service::Request, response: &Option<simple_service::Response>) {
+            let method = method_name::<simple_service::Service>(request).unwrap();
+            self.calls.lock().unwrap().push((method, response.is_some()));
+        }
+    }
+
+    /// Count calls of any service.
+    struct Counter(Arc<Mutex<u32>>);
+
+    impl<S: Service> ServiceMiddleware<S> for Counter {
+        fn after_dispatch(&self, _request: &S::Request, _response: &Option<S::Response>) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn test_layered() {
+        let recorder = Recorder { max: 10, calls: Arc::new(Mutex::new(Vec::new())) };
+        let count = Arc::new(Mutex::new(0));
+        let (server_transport, client_transport) =
+            MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service::Client::new(client_transport);
+            assert_eq!(client.add(4).await, Ok(4));
+            assert_eq!(client.add(11).await, Err(CallError::Failed));
+            assert_eq!(client.clear().await, Ok(()));
+            assert_eq!(client.get().await, Ok(0));
+        };
+        let server_fut = {
+            let (recorder, count) = (recorder.clone(), count.clone());
+            async move {
+                let (s,r) = server_transport.split();
+                let mut service = Layered::new(simple_service::Service::new(), recorder)
+                                      .layer(Counter(count));
+                service.serve(Transport::new(s, r)).await;
+            }
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        // denied calls are not dispatched by inner layers
+        assert_eq!(*recorder.calls.lock().unwrap(), vec![("add", true), ("clear", false), ("get", true)]);
+        assert_eq!(*count.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_layered_streaming() {
+        use crate::rpc::service::tests::streaming_service;
+        let count = Arc::new(Mutex::new(0));
+        let (server_transport, client_transport) =
+            MPSCTransport::<streaming_service::Response, streaming_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = streaming_service::Client::new(client_transport);
+            let items = client.count(3).await.unwrap().collect::<Vec<_>>().await;
+            assert_eq!(items, vec![0, 1, 2]);
+            assert_eq!(client.start().await, Ok(0));
+        };
+        let server_fut = {
+            let count = count.clone();
+            async move {
+                let (s,r) = server_transport.split();
+                let mut service = Layered::new(streaming_service::Service { start: 0 }, Counter(count));
+                service.serve(Transport::new(s, r)).await;
+            }
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        // streamed items and their end, then the unary call
+        assert_eq!(*count.lock().unwrap(), 5);
+    }
+}
@@ -1,16 +1,62 @@
+pub mod admission;
+pub mod backpressure;
+pub mod bounded;
+pub mod budget;
+pub mod bus;
+pub mod cancel;
+pub mod call;
 pub mod codec;
 pub mod config;
+pub mod deps;
 pub mod dispatch;
+pub mod enforce;
+pub mod events;
+pub mod filter;
+pub mod handshake;
+pub mod hooks;
+pub mod leak;
+pub mod manifest;
+pub mod message;
+pub mod middleware;
+pub mod protocol;
+pub mod receipt;
+pub mod reaper;
+pub mod revocation;
+pub mod router;
+pub mod schema;
 pub mod service;
+pub mod stable;
+pub mod stream;
+pub mod throttle;
+pub mod trace;
 pub mod transport;
+pub mod validated;
+pub mod version;
+pub mod wire;
 
 
+#[cfg(feature="network")]
+pub mod client;
 #[cfg(feature="network")]
 pub mod context;
 #[cfg(feature="network")]
+pub mod extract;
+#[cfg(feature="network")]
+pub mod peer;
+#[cfg(feature="network")]
+pub mod pipeline;
+#[cfg(feature="network")]
 pub mod server;
-//#[cfg(feature="network")]
-//pub mod client;
+#[cfg(feature="metrics")]
+pub mod metrics;
+#[cfg(feature="testing")]
+pub mod testing;
+#[cfg(any(feature="tower", feature="tarpc-compat"))]
+pub mod compat;
+#[cfg(feature="mmap")]
+pub mod blob;
+#[cfg(feature="gateway")]
+pub mod gateway;
 
 pub use codec::BincodeCodec;
 pub use service::Service;
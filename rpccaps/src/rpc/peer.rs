@@ -0,0 +1,174 @@
+//! Peer mode: a single endpoint both accepting and dialing connections.
+//!
+//! In symmetric capability networks, nodes are not strictly clients or
+//! servers: a `PeerEndpoint` binds one QUIC endpoint with both the server
+//! and the client configuration, sharing its server's dispatch table among
+//! all its connections:
+//! - accepted connections are dispatched to services as by the server, and
+//!   a `Client` is returned for each one, in order to call the services of
+//!   the remote peer over the same connection;
+//! - streams opened by dialed peers are dispatched to services as well.
+//!
+//! ```ignore
+//! let (peer, incoming) = PeerEndpoint::bind(server, &client_config, address)?;
+//! let peer = Arc::new(peer);
+//! tokio::spawn({
+//!     let peer = peer.clone();
+//!     async move { peer.accept(incoming).for_each(|client| ...).await }
+//! });
+//! let client = peer.connect(remote, "remote").await?;
+//! ```
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use serde::Deserialize;
+
+use crate::{ErrorKind, Result};
+use super::client::Client;
+use super::config::ClientConfig;
+use super::context::{Context, DefaultContext};
+use super::server::Server;
+use super::trace;
+
+
+/// Endpoint accepting and dialing connections, dispatching streams opened
+/// by its peers to its server's services.
+pub struct PeerEndpoint<Id=u64, C=DefaultContext>
+    where Id: std::cmp::Ord,
+          C: Context
+{
+    server: Arc<Server<Id, C>>,
+    endpoint: quinn::Endpoint,
+    reaper: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl<Id, C> PeerEndpoint<Id, C>
+    where for<'de> Id: 'static+std::cmp::Ord+Clone+Send+Sync+Deserialize<'de>+Unpin
+                       +trace::SpanField,
+                   C: 'static+Context+Send+Sync
+{
+    /// Bind endpoint to provided address, returning its incoming
+    /// connections, to be accepted with `accept()`. Server's reaper runs
+    /// until the endpoint is dropped.
+    pub fn bind(server: Server<Id, C>, client_config: &ClientConfig, address: SocketAddr)
+        -> Result<(Self, quinn::Incoming)>
+    {
+        let server_config = server.config.get_server_config()?;
+        let (mut endpoint, incoming) = quinn::Endpoint::server(server_config, address)
+                .or(ErrorKind::Endpoint.err("can't init endpoint"))?;
+        endpoint.set_default_client_config(client_config.get_client_config()?);
+        let reaper = server.config.reap_interval.map(|_| server.reaper.clone().spawn());
+        Ok((Self { server: Arc::new(server), endpoint, reaper }, incoming))
+    }
+
+    /// Server dispatching peers' streams.
+    pub fn server(&self) -> &Arc<Server<Id, C>> {
+        &self.server
+    }
+
+    /// Underlying endpoint.
+    pub fn endpoint(&self) -> &quinn::Endpoint {
+        &self.endpoint
+    }
+
+    /// Address the endpoint is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.endpoint.local_addr().or_else(|err| ErrorKind::IO.err(err.to_string()))
+    }
+
+    /// Accept incoming connections, returning a client for each accepted
+    /// one. Connections are only accepted while the stream is polled.
+    pub fn accept(&self, incoming: quinn::Incoming) -> BoxStream<'_, Client> {
+        incoming.filter_map(move |conn| async move {
+            let (connection, uni_streams) = self.server.accept(&self.endpoint, conn).await?;
+            Some(Client::new(self.endpoint.clone(), connection, uni_streams))
+        }).boxed()
+    }
+
+    /// Dial peer at provided address, using `server_name` for its
+    /// certificate validation. Streams opened by the peer over the
+    /// connection are dispatched to services.
+    pub async fn connect(&self, address: SocketAddr, server_name: &str) -> Result<Client> {
+        let connecting = self.endpoint.connect(address, server_name)
+                .or_else(|err| ErrorKind::Endpoint.err(err.to_string()))?;
+        let quinn::NewConnection { connection, bi_streams, uni_streams, .. } = connecting.await
+                .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        self.server.dispatch_connection(&self.endpoint, connection.clone(), bi_streams, None);
+        Ok(Client::new(self.endpoint.clone(), connection, uni_streams))
+    }
+}
+
+impl<Id, C> Drop for PeerEndpoint<Id, C>
+    where Id: std::cmp::Ord,
+          C: Context
+{
+    fn drop(&mut self) {
+        if let Some(ref reaper) = self.reaper {
+            reaper.abort();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use super::super::config::ServerConfig;
+    use super::super::dispatch::HandlerOptions;
+    use super::super::service::tests::simple_service;
+    use crate::data::tls;
+
+    fn peer(name: &str, pinned: &[PathBuf]) -> (PeerEndpoint<u32>, quinn::Incoming) {
+        let cert_path = env::temp_dir().join(format!("rpccaps-test-peer-{}.der", name));
+        let key_path = env::temp_dir().join(format!("rpccaps-test-peer-{}-key.der", name));
+        let mut server_config = ServerConfig::default();
+        server_config.connection_config.cert_subjects = vec![String::from(name)];
+        server_config.connection_config.cert_path = Some((cert_path, key_path));
+        let server = Server::<u32>::new(server_config);
+        server.dispatch.add_builder(0, Box::new(|_| simple_service::Service::new()),
+                                    HandlerOptions::default()).unwrap();
+
+        // peers only trust each other's certificate
+        let client_config = ClientConfig { pinned_certs: pinned.to_vec(), ..Default::default() };
+        PeerEndpoint::bind(server, &client_config, SocketAddr::from(([127,0,0,1], 0))).unwrap()
+    }
+
+    #[test]
+    fn test_peers() {
+        let runtime = Runtime::new().unwrap();
+        let pinned = ["alice", "bob"].map(|name| {
+            let (certs, key) = tls::new_cert(vec![String::from(name)]).unwrap();
+            let cert_path = env::temp_dir().join(format!("rpccaps-test-peer-{}.der", name));
+            let key_path = env::temp_dir().join(format!("rpccaps-test-peer-{}-key.der", name));
+            tls::write_cert(&certs, &key, &cert_path, &key_path).unwrap();
+            cert_path
+        });
+        runtime.block_on(async move {
+            let (alice, alice_incoming) = peer("alice", &pinned);
+            let (bob, bob_incoming) = peer("bob", &pinned);
+            let (alice, bob) = (Arc::new(alice), Arc::new(bob));
+            let address = bob.local_addr().unwrap();
+            let accepted = tokio::spawn({
+                let bob = bob.clone();
+                async move { bob.accept(bob_incoming).next().await }
+            });
+            drop(alice_incoming);
+
+            // alice calls bob over the connection she dialed
+            let to_bob = alice.connect(address, "bob").await.unwrap();
+            let transport = to_bob.service::<simple_service::Service, u32>(0).await.unwrap();
+            assert_eq!(simple_service::Client::new(transport).add(2).await, Ok(2));
+
+            // bob calls alice back over the accepted connection
+            let to_alice = accepted.await.unwrap().unwrap();
+            let transport = to_alice.service::<simple_service::Service, u32>(0).await.unwrap();
+            assert_eq!(simple_service::Client::new(transport).add(3).await, Ok(3));
+            to_bob.close();
+        });
+    }
+}
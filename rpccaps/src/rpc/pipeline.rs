@@ -0,0 +1,210 @@
+//! Composition of calls across services of a same connection.
+//!
+//! A pipeline runs successive steps (e.g. authenticate, fetch a reference,
+//! then call a service) sharing a deadline and a cancellation handle. Steps
+//! can register rollback callbacks, which are run in reverse order on a
+//! best-effort basis when a later step fails:
+//!
+//! ```ignore
+//! let mut pipeline = client.pipeline().with_timeout(Duration::from_secs(5));
+//! let mut auth = auth::Client::new(client.open(AUTH).await?);
+//! let token = pipeline.step(async { auth.login(creds).await.or(...) }).await?;
+//! pipeline.on_rollback(move || async move { ... });
+//! let value = pipeline.step(async { ... }).await?;
+//! pipeline.commit();
+//! ```
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::future::{self, BoxFuture, Either};
+use futures::prelude::*;
+use futures::task::{AtomicWaker, Poll};
+use tokio::time::Instant;
+
+use crate::{ErrorKind, Result};
+use super::client::Client;
+
+
+/// Rollback callback of a pipeline's step.
+type Rollback<'a> = Box<dyn FnOnce() -> BoxFuture<'a, ()> + Send + 'a>;
+
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Handle cancelling a pipeline, which can be shared among tasks.
+#[derive(Clone,Default)]
+pub struct CancelHandle(Arc<CancelState>);
+
+impl CancelHandle {
+    /// Cancel pipeline: running step is interrupted and following ones fail.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Release);
+        self.0.waker.wake();
+    }
+
+    /// Return true if pipeline has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Wait for cancellation.
+    fn cancelled(&self) -> impl Future<Output=()> + '_ {
+        future::poll_fn(move |cx| {
+            self.0.waker.register(cx.waker());
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+    }
+}
+
+
+/// Calls composed over a client's connection, with shared deadline and
+/// cancellation.
+pub struct Pipeline<'a, C=Client> {
+    client: &'a C,
+    deadline: Option<Instant>,
+    cancel: CancelHandle,
+    rollbacks: Vec<Rollback<'a>>,
+}
+
+impl<'a, C> Pipeline<'a, C> {
+    pub fn new(client: &'a C) -> Self {
+        Self { client, deadline: None, cancel: CancelHandle::default(), rollbacks: Vec::new() }
+    }
+
+    /// Set deadline of the whole pipeline.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set deadline of the whole pipeline, from now.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Return client.
+    pub fn client(&self) -> &'a C {
+        self.client
+    }
+
+    /// Return handle cancelling this pipeline.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Time remaining before deadline, if any.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Run a step, until completion, deadline or cancellation. When it
+    /// fails, registered rollbacks are run before returning the error.
+    pub async fn step<F, T>(&mut self, step: F) -> Result<T>
+        where F: Future<Output=Result<T>>
+    {
+        let result = self.run(step).await;
+        if result.is_err() {
+            self.rollback_all().await;
+        }
+        result
+    }
+
+    /// Register a callback run if a later step fails.
+    pub fn on_rollback<F, Fut>(&mut self, rollback: F)
+        where F: 'a+Send+FnOnce() -> Fut,
+              Fut: 'a+Send+Future<Output=()>
+    {
+        self.rollbacks.push(Box::new(move || rollback().boxed()));
+    }
+
+    /// End pipeline successfully, discarding rollbacks.
+    pub fn commit(self) {}
+
+    /// End pipeline, running rollbacks.
+    pub async fn rollback(mut self) {
+        self.rollback_all().await
+    }
+
+    async fn run<F, T>(&self, step: F) -> Result<T>
+        where F: Future<Output=Result<T>>
+    {
+        if self.cancel.is_cancelled() {
+            return ErrorKind::Cancelled.err("pipeline cancelled");
+        }
+
+        futures::pin_mut!(step);
+        let step = future::select(step, self.cancel.cancelled().boxed_local()).map(|either| {
+            match either {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => ErrorKind::Cancelled.err("pipeline cancelled"),
+            }
+        });
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, step).await
+                                .unwrap_or_else(|_| ErrorKind::Timeout.err("pipeline deadline exceeded")),
+            None => step.await,
+        }
+    }
+
+    /// Run rollbacks in reverse order of registration.
+    async fn rollback_all(&mut self) {
+        while let Some(rollback) = self.rollbacks.pop() {
+            rollback().await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let rollbacks = Arc::new(AtomicUsize::new(0));
+        Runtime::new().unwrap().block_on(async {
+            let mut pipeline = Pipeline::new(&()).with_timeout(Duration::from_millis(50));
+            assert_eq!(pipeline.step(async { Ok(1) }).await, Ok(1));
+
+            let counter = rollbacks.clone();
+            pipeline.on_rollback(move || async move { counter.fetch_add(1, Ordering::Relaxed); });
+            assert_eq!(pipeline.step(async { Ok(2) }).await, Ok(2));
+            assert_eq!(rollbacks.load(Ordering::Relaxed), 0);
+
+            let err = pipeline.step(async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Ok(3)
+            }).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Timeout);
+            assert_eq!(rollbacks.load(Ordering::Relaxed), 1);
+            assert_eq!(pipeline.remaining(), Some(Duration::ZERO));
+        });
+    }
+
+    #[test]
+    fn test_pipeline_cancel() {
+        Runtime::new().unwrap().block_on(async {
+            let mut pipeline = Pipeline::new(&());
+            let handle = pipeline.cancel_handle();
+            let err = pipeline.step(async {
+                handle.cancel();
+                future::pending::<Result<()>>().await
+            }).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Cancelled);
+
+            let err = pipeline.step(async { Ok(()) }).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Cancelled);
+        });
+    }
+}
@@ -0,0 +1,583 @@
+//! Per-stream protocol, as an explicit state machine.
+//!
+//! A stream goes through the following phases:
+//! - the client opens the stream with a `Hello` (the id of the service it
+//!   is dispatched to);
+//! - optionally, the client authenticates: `AuthRequest`, then server's
+//!   `AuthChallenge`, client's `AuthResponse` and server's `AuthResult`.
+//!   Authentication can only start when no call is pending. A client can
+//!   also `Resume` a previous authentication, answered by an `AuthResult`;
+//! - the client sends requests, to which the server answers with a single
+//!   `Response`, a stream of `ResponseChunk` ended by a `ResponseEnd`, or
+//!   nothing for notifications. Client-streaming requests are followed by
+//!   their `RequestChunk` items and a `RequestEnd`, before the client sends
+//!   anything else. Any request can be answered by a `Denied` instead;
+//! - the client closes its side, and the server closes the stream once
+//!   pending calls are answered.
+//!
+//! `Protocol` tracks a stream's state from the frames sent by both peers,
+//! returning a typed `ProtocolError` for illegal sequences. `Checked` uses
+//! it to check a transport's messages, as classified by
+//! `Service::request_frame` and `Service::response_frame`.
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+
+use crate::{Error, ErrorKind};
+
+
+/// Stream's peer.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Peer {
+    Client,
+    Server,
+}
+
+impl Peer {
+    /// The other peer.
+    pub fn other(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
+}
+
+
+/// Responses expected by a request.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Reply {
+    /// No response (notification).
+    None,
+    /// A single response.
+    Single,
+    /// A stream of responses.
+    Stream,
+}
+
+
+/// Kind of a call made by a request.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct Call {
+    /// Request is followed by streamed items.
+    pub incoming: bool,
+    /// Expected responses.
+    pub reply: Reply,
+}
+
+impl Call {
+    pub const NOTIFY: Call = Call { incoming: false, reply: Reply::None };
+    pub const UNARY: Call = Call { incoming: false, reply: Reply::Single };
+    pub const STREAMING: Call = Call { incoming: false, reply: Reply::Stream };
+
+    /// Client-streaming call with provided reply.
+    pub fn incoming(reply: Reply) -> Self {
+        Self { incoming: true, reply }
+    }
+}
+
+
+/// Frame of a stream, as seen by the protocol.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Frame {
+    /// Client opens the stream.
+    Hello,
+    /// Client requests authentication.
+    AuthRequest,
+    /// Server returns a challenge, or fails.
+    AuthChallenge(bool),
+    /// Client answers the challenge.
+    AuthResponse,
+    /// Server returns whether the client is authenticated.
+    AuthResult(bool),
+    /// Client resumes a previous authentication.
+    Resume,
+    /// Client calls a method.
+    Request(Call),
+    /// Client sends an item of a client-streaming call.
+    RequestChunk,
+    /// Client ends items of a client-streaming call.
+    RequestEnd,
+    /// Server answers a call.
+    Response,
+    /// Server sends an item of a call's responses stream.
+    ResponseChunk,
+    /// Server ends a call's responses stream.
+    ResponseEnd,
+    /// Server denies a call.
+    Denied,
+    /// Peer closes its side of the stream.
+    Close(Peer),
+}
+
+impl Frame {
+    /// Peer sending the frame.
+    pub fn sender(&self) -> Peer {
+        match self {
+            Self::Hello | Self::AuthRequest | Self::AuthResponse | Self::Resume | Self::Request(_) |
+            Self::RequestChunk | Self::RequestEnd => Peer::Client,
+            Self::AuthChallenge(_) | Self::AuthResult(_) | Self::Response | Self::ResponseChunk |
+            Self::ResponseEnd | Self::Denied => Peer::Server,
+            Self::Close(peer) => *peer,
+        }
+    }
+}
+
+
+/// Step of an authentication, waiting for a frame.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum AuthStep {
+    /// Waiting for server's challenge.
+    Challenge,
+    /// Waiting for client's response.
+    Response,
+    /// Waiting for server's result.
+    Result,
+}
+
+
+/// State of a stream.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum State {
+    /// Waiting for client's hello.
+    Start,
+    /// Client can send requests.
+    Open,
+    /// Authentication in progress.
+    Auth(AuthStep),
+    /// Client is sending the items of a client-streaming call.
+    Incoming,
+    /// Client closed its side, server may still answer pending calls.
+    HalfClosed,
+    /// Stream is closed, or failed.
+    Closed,
+}
+
+
+/// Illegal frame sequence.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ProtocolError {
+    /// Frame is not allowed in this state.
+    Unexpected { state: State, frame: Frame },
+    /// Response frame without a matching pending call.
+    Unsolicited(Frame),
+    /// Frame sent after its peer closed its side.
+    Closed(Frame),
+    /// Server closed the stream while calls are pending.
+    Unanswered(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unexpected { state, frame } =>
+                write!(f, "unexpected frame {:?} in state {:?}", frame, state),
+            Self::Unsolicited(frame) => write!(f, "unsolicited frame {:?}", frame),
+            Self::Closed(frame) => write!(f, "frame {:?} sent after closing", frame),
+            Self::Unanswered(count) => write!(f, "stream closed with {} unanswered calls", count),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Self {
+        ErrorKind::InvalidData.error(err.to_string())
+    }
+}
+
+
+/// State machine of a stream's protocol.
+#[derive(Clone,Debug)]
+pub struct Protocol {
+    state: State,
+    authenticated: bool,
+    /// Calls waiting for a single response.
+    single: usize,
+    /// Calls waiting for a responses stream.
+    streams: usize,
+    /// Server is sending a responses stream.
+    responding: bool,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol {
+    /// Protocol of a stream not yet opened.
+    pub fn new() -> Self {
+        Self { state: State::Start, authenticated: false, single: 0, streams: 0, responding: false }
+    }
+
+    /// Protocol of a stream whose hello has been exchanged.
+    pub fn opened() -> Self {
+        Self { state: State::Open, ..Self::new() }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Return true once the client has been authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Count of calls waiting for their response(s).
+    pub fn pending(&self) -> usize {
+        self.single + self.streams + self.responding as usize
+    }
+
+    /// Handle frame sent by either peer, returning the new state. On error,
+    /// the stream is closed.
+    pub fn next(&mut self, frame: Frame) -> Result<State, ProtocolError> {
+        match self.transition(frame) {
+            Ok(()) => Ok(self.state),
+            Err(err) => {
+                self.state = State::Closed;
+                Err(err)
+            }
+        }
+    }
+
+    fn transition(&mut self, frame: Frame) -> Result<(), ProtocolError> {
+        let unexpected = ProtocolError::Unexpected { state: self.state, frame };
+        match (self.state, frame) {
+            (State::Closed, _) => return Err(ProtocolError::Closed(frame)),
+            (State::Start, Frame::Hello) => self.state = State::Open,
+            (State::Start, _) | (_, Frame::Hello) => return Err(unexpected),
+            (State::HalfClosed, frame) if frame.sender() == Peer::Client =>
+                return Err(ProtocolError::Closed(frame)),
+
+            // client frames
+            (State::Open, Frame::AuthRequest) if self.pending() == 0 =>
+                self.state = State::Auth(AuthStep::Challenge),
+            (State::Auth(AuthStep::Response), Frame::AuthResponse) =>
+                self.state = State::Auth(AuthStep::Result),
+            (State::Open, Frame::Resume) if self.pending() == 0 =>
+                self.state = State::Auth(AuthStep::Result),
+            (State::Open, Frame::Request(call)) => {
+                match call.reply {
+                    Reply::None => (),
+                    Reply::Single => self.single += 1,
+                    Reply::Stream => self.streams += 1,
+                }
+                if call.incoming {
+                    self.state = State::Incoming;
+                }
+            },
+            (State::Incoming, Frame::RequestChunk) => (),
+            (State::Incoming, Frame::RequestEnd) => self.state = State::Open,
+            (State::Open, Frame::Close(Peer::Client)) => self.state = State::HalfClosed,
+
+            // server frames
+            (State::Auth(AuthStep::Challenge), Frame::AuthChallenge(ok)) =>
+                self.state = match ok {
+                    true => State::Auth(AuthStep::Response),
+                    false => State::Open,
+                },
+            (State::Auth(AuthStep::Result), Frame::AuthResult(ok)) => {
+                self.authenticated = ok;
+                self.state = State::Open;
+            },
+            (State::Auth(_), _) => return Err(unexpected),
+            (_, Frame::Response | Frame::Denied) if self.responding => return Err(unexpected),
+            (_, Frame::Response) => match self.single {
+                0 => return Err(ProtocolError::Unsolicited(frame)),
+                _ => self.single -= 1,
+            },
+            // denial of a notification is not counted
+            (_, Frame::Denied) => match (self.single, self.streams) {
+                (0, 0) => (),
+                (0, _) => self.streams -= 1,
+                _ => self.single -= 1,
+            },
+            (_, Frame::ResponseChunk | Frame::ResponseEnd) if !self.responding => {
+                if self.streams == 0 {
+                    return Err(ProtocolError::Unsolicited(frame))
+                }
+                self.streams -= 1;
+                self.responding = frame == Frame::ResponseChunk;
+            },
+            (_, Frame::ResponseChunk) => (),
+            (_, Frame::ResponseEnd) => self.responding = false,
+            (_, Frame::Close(Peer::Server)) => match self.pending() {
+                0 => self.state = State::Closed,
+                count => return Err(ProtocolError::Unanswered(count)),
+            },
+            _ => return Err(unexpected),
+        }
+        Ok(())
+    }
+}
+
+
+/// Error of a `Checked` transport's sink.
+#[derive(Debug)]
+pub enum CheckedError<E> {
+    /// Inner transport's error.
+    Transport(E),
+    /// Sent message violates the protocol.
+    Protocol(ProtocolError),
+}
+
+
+/// Transport checking exchanged messages against the protocol, on behalf of
+/// one of the peers.
+///
+/// Messages are classified into frames by provided functions, unclassified
+/// ones not being checked. Once the peer violates the protocol, received
+/// messages' stream ends; sending an illegal message fails.
+pub struct Checked<T, In, Out> {
+    inner: T,
+    peer: Peer,
+    protocol: Protocol,
+    error: Option<ProtocolError>,
+    received: fn(&In) -> Option<Frame>,
+    sent: fn(&Out) -> Option<Frame>,
+    phantom: PhantomData<fn(Out) -> In>,
+}
+
+impl<T, In, Out> Checked<T, In, Out> {
+    /// Check messages of an opened stream for provided peer.
+    pub fn new(inner: T, peer: Peer, received: fn(&In) -> Option<Frame>,
+               sent: fn(&Out) -> Option<Frame>) -> Self
+    {
+        Self { inner, peer, protocol: Protocol::opened(), error: None, received, sent,
+               phantom: PhantomData }
+    }
+
+    /// Stream's protocol.
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    /// Protocol violation, if any.
+    pub fn error(&self) -> Option<ProtocolError> {
+        self.error
+    }
+
+    /// Return inner transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn check(&mut self, frame: Option<Frame>) -> Result<(), ProtocolError> {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        self.protocol.next(frame).map(|_| ()).inspect_err(|err| {
+            self.error.get_or_insert(*err);
+        })
+    }
+}
+
+impl<T: Unpin, In, Out> Unpin for Checked<T, In, Out> {}
+
+impl<T, In, Out> Stream for Checked<T, In, Out>
+    where T: Stream<Item=In>+Unpin
+{
+    type Item = In;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<In>> {
+        if self.error.is_some() {
+            return Poll::Ready(None)
+        }
+        let this = self.as_mut().get_mut();
+        match futures::ready!(this.inner.poll_next_unpin(cx)) {
+            Some(item) => {
+                let frame = (this.received)(&item);
+                Poll::Ready(this.check(frame).ok().map(|_| item))
+            },
+            None => {
+                // closing a stream that already is is not an error
+                if this.protocol.state() != State::Closed {
+                    this.check(Some(Frame::Close(this.peer.other()))).ok();
+                }
+                Poll::Ready(None)
+            },
+        }
+    }
+}
+
+impl<T, In, Out> Sink<Out> for Checked<T, In, Out>
+    where T: Sink<Out>+Unpin
+{
+    type Error = CheckedError<T::Error>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx).map_err(CheckedError::Transport)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let frame = (self.sent)(&item);
+        self.check(frame).map_err(CheckedError::Protocol)?;
+        self.inner.start_send_unpin(item).map_err(CheckedError::Transport)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx).map_err(CheckedError::Transport)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.inner.poll_close_unpin(cx)).map_err(CheckedError::Transport)?;
+        let peer = self.peer;
+        Poll::Ready(self.check(Some(Frame::Close(peer))).map_err(CheckedError::Protocol))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(frames: &[Frame]) -> Result<Protocol, ProtocolError> {
+        let mut protocol = Protocol::new();
+        for frame in frames {
+            protocol.next(*frame)?;
+        }
+        Ok(protocol)
+    }
+
+    #[test]
+    fn test_calls() {
+        use Frame::*;
+        let protocol = run(&[Hello, Request(Call::UNARY), Request(Call::STREAMING),
+                             Request(Call::NOTIFY), ResponseChunk, ResponseChunk, ResponseEnd,
+                             Response, Close(Peer::Client)]).unwrap();
+        assert_eq!((protocol.state(), protocol.pending()), (State::HalfClosed, 0));
+
+        // client-streaming and empty responses stream
+        let protocol = run(&[Hello, Request(Call::incoming(Reply::Stream)), RequestChunk,
+                             RequestChunk, RequestEnd, ResponseEnd, Request(Call::UNARY),
+                             Denied, Close(Peer::Client), Close(Peer::Server)]).unwrap();
+        assert_eq!((protocol.state(), protocol.pending()), (State::Closed, 0));
+    }
+
+    #[test]
+    fn test_auth() {
+        use Frame::*;
+        let protocol = run(&[Hello, AuthRequest, AuthChallenge(false), AuthRequest,
+                             AuthChallenge(true), AuthResponse, AuthResult(true)]).unwrap();
+        assert!(protocol.is_authenticated());
+        assert_eq!(protocol.state(), State::Open);
+
+        assert_eq!(run(&[Hello, AuthRequest, Request(Call::UNARY)]).unwrap_err(),
+                   ProtocolError::Unexpected { state: State::Auth(AuthStep::Challenge),
+                                               frame: Request(Call::UNARY) });
+        assert_eq!(run(&[Hello, AuthRequest, AuthChallenge(true), AuthResult(true)]).unwrap_err(),
+                   ProtocolError::Unexpected { state: State::Auth(AuthStep::Response),
+                                               frame: AuthResult(true) });
+        // no authentication while calls are pending
+        assert_eq!(run(&[Hello, Request(Call::UNARY), AuthRequest]).unwrap_err(),
+                   ProtocolError::Unexpected { state: State::Open, frame: AuthRequest });
+
+        // resumption
+        let protocol = run(&[Hello, Resume, AuthResult(true)]).unwrap();
+        assert!(protocol.is_authenticated());
+        assert_eq!(run(&[Hello, Resume, Request(Call::UNARY)]).unwrap_err(),
+                   ProtocolError::Unexpected { state: State::Auth(AuthStep::Result),
+                                               frame: Request(Call::UNARY) });
+    }
+
+    #[test]
+    fn test_illegal_sequences() {
+        use Frame::*;
+        let open = State::Open;
+        let cases: &[(&[Frame], ProtocolError)] = &[
+            (&[Request(Call::UNARY)],
+             ProtocolError::Unexpected { state: State::Start, frame: Request(Call::UNARY) }),
+            (&[Hello, Hello], ProtocolError::Unexpected { state: open, frame: Hello }),
+            (&[Hello, Response], ProtocolError::Unsolicited(Response)),
+            (&[Hello, Request(Call::UNARY), ResponseChunk], ProtocolError::Unsolicited(ResponseChunk)),
+            (&[Hello, Request(Call::STREAMING), Response], ProtocolError::Unsolicited(Response)),
+            (&[Hello, Request(Call::STREAMING), Request(Call::UNARY), ResponseChunk, Response],
+             ProtocolError::Unexpected { state: open, frame: Response }),
+            (&[Hello, RequestChunk], ProtocolError::Unexpected { state: open, frame: RequestChunk }),
+            (&[Hello, Request(Call::incoming(Reply::Single)), Request(Call::UNARY)],
+             ProtocolError::Unexpected { state: State::Incoming, frame: Request(Call::UNARY) }),
+            (&[Hello, Request(Call::incoming(Reply::None)), Close(Peer::Client)],
+             ProtocolError::Unexpected { state: State::Incoming, frame: Close(Peer::Client) }),
+            (&[Hello, Close(Peer::Client), Request(Call::UNARY)],
+             ProtocolError::Closed(Request(Call::UNARY))),
+            (&[Hello, Request(Call::UNARY), Close(Peer::Client), Close(Peer::Server)],
+             ProtocolError::Unanswered(1)),
+            (&[Hello, Close(Peer::Server), Response], ProtocolError::Closed(Response)),
+        ];
+        for (frames, error) in cases {
+            assert_eq!(run(frames).unwrap_err(), *error, "frames: {:?}", frames);
+        }
+
+        // stream is closed on error
+        let mut protocol = Protocol::opened();
+        assert!(protocol.next(Response).is_err());
+        assert_eq!(protocol.next(Request(Call::UNARY)), Err(ProtocolError::Closed(Request(Call::UNARY))));
+    }
+
+    #[test]
+    fn test_service_frames() {
+        use crate::rpc::service::Service as _;
+        use crate::rpc::service::tests::{incoming_service, simple_service, streaming_service};
+        use Frame::*;
+
+        type Simple = simple_service::Service;
+        assert_eq!(Simple::request_frame(&simple_service::Request::Add(1)), Some(Request(Call::UNARY)));
+        assert_eq!(Simple::request_frame(&simple_service::Request::Clear()), Some(Request(Call::NOTIFY)));
+        assert_eq!(Simple::response_frame(&simple_service::Response::Add(1)), Some(Response));
+        assert_eq!(Simple::response_frame(&simple_service::Response::__Denied(None, String::new())),
+                   Some(Denied));
+
+        type Streams = streaming_service::Service;
+        assert_eq!(Streams::request_frame(&streaming_service::Request::Count(2)),
+                   Some(Request(Call::STREAMING)));
+        assert_eq!(Streams::response_frame(&streaming_service::Response::CountChunk(1)),
+                   Some(ResponseChunk));
+        assert_eq!(Streams::response_frame(&streaming_service::Response::CountEnd), Some(ResponseEnd));
+
+        type Incoming = incoming_service::Service;
+        assert_eq!(Incoming::request_frame(&incoming_service::Request::Sum(0)),
+                   Some(Request(Call::incoming(Reply::Single))));
+        assert_eq!(Incoming::request_frame(&incoming_service::Request::Double()),
+                   Some(Request(Call::incoming(Reply::Stream))));
+        assert_eq!(Incoming::request_frame(&incoming_service::Request::SumChunk(1)), Some(RequestChunk));
+        assert_eq!(Incoming::request_frame(&incoming_service::Request::SumEnd), Some(RequestEnd));
+    }
+
+    #[test]
+    fn test_checked() {
+        use futures::executor::block_on;
+        use crate::rpc::service::Service as _;
+        use crate::rpc::service::tests::incoming_service::{Request, Response, Service};
+        use crate::rpc::transport::MPSCTransport;
+
+        let (_server, client) = MPSCTransport::<Response, Request>::bi(8);
+        let mut client = Checked::new(client, Peer::Client, Service::response_frame,
+                                      Service::request_frame);
+        block_on(async {
+            // chunks can only be sent by client-streaming calls
+            assert!(matches!(client.send(Request::SumChunk(1)).await,
+                             Err(CheckedError::Protocol(ProtocolError::Unexpected { .. }))));
+        });
+
+        let (mut server, client) = MPSCTransport::<Response, Request>::bi(8);
+        let mut client = Checked::new(client, Peer::Client, Service::response_frame,
+                                      Service::request_frame);
+        block_on(async {
+            client.send(Request::Total()).await.unwrap();
+            server.send(Response::Total(0)).await.unwrap();
+            server.send(Response::Total(0)).await.unwrap();
+            assert!(matches!(client.next().await, Some(Response::Total(0))));
+            // unsolicited response ends the stream
+            assert!(client.next().await.is_none());
+            assert_eq!(client.error(), Some(ProtocolError::Unsolicited(Frame::Response)));
+        });
+    }
+}
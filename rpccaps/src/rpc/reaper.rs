@@ -0,0 +1,158 @@
+//! Periodic cleanup of expired state kept by long-running servers.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::data::{Clock, SystemClock};
+
+
+/// State from which expired entries can be reclaimed.
+pub trait Reap: Send+Sync {
+    /// Name used in reaper's statistics.
+    fn name(&self) -> &str;
+
+    /// Remove entries expired at `now` (since UNIX epoch), returning the
+    /// count of reclaimed ones.
+    fn reap(&self, now: Duration) -> usize;
+}
+
+
+/// Reaper's statistics.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ReaperStats {
+    /// Count of cleanup runs.
+    pub runs: u64,
+    /// Total count of reclaimed entries.
+    pub reclaimed: u64,
+    /// Count of reclaimed entries by target's name.
+    pub targets: BTreeMap<String, u64>,
+}
+
+
+/// Reclaim expired entries of registered targets every `interval`.
+pub struct Reaper<C=SystemClock> {
+    interval: Duration,
+    clock: C,
+    targets: Mutex<Vec<Arc<dyn Reap>>>,
+    stats: Mutex<ReaperStats>,
+}
+
+impl Reaper {
+    pub fn new(interval: Duration) -> Self {
+        Self::with_clock(interval, SystemClock)
+    }
+}
+
+impl<C: Clock> Reaper<C> {
+    /// Create reaper using provided clock.
+    pub fn with_clock(interval: Duration, clock: C) -> Self {
+        Self { interval, clock, targets: Mutex::new(Vec::new()),
+               stats: Mutex::new(ReaperStats::default()) }
+    }
+
+    /// Interval between cleanups.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Register target to clean up.
+    pub fn add(&self, target: Arc<dyn Reap>) {
+        self.targets.lock().unwrap().push(target);
+    }
+
+    /// Return statistics about reclaimed entries.
+    pub fn stats(&self) -> ReaperStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Clean up targets once, returning count of reclaimed entries.
+    pub fn reap(&self) -> usize {
+        let now = self.clock.now();
+        let targets = self.targets.lock().unwrap().clone();
+        let reclaimed = targets.iter().map(|target| (target.name().to_string(), target.reap(now)))
+                               .collect::<Vec<_>>();
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.runs += 1;
+        let mut total = 0;
+        for (name, count) in reclaimed {
+            *stats.targets.entry(name).or_default() += count as u64;
+            total += count;
+        }
+        stats.reclaimed += total as u64;
+        total
+    }
+}
+
+impl<C: 'static+Clock> Reaper<C> {
+    /// Clean up targets every interval, forever. Requires a tokio runtime.
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            self.reap();
+        }
+    }
+
+    /// Spawn reaper's loop on the current tokio runtime.
+    pub fn spawn(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::data::clock::MockClock;
+
+    /// Entries expiring at their value.
+    struct Entries(Mutex<Vec<Duration>>, AtomicUsize);
+
+    impl Reap for Entries {
+        fn name(&self) -> &str {
+            "entries"
+        }
+
+        fn reap(&self, now: Duration) -> usize {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            let mut entries = self.0.lock().unwrap();
+            let len = entries.len();
+            entries.retain(|expires| *expires > now);
+            len - entries.len()
+        }
+    }
+
+    #[test]
+    fn test_reaper() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let reaper = Reaper::with_clock(Duration::from_secs(1), clock.clone());
+        let secs = |values: &[u64]| values.iter().map(|v| Duration::from_secs(*v)).collect();
+        let entries = Arc::new(Entries(Mutex::new(secs(&[5, 15, 25])), AtomicUsize::new(0)));
+        reaper.add(entries.clone());
+
+        assert_eq!(reaper.reap(), 1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(reaper.reap(), 1);
+        assert_eq!(reaper.reap(), 0);
+
+        let stats = reaper.stats();
+        assert_eq!((stats.runs, stats.reclaimed), (3, 2));
+        assert_eq!(stats.targets.get("entries"), Some(&2));
+        assert_eq!(entries.1.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_reaper_run() {
+        let reaper = Arc::new(Reaper::new(Duration::from_millis(10)));
+        reaper.add(Arc::new(Entries(Mutex::new(Vec::new()), AtomicUsize::new(0))));
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let handle = reaper.clone().spawn();
+            tokio::time::sleep(Duration::from_millis(55)).await;
+            handle.abort();
+        });
+        assert!(reaper.stats().runs >= 2);
+    }
+}
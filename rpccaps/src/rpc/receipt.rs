@@ -0,0 +1,501 @@
+//! Counter-signed receipts of completed calls.
+//!
+//! A service wrapped into `Signed` returns each of its responses along with
+//! a `Receipt`: digests of the request and the response, and a timestamp,
+//! signed by the server's identity. Receipts are non-repudiable evidence
+//! that an operation has been performed by the server.
+//!
+//! On the client side, `Verified` wraps the service's transport: receipts
+//! are verified against the server's public key, then kept in `Receipts`.
+//! Responses with a missing or invalid receipt end the transport (calls
+//! fail), except for refusals (denials and slow downs), since the call has
+//! not been performed. Errors, including the ones of methods, require a
+//! receipt.
+//!
+//! ```ignore
+//! server.dispatch.add_builder(0, Box::new(move |_| {
+//!     Signed::<_, Dalek>::new(storage::Service::new(), signer.clone())
+//! }), HandlerOptions::default())?;
+//!
+//! let receipts = Receipts::default();
+//! let transport = Verified::<_, storage::Service, Dalek>::new(transport, issuer, receipts.clone());
+//! let mut client = storage::Client::new(transport);
+//! ```
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use serde::{Serialize, Deserialize};
+
+use crate::{ErrorKind, Result};
+use crate::data::{bytes, canonical, hash, Clock, SystemClock};
+use crate::data::signature::{self as sign, SignMethod};
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Hash of a message serialized using `canonical` profile.
+pub type Digest = hash::Hash;
+
+/// Number of sent requests' digests kept by `Verified` in order to match
+/// receipts against.
+const SENT_WINDOW: usize = 256;
+
+
+/// Return message's digest.
+pub fn digest<T: Serialize>(value: &T) -> Result<Digest> {
+    let data = canonical::serialize(value)
+        .or_else(|err| ErrorKind::Codec.err(err.to_string()))?;
+    Ok(hash::digest(&data))
+}
+
+
+/// Data signed by a receipt, serialized using `canonical` profile.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub struct ReceiptData {
+    /// Request's digest.
+    pub request: Digest,
+    /// Response's digest.
+    pub response: Digest,
+    /// Timestamp (in milliseconds) of the response.
+    pub timestamp: u64,
+}
+
+
+/// Server's signature of a call.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct Receipt {
+    pub data: ReceiptData,
+    #[serde(with="bytes")]
+    pub signature: sign::Signature,
+}
+
+impl Receipt {
+    /// Sign receipt data using provided signer.
+    pub fn sign<S: sign::Signer>(signer: &S, data: ReceiptData) -> Result<Self> {
+        let buf = canonical::serialize(&data)
+            .or_else(|err| ErrorKind::Codec.err(err.to_string()))?;
+        let signature = signer.try_sign(&buf)
+            .or_else(|err| ErrorKind::Certificate.err(err.to_string()))?;
+        Ok(Self { data, signature })
+    }
+
+    /// Verify receipt's signature by `issuer`.
+    pub fn verify<V: sign::Verifier>(&self, issuer: &V) -> Result<()> {
+        let buf = canonical::serialize(&self.data)
+            .or_else(|err| ErrorKind::Codec.err(err.to_string()))?;
+        issuer.verify(&buf, &self.signature)
+            .or_else(|err| ErrorKind::Certificate.err(err.to_string()))
+    }
+
+    /// Verify receipt's signature by `issuer`, and that it has been issued
+    /// for the provided request and response.
+    pub fn verify_call<V, Req, Resp>(&self, issuer: &V, request: &Req, response: &Resp) -> Result<()>
+        where V: sign::Verifier, Req: Serialize, Resp: Serialize
+    {
+        self.verify(issuer)?;
+        if self.data.request != digest(request)? || self.data.response != digest(response)? {
+            return ErrorKind::InvalidData.err("receipt is not issued for this call")
+        }
+        Ok(())
+    }
+}
+
+
+/// Response along with its receipt.
+#[derive(Serialize,Deserialize,Clone)]
+pub struct Receipted<T> {
+    pub response: T,
+    /// Receipt, if any: denials are not signed.
+    pub receipt: Option<Receipt>,
+}
+
+
+/// Receipt issuer, shared among a service's responses streams.
+struct Issuer<Sign: SignMethod, C> {
+    signer: Arc<Sign::Signer>,
+    clock: C,
+}
+
+impl<Sign: SignMethod, C: Clock+Clone> Clone for Issuer<Sign, C> {
+    fn clone(&self) -> Self {
+        Self { signer: self.signer.clone(), clock: self.clock.clone() }
+    }
+}
+
+impl<Sign: SignMethod, C: Clock> Issuer<Sign, C> {
+    /// Return response with its receipt, not signed when it can't be done.
+    fn receipted<T: Serialize>(&self, request: Option<Digest>, response: T) -> Receipted<T> {
+        let timestamp = self.clock.now().as_millis() as u64;
+        let receipt = request.and_then(|request| {
+            let data = ReceiptData { request, response: digest(&response).ok()?, timestamp };
+            Receipt::sign(self.signer.as_ref(), data).ok()
+        });
+        Receipted { response, receipt }
+    }
+}
+
+
+/// Service returning a receipt with each of its responses.
+pub struct Signed<S: Service, Sign: SignMethod, C=SystemClock> {
+    inner: S,
+    issuer: Issuer<Sign, C>,
+}
+
+impl<S: Service, Sign: SignMethod> Signed<S, Sign> {
+    pub fn new(inner: S, signer: Arc<Sign::Signer>) -> Self {
+        Self::with_clock(inner, signer, SystemClock)
+    }
+}
+
+impl<S: Service, Sign: SignMethod, C: Clock> Signed<S, Sign, C> {
+    /// Create a new signed service, timestamping receipts with `clock`.
+    pub fn with_clock(inner: S, signer: Arc<Sign::Signer>, clock: C) -> Self {
+        Self { inner, issuer: Issuer { signer, clock } }
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Service+Clone, Sign: SignMethod, C: Clock+Clone> Clone for Signed<S, Sign, C> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), issuer: self.issuer.clone() }
+    }
+}
+
+#[async_trait]
+impl<S, Sign, C> Service for Signed<S, Sign, C>
+    where S: Service, S::Request: Serialize, S::Response: 'static+Serialize,
+          Sign: 'static+SignMethod+Send+Sync+Unpin,
+          Sign::Signer: Send+Sync,
+          C: 'static+Clock+Clone+Unpin
+{
+    type Request = S::Request;
+    type Response = Receipted<S::Response>;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(&response.response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(&response.response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason).map(|response| Receipted { response, receipt: None })
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after).map(|response| Receipted { response, receipt: None })
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error).map(|response| Receipted { response, receipt: None })
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        let Receipted { response, receipt } = response;
+        S::downgrade(response, version).map(|response| Receipted { response, receipt })
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(&response.response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let request_digest = digest(&request).ok();
+        let response = self.inner.dispatch(request).await?;
+        Some(self.issuer.receipted(request_digest, response))
+    }
+
+    /// Each item of the stream is signed for the request.
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> std::result::Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let request_digest = digest(&request).ok();
+        let issuer = self.issuer.clone();
+        let responses = self.inner.dispatch_streaming(request).await?;
+        Ok(responses.map(move |response| issuer.receipted(request_digest, response)).boxed())
+    }
+
+    /// Responses are signed for the request opening the call, not for the
+    /// streamed items.
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> std::result::Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let request_digest = digest(&request).ok();
+        let issuer = self.issuer.clone();
+        let responses = self.inner.dispatch_incoming(request, requests).await?;
+        Ok(responses.map(move |response| issuer.receipted(request_digest, response)).boxed())
+    }
+}
+
+
+/// Verified receipts, shared between a client and its owner.
+#[derive(Clone,Default)]
+pub struct Receipts(Arc<Mutex<Vec<Receipt>>>);
+
+impl Receipts {
+    pub fn push(&self, receipt: Receipt) {
+        self.0.lock().unwrap().push(receipt)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return stored receipts.
+    pub fn to_vec(&self) -> Vec<Receipt> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Remove and return stored receipts.
+    pub fn take(&self) -> Vec<Receipt> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+
+/// Client transport verifying receipts of the service `S`'s responses.
+pub struct Verified<T, S: Service, Sign: SignMethod> {
+    transport: T,
+    issuer: Sign::Verifier,
+    receipts: Receipts,
+    /// Digests of the last sent requests.
+    sent: VecDeque<Digest>,
+    failed: bool,
+    phantom: PhantomData<fn(S)>,
+}
+
+impl<T, S: Service, Sign: SignMethod> Verified<T, S, Sign> {
+    /// Verify responses' receipts against `issuer`, the server's public
+    /// key, storing them into `receipts`.
+    pub fn new(transport: T, issuer: Sign::Verifier, receipts: Receipts) -> Self {
+        Self { transport, issuer, receipts, sent: VecDeque::new(), failed: false, phantom: PhantomData }
+    }
+
+    /// Stored receipts.
+    pub fn receipts(&self) -> &Receipts {
+        &self.receipts
+    }
+
+    /// Return inner transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Verify response's receipt, returning it if valid.
+    fn verify(&self, response: &Receipted<S::Response>) -> Option<Receipt>
+        where S::Response: Serialize
+    {
+        let receipt = response.receipt.as_ref()?;
+        receipt.verify(&self.issuer).ok()?;
+        let valid = self.sent.contains(&receipt.data.request)
+                    && digest(&response.response).ok()? == receipt.data.response;
+        valid.then(|| receipt.clone())
+    }
+}
+
+impl<T, S: Service, Sign: SignMethod> Unpin for Verified<T, S, Sign> where T: Unpin {}
+
+impl<T, S, Sign> Stream for Verified<T, S, Sign>
+    where T: Stream<Item=Receipted<S::Response>>+Unpin,
+          S: Service, S::Response: Serialize,
+          Sign: SignMethod
+{
+    type Item = S::Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.failed {
+            return Poll::Ready(None)
+        }
+        let response = match futures::ready!(self.transport.poll_next_unpin(cx)) {
+            Some(response) => response,
+            None => return Poll::Ready(None),
+        };
+        match self.verify(&response) {
+            Some(receipt) => self.receipts.push(receipt),
+            None if response.receipt.is_none() && S::is_refused(&response.response) => (),
+            None => {
+                self.failed = true;
+                return Poll::Ready(None)
+            },
+        }
+        Poll::Ready(Some(response.response))
+    }
+}
+
+impl<T, S, Sign> Sink<S::Request> for Verified<T, S, Sign>
+    where T: Sink<S::Request>+Unpin,
+          S: Service, S::Request: Serialize,
+          Sign: SignMethod
+{
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: S::Request) -> std::result::Result<(), Self::Error> {
+        if let Ok(digest) = digest(&item) {
+            if self.sent.len() >= SENT_WINDOW {
+                self.sent.pop_front();
+            }
+            self.sent.push_back(digest);
+        }
+        self.transport.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.transport.poll_close_unpin(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::data::clock::MockClock;
+    use crate::data::signature::Dalek;
+    use crate::rpc::call::CallError;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    type TestSigned = Signed<simple_service::Service, Dalek, Arc<MockClock>>;
+
+    #[test]
+    fn test_receipt() {
+        let signer = Arc::new(Dalek::generate().unwrap());
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let mut service = TestSigned::with_clock(simple_service::Service::new(), signer.clone(), clock);
+        let response = futures::executor::block_on(service.dispatch(simple_service::Request::Add(2)))
+                            .unwrap();
+        let receipt = response.receipt.clone().unwrap();
+        assert_eq!(receipt.data.timestamp, 10_000);
+        receipt.verify_call(&signer.public, &simple_service::Request::Add(2), &response.response)
+               .unwrap();
+
+        // receipt does not prove another call
+        assert!(receipt.verify_call(&signer.public, &simple_service::Request::Add(3),
+                                    &response.response).is_err());
+        let mut forged = receipt.clone();
+        forged.data.timestamp += 1;
+        assert_eq!(forged.verify(&signer.public).unwrap_err().kind(), ErrorKind::Certificate);
+        let other = Dalek::generate().unwrap();
+        assert!(receipt.verify(&other.public).is_err());
+    }
+
+    #[test]
+    fn test_verified() {
+        let signer = Arc::new(Dalek::generate().unwrap());
+        let other = Dalek::generate().unwrap();
+        let run = |issuer| {
+            let (server_transport, client_transport) =
+                MPSCTransport::<Receipted<simple_service::Response>, simple_service::Request>::bi(8);
+            let receipts = Receipts::default();
+            let client_fut = {
+                let receipts = receipts.clone();
+                async move {
+                    let transport = Verified::<_, simple_service::Service, Dalek>::new(
+                        client_transport, issuer, receipts);
+                    let mut client = simple_service::Client::new(transport);
+                    (client.add(2).await, client.add(3).await)
+                }
+            };
+            let server_fut = {
+                let signer = signer.clone();
+                async move {
+                    let (s,r) = server_transport.split();
+                    Signed::<_, Dalek>::new(simple_service::Service::new(), signer)
+                        .serve(Transport::new(s, r)).await;
+                }
+            };
+            let (results, _) = LocalPool::new().run_until(join(client_fut, server_fut));
+            (results, receipts.take())
+        };
+
+        let (results, receipts) = run(signer.public);
+        assert_eq!(results, (Ok(2), Ok(5)));
+        assert_eq!(receipts.len(), 2);
+        receipts[1].verify_call(&signer.public, &simple_service::Request::Add(3),
+                                &simple_service::Response::Add(5)).unwrap();
+
+        // receipts not signed by the expected server are rejected
+        let (results, receipts) = run(other.public);
+        assert_eq!(results, (Err(CallError::Failed), Err(CallError::Failed)));
+        assert!(receipts.is_empty());
+    }
+
+    #[test]
+    fn test_unsigned() {
+        use crate::rpc::message::{Error, SlowDown};
+        use simple_service::Response;
+
+        let signer = Dalek::generate().unwrap();
+        let (mut server_transport, client_transport) =
+            MPSCTransport::<Receipted<Response>, simple_service::Request>::bi(8);
+        let mut transport = Verified::<_, simple_service::Service, Dalek>::new(
+            client_transport, signer.public, Receipts::default());
+        futures::executor::block_on(async {
+            for response in [Response::__Denied(None, String::new()), Response::__SlowDown(SlowDown { retry_after: Duration::ZERO }),
+                             Response::__Error(None, Error::method(&0u32))]
+            {
+                server_transport.send(Receipted { response, receipt: None }).await.unwrap();
+            }
+            // refusals are not performed calls, method errors require a receipt
+            assert!(matches!(transport.next().await, Some(Response::__Denied(..))));
+            assert!(matches!(transport.next().await, Some(Response::__SlowDown(_))));
+            assert!(transport.next().await.is_none());
+        });
+    }
+}
@@ -0,0 +1,159 @@
+//! Push of capability revocations to connected peers.
+//!
+//! Server keeps track of the references held by each connection. When one
+//! is revoked, a `Control::Revoked` message is pushed to the connections
+//! holding it, on a control stream opened by the server, so that clients
+//! can invalidate it immediately instead of at their next failed call.
+//!
+//! References are held by a connection once it presented them (see
+//! `Context::hold()`). Revocations are kept until the revoked reference
+//! expires, then reclaimed as a `Reap` target: references without
+//! expiration are kept revoked for the server's lifetime.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use serde::Serialize;
+
+use crate::{ErrorKind, Result};
+use crate::data::{ObjectId, Reference};
+use crate::data::signature::SignMethod;
+use super::admission::ConnectionId;
+use super::message::Control;
+use super::reaper::Reap;
+
+
+#[derive(Default)]
+struct State {
+    /// Revoked references, with their expiration timestamp (in seconds).
+    revoked: BTreeMap<ObjectId, Option<u64>>,
+    holders: BTreeMap<ObjectId, BTreeSet<ConnectionId>>,
+    connections: BTreeMap<ConnectionId, mpsc::UnboundedSender<Control>>,
+}
+
+
+/// Revoked references, and references held by connections.
+#[derive(Default)]
+pub struct Revocations {
+    state: Mutex<State>,
+}
+
+impl Revocations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register connection, returning the stream of control messages to
+    /// push to it.
+    pub fn connect(&self, connection: ConnectionId) -> mpsc::UnboundedReceiver<Control> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state.lock().unwrap().connections.insert(connection, sender);
+        receiver
+    }
+
+    /// Unregister connection and the references it holds.
+    pub fn disconnect(&self, connection: ConnectionId) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.remove(&connection);
+        state.holders.retain(|_, holders| {
+            holders.remove(&connection);
+            !holders.is_empty()
+        });
+    }
+
+    /// Return ids of registered connections.
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.state.lock().unwrap().connections.keys().cloned().collect()
+    }
+
+    /// Record that connection holds reference to the object `id`. Fail if
+    /// it has been revoked.
+    pub fn hold(&self, connection: ConnectionId, id: ObjectId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.revoked.contains_key(&id) {
+            return ErrorKind::InvalidData.err("reference has been revoked");
+        }
+        state.holders.entry(id).or_default().insert(connection);
+        Ok(())
+    }
+
+    /// Return true if reference to the object `id` has been revoked.
+    pub fn is_revoked(&self, id: &ObjectId) -> bool {
+        self.state.lock().unwrap().revoked.contains_key(id)
+    }
+
+    /// Revoke provided reference until it expires, notifying connections
+    /// holding it. Return the count of notified connections.
+    pub fn revoke_reference<Id,Sign>(&self, reference: &Reference<Id,Sign>) -> Result<usize>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        let id = reference.object_id().or(ErrorKind::InvalidData.err("invalid reference"))?;
+        let expires = reference.certs().iter().filter_map(|cert| cert.auth.expires).min();
+        Ok(self.revoke(id, expires))
+    }
+
+    /// Revoke reference to the object `id`, expiring at `expires`
+    /// (timestamp in seconds), notifying connections holding it. Return
+    /// the count of notified connections.
+    pub fn revoke(&self, id: ObjectId, expires: Option<u64>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.revoked.insert(id, expires);
+        let holders = state.holders.remove(&id).unwrap_or_default();
+        holders.iter().filter_map(|conn| state.connections.get(conn))
+               .filter(|sender| sender.unbounded_send(Control::Revoked(id)).is_ok())
+               .count()
+    }
+}
+
+impl Reap for Revocations {
+    fn name(&self) -> &str {
+        "revocations"
+    }
+
+    /// Remove revocations of expired references.
+    fn reap(&self, now: Duration) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let len = state.revoked.len();
+        state.revoked.retain(|_, expires| expires.is_none_or(|expires| expires > now.as_secs()));
+        len - state.revoked.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_revocations() {
+        let (a, b) = (ObjectId([1; 32]), ObjectId([2; 32]));
+        let revocations = Revocations::new();
+        let mut conn_1 = revocations.connect(1);
+        let mut conn_2 = revocations.connect(2);
+        revocations.hold(1, a).unwrap();
+        revocations.hold(2, a).unwrap();
+        revocations.hold(2, b).unwrap();
+
+        revocations.disconnect(1);
+        assert_eq!(revocations.revoke(a, Some(100)), 1);
+        assert!(revocations.is_revoked(&a) && !revocations.is_revoked(&b));
+        assert!(revocations.hold(2, a).is_err());
+        assert_eq!(revocations.revoke(a, Some(100)), 0);
+
+        // revocations are kept until the reference expires
+        assert_eq!(revocations.revoke(b, None), 1);
+        assert_eq!(revocations.reap(Duration::from_secs(99)), 0);
+        assert_eq!(revocations.reap(Duration::from_secs(100)), 1);
+        assert!(!revocations.is_revoked(&a) && revocations.is_revoked(&b));
+
+        drop(revocations);
+        assert_eq!(block_on(conn_2.next()), Some(Control::Revoked(a)));
+        assert_eq!(block_on(conn_2.next()), Some(Control::Revoked(b)));
+        assert_eq!(block_on(conn_2.next()), None);
+        assert_eq!(block_on(conn_1.next()), None);
+    }
+}
@@ -0,0 +1,345 @@
+//! Composition of services under a single dispatch id.
+//!
+//! A `Router` is a service dispatching requests to child services by the
+//! path of the request. Paths are dot-separated prefixes (e.g.
+//! `storage.bucket`): a router can be nested under another one, so that
+//! hierarchical APIs are composed of independent service implementations.
+//!
+//! Child requests and responses are carried bincode-encoded as payload of
+//! `RouterRequest` and `RouterResponse`, decoded with the default depth
+//! limit (see `data::depth`). On the client side, `Routed` wraps
+//! a router's transport into the one of a child service, to be used by its
+//! generated `Client`.
+//!
+//! Only request-response methods are routed: streaming methods are not.
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{ErrorKind, Result};
+use crate::data::depth;
+use super::codec;
+use super::service::Service;
+
+
+/// Separator of paths' segments.
+pub const SEPARATOR: char = '.';
+
+
+/// Request routed to the child service at `path`.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct RouterRequest {
+    pub path: String,
+    /// Encoded child's request.
+    pub payload: Vec<u8>,
+}
+
+impl RouterRequest {
+    /// Encode child's request routed to provided path.
+    pub fn encode(path: impl Into<String>, request: &impl Serialize) -> Result<Self> {
+        let payload = bincode::serialize(request)
+                        .or_else(|err| ErrorKind::Codec.err(err.to_string()))?;
+        Ok(Self { path: path.into(), payload })
+    }
+}
+
+
+/// Response of a routed request.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum RouterResponse {
+    /// Encoded child's response.
+    Response(Vec<u8>),
+    /// No service is mounted at provided path.
+    NotFound(String),
+    /// Request's payload can not be decoded.
+    InvalidData,
+}
+
+impl RouterResponse {
+    /// Decode child's response.
+    pub fn decode<T: DeserializeOwned>(self) -> Result<T> {
+        match self {
+            Self::Response(payload) => codec::deserialize(&payload, depth::DEFAULT_MAX_DEPTH)
+                                        .or_else(|err| ErrorKind::Codec.err(err.to_string())),
+            Self::NotFound(path) => ErrorKind::NotFound.err(format!("no service at `{}`", path)),
+            Self::InvalidData => ErrorKind::InvalidData.err("invalid request payload"),
+        }
+    }
+}
+
+
+/// Child of a router, handling requests at a path relative to its prefix.
+#[async_trait]
+trait Route: Send+Sync {
+    fn is_alive(&self) -> bool;
+
+    async fn route(&mut self, path: &str, payload: Vec<u8>) -> Option<RouterResponse>;
+}
+
+/// Service mounted on a router.
+struct Mounted<S: Service>(S);
+
+#[async_trait]
+impl<S> Route for Mounted<S>
+    where S: Service,
+          S::Request: DeserializeOwned,
+          S::Response: Serialize,
+{
+    fn is_alive(&self) -> bool {
+        self.0.is_alive()
+    }
+
+    async fn route(&mut self, path: &str, payload: Vec<u8>) -> Option<RouterResponse> {
+        if !path.is_empty() {
+            return Some(RouterResponse::NotFound(path.to_string()))
+        }
+        let request = match codec::deserialize(&payload, depth::DEFAULT_MAX_DEPTH) {
+            Ok(request) => request,
+            Err(_) => return Some(RouterResponse::InvalidData),
+        };
+        let response = self.0.dispatch(request).await?;
+        // responses that can't be encoded are not sent, as for other
+        // transports' encoding failures
+        bincode::serialize(&response).ok().map(RouterResponse::Response)
+    }
+}
+
+
+/// Service dispatching requests to services mounted by path prefix.
+#[derive(Default)]
+pub struct Router {
+    routes: BTreeMap<String, Box<dyn Route>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mount service at provided prefix.
+    pub fn mount<S>(&mut self, prefix: impl Into<String>, service: S) -> Result<()>
+        where S: Service+'static,
+              S::Request: DeserializeOwned,
+              S::Response: Serialize,
+    {
+        self.insert(prefix.into(), Box::new(Mounted(service)))
+    }
+
+    /// Nest router at provided prefix, its services' paths being prefixed
+    /// by it.
+    pub fn nest(&mut self, prefix: impl Into<String>, router: Router) -> Result<()> {
+        self.insert(prefix.into(), Box::new(router))
+    }
+
+    /// Remove child at prefix, returning true if there was one.
+    pub fn unmount(&mut self, prefix: &str) -> bool {
+        self.routes.remove(prefix).is_some()
+    }
+
+    /// Mounted prefixes.
+    pub fn prefixes(&self) -> impl Iterator<Item=&str> {
+        self.routes.keys().map(String::as_str)
+    }
+
+    fn insert(&mut self, prefix: String, route: Box<dyn Route>) -> Result<()> {
+        if prefix.is_empty() || prefix.contains(SEPARATOR) {
+            return ErrorKind::InvalidInput.err(format!("invalid prefix `{}`", prefix))
+        }
+        if self.routes.contains_key(&prefix) {
+            return ErrorKind::KeyError.err(format!("prefix `{}` already mounted", prefix))
+        }
+        self.routes.insert(prefix, route);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Route for Router {
+    fn is_alive(&self) -> bool {
+        self.routes.values().any(|route| route.is_alive())
+    }
+
+    async fn route(&mut self, path: &str, payload: Vec<u8>) -> Option<RouterResponse> {
+        let (prefix, rest) = path.split_once(SEPARATOR).unwrap_or((path, ""));
+        match self.routes.get_mut(prefix) {
+            Some(route) if route.is_alive() => route.route(rest, payload).await,
+            _ => Some(RouterResponse::NotFound(path.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl Service for Router {
+    type Request = RouterRequest;
+    type Response = RouterResponse;
+
+    /// Router is alive while one of its children is.
+    fn is_alive(&self) -> bool {
+        Route::is_alive(self)
+    }
+
+    async fn dispatch(&mut self, request: RouterRequest) -> Option<RouterResponse> {
+        match self.route(&request.path, request.payload).await {
+            Some(RouterResponse::NotFound(_)) => Some(RouterResponse::NotFound(request.path)),
+            response => response,
+        }
+    }
+}
+
+
+/// Transport of a child service's messages over a router's transport.
+///
+/// Responses that can not be decoded end the stream, as `Framed` does.
+pub struct Routed<T, Req, Resp> {
+    inner: T,
+    path: String,
+    ended: bool,
+    phantom: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<T, Req, Resp> Routed<T, Req, Resp> {
+    pub fn new(inner: T, path: impl Into<String>) -> Self {
+        Self { inner, path: path.into(), ended: false, phantom: PhantomData }
+    }
+
+    /// Routed requests' path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return inner transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Unpin, Req, Resp> Unpin for Routed<T, Req, Resp> {}
+
+impl<T, Req, Resp> Stream for Routed<T, Req, Resp>
+    where T: Stream<Item=RouterResponse>+Unpin,
+          Resp: DeserializeOwned,
+{
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Resp>> {
+        if self.ended {
+            return Poll::Ready(None)
+        }
+        let response = futures::ready!(self.inner.poll_next_unpin(cx));
+        match response.map(RouterResponse::decode) {
+            Some(Ok(response)) => Poll::Ready(Some(response)),
+            _ => {
+                self.ended = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl<T, Req, Resp> Sink<Req> for Routed<T, Req, Resp>
+    where T: Sink<RouterRequest>+Unpin,
+          T::Error: std::fmt::Display,
+          Req: Serialize,
+{
+    type Error = crate::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.inner.poll_ready_unpin(cx).map_err(|err| ErrorKind::IO.error(err.to_string()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Req) -> Result<()> {
+        let request = RouterRequest::encode(self.path.clone(), &item)?;
+        self.inner.start_send_unpin(request).map_err(|err| ErrorKind::IO.error(err.to_string()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.inner.poll_flush_unpin(cx).map_err(|err| ErrorKind::IO.error(err.to_string()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.inner.poll_close_unpin(cx).map_err(|err| ErrorKind::IO.error(err.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::{block_on, LocalPool};
+    use futures::future::join;
+
+    use super::*;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    fn router() -> Router {
+        let mut storage = Router::new();
+        storage.mount("bucket", simple_service::Service::new()).unwrap();
+        let mut router = Router::new();
+        router.mount("simple", simple_service::Service::new()).unwrap();
+        router.nest("storage", storage).unwrap();
+        router
+    }
+
+    fn call(router: &mut Router, path: &str, request: simple_service::Request)
+        -> Result<simple_service::Response>
+    {
+        let request = RouterRequest::encode(path, &request).unwrap();
+        block_on(router.dispatch(request)).unwrap().decode()
+    }
+
+    #[test]
+    fn test_mount() {
+        let mut router = router();
+        assert_eq!(router.mount("simple", simple_service::Service::new()).unwrap_err().kind(),
+                   ErrorKind::KeyError);
+        assert_eq!(router.mount("a.b", simple_service::Service::new()).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        assert_eq!(router.prefixes().collect::<Vec<_>>(), vec!["simple", "storage"]);
+        assert!(router.unmount("simple"));
+        assert!(!router.unmount("simple"));
+    }
+
+    #[test]
+    fn test_route() {
+        let mut router = router();
+        assert!(matches!(call(&mut router, "simple", simple_service::Request::Add(2)),
+                         Ok(simple_service::Response::Add(2))));
+        assert!(matches!(call(&mut router, "storage.bucket", simple_service::Request::Add(5)),
+                         Ok(simple_service::Response::Add(5))));
+        assert!(matches!(call(&mut router, "simple", simple_service::Request::Get()),
+                         Ok(simple_service::Response::Get(2))));
+
+        for path in ["unknown", "storage", "storage.unknown", "simple.add"] {
+            let request = RouterRequest::encode(path, &simple_service::Request::Get()).unwrap();
+            assert_eq!(block_on(router.dispatch(request)),
+                       Some(RouterResponse::NotFound(path.to_string())));
+        }
+
+        let request = RouterRequest { path: "simple".into(), payload: vec![0xff; 2] };
+        assert_eq!(block_on(router.dispatch(request)), Some(RouterResponse::InvalidData));
+    }
+
+    #[test]
+    fn test_routed_client() {
+        let (server_transport, client_transport) = MPSCTransport::<RouterResponse, RouterRequest>::bi(8);
+
+        let client_fut = async move {
+            let transport = Routed::new(client_transport, "storage.bucket");
+            let mut client = simple_service::Client::new(transport);
+            assert_eq!(client.add(3).await, Ok(3));
+            assert_eq!(client.sub(1).await, Ok(2));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            router().serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+}
@@ -0,0 +1,234 @@
+//! Schema of services' methods, validated by clients at connect time.
+//!
+//! Requests and responses are encoded by methods' index: a client built
+//! against another version of a service's `impl` (methods added in the
+//! middle, arguments changed...) would fail with garbled decode errors in
+//! the middle of a session. `#[service]` generates the signatures of the
+//! methods, by index, that the server returns to the implicit
+//! `__schema()` method. `Client::service()` validates it against the
+//! client's own schema when opening the stream, failing with a
+//! `SchemaMismatch` listing the methods that differ; generated clients can
+//! also check it with `validate_schema()`:
+//!
+//! ```ignore
+//! // fails if the server's `storage::Service` differs from the client's one
+//! let transport = client.service::<storage::Service, _>(STORAGE).await?;
+//! let mut service = storage::Client::new(transport);
+//! ```
+//!
+//! Implicit variants such as `__Schema` are encoded before methods' ones,
+//! so that servers decode the request whatever their methods.
+//!
+//! Signatures are the methods' tokens as written in the `impl`: arguments
+//! and outputs are compared by type name, not by serialized layout. A
+//! changed field of a type, or a type alias resolving to another type, is
+//! not detected, and must come with a renamed type or a new method.
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::data::canonical;
+use crate::data::hash::{self, Hash};
+use super::call::{with_timeout, CallError};
+use super::service::Service;
+
+
+/// Signatures of a service's methods, by index.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq,Eq)]
+pub struct Schema {
+    pub methods: Vec<String>,
+    /// Methods' stable ids, for services encoding messages with them (see
+    /// `rpc::stable`). Methods are identified by their index when empty.
+    pub ids: Vec<u32>,
+}
+
+impl Schema {
+    pub fn new<S: ToString>(methods: &[S]) -> Self {
+        Self { methods: methods.iter().map(ToString::to_string).collect(), ids: Vec::new() }
+    }
+
+    /// Schema of a service with stable ids, from its methods' id and
+    /// signature.
+    pub fn with_ids<S: ToString>(methods: &[(u32, S)]) -> Self {
+        Self { methods: methods.iter().map(|(_, signature)| signature.to_string()).collect(),
+               ids: methods.iter().map(|(id, _)| *id).collect() }
+    }
+
+    /// Return methods' signatures by id: their stable id if any, their
+    /// index otherwise.
+    pub fn by_id(&self) -> BTreeMap<u32, &str> {
+        self.methods.iter().enumerate().map(|(index, signature)| {
+            (self.ids.get(index).copied().unwrap_or(index as u32), signature.as_str())
+        }).collect()
+    }
+
+    /// Return schema's hash. Methods being hashed by id, the ones of
+    /// services with stable ids can be reordered.
+    pub fn hash(&self) -> Hash {
+        let mut data = Vec::new();
+        canonical::serialize_into(&mut data, &self.by_id()).expect("schema can not be serialized");
+        hash::digest(&data)
+    }
+
+    /// Return methods whose signature differs between `self` (the
+    /// server's schema) and `client`, by id.
+    pub fn diff(&self, client: &Schema) -> Vec<MethodChange> {
+        let (server, client) = (self.by_id(), client.by_id());
+        let ids: BTreeSet<u32> = server.keys().chain(client.keys()).copied().collect();
+        ids.into_iter().filter_map(|id| {
+            let (server, client) = (server.get(&id), client.get(&id));
+            match server == client {
+                true => None,
+                false => Some(MethodChange { id, server: server.map(|s| s.to_string()),
+                                             client: client.map(|s| s.to_string()) }),
+            }
+        }).collect()
+    }
+
+    /// Validate client's schema against the server's one (`self`).
+    pub fn validate(&self, client: &Schema) -> Result<(), SchemaMismatch> {
+        let (server_hash, client_hash) = (self.hash(), client.hash());
+        match server_hash == client_hash {
+            true => Ok(()),
+            false => Err(SchemaMismatch { server: server_hash, client: client_hash,
+                                          changes: self.diff(client) }),
+        }
+    }
+}
+
+
+/// Method whose signature differs between server and client. Signature is
+/// `None` when the peer has no method of this id.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct MethodChange {
+    /// Method's stable id, or its index (see `Schema::by_id`).
+    pub id: u32,
+    pub server: Option<String>,
+    pub client: Option<String>,
+}
+
+
+/// Schemas of server and client differ.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SchemaMismatch {
+    /// Hash of server's schema.
+    pub server: Hash,
+    /// Hash of client's schema.
+    pub client: Hash,
+    /// Methods that differ, by id.
+    pub changes: Vec<MethodChange>,
+}
+
+impl std::fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "service schema mismatch")?;
+        for MethodChange { id, server, client } in self.changes.iter() {
+            write!(f, "\n  #{}: server `{}`, client `{}`", id,
+                   server.as_deref().unwrap_or("-"), client.as_deref().unwrap_or("-"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+impl From<SchemaMismatch> for crate::Error {
+    fn from(err: SchemaMismatch) -> Self {
+        crate::ErrorKind::InvalidData.error(err.to_string())
+    }
+}
+
+
+/// Error returned by generated clients' `validate_schema()`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum SchemaError {
+    /// Server's schema could not be fetched.
+    Call(CallError),
+    /// Server's schema differs from client's one.
+    Mismatch(SchemaMismatch),
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Call(err) => write!(f, "can not fetch service schema: {}", err),
+            Self::Mismatch(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl From<CallError> for SchemaError {
+    fn from(err: CallError) -> Self {
+        Self::Call(err)
+    }
+}
+
+impl From<SchemaMismatch> for SchemaError {
+    fn from(err: SchemaMismatch) -> Self {
+        Self::Mismatch(err)
+    }
+}
+
+impl From<SchemaError> for crate::Error {
+    fn from(err: SchemaError) -> Self {
+        match err {
+            SchemaError::Call(err) => crate::ErrorKind::IO.error(err.to_string()),
+            SchemaError::Mismatch(err) => err.into(),
+        }
+    }
+}
+
+
+/// Fetch the schema of service `Sv` served over `transport`, validating it
+/// against the client's one. Services without schema (not generated by
+/// `#[service]`) are not validated.
+pub async fn validate<Sv, T>(transport: &mut T, timeout: Option<Duration>) -> Result<(), SchemaError>
+    where Sv: Service, T: Stream<Item=Sv::Response>+Sink<Sv::Request>+Unpin
+{
+    let (client, request) = match (Sv::schema(), Sv::schema_request()) {
+        (Some(client), Some(request)) => (client, request),
+        _ => return Ok(()),
+    };
+    transport.send(request).await.or(Err(CallError::Failed))?;
+    let server = with_timeout(timeout, transport.next()).await?
+                     .and_then(Sv::into_schema).ok_or(CallError::Failed)?;
+    Ok(server.validate(&client)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let server = Schema::new(&["fn clear()", "fn add(u32) -> u32", "fn sub(u32) -> u32"]);
+        assert_eq!(server.validate(&server.clone()), Ok(()));
+
+        let client = Schema::new(&["fn clear()", "fn sub(u32) -> u32"]);
+        let mismatch = server.validate(&client).unwrap_err();
+        assert_eq!((mismatch.server, mismatch.client), (server.hash(), client.hash()));
+        assert_eq!(mismatch.changes, vec![
+            MethodChange { id: 1, server: Some("fn add(u32) -> u32".into()),
+                           client: Some("fn sub(u32) -> u32".into()) },
+            MethodChange { id: 2, server: Some("fn sub(u32) -> u32".into()), client: None },
+        ]);
+        assert!(mismatch.to_string().ends_with("#2: server `fn sub(u32) -> u32`, client `-`"));
+
+        // methods with stable ids are compared by id, whatever their order
+        let server = Schema::with_ids(&[(1, "fn clear()"), (5, "fn sub(u32) -> u32"), (2, "fn add(u32) -> u32")]);
+        let client = Schema::with_ids(&[(1, "fn clear()"), (2, "fn add(u32) -> u32"), (5, "fn sub(u32) -> u32")]);
+        assert_eq!(server.validate(&client), Ok(()));
+
+        let client = Schema::with_ids(&[(1, "fn clear()"), (2, "fn add(u32) -> u32"), (3, "fn touch()"),
+                                        (5, "fn sub(u32) -> u32")]);
+        let mismatch = server.validate(&client).unwrap_err();
+        assert_eq!(mismatch.changes, vec![
+            MethodChange { id: 3, server: None, client: Some("fn touch()".into()) },
+        ]);
+    }
+}
@@ -12,10 +12,30 @@ use tokio::{
 use serde::{Deserialize,Serialize};
 
 use crate::{ErrorKind, Result};
-use super::codec::BincodeCodec;
+use crate::services::registry::Registry;
+use super::admission::{Admission, IpConnections, IpPermit, TokenBucket};
+use super::backpressure::Watch;
+use super::budget::Budgets;
+use super::codec::{BincodeCodec, Framed};
 use super::context::{Context, DefaultContext};
-use super::dispatch::Dispatch;
+use super::bus::EventBus;
+use super::deps::Dependencies;
+use super::enforce::fingerprint;
+use super::dispatch::{Dispatch, HandlerOptions};
 use super::config::ServerConfig;
+use super::events::{ServerEvent, ServerEvents};
+use super::extract::Builder;
+use super::message::Rejection;
+use super::service::Service;
+use super::reaper::Reaper;
+use super::revocation::Revocations;
+use super::trace::{self, Instrument};
+#[cfg(feature="metrics")]
+use crate::services::metrics::Exporter;
+#[cfg(feature="metrics")]
+use super::enforce::Fingerprint;
+#[cfg(feature="metrics")]
+use super::metrics::Collector;
 
 
 pub type IncomingStream<C> = (quinn::SendStream, quinn::RecvStream, Arc<C>);
@@ -32,20 +52,94 @@ pub struct Server<Id=u64, C=DefaultContext>
     pub dispatch: Arc<Dispatch<Id,IncomingStream<C>>>,
     /// Server configuration
     pub config: ServerConfig,
+    /// Server events' subscriptions.
+    pub events: Arc<ServerEvents>,
+    /// Cleanup of expired state, running every `config.reap_interval`
+    /// while listening. Other targets can be registered to it.
+    pub reaper: Arc<Reaper>,
+    /// Admission of streams across connections.
+    pub admission: Arc<Admission>,
+    /// Open connections per remote IP, when limited.
+    pub ip_connections: Option<Arc<IpConnections>>,
+    /// Revoked references, pushed to the connections holding them.
+    pub revocations: Arc<Revocations>,
+    /// Dependencies shared with services, attached to connections' context.
+    pub dependencies: Arc<Dependencies>,
+    /// Events exchanged between services, registered among dependencies.
+    pub bus: Arc<EventBus>,
+    /// Execution time accounting of peers' identities, when budgeted.
+    pub budgets: Option<Arc<Budgets>>,
+    /// Metrics collection, counting open connections.
+    #[cfg(feature="metrics")]
+    pub metrics: Option<Arc<Collector>>,
 }
 
 
 impl<Id, C> Server<Id, C>
-    where for<'de> Id: 'static+std::cmp::Ord+Send+Sync+Deserialize<'de>+Unpin,
+    where for<'de> Id: 'static+std::cmp::Ord+Clone+Send+Sync+Deserialize<'de>+Unpin+trace::SpanField,
                    C: 'static+Context+Send+Sync
 {
     /// Create new server.
     pub fn new(config: ServerConfig) -> Self {
-        Self {
-            // max dispatch is handled by ServerConfig::concurrent_streams
-            dispatch: Arc::new(Dispatch::new(None)),
-            config: config,
+        // max dispatch is handled by ServerConfig::concurrent_streams
+        let dispatch = Arc::new(Dispatch::new(None).with_limits(config.connection_config.codec_limits)
+                                .with_header_timeout(config.connection_config.handshake_timeout));
+        let events = Arc::new(ServerEvents::new());
+        let reaper = Arc::new(Reaper::new(config.reap_interval.unwrap_or_default()));
+        reaper.add(dispatch.clone());
+        reaper.add(events.clone());
+        let mut admission = Admission::new(config.max_streams, config.connection_streams);
+        if let Some(marks) = config.backpressure {
+            let events = events.clone();
+            admission = admission.with_watch(Watch::new(marks, move |pressure| {
+                events.emit(ServerEvent::Backpressure(pressure))
+            }));
         }
+        let admission = Arc::new(admission);
+        let revocations = Arc::new(Revocations::new());
+        reaper.add(revocations.clone());
+        let ip_connections = config.ip_connections.map(|max| Arc::new(IpConnections::new(max)));
+        let budgets = config.identity_budget.map(|limit| Arc::new(Budgets::new(limit)));
+        if let Some(ref budgets) = budgets {
+            reaper.add(budgets.clone());
+        }
+        let bus = Arc::new(EventBus::new());
+        reaper.add(bus.clone());
+        let dependencies = Arc::new(Dependencies::new());
+        dependencies.insert_arc(bus.clone());
+        // held references are recorded by contexts
+        dependencies.insert_arc(revocations.clone());
+        Self { dispatch, config, events, reaper, admission, ip_connections, revocations,
+               dependencies, bus, budgets,
+               #[cfg(feature="metrics")]
+               metrics: None }
+    }
+
+    /// Register a service using factory function whose arguments are
+    /// extracted from connection context. Builders requiring an
+    /// authenticated peer are refused when client authentication is not
+    /// configured.
+    pub fn add_context_builder<B,Args>(&self, id: Id, builder: B, options: HandlerOptions)
+            -> Result<()>
+        where B: 'static+Builder<C,Args>+Unpin,
+              B::Service: 'static,
+              for <'de> <B::Service as Service>::Request: Deserialize<'de>,
+              <B::Service as Service>::Response: Serialize
+    {
+        if B::AUTHENTICATED && !self.config.client_auth() {
+            return ErrorKind::Config.err("service requires client authentication");
+        }
+        self.dispatch.add_context_builder(id, builder, options)
+    }
+
+    /// Register the registry service at `id`, listing the services
+    /// registered on this server (see `services::registry`). Its id should
+    /// be pinned to trusted identities (see `Dispatch::pin`).
+    pub fn add_registry(&self, id: Id, options: HandlerOptions) -> Result<()>
+        where Id: Serialize
+    {
+        let registry = Registry::new(Arc::downgrade(&self.dispatch));
+        self.dispatch.add_builder(id, Box::new(move |_| registry.clone()), options)
     }
 
     /// Listen at provided address, dispatching services on provided runtime.
@@ -70,30 +164,184 @@ impl<Id, C> Server<Id, C>
                                    mut incoming: quinn::Incoming)
         -> Result<()>
     {
+        let reaper = self.config.reap_interval.map(|_| self.reaper.clone().spawn());
         while let Some(conn) = incoming.next().await {
-            let quinn::NewConnection {connection, bi_streams, .. } = conn.await.unwrap();
-            let context = C::from_connection(endpoint.clone(), connection);
-            self.dispatch_streams(context, bi_streams);
+            self.accept(&endpoint, conn).await;
+        }
+        if let Some(reaper) = reaper {
+            reaper.abort();
         }
         Ok(())
     }
 
-    /// Dispatch incoming bi_streams through the services.
-    fn dispatch_streams(&self, context: C, mut bi_streams: quinn::IncomingBiStreams)
+    /// Accept incoming connection and dispatch its streams to services,
+    /// returning the connection and its unidirectional streams once
+    /// established. Return None when the connection is refused or its
+    /// handshake fails.
+    pub async fn accept(&self, endpoint: &quinn::Endpoint, conn: quinn::Connecting)
+        -> Option<(quinn::Connection, quinn::IncomingUniStreams)>
     {
-        let dispatch = self.dispatch.clone();
+        // dropping connection refuses it before handshake completion
+        let remote = conn.remote_address();
+        if !self.config.address_filter.is_allowed(&remote.ip()) {
+            self.events.emit(ServerEvent::ConnectionRejected(remote));
+            return None;
+        }
+
+        let quinn::NewConnection {connection, bi_streams, uni_streams, .. } = conn.await.ok()?;
+        // connection is established in order to send the rejection reason
+        let ip_permit = match self.ip_connections {
+            Some(ref ip_connections) => match ip_connections.acquire(remote.ip()) {
+                Some(permit) => Some(permit),
+                None => {
+                    let rejection = Rejection::TooManyConnections;
+                    connection.close(rejection.code().into(), rejection.reason().as_bytes());
+                    self.events.emit(ServerEvent::ConnectionRejected(remote));
+                    return None;
+                },
+            },
+            None => None,
+        };
+
+        self.events.emit(ServerEvent::ConnectionOpened(connection.remote_address()));
+        self.push_control(connection.clone());
+        self.dispatch_connection(endpoint, connection.clone(), bi_streams, ip_permit);
+        Some((connection, uni_streams))
+    }
+
+    /// Dispatch streams opened by the peer of a connection, which can also
+    /// have been dialed by this endpoint (see `peer::PeerEndpoint`).
+    pub fn dispatch_connection(&self, endpoint: &quinn::Endpoint, connection: quinn::Connection,
+                               bi_streams: quinn::IncomingBiStreams, ip_permit: Option<IpPermit>)
+    {
+        let context = C::from_connection(endpoint.clone(), connection)
+                        .with_dependencies(self.dependencies.clone());
+        self.dispatch_streams(context, bi_streams, ip_permit);
+    }
+
+    /// Push control messages to connection, on a unidirectional stream
+    /// opened with the first one.
+    fn push_control(&self, connection: quinn::Connection) {
+        let mut messages = self.revocations.connect(connection.stable_id());
+        tokio::spawn(async move {
+            let mut sink = None;
+            while let Some(message) = messages.next().await {
+                let sink = match sink {
+                    Some(ref mut sink) => sink,
+                    None => match connection.open_uni().await {
+                        Ok(stream) => sink.insert(Framed::new(stream, BincodeCodec::new())),
+                        Err(_) => break,
+                    },
+                };
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Dispatch incoming bi_streams through the services. Streams are not
+    /// accepted from the connection until they are admitted, and are reset
+    /// when exceeding the connection's stream rate or its peer's identity
+    /// budget. Peers are identified by their certificate, to which ids can
+    /// be pinned. The connection is counted for its IP until it is
+    /// closed.
+    fn dispatch_streams(&self, context: C, mut bi_streams: quinn::IncomingBiStreams,
+                        ip_permit: Option<IpPermit>)
+    {
+        let (dispatch, events) = (self.dispatch.clone(), self.events.clone());
+        let (admission, revocations) = (self.admission.clone(), self.revocations.clone());
         let context = Arc::new(context);
+        let address = context.connection().remote_address();
+        let connection_id = context.connection().stable_id();
+        let mut stream_rate = self.config.stream_rate.map(TokenBucket::new);
+        // only authenticated peers are budgeted
+        let identity = context.peer_certificate().map(|cert| fingerprint(&cert.0));
+        let budget = self.budgets.clone().zip(identity);
+        let span = trace::connection(address, connection_id);
+        let connection_span = span.clone();
+        #[cfg(feature="metrics")]
+        let connection_guard = self.metrics.as_ref().map(Collector::connection);
 
         tokio::spawn(async move {
-            while let Some(stream) = bi_streams.next().await {
-                let (dispatch_, context) = (dispatch.clone(), context.clone()) ;
+            let _ip_permit = ip_permit;
+            #[cfg(feature="metrics")]
+            let _connection_guard = connection_guard;
+            while let Some(Ok(mut stream)) = bi_streams.next().await {
+                if let Some(false) = stream_rate.as_mut().map(TokenBucket::try_acquire) {
+                    let code = Rejection::RateLimited.code().into();
+                    stream.0.reset(code).ok();
+                    stream.1.stop(code).ok();
+                    events.emit(ServerEvent::LimitReached(address));
+                    continue;
+                }
+                if let Some((ref budgets, identity)) = budget {
+                    if budgets.check(&identity).is_some() {
+                        let code = Rejection::BudgetExhausted.code().into();
+                        stream.0.reset(code).ok();
+                        stream.1.stop(code).ok();
+                        events.emit(ServerEvent::BudgetExhausted(address, identity));
+                        continue;
+                    }
+                }
+                let permit = match admission.acquire(connection_id).await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let (dispatch_, events, context) = (dispatch.clone(), events.clone(), context.clone());
+                let budget = budget.clone();
                 tokio::spawn(async move {
-                    let stream = stream.unwrap();
+                    let _permit = permit;
+                    events.emit(ServerEvent::StreamDispatched(address));
                     let data = (stream.0, stream.1, context);
-                    dispatch_.dispatch_stream::<BincodeCodec<Id>>(data).await
-                });
+                    let fut = dispatch_.dispatch_stream_as::<BincodeCodec<Id>>(data, identity.as_ref());
+                    let result = match budget {
+                        Some((budgets, identity)) => match budgets.metered(identity, fut).await {
+                            Some(result) => result,
+                            None => {
+                                events.emit(ServerEvent::BudgetExhausted(address, identity));
+                                return;
+                            },
+                        },
+                        None => fut.await,
+                    };
+                    match result {
+                        Ok(_) => (),
+                        Err(err) if err.kind() == ErrorKind::LimitReached =>
+                            events.emit(ServerEvent::LimitReached(address)),
+                        Err(err) => events.emit(ServerEvent::HandlerError(address, err)),
+                    }
+                }.instrument(connection_span.clone()));
             }
-        });
+            revocations.disconnect(connection_id);
+            events.emit(ServerEvent::ConnectionClosed(address));
+        }.instrument(span));
+    }
+}
+
+#[cfg(feature="metrics")]
+impl<Id, C> Server<Id, C>
+    where for<'de> Id: 'static+std::cmp::Ord+Clone+Send+Sync+Deserialize<'de>+Serialize+Unpin
+                       +trace::SpanField,
+                   C: 'static+Context+Send+Sync
+{
+    /// Collect server's metrics into provided collector: open connections,
+    /// in-flight dispatches, identities' budgets, and calls and bytes of
+    /// the services registered afterwards. They are exported at `id`,
+    /// pinned to provided identities.
+    pub fn add_metrics(&mut self, id: Id, collector: Arc<Collector>,
+                       identities: impl IntoIterator<Item=Fingerprint>) -> Result<()>
+    {
+        self.dispatch.pin(id.clone(), identities);
+        let exporter = Exporter::new(collector.clone());
+        self.dispatch.add_builder(id, Box::new(move |_| exporter.clone()), HandlerOptions::default())?;
+        collector.watch_dispatch(&self.dispatch);
+        if let Some(ref budgets) = self.budgets {
+            collector.watch_budgets(budgets);
+        }
+        self.dispatch.collect(collector.clone());
+        self.metrics = Some(collector);
+        Ok(())
     }
 }
 
@@ -105,6 +353,7 @@ pub mod tests {
         net::SocketAddr,
         str::FromStr,
     };
+    use super::super::dispatch::HandlerOptions;
     use super::super::service::tests::{simple_service,simple_service_2};
 
 
@@ -112,13 +361,48 @@ pub mod tests {
         let mut server = Server::new(ServerConfig::default());
         server.dispatch.add_builder(0, Box::new(move |context| {
             simple_service::Service::new()
-        }), false).unwrap();
+        }), HandlerOptions::default()).unwrap();
         server.dispatch.add_builder(1, Box::new(move |context| {
             simple_service_2::Service::new()
-        }), false).unwrap();
+        }), HandlerOptions::default()).unwrap();
         server
     }
 
+    #[test]
+    fn test_add_context_builder() {
+        use super::super::context::{AuthenticatedContext, TypedContext};
+
+        let server = get_server();
+        let options = HandlerOptions::default();
+        server.add_context_builder(2, |_: TypedContext<DefaultContext>| {
+            simple_service::Service::new()
+        }, options).unwrap();
+        let err = server.add_context_builder(3, |_: AuthenticatedContext<DefaultContext>| {
+            simple_service::Service::new()
+        }, options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Config);
+        assert!(server.dispatch.handlers.get(&3).unwrap().is_none());
+
+        let mut config = ServerConfig::default();
+        config.connection_config.with_no_client_auth = false;
+        let server = Server::<u32, DefaultContext>::new(config);
+        server.add_context_builder(3, |_: AuthenticatedContext<DefaultContext>| {
+            simple_service::Service::new()
+        }, options).unwrap();
+    }
+
+    #[test]
+    fn test_registry() {
+        let server = get_server();
+        // not registered by default
+        assert_eq!(server.dispatch.services().len(), 2);
+        server.dispatch.pin(u32::MAX, [[0; 32]]);
+        server.add_registry(u32::MAX, HandlerOptions::default()).unwrap();
+        let services = server.dispatch.services();
+        assert_eq!(services.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![0, 1, u32::MAX]);
+        assert_eq!(services[1].1.methods, simple_service_2::Service::methods());
+    }
+
     #[test]
     fn test_server() {
         let runtime = Runtime::new().unwrap();
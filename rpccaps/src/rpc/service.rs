@@ -1,10 +1,83 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 use futures::prelude::*;
+use futures::future::Either;
 use futures::io::{AsyncRead,AsyncWrite};
+use futures::future::{AbortHandle, BoxFuture};
+use futures::stream::{BoxStream, FuturesUnordered, SelectAll};
+use serde::{de::DeserializeOwned,Deserialize,Serialize};
 use tokio_util::codec::{Decoder,Encoder};
 
+use crate::data::{Capability, CapabilitySet};
 use super::codec::Framed;
+use super::message::Error;
+use super::protocol::{Call, Checked, Frame, Peer, Reply};
+use super::schema::Schema;
+use super::trace::{self, Instrument, TraceContext};
 use super::transport::Transport;
+use super::transport::local::{self, LocalClient};
+
+pub use super::middleware::{Layered, ServiceMiddleware};
+
+
+/// Service's methods and caller's capability, as returned by the implicit
+/// `__capabilities()` RPC method.
+#[derive(Serialize,Deserialize,PartialEq,Clone,Debug)]
+pub struct Capabilities<C = Capability> {
+    /// Methods' name and the index of the action they require.
+    pub methods: Vec<(String, usize)>,
+    /// Caller's effective capability, as seen by the service.
+    pub capability: C,
+}
+
+
+/// Optional features advertised by services, so that clients can detect
+/// them at discovery instead of failing on unsupported requests.
+///
+/// Features are listed in the `features` meta as comma-separated flags.
+/// `#[service]` advertises `streaming` and `cancellation` when relevant,
+/// along with custom flags declared by `#[meta(features="...")]`.
+pub mod features {
+    /// Meta key of features.
+    pub const META: &str = "features";
+    /// Service has server or client streaming methods.
+    pub const STREAMING: &str = "streaming";
+    /// Service has unordered methods, whose calls can be cancelled.
+    pub const CANCELLATION: &str = "cancellation";
+
+    /// Return features advertised in provided metas.
+    pub fn of<'a, K, V>(metas: impl IntoIterator<Item=&'a (K, V)>) -> Vec<&'a str>
+        where K: AsRef<str>+'a, V: AsRef<str>+'a
+    {
+        metas.into_iter().filter(|(key, _)| key.as_ref() == META)
+             .flat_map(|(_, value)| value.as_ref().split(','))
+             .map(str::trim).filter(|flag| !flag.is_empty())
+             .collect()
+    }
+}
+
+
+/// Options of `Service::serve_concurrent`.
+#[derive(Clone,Copy,Debug)]
+pub struct ServeOptions {
+    /// Maximum requests being dispatched or waiting for their response to
+    /// be sent.
+    pub concurrency: usize,
+    /// If true, responses are sent in requests' order, except for requests
+    /// for which `Service::is_ordered` returns false. Otherwise they are
+    /// all sent as soon as they are ready, thus responses must be
+    /// identifiable by the client.
+    pub ordered: bool,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self { concurrency: 8, ordered: true }
+    }
+}
 
 
 /// Generic Service trait that handling requests and call corresponding RPC method.
@@ -15,6 +88,9 @@ pub trait Service: Send+Sync+Unpin
     type Request: Sized+Send+Sync+Unpin;
     /// Response message
     type Response: Sized+Send+Sync+Unpin;
+    /// Capability presented by callers, `Capability` by default. Services
+    /// of more than 64 actions use a `WideCapability`.
+    type Capability: CapabilitySet+Serialize+DeserializeOwned+fmt::Debug+Send+Sync+Unpin+'static = Capability;
 
     /// Return True if service should be kept alive
     fn is_alive(&self) -> bool;
@@ -25,26 +101,409 @@ pub trait Service: Send+Sync+Unpin
         &metas
     }
 
+    /// Optional features advertised in service's metas.
+    fn features() -> Vec<&'static str> {
+        features::of(Self::metas())
+    }
+
+    /// Return true if service advertises provided feature.
+    fn supports(feature: &str) -> bool {
+        Self::features().contains(&feature)
+    }
+
+    /// Service methods' name and the index of the action they require in
+    /// callers' capability.
+    fn methods() -> &'static [(&'static str, usize)] {
+        static METHODS: [(&str, usize);0] = [];
+        &METHODS
+    }
+
+    /// Names of the actions required by `methods()`, by index: a method's
+    /// `cap` name, otherwise its own name, and empty for unused actions.
+    /// Used to name callers' capability (see `Capability::with_names()`).
+    fn action_names() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Signatures of service's methods, as returned to clients validating
+    /// their schema (see `rpc::schema`). None for services not generated
+    /// by `#[service]`.
+    fn schema() -> Option<Schema> {
+        None
+    }
+
+    /// Request fetching the server's schema, sent by clients validating it
+    /// at connect time (see `schema::validate`).
+    fn schema_request() -> Option<Self::Request> {
+        None
+    }
+
+    /// Server's schema carried by `response`, if it answers
+    /// `schema_request()`.
+    fn into_schema(_response: Self::Response) -> Option<Schema> {
+        None
+    }
+
+    /// Caller's effective capability. By default, all actions are allowed.
+    fn capability(&self) -> Self::Capability {
+        Self::Capability::all()
+    }
+
+    /// Return true if dispatch futures can be dropped before completion
+    /// without corrupting service's state (see `rpc::cancel`). Otherwise,
+    /// cancelled calls run to completion. By default, services are not
+    /// cancellation-safe.
+    fn is_cancellation_safe() -> bool {
+        false
+    }
+
+    /// Return false if response to `request` can be sent before the ones of
+    /// previous requests, when served concurrently.
+    fn is_ordered(_request: &Self::Request) -> bool {
+        true
+    }
+
+    /// Index of the method called by `request`, as listed by `methods()`.
+    /// Streamed items of client-streaming methods have none.
+    fn method_index(_request: &Self::Request) -> Option<usize> {
+        None
+    }
+
+    /// Index of the method `request` is part of, as listed by `methods()`,
+    /// streamed items of client-streaming methods included: callers'
+    /// capability is checked against its bits (see `rpc::enforce`). By
+    /// default, the one of `method_index()`.
+    fn method_of(request: &Self::Request) -> Option<usize> {
+        Self::method_index(request)
+    }
+
+    /// Return true if handling `request` again has no further effect, so
+    /// that it can be re-issued when its stream fails. By default, requests
+    /// are not idempotent.
+    fn is_idempotent(_request: &Self::Request) -> bool {
+        false
+    }
+
+    /// Id of the call made by `request`, for methods whose requests are
+    /// tagged with one. Such calls can be cancelled by the client.
+    fn call_id(_request: &Self::Request) -> Option<u64> {
+        None
+    }
+
+    /// Id of the call cancelled by `request`, if it is a cancellation.
+    fn cancelled(_request: &Self::Request) -> Option<u64> {
+        None
+    }
+
+    /// Trace context carried by `request`, if it is the one of the
+    /// following call (see `rpc::trace`).
+    fn trace_context(_request: &Self::Request) -> Option<TraceContext> {
+        None
+    }
+
+    /// Return true if `response` carries a method's error.
+    fn is_error(_response: &Self::Response) -> bool {
+        false
+    }
+
+    /// Return true if `response` refuses a call without performing it
+    /// (see `denied()` and `slow_down()`).
+    fn is_refused(_response: &Self::Response) -> bool {
+        false
+    }
+
+    /// Protocol frame of `request`. Requests without frame are not checked
+    /// against the protocol.
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        None
+    }
+
+    /// Protocol frame of `response`. Responses without frame are not
+    /// checked against the protocol.
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        None
+    }
+
+    /// Response sent instead of a denied request's one, with provided
+    /// (possibly empty) reason and the call id of unordered requests (see
+    /// `call_id()`). By default, nothing is sent.
+    fn denied(_call_id: Option<u64>, _reason: String) -> Option<Self::Response> {
+        None
+    }
+
+    /// Response sent instead of the one of a request exceeding service's
+    /// rate, asking the client to wait `retry_after` before calling the
+    /// method again. By default, nothing is sent.
+    fn slow_down(_retry_after: Duration) -> Option<Self::Response> {
+        None
+    }
+
+    /// Response sent instead of the one of a request the service failed to
+    /// handle, with the call id of unordered requests. By default, nothing
+    /// is sent.
+    fn error(_call_id: Option<u64>, _error: Error) -> Option<Self::Response> {
+        None
+    }
+
+    /// Convert `response` into one that peers of the provided protocol
+    /// version can decode (see `rpc::version`), or None when it can't be
+    /// sent to them. By default, responses are sent as is.
+    fn downgrade(response: Self::Response, _version: u16) -> Option<Self::Response> {
+        Some(response)
+    }
+
+    /// Return service's methods and caller's capability.
+    fn capabilities(&self) -> Capabilities<Self::Capability> where Self: Sized {
+        Capabilities {
+            methods: Self::methods().iter().map(|(n, a)| (n.to_string(), *a)).collect(),
+            capability: self.capability(),
+        }
+    }
+
     /// Dispatch request
     async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response>;
 
-    /// Serve provided request-response transport
+    /// Dispatch request of a streaming method, returning the stream of its
+    /// responses. Other requests are given back.
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        Err(request)
+    }
+
+    /// Dispatch request of a client-streaming method, whose items are read
+    /// from `requests`, returning the stream of its responses. Other
+    /// requests are given back.
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               _requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        Err(request)
+    }
+
+    /// Serve provided request-response transport.
+    ///
+    /// While a call tagged with an id is dispatched, the next request is
+    /// read: when it cancels the call, its dispatch is aborted if the
+    /// service is cancellation-safe, completed otherwise.
     async fn serve<T,E>(&mut self, mut transport: T)
         where T: Stream<Item=Self::Request>+Sink<Self::Response,Error=E>+Send+Unpin,
               E: Send+Unpin
     {
-        while let (true, Some(req)) = (self.is_alive(), transport.next().await) {
-            match self.dispatch(req).await {
-                Some(resp) => match transport.send(resp).await {
-                    Ok(_) => (),
-                    Err(_) => break,
+        // request read while dispatching the previous one
+        let mut next = None;
+        // trace context of the next call
+        let mut trace_context = None;
+        loop {
+            let req = match next.take() {
+                Some(req) => req,
+                None => transport.next().await,
+            };
+            let req = match (self.is_alive(), req) {
+                (true, Some(req)) => req,
+                _ => break,
+            };
+            if let Some(context) = Self::trace_context(&req) {
+                trace_context = Some(context);
+                continue
+            }
+            let (span, start) = (trace::request::<Self>(&req), Instant::now());
+            let context = trace_context.take();
+            let dispatch = self.dispatch_incoming(req, &mut transport);
+            let req = match trace::with_context(context, dispatch).instrument(span.clone()).await {
+                Ok(responses) => Ok(responses),
+                Err(req) => trace::with_context(context, self.dispatch_streaming(req))
+                                  .instrument(span.clone()).await,
+            };
+            let req = match req {
+                Ok(mut responses) => {
+                    while let Some(resp) = responses.next().await {
+                        if transport.send(resp).await.is_err() {
+                            return
+                        }
+                    }
+                    trace::elapsed(&span, start);
+                    continue
+                },
+                Err(req) => req,
+            };
+            let (frame, call_id) = (Self::request_frame(&req), Self::call_id(&req));
+            let dispatch = trace::with_context(context, self.dispatch(req)).instrument(span.clone());
+            let resp = match call_id {
+                Some(call_id) => {
+                    let (resp, read) = cancellable::<Self,_>(dispatch.boxed(), &mut transport,
+                                                             call_id).await;
+                    next = read;
+                    resp
+                },
+                None => dispatch.await,
+            };
+            trace::elapsed(&span, start);
+            if let Some(resp) = resp.or_else(|| unanswered::<Self>(frame, call_id)) {
+                if transport.send(resp).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Serve provided request-response transport, dispatching up to
+    /// `options.concurrency` requests at once.
+    ///
+    /// Each request is dispatched on a clone of the service: state shared
+    /// among requests must be kept behind `Arc` or alike. Requests are not
+    /// read from the transport while the maximum of pending responses is
+    /// reached.
+    ///
+    /// Responses of streaming methods are sent as they are produced, a call
+    /// being pending until its stream is complete. Responses of ordered
+    /// calls are sent after all the responses of the previous ones.
+    /// Client-streaming requests are dispatched on the service itself, and
+    /// no other request is read until they return. Cancelled calls are
+    /// aborted when the service is cancellation-safe; otherwise they run to
+    /// completion, their responses being discarded.
+    async fn serve_concurrent<T,E>(&mut self, mut transport: T, options: ServeOptions)
+        where Self: Clone,
+              T: Stream<Item=Self::Request>+Sink<Self::Response,Error=E>+Send+Unpin,
+              E: Send+Unpin
+    {
+        let mut running = FuturesUnordered::new();
+        // responses of ordered calls waiting for the previous ones, by id
+        let mut ready = BTreeMap::new();
+        // responses being sent, tagged with their call and ended by `None`
+        let mut sending = SelectAll::new();
+        // pending calls' abort handles, by call id
+        let mut aborts = BTreeMap::new();
+        // cancelled calls running to completion
+        let mut cancelled = BTreeSet::new();
+        let (mut next_id, mut next_send) = (0u64, 0u64);
+        let mut closed = false;
+        let mut trace_context = None;
+
+        loop {
+            let pending = running.len() + ready.len() + sending.len();
+            let accept = !closed && self.is_alive() && pending < options.concurrency.max(1);
+            let event = if accept {
+                futures::select! {
+                    req = transport.next().fuse() => Either::Left(req),
+                    done = running.select_next_some() => Either::Right(Either::Left(done)),
+                    sent = sending.select_next_some() => Either::Right(Either::Right(sent)),
+                }
+            } else if !running.is_empty() || !sending.is_empty() {
+                futures::select! {
+                    done = running.select_next_some() => Either::Right(Either::Left(done)),
+                    sent = sending.select_next_some() => Either::Right(Either::Right(sent)),
+                    // streams ended meanwhile
+                    complete => continue,
+                }
+            } else {
+                break
+            };
+
+            let resp = match event {
+                Either::Left(Some(req)) => {
+                    let cancel = Self::cancelled(&req)
+                        .and_then(|call_id| Some((call_id, aborts.remove(&call_id)?)));
+                    if let Some((call_id, abort)) = cancel {
+                        match Self::is_cancellation_safe() {
+                            true => AbortHandle::abort(&abort),
+                            false => { cancelled.insert(call_id); },
+                        }
+                        continue
+                    }
+                    if let Some(context) = Self::trace_context(&req) {
+                        trace_context = Some(context);
+                        continue
+                    }
+                    let call_id = Self::call_id(&req);
+                    let (span, start) = (trace::request::<Self>(&req), Instant::now());
+                    let context = trace_context.take();
+                    let id = match options.ordered && Self::is_ordered(&req) {
+                        true => {
+                            next_id += 1;
+                            Some(next_id - 1)
+                        },
+                        false => None,
+                    };
+                    let dispatch = self.dispatch_incoming(req, &mut transport);
+                    let req = trace::with_context(context, dispatch).instrument(span.clone()).await;
+                    let (mut service, request_span) = (self.clone(), span.clone());
+                    let (responses, abort) = future::abortable(trace::with_context(context, async move {
+                        let responses = match req {
+                            Ok(responses) => responses,
+                            Err(req) => match service.dispatch_streaming(req).await {
+                                Ok(responses) => responses,
+                                Err(req) => {
+                                    let frame = Self::request_frame(&req);
+                                    let resp = service.dispatch(req).await.or_else(|| unanswered::<Self>(frame, call_id));
+                                    stream::iter(resp).boxed()
+                                },
+                            },
+                        };
+                        trace::elapsed(&request_span, start);
+                        responses
+                    }).instrument(span));
+                    if let Some(call_id) = call_id {
+                        aborts.insert(call_id, abort);
+                    }
+                    running.push(async move {
+                        let responses = responses.await.unwrap_or_else(|_| {
+                            let error = call_id.and_then(|call_id| Self::error(Some(call_id), Error::Cancelled(call_id)));
+                            stream::iter(error).boxed()
+                        });
+                        (id, call_id, responses)
+                    });
+                    continue
+                },
+                Either::Left(None) => {
+                    closed = true;
+                    continue
+                },
+                // call is dispatched, its responses can be sent
+                Either::Right(Either::Left((id, call_id, responses))) => {
+                    let responses = match call_id {
+                        Some(call_id) => {
+                            let (responses, abort) = stream::abortable(responses);
+                            aborts.insert(call_id, abort);
+                            responses.boxed()
+                        },
+                        None => responses,
+                    };
+                    let responses = responses.map(Some).chain(stream::once(future::ready(None)))
+                                             .map(move |resp| (id, call_id, resp)).boxed();
+                    match id {
+                        Some(id) if id != next_send => { ready.insert(id, responses); },
+                        _ => sending.push(responses),
+                    }
+                    continue
+                },
+                Either::Right(Either::Right((_, Some(call_id), Some(_)))) if cancelled.contains(&call_id) =>
+                    continue,
+                Either::Right(Either::Right((_, _, Some(resp)))) => Some(resp),
+                // call's responses are complete
+                Either::Right(Either::Right((id, call_id, None))) => {
+                    if let Some(id) = id {
+                        next_send = id + 1;
+                        if let Some(responses) = ready.remove(&next_send) {
+                            sending.push(responses);
+                        }
+                    }
+                    call_id.filter(|call_id| aborts.remove(call_id).is_none() | cancelled.remove(call_id))
+                           .and_then(|call_id| Self::error(Some(call_id), Error::Cancelled(call_id)))
+                },
+            };
+
+            if let Some(resp) = resp {
+                if transport.send(resp).await.is_err() {
+                    return
                 }
-                _ => (),
             }
         }
     }
 
-    /// Run service for provided sender/receiver using bincode format.
+    /// Run service for provided sender/receiver using bincode format,
+    /// checking messages against the protocol. Stream is closed when the
+    /// client violates it.
     async fn serve_stream<S,R,E,D>(mut self, (sender, receiver): (S,R),
                                    encoder: E, decoder: D)
         where Self: Sized,
@@ -56,7 +515,20 @@ pub trait Service: Send+Sync+Unpin
     {
         let stream = Framed::new(receiver, decoder);
         let sink = Framed::new(sender, encoder);
-        self.serve(Transport::new(sink,stream)).await
+        let transport = Checked::new(Transport::new(sink,stream), Peer::Server,
+                                     Self::request_frame, Self::response_frame);
+        self.serve(transport).await
+    }
+
+    /// Serve service in-process, returning a client of type `Cl` (e.g. the
+    /// generated `Client`) along with the future serving it, to be spawned
+    /// or polled with the client's calls. Messages are not encoded (see
+    /// `rpc::transport::local`).
+    fn serve_local<Cl>(mut self, capacity: usize) -> (Cl, BoxFuture<'static, ()>)
+        where Self: 'static+Sized, Cl: From<LocalClient<Self>>
+    {
+        let (server, client) = local::pair::<Self>(capacity);
+        (Cl::from(client), async move { self.serve(server).await }.boxed())
     }
 
     /// Run service for provided sender/receiver using bincode format.
@@ -77,9 +549,93 @@ pub trait Service: Send+Sync+Unpin
 }
 
 
+/// Implement the functions of `Service` describing the service and
+/// classifying its requests by forwarding them to `$inner`, for wrappers
+/// whose requests are the ones of the inner service. With `@requests`,
+/// `is_cancellation_safe()` is left to the wrapper.
+macro_rules! forward_requests {
+    ($inner:ident) => {
+        fn is_cancellation_safe() -> bool {
+            $inner::is_cancellation_safe()
+        }
+
+        $crate::rpc::service::forward_requests!(@requests $inner);
+    };
+    (@requests $inner:ident) => {
+        fn is_ordered(request: &Self::Request) -> bool {
+            $inner::is_ordered(request)
+        }
+
+        fn is_idempotent(request: &Self::Request) -> bool {
+            $inner::is_idempotent(request)
+        }
+
+        fn method_index(request: &Self::Request) -> Option<usize> {
+            $inner::method_index(request)
+        }
+
+        fn method_of(request: &Self::Request) -> Option<usize> {
+            $inner::method_of(request)
+        }
+
+        fn call_id(request: &Self::Request) -> Option<u64> {
+            $inner::call_id(request)
+        }
+
+        fn cancelled(request: &Self::Request) -> Option<u64> {
+            $inner::cancelled(request)
+        }
+
+        fn trace_context(request: &Self::Request) -> Option<$crate::rpc::trace::TraceContext> {
+            $inner::trace_context(request)
+        }
+
+        fn request_frame(request: &Self::Request) -> Option<$crate::rpc::protocol::Frame> {
+            $inner::request_frame(request)
+        }
+    };
+}
+
+pub(crate) use forward_requests;
+
+
+/// Await `dispatch` of call `call_id`, reading the next request from
+/// `requests` meanwhile: when it cancels the call, dispatch is aborted (or
+/// completed for services that are not cancellation-safe). Return the
+/// response to send and the request read, if any.
+async fn cancellable<S, T>(dispatch: BoxFuture<'_, Option<S::Response>>, requests: &mut T,
+                           call_id: u64)
+    -> (Option<S::Response>, Option<Option<S::Request>>)
+    where S: Service+?Sized, T: Stream<Item=S::Request>+Unpin
+{
+    match future::select(dispatch, requests.next()).await {
+        Either::Left((resp, _)) => (resp, None),
+        Either::Right((Some(req), dispatch)) if S::cancelled(&req) == Some(call_id) => {
+            if !S::is_cancellation_safe() {
+                dispatch.await;
+            }
+            (S::error(Some(call_id), Error::Cancelled(call_id)), None)
+        },
+        Either::Right((req, dispatch)) => (dispatch.await, Some(req)),
+    }
+}
+
+/// Error response to a request of `frame` that has not been answered by
+/// dispatch: requests without frame are none of service's calls, while
+/// calls expecting a single response failed.
+fn unanswered<S: Service+?Sized>(frame: Option<Frame>, call_id: Option<u64>) -> Option<S::Response> {
+    match frame {
+        None => S::error(call_id, Error::ActionNotFound),
+        Some(Frame::Request(Call { reply: Reply::Single, .. })) =>
+            S::error(call_id, Error::Internal(String::from("no response"))),
+        _ => None,
+    }
+}
+
+
 #[cfg(test)]
 pub mod tests {
-    use futures::future::join;
+    use futures::future::{join, join3};
     use futures::executor::LocalPool;
 
     use crate as rpccaps;
@@ -116,6 +672,7 @@ pub mod tests {
                 self.a
             }
 
+            #[rpc(idempotent)]
             async fn get(&mut self) -> u32 {
                 self.a
             }
@@ -135,7 +692,7 @@ pub mod tests {
             }
         }
 
-        #[service]
+        #[service(cancellation_safe)]
         impl Service {
             pub fn clear(&mut self) {
                 self.a = 1.0;
@@ -162,10 +719,497 @@ pub mod tests {
         }
     }
 
+    pub mod concurrent_service {
+        use super::*;
+
+        /// Future yielding to the executor `count` times before completion.
+        pub struct Yield(pub u32);
+
+        impl Future for Yield {
+            type Output = ();
+
+            fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+                -> std::task::Poll<()>
+            {
+                match self.0 {
+                    0 => std::task::Poll::Ready(()),
+                    _ => {
+                        self.0 -= 1;
+                        cx.waker().wake_by_ref();
+                        std::task::Poll::Pending
+                    }
+                }
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct Service;
+
+        #[service(wire_tests)]
+        impl Service {
+            async fn echo(&mut self, value: u32, yields: u32) -> u32 {
+                Yield(yields).await;
+                value
+            }
+
+            #[rpc(unordered)]
+            async fn echo_unordered(&mut self, value: u32, yields: u32) -> u32 {
+                Yield(yields).await;
+                value
+            }
+        }
+    }
+
+    pub mod error_service {
+        use super::*;
+        use rpccaps::rpc::message::RemoteError;
+
+        #[derive(Debug,PartialEq)]
+        pub enum DivError {
+            DivByZero,
+            Unknown(String),
+        }
+
+        impl From<DivError> for RemoteError {
+            fn from(err: DivError) -> Self {
+                match err {
+                    DivError::DivByZero => RemoteError::new(1, "division by zero"),
+                    DivError::Unknown(message) => RemoteError::new(0, message),
+                }
+            }
+        }
+
+        impl From<RemoteError> for DivError {
+            fn from(err: RemoteError) -> Self {
+                match err.code {
+                    1 => DivError::DivByZero,
+                    _ => DivError::Unknown(err.message),
+                }
+            }
+        }
+
+        pub struct Service;
+
+        #[service]
+        #[rpc(error="DivError")]
+        impl Service {
+            fn div(&mut self, a: u32, b: u32) -> Result<u32, DivError> {
+                match b {
+                    0 => Err(DivError::DivByZero),
+                    b => Ok(a / b),
+                }
+            }
+        }
+    }
+
+    pub mod streaming_service {
+        use super::*;
+        use rpccaps::rpc::stream::Streaming;
+
+        #[derive(Clone)]
+        pub struct Service {
+            pub start: u32,
+        }
+
+        #[service]
+        #[meta(features="resumable")]
+        impl Service {
+            fn count(&mut self, n: u32) -> Streaming<u32> {
+                Streaming::new(futures::stream::iter(self.start..self.start+n))
+            }
+
+            async fn count_from(&mut self, start: u32, n: u32) -> Streaming<u32> {
+                self.start = start;
+                self.count(n)
+            }
+
+            fn start(&mut self) -> u32 {
+                self.start
+            }
+        }
+    }
+
+    pub mod wide_service {
+        use super::*;
+        use crate::data::WideCapability;
+
+        pub struct Service {
+            pub cap: WideCapability<2>,
+        }
+
+        #[service]
+        #[rpc(capability_type="WideCapability<2>", capability="self.cap")]
+        impl Service {
+            fn get(&mut self) -> u32 {
+                1
+            }
+
+            #[rpc(cap_bit=100)]
+            fn admin(&mut self) -> u32 {
+                2
+            }
+        }
+    }
+
+    pub mod incoming_service {
+        use super::*;
+        use rpccaps::rpc::stream::{Incoming, Streaming};
+
+        pub struct Service {
+            pub total: u32,
+        }
+
+        #[service]
+        impl Service {
+            #[rpc(cap="write")]
+            async fn sum(&mut self, offset: u32, items: Incoming<'_, u32>) -> u32 {
+                self.total = items.fold(offset, |acc, item| async move { acc + item }).await;
+                self.total
+            }
+
+            #[rpc(cap="write")]
+            async fn double(&mut self, items: Incoming<'_, u32>) -> Streaming<u32> {
+                let items = items.map(|item| item * 2).collect::<Vec<_>>().await;
+                Streaming::new(futures::stream::iter(items))
+            }
+
+            #[rpc(cap_bit=4)]
+            fn total(&mut self) -> u32 {
+                self.total
+            }
+        }
+    }
+
     use super::*;
     use rpccaps::rpc::Transport;
+    use rpccaps::rpc::cancel::CancellationSafe;
     use futures::stream::StreamExt;
 
+    fn run_concurrent(options: ServeOptions, requests: Vec<concurrent_service::Request>)
+        -> Vec<u32>
+    {
+        use concurrent_service::{Request, Response};
+        let (server_transport, mut client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            for request in requests {
+                client_transport.send(request).await.unwrap();
+            }
+            client_transport.sender.close_channel();
+
+            let mut values = Vec::new();
+            while let Some(resp) = client_transport.next().await {
+                match resp {
+                    Response::Echo(value) | Response::EchoUnordered(_, value) => values.push(value),
+                    _ => (),
+                }
+            }
+            values
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = concurrent_service::Service;
+            service.serve_concurrent(Transport::new(s, r), options).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut)).0
+    }
+
+    #[test]
+    fn test_error_mapping() {
+        use error_service::{Request, Response, DivError};
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = error_service::Client::new(client_transport);
+            assert_eq!(client.div(12, 4).await, Ok(Ok(3)));
+            assert_eq!(client.div(12, 0).await, Ok(Err(DivError::DivByZero)));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            error_service::Service.serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_method_error() {
+        use simple_service_2::{Request, Response};
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service_2::Client::new(client_transport);
+            assert_eq!(client.div(2.0).await, Ok(Ok(0.5)));
+            assert_eq!(client.div(0.0).await, Ok(Err(())));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            simple_service_2::Service::new().serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_encoded_size_hint() {
+        use crate::rpc::codec::{serialized_size, EncodedSize};
+        fn check<T: EncodedSize+Serialize>(value: T) {
+            assert_eq!(value.encoded_size_hint(), serialized_size(&value));
+        }
+        check(simple_service::Request::Add(1));
+        check(simple_service::Request::Get());
+        check(simple_service::Request::__Cancel(1));
+        check(simple_service::Response::Clear);
+        check(simple_service::Response::Add(1));
+        check(simple_service::Response::__Denied(Some(1), String::from("denied")));
+        check(concurrent_service::Request::EchoUnordered(1, 2, 3));
+        check(concurrent_service::Response::EchoUnordered(1, 2));
+        check(error_service::Response::Div(3));
+    }
+
+    #[test]
+    fn test_error_response() {
+        use error_service::{Request, Response};
+        use crate::rpc::message::{Error, RemoteError};
+        let (server_transport, mut client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            // requests calling none of the methods are answered with an error
+            client_transport.send(Request::_Phantom(std::marker::PhantomData)).await.unwrap();
+            client_transport.send(Request::Div(12, 4)).await.unwrap();
+            client_transport.send(Request::Div(12, 0)).await.unwrap();
+            assert!(matches!(client_transport.next().await,
+                             Some(Response::__Error(None, Error::ActionNotFound))));
+            assert!(matches!(client_transport.next().await, Some(Response::Div(3))));
+            // methods' errors are sent through the error response
+            match client_transport.next().await {
+                Some(Response::__Error(None, error)) =>
+                    assert_eq!(error.method_error(), Some(RemoteError::new(1, "division by zero"))),
+                _ => panic!("expected method's error"),
+            }
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            error_service::Service.serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    fn echo_requests() -> Vec<concurrent_service::Request> {
+        use concurrent_service::Request;
+        vec![Request::Echo(1, 20), Request::Echo(2, 10), Request::Echo(3, 0)]
+    }
+
+    #[test]
+    fn test_serve_concurrent_ordered() {
+        let options = ServeOptions { concurrency: 4, ordered: true };
+        assert_eq!(run_concurrent(options, echo_requests()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_serve_concurrent_unordered() {
+        let options = ServeOptions { concurrency: 4, ordered: false };
+        assert_eq!(run_concurrent(options, echo_requests()), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_serve_concurrent_bounded() {
+        // only one request at once: responses are sent in requests order
+        let options = ServeOptions { concurrency: 1, ordered: false };
+        assert_eq!(run_concurrent(options, echo_requests()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_serve_concurrent_unordered_method() {
+        use concurrent_service::Request;
+        let options = ServeOptions { concurrency: 4, ordered: true };
+        let requests = vec![Request::Echo(1, 20), Request::EchoUnordered(0, 2, 10),
+                            Request::Echo(3, 0)];
+        assert_eq!(run_concurrent(options, requests), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn test_serve_concurrent_streaming() {
+        use streaming_service::{Request, Response};
+        let (server_transport, mut client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            // responses are sent as they are produced, in requests order
+            client_transport.send(Request::Count(u32::MAX)).await.unwrap();
+            client_transport.send(Request::Start()).await.unwrap();
+            for i in 0..3 {
+                assert!(matches!(client_transport.next().await, Some(Response::CountChunk(item)) if item == i));
+            }
+        };
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = streaming_service::Service { start: 0 };
+            service.serve_concurrent(Transport::new(s, r), ServeOptions::default()).await;
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        let (server_transport, mut client_transport) = MPSCTransport::<Response, Request>::bi(8);
+        let client_fut = async move {
+            client_transport.send(Request::Count(2)).await.unwrap();
+            client_transport.send(Request::Start()).await.unwrap();
+            client_transport.sender.close_channel();
+            let responses = client_transport.collect::<Vec<_>>().await;
+            assert!(matches!(responses.as_slice(), [Response::CountChunk(0), Response::CountChunk(1),
+                                                    Response::CountEnd, Response::Start(0)]));
+        };
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = streaming_service::Service { start: 0 };
+            service.serve_concurrent(Transport::new(s, r), ServeOptions::default()).await;
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_cancel() {
+        use concurrent_service::{Request, Response};
+
+        for concurrent in [false, true] {
+            let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+            let client_fut = async move {
+                let mut client = concurrent_service::Client::new(client_transport);
+                let pending = client.start_echo_unordered(1, u32::MAX).await.unwrap();
+                pending.cancel().await.unwrap();
+                // cancelled call's error is skipped
+                assert_eq!(client.echo_unordered(2, 0).await, Ok(2));
+
+                // dropped calls are cancelled
+                drop(client.start_echo_unordered(3, u32::MAX).await.unwrap());
+                let pending = client.start_echo_unordered(4, 0).await.unwrap();
+                assert_eq!(pending.response().await, Ok(4));
+                assert_eq!(client.echo(5, 0).await, Ok(5));
+            };
+            let server_fut = async move {
+                let (s,r) = server_transport.split();
+                let mut service = CancellationSafe::new(concurrent_service::Service);
+                match concurrent {
+                    true => service.serve_concurrent(Transport::new(s, r), ServeOptions::default()).await,
+                    false => service.serve(Transport::new(s, r)).await,
+                }
+            };
+            LocalPool::new().run_until(join(client_fut, server_fut));
+        }
+    }
+
+    #[test]
+    fn test_cancel_unsafe() {
+        use concurrent_service::{Request, Response};
+
+        for concurrent in [false, true] {
+            let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+            let client_fut = async move {
+                let mut client = concurrent_service::Client::new(client_transport);
+                // call is not aborted, but still answered as cancelled
+                let pending = client.start_echo_unordered(1, 100).await.unwrap();
+                pending.cancel().await.unwrap();
+                assert_eq!(client.echo_unordered(2, 0).await, Ok(2));
+                assert_eq!(client.echo(3, 0).await, Ok(3));
+            };
+            let server_fut = async move {
+                let (s,r) = server_transport.split();
+                let mut service = concurrent_service::Service;
+                match concurrent {
+                    true => service.serve_concurrent(Transport::new(s, r), ServeOptions::default()).await,
+                    false => service.serve(Transport::new(s, r)).await,
+                }
+            };
+            LocalPool::new().run_until(join(client_fut, server_fut));
+        }
+    }
+
+    #[test]
+    fn test_streaming() {
+        use streaming_service::{Request, Response};
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = streaming_service::Client::new(client_transport);
+            let items = client.count(3).await.unwrap().collect::<Vec<_>>().await;
+            assert_eq!(items, vec![0, 1, 2]);
+            let items = client.count_from(10, 2).await.unwrap().collect::<Vec<_>>().await;
+            assert_eq!(items, vec![10, 11]);
+            assert_eq!(client.start().await, Ok(10));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = streaming_service::Service { start: 0 };
+            service.serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_incoming() {
+        use incoming_service::{Request, Response};
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = incoming_service::Client::new(client_transport);
+            let mut sink = client.sum(10).await.unwrap();
+            sink.send(1).await.unwrap();
+            sink.send_all(futures::stream::iter(vec![2, 3])).await.unwrap();
+            assert_eq!(sink.finish().await, Ok(16));
+
+            let mut sink = client.double().await.unwrap();
+            sink.send_all(futures::stream::iter(vec![1, 2, 3])).await.unwrap();
+            let items = sink.finish().await.unwrap().collect::<Vec<_>>().await;
+            assert_eq!(items, vec![2, 4, 6]);
+            assert_eq!(client.total().await, Ok(16));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = incoming_service::Service { total: 0 };
+            service.serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_desynced() {
+        use crate::rpc::call::CallError;
+        let (server_transport, client_transport) = MPSCTransport::<streaming_service::Response, streaming_service::Request>::bi(8);
+        let client_fut = async move {
+            let mut client = streaming_service::Client::new(client_transport);
+            let mut items = client.count(3).await.unwrap();
+            assert_eq!(items.next().await, Some(0));
+            drop(items);
+            assert_eq!(client.start().await, Err(CallError::Desynced));
+        };
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            streaming_service::Service { start: 0 }.serve(Transport::new(s, r)).await;
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        let (server_transport, client_transport) = MPSCTransport::<incoming_service::Response, incoming_service::Request>::bi(8);
+        let client_fut = async move {
+            let mut client = incoming_service::Client::new(client_transport);
+            // sink is dropped before it is finished
+            client.sum(10).await.unwrap().send(1).await.unwrap();
+            assert_eq!(client.total().await, Err(CallError::Desynced));
+        };
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            incoming_service::Service { total: 0 }.serve(Transport::new(s, r)).await;
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
     #[test]
     fn test_request_response() {
         let (server_transport, client_transport) = MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
@@ -174,7 +1218,7 @@ pub mod tests {
             let mut client = simple_service::Client::new(client_transport);
             assert_eq!(client.add(13).await, Ok(13));
             assert_eq!(client.sub(1).await, Ok(12));
-            client.clear().await;
+            assert_eq!(client.clear().await, Ok(()));
             assert_eq!(client.get().await, Ok(0));
         };
 
@@ -187,6 +1231,131 @@ pub mod tests {
 
         LocalPool::new().run_until(join(client_fut, server_fut));
     }
-}
 
+    #[test]
+    fn test_serve_local() {
+        let (mut client, server_fut) = simple_service::Service::new()
+            .serve_local::<simple_service::Client<_,_>>(8);
+        let client_fut = async move {
+            assert_eq!(client.add(13).await, Ok(13));
+            assert_eq!(client.clear().await, Ok(()));
+            assert_eq!(client.sub(0).await, Ok(0));
+            // server stops once client is dropped
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (server_transport, client_transport) = MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service::Client::new(client_transport);
+            let caps = client.__capabilities().await.unwrap();
+            assert_eq!(caps.methods, vec![(String::from("clear"), 0),
+                                          (String::from("add"), 1),
+                                          (String::from("sub"), 2),
+                                          (String::from("get"), 3)]);
+            assert_eq!(caps.capability, Capability::new(u64::MAX, 0));
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let mut service = simple_service::Service::new();
+            service.serve(Transport::new(s, r)).await;
+        };
+
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+
+    #[test]
+    fn test_required_capability() {
+        use incoming_service::{Request, Service};
 
+        // methods of the same `cap` share the bit of the first one's index
+        assert_eq!(Service::methods(), &[("sum", 0), ("double", 0), ("total", 4)]);
+        assert_eq!(Request::Sum(1).required_capability(), Capability::new(0b1, 0));
+        assert_eq!(Request::SumChunk(1).required_capability(), Capability::new(0b1, 0));
+        assert_eq!(Request::Double().required_capability(), Capability::new(0b1, 0));
+        assert_eq!(Request::Total().required_capability(), Capability::new(0b10000, 0));
+        assert_eq!(Request::__Cancel(0).required_capability(), Capability::empty());
+
+        assert_eq!(Service::action_names(), &["write", "", "", "", "total"]);
+        let cap = Request::Total().required_capability();
+        assert_eq!(format!("{:?}", cap.with_names(Service::action_names())),
+                   "Capability { actions: total, share: 0 }");
+
+        assert_eq!(simple_service::Request::Sub(1).required_capability(), Capability::new(0b0100, 0));
+    }
+
+    #[test]
+    fn test_wide_capability() {
+        use crate::data::WideCapability;
+        use crate::rpc::enforce::{Enforced, Origin};
+        use wide_service::{Request, Response, Service};
+
+        assert_eq!(Service::methods(), &[("get", 0), ("admin", 100)]);
+        assert_eq!(Request::Admin().required_capability(), WideCapability::from_indexes(&[100], &[]));
+
+        let cap = WideCapability::from_indexes(&[0], &[]);
+        let mut service = Enforced::new(Service { cap }, Origin::new("wide"));
+        LocalPool::new().run_until(async {
+            assert!(matches!(service.dispatch(Request::Get()).await, Some(Response::Get(1))));
+            assert!(matches!(service.dispatch(Request::Admin()).await, Some(Response::__Denied(..))));
+            match service.dispatch(Request::__Capabilities).await {
+                Some(Response::__Capabilities(caps)) => assert_eq!(caps.capability, cap),
+                _ => panic!("capabilities expected"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_schema() {
+        let (mut client, server_fut) = simple_service::Service::new()
+            .serve_local::<simple_service::Client<_,_>>(8);
+        let (mut client_2, server_fut_2) = simple_service_2::Service::new()
+            .serve_local::<simple_service_2::Client<_,_>>(8);
+        let client_fut = async move {
+            assert_eq!(client.validate_schema().await, Ok(()));
+            let schema = client.__schema().await.unwrap();
+            assert_eq!(schema.methods, vec!["fn clear()", "fn add(u32) -> u32", "fn sub(u32) -> u32",
+                                            "fn get() -> u32"]);
+
+            // simple_service_2's server, as seen by a simple_service's client
+            let server = client_2.__schema().await.unwrap();
+            let mismatch = server.validate(&schema).unwrap_err();
+            assert_eq!(mismatch.changes.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert_eq!(mismatch.changes[0].server.as_deref(), Some("fn mul(f32) -> f32"));
+            drop((client, client_2));
+        };
+        LocalPool::new().run_until(join3(client_fut, server_fut, server_fut_2));
+    }
+
+    #[test]
+    fn test_features() {
+        assert!(simple_service::Service::features().is_empty());
+        assert_eq!(concurrent_service::Service::features(), vec![features::CANCELLATION]);
+        assert_eq!(streaming_service::Service::features(), vec!["resumable", features::STREAMING]);
+        assert!(streaming_service::Service::supports("resumable"));
+        assert!(!streaming_service::Service::supports(features::CANCELLATION));
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        use rpccaps::rpc::call::{CallError, ClientOptions};
+        let (server_transport, client_transport) = MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+
+        runtime.block_on(async move {
+            // server never responds
+            let timeout = std::time::Duration::from_millis(10);
+            let options = ClientOptions::default().with_request_timeout(timeout);
+            let mut client = simple_service::Client::with_options(client_transport, options);
+            assert_eq!(client.get().await, Err(CallError::Timeout));
+            // response may still come: further calls are refused
+            assert!(matches!(client.__capabilities().await, Err(CallError::Desynced)));
+            assert_eq!(client.get().await, Err(CallError::Desynced));
+            drop(server_transport);
+        });
+    }
+}
@@ -0,0 +1,187 @@
+//! Stable ids of services' methods on the wire.
+//!
+//! By default, requests and responses are encoded by variant index, i.e.
+//! by methods' position in the `impl` block: adding a method in the middle
+//! of it silently breaks peers built before. Services declared with
+//! `#[service(stable_ids)]` assign an explicit id to each of their methods,
+//! which requests and responses are encoded with instead:
+//!
+//! ```ignore
+//! #[service(stable_ids)]
+//! impl Service {
+//!     #[rpc(id=1)]
+//!     fn put(&mut self, key: String, value: Vec<u8>) { ... }
+//!     #[rpc(id=3)]        // added later
+//!     fn touch(&mut self, key: String) { ... }
+//!     #[rpc(id=2)]
+//!     fn get(&mut self, key: String) -> Option<Vec<u8>> { ... }
+//! }
+//! ```
+//!
+//! Messages are encoded as the `(id, fields)` tuple, which takes the same
+//! size as variant-indexed ones with bincode. Chunks and ends of streaming
+//! methods are identified by the method's id flagged with `CHUNK` and `END`,
+//! implicit variants by ids at and above `IMPLICIT`. Thus methods' ids must
+//! be lower than `MAX_ID`.
+//!
+//! Messages of unknown ids, such as requests to methods the service does
+//! not have yet, are decoded as `__Unknown(id)` variants: the service
+//! responds to such requests with a `message::Error::ActionNotFound`,
+//! instead of failing the stream. Likewise, services' schemas list methods
+//! by id (see `rpc::schema`): clients only fail on methods whose signature
+//! changed, not on reordered ones.
+use serde::de::{self, Deserialize, IgnoredAny, SeqAccess};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
+
+/// Maximum method id (excluded).
+pub const MAX_ID: u32 = 1 << 24;
+/// Flag of streamed chunks' ids.
+pub const CHUNK: u32 = 1 << 24;
+/// Flag of streams' ends ids.
+pub const END: u32 = 2 << 24;
+/// First id of implicit variants.
+pub const IMPLICIT: u32 = 0xff00_0000;
+
+/// Id of `__Capabilities` variants.
+pub const CAPABILITIES: u32 = IMPLICIT;
+/// Id of `__Cancel` requests.
+pub const CANCEL: u32 = IMPLICIT + 1;
+/// Id of `__Schema` variants.
+pub const SCHEMA: u32 = IMPLICIT + 2;
+/// Id of `__Denied` responses.
+pub const DENIED: u32 = IMPLICIT + 3;
+/// Id of `__SlowDown` responses.
+pub const SLOW_DOWN: u32 = IMPLICIT + 4;
+/// Id of `__Error` responses.
+pub const ERROR: u32 = IMPLICIT + 5;
+/// Id of `__Trace` requests.
+pub const TRACE: u32 = IMPLICIT + 6;
+
+
+/// Serialize message of provided id and fields.
+pub fn serialize<S: Serializer, T: Serialize>(serializer: S, id: u32, fields: &T)
+    -> Result<S::Ok, S::Error>
+{
+    let mut tuple = serializer.serialize_tuple(2)?;
+    tuple.serialize_element(&id)?;
+    tuple.serialize_element(fields)?;
+    tuple.end()
+}
+
+/// Read next item of a message: its id, then its fields.
+pub fn next<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(seq: &mut A, index: usize)
+    -> Result<T, A::Error>
+{
+    seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &"a message id and its fields"))
+}
+
+/// Skip fields of a message of unknown id. Formats that are not
+/// self-describing, such as bincode, can't skip them: messages being
+/// framed, they are left unread.
+pub fn skip<'de, A: SeqAccess<'de>>(seq: &mut A) {
+    let _ = seq.next_element::<IgnoredAny>();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::executor::LocalPool;
+
+    use crate as rpccaps;
+    use crate::rpc::codec::{BincodeCodec, Decoder, Encoder};
+    use crate::rpc::message::Error;
+    use crate::rpc::service::Service as _;
+    use crate::rpc::service::tests::simple_service;
+    use rpccaps_derive::*;
+
+    pub mod stable_service {
+        use super::*;
+
+        pub struct Service {
+            pub a: u32,
+        }
+
+        #[service(stable_ids)]
+        impl Service {
+            #[rpc(id=1)]
+            pub fn clear(&mut self) {
+                self.a = 0;
+            }
+
+            #[rpc(id=5)]
+            pub fn sub(&mut self, a: u32) -> u32 {
+                self.a -= a;
+                self.a
+            }
+
+            #[rpc(id=2)]
+            pub fn add(&mut self, a: u32) -> u32 {
+                self.a += a;
+                self.a
+            }
+
+            #[rpc(id=3)]
+            fn names(&mut self, #[rpc(max_len=2)] names: Vec<String>) -> usize {
+                names.len()
+            }
+        }
+    }
+
+    fn encode<T: serde::Serialize>(message: T) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        BincodeCodec::new().encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        use stable_service::{Request, Response};
+
+        // encoded by id, with the same size as by variant index
+        let data = bincode::serialize(&Request::Add(7)).unwrap();
+        assert_eq!(data, [2, 0, 0, 0, 7, 0, 0, 0]);
+        let request = BincodeCodec::<Request>::new().decode(&mut encode(Request::Sub(1))).unwrap();
+        assert!(matches!(request, Some(Request::Sub(1))));
+        let request = BincodeCodec::<Request>::new().decode(&mut encode(Request::__Cancel(4))).unwrap();
+        assert!(matches!(request, Some(Request::__Cancel(4))));
+        let response = BincodeCodec::<Response>::new().decode(&mut encode(Response::Clear)).unwrap();
+        assert!(matches!(response, Some(Response::Clear)));
+
+        let names = vec![String::from("a"); 3];
+        assert!(BincodeCodec::<Request>::new().decode(&mut encode(Request::Names(names))).is_err());
+
+        // index-encoded `Clear` of simple_service has id 4, following the
+        // implicit variants: unknown ones are rejected by the service
+        let mut service = stable_service::Service { a: 0 };
+        let mut data = encode(simple_service::Request::Clear());
+        let unknown = BincodeCodec::<Request>::new().decode(&mut data).unwrap().unwrap();
+        assert!(matches!(unknown, Request::__Unknown(4)));
+        let mut data = encode((12u32, 1u32));
+        let unknown = BincodeCodec::<Request>::new().decode(&mut data).unwrap().unwrap();
+        assert!(matches!(unknown, Request::__Unknown(12)));
+        assert!(data.is_empty());
+
+        LocalPool::new().run_until(async {
+            assert!(matches!(service.dispatch(unknown).await, Some(Response::__Error(None, Error::ActionNotFound))));
+            assert!(matches!(service.dispatch(Request::Add(3)).await, Some(Response::Add(3))));
+        });
+    }
+
+    #[cfg(feature="json")]
+    #[test]
+    fn test_stable_ids_json() {
+        use crate::rpc::codec::JsonCodec;
+        use stable_service::Request;
+
+        let mut buffer = BytesMut::new();
+        JsonCodec::new().encode(Request::Add(7), &mut buffer).unwrap();
+        assert_eq!(&buffer[8..], b"[2,[7]]");
+
+        let mut buffer = BytesMut::new();
+        JsonCodec::new().encode((9u32, ("a", [1, 2])), &mut buffer).unwrap();
+        let unknown = JsonCodec::<Request>::new().decode(&mut buffer).unwrap();
+        assert!(matches!(unknown, Some(Request::__Unknown(9))));
+    }
+}
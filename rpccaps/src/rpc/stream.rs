@@ -0,0 +1,270 @@
+//! Streamed requests and responses of streaming methods, and their caller's
+//! authorization checks while they are produced.
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::task::{Context,Poll};
+use serde::{Deserialize,Serialize};
+
+use crate::data::{Clock, SystemClock};
+use super::call::CallError;
+
+
+/// Return type of server-streaming methods.
+///
+/// On the wire, each item is sent as a `Response::{Method}Chunk`, the
+/// stream's end being signaled by a `Response::{Method}End`.
+pub struct Streaming<T>(BoxStream<'static, T>);
+
+impl<T> Streaming<T> {
+    pub fn new(stream: impl Stream<Item=T>+Send+'static) -> Self {
+        Self(stream.boxed())
+    }
+}
+
+impl<T> Stream for Streaming<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+
+/// Argument type of client-streaming methods.
+///
+/// On the wire, each item is sent as a `Request::{Method}Chunk`, the
+/// stream's end being signaled by a `Request::{Method}End`. Requests are
+/// not dispatched until the method returns: a method returning
+/// `Streaming<T>` (bidirectional) sends its responses once it has returned.
+/// Items that the method does not consume are dispatched as other requests
+/// and ignored.
+pub type Incoming<'a, T> = BoxStream<'a, T>;
+
+/// Stream of a streaming method's items, as returned by clients.
+pub type ClientStream<'a, T> = BoxStream<'a, T>;
+
+
+/// Client-side handle of a client-streaming method's call, sending its
+/// items before getting method's output with `finish()`.
+///
+/// Client's `synced` flag is unset until the output has been received: a
+/// sink dropped before leaves the client out of sync with its transport
+/// (see `CallError::Desynced`).
+pub struct ClientSink<'a, Tr, Req, T, O> {
+    transport: &'a mut Tr,
+    synced: &'a mut bool,
+    chunk: fn(T) -> Req,
+    end: Req,
+    finish: Finish<'a, Tr, O>,
+    timeout: Option<Duration>,
+}
+
+/// Function returning a client-streaming method's output, provided with
+/// client's `synced` flag and request timeout. It sets the flag back once
+/// the output has been received.
+pub type Finish<'a, Tr, O> = fn(&'a mut Tr, &'a mut bool, Option<Duration>)
+    -> BoxFuture<'a, Result<O,CallError>>;
+
+impl<'a, Tr, Req, T, O> ClientSink<'a, Tr, Req, T, O>
+    where Tr: Sink<Req>+Unpin
+{
+    pub fn new(transport: &'a mut Tr, synced: &'a mut bool, chunk: fn(T) -> Req, end: Req,
+               finish: Finish<'a, Tr, O>) -> Self
+    {
+        *synced = false;
+        Self { transport, synced, chunk, end, finish, timeout: None }
+    }
+
+    /// Set timeout waiting for method's output.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send an item.
+    pub async fn send(&mut self, item: T) -> Result<(),CallError> {
+        self.transport.send((self.chunk)(item)).await.or(Err(CallError::Failed))
+    }
+
+    /// Send all items of provided stream.
+    pub async fn send_all<S: Stream<Item=T>+Unpin>(&mut self, mut items: S) -> Result<(),CallError> {
+        while let Some(item) = items.next().await {
+            self.send(item).await?;
+        }
+        Ok(())
+    }
+
+    /// Signal the end of items, returning method's output.
+    pub async fn finish(self) -> Result<O,CallError> {
+        self.transport.send(self.end).await.or(Err(CallError::Failed))?;
+        (self.finish)(self.transport, self.synced, self.timeout).await
+    }
+}
+
+/// Return responses of a streaming method: one per item, then `end`.
+pub fn respond<T, R, F>(stream: Streaming<T>, chunk: F, end: R) -> BoxStream<'static, R>
+    where T: 'static, R: Send+'static, F: Fn(T) -> R+Send+'static
+{
+    stream.map(chunk).chain(stream::once(future::ready(end))).boxed()
+}
+
+/// Return stream of items received on `transport`, until `chunk` returns
+/// `None` for a response (at stream's end or for unexpected responses).
+pub fn receive<'a, Tr, R, T, F>(transport: &'a mut Tr, chunk: F) -> ClientStream<'a, T>
+    where Tr: Stream<Item=R>+Unpin+Send+?Sized, T: Send+'a, F: Fn(R) -> Option<T>+Send+Sync+'a
+{
+    stream::unfold((transport, chunk), |(transport, chunk)| async move {
+        let item = chunk(transport.next().await?)?;
+        Some((item, (transport, chunk)))
+    }).boxed()
+}
+
+/// Return stream of a call's items received on `transport`, as `receive()`
+/// does. Client's `synced` flag is unset until the stream ends: a stream
+/// dropped before leaves the client out of sync with its transport (see
+/// `CallError::Desynced`).
+pub fn receive_call<'a, Tr, R, T, F>(transport: &'a mut Tr, synced: &'a mut bool, chunk: F)
+    -> ClientStream<'a, T>
+    where Tr: Stream<Item=R>+Unpin+Send+?Sized, T: Send+'a, F: Fn(R) -> Option<T>+Send+Sync+'a
+{
+    *synced = false;
+    stream::unfold((transport, synced, chunk), |(transport, synced, chunk)| async move {
+        match transport.next().await.and_then(&chunk) {
+            Some(item) => Some((item, (transport, synced, chunk))),
+            None => {
+                *synced = true;
+                None
+            },
+        }
+    }).boxed()
+}
+
+
+/// Reason of a stream's end.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum StreamEnd {
+    /// All items have been sent.
+    Complete,
+    /// Caller's capability has been revoked or expired while streaming.
+    Unauthorized,
+}
+
+
+/// Message of a streamed response.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum StreamItem<T> {
+    Item(T),
+    End(StreamEnd),
+}
+
+
+/// Stream re-checking caller's authorization at most every `interval`
+/// while items are produced, ending with `StreamEnd::Unauthorized` once
+/// the check fails.
+///
+/// Authorization is also checked before the first item, and the stream is
+/// always terminated by a `StreamItem::End`.
+pub struct Authorized<S, F, C=SystemClock> {
+    inner: S,
+    check: F,
+    clock: C,
+    interval: Duration,
+    checked_at: Option<Duration>,
+    done: bool,
+}
+
+impl<S, F> Authorized<S, F>
+    where S: Stream+Unpin, F: FnMut() -> bool+Unpin
+{
+    pub fn new(inner: S, check: F, interval: Duration) -> Self {
+        Self::with_clock(inner, check, interval, SystemClock)
+    }
+}
+
+impl<S, F, C> Authorized<S, F, C>
+    where S: Stream+Unpin, F: FnMut() -> bool+Unpin, C: Clock+Unpin
+{
+    /// Create stream using provided clock.
+    pub fn with_clock(inner: S, check: F, interval: Duration, clock: C) -> Self {
+        Self { inner, check, clock, interval, checked_at: None, done: false }
+    }
+
+    /// Return true if authorization check is due.
+    fn check_due(&self, now: Duration) -> bool {
+        match self.checked_at {
+            None => true,
+            Some(at) => now.saturating_sub(at) >= self.interval,
+        }
+    }
+}
+
+impl<S, F, C> Stream for Authorized<S, F, C>
+    where S: Stream+Unpin, F: FnMut() -> bool+Unpin, C: Clock+Unpin
+{
+    type Item = StreamItem<S::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.as_mut().get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let now = this.clock.now();
+        if this.check_due(now) {
+            this.checked_at = Some(now);
+            if !(this.check)() {
+                this.done = true;
+                return Poll::Ready(Some(StreamItem::End(StreamEnd::Unauthorized)));
+            }
+        }
+
+        match this.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(Some(StreamItem::Item(item))),
+            Poll::Ready(None) => {
+                this.done = true;
+                Poll::Ready(Some(StreamItem::End(StreamEnd::Complete)))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::clock::MockClock;
+
+    #[test]
+    fn test_authorized() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(10)));
+        let allowed = Arc::new(AtomicBool::new(true));
+        let allowed_ = allowed.clone();
+        let mut stream = Authorized::with_clock(stream::iter(0..10),
+                                                move || allowed_.load(Ordering::Relaxed),
+                                                Duration::from_secs(1), clock.clone());
+        block_on(async {
+            assert_eq!(stream.next().await, Some(StreamItem::Item(0)));
+
+            // not checked before interval elapsed
+            allowed.store(false, Ordering::Relaxed);
+            assert_eq!(stream.next().await, Some(StreamItem::Item(1)));
+
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(stream.next().await, Some(StreamItem::End(StreamEnd::Unauthorized)));
+            assert_eq!(stream.next().await, None);
+        });
+
+        let stream = Authorized::new(stream::iter(0..2), || true, Duration::from_secs(1));
+        assert_eq!(block_on(stream.collect::<Vec<_>>()),
+                   vec![StreamItem::Item(0), StreamItem::Item(1), StreamItem::End(StreamEnd::Complete)]);
+    }
+}
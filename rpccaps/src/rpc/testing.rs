@@ -0,0 +1,242 @@
+//! Scripted transport to unit test client code without a server.
+//!
+//! A `TestTransport` plugs into generated clients' `Client::new` in place of
+//! a connection's transport: it returns scripted responses, and can inject
+//! delays, decode errors and disconnects between them.
+//!
+//! ```ignore
+//! let transport = TestTransport::new()
+//!     .respond(Response::Get(12))
+//!     .delay(Duration::from_millis(100))
+//!     .decode_error();
+//! let sent = transport.sent();
+//! let mut client = my_service::Client::new(transport);
+//! ```
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+
+
+/// A step of a `TestTransport`'s script.
+pub enum Scripted<Resp> {
+    /// Receive a response.
+    Response(Resp),
+    /// Wait before handling next step.
+    Delay(Duration),
+    /// Fail to decode a response: as for `Framed`, the stream of responses
+    /// ends while requests can still be sent.
+    DecodeError,
+    /// Close the connection: responses end and sending requests fails.
+    Disconnect,
+}
+
+
+/// Requests sent over a `TestTransport`, shared with its tests.
+pub type Sent<Req> = Arc<Mutex<Vec<Req>>>;
+
+
+/// Transport returning scripted responses, and recording sent requests.
+///
+/// Once its script is exhausted, it behaves as a server that never responds.
+/// Delays are driven by tokio's timer, thus requiring a tokio runtime.
+pub struct TestTransport<Req, Resp> {
+    script: VecDeque<Scripted<Resp>>,
+    sent: Sent<Req>,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+    ended: bool,
+    disconnected: bool,
+}
+
+impl<Req, Resp> Default for TestTransport<Req, Resp> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Req, Resp> TestTransport<Req, Resp> {
+    pub fn new() -> Self {
+        Self { script: VecDeque::new(), sent: Default::default(), sleep: None,
+               ended: false, disconnected: false }
+    }
+
+    /// Append a step to the script.
+    pub fn push(mut self, step: Scripted<Resp>) -> Self {
+        self.script.push_back(step);
+        self
+    }
+
+    /// Append a response to the script.
+    pub fn respond(self, response: Resp) -> Self {
+        self.push(Scripted::Response(response))
+    }
+
+    /// Append a delay to the script.
+    pub fn delay(self, duration: Duration) -> Self {
+        self.push(Scripted::Delay(duration))
+    }
+
+    /// Append a decode error to the script.
+    pub fn decode_error(self) -> Self {
+        self.push(Scripted::DecodeError)
+    }
+
+    /// Append a disconnection to the script.
+    pub fn disconnect(self) -> Self {
+        self.push(Scripted::Disconnect)
+    }
+
+    /// Return requests sent so far, kept once transport has been moved into
+    /// a client.
+    pub fn sent(&self) -> Sent<Req> {
+        self.sent.clone()
+    }
+
+    /// Remaining steps of the script.
+    pub fn remaining(&self) -> usize {
+        self.script.len()
+    }
+}
+
+impl<Req, Resp> Unpin for TestTransport<Req, Resp> {}
+
+impl<Req, Resp> Stream for TestTransport<Req, Resp> {
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Resp>> {
+        let this = self.as_mut().get_mut();
+        loop {
+            if this.ended || this.disconnected {
+                return Poll::Ready(None);
+            }
+            if let Some(sleep) = this.sleep.as_mut() {
+                futures::ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match this.script.pop_front() {
+                Some(Scripted::Response(response)) => return Poll::Ready(Some(response)),
+                Some(Scripted::Delay(duration)) =>
+                    this.sleep = Some(Box::pin(tokio::time::sleep(duration))),
+                Some(Scripted::DecodeError) => this.ended = true,
+                Some(Scripted::Disconnect) => this.disconnected = true,
+                None => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<Req, Resp> Sink<Req> for TestTransport<Req, Resp> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match self.disconnected {
+            true => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            false => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Req) -> Result<(), Self::Error> {
+        if self.disconnected {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+        self.sent.lock().unwrap().push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_ready(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as rpccaps;
+    use crate::rpc::call::{CallError, ClientOptions};
+    use rpccaps_derive::*;
+
+    // only service's client and messages are used
+    #[allow(dead_code)]
+    mod counter_service {
+        use super::*;
+
+        pub struct Service {
+            value: u32,
+        }
+
+        #[service]
+        impl Service {
+            fn add(&mut self, value: u32) -> u32 {
+                self.value += value;
+                self.value
+            }
+        }
+    }
+
+    use counter_service::{Client, Request, Response};
+
+    fn run<F: Future>(fut: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap().block_on(fut)
+    }
+
+    #[test]
+    fn test_responses() {
+        let transport = TestTransport::new().respond(Response::Add(3)).respond(Response::Add(5));
+        let sent = transport.sent();
+        let mut client = Client::new(transport);
+        run(async {
+            assert_eq!(client.add(3).await, Ok(3));
+            assert_eq!(client.add(2).await, Ok(5));
+        });
+        let sent = sent.lock().unwrap();
+        assert!(matches!(sent.as_slice(), [Request::Add(3), Request::Add(2)]));
+    }
+
+    #[test]
+    fn test_delay() {
+        let transport = TestTransport::new().delay(Duration::from_millis(50)).respond(Response::Add(1));
+        let options = ClientOptions::default().with_request_timeout(Duration::from_millis(10));
+        let mut client = Client::with_options(transport, options);
+        run(async {
+            assert_eq!(client.add(1).await, Err(CallError::Timeout));
+            // late response must not be taken as the next call's one
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(client.add(1).await, Err(CallError::Desynced));
+        });
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let transport = TestTransport::new().decode_error().respond(Response::Add(1));
+        let sent = transport.sent();
+        let mut client = Client::new(transport);
+        run(async {
+            assert_eq!(client.add(1).await, Err(CallError::Failed));
+            assert_eq!(client.add(1).await, Err(CallError::Failed));
+        });
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let transport = TestTransport::new().respond(Response::Add(1)).disconnect();
+        let sent = transport.sent();
+        let mut client = Client::new(transport);
+        run(async {
+            assert_eq!(client.add(1).await, Ok(1));
+            assert_eq!(client.add(1).await, Err(CallError::Failed));
+            // requests can't be sent anymore
+            assert_eq!(client.add(1).await, Err(CallError::Failed));
+        });
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,251 @@
+//! Per-service maximum request rate.
+//!
+//! A service registered with `HandlerOptions::max_rate` is wrapped into a
+//! `Throttled` service, sharing a token bucket among all its instances
+//! (thus among connections). Requests exceeding the rate are not
+//! dispatched: they are answered with the service's `slow_down()` response
+//! (`Response::__SlowDown` for generated services), and generated clients
+//! wait for its `retry_after` before calling the method again.
+//!
+//! Notifications exceeding the rate are dropped, since the client does not
+//! read their response.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use super::admission::{RateLimit, TokenBucket};
+use super::message::Error;
+use super::protocol::{Call, Frame, Reply};
+use super::service::Service;
+
+
+/// Token bucket shared among throttled services' instances.
+#[derive(Clone)]
+pub struct Throttle(Arc<Mutex<TokenBucket>>);
+
+impl Throttle {
+    pub fn new(limit: RateLimit) -> Self {
+        Self(Arc::new(Mutex::new(TokenBucket::new(limit))))
+    }
+
+    /// Take a token for a request, returning the duration to wait before
+    /// the next one when there is none.
+    pub fn check(&self) -> Option<Duration> {
+        let mut bucket = self.0.lock().unwrap();
+        match bucket.try_acquire() {
+            true => None,
+            false => Some(bucket.retry_after()),
+        }
+    }
+}
+
+
+/// Service answering requests above its throttle's rate with a slow down.
+pub struct Throttled<S: Service> {
+    inner: S,
+    throttle: Throttle,
+}
+
+impl<S: Service> Throttled<S> {
+    pub fn new(inner: S, throttle: Throttle) -> Self {
+        Self { inner, throttle }
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Check request against the rate, returning the response to send
+    /// instead of dispatching it. Only methods' calls are counted.
+    pub fn check(&self, request: &S::Request) -> Option<Option<S::Response>> {
+        S::method_index(request)?;
+        let retry_after = self.throttle.check()?;
+        match Self::call(request) {
+            Some(Call { reply: Reply::None, .. }) => Some(None),
+            _ => Some(S::slow_down(retry_after)),
+        }
+    }
+
+    /// Request's call, as classified by `request_frame()`. Since requests
+    /// go through the dispatch methods in turn, each one only checks the
+    /// calls it handles, so that requests are counted once.
+    fn call(request: &S::Request) -> Option<Call> {
+        match S::request_frame(request) {
+            Some(Frame::Request(call)) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+impl<S: Service+Clone> Clone for Throttled<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), throttle: self.throttle.clone() }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for Throttled<S>
+    where S::Response: 'static
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let call = Self::call(&request);
+        if matches!(call, Some(Call { incoming: true, .. } | Call { reply: Reply::Stream, .. })) {
+            return self.inner.dispatch(request).await
+        }
+        match self.check(&request) {
+            Some(response) => response,
+            None => self.inner.dispatch(request).await,
+        }
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: false, reply: Reply::Stream })) {
+            return self.inner.dispatch_streaming(request).await
+        }
+        match self.check(&request) {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => self.inner.dispatch_streaming(request).await,
+        }
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: true, .. })) {
+            return self.inner.dispatch_incoming(request, requests).await
+        }
+        match self.check(&request) {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => self.inner.dispatch_incoming(request, requests).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join;
+
+    use super::*;
+    use crate::rpc::call::CallError;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    #[test]
+    fn test_throttled() {
+        let throttle = Throttle::new(RateLimit::new(20.0, 2));
+        let mut service = Throttled::new(simple_service::Service::new(), throttle.clone());
+        futures::executor::block_on(async {
+            assert!(matches!(service.dispatch(simple_service::Request::Add(1)).await,
+                             Some(simple_service::Response::Add(1))));
+            // capabilities are not counted
+            assert!(service.dispatch(simple_service::Request::__Capabilities).await.is_some());
+
+            // bucket is shared among instances
+            let mut other = Throttled::new(simple_service::Service::new(), throttle);
+            assert!(other.dispatch(simple_service::Request::Add(1)).await.is_some());
+            match service.dispatch(simple_service::Request::Add(1)).await {
+                Some(simple_service::Response::__SlowDown(slow_down)) =>
+                    assert!(slow_down.retry_after <= Duration::from_millis(50)),
+                _ => panic!("request expected to be slowed down"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_client_backoff() {
+        let (server_transport, client_transport) =
+            MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service::Client::new(client_transport);
+            assert_eq!(client.add(1).await, Ok(1));
+            let retry_after = match client.add(1).await {
+                Err(CallError::SlowDown { retry_after }) => retry_after,
+                result => panic!("call expected to be slowed down: {:?}", result),
+            };
+            assert!(retry_after > Duration::ZERO);
+            assert!(client.backoff().remaining("add").is_some());
+
+            // next call of the method waits for the bucket to be refilled
+            assert_eq!(client.add(1).await, Ok(2));
+            assert!(client.backoff().remaining("add").is_none());
+        };
+
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            let throttle = Throttle::new(RateLimit::new(20.0, 1));
+            Throttled::new(simple_service::Service::new(), throttle)
+                .serve(Transport::new(s, r)).await;
+        };
+
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap()
+            .block_on(join(client_fut, server_fut));
+    }
+}
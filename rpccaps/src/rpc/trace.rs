@@ -0,0 +1,381 @@
+//! Tracing instrumentation, enabled by the `tracing` feature: spans of
+//! connections, of dispatched streams (with their service's id), of
+//! dispatched requests (with their method and duration), and of services'
+//! methods, along with error events on codec failures. Without the feature,
+//! spans are no-ops.
+//!
+//! Calls are traced across peers by their `TraceContext`: clients whose
+//! options enable `trace` send it in a `__Trace` request ahead of each call
+//! (see `version::TRACE`). Servers run the call's dispatch with it
+//! (`with_context`) under a random span id, and `#[service]` enters a
+//! `method` span recording both for the duration of unary methods' body.
+//! Calls made from a method get the same trace id, with the served call's
+//! span id as parent. Unlike `tracing`'s span ids, which are local to a
+//! process and reused, these ids can be matched across peers' logs.
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+pub use spans::*;
+
+
+/// Trace context of a call, sent by the client.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+pub struct TraceContext {
+    /// Id of the trace the call belongs to.
+    pub trace_id: u128,
+    /// Span id of the served call making this one (see `span_id()`), 0
+    /// if none.
+    pub parent: u64,
+}
+
+impl TraceContext {
+    /// Context of a new trace, without parent.
+    pub fn new() -> Self {
+        let trace_id = (OsRng.next_u64() as u128) << 64 | OsRng.next_u64() as u128;
+        Self { trace_id, parent: 0 }
+    }
+
+    /// Context of a call made from the current task: in the trace of the
+    /// request it serves if any, otherwise in a new trace.
+    pub fn current() -> Self {
+        match SERVED.with(Cell::get) {
+            Some((context, span_id)) => Self { trace_id: context.trace_id, parent: span_id },
+            None => Self::new(),
+        }
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+thread_local! {
+    /// Trace context and span id of the request served by the current task.
+    static SERVED: Cell<Option<(TraceContext, u64)>> = const { Cell::new(None) };
+}
+
+/// Return trace context of the request served by the current task.
+pub fn context() -> Option<TraceContext> {
+    SERVED.with(Cell::get).map(|(context, _)| context)
+}
+
+/// Return span id of the request served by the current task, parent of
+/// the calls it makes.
+pub fn span_id() -> Option<u64> {
+    SERVED.with(Cell::get).map(|(_, span_id)| span_id)
+}
+
+/// Run `future` with provided trace context, as returned by `context()`
+/// while it is polled, under a new span id. Without a context, `future`
+/// runs with the one of its caller.
+pub fn with_context<F: Future>(context: Option<TraceContext>, future: F) -> WithContext<F> {
+    let served = context.map(|context| (context, OsRng.next_u64()));
+    WithContext { served, future }
+}
+
+pin_project_lite::pin_project! {
+    /// Future running with a trace context (see `with_context`).
+    pub struct WithContext<F> {
+        served: Option<(TraceContext, u64)>,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        if this.served.is_none() {
+            return this.future.poll(cx);
+        }
+        let previous = SERVED.with(|cell| cell.replace(*this.served));
+        let poll = this.future.poll(cx);
+        SERVED.with(|cell| cell.set(previous));
+        poll
+    }
+}
+
+
+#[cfg(feature="tracing")]
+mod spans {
+    use std::fmt::{Debug, Display};
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use crate::rpc::service::Service;
+
+    pub use tracing::{Instrument, Span};
+
+    /// Value recorded on spans and events: with the feature, it must be
+    /// `Debug`.
+    pub trait SpanField: Debug {}
+
+    impl<T: Debug+?Sized> SpanField for T {}
+
+    /// Span of a connection with the peer at `address`.
+    pub fn connection(address: SocketAddr, id: usize) -> Span {
+        tracing::info_span!("connection", %address, id)
+    }
+
+    /// Span of a stream dispatched to the service of provided id.
+    pub fn stream<Id: SpanField>(id: &Id) -> Span {
+        tracing::info_span!("stream", service = ?id)
+    }
+
+    /// Span of a dispatched request, whose duration is recorded by
+    /// `elapsed()`.
+    pub fn request<S: Service+?Sized>(request: &S::Request) -> Span {
+        let method = S::method_index(request).and_then(|index| S::methods().get(index))
+                                              .map(|(name, _)| *name);
+        tracing::debug_span!("request", method, call_id = S::call_id(request),
+                             elapsed = tracing::field::Empty)
+    }
+
+    /// Span of a service's method body, recording the trace context and
+    /// span id of the request.
+    pub fn method(name: &'static str) -> Span {
+        let context = super::context();
+        tracing::debug_span!("method", name, trace_id = context.map(|c| tracing::field::display(c.trace_id)),
+                             parent = context.map(|c| c.parent), span_id = super::span_id())
+    }
+
+    /// Record duration of the request since `start` on its span.
+    pub fn elapsed(span: &Span, start: Instant) {
+        span.record("elapsed", tracing::field::debug(start.elapsed()));
+    }
+
+    /// Emit error event of a codec failing to encode or decode a message
+    /// of type `message_type`. It is emitted by the crate's codecs, which
+    /// know their errors.
+    pub fn codec_error(operation: &'static str, message_type: &'static str, error: &dyn Display) {
+        tracing::error!(operation, message_type, %error, "codec failure");
+    }
+}
+
+
+#[cfg(not(feature="tracing"))]
+mod spans {
+    use std::fmt::Display;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use crate::rpc::service::Service;
+
+    /// Value recorded on spans and events, of any type without the
+    /// feature.
+    pub trait SpanField {}
+
+    impl<T: ?Sized> SpanField for T {}
+
+    /// Disabled span.
+    #[derive(Clone,Debug)]
+    pub struct Span;
+
+    /// Futures' instrumentation, leaving them as is.
+    pub trait Instrument: Sized {
+        fn instrument(self, _span: Span) -> Self {
+            self
+        }
+    }
+
+    impl<T> Instrument for T {}
+
+    impl Span {
+        pub fn enter(&self) {}
+    }
+
+    pub fn connection(_address: SocketAddr, _id: usize) -> Span {
+        Span
+    }
+
+    pub fn stream<Id: SpanField>(_id: &Id) -> Span {
+        Span
+    }
+
+    pub fn request<S: Service+?Sized>(_request: &S::Request) -> Span {
+        Span
+    }
+
+    pub fn method(_name: &'static str) -> Span {
+        Span
+    }
+
+    pub fn elapsed(_span: &Span, _start: Instant) {}
+
+    pub fn codec_error(_operation: &'static str, _message_type: &'static str, _error: &dyn Display) {}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::{block_on, LocalPool};
+    use futures::future::join;
+
+    use crate as rpccaps;
+    use crate::rpc::call::ClientOptions;
+    use crate::rpc::service::Service as _;
+    use crate::rpc::transport::local::LocalClient;
+    use rpccaps_derive::*;
+    use super::*;
+
+    pub mod traced_service {
+        use super::*;
+
+        pub struct Service;
+
+        #[service]
+        impl Service {
+            fn trace_id(&mut self) -> Option<u128> {
+                context().map(|context| context.trace_id)
+            }
+
+            async fn parent(&mut self) -> Option<u64> {
+                context().map(|context| context.parent)
+            }
+        }
+    }
+
+    /// Subscriber recording spans and events, along with their fields.
+    #[cfg(feature="tracing")]
+    #[derive(Clone,Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<Record>>>);
+
+    #[cfg(feature="tracing")]
+    #[derive(Debug,Default)]
+    struct Record {
+        name: &'static str,
+        fields: Vec<(&'static str, String)>,
+    }
+
+    #[cfg(feature="tracing")]
+    impl Record {
+        fn field(&self, name: &str) -> Option<&str> {
+            self.fields.iter().find(|(field, _)| *field == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    #[cfg(feature="tracing")]
+    impl tracing::field::Visit for Record {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.fields.push((field.name(), format!("{:?}", value)));
+        }
+    }
+
+    #[cfg(feature="tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _metadata: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes) -> tracing::span::Id {
+            let mut record = Record { name: attrs.metadata().name(), ..Record::default() };
+            attrs.record(&mut record);
+            let mut records = self.0.lock().unwrap();
+            records.push(record);
+            tracing::span::Id::from_u64(records.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record) {
+            values.record(&mut self.0.lock().unwrap()[span.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event) {
+            let mut record = Record { name: "event", ..Record::default() };
+            event.record(&mut record);
+            self.0.lock().unwrap().push(record);
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature="tracing")]
+    #[test]
+    fn test_spans() {
+        use futures::StreamExt;
+        use crate::rpc::codec::{BincodeCodec, Framed};
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let _span = stream(&7u32);
+            let (mut client, server_fut) = traced_service::Service.serve_local::<traced_service::Client<_,_>>(8);
+            let client_fut = async move {
+                assert_eq!(client.trace_id().await, Ok(None));
+            };
+            LocalPool::new().run_until(join(client_fut, server_fut));
+
+            // frame of one byte, too short for an u32
+            let data = [1u8, 0, 0, 0, 0, 0, 0, 0, 1];
+            let mut framed = Framed::new(&data[..], BincodeCodec::<u32>::new());
+            assert_eq!(block_on(framed.next()), None);
+        });
+
+        let records = recorder.0.lock().unwrap();
+        let find = |name| records.iter().find(|record| record.name == name).unwrap();
+        assert_eq!(find("stream").field("service"), Some("7"));
+        let request = find("request");
+        assert_eq!(request.field("method"), Some("\"trace_id\""));
+        assert!(request.field("elapsed").is_some());
+        assert_eq!(find("method").field("name"), Some("\"trace_id\""));
+        let event = find("event");
+        assert_eq!(event.field("operation"), Some("\"decode\""));
+        assert!(event.field("error").is_some());
+    }
+
+    #[test]
+    fn test_with_context() {
+        let context = TraceContext { trace_id: 7, parent: 3 };
+        assert_eq!(super::context(), None);
+        let nested = block_on(with_context(Some(context), async {
+            assert_eq!(super::context(), Some(context));
+            // without a context, the caller's one is kept
+            let span_id = with_context(None, async { super::span_id() }).await;
+            assert_eq!(span_id, super::span_id());
+            futures::future::ready(()).await;
+            (TraceContext::current(), span_id)
+        }));
+        let (nested, span_id) = nested;
+        assert_eq!(nested.trace_id, 7);
+        assert_eq!(Some(nested.parent), span_id);
+        assert_eq!(super::context(), None);
+        assert_eq!(TraceContext::current().parent, 0);
+        assert_ne!(TraceContext::current().trace_id, 7);
+    }
+
+    #[test]
+    fn test_propagation() {
+        let (client, server_fut) = traced_service::Service.serve_local::<LocalClient<traced_service::Service>>(8);
+        let mut client = traced_service::Client::with_options(client, ClientOptions::default().with_trace());
+        let context = TraceContext { trace_id: 7, parent: 3 };
+
+        let client_fut = async move {
+            // calls made while serving a request are in its trace
+            let trace_id = with_context(Some(context), client.trace_id()).await;
+            assert_eq!(trace_id, Ok(Some(7)));
+            assert_eq!(client.parent().await.map(|parent| parent.is_some()), Ok(true));
+            let trace_id = client.trace_id().await.unwrap();
+            assert!(trace_id.is_some() && trace_id != Some(7));
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        // without the option, no context is sent
+        let (mut client, server_fut) = traced_service::Service.serve_local::<traced_service::Client<_,_>>(8);
+        let client_fut = async move {
+            assert_eq!(client.trace_id().await, Ok(None));
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+    }
+}
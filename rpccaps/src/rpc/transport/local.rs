@@ -0,0 +1,32 @@
+//! In-process transport of a service's requests and responses.
+//!
+//! Messages are moved over `MPSCTransport` channels without going through
+//! a codec, saving their (de)serialization for same-process composition
+//! and tests. As over the network, both ends check messages against the
+//! protocol.
+//!
+//! ```ignore
+//! let (mut client, server) = Service::new().serve_local::<Client<_,_>>(8);
+//! let (result, _) = join(client.add(1), server).await;
+//! ```
+use crate::rpc::protocol::{Checked, Peer};
+use crate::rpc::service::Service;
+use super::MPSCTransport;
+
+
+/// Server end of a local transport, for service `Sv`.
+pub type LocalServer<Sv> = Checked<MPSCTransport<<Sv as Service>::Response, <Sv as Service>::Request>,
+                                   <Sv as Service>::Request, <Sv as Service>::Response>;
+
+/// Client end of a local transport, for service `Sv`.
+pub type LocalClient<Sv> = Checked<MPSCTransport<<Sv as Service>::Request, <Sv as Service>::Response>,
+                                   <Sv as Service>::Response, <Sv as Service>::Request>;
+
+
+/// Return both ends of a local transport for service `Sv`, queuing up to
+/// `capacity` messages each way.
+pub fn pair<Sv: Service>(capacity: usize) -> (LocalServer<Sv>, LocalClient<Sv>) {
+    let (server, client) = MPSCTransport::bi(capacity);
+    (Checked::new(server, Peer::Server, Sv::request_frame, Sv::response_frame),
+     Checked::new(client, Peer::Client, Sv::response_frame, Sv::request_frame))
+}
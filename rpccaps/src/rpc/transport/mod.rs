@@ -8,6 +8,11 @@ use futures::prelude::*;
 use futures::task::{Context,Poll};
 use tokio::io::{AsyncRead,AsyncWrite,ReadBuf};
 
+use super::backpressure::{self, Capacity};
+
+pub mod local;
+#[cfg(feature="network")]
+pub mod tcp;
 
 
 /// Transport implementing `Stream+Sink` or `AsyncRead+AsyncWrite` depending
@@ -40,6 +45,20 @@ impl<S,R> Transport<S,R>
     }
 }
 
+impl<S: Capacity+Unpin, R> Transport<S,R>
+{
+    /// Poll for sender's room: pending while it is full, then how much can
+    /// be sent without waiting.
+    pub fn poll_capacity(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<usize>> {
+        Pin::new(&mut self.sender).poll_capacity(cx)
+    }
+
+    /// Wait for sender to have room, returning its capacity.
+    pub async fn capacity(&mut self) -> crate::Result<usize> {
+        backpressure::capacity(&mut self.sender).await
+    }
+}
+
 impl<S,R> Transport<mpsc::Sender<S>, mpsc::Receiver<R>>
 {
     /// Return new bidirectionnal mpsc transport.
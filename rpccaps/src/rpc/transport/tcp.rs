@@ -0,0 +1,948 @@
+//! TCP transport, optionally secured by TLS, as an alternative to QUIC on
+//! networks blocking UDP.
+//!
+//! TCP connections are not multiplexed: each stream opened by a client is
+//! a connection of its own. As over QUIC, it starts with a handshake and
+//! the service's id, then carries the service's messages; the server dispatches it with the
+//! same `Dispatch`, providing the connection's `TcpPeer` to builders. As
+//! TCP has no error codes, the server answers the stream's header with its
+//! status: `ADMITTED`, or the code of its `Rejection`. TLS
+//! is used when the transport is `TransportKind::TcpTls`. `AnyServer` and
+//! `AnyClient` use the transport selected by their configuration.
+//!
+//! Over TLS, connections have a channel binding as QUIC ones: services
+//! get it from `TcpPeer::channel_binding()`, and clients from
+//! `TcpClient::open_bound()`. Plain TCP connections have none.
+//!
+//! ```ignore
+//! config.connection_config.transport = TransportKind::TcpTls;
+//! let server = TcpServer::<u64>::new(config);
+//! server.dispatch.add_builder(1, Box::new(|_| my_service::Service::new()),
+//!                             HandlerOptions::default())?;
+//! server.listen(address).await?;
+//!
+//! let client = TcpClient::new(&client_config, address, "localhost")?;
+//! let transport = client.open::<_, my_service::Request, my_service::Response>(1u64).await?;
+//! ```
+use std::io;
+use std::fmt;
+use std::net::SocketAddr;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::io::{ReadHalf, WriteHalf};
+use futures::task::{Context, Poll};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::net;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+use crate::{ErrorKind, Result};
+use crate::data::Reference;
+use crate::data::presentation::{ChannelBinding, Presentation, ReferenceBundle};
+use crate::data::signature::SignMethod;
+use crate::data::validate::Validate;
+use crate::services::registry::Registry;
+use super::Transport;
+use super::super::admission::{Admission, ConnectionId, IpConnections, IpRates};
+use super::super::backpressure::Watch;
+use super::super::budget::Budgets;
+use super::super::client::Client;
+use super::super::codec::{BincodeCodec, CodecLimits, Framed};
+use super::super::config::{ClientConfig, ServerConfig, TransportKind};
+use super::super::context::CHANNEL_BINDING_LABEL;
+use super::super::dispatch::{Dispatch, HandlerOptions, Prioritize, Reject};
+use super::super::enforce::{fingerprint, Fingerprint};
+use super::super::events::{ServerEvent, ServerEvents};
+use super::super::handshake;
+use super::super::message::Rejection;
+use super::super::reaper::Reaper;
+use super::super::revocation::Revocations;
+use super::super::server::Server;
+use super::super::service::Service;
+use super::super::trace::{self, Instrument};
+#[cfg(feature="metrics")]
+use crate::services::metrics::Exporter;
+#[cfg(feature="metrics")]
+use super::super::metrics::Collector;
+
+
+/// Status of an admitted stream, sent as `u32` (little endian) as the
+/// codes of rejections.
+pub const ADMITTED: u32 = 0;
+
+
+/// Stream dispatched by a `TcpServer`.
+pub type TcpIncoming = (WriteHalf<TcpConnection>, ReadHalf<TcpConnection>, Arc<TcpPeer>);
+
+/// Transport of a stream opened by a `TcpClient`.
+pub type TcpTransport<Req, Resp> = Transport<Framed<WriteHalf<TcpConnection>, BincodeCodec<Req>>,
+                                             Framed<ReadHalf<TcpConnection>, BincodeCodec<Resp>>>;
+
+
+/// TCP connection, secured by TLS or not.
+pub enum TcpConnection {
+    Plain(Compat<net::TcpStream>),
+    Tls(Box<Compat<TlsStream<net::TcpStream>>>),
+}
+
+impl TcpConnection {
+    /// Accept connection of a client, completing TLS handshake when an
+    /// acceptor is provided.
+    pub async fn accept(stream: net::TcpStream, tls: Option<&TlsAcceptor>) -> Result<Self> {
+        stream.set_nodelay(true).or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        match tls {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => Ok(Self::Tls(Box::new(TlsStream::from(stream).compat()))),
+                Err(err) => ErrorKind::Endpoint.err(err.to_string()),
+            },
+            None => Ok(Self::Plain(stream.compat())),
+        }
+    }
+
+    /// Connect to server at provided address, securing connection by TLS
+    /// when a connector is provided.
+    pub async fn connect(address: SocketAddr, server_name: &str, tls: Option<&TlsConnector>)
+        -> Result<Self>
+    {
+        let stream = net::TcpStream::connect(address).await
+                        .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        stream.set_nodelay(true).or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        match tls {
+            Some(connector) => {
+                let server_name = rustls::ServerName::try_from(server_name)
+                    .or(ErrorKind::ValueError.err("invalid server name"))?;
+                match connector.connect(server_name, stream).await {
+                    Ok(stream) => Ok(Self::Tls(Box::new(TlsStream::from(stream).compat()))),
+                    Err(err) => ErrorKind::Endpoint.err(err.to_string()),
+                }
+            },
+            None => Ok(Self::Plain(stream.compat())),
+        }
+    }
+
+    /// Return channel binding material derived from connection's TLS
+    /// session, as `context::channel_binding()`. Fail over plain TCP.
+    pub fn channel_binding(&self) -> Result<ChannelBinding> {
+        match self {
+            Self::Plain(_) => ErrorKind::Unavailable.err("channel binding requires TLS"),
+            Self::Tls(stream) => {
+                let mut binding = [0u8;32];
+                let exported = match stream.get_ref() {
+                    TlsStream::Client(stream) =>
+                        stream.get_ref().1.export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, Some(b"")),
+                    TlsStream::Server(stream) =>
+                        stream.get_ref().1.export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, Some(b"")),
+                };
+                exported.or(ErrorKind::Internal.err("can not export channel binding"))?;
+                Ok(binding)
+            },
+        }
+    }
+
+    /// Return peer's information, as provided to services.
+    pub fn peer(&self, address: SocketAddr, connection: ConnectionId) -> TcpPeer {
+        let (certs, alpn_protocol) = match self {
+            Self::Plain(_) => (None, None),
+            Self::Tls(stream) => {
+                let (_, state) = stream.get_ref().get_ref();
+                (state.peer_certificates().map(<[_]>::to_vec), state.alpn_protocol().map(<[_]>::to_vec))
+            },
+        };
+        TcpPeer { address, connection, certs, alpn_protocol, channel_binding: self.channel_binding().ok(),
+                  revocations: None }
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_close(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_close(cx),
+        }
+    }
+}
+
+/// Connections carry a single stream, thus there is no priority among them.
+impl Prioritize for WriteHalf<TcpConnection> {
+    fn set_priority(&self, _priority: i32) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// TCP has no error code: the stream's status is sent before its
+/// messages, rejected connections being closed afterwards.
+#[async_trait]
+impl Reject for WriteHalf<TcpConnection> {
+    async fn reject(&mut self, rejection: Rejection) {
+        if self.write_all(&rejection.code().to_le_bytes()).await.is_ok() {
+            // flushed by closing
+            self.close().await.ok();
+        }
+    }
+
+    async fn admit(&mut self) -> Result<()> {
+        self.write_all(&ADMITTED.to_le_bytes()).await
+            .or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+        self.flush().await.or_else(|err| ErrorKind::IO.err(err.to_string()))
+    }
+}
+
+
+/// Peer of a TCP connection.
+#[derive(Clone)]
+pub struct TcpPeer {
+    /// Peer's address.
+    pub address: SocketAddr,
+    /// Id of the connection, among server's ones.
+    pub connection: ConnectionId,
+    /// Certificates chain presented by the peer over TLS, if any.
+    pub certs: Option<Vec<rustls::Certificate>>,
+    /// Application protocol negotiated by ALPN, if any.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Channel binding of the TLS session, if any.
+    channel_binding: Option<ChannelBinding>,
+    /// Server's revocations, recording held references.
+    revocations: Option<Arc<Revocations>>,
+}
+
+impl TcpPeer {
+    /// Return peer's end-entity certificate, identifying a client verified
+    /// against server's `client_roots`.
+    pub fn peer_certificate(&self) -> Option<&rustls::Certificate> {
+        self.certs.as_ref().and_then(|certs| certs.first())
+    }
+
+    /// Return channel binding material derived from the TLS session, as
+    /// `Context::channel_binding()`. Fail over plain TCP, whose peers can
+    /// not be authenticated by `Auth`, nor present references.
+    pub fn channel_binding(&self) -> Result<ChannelBinding> {
+        self.channel_binding.ok_or(ErrorKind::Unavailable.error("channel binding requires TLS"))
+    }
+
+    /// Validate a presentation made by the peer over this connection, as
+    /// `Context::validate_presentation()`.
+    pub fn validate_presentation<Id,Sign>(&self, presentation: &Presentation<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod+Serialize
+    {
+        let binding = self.channel_binding()?;
+        presentation.validate(&binding)
+                    .or(ErrorKind::InvalidData.err("invalid presentation"))?;
+        self.hold(presentation.reference())
+    }
+
+    /// Validate a reference bundle made by the peer over this connection,
+    /// as `Context::validate_bundle()`.
+    pub fn validate_bundle<Id,Sign>(&self, bundle: &ReferenceBundle<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod+Serialize
+    {
+        let binding = self.channel_binding()?;
+        bundle.validate(&binding)
+              .or(ErrorKind::InvalidData.err("invalid reference bundle"))?;
+        bundle.references().iter().try_for_each(|reference| self.hold(reference))
+    }
+
+    /// Record that the peer holds provided reference, so that the
+    /// connection is closed when it is revoked. Fail if it has been
+    /// revoked.
+    pub fn hold<Id,Sign>(&self, reference: &Reference<Id,Sign>) -> Result<()>
+        where Id: Clone+Serialize, Sign: SignMethod
+    {
+        let revocations = match self.revocations {
+            Some(ref revocations) => revocations,
+            None => return Ok(()),
+        };
+        let id = reference.object_id().or(ErrorKind::InvalidData.err("invalid reference"))?;
+        revocations.hold(self.connection, id)
+    }
+}
+
+impl fmt::Debug for TcpPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpPeer").field("address", &self.address)
+         .field("connection", &self.connection).field("certs", &self.certs)
+         .field("alpn_protocol", &self.alpn_protocol).finish()
+    }
+}
+
+
+/// Delay before accepting connections again after the listener failed,
+/// e.g. when running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Return true if accept error only concerns the incoming connection.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                         | io::ErrorKind::ConnectionRefused)
+}
+
+/// Task aborted when dropped.
+struct Spawned(tokio::task::JoinHandle<()>);
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+
+/// Server dispatching streams received over TCP to services, using Bincode
+/// for messages' de-serialization.
+///
+/// Connections are filtered and limited as on `Server`. Each one being a
+/// single stream, streams are rate limited per remote IP, before the TLS
+/// handshake. Without control stream, connections holding a revoked
+/// reference are closed.
+pub struct TcpServer<Id=u64>
+    where Id: Ord
+{
+    /// Services dispatch.
+    pub dispatch: Arc<Dispatch<Id, TcpIncoming>>,
+    /// Server configuration, whose transport must be over TCP.
+    pub config: ServerConfig,
+    /// Server events' subscriptions.
+    pub events: Arc<ServerEvents>,
+    /// Cleanup of expired state, running every `config.reap_interval`
+    /// while listening. Other targets can be registered to it.
+    pub reaper: Arc<Reaper>,
+    /// Admission of streams across connections.
+    pub admission: Arc<Admission>,
+    /// Open connections per remote IP, when limited.
+    pub ip_connections: Option<Arc<IpConnections>>,
+    /// Rate of new streams per remote IP, when limited.
+    pub ip_rates: Option<Arc<IpRates>>,
+    /// Revoked references, closing the connections holding them.
+    pub revocations: Arc<Revocations>,
+    /// Execution time accounting of peers' identities, when budgeted.
+    pub budgets: Option<Arc<Budgets>>,
+    /// Metrics collection, counting open connections.
+    #[cfg(feature="metrics")]
+    pub metrics: Option<Arc<Collector>>,
+    /// Id of the next connection.
+    next_id: AtomicUsize,
+}
+
+impl<Id> TcpServer<Id>
+    where for<'de> Id: 'static+Ord+Clone+Send+Sync+Deserialize<'de>+Unpin+trace::SpanField
+{
+    /// Create new server.
+    pub fn new(config: ServerConfig) -> Self {
+        let dispatch = Arc::new(Dispatch::new(None).with_limits(config.connection_config.codec_limits)
+                                .with_header_timeout(config.connection_config.handshake_timeout));
+        let events = Arc::new(ServerEvents::new());
+        let reaper = Arc::new(Reaper::new(config.reap_interval.unwrap_or_default()));
+        reaper.add(dispatch.clone());
+        reaper.add(events.clone());
+        let mut admission = Admission::new(config.max_streams, config.connection_streams);
+        if let Some(marks) = config.backpressure {
+            let events = events.clone();
+            admission = admission.with_watch(Watch::new(marks, move |pressure| {
+                events.emit(ServerEvent::Backpressure(pressure))
+            }));
+        }
+        let revocations = Arc::new(Revocations::new());
+        reaper.add(revocations.clone());
+        let ip_connections = config.ip_connections.map(|max| Arc::new(IpConnections::new(max)));
+        let ip_rates = config.stream_rate.map(|limit| Arc::new(IpRates::new(limit)));
+        if let Some(ref ip_rates) = ip_rates {
+            reaper.add(ip_rates.clone());
+        }
+        let budgets = config.identity_budget.map(|limit| Arc::new(Budgets::new(limit)));
+        if let Some(ref budgets) = budgets {
+            reaper.add(budgets.clone());
+        }
+        Self { dispatch, config, events, reaper, admission: Arc::new(admission), ip_connections,
+               ip_rates, revocations, budgets,
+               #[cfg(feature="metrics")]
+               metrics: None,
+               next_id: AtomicUsize::new(0) }
+    }
+
+    /// Register the registry service at `id`, listing the services
+    /// registered on this server (see `services::registry`). Its id should
+    /// be pinned to trusted identities (see `Dispatch::pin`).
+    pub fn add_registry(&self, id: Id, options: HandlerOptions) -> Result<()>
+        where Id: Serialize
+    {
+        let registry = Registry::new(Arc::downgrade(&self.dispatch));
+        self.dispatch.add_builder(id, Box::new(move |_| registry.clone()), options)
+    }
+
+    /// Return TLS acceptor of connections, if they are secured.
+    pub fn get_tls_acceptor(&self) -> Result<Option<TlsAcceptor>> {
+        match self.config.connection_config.transport {
+            TransportKind::Tcp => Ok(None),
+            TransportKind::TcpTls => Ok(Some(Arc::new(self.config.get_tls_config()?).into())),
+            TransportKind::Quic => ErrorKind::Config.err("server is configured for QUIC transport"),
+        }
+    }
+
+    /// Listen at provided address, dispatching incoming connections.
+    pub async fn listen(&self, address: SocketAddr) -> Result<()> {
+        let listener = net::TcpListener::bind(address).await
+                            .or_else(|err| ErrorKind::Endpoint.err(err.to_string()))?;
+        self.dispatch_incoming(listener).await
+    }
+
+    /// Accept connections from listener and dispatch them to services.
+    pub async fn dispatch_incoming(&self, listener: net::TcpListener) -> Result<()> {
+        let tls = self.get_tls_acceptor()?;
+        let _reaper = self.config.reap_interval.map(|_| Spawned(self.reaper.clone().spawn()));
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => self.accept(stream, address, tls.clone()),
+                // failing connections (e.g. reset before accept) are skipped
+                Err(err) if is_connection_error(&err) => (),
+                Err(_) => tokio::time::sleep(ACCEPT_BACKOFF).await,
+            }
+        }
+    }
+
+    /// Accept connection from the client at `address`, dispatching its
+    /// stream once TLS handshake is completed, within the configured
+    /// `handshake_timeout`. Clients authenticated by their certificate are
+    /// identified by it, to which handlers can be restricted, and are
+    /// budgeted.
+    pub fn accept(&self, stream: net::TcpStream, address: SocketAddr, tls: Option<TlsAcceptor>) {
+        if !self.config.address_filter.is_allowed(&address.ip()) {
+            self.events.emit(ServerEvent::ConnectionRejected(address));
+            return;
+        }
+        if let Some(false) = self.ip_rates.as_ref().map(|ip_rates| ip_rates.try_acquire(address.ip())) {
+            self.events.emit(ServerEvent::LimitReached(address));
+            return;
+        }
+        let ip_permit = match self.ip_connections {
+            Some(ref ip_connections) => match ip_connections.acquire(address.ip()) {
+                Some(permit) => Some(permit),
+                None => {
+                    self.events.emit(ServerEvent::ConnectionRejected(address));
+                    return;
+                },
+            },
+            None => None,
+        };
+
+        let (dispatch, events) = (self.dispatch.clone(), self.events.clone());
+        let (admission, revocations) = (self.admission.clone(), self.revocations.clone());
+        let budgets = self.budgets.clone();
+        let handshake_timeout = self.config.connection_config.handshake_timeout;
+        let connection_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let span = trace::connection(address, connection_id);
+        #[cfg(feature="metrics")]
+        let connection_guard = self.metrics.as_ref().map(Collector::connection);
+        events.emit(ServerEvent::ConnectionOpened(address));
+        tokio::spawn(async move {
+            let _ip_permit = ip_permit;
+            #[cfg(feature="metrics")]
+            let _connection_guard = connection_guard;
+            let mut revoked = revocations.connect(connection_id);
+            let result: Result<()> = async {
+                let accept = TcpConnection::accept(stream, tls.as_ref());
+                let connection = match handshake_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, accept).await.unwrap_or_else(|_|
+                        ErrorKind::Timeout.err("TLS handshake not completed in time"))?,
+                    None => accept.await?,
+                };
+                let peer = TcpPeer { revocations: Some(revocations.clone()),
+                                     ..connection.peer(address, connection_id) };
+                let identity = peer.peer_certificate().map(|cert| fingerprint(&cert.0));
+                let budget = budgets.zip(identity);
+                let (receiver, mut sender) = connection.split();
+                if let Some((ref budgets, identity)) = budget {
+                    if budgets.check(&identity).is_some() {
+                        sender.reject(Rejection::BudgetExhausted).await;
+                        events.emit(ServerEvent::BudgetExhausted(address, identity));
+                        return Ok(())
+                    }
+                }
+
+                let _permit = admission.acquire(connection_id).await?;
+                events.emit(ServerEvent::StreamDispatched(address));
+                let data = (sender, receiver, Arc::new(peer));
+                let fut = dispatch.dispatch_stream_as::<BincodeCodec<Id>>(data, identity.as_ref());
+                let fut = async {
+                    match budget {
+                        Some((budgets, identity)) => match budgets.metered(identity, fut).await {
+                            Some(result) => result,
+                            None => {
+                                events.emit(ServerEvent::BudgetExhausted(address, identity));
+                                Ok(())
+                            },
+                        },
+                        None => fut.await,
+                    }
+                };
+                // dispatch is dropped, closing the connection, once a
+                // reference it holds is revoked
+                futures::pin_mut!(fut);
+                match future::select(fut, revoked.next()).await {
+                    future::Either::Left((result, _)) => result,
+                    future::Either::Right(_) => Ok(()),
+                }
+            }.await;
+
+            revocations.disconnect(connection_id);
+            match result {
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::LimitReached =>
+                    events.emit(ServerEvent::LimitReached(address)),
+                Err(err) => events.emit(ServerEvent::HandlerError(address, err)),
+            }
+            events.emit(ServerEvent::ConnectionClosed(address));
+        }.instrument(span));
+    }
+}
+
+#[cfg(feature="metrics")]
+impl<Id> TcpServer<Id>
+    where for<'de> Id: 'static+Ord+Clone+Send+Sync+Deserialize<'de>+Serialize+Unpin+trace::SpanField
+{
+    /// Collect server's metrics into provided collector, exported at `id`
+    /// pinned to provided identities, as `Server::add_metrics`.
+    pub fn add_metrics(&mut self, id: Id, collector: Arc<Collector>,
+                       identities: impl IntoIterator<Item=Fingerprint>) -> Result<()>
+    {
+        self.dispatch.pin(id.clone(), identities);
+        let exporter = Exporter::new(collector.clone());
+        self.dispatch.add_builder(id, Box::new(move |_| exporter.clone()), HandlerOptions::default())?;
+        collector.watch_dispatch(&self.dispatch);
+        if let Some(ref budgets) = self.budgets {
+            collector.watch_budgets(budgets);
+        }
+        self.dispatch.collect(collector.clone());
+        self.metrics = Some(collector);
+        Ok(())
+    }
+}
+
+
+/// Client opening streams to a `TcpServer`, each over a new connection.
+pub struct TcpClient {
+    address: SocketAddr,
+    server_name: String,
+    tls: Option<TlsConnector>,
+    /// Maximum duration of streams' opening, from connection to status.
+    handshake_timeout: Option<Duration>,
+    /// Limits of opened streams' messages.
+    limits: CodecLimits,
+}
+
+impl TcpClient {
+    /// Create client of the server at provided address, securing
+    /// connections as configured by `config`'s transport.
+    pub fn new(config: &ClientConfig, address: SocketAddr, server_name: &str) -> Result<Self> {
+        let tls = match config.connection_config.transport {
+            TransportKind::Tcp => None,
+            TransportKind::TcpTls => Some(Arc::new(config.get_tls_config()?)),
+            TransportKind::Quic => return ErrorKind::Config.err("client is configured for QUIC transport"),
+        };
+        Ok(Self::with_tls(address, server_name, tls).with_limits(config.connection_config.codec_limits)
+               .with_handshake_timeout(config.connection_config.handshake_timeout))
+    }
+
+    /// Create client with provided TLS configuration, connecting over
+    /// plain TCP when None.
+    pub fn with_tls(address: SocketAddr, server_name: &str, tls: Option<Arc<rustls::ClientConfig>>)
+        -> Self
+    {
+        Self { address, server_name: server_name.to_string(), tls: tls.map(TlsConnector::from),
+               handshake_timeout: None, limits: CodecLimits::default() }
+    }
+
+    /// Enforce provided limits on messages of streams opened from now on.
+    pub fn with_limits(mut self, limits: CodecLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Fail streams whose opening (connection, TLS handshake and server's
+    /// status) is not completed within `timeout`.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Open a new stream to the service registered at provided id.
+    pub async fn open<Id, Req, Resp>(&self, id: Id) -> Result<TcpTransport<Req, Resp>>
+        where Id: Serialize, Req: Serialize, Resp: DeserializeOwned
+    {
+        self.open_with(id).await.map(|(transport, _)| transport)
+    }
+
+    /// Open a new stream to the service registered at provided id, along
+    /// with the channel binding of its connection, used to sign `Auth`
+    /// challenges and presentations. Fail over plain TCP.
+    pub async fn open_bound<Id, Req, Resp>(&self, id: Id)
+        -> Result<(TcpTransport<Req, Resp>, ChannelBinding)>
+        where Id: Serialize, Req: Serialize, Resp: DeserializeOwned
+    {
+        let (transport, binding) = self.open_with(id).await?;
+        Ok((transport, binding.ok_or(ErrorKind::Unavailable.error("channel binding requires TLS"))?))
+    }
+
+    /// Open a new stream, along with the channel binding of its
+    /// connection, if any.
+    async fn open_with<Id, Req, Resp>(&self, id: Id)
+        -> Result<(TcpTransport<Req, Resp>, Option<ChannelBinding>)>
+        where Id: Serialize, Req: Serialize, Resp: DeserializeOwned
+    {
+        let header = handshake::header(id)?;
+        let open = async {
+            let connection = TcpConnection::connect(self.address, &self.server_name, self.tls.as_ref()).await?;
+            let binding = connection.channel_binding().ok();
+            let (mut receiver, mut sender) = connection.split();
+            sender.write_all(&header).await.or_else(|err| ErrorKind::IO.err(err.to_string()))?;
+            let mut status = [0u8; 4];
+            receiver.read_exact(&mut status).await
+                    .or_else(|err| ErrorKind::IO.err(format!("stream's status not received: {}", err)))?;
+            match u32::from_le_bytes(status) {
+                ADMITTED => Ok((receiver, sender, binding)),
+                code => Err(Rejection::from_code(code.into()).map(Rejection::error).unwrap_or_else(||
+                    ErrorKind::InvalidData.error("invalid stream's status"))),
+            }
+        };
+        let (receiver, sender, binding) = match self.handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, open).await.unwrap_or_else(|_|
+                ErrorKind::Timeout.err("stream not opened in time"))?,
+            None => open.await?,
+        };
+
+        Ok((Transport::new(Framed::new(sender, BincodeCodec::with_limits(self.limits)),
+                           Framed::new(receiver, BincodeCodec::with_limits(self.limits))),
+            binding))
+    }
+}
+
+
+/// Server over the transport selected by its configuration, for services
+/// which do not depend on connections' data.
+pub enum AnyServer<Id=u64>
+    where Id: Ord
+{
+    Quic(Server<Id>),
+    Tcp(TcpServer<Id>),
+}
+
+impl<Id> AnyServer<Id>
+    where for<'de> Id: 'static+Ord+Clone+Send+Sync+Deserialize<'de>+Unpin+trace::SpanField
+{
+    /// Create new server over `config`'s transport.
+    pub fn new(config: ServerConfig) -> Self {
+        match config.connection_config.transport {
+            TransportKind::Quic => Self::Quic(Server::new(config)),
+            TransportKind::Tcp | TransportKind::TcpTls => Self::Tcp(TcpServer::new(config)),
+        }
+    }
+
+    /// Server events' subscriptions.
+    pub fn events(&self) -> &Arc<ServerEvents> {
+        match self {
+            Self::Quic(server) => &server.events,
+            Self::Tcp(server) => &server.events,
+        }
+    }
+
+    /// Register a service built by `builder` for each stream.
+    pub fn add_builder<F,Sv>(&self, id: Id, builder: F, options: HandlerOptions) -> Result<()>
+        where F: 'static+Send+Sync+Unpin+Fn()->Sv,
+              Sv: 'static+Send+Sync+Service,
+              for <'de> Sv::Request: Deserialize<'de>, Sv::Response: Serialize
+    {
+        match self {
+            Self::Quic(server) => server.dispatch.add_builder(id, Box::new(move |_| builder()), options),
+            Self::Tcp(server) => server.dispatch.add_builder(id, Box::new(move |_| builder()), options),
+        }
+    }
+
+    /// Restrict streams to `id` to the peers of provided identities (see
+    /// `Dispatch::pin`).
+    pub fn pin(&self, id: Id, identities: impl IntoIterator<Item=Fingerprint>) {
+        match self {
+            Self::Quic(server) => server.dispatch.pin(id, identities),
+            Self::Tcp(server) => server.dispatch.pin(id, identities),
+        }
+    }
+
+    /// Listen at provided address, dispatching incoming connections.
+    pub async fn listen(&mut self, address: SocketAddr) -> Result<()> {
+        match self {
+            Self::Quic(server) => server.listen(address).await,
+            Self::Tcp(server) => server.listen(address).await,
+        }
+    }
+}
+
+
+/// Transport of a stream opened by an `AnyClient`.
+pub type AnyTransport<Req, Resp> = Transport<Pin<Box<dyn Sink<Req, Error=crate::Error>+Send>>,
+                                             Pin<Box<dyn Stream<Item=Resp>+Send>>>;
+
+/// Client over the transport selected by its configuration.
+pub enum AnyClient {
+    Quic(Client),
+    Tcp(TcpClient),
+}
+
+impl AnyClient {
+    /// Connect to server at provided address over `config`'s transport,
+    /// using `server_name` for its certificate validation.
+    pub async fn connect(config: &ClientConfig, address: SocketAddr, server_name: &str)
+        -> Result<Self>
+    {
+        match config.connection_config.transport {
+            TransportKind::Quic => Ok(Self::Quic(Client::connect(config, address, server_name).await?)),
+            TransportKind::Tcp | TransportKind::TcpTls =>
+                Ok(Self::Tcp(TcpClient::new(config, address, server_name)?)),
+        }
+    }
+
+    /// Open a new stream to the service registered at provided id.
+    pub async fn open<Id, Req, Resp>(&self, id: Id) -> Result<AnyTransport<Req, Resp>>
+        where Id: Serialize, Req: 'static+Serialize+Send+Unpin,
+              Resp: 'static+DeserializeOwned+Send+Unpin
+    {
+        let (sender, receiver) = match self {
+            Self::Quic(client) => {
+                let (sender, receiver) = client.open(id).await?.into_inner();
+                (Box::pin(sender) as Pin<Box<dyn Sink<Req, Error=crate::Error>+Send>>,
+                 Box::pin(receiver) as Pin<Box<dyn Stream<Item=Resp>+Send>>)
+            },
+            Self::Tcp(client) => {
+                let (sender, receiver) = client.open(id).await?.into_inner();
+                (Box::pin(sender) as _, Box::pin(receiver) as _)
+            },
+        };
+        Ok(Transport::new(sender, receiver))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::data::tls;
+    use crate::rpc::service::tests::simple_service;
+
+    /// Serve simple service at id 1 over configured transport, returning
+    /// server's address.
+    async fn serve(mut config: ServerConfig, transport: TransportKind) -> SocketAddr {
+        config.connection_config.transport = transport;
+        listen(TcpServer::<u64>::new(config)).await
+    }
+
+    /// Serve simple service at id 1 on provided server, returning its
+    /// address.
+    async fn listen(server: TcpServer<u64>) -> SocketAddr {
+        server.dispatch.add_builder(1, Box::new(|_| simple_service::Service::new()),
+                                    HandlerOptions::default()).unwrap();
+        let listener = net::TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                            .await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { server.dispatch_incoming(listener).await });
+        address
+    }
+
+    async fn call(client: &TcpClient) {
+        let transport = client.open(1u64).await.unwrap();
+        let mut service = simple_service::Client::new(transport);
+        assert_eq!(service.add(3).await, Ok(3));
+        assert_eq!(service.add(4).await, Ok(7));
+    }
+
+    #[test]
+    fn test_tcp() {
+        let mut config = ServerConfig::default();
+        config.connection_config.transport = TransportKind::Tcp;
+        let server = TcpServer::<u64>::new(config);
+        server.dispatch.pin(2, [[0u8; 32]]);
+        let dispatch = server.dispatch.clone();
+        Runtime::new().unwrap().block_on(async {
+            let address = listen(server).await;
+            let client = TcpClient::with_tls(address, "localhost", None);
+            call(&client).await;
+            // each stream has its own connection
+            call(&client).await;
+
+            // rejections are sent as the stream's status
+            let result = client.open::<_, simple_service::Request, simple_service::Response>(2u64).await;
+            assert_eq!(result.err().map(|err| err.kind()), Some(ErrorKind::Forbidden));
+            dispatch.drain(&1).unwrap().await;
+            let result = client.open::<_, simple_service::Request, simple_service::Response>(1u64).await;
+            assert_eq!(result.err().map(|err| err.kind()), Some(ErrorKind::Unavailable));
+        });
+    }
+
+    #[test]
+    fn test_tcp_tls() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let mut config = ServerConfig::default();
+        config.connection_config.cert_data = Some((certs.clone(), key));
+
+        let client_config = rustls::ClientConfig::builder().with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(tls::PinnedCertVerifier::new(certs)))
+            .with_no_client_auth();
+        Runtime::new().unwrap().block_on(async {
+            let address = serve(config, TransportKind::TcpTls).await;
+            let client = TcpClient::with_tls(address, "localhost", Some(Arc::new(client_config)));
+            call(&client).await;
+
+            // plain client can not talk to TLS server
+            let client = TcpClient::with_tls(address, "localhost", None);
+            assert!(client.open::<_, simple_service::Request, simple_service::Response>(1u64)
+                          .await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_tcp_channel_binding() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let client_config = rustls::ClientConfig::builder().with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(tls::PinnedCertVerifier::new(certs.clone())))
+            .with_no_client_auth();
+        let client_config = Some(Arc::new(client_config));
+
+        Runtime::new().unwrap().block_on(async {
+            for (transport, client_config) in [(TransportKind::TcpTls, client_config), (TransportKind::Tcp, None)] {
+                let mut config = ServerConfig::default();
+                config.connection_config.cert_data = Some((certs.clone(), key.clone()));
+                config.connection_config.transport = transport;
+                let server = TcpServer::<u64>::new(config);
+                let bindings = Arc::new(std::sync::Mutex::new(Vec::new()));
+                let bindings_ = bindings.clone();
+                server.dispatch.add_builder(2, Box::new(move |peer: Arc<TcpPeer>| {
+                    bindings_.lock().unwrap().push(peer.channel_binding());
+                    simple_service::Service::new()
+                }), HandlerOptions::default()).unwrap();
+                let address = listen(server).await;
+                let client = TcpClient::with_tls(address, "localhost", client_config);
+
+                let result = client.open_bound::<_, simple_service::Request, simple_service::Response>(2u64).await;
+                match transport {
+                    // both peers get the same binding, differing among connections
+                    TransportKind::TcpTls => {
+                        let (_, binding) = result.unwrap();
+                        let (_, other) = client.open_bound::<_, simple_service::Request,
+                                                             simple_service::Response>(2u64).await.unwrap();
+                        assert_ne!(binding, other);
+                        assert_eq!(*bindings.lock().unwrap(), vec![Ok(binding), Ok(other)]);
+                    },
+                    _ => {
+                        assert_eq!(result.err().map(|err| err.kind()), Some(ErrorKind::Unavailable));
+                        let bindings = bindings.lock().unwrap();
+                        assert_eq!(bindings[0].as_ref().err().map(|err| err.kind()), Some(ErrorKind::Unavailable));
+                    },
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_tcp_handshake_timeout() {
+        let (certs, key) = tls::new_cert(vec![String::from("localhost")]).unwrap();
+        let mut config = ServerConfig::default();
+        config.connection_config.cert_data = Some((certs, key));
+        config.connection_config.handshake_timeout = Some(Duration::from_millis(50));
+        Runtime::new().unwrap().block_on(async {
+            let tls_address = serve(config, TransportKind::TcpTls).await;
+            let mut config = ServerConfig::default();
+            config.connection_config.handshake_timeout = Some(Duration::from_millis(50));
+            let address = serve(config, TransportKind::Tcp).await;
+
+            // idle peers are disconnected, whether before TLS handshake or
+            // stream's header
+            for address in [tls_address, address].iter() {
+                let mut stream = net::TcpStream::connect(address).await.unwrap().compat();
+                let mut buf = [0u8; 1];
+                let read = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+                assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+            }
+
+            // clients give up on servers not answering
+            let listener = net::TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                                .await.unwrap();
+            let client = TcpClient::with_tls(listener.local_addr().unwrap(), "localhost", None)
+                             .with_handshake_timeout(Some(Duration::from_millis(50)));
+            let result = client.open::<_, simple_service::Request, simple_service::Response>(1u64).await;
+            assert_eq!(result.err().map(|err| err.kind()), Some(ErrorKind::Timeout));
+        });
+    }
+
+    #[test]
+    fn test_tcp_limits() {
+        use crate::rpc::admission::RateLimit;
+
+        let mut config = ServerConfig::default();
+        config.connection_config.transport = TransportKind::Tcp;
+        config.stream_rate = Some(RateLimit::new(0.01, 1));
+        let server = TcpServer::<u64>::new(config);
+        let mut events = server.events.subscribe(16);
+        Runtime::new().unwrap().block_on(async {
+            let address = listen(server).await;
+            let client = TcpClient::with_tls(address, "localhost", None);
+            call(&client).await;
+
+            // streams are rate limited per IP
+            assert!(client.open::<_, simple_service::Request, simple_service::Response>(1u64)
+                          .await.is_err());
+            while let Some(event) = events.next().await {
+                if let ServerEvent::LimitReached(_) = event {
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_any_transport() {
+        let mut config = ServerConfig::default();
+        config.connection_config.transport = TransportKind::Tcp;
+        assert!(matches!(AnyServer::<u64>::new(config), AnyServer::Tcp(_)));
+        assert!(matches!(AnyServer::<u64>::new(ServerConfig::default()), AnyServer::Quic(_)));
+
+        let mut client_config = ClientConfig::default();
+        client_config.connection_config.transport = TransportKind::Tcp;
+        Runtime::new().unwrap().block_on(async {
+            let address = serve(ServerConfig::default(), TransportKind::Tcp).await;
+            let client = AnyClient::connect(&client_config, address, "localhost").await.unwrap();
+            assert!(matches!(client, AnyClient::Tcp(_)));
+            let mut service = simple_service::Client::new(client.open(1u64).await.unwrap());
+            assert_eq!(service.add(3).await, Ok(3));
+        });
+    }
+}
@@ -0,0 +1,255 @@
+//! Validation of requests requiring I/O, before they are dispatched.
+//!
+//! Codecs decode requests synchronously, thus can't run validators looking
+//! up revocations or querying a store. A `Validated` service validates
+//! requests implementing `data::validate::AsyncValidate` once they are
+//! decoded, before dispatching them to the inner service: invalid requests
+//! are denied with the validation error as reason. The validation context
+//! is shared among the services' instances:
+//!
+//! ```ignore
+//! #[async_trait]
+//! impl AsyncValidate for storage::Request {
+//!     type Error = Error;
+//!     type Context = Revocations;
+//!     ...
+//! }
+//!
+//! let revocations = Arc::new(revocations);
+//! dispatch.add_builder(0, Box::new(move |_| {
+//!     Validated::new(storage::Service::new(), revocations.clone())
+//! }), HandlerOptions::default())?;
+//! ```
+//!
+//! Only methods' calls are validated, not streamed items of client-streaming
+//! methods. Invalid notifications are dropped, since the client does not
+//! read their response.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use crate::data::validate::AsyncValidate;
+use super::message::Error;
+use super::protocol::{Call, Frame, Reply};
+use super::service::Service;
+
+
+/// Service validating requests before dispatching them to the inner one.
+pub struct Validated<S: Service, C> {
+    inner: S,
+    context: Arc<C>,
+}
+
+impl<S: Service, C> Validated<S, C> {
+    pub fn new(inner: S, context: Arc<C>) -> Self {
+        Self { inner, context }
+    }
+
+    /// Inner service.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Validation context.
+    pub fn context(&self) -> &Arc<C> {
+        &self.context
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Request's call, as classified by `request_frame()`. Since requests
+    /// go through the dispatch methods in turn, each one only validates the
+    /// calls it handles, so that requests are validated once.
+    fn call(request: &S::Request) -> Option<Call> {
+        match S::request_frame(request) {
+            Some(Frame::Request(call)) => Some(call),
+            _ => None,
+        }
+    }
+}
+
+impl<S, C> Validated<S, C>
+    where S: Service, S::Request: AsyncValidate<Context=C>, C: Send+Sync
+{
+    /// Validate request, returning the response to send instead of
+    /// dispatching it when invalid.
+    pub async fn check(&self, request: &S::Request) -> Option<Option<S::Response>> {
+        S::method_index(request)?;
+        let err = AsyncValidate::validate(request, &self.context).await.err()?;
+        match Self::call(request) {
+            Some(Call { reply: Reply::None, .. }) => Some(None),
+            _ => Some(S::denied(S::call_id(request), err.to_string())),
+        }
+    }
+}
+
+impl<S: Service+Clone, C> Clone for Validated<S, C> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), context: self.context.clone() }
+    }
+}
+
+#[async_trait]
+impl<S, C> Service for Validated<S, C>
+    where S: Service, S::Request: AsyncValidate<Context=C>, S::Response: 'static,
+          C: Send+Sync
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let call = Self::call(&request);
+        if matches!(call, Some(Call { incoming: true, .. } | Call { reply: Reply::Stream, .. })) {
+            return self.inner.dispatch(request).await
+        }
+        match self.check(&request).await {
+            Some(response) => response,
+            None => self.inner.dispatch(request).await,
+        }
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: false, reply: Reply::Stream })) {
+            return self.inner.dispatch_streaming(request).await
+        }
+        match self.check(&request).await {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => self.inner.dispatch_streaming(request).await,
+        }
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        if !matches!(Self::call(&request), Some(Call { incoming: true, .. })) {
+            return self.inner.dispatch_incoming(request, requests).await
+        }
+        match self.check(&request).await {
+            Some(response) => Ok(stream::iter(response).boxed()),
+            None => self.inner.dispatch_incoming(request, requests).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::data::validate::Unsafe;
+    use crate::rpc::call::CallError;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    /// Maximum value to add, as read from a store.
+    pub struct Limits {
+        max: Mutex<u32>,
+    }
+
+    #[async_trait]
+    impl AsyncValidate for simple_service::Request {
+        type Error = String;
+        type Context = Limits;
+
+        async fn validate(&self, context: &Limits) -> Result<(), String> {
+            match self {
+                simple_service::Request::Add(a) if *a > *context.max.lock().unwrap() =>
+                    Err(format!("{} is too large", a)),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_validated() {
+        let limits = Arc::new(Limits { max: Mutex::new(10) });
+        let (server_transport, client_transport) =
+            MPSCTransport::<simple_service::Response, simple_service::Request>::bi(8);
+
+        let client_fut = async move {
+            let mut client = simple_service::Client::new(client_transport);
+            assert_eq!(client.add(4).await, Ok(4));
+            assert_eq!(client.add(11).await, Err(CallError::Failed));
+            assert_eq!(client.get().await, Ok(4));
+        };
+        let server_fut = {
+            let limits = limits.clone();
+            async move {
+                let (s,r) = server_transport.split();
+                let mut service = Validated::new(simple_service::Service::new(), limits);
+                service.serve(Transport::new(s, r)).await;
+            }
+        };
+        LocalPool::new().run_until(join(client_fut, server_fut));
+
+        // decoded data is validated asynchronously
+        let encoded = bincode::serialize(&simple_service::Request::Add(11)).unwrap();
+        let request: Unsafe<simple_service::Request> = bincode::deserialize(&encoded).unwrap();
+        *limits.max.lock().unwrap() = 20;
+        let request = futures::executor::block_on(request.validate_async(&limits));
+        assert!(matches!(request, Ok(simple_service::Request::Add(11))));
+    }
+}
@@ -0,0 +1,304 @@
+//! Wire protocol versions.
+//!
+//! Services' messages evolve along with the protocol (implicit responses,
+//! envelopes, streaming frames), and peers of a previous version can't
+//! decode messages introduced after it. Peers negotiate their version by
+//! ALPN, offering `alpn_protocols()` (the default of
+//! `ConnectionConfig::alpn_protocols`). Each stream also opens with a
+//! handshake carrying the client's version, rejected by the server when
+//! unsupported (see `handshake`), and returned by `peer_version()` while
+//! the stream is dispatched. Responses sent to peers of a previous version
+//! are converted by `Service::downgrade`, which services registered by
+//! `Dispatch` builders go through (see `Downgraded`).
+//!
+//! Versions:
+//! - 1: initial protocol;
+//! - 2: `__SlowDown` responses of throttled services (`SLOW_DOWN`).
+//! - 3: `__Error` responses to requests the service failed to handle
+//!   (`ERROR`);
+//! - 4: `__Cancel` requests, cancelling calls of unordered methods
+//!   (`CANCEL`). Clients must not send them to peers of previous versions,
+//!   which can't decode them.
+//! - 5: `__Schema` requests, returning the schema of services' methods
+//!   (`SCHEMA`).
+//! - 6: `__Trace` requests, carrying the trace context of the following
+//!   call (`TRACE`). Clients must not send them to peers of previous
+//!   versions.
+//! - 7: `__Denied` and `__Error` responses carry the call id of unordered
+//!   calls, and methods returning a `Result` only send their Ok value in
+//!   their response, their errors being sent as `Error::Method`
+//!   (`METHOD_ERROR`). Responses can't be downgraded to previous versions.
+//! - 8: streams open with a handshake (see `handshake`), and TCP servers
+//!   answer it with the stream's admission (`HANDSHAKE`). Implicit
+//!   variants of requests and responses are encoded before methods' ones.
+//!   Previous versions are not supported anymore.
+use std::cell::Cell;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+use super::message::Error;
+use super::protocol::Frame;
+use super::service::Service;
+
+
+/// Current protocol version.
+pub const PROTOCOL_VERSION: u16 = 8;
+/// Oldest protocol version peers can still use.
+pub const MIN_PROTOCOL_VERSION: u16 = HANDSHAKE;
+
+/// Version introducing slow down responses.
+pub const SLOW_DOWN: u16 = 2;
+/// Version introducing error responses.
+pub const ERROR: u16 = 3;
+/// Version introducing calls' cancellation.
+pub const CANCEL: u16 = 4;
+/// Version introducing services' schema.
+pub const SCHEMA: u16 = 5;
+/// Version introducing calls' trace context.
+pub const TRACE: u16 = 6;
+/// Version introducing methods' errors and call ids of error responses.
+pub const METHOD_ERROR: u16 = 7;
+/// Version introducing streams' handshake.
+pub const HANDSHAKE: u16 = 8;
+
+/// Prefix of versions' ALPN protocol names.
+const ALPN_PREFIX: &str = "rpccaps/";
+
+
+/// Return true if peers of this version are supported.
+pub fn is_supported(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// ALPN protocol name of a version.
+pub fn alpn(version: u16) -> Vec<u8> {
+    format!("{}{}", ALPN_PREFIX, version).into_bytes()
+}
+
+/// ALPN protocol names of supported versions, most recent first, as set to
+/// `ConnectionConfig::alpn_protocols`.
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).rev().map(alpn).collect()
+}
+
+/// Return the supported version of an ALPN protocol name, if any.
+pub fn from_alpn(protocol: &[u8]) -> Option<u16> {
+    std::str::from_utf8(protocol).ok()?
+        .strip_prefix(ALPN_PREFIX)?
+        .parse().ok()
+        .filter(|version| is_supported(*version))
+}
+
+
+thread_local! {
+    static PEER_VERSION: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+/// Return protocol version announced by the peer of the stream dispatched
+/// by the current task, or the current version outside of a dispatch.
+pub fn peer_version() -> u16 {
+    stream_version().unwrap_or(PROTOCOL_VERSION)
+}
+
+/// Return protocol version announced by the handshake of the stream
+/// dispatched by the current task, if any.
+pub fn stream_version() -> Option<u16> {
+    PEER_VERSION.with(Cell::get)
+}
+
+/// Run `future` with provided peer's version, as returned by
+/// `peer_version()` while it is polled.
+pub fn with_peer_version<F: Future>(version: u16, future: F) -> WithPeerVersion<F> {
+    WithPeerVersion { version, future }
+}
+
+pin_project_lite::pin_project! {
+    /// Future running with a peer's version (see `with_peer_version`).
+    pub struct WithPeerVersion<F> {
+        version: u16,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F: Future> Future for WithPeerVersion<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        let previous = PEER_VERSION.with(|cell| cell.replace(Some(*this.version)));
+        let poll = this.future.poll(cx);
+        PEER_VERSION.with(|cell| cell.set(previous));
+        poll
+    }
+}
+
+
+/// Service whose responses are downgraded to peer's protocol version.
+pub struct Downgraded<S: Service> {
+    inner: S,
+    version: u16,
+}
+
+impl<S: Service> Downgraded<S> {
+    pub fn new(inner: S, version: u16) -> Self {
+        Self { inner, version }
+    }
+
+    /// Peer's protocol version.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Return inner service, consuming self.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+}
+
+impl<S: Service> Downgraded<S>
+    where S::Response: 'static
+{
+    fn downgrade_stream(&self, responses: BoxStream<'static, S::Response>)
+        -> BoxStream<'static, S::Response>
+    {
+        // function pointer, so that the stream does not depend on `S`
+        let downgrade: fn(S::Response, u16) -> Option<S::Response> = S::downgrade;
+        match self.version {
+            PROTOCOL_VERSION => responses,
+            version => responses.filter_map(move |resp| future::ready(downgrade(resp, version)))
+                                .boxed(),
+        }
+    }
+}
+
+impl<S: Service+Clone> Clone for Downgraded<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), version: self.version }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for Downgraded<S>
+    where S::Response: 'static
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.inner.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    fn capability(&self) -> S::Capability {
+        self.inner.capability()
+    }
+
+    super::service::forward_requests!(S);
+
+    fn is_error(response: &Self::Response) -> bool {
+        S::is_error(response)
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        S::is_refused(response)
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after)
+    }
+
+    fn error(call_id: Option<u64>, error: Error) -> Option<Self::Response> {
+        S::error(call_id, error)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        S::downgrade(response, version)
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        S::response_frame(response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let response = self.inner.dispatch(request).await?;
+        S::downgrade(response, self.version)
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let responses = self.inner.dispatch_streaming(request).await?;
+        Ok(self.downgrade_stream(responses))
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let responses = self.inner.dispatch_incoming(request, requests).await?;
+        Ok(self.downgrade_stream(responses))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::service::tests::simple_service;
+
+    #[test]
+    fn test_alpn() {
+        assert_eq!(alpn_protocols().first(), Some(&alpn(PROTOCOL_VERSION)));
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            assert_eq!(from_alpn(&alpn(version)), Some(version));
+        }
+        assert_eq!(from_alpn(&alpn(PROTOCOL_VERSION + 1)), None);
+        assert_eq!(from_alpn(&alpn(MIN_PROTOCOL_VERSION - 1)), None);
+        assert_eq!(from_alpn(b"rpccaps/test"), None);
+        #[cfg(feature="network")]
+        assert_eq!(crate::rpc::config::ConnectionConfig::default().alpn_protocols, alpn_protocols());
+    }
+
+    #[test]
+    fn test_peer_version() {
+        assert_eq!((stream_version(), peer_version()), (None, PROTOCOL_VERSION));
+        let version = futures::executor::block_on(with_peer_version(HANDSHAKE, async {
+            (stream_version(), peer_version())
+        }));
+        assert_eq!(version, (Some(HANDSHAKE), HANDSHAKE));
+    }
+
+    #[test]
+    fn test_downgrade() {
+        use simple_service::{Response, Service};
+        use crate::rpc::message::Error;
+        let error = || Response::__Error(None, Error::ActionNotFound);
+        assert!(matches!(Service::downgrade(error(), PROTOCOL_VERSION), Some(Response::__Error(..))));
+        assert!(Service::downgrade(error(), ERROR - 1).is_none());
+        assert!(matches!(Service::downgrade(Response::Add(1), MIN_PROTOCOL_VERSION),
+                         Some(Response::Add(1))));
+    }
+}
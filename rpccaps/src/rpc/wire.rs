@@ -0,0 +1,290 @@
+//! Snapshots of services' wire format.
+//!
+//! Services declared with `#[service(wire_tests)]` get a test encoding a
+//! sample of each of their `Request` and `Response` variants, as sent by
+//! `codec::BincodeCodec`. Encodings are compared to the snapshot saved in
+//! the `wire/` directory of the crate declaring the service: an accidental
+//! change of the wire format (reordered methods, changed argument types...)
+//! fails the crate's tests instead of breaking deployed peers.
+//!
+//! Snapshots are only written when the `RPCCAPS_UPDATE_WIRE` environment
+//! variable is set, for new services or intended changes: a missing one
+//! fails the test. They are meant to be committed along with the services.
+//!
+//! Samples are built by `Sample`, which arguments and outputs of methods
+//! must implement:
+//!
+//! ```ignore
+//! impl Sample for Item {
+//!     fn sample() -> Self {
+//!         Item { name: sample(), count: sample() }
+//!     }
+//! }
+//! ```
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::data::{Capability, WideCapability};
+use super::message::{Error, RemoteError, SlowDown};
+use super::schema::Schema;
+use super::service::Capabilities;
+use super::trace::TraceContext;
+
+
+/// Environment variable forcing snapshots to be written.
+pub const UPDATE_ENV: &str = "RPCCAPS_UPDATE_WIRE";
+
+
+/// Value of a type with its fields set, used as sample of its encoding.
+pub trait Sample {
+    fn sample() -> Self;
+}
+
+/// Return sample of type `T`.
+pub fn sample<T: Sample>() -> T {
+    T::sample()
+}
+
+macro_rules! impl_sample {
+    ($($ty:ty => $value:expr),* $(,)?) => {
+        $(impl Sample for $ty {
+            fn sample() -> Self {
+                $value
+            }
+        })*
+    };
+}
+
+impl_sample! {
+    () => (),
+    bool => true,
+    char => 'a',
+    u8 => 0x01, u16 => 0x0102, u32 => 0x01020304, u64 => 0x0102030405060708,
+    u128 => 0x0102030405060708090a0b0c0d0e0f10, usize => 0x0102030405060708,
+    i8 => -1, i16 => -0x0102, i32 => -0x01020304, i64 => -0x0102030405060708,
+    i128 => -0x0102030405060708090a0b0c0d0e0f10, isize => -0x0102030405060708,
+    f32 => 1.5, f64 => -1.5,
+    String => String::from("sample"),
+    Duration => Duration::new(1, 500),
+    Capability => Capability::new(0b1011, 0b0010),
+    SlowDown => SlowDown { retry_after: sample() },
+    Error => Error::Internal(sample()),
+    RemoteError => RemoteError::new(sample(), "sample"),
+    Schema => Schema { methods: sample(), ids: sample() },
+    TraceContext => TraceContext { trace_id: sample(), parent: sample() },
+}
+
+impl<C: Sample> Sample for Capabilities<C> {
+    fn sample() -> Self {
+        Capabilities { methods: vec![sample()], capability: sample() }
+    }
+}
+
+impl<const N: usize> Sample for WideCapability<N> {
+    fn sample() -> Self {
+        Self::from(Capability::sample())
+    }
+}
+
+impl<T: Sample> Sample for Option<T> {
+    fn sample() -> Self {
+        Some(T::sample())
+    }
+}
+
+impl<T: Sample, E> Sample for Result<T, E> {
+    fn sample() -> Self {
+        Ok(T::sample())
+    }
+}
+
+impl<T: Sample> Sample for Box<T> {
+    fn sample() -> Self {
+        Box::new(T::sample())
+    }
+}
+
+impl<T: Sample> Sample for Arc<T> {
+    fn sample() -> Self {
+        Arc::new(T::sample())
+    }
+}
+
+impl<T: Sample> Sample for Vec<T> {
+    fn sample() -> Self {
+        vec![T::sample()]
+    }
+}
+
+impl<T: Sample, const N: usize> Sample for [T; N] {
+    fn sample() -> Self {
+        std::array::from_fn(|_| T::sample())
+    }
+}
+
+impl<T: Sample+Ord> Sample for BTreeSet<T> {
+    fn sample() -> Self {
+        std::iter::once(T::sample()).collect()
+    }
+}
+
+impl<T: Sample+Eq+Hash> Sample for HashSet<T> {
+    fn sample() -> Self {
+        std::iter::once(T::sample()).collect()
+    }
+}
+
+impl<K: Sample+Ord, V: Sample> Sample for BTreeMap<K, V> {
+    fn sample() -> Self {
+        std::iter::once((K::sample(), V::sample())).collect()
+    }
+}
+
+impl<K: Sample+Eq+Hash, V: Sample> Sample for HashMap<K, V> {
+    fn sample() -> Self {
+        std::iter::once((K::sample(), V::sample())).collect()
+    }
+}
+
+macro_rules! impl_sample_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: Sample),+> Sample for ($($name,)+) {
+            fn sample() -> Self {
+                ($($name::sample(),)+)
+            }
+        }
+    };
+}
+
+impl_sample_tuple! { A }
+impl_sample_tuple! { A B }
+impl_sample_tuple! { A B C }
+impl_sample_tuple! { A B C D }
+impl_sample_tuple! { A B C D E }
+impl_sample_tuple! { A B C D E F }
+
+
+/// Encodings of a service's messages, checked against its saved snapshot.
+pub struct Snapshot {
+    path: PathBuf,
+    entries: Vec<(String, String)>,
+}
+
+impl Snapshot {
+    /// Snapshot of service `name` declared in module `module`, saved in
+    /// `wire/` directory of the crate at `manifest_dir`.
+    pub fn new(manifest_dir: impl AsRef<Path>, module: &str, name: &str) -> Self {
+        let file = format!("{}.{}.snap", module.replace("::", "."), name);
+        Self { path: manifest_dir.as_ref().join("wire").join(file), entries: Vec::new() }
+    }
+
+    /// Return path of the saved snapshot.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add message's encoding, labelled by its variant.
+    pub fn add<T: Serialize>(&mut self, variant: &str, message: &T) {
+        let data = bincode::serialize(message).expect("sample can not be serialized");
+        let mut hex = String::with_capacity(data.len() * 2);
+        for byte in data {
+            write!(hex, "{:02x}", byte).unwrap();
+        }
+        self.entries.push((variant.to_string(), hex));
+    }
+
+    /// Return snapshot's content.
+    pub fn content(&self) -> String {
+        self.entries.iter().map(|(variant, hex)| format!("{} {}\n", variant, hex)).collect()
+    }
+
+    /// Compare encodings to the saved snapshot, returning the description
+    /// of changed variants. Snapshot is only written when `update` is
+    /// true: a missing one is an error, so that it can't be silently
+    /// skipped.
+    pub fn compare(&self, update: bool) -> Result<(), String> {
+        if update {
+            return self.write();
+        }
+        let saved = match std::fs::read_to_string(&self.path) {
+            Ok(saved) => saved,
+            Err(_) => return Err(format!("snapshot {} is missing\n", self.path.display())),
+        };
+        let saved: BTreeMap<&str, &str> = saved.lines().filter_map(|line| line.split_once(' ')).collect();
+        let mut changes = String::new();
+        for (variant, hex) in self.entries.iter() {
+            match saved.get(variant.as_str()) {
+                Some(saved) if saved == hex => (),
+                Some(saved) => writeln!(changes, "- {} {}\n+ {} {}", variant, saved, variant, hex).unwrap(),
+                None => writeln!(changes, "+ {} {}", variant, hex).unwrap(),
+            }
+        }
+        for (variant, hex) in saved.iter() {
+            if !self.entries.iter().any(|(v, _)| v == variant) {
+                writeln!(changes, "- {} {}", variant, hex).unwrap();
+            }
+        }
+        match changes.is_empty() {
+            true => Ok(()),
+            false => Err(changes),
+        }
+    }
+
+    /// Compare encodings to the saved snapshot, panicking on changes or
+    /// when it is missing. Snapshot is written when `RPCCAPS_UPDATE_WIRE`
+    /// is set.
+    pub fn check(&self) {
+        let update = std::env::var_os(UPDATE_ENV).is_some();
+        if let Err(changes) = self.compare(update) {
+            panic!("wire format differs from snapshot {}:\n{}\
+                    (set {} to update it if the change is intended)",
+                   self.path.display(), changes, UPDATE_ENV);
+        }
+    }
+
+    fn write(&self) -> Result<(), String> {
+        let dir = self.path.parent().unwrap();
+        std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&self.path, self.content()))
+            .map_err(|err| format!("can not write snapshot {}: {}", self.path.display(), err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = env::temp_dir().join("rpccaps-test-wire");
+        std::fs::remove_dir_all(&dir).ok();
+        let snapshot = |value: u32| {
+            let mut snapshot = Snapshot::new(&dir, "crate::module", "Service");
+            snapshot.add("Request::Add", &(1u8, value));
+            snapshot.add("Request::Sub", &sample::<(u8, Option<String>)>());
+            snapshot
+        };
+
+        let saved = snapshot(1);
+        assert_eq!(saved.path(), dir.join("wire/crate.module.Service.snap"));
+        assert_eq!(saved.content().lines().next(), Some("Request::Add 0101000000"));
+        assert_eq!(saved.compare(false), Err(format!("snapshot {} is missing\n", saved.path().display())));
+        assert!(!saved.path().exists());
+        assert_eq!(saved.compare(true), Ok(()));
+        assert_eq!(snapshot(1).compare(false), Ok(()));
+
+        let changes = snapshot(2).compare(false).unwrap_err();
+        assert_eq!(changes, "- Request::Add 0101000000\n+ Request::Add 0102000000\n");
+        assert_eq!(snapshot(2).compare(true), Ok(()));
+        assert_eq!(snapshot(2).compare(false), Ok(()));
+    }
+}
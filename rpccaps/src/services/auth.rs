@@ -1,90 +1,953 @@
-// TODO:
-// - auth a single identity
-// - auth flow
-//   - nonce & key exchange
-//     - certificate validation
-//   - auth signature exchange
-//   - expiration and renewal
-// - auth multiple identities -> stream per identity
-//      - use of channel id or Dispatch
-// - reference:
-//   - expiration timeout
-//
+//! Authentication of a peer's identity, wrapping a service that is only
+//! served once the peer is authenticated.
+//!
+//! The flow is the following:
+//! - the client sends an `AuthRequest` with its identity reference; the
+//!   reference is validated for its last subject, and must be issued by
+//!   the server's configured issuer. A nonce is then returned;
+//! - the client signs the nonce along with the connection's channel binding
+//!   (see `sign_challenge`) using the subject's key, and sends it as an
+//!   `AuthResponse`: once verified, the peer is authenticated until the
+//!   returned expiration timestamp. Since the signature is bound to the TLS
+//!   session, it can't be relayed to another connection;
+//! - requests to the inner service are forwarded until authentication
+//!   expires. The flow can be run again meanwhile in order to renew it.
+//!
+//! When `Resumptions` are enabled, an authenticated client can request a
+//! resumption token: a short-lived token signed by the server, capturing
+//! its identity, the negotiated codec, a digest of its capability and the
+//! expiration of its authentication. Presenting it with `Resume` on a new
+//! stream, along with a proof of possession of the subject's key bound to
+//! the new connection (see `sign_resumption`), authenticates the peer
+//! without running the challenge again, until the original authentication
+//! expires. Tokens are single-use, and are refused once the peer's
+//! capability changed.
+//!
+//! As ids of the dispatcher can be pinned to peers identified by mTLS (see
+//! `Dispatch::pin`), `Auth::with_identities` pins the wrapped service to
+//! identities authenticated in-band. Identities are fingerprinted by
+//! their reference's id.
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use async_trait::async_trait;
 use futures::prelude::*;
+use futures::stream::BoxStream;
+use rand_core::{OsRng, RngCore};
 use serde::{Serialize,Deserialize};
+use signature::{Signer,Verifier};
 
-
-use crate::data::bytes;
-use crate::data::signature::*;
+use crate::data::{bytes, canonical, hash, CapabilitySet, Clock, SystemClock};
+use crate::data::presentation::ChannelBinding;
+use crate::data::signature::{self as sign, SignMethod};
 use crate::data::reference::Reference;
+use crate::rpc::dispatch::Identities;
+use crate::rpc::enforce::fingerprint;
+use crate::rpc::leak::{self, Kind};
+use crate::rpc::message;
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::reaper::{Reap, Reaper};
 use crate::rpc::service::Service;
+use crate::rpc::trace::TraceContext;
 
-#[derive(Serialize,Deserialize)]
+
+/// Reference proving that its last subject's key is allowed to act as
+/// the identity (reference's id).
+pub type IdentityRef<Sign> = Reference<Vec<u8>, Sign>;
+pub type Nonce = [u8;32];
+
+/// Prefix of the signed challenge data, so that the signature of a nonce
+/// can not be used for anything else.
+const CHALLENGE_PREFIX: &[u8] = b"rpccaps-auth:";
+
+/// Prefix of the signed resumption data.
+const RESUMPTION_PREFIX: &[u8] = b"rpccaps-resume:";
+
+/// Prefix of the data signed by a client resuming its authentication.
+const RESUME_PREFIX: &[u8] = b"rpccaps-resume-auth:";
+
+
+/// Authentication errors.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
 pub enum Error {
+    /// Identity reference is invalid.
+    Identity,
+    /// No challenge is pending.
+    NoChallenge,
+    /// Challenge has expired.
+    Expired,
+    /// Challenge's signature is invalid.
+    Signature,
+    /// Resumption is not enabled.
+    Unsupported,
+    /// Resumption token is invalid, already used, or its session state
+    /// does not match the current one.
+    Token,
+    /// Identity is not allowed to the service.
+    Forbidden,
 }
 
-pub type IdentityRef<Sign> = Reference<bytes::AsBytes<PublicKey>, Sign>;
-pub type Nonce = [u8;32];
 
+/// Authentication requests, or request to the inner service.
 #[derive(Serialize,Deserialize)]
-pub enum Message<Sign>
-    where Sign: SignMethod
-{
-	AuthRequest(Nonce, IdentityRef),
-	AuthResponse(Nonce, #[serde(with="bytes")] Signature),
-    Message(Vec<u8>, #[serde(with="bytes")] Signature),
+#[serde(bound(serialize="R: Serialize, Sign: SignMethod+Serialize",
+              deserialize="R: Deserialize<'de>, Sign: SignMethod+Deserialize<'de>"))]
+pub enum Request<Sign: SignMethod, R> {
+    /// Present identity, requesting a challenge.
+    AuthRequest(IdentityRef<Sign>),
+    /// Signature of the challenge.
+    AuthResponse(#[serde(with="bytes")] sign::Signature),
+    /// Request a resumption token, once authenticated.
+    ResumptionToken,
+    /// Authenticate with a resumption token, and its signature over the
+    /// stream's connection (see `sign_resumption`).
+    Resume(Vec<u8>, #[serde(with="bytes")] sign::Signature),
+    Request(R),
+}
+
+
+/// Authentication responses, or response of the inner service.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Response<R> {
+    /// Nonce to sign.
+    AuthRequest(Result<Nonce, Error>),
+    /// Authentication's expiration timestamp (in seconds).
+    AuthResponse(Result<u64, Error>),
+    /// Resumption token, opaque to the client.
+    ResumptionToken(Result<Vec<u8>, Error>),
+    /// Authentication's expiration timestamp (in seconds), once resumed.
+    Resume(Result<u64, Error>),
+    Response(R),
+    /// Request has not been forwarded: peer is not authenticated.
+    Unauthenticated,
 }
 
 
+/// Authentication state of the peer.
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum IdentityState {
     /// Unauthenticated
     Unauthenticated,
-    /// Authentication requested, provided Nonce is 
+    /// Authentication requested, waiting for the challenge's signature.
     Requested,
-    /// Authenticated
-    Authenticated,
+    /// Authenticated until provided time.
+    Authenticated(Duration),
 }
 
 
-pub struct Identity<Sign>
-    where Sign: SignMethod
+/// Pending challenge.
+struct Challenge<Sign: SignMethod> {
+    identity: IdentityRef<Sign>,
+    nonce: Nonce,
+    expires: Duration,
+}
+
+
+/// Authentication options.
+#[derive(Clone,Copy,Debug)]
+pub struct AuthOptions {
+    /// Duration during which a challenge can be answered.
+    pub challenge_timeout: Duration,
+    /// Duration of an authentication, before it must be renewed.
+    pub ttl: Duration,
+}
+
+impl Default for AuthOptions {
+    fn default() -> Self {
+        Self { challenge_timeout: Duration::from_secs(30), ttl: Duration::from_secs(3600) }
+    }
+}
+
+
+/// Return data to sign in order to answer challenge's nonce over the
+/// connection of provided channel binding.
+pub fn challenge_data(channel_binding: &ChannelBinding, nonce: &Nonce) -> Vec<u8> {
+    [CHALLENGE_PREFIX, channel_binding, nonce].concat()
+}
+
+/// Sign challenge's nonce (client side), with the channel binding of the
+/// connection (as returned by `Client::channel_binding()`).
+pub fn sign_challenge<Sign: SignMethod>(signer: &Sign::Signer, channel_binding: &ChannelBinding,
+                                        nonce: &Nonce)
+    -> Result<sign::Signature, sign::Error>
 {
-    pub state: IdentityState,
-    /// Signer instance
-    pub signer: Sign::Verifier,
-    /// A reference issued by identity owner, proving sign_key is allowed
-    /// to sign as the owner.
-    pub identity: Reference<bytes::AsBytes<PublicKey>,Sign>,
-    pub nonce: [u8;32],
-    // nonce_timeout, nonce_next_timeout
+    signer.try_sign(&challenge_data(channel_binding, nonce))
 }
 
+/// Return data to sign in order to resume authentication with provided
+/// token over the connection of provided channel binding.
+pub fn resumption_data(channel_binding: &ChannelBinding, token: &[u8]) -> Vec<u8> {
+    [RESUME_PREFIX, channel_binding, token].concat()
+}
 
-pub struct Auth<S,Sign>
-    where S: Service, Sign: SignMethod
+/// Sign resumption token (client side) with the channel binding of the
+/// new connection, proving that the token is presented by its subject.
+pub fn sign_resumption<Sign: SignMethod>(signer: &Sign::Signer, channel_binding: &ChannelBinding,
+                                         token: &[u8])
+    -> Result<sign::Signature, sign::Error>
 {
+    signer.try_sign(&resumption_data(channel_binding, token))
+}
+
+
+/// Session state captured by a resumption token.
+#[derive(Serialize,Deserialize,Clone)]
+#[serde(bound(serialize="Sign: SignMethod+Serialize", deserialize="Sign: SignMethod+Deserialize<'de>"))]
+pub struct Resumption<Sign: SignMethod> {
+    /// Authenticated identity.
+    pub identity: IdentityRef<Sign>,
+    /// Name of the negotiated codec.
+    pub codec: String,
+    /// Digest of the peer's capability.
+    pub capability: hash::Hash,
+    /// Unique token's nonce, refused once used.
+    pub nonce: Nonce,
+    /// Expiration timestamp (in seconds).
+    pub expires: u64,
+    /// Expiration timestamp of the resumed authentication (in seconds),
+    /// which resumed sessions do not outlive.
+    pub session_expires: u64,
+}
+
+/// Resumption signed by the server.
+#[derive(Serialize,Deserialize,Clone)]
+#[serde(bound(serialize="Sign: SignMethod+Serialize", deserialize="Sign: SignMethod+Deserialize<'de>"))]
+pub struct ResumptionToken<Sign: SignMethod> {
+    pub resumption: Resumption<Sign>,
+    #[serde(with="bytes")]
+    pub signature: sign::Signature,
+}
+
+/// Return digest of a capability, as captured by resumption tokens.
+pub fn capability_digest<Cap: Serialize>(capability: &Cap) -> hash::Hash {
+    let data = canonical::serialize(capability).unwrap_or_default();
+    hash::digest(&data)
+}
+
+
+/// Issuer and validator of resumption tokens, as used by `Auth`.
+pub trait ResumptionStore<Sign: SignMethod>: Send+Sync {
+    /// Issue an encoded token resuming provided session state, whose
+    /// authentication expires at `session_expires` (in seconds). The
+    /// capability is provided as its digest (see `capability_digest`).
+    fn issue(&self, identity: IdentityRef<Sign>, codec: &str, capability: &hash::Hash,
+             session_expires: u64)
+        -> Result<Vec<u8>, Error>;
+
+    /// Return the session state of encoded token, if it is valid for
+    /// provided codec and capability's digest.
+    fn open(&self, token: &[u8], codec: &str, capability: &hash::Hash)
+        -> Result<Resumption<Sign>, Error>;
+
+    /// Mark opened token as used, failing if it already was.
+    fn consume(&self, resumption: &Resumption<Sign>) -> Result<(), Error>;
+}
+
+
+/// Issue and validate resumption tokens, shared among the streams of a
+/// server. Nonces of used tokens are kept until their expiration: they are
+/// reclaimed as a `Reap` target (see `Resumptions::reaped`).
+pub struct Resumptions<Sign: SignMethod, C=SystemClock> {
     signer: Sign::Signer,
-    service: S,
-    peer: Option<Identity<Sign>>,
+    clock: C,
+    /// Validity of issued tokens.
+    ttl: Duration,
+    /// Used tokens' nonces, with their expiration timestamp.
+    used: Mutex<BTreeMap<Nonce, u64>>,
+}
+
+impl<Sign: SignMethod> Resumptions<Sign> {
+    pub fn new(signer: Sign::Signer, ttl: Duration) -> Self {
+        Self::with_clock(signer, ttl, SystemClock)
+    }
 }
 
+impl<Sign: SignMethod, C: Clock> Resumptions<Sign, C> {
+    /// Create issuer using provided clock.
+    pub fn with_clock(signer: Sign::Signer, ttl: Duration, clock: C) -> Self {
+        Self { signer, clock, ttl, used: Mutex::new(BTreeMap::new()) }
+    }
+}
 
-impl<S,Sign> Auth<S,Sign>
-    where S: Service, Sign: SignMethod
+impl<Sign, C> Resumptions<Sign, C>
+    where Sign: 'static+SignMethod, Sign::Signer: Send+Sync, C: 'static+Clock
 {
-    fn new(signer: Sign::Signer, service: S) -> Self {
-        Self { signer, service, peer: None }
+    /// Share resumptions, registered to provided reaper (such as
+    /// `Server::reaper`) which reclaims used tokens' nonces.
+    pub fn reaped<RC: Clock>(self, reaper: &Reaper<RC>) -> Arc<Self> {
+        let resumptions = Arc::new(self);
+        reaper.add(resumptions.clone());
+        resumptions
     }
 }
 
-#[service]
-impl<S,Sign> Auth<S,Sign>
+impl<Sign, C> Resumptions<Sign, C>
+    where Sign: SignMethod+Serialize, C: Clock
+{
+    fn signed_data(resumption: &Resumption<Sign>) -> Result<Vec<u8>, Error> {
+        let mut data = RESUMPTION_PREFIX.to_vec();
+        canonical::serialize_into(&mut data, resumption).or(Err(Error::Token))?;
+        Ok(data)
+    }
+}
+
+impl<Sign, C> ResumptionStore<Sign> for Resumptions<Sign, C>
+    where for<'de> Sign: SignMethod+Serialize+Deserialize<'de>, Sign::Signer: Send+Sync, C: Clock
+{
+    fn issue(&self, identity: IdentityRef<Sign>, codec: &str, capability: &hash::Hash,
+             session_expires: u64)
+        -> Result<Vec<u8>, Error>
+    {
+        let mut nonce = [0u8;32];
+        OsRng.fill_bytes(&mut nonce);
+        let expires = (self.clock.now() + self.ttl).as_secs().min(session_expires);
+        let resumption = Resumption { identity, codec: codec.to_string(),
+                                      capability: *capability, nonce,
+                                      expires, session_expires };
+        let data = Self::signed_data(&resumption)?;
+        let signature = self.signer.try_sign(&data).or(Err(Error::Signature))?;
+        canonical::serialize(&ResumptionToken { resumption, signature }).or(Err(Error::Token))
+    }
+
+    fn open(&self, token: &[u8], codec: &str, capability: &hash::Hash)
+        -> Result<Resumption<Sign>, Error>
+    {
+        let token: ResumptionToken<Sign> = canonical::deserialize(token).or(Err(Error::Token))?;
+        let data = Self::signed_data(&token.resumption)?;
+        let verifier = Sign::verifier(&self.signer).or(Err(Error::Signature))?;
+        verifier.verify(&data, &token.signature).or(Err(Error::Token))?;
+
+        let resumption = token.resumption;
+        if self.clock.timestamp() >= resumption.expires {
+            return Err(Error::Expired);
+        }
+        if resumption.codec != codec || resumption.capability != *capability {
+            return Err(Error::Token);
+        }
+        Ok(resumption)
+    }
+
+    fn consume(&self, resumption: &Resumption<Sign>) -> Result<(), Error> {
+        match self.used.lock().unwrap().insert(resumption.nonce, resumption.expires) {
+            Some(_) => Err(Error::Token),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<Sign, C> Reap for Resumptions<Sign, C>
+    where Sign: SignMethod, Sign::Signer: Send+Sync, C: Clock
+{
+    fn name(&self) -> &str {
+        "resumptions"
+    }
+
+    /// Remove nonces of expired tokens.
+    fn reap(&self, now: Duration) -> usize {
+        let mut used = self.used.lock().unwrap();
+        let len = used.len();
+        used.retain(|_, expires| *expires > now.as_secs());
+        len - used.len()
+    }
+}
+
+
+/// Service forwarding requests to the inner service once the peer is
+/// authenticated.
+///
+/// Identities must be issued by the configured issuer, and challenges are
+/// signed with the channel binding of the stream's connection (as returned
+/// by `Context::channel_binding()`, or `TcpPeer::channel_binding()` over
+/// TCP with TLS):
+///
+/// ```ignore
+/// server.add_context_builder(ID, move |ctx: &Ctx| Ok(Auth::new(Service::new(), issuer.clone(),
+///     ctx.channel_binding()?, AuthOptions::default())), options)?;
+/// ```
+pub struct Auth<S, Sign, C=SystemClock>
     where S: Service, Sign: SignMethod
 {
-	pub fn request_auth(&mut self, nonce: Nonce, identity: IdentityRef)
-		-> Result<(Nonce, IdentityRef, Signature)>
-	{
-	}
+    service: S,
+    /// Root issuer of accepted identities.
+    issuer: Sign::Verifier,
+    channel_binding: ChannelBinding,
+    clock: C,
+    options: AuthOptions,
+    state: IdentityState,
+    identity: Option<IdentityRef<Sign>>,
+    /// Authenticated session, tracked for leak detection.
+    session: Option<leak::Tracked>,
+    challenge: Option<Challenge<Sign>>,
+    /// Resumption tokens' issuer, along with the negotiated codec's name.
+    resumptions: Option<(Arc<dyn ResumptionStore<Sign>>, String)>,
+    /// Identities allowed to authenticate, if pinned.
+    identities: Option<Arc<Identities>>,
+    phantom: PhantomData<Sign>,
+}
+
+impl<S, Sign> Auth<S, Sign>
+    where S: Service, Sign: SignMethod
+{
+    pub fn new(service: S, issuer: Sign::Verifier, channel_binding: ChannelBinding,
+               options: AuthOptions) -> Self
+    {
+        Self::with_clock(service, issuer, channel_binding, options, SystemClock)
+    }
 }
 
+impl<S, Sign, C> Auth<S, Sign, C>
+    where S: Service, Sign: SignMethod, C: Clock
+{
+    /// Create service using provided clock.
+    pub fn with_clock(service: S, issuer: Sign::Verifier, channel_binding: ChannelBinding,
+                      options: AuthOptions, clock: C) -> Self
+    {
+        Self { service, issuer, channel_binding, clock, options, state: IdentityState::Unauthenticated,
+               identity: None, session: None, challenge: None, resumptions: None, identities: None,
+               phantom: PhantomData }
+    }
+
+    /// Enable resumption tokens, for the stream's codec of provided name.
+    pub fn with_resumptions<R>(mut self, resumptions: Arc<R>, codec: &str) -> Self
+        where R: 'static+ResumptionStore<Sign>
+    {
+        self.resumptions = Some((resumptions, codec.to_string()));
+        self
+    }
+
+    /// Only authenticate peers of provided identities, as returned by
+    /// `Dispatch::pinned`. Others fail with `Error::Forbidden`.
+    pub fn with_identities(mut self, identities: Arc<Identities>) -> Self {
+        self.identities = Some(identities);
+        self
+    }
+
+    /// Return inner service.
+    pub fn inner(&self) -> &S {
+        &self.service
+    }
+
+    /// Return peer's authentication state, updating it on expiration.
+    pub fn state(&mut self) -> IdentityState {
+        if let IdentityState::Authenticated(expires) = self.state {
+            if self.clock.now() >= expires {
+                self.state = IdentityState::Unauthenticated;
+                self.identity = None;
+                self.session = None;
+            }
+        }
+        self.state
+    }
+
+    /// Return authenticated identity.
+    pub fn identity(&mut self) -> Option<&IdentityRef<Sign>> {
+        match self.state() {
+            IdentityState::Authenticated(_) => self.identity.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Return true if peer is authenticated.
+    pub fn is_authenticated(&mut self) -> bool {
+        matches!(self.state(), IdentityState::Authenticated(_))
+    }
+
+    /// Validate identity: it must be issued by the configured issuer, and
+    /// be allowed when pinned.
+    fn validate(&self, identity: &IdentityRef<Sign>) -> Result<(), Error> {
+        if identity.issuer() != &self.issuer {
+            return Err(Error::Identity);
+        }
+        let subject = &identity.last().ok_or(Error::Identity)?.auth.subject;
+        identity.validate_with(subject, &self.clock).or(Err(Error::Identity))?;
+        match self.identities {
+            Some(ref identities) if !identities.contains(&fingerprint(identity.id())) =>
+                Err(Error::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    /// Validate identity and return a new challenge's nonce.
+    fn request(&mut self, identity: IdentityRef<Sign>) -> Result<Nonce, Error> {
+        self.validate(&identity)?;
+
+        let mut nonce = [0u8;32];
+        OsRng.fill_bytes(&mut nonce);
+        let expires = self.clock.now() + self.options.challenge_timeout;
+        self.challenge = Some(Challenge { identity, nonce, expires });
+        if self.state() == IdentityState::Unauthenticated {
+            self.state = IdentityState::Requested;
+        }
+        Ok(nonce)
+    }
+
+    /// Verify challenge's signature, authenticating peer on success.
+    fn respond(&mut self, signature: sign::Signature) -> Result<u64, Error> {
+        let challenge = self.challenge.take().ok_or(Error::NoChallenge)?;
+        if self.state() == IdentityState::Requested {
+            self.state = IdentityState::Unauthenticated;
+        }
+
+        let now = self.clock.now();
+        if now >= challenge.expires {
+            return Err(Error::Expired);
+        }
+
+        let subject = &challenge.identity.last().ok_or(Error::Identity)?.auth.subject;
+        subject.verify(&challenge_data(&self.channel_binding, &challenge.nonce), &signature)
+               .or(Err(Error::Signature))?;
+
+        let expires = self.clock.now() + self.options.ttl;
+        Ok(self.authenticated(challenge.identity, expires))
+    }
+
+    /// Authenticate peer as identity until `expires`, returning it as
+    /// timestamp.
+    fn authenticated(&mut self, identity: IdentityRef<Sign>, expires: Duration) -> u64 {
+        self.state = IdentityState::Authenticated(expires);
+        self.session = Some(leak::track(Kind::Session, "auth session"));
+        self.identity = Some(identity);
+        expires.as_secs()
+    }
+
+    /// Issue a resumption token of the current authentication.
+    fn resumption_token(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        let expires = match self.state() {
+            IdentityState::Authenticated(expires) => expires.as_secs(),
+            _ => return None,
+        };
+        let identity = self.identity.clone()?;
+        Some(match self.resumptions {
+            Some((ref resumptions, ref codec)) =>
+                resumptions.issue(identity, codec, &capability_digest(&self.service.capability()), expires),
+            None => Err(Error::Unsupported),
+        })
+    }
+
+    /// Authenticate peer from a resumption token signed by its subject for
+    /// this connection, until the resumed authentication expires.
+    fn resume(&mut self, token: &[u8], signature: sign::Signature) -> Result<u64, Error> {
+        let (resumptions, codec) = self.resumptions.as_ref().ok_or(Error::Unsupported)?;
+        let capability = capability_digest(&self.service.capability());
+        let resumption = resumptions.open(token, codec, &capability)?;
+        let subject = &resumption.identity.last().ok_or(Error::Identity)?.auth.subject;
+        subject.verify(&resumption_data(&self.channel_binding, token), &signature)
+               .or(Err(Error::Signature))?;
+        resumptions.consume(&resumption)?;
+        self.validate(&resumption.identity)?;
+
+        let expires = (self.clock.now() + self.options.ttl)
+                        .min(Duration::from_secs(resumption.session_expires));
+        Ok(self.authenticated(resumption.identity, expires))
+    }
+}
+
+
+#[async_trait]
+impl<S, Sign, C> Service for Auth<S, Sign, C>
+    where S: Service, S::Response: 'static,
+          Sign: 'static+SignMethod+Send+Sync+Unpin,
+          Sign::Verifier: Send+Sync+Unpin,
+          C: 'static+Clock+Unpin
+{
+    type Request = Request<Sign, S::Request>;
+    type Response = Response<S::Response>;
+    type Capability = S::Capability;
+
+    fn is_alive(&self) -> bool {
+        self.service.is_alive()
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        S::metas()
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        S::methods()
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        S::action_names()
+    }
+
+    /// Inner service's capability once authenticated, empty otherwise.
+    fn capability(&self) -> S::Capability {
+        match self.state {
+            IdentityState::Authenticated(expires) if self.clock.now() < expires =>
+                self.service.capability(),
+            _ => S::Capability::empty(),
+        }
+    }
+
+    fn is_cancellation_safe() -> bool {
+        S::is_cancellation_safe()
+    }
+
+    fn is_ordered(request: &Self::Request) -> bool {
+        match request {
+            Request::Request(request) => S::is_ordered(request),
+            _ => true,
+        }
+    }
+
+    fn method_index(request: &Self::Request) -> Option<usize> {
+        match request {
+            Request::Request(request) => S::method_index(request),
+            _ => None,
+        }
+    }
+
+    fn method_of(request: &Self::Request) -> Option<usize> {
+        match request {
+            Request::Request(request) => S::method_of(request),
+            _ => None,
+        }
+    }
+
+    // authenticating again over a new stream has no further effect, unlike
+    // issuing a token or resuming with a consumed one
+    fn is_idempotent(request: &Self::Request) -> bool {
+        match request {
+            Request::AuthRequest(_) | Request::AuthResponse(_) => true,
+            Request::ResumptionToken | Request::Resume(..) => false,
+            Request::Request(request) => S::is_idempotent(request),
+        }
+    }
+
+    fn call_id(request: &Self::Request) -> Option<u64> {
+        match request {
+            Request::Request(request) => S::call_id(request),
+            _ => None,
+        }
+    }
+
+    fn cancelled(request: &Self::Request) -> Option<u64> {
+        match request {
+            Request::Request(request) => S::cancelled(request),
+            _ => None,
+        }
+    }
+
+    fn trace_context(request: &Self::Request) -> Option<TraceContext> {
+        match request {
+            Request::Request(request) => S::trace_context(request),
+            _ => None,
+        }
+    }
+
+    fn is_error(response: &Self::Response) -> bool {
+        match response {
+            Response::Response(response) => S::is_error(response),
+            _ => false,
+        }
+    }
+
+    fn is_refused(response: &Self::Response) -> bool {
+        match response {
+            Response::Response(response) => S::is_refused(response),
+            _ => false,
+        }
+    }
+
+    fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+        S::denied(call_id, reason).map(Response::Response)
+    }
+
+    fn slow_down(retry_after: Duration) -> Option<Self::Response> {
+        S::slow_down(retry_after).map(Response::Response)
+    }
+
+    fn error(call_id: Option<u64>, error: message::Error) -> Option<Self::Response> {
+        S::error(call_id, error).map(Response::Response)
+    }
+
+    fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+        match response {
+            Response::Response(response) => S::downgrade(response, version).map(Response::Response),
+            response => Some(response),
+        }
+    }
+
+    fn request_frame(request: &Self::Request) -> Option<Frame> {
+        match request {
+            Request::AuthRequest(_) => Some(Frame::AuthRequest),
+            Request::AuthResponse(_) => Some(Frame::AuthResponse),
+            Request::ResumptionToken => Some(Frame::Request(Call::UNARY)),
+            Request::Resume(..) => Some(Frame::Resume),
+            Request::Request(request) => S::request_frame(request),
+        }
+    }
+
+    fn response_frame(response: &Self::Response) -> Option<Frame> {
+        match response {
+            Response::AuthRequest(result) => Some(Frame::AuthChallenge(result.is_ok())),
+            Response::AuthResponse(result) => Some(Frame::AuthResult(result.is_ok())),
+            Response::ResumptionToken(_) => Some(Frame::Response),
+            Response::Resume(result) => Some(Frame::AuthResult(result.is_ok())),
+            Response::Response(response) => S::response_frame(response),
+            Response::Unauthenticated => Some(Frame::Denied),
+        }
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        match request {
+            Request::AuthRequest(identity) => Some(Response::AuthRequest(self.request(identity))),
+            Request::AuthResponse(signature) => Some(Response::AuthResponse(self.respond(signature))),
+            Request::ResumptionToken => Some(match self.resumption_token() {
+                Some(token) => Response::ResumptionToken(token),
+                None => Response::Unauthenticated,
+            }),
+            Request::Resume(token, signature) =>
+                Some(Response::Resume(self.resume(&token, signature))),
+            Request::Request(_) if !self.is_authenticated() => Some(Response::Unauthenticated),
+            Request::Request(request) => self.service.dispatch(request).await.map(Response::Response),
+        }
+    }
+
+    async fn dispatch_streaming(&mut self, request: Self::Request)
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        match request {
+            Request::Request(request) if self.is_authenticated() =>
+                match self.service.dispatch_streaming(request).await {
+                    Ok(responses) => Ok(responses.map(Response::Response).boxed()),
+                    Err(request) => Err(Request::Request(request)),
+                },
+            request => Err(request),
+        }
+    }
+
+    async fn dispatch_incoming(&mut self, request: Self::Request,
+                               requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+        -> Result<BoxStream<'static, Self::Response>, Self::Request>
+    {
+        let request = match request {
+            Request::Request(request) if self.is_authenticated() => request,
+            request => return Err(request),
+        };
+        let mut requests = requests.filter_map(|request| future::ready(match request {
+            Request::Request(request) => Some(request),
+            _ => None,
+        }));
+        match self.service.dispatch_incoming(request, &mut requests).await {
+            Ok(responses) => Ok(responses.map(Response::Response).boxed()),
+            Err(request) => Err(Request::Request(request)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::data::{Authorization, Capability};
+    use crate::data::clock::MockClock;
+    use crate::data::signature::Dalek;
+    use crate::data::testing;
+    use crate::rpc::service::tests::simple_service;
+
+    type TestAuth = Auth<simple_service::Service, Dalek, Arc<MockClock>>;
+
+    const BINDING: ChannelBinding = [7u8;32];
+
+    fn new_auth(options: AuthOptions, clock: Arc<MockClock>) -> TestAuth {
+        let issuer = testing::signer::<Dalek>(0).public;
+        TestAuth::with_clock(simple_service::Service::new(), issuer, BINDING, options, clock)
+    }
+
+    fn identity() -> (IdentityRef<Dalek>, <Dalek as SignMethod>::Signer) {
+        let (owner, subject) = (testing::signer::<Dalek>(0), testing::signer::<Dalek>(1));
+        let auth = Authorization::new(Capability::new(u64::MAX, 0), subject.public);
+        let identity = Reference::new(owner.public.to_bytes().to_vec(), &owner, 0, auth).unwrap();
+        (identity, subject)
+    }
+
+    fn authenticate(auth: &mut TestAuth, identity: IdentityRef<Dalek>,
+                    signer: &<Dalek as SignMethod>::Signer) -> Response<simple_service::Response>
+    {
+        let nonce = match block_on(auth.dispatch(Request::AuthRequest(identity))) {
+            Some(Response::AuthRequest(Ok(nonce))) => nonce,
+            _ => panic!("challenge expected"),
+        };
+        let signature = sign_challenge::<Dalek>(signer, &BINDING, &nonce).unwrap();
+        block_on(auth.dispatch(Request::AuthResponse(signature))).unwrap()
+    }
+
+    fn add(auth: &mut TestAuth, value: u32) -> Option<Response<simple_service::Response>> {
+        block_on(auth.dispatch(Request::Request(simple_service::Request::Add(value))))
+    }
+
+    #[test]
+    fn test_auth() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let options = AuthOptions { challenge_timeout: Duration::from_secs(5),
+                                    ttl: Duration::from_secs(60) };
+        let mut auth = new_auth(options, clock.clone());
+        let (identity, signer) = identity();
+
+        assert!(matches!(add(&mut auth, 1), Some(Response::Unauthenticated)));
+        assert!(auth.capability().is_empty());
+
+        // wrong signer
+        let other = Dalek::generate().unwrap();
+        assert!(matches!(authenticate(&mut auth, identity.clone(), &other),
+                   Response::AuthResponse(Err(Error::Signature))));
+        assert_eq!(auth.state(), IdentityState::Unauthenticated);
+
+        assert!(matches!(authenticate(&mut auth, identity.clone(), &signer),
+                   Response::AuthResponse(Ok(160))));
+        assert!(auth.identity().is_some());
+        assert!(matches!(add(&mut auth, 2), Some(Response::Response(simple_service::Response::Add(2)))));
+
+        // renewal keeps peer authenticated
+        clock.advance(Duration::from_secs(50));
+        assert!(matches!(authenticate(&mut auth, identity.clone(), &signer),
+                   Response::AuthResponse(Ok(210))));
+        clock.advance(Duration::from_secs(20));
+        assert!(auth.is_authenticated());
+
+        clock.advance(Duration::from_secs(40));
+        assert!(matches!(add(&mut auth, 1), Some(Response::Unauthenticated)));
+        assert_eq!(auth.state(), IdentityState::Unauthenticated);
+    }
+
+    #[test]
+    fn test_auth_identities() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let (identity, signer) = identity();
+        let mut auth = new_auth(AuthOptions::default(), clock.clone())
+                            .with_identities(Arc::new(std::iter::once([0u8; 32]).collect()));
+        assert!(matches!(block_on(auth.dispatch(Request::AuthRequest(identity.clone()))),
+                   Some(Response::AuthRequest(Err(Error::Forbidden)))));
+        assert!(!auth.is_authenticated());
+
+        let allowed = std::iter::once(fingerprint(identity.id())).collect();
+        let mut auth = new_auth(AuthOptions::default(), clock).with_identities(Arc::new(allowed));
+        assert!(matches!(authenticate(&mut auth, identity, &signer),
+                   Response::AuthResponse(Ok(_))));
+    }
+
+    #[test]
+    fn test_auth_challenge() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let mut auth = new_auth(AuthOptions::default(), clock.clone());
+        let (identity, signer) = identity();
+        let signature = sign_challenge::<Dalek>(&signer, &BINDING, &[0u8;32]).unwrap();
+
+        assert!(matches!(block_on(auth.dispatch(Request::AuthResponse(signature))),
+                   Some(Response::AuthResponse(Err(Error::NoChallenge)))));
+
+        // tampered certificate's signature
+        let mut data = bincode::serialize(&identity).unwrap();
+        let index = data.len() - 64;
+        data[index] ^= 0xff;
+        let invalid: IdentityRef<Dalek> = bincode::deserialize(&data).unwrap();
+        assert!(matches!(block_on(auth.dispatch(Request::AuthRequest(invalid))),
+                   Some(Response::AuthRequest(Err(Error::Identity)))));
+        assert_eq!(auth.state(), IdentityState::Unauthenticated);
+
+        let nonce = match block_on(auth.dispatch(Request::AuthRequest(identity))) {
+            Some(Response::AuthRequest(Ok(nonce))) => nonce,
+            _ => panic!("challenge expected"),
+        };
+        assert_eq!(auth.state(), IdentityState::Requested);
+        clock.advance(AuthOptions::default().challenge_timeout);
+        let signature = sign_challenge::<Dalek>(&signer, &BINDING, &nonce).unwrap();
+        assert!(matches!(block_on(auth.dispatch(Request::AuthResponse(signature))),
+                   Some(Response::AuthResponse(Err(Error::Expired)))));
+    }
+
+    #[test]
+    fn test_auth_issuer_binding() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let mut auth = new_auth(AuthOptions::default(), clock);
+
+        // self-signed identity
+        let (owner, subject) = (testing::signer::<Dalek>(2), testing::signer::<Dalek>(1));
+        let self_signed = Reference::new(owner.public.to_bytes().to_vec(), &owner, 0,
+                                         Authorization::new(Capability::new(u64::MAX, 0), subject.public))
+                                    .unwrap();
+        assert!(matches!(block_on(auth.dispatch(Request::AuthRequest(self_signed))),
+                   Some(Response::AuthRequest(Err(Error::Identity)))));
+
+        // signature relayed from another connection
+        let (identity, signer) = identity();
+        let nonce = match block_on(auth.dispatch(Request::AuthRequest(identity))) {
+            Some(Response::AuthRequest(Ok(nonce))) => nonce,
+            _ => panic!("challenge expected"),
+        };
+        let signature = sign_challenge::<Dalek>(&signer, &[0u8;32], &nonce).unwrap();
+        assert!(matches!(block_on(auth.dispatch(Request::AuthResponse(signature))),
+                   Some(Response::AuthResponse(Err(Error::Signature)))));
+        assert!(!auth.is_authenticated());
+    }
+
+    #[test]
+    fn test_resumption() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(100)));
+        let reaper = Reaper::with_clock(Duration::from_secs(60), clock.clone());
+        let resumptions = Resumptions::<Dalek, _>::with_clock(
+            testing::signer::<Dalek>(3), Duration::from_secs(300), clock.clone()).reaped(&reaper);
+        let resumable = |codec: &str| {
+            new_auth(AuthOptions::default(), clock.clone()).with_resumptions(resumptions.clone(), codec)
+        };
+        let (identity, signer) = identity();
+        let resume_as = |auth: &mut TestAuth, token: &Vec<u8>, binding: &ChannelBinding| {
+            let signature = sign_resumption::<Dalek>(&signer, binding, token).unwrap();
+            match block_on(auth.dispatch(Request::Resume(token.clone(), signature))) {
+                Some(Response::Resume(result)) => result,
+                _ => panic!("resume response expected"),
+            }
+        };
+        let resume = |auth: &mut TestAuth, token: &Vec<u8>| resume_as(auth, token, &BINDING);
+        let token = |auth: &mut TestAuth| {
+            match block_on(auth.dispatch(Request::ResumptionToken)) {
+                Some(Response::ResumptionToken(Ok(token))) => token,
+                _ => panic!("token expected"),
+            }
+        };
+
+        let mut auth = resumable("bincode");
+        assert!(matches!(block_on(auth.dispatch(Request::ResumptionToken)),
+                         Some(Response::Unauthenticated)));
+        authenticate(&mut auth, identity, &signer);
+        let first = token(&mut auth);
+
+        // token relayed to another connection, which does not consume it
+        let mut resumed = resumable("bincode");
+        assert_eq!(resume_as(&mut resumed, &first, &[0u8;32]), Err(Error::Signature));
+        assert!(!resumed.is_authenticated());
+
+        // resumed on a new stream, only once
+        assert_eq!(resume(&mut resumed, &first), Ok(100 + AuthOptions::default().ttl.as_secs()));
+        assert!(resumed.is_authenticated());
+        assert!(matches!(add(&mut resumed, 2), Some(Response::Response(simple_service::Response::Add(2)))));
+        assert_eq!(resume(&mut resumable("bincode"), &first), Err(Error::Token));
+
+        // another codec, tampered or expired token
+        let second = token(&mut resumed);
+        assert_eq!(resume(&mut resumable("json"), &second), Err(Error::Token));
+        let mut tampered = second.clone();
+        let index = tampered.len() - 1;
+        tampered[index] ^= 0xff;
+        assert_eq!(resume(&mut resumable("bincode"), &tampered), Err(Error::Token));
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(resume(&mut resumable("bincode"), &second), Err(Error::Expired));
+
+        // resumed sessions do not outlive the original authentication
+        clock.advance(Duration::from_secs(3000));
+        let third = token(&mut resumed);
+        assert_eq!(resume(&mut resumable("bincode"), &third),
+                   Ok(100 + AuthOptions::default().ttl.as_secs()));
+
+        // not enabled
+        let mut auth = new_auth(AuthOptions::default(), clock.clone());
+        assert_eq!(resume(&mut auth, &second), Err(Error::Unsupported));
+        assert_eq!(reaper.reap(), 1);
+    }
+}
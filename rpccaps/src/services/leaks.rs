@@ -0,0 +1,132 @@
+//! Report of a server's outstanding resources (see `rpc::leak`), enabled
+//! by the `leak-detection` feature. The service is meant for operators:
+//! its id should be pinned to trusted identities (see `Dispatch::pin`),
+//! since reports expose the server's backtraces.
+//!
+//! ```ignore
+//! server.dispatch.pin(LEAKS_ID, [admin]);
+//! server.dispatch.add_with(LEAKS_ID, Box::new(|(s, r, _)| Box::pin(
+//!     async move { Reporter.serve(Transport::new(s, r)).await; })),
+//!     HandlerOptions::default())?;
+//!
+//! let transport = client.open(LEAKS_ID).await?;
+//! let report = leaks::Client::new(transport).report(Duration::from_secs(600)).await?;
+//! println!("{}", report.to_text());
+//! ```
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use serde::{Serialize,Deserialize};
+
+use crate::rpc::call::{with_timeout, CallError, ClientOptions};
+use crate::rpc::leak::{self, Report};
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::service::Service;
+
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Request {
+    /// Return resources outstanding for at least provided duration.
+    Report(Duration),
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Response {
+    Report(Report),
+}
+
+
+/// Service reporting outstanding resources.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Reporter;
+
+#[async_trait]
+impl Service for Reporter {
+    type Request = Request;
+    type Response = Response;
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        &[("name", "leaks")]
+    }
+
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        Some(Frame::Request(Call::UNARY))
+    }
+
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        Some(Frame::Response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        match request {
+            Request::Report(age) => Some(Response::Report(leak::report().older_than(age))),
+        }
+    }
+}
+
+
+/// Client of the leaks reporter.
+pub struct Client<T> {
+    transport: T,
+    options: ClientOptions,
+    /// False once a call timed out: its response may still come.
+    synced: bool,
+}
+
+impl<T> Client<T>
+    where T: Stream<Item=Response>+Sink<Request>+Unpin
+{
+    pub fn new(transport: T) -> Self {
+        Self::with_options(transport, ClientOptions::default())
+    }
+
+    pub fn with_options(transport: T, options: ClientOptions) -> Self {
+        Self { transport, options, synced: true }
+    }
+
+    /// Return resources outstanding for at least `age`.
+    pub async fn report(&mut self, age: Duration) -> Result<Report, CallError> {
+        if !self.synced {
+            return Err(CallError::Desynced);
+        }
+        self.transport.send(Request::Report(age)).await.or(Err(CallError::Failed))?;
+        self.synced = false;
+        let response = with_timeout(self.options.request_timeout, self.transport.next()).await?;
+        self.synced = true;
+        match response {
+            Some(Response::Report(report)) => Ok(report),
+            None => Err(CallError::Failed),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::rpc::leak::Kind;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    #[test]
+    fn test_reporter() {
+        let _session = leak::track(Kind::Session, "test-reporter");
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+        let client_fut = async move {
+            Client::new(client_transport).report(Duration::ZERO).await.unwrap()
+        };
+        let server_fut = async move {
+            let (s,r) = server_transport.split();
+            Reporter.serve(Transport::new(s, r)).await;
+        };
+        let (report, _) = LocalPool::new().run_until(join(client_fut, server_fut));
+        assert!(report.entries.iter().any(|entry| entry.label == "test-reporter"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Export of a server's metrics.
+//!
+//! The exporter service returns the metrics of a `Collector` in
+//! Prometheus' text format. `Server::add_metrics()` registers it at the
+//! provided id, pinned to the scrapers' identities (see `Dispatch::pin`):
+//! metrics expose the server's load and its callers' identities. Its
+//! `metrics` method is also guarded by the first capability bit, for exporters
+//! wrapped by an authorization layer.
+//!
+//! ```ignore
+//! server.add_metrics(METRICS_ID, collector, [scraper])?;
+//!
+//! let transport = client.open(METRICS_ID).await?;
+//! let text = metrics::Client::new(transport).metrics().await?;
+//! ```
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use serde::{Serialize,Deserialize};
+
+use crate::rpc::call::{with_timeout, CallError, ClientOptions};
+use crate::rpc::metrics::Collector;
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::service::Service;
+
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Request {
+    /// Return metrics in Prometheus' text format.
+    Metrics,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Response {
+    Metrics(String),
+}
+
+
+/// Service exporting the metrics of a collector.
+#[derive(Clone)]
+pub struct Exporter {
+    collector: Arc<Collector>,
+}
+
+impl Exporter {
+    pub fn new(collector: Arc<Collector>) -> Self {
+        Self { collector }
+    }
+}
+
+#[async_trait]
+impl Service for Exporter {
+    type Request = Request;
+    type Response = Response;
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        &[("name", "metrics")]
+    }
+
+    fn methods() -> &'static [(&'static str, usize)] {
+        &[("metrics", 0)]
+    }
+
+    fn action_names() -> &'static [&'static str] {
+        &["metrics"]
+    }
+
+    fn method_index(request: &Self::Request) -> Option<usize> {
+        match request {
+            Request::Metrics => Some(0),
+        }
+    }
+
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        Some(Frame::Request(Call::UNARY))
+    }
+
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        Some(Frame::Response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        match request {
+            Request::Metrics => Some(Response::Metrics(self.collector.snapshot().to_prometheus())),
+        }
+    }
+}
+
+
+/// Client of the metrics exporter.
+pub struct Client<T> {
+    transport: T,
+    options: ClientOptions,
+}
+
+impl<T> Client<T>
+    where T: Stream<Item=Response>+Sink<Request>+Unpin
+{
+    pub fn new(transport: T) -> Self {
+        Self::with_options(transport, ClientOptions::default())
+    }
+
+    pub fn with_options(transport: T, options: ClientOptions) -> Self {
+        Self { transport, options }
+    }
+
+    /// Return server's metrics in Prometheus' text format.
+    pub async fn metrics(&mut self) -> Result<String, CallError> {
+        self.transport.send(Request::Metrics).await.or(Err(CallError::Failed))?;
+        match with_timeout(self.options.request_timeout, self.transport.next()).await? {
+            Some(Response::Metrics(text)) => Ok(text),
+            None => Err(CallError::Failed),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    #[test]
+    fn test_exporter() {
+        let collector = Arc::new(Collector::new());
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+        let client_fut = async move {
+            Client::new(client_transport).metrics().await.unwrap()
+        };
+        let server_fut = {
+            let collector = collector.clone();
+            async move {
+                let mut service = collector.measure("simple", simple_service::Service::new());
+                service.dispatch(simple_service::Request::Add(1)).await;
+
+                let (s,r) = server_transport.split();
+                Exporter::new(collector).serve(Transport::new(s, r)).await;
+            }
+        };
+        let (text, _) = LocalPool::new().run_until(join(client_fut, server_fut));
+        assert!(text.contains("rpccaps_requests_total{service=\"simple\",method=\"add\"} 1\n"));
+    }
+}
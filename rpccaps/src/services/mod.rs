@@ -1,2 +1,9 @@
-// pub mod auth;
-
+pub mod auth;
+#[cfg(feature="leak-detection")]
+pub mod leaks;
+pub mod ping;
+#[cfg(feature="pairing")]
+pub mod pairing;
+pub mod registry;
+#[cfg(feature="metrics")]
+pub mod metrics;
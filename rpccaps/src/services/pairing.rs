@@ -0,0 +1,588 @@
+//! Pairing of a new device over an insecure channel, using a short code.
+//!
+//! Bootstrapping a device requires delegating it a reference, while both
+//! devices share no key nor certificate yet. Pairing runs SPAKE2 over the
+//! Ristretto group, keyed by a short code that the user reads on the
+//! issuing device and types on the new one: peers derive the same keys
+//! only when they used the same code, and an attacker on the channel gets
+//! a single guess of it.
+//!
+//! The flow is the following:
+//! - the issuer creates a `Ceremony` with the reference to delegate, its
+//!   signer and a code from `generate_code()` to display, and serves it
+//!   with `Pairing`;
+//! - the new device sends its PAKE message along with its public key
+//!   (`Request::Start`); the issuer answers with its own message and the
+//!   confirmation of the derived keys;
+//! - the new device checks the confirmation and sends its own one
+//!   (`Request::Confirm`). The issuer then delegates the reference to the
+//!   device's key, and sends it authenticated by the derived keys.
+//!
+//! The public key of the new device is part of the PAKE transcript, so
+//! that it can not be replaced on the way.
+//!
+//! Derived values are hashed with the crate's hasher (see `data::hash`),
+//! and authenticated with HMAC over it: both devices must use the same
+//! one.
+//!
+//! ```ignore
+//! let code = pairing::generate_code();
+//! println!("pairing code: {}", code);
+//! let ceremony = Arc::new(Ceremony::new(&code, reference, signer).expires_in(Duration::from_secs(86400)));
+//! server.dispatch.add_builder(PAIRING_ID, Box::new(move |_| Pairing::new(ceremony.clone())),
+//!                             HandlerOptions::default())?;
+//!
+//! // on the new device
+//! let reference = pairing::pair::<_, Vec<u8>, Dalek, Capability>(&mut transport, &code, keypair.public).await?;
+//! ```
+//!
+//! A ceremony is single-use: the first `Start` request consumes it,
+//! whatever the outcome of the pairing.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use futures::prelude::*;
+use hmac::{Mac as _, NewMac};
+use rand_core::{OsRng, RngCore};
+use serde::{Serialize,Deserialize,de::DeserializeOwned};
+use sha2::digest::{self, generic_array::{GenericArray, typenum::{U32, U64}}};
+
+use crate::data::bytes::{self, Bytes};
+use crate::data::{Capability, CapabilitySet};
+use crate::data::hash::{DefaultHasher, Hash, Hasher};
+use crate::data::signature::SignMethod;
+use crate::data::reference::Reference;
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::service::Service;
+
+
+/// PAKE message: a compressed Ristretto point.
+pub type Message = [u8;32];
+/// Message authentication code.
+pub type Mac = [u8;32];
+
+/// Domain separator of the derived values.
+const CONTEXT: &[u8] = b"rpccaps-pairing:";
+/// Seeds of the SPAKE2 blinding points, of the issuer (M) and the new
+/// device (N).
+const SEED_M: &[u8] = b"rpccaps-pairing:M";
+const SEED_N: &[u8] = b"rpccaps-pairing:N";
+
+/// Count of digits of generated codes.
+const CODE_DIGITS: u32 = 8;
+
+
+/// Pairing errors.
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Error {
+    /// Ceremony has already been used.
+    Used,
+    /// No pairing has been started.
+    NotStarted,
+    /// Peer's PAKE message is invalid.
+    Message,
+    /// Keys' confirmation failed: peers did not use the same code.
+    Confirmation,
+    /// Reference can not be delegated, or is invalid.
+    Reference,
+    /// Stream closed or unexpected response (client side).
+    Transport,
+}
+
+
+#[derive(Serialize,Deserialize)]
+#[serde(bound(serialize="Sign: SignMethod+Serialize", deserialize="Sign: SignMethod+Deserialize<'de>"))]
+pub enum Request<Sign: SignMethod> {
+    /// New device's PAKE message and public key.
+    Start(Message, #[serde(with="bytes")] Sign::Verifier),
+    /// New device's keys confirmation.
+    Confirm(Mac),
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Response {
+    /// Issuer's PAKE message and keys confirmation.
+    Start(Result<(Message, Mac), Error>),
+    /// Delegated reference.
+    Confirm(Result<Delivery, Error>),
+}
+
+/// Reference delegated to the new device, as a token authenticated by the
+/// derived keys.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct Delivery {
+    pub token: String,
+    pub mac: Mac,
+}
+
+
+/// Return a new random code, as displayed to the user: digits grouped by
+/// four.
+pub fn generate_code() -> String {
+    let code = OsRng.next_u64() % 10u64.pow(CODE_DIGITS);
+    let code = format!("{:0width$}", code, width=CODE_DIGITS as usize);
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Return code as used by the PAKE: only its alphanumeric characters, so
+/// that users can type it with or without separators.
+fn normalize(code: &str) -> String {
+    code.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// Return 64 bytes derived from `data`, as hashed onto the Ristretto
+/// group.
+fn wide(data: &[&[u8]]) -> [u8;64] {
+    let mut wide = [0u8;64];
+    for (index, half) in wide.chunks_mut(32).enumerate() {
+        let mut hasher = DefaultHasher::default();
+        hasher.update(&[index as u8]);
+        data.iter().for_each(|data| hasher.update(data));
+        half.copy_from_slice(&hasher.finalize());
+    }
+    wide
+}
+
+
+/// Crate's hasher as a `digest` hash function, used by HMAC.
+#[derive(Clone,Default)]
+struct HashDigest(DefaultHasher);
+
+impl digest::Update for HashDigest {
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        self.0.update(data.as_ref());
+    }
+}
+
+impl digest::BlockInput for HashDigest {
+    type BlockSize = U64;
+}
+
+impl digest::FixedOutput for HashDigest {
+    type OutputSize = U32;
+
+    fn finalize_into(self, out: &mut GenericArray<u8, U32>) {
+        out.copy_from_slice(&self.0.finalize());
+    }
+
+    fn finalize_into_reset(&mut self, out: &mut GenericArray<u8, U32>) {
+        out.copy_from_slice(&std::mem::take(&mut self.0).finalize());
+    }
+}
+
+impl digest::Reset for HashDigest {
+    fn reset(&mut self) {
+        self.0 = DefaultHasher::default();
+    }
+}
+
+type Hmac = hmac::Hmac<HashDigest>;
+
+/// Return HMAC of `data` with `key`.
+fn hmac(key: &Hash, data: &[&[u8]]) -> Hmac {
+    let mut hmac = Hmac::new_from_slice(key).unwrap();
+    data.iter().for_each(|data| hmac.update(data));
+    hmac
+}
+
+
+/// Side of a pairing.
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Side {
+    Issuer,
+    Subject,
+}
+
+/// Keys derived by the PAKE.
+struct Keys {
+    /// Key of the confirmations.
+    confirm: Hash,
+    /// Key authenticating the delivery.
+    session: Hash,
+}
+
+impl Keys {
+    fn confirmation_hmac(&self, side: Side) -> Hmac {
+        let label: &[u8] = match side {
+            Side::Issuer => b"issuer",
+            Side::Subject => b"subject",
+        };
+        hmac(&self.confirm, &[label])
+    }
+
+    /// Return confirmation sent by `side`.
+    fn confirmation(&self, side: Side) -> Mac {
+        self.confirmation_hmac(side).finalize().into_bytes().into()
+    }
+
+    /// Verify confirmation sent by `side`, in constant time.
+    fn is_confirmation(&self, side: Side, confirmation: &Mac) -> bool {
+        self.confirmation_hmac(side).verify(confirmation).is_ok()
+    }
+
+    /// Return MAC of a delivered token.
+    fn delivery(&self, token: &str) -> Mac {
+        hmac(&self.session, &[b"reference", token.as_bytes()]).finalize().into_bytes().into()
+    }
+
+    /// Verify MAC of a delivered token, in constant time.
+    fn is_delivery(&self, token: &str, mac: &Mac) -> bool {
+        hmac(&self.session, &[b"reference", token.as_bytes()]).verify(mac).is_ok()
+    }
+}
+
+/// SPAKE2 state of one side.
+struct Pake {
+    side: Side,
+    password: Scalar,
+    secret: Scalar,
+    message: Message,
+}
+
+impl Pake {
+    fn new(side: Side, code: &str) -> Self {
+        let password = Scalar::from_bytes_mod_order_wide(&wide(&[CONTEXT, normalize(code).as_bytes()]));
+        let secret = Scalar::random(&mut OsRng);
+        let point = &secret * &RISTRETTO_BASEPOINT_TABLE + password * Self::blind(side);
+        Self { side, password, secret, message: point.compress().to_bytes() }
+    }
+
+    /// Blinding point of provided side.
+    fn blind(side: Side) -> RistrettoPoint {
+        RistrettoPoint::from_uniform_bytes(&wide(&[match side {
+            Side::Issuer => SEED_M,
+            Side::Subject => SEED_N,
+        }]))
+    }
+
+    /// Derive keys from peer's message, `context` being added to the
+    /// transcript.
+    fn finish(&self, peer: &Message, context: &[u8]) -> Result<Keys, Error> {
+        let peer_side = match self.side {
+            Side::Issuer => Side::Subject,
+            Side::Subject => Side::Issuer,
+        };
+        let point = CompressedRistretto(*peer).decompress().ok_or(Error::Message)?;
+        let shared = self.secret * (point - self.password * Self::blind(peer_side));
+        if shared == RistrettoPoint::identity() {
+            return Err(Error::Message);
+        }
+
+        let (issuer, subject) = match self.side {
+            Side::Issuer => (&self.message, peer),
+            Side::Subject => (peer, &self.message),
+        };
+        let mut hasher = DefaultHasher::default();
+        hasher.update(CONTEXT);
+        for data in [&issuer[..], &subject[..], shared.compress().as_bytes(), self.password.as_bytes(),
+                     context].iter()
+        {
+            hasher.update(&(data.len() as u64).to_le_bytes());
+            hasher.update(data);
+        }
+        let transcript = hasher.finalize();
+        let key = |label: &[u8]| hmac(&transcript, &[label]).finalize().into_bytes().into();
+        Ok(Keys { confirm: key(b"confirm"), session: key(b"session") })
+    }
+}
+
+
+/// Issuer's side of a pairing: reference to delegate to the new device,
+/// shared among the streams of a server.
+pub struct Ceremony<Id: Clone, Sign: SignMethod, Cap=Capability> {
+    issuer: Mutex<Option<Issuer<Id, Sign, Cap>>>,
+}
+
+struct Issuer<Id: Clone, Sign: SignMethod, Cap> {
+    code: String,
+    reference: Reference<Id, Sign, Cap>,
+    signer: Sign::Signer,
+    capability: Option<Cap>,
+    ttl: Option<Duration>,
+}
+
+impl<Id: Clone, Sign: SignMethod, Cap> Ceremony<Id, Sign, Cap> {
+    /// Create ceremony delegating `reference`, whose last subject is
+    /// `signer`'s key.
+    pub fn new(code: &str, reference: Reference<Id, Sign, Cap>, signer: Sign::Signer) -> Self {
+        let issuer = Issuer { code: code.to_string(), reference, signer, capability: None, ttl: None };
+        Self { issuer: Mutex::new(Some(issuer)) }
+    }
+
+    /// Delegate provided capability instead of the delegable actions.
+    pub fn with_capability(mut self, capability: Cap) -> Self {
+        if let Some(issuer) = self.issuer.get_mut().unwrap().as_mut() {
+            issuer.capability = Some(capability);
+        }
+        self
+    }
+
+    /// Expire delegation after provided duration from the pairing.
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        if let Some(issuer) = self.issuer.get_mut().unwrap().as_mut() {
+            issuer.ttl = Some(ttl);
+        }
+        self
+    }
+
+    /// Return true if the ceremony has been used.
+    pub fn is_used(&self) -> bool {
+        self.issuer.lock().unwrap().is_none()
+    }
+
+    fn take(&self) -> Option<Issuer<Id, Sign, Cap>> {
+        self.issuer.lock().unwrap().take()
+    }
+}
+
+
+/// Pairing started by the new device, waiting for its confirmation.
+struct Pending<Id: Clone, Sign: SignMethod, Cap> {
+    issuer: Issuer<Id, Sign, Cap>,
+    subject: Sign::Verifier,
+    keys: Keys,
+}
+
+/// Service running the issuer's side of a pairing ceremony.
+pub struct Pairing<Id: Clone, Sign: SignMethod, Cap=Capability> {
+    ceremony: Arc<Ceremony<Id, Sign, Cap>>,
+    pending: Option<Pending<Id, Sign, Cap>>,
+    /// Pairing is over, whatever its outcome.
+    finished: bool,
+}
+
+impl<Id, Sign, Cap> Pairing<Id, Sign, Cap>
+    where Id: Clone+Serialize, Sign: SignMethod, Cap: CapabilitySet+Serialize
+{
+    pub fn new(ceremony: Arc<Ceremony<Id, Sign, Cap>>) -> Self {
+        Self { ceremony, pending: None, finished: false }
+    }
+
+    /// Consume the ceremony, returning issuer's message and confirmation.
+    fn start(&mut self, message: Message, subject: Sign::Verifier) -> Result<(Message, Mac), Error> {
+        let issuer = self.ceremony.take().ok_or(Error::Used)?;
+        let pake = Pake::new(Side::Issuer, &issuer.code);
+        let keys = pake.finish(&message, subject.as_bytes())?;
+        let confirmation = keys.confirmation(Side::Issuer);
+        self.pending = Some(Pending { issuer, subject, keys });
+        Ok((pake.message, confirmation))
+    }
+
+    /// Verify new device's confirmation, returning the delegated reference.
+    fn confirm(&mut self, confirmation: Mac) -> Result<Delivery, Error>
+        where Sign: Serialize+DeserializeOwned, Id: DeserializeOwned, Cap: DeserializeOwned
+    {
+        let Pending { issuer, subject, keys } = self.pending.take().ok_or(Error::NotStarted)?;
+        if !keys.is_confirmation(Side::Subject, &confirmation) {
+            return Err(Error::Confirmation);
+        }
+
+        let Issuer { mut reference, signer, capability, ttl, .. } = issuer;
+        let mut delegation = reference.delegate_to(subject);
+        if let Some(capability) = capability {
+            delegation = delegation.with_capability(capability);
+        }
+        if let Some(ttl) = ttl {
+            delegation = delegation.expires_in(ttl);
+        }
+        delegation.sign(&signer).or(Err(Error::Reference))?;
+        let token = reference.to_token().or(Err(Error::Reference))?;
+        Ok(Delivery { mac: keys.delivery(&token), token })
+    }
+}
+
+#[async_trait]
+impl<Id, Sign, Cap> Service for Pairing<Id, Sign, Cap>
+    where Id: 'static+Clone+Serialize+DeserializeOwned+Send+Sync+Unpin,
+          Cap: 'static+CapabilitySet+Serialize+DeserializeOwned+Send+Sync+Unpin,
+          for<'de> Sign: 'static+SignMethod+Serialize+Deserialize<'de>+Send+Sync+Unpin,
+          Sign::Signer: Send+Sync+Unpin,
+          Sign::Verifier: Send+Sync+Unpin
+{
+    type Request = Request<Sign>;
+    type Response = Response;
+
+    /// Alive until the pairing is over.
+    fn is_alive(&self) -> bool {
+        !self.finished
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        &[("name", "pairing")]
+    }
+
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        Some(Frame::Request(Call::UNARY))
+    }
+
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        Some(Frame::Response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        Some(match request {
+            Request::Start(message, subject) => {
+                let response = self.start(message, subject);
+                self.finished = response.is_err();
+                Response::Start(response)
+            },
+            Request::Confirm(confirmation) => {
+                self.finished = true;
+                Response::Confirm(self.confirm(confirmation))
+            },
+        })
+    }
+}
+
+
+/// New device's side of a pairing.
+pub struct Joiner<Sign: SignMethod> {
+    pake: Pake,
+    subject: Sign::Verifier,
+    keys: Option<Keys>,
+}
+
+impl<Sign: SignMethod> Joiner<Sign> {
+    /// Join pairing using the code displayed by the issuer, for the key of
+    /// `subject`.
+    pub fn new(code: &str, subject: Sign::Verifier) -> Self {
+        Self { pake: Pake::new(Side::Subject, code), subject, keys: None }
+    }
+
+    /// Return request starting the pairing.
+    pub fn start(&self) -> Request<Sign> {
+        Request::Start(self.pake.message, self.subject.clone())
+    }
+
+    /// Verify issuer's confirmation, returning the request confirming
+    /// ours.
+    pub fn confirm(&mut self, message: &Message, confirmation: &Mac) -> Result<Request<Sign>, Error> {
+        let keys = self.pake.finish(message, self.subject.as_bytes())?;
+        if !keys.is_confirmation(Side::Issuer, confirmation) {
+            return Err(Error::Confirmation);
+        }
+        let request = Request::Confirm(keys.confirmation(Side::Subject));
+        self.keys = Some(keys);
+        Ok(request)
+    }
+
+    /// Verify delivery, returning the reference delegated to the subject.
+    pub fn finish<Id, Cap>(&self, delivery: &Delivery) -> Result<Reference<Id, Sign, Cap>, Error>
+        where Id: Clone+Serialize+DeserializeOwned, Sign: Serialize+DeserializeOwned,
+              Cap: CapabilitySet+Serialize+DeserializeOwned
+    {
+        let keys = self.keys.as_ref().ok_or(Error::NotStarted)?;
+        if !keys.is_delivery(&delivery.token, &delivery.mac) {
+            return Err(Error::Confirmation);
+        }
+        let reference = Reference::from_token(&delivery.token).or(Err(Error::Reference))?;
+        reference.validate_with(&self.subject, &crate::data::SystemClock).or(Err(Error::Reference))?;
+        Ok(reference)
+    }
+}
+
+
+/// Run the new device's side of a pairing over `transport`, returning the
+/// reference delegated to `subject`.
+pub async fn pair<T, Id, Sign, Cap>(transport: &mut T, code: &str, subject: Sign::Verifier)
+    -> Result<Reference<Id, Sign, Cap>, Error>
+    where T: Stream<Item=Response>+Sink<Request<Sign>>+Unpin,
+          Id: Clone+Serialize+DeserializeOwned,
+          Sign: SignMethod+Serialize+DeserializeOwned,
+          Cap: CapabilitySet+Serialize+DeserializeOwned
+{
+    let mut joiner = Joiner::<Sign>::new(code, subject);
+    transport.send(joiner.start()).await.or(Err(Error::Transport))?;
+    let request = match transport.next().await {
+        Some(Response::Start(response)) => {
+            let (message, confirmation) = response?;
+            joiner.confirm(&message, &confirmation)?
+        },
+        _ => return Err(Error::Transport),
+    };
+    transport.send(request).await.or(Err(Error::Transport))?;
+    match transport.next().await {
+        Some(Response::Confirm(delivery)) => joiner.finish(&delivery?),
+        _ => Err(Error::Transport),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::future::join;
+
+    use super::*;
+    use crate::data::reference::Authorization;
+    use crate::data::signature::Dalek;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    fn ceremony(code: &str) -> (Arc<Ceremony<u64, Dalek>>, <Dalek as SignMethod>::Verifier) {
+        let (root, issuer, device) = (Dalek::generate().unwrap(), Dalek::generate().unwrap(),
+                                      Dalek::generate().unwrap());
+        let auth = Authorization::new(Capability::new(0b1111, 0b0111), issuer.public);
+        let reference = Reference::<u64, Dalek>::new(1, &root, 4, auth).unwrap();
+        let ceremony = Ceremony::new(code, reference, issuer).with_capability(Capability::new(0b0011, 0));
+        (Arc::new(ceremony), device.public)
+    }
+
+    fn run(ceremony: Arc<Ceremony<u64, Dalek>>, code: &str, subject: <Dalek as SignMethod>::Verifier)
+        -> Result<Reference<u64, Dalek>, Error>
+    {
+        let (server_transport, mut client_transport) = MPSCTransport::<Response, Request<Dalek>>::bi(4);
+        let server_fut = async move {
+            let (mut sender, mut receiver) = server_transport.split();
+            Pairing::new(ceremony).serve(Transport::new(&mut sender, &mut receiver)).await;
+        };
+        let client_fut = async move {
+            let reference = pair(&mut client_transport, code, subject).await;
+            drop(client_transport);
+            reference
+        };
+        block_on(join(client_fut, server_fut)).0
+    }
+
+    #[test]
+    fn test_pair() {
+        let code = generate_code();
+        assert_eq!(code.len(), 9);
+        let (ceremony, subject) = ceremony(&code);
+
+        // typed without separator
+        let reference = run(ceremony.clone(), &normalize(&code), subject).unwrap();
+        let auth = &reference.last().unwrap().auth;
+        assert_eq!((auth.subject, auth.capability.clone()), (subject, Capability::new(0b0011, 0)));
+        assert!(ceremony.is_used());
+        assert_eq!(run(ceremony, &code, subject).err(), Some(Error::Used));
+    }
+
+    #[test]
+    fn test_pair_wrong_code() {
+        let (ceremony, subject) = ceremony("1234-5678");
+        assert_eq!(run(ceremony.clone(), "1234-5679", subject).err(), Some(Error::Confirmation));
+        // single guess
+        assert_eq!(run(ceremony, "1234-5678", subject).err(), Some(Error::Used));
+    }
+
+    #[test]
+    fn test_replaced_subject() {
+        let (ceremony, subject) = ceremony("1234-5678");
+        let mut pairing = Pairing::new(ceremony);
+        let mut joiner = Joiner::<Dalek>::new("1234-5678", subject);
+
+        // key replaced on the way by an attacker's one
+        let attacker = Dalek::generate().unwrap().public;
+        let message = match joiner.start() {
+            Request::Start(message, _) => message,
+            _ => unreachable!(),
+        };
+        let (message, confirmation) = pairing.start(message, attacker).unwrap();
+        assert_eq!(joiner.confirm(&message, &confirmation).err(), Some(Error::Confirmation));
+    }
+}
@@ -0,0 +1,262 @@
+//! Application-level liveness of a peer.
+//!
+//! QUIC idle timeouts keep the connection up, but don't tell the
+//! application whether the peer still serves requests. The `Ping` service
+//! answers each ping with its sequence number; on the client side, a
+//! `Heartbeat` sends pings over a dedicated stream every interval,
+//! measuring round-trip times into a shared `Liveness`:
+//!
+//! ```ignore
+//! server.dispatch.add_builder(PING_ID, Box::new(|_| Ping), HandlerOptions::default())?;
+//!
+//! let heartbeat = Heartbeat::new(client.open(PING_ID).await?, HeartbeatOptions::default());
+//! let liveness = heartbeat.liveness().clone();
+//! tokio::spawn(heartbeat.run());
+//! if !liveness.is_alive() { ... }
+//! ```
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use serde::{Serialize,Deserialize};
+
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::service::Service;
+
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Request {
+    /// Ping with its sequence number.
+    Ping(u64),
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Response {
+    /// Answer to the ping of this sequence number.
+    Pong(u64),
+}
+
+
+/// Service answering pings.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Ping;
+
+#[async_trait]
+impl Service for Ping {
+    type Request = Request;
+    type Response = Response;
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        &[("name", "ping")]
+    }
+
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        Some(Frame::Request(Call::UNARY))
+    }
+
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        Some(Frame::Response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        match request {
+            Request::Ping(seq) => Some(Response::Pong(seq)),
+        }
+    }
+}
+
+
+/// Heartbeat's options.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct HeartbeatOptions {
+    /// Interval between pings.
+    pub interval: Duration,
+    /// Duration after which a ping is missed.
+    pub timeout: Duration,
+    /// Count of consecutive missed pings after which the peer is not alive.
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(5), timeout: Duration::from_secs(2), max_missed: 3 }
+    }
+}
+
+
+/// Liveness state.
+#[derive(Clone,Copy,Debug,Default)]
+struct State {
+    /// Smoothed round-trip time.
+    latency: Option<Duration>,
+    /// Last round-trip time.
+    last_rtt: Option<Duration>,
+    /// Time of the last received pong.
+    last_seen: Option<Instant>,
+    /// Count of consecutive missed pings.
+    missed: u32,
+    /// Heartbeat has stopped: the stream is closed.
+    closed: bool,
+}
+
+
+/// Liveness of a peer, updated by its heartbeat.
+#[derive(Clone,Debug)]
+pub struct Liveness {
+    state: Arc<Mutex<State>>,
+    max_missed: u32,
+}
+
+impl Liveness {
+    fn new(max_missed: u32) -> Self {
+        Self { state: Arc::new(Mutex::new(State::default())), max_missed }
+    }
+
+    /// Return true while the peer answers pings.
+    pub fn is_alive(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.closed && state.missed < self.max_missed
+    }
+
+    /// Smoothed round-trip time, if any pong has been received.
+    pub fn latency(&self) -> Option<Duration> {
+        self.state.lock().unwrap().latency
+    }
+
+    /// Round-trip time of the last answered ping.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().last_rtt
+    }
+
+    /// Time elapsed since the last pong.
+    pub fn last_seen(&self) -> Option<Duration> {
+        self.state.lock().unwrap().last_seen.map(|at| at.elapsed())
+    }
+
+    /// Record a pong received after `rtt`.
+    fn pong(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        // same smoothing as TCP's srtt (RFC 6298)
+        state.latency = Some(match state.latency {
+            Some(latency) => latency.mul_f64(0.875) + rtt.mul_f64(0.125),
+            None => rtt,
+        });
+        state.last_rtt = Some(rtt);
+        state.last_seen = Some(Instant::now());
+        state.missed = 0;
+    }
+
+    fn missed(&self) {
+        self.state.lock().unwrap().missed += 1;
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+    }
+}
+
+
+/// Send pings over a stream to a `Ping` service, updating liveness.
+pub struct Heartbeat<T> {
+    transport: T,
+    options: HeartbeatOptions,
+    liveness: Liveness,
+}
+
+impl<T> Heartbeat<T>
+    where T: Stream<Item=Response>+Sink<Request>+Unpin
+{
+    pub fn new(transport: T, options: HeartbeatOptions) -> Self {
+        Self { transport, options, liveness: Liveness::new(options.max_missed) }
+    }
+
+    /// Liveness updated by the heartbeat.
+    pub fn liveness(&self) -> &Liveness {
+        &self.liveness
+    }
+
+    /// Ping peer every interval until the stream is closed. This requires
+    /// a tokio runtime.
+    pub async fn run(mut self) {
+        let mut seq = 0u64;
+        loop {
+            seq += 1;
+            let start = Instant::now();
+            if self.transport.send(Request::Ping(seq)).await.is_err() {
+                break;
+            }
+            match self.pong(seq).await {
+                Ok(true) => self.liveness.pong(start.elapsed()),
+                Ok(false) => break,
+                Err(_) => self.liveness.missed(),
+            }
+            tokio::time::sleep(self.options.interval.saturating_sub(start.elapsed())).await;
+        }
+        self.liveness.close();
+    }
+
+    /// Wait for the pong of `seq`, skipping late pongs of missed pings.
+    /// Return false when the stream is closed.
+    async fn pong(&mut self, seq: u64) -> Result<bool, tokio::time::error::Elapsed> {
+        let transport = &mut self.transport;
+        tokio::time::timeout(self.options.timeout, async move {
+            while let Some(Response::Pong(pong)) = transport.next().await {
+                if pong == seq {
+                    return true;
+                }
+            }
+            false
+        }).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{join, select, Either};
+
+    use super::*;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    #[test]
+    fn test_heartbeat() {
+        let (server_transport, client_transport) = MPSCTransport::<Response, Request>::bi(8);
+        let options = HeartbeatOptions { interval: Duration::from_millis(10),
+                                         timeout: Duration::from_millis(20), max_missed: 2 };
+        let heartbeat = Heartbeat::new(client_transport, options);
+        let liveness = heartbeat.liveness().clone();
+        assert!(liveness.is_alive() && liveness.latency().is_none());
+
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let server_fut = async move {
+            let (mut sender, mut receiver) = server_transport.split();
+            let mut ping = Ping;
+            select(ping.serve(Transport::new(&mut sender, &mut receiver)), stopped).await;
+            // peer stops answering without closing the stream
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        let client_fut = {
+            let liveness = liveness.clone();
+            async move {
+                match select(heartbeat.run().boxed(), async move {
+                    tokio::time::sleep(Duration::from_millis(35)).await;
+                    assert!(liveness.is_alive());
+                    assert!(liveness.latency().is_some() && liveness.last_seen().is_some());
+                    stop.send(()).unwrap();
+                    tokio::time::sleep(Duration::from_millis(60)).await;
+                }.boxed()).await {
+                    Either::Left(_) => panic!("heartbeat stopped while stream is open"),
+                    Either::Right(_) => (),
+                }
+            }
+        };
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap()
+            .block_on(join(client_fut, server_fut));
+        assert!(!liveness.is_alive());
+    }
+}
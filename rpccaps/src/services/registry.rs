@@ -0,0 +1,213 @@
+//! Discovery of the services hosted by a server.
+//!
+//! The registry service returns the registered dispatch ids, along with
+//! their services' metadata, methods and schema. It is not registered by
+//! default: listing services tells peers what to probe, thus the registry
+//! id should be pinned to trusted identities:
+//!
+//! ```ignore
+//! server.dispatch.pin(REGISTRY_ID, [admin]);
+//! server.add_registry(REGISTRY_ID, HandlerOptions::default())?;
+//!
+//! let transport = client.open(REGISTRY_ID).await?;
+//! let services = registry::Client::<_, u64>::new(transport).services().await?;
+//! ```
+//!
+//! Only services registered by builders are listed, not raw handlers.
+use std::marker::PhantomData;
+use std::sync::Weak;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use serde::{Serialize,Deserialize};
+
+use crate::rpc::call::{with_timeout, CallError, ClientOptions};
+use crate::rpc::dispatch::{Dispatch, ServiceInfo};
+use crate::rpc::protocol::{Call, Frame};
+use crate::rpc::schema::Schema;
+use crate::rpc::service::{features, Service};
+
+
+/// Registered service.
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct ServiceEntry<Id> {
+    /// Dispatch id of the service.
+    pub id: Id,
+    /// Service's metadata.
+    pub metas: Vec<(String, String)>,
+    /// Service's methods and the index of the action they require.
+    pub methods: Vec<(String, usize)>,
+    /// Service's schema, if generated by `#[service]`.
+    pub schema: Option<Schema>,
+}
+
+impl<Id> ServiceEntry<Id> {
+    /// Optional features advertised by the service.
+    pub fn features(&self) -> Vec<&str> {
+        features::of(&self.metas)
+    }
+
+    /// Return true if service advertises provided feature.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features().contains(&feature)
+    }
+}
+
+impl<Id> From<(Id, ServiceInfo)> for ServiceEntry<Id> {
+    fn from((id, info): (Id, ServiceInfo)) -> Self {
+        Self { id,
+               metas: info.metas.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+               methods: info.methods.iter().map(|(name, action)| (name.to_string(), *action)).collect(),
+               schema: info.schema }
+    }
+}
+
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+pub enum Request {
+    /// List registered services.
+    Services,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub enum Response<Id> {
+    Services(Vec<ServiceEntry<Id>>),
+}
+
+
+/// Service listing the services registered on a dispatcher.
+pub struct Registry<Id: Ord, D> {
+    /// Weak reference, since the registry is registered to the dispatcher.
+    dispatch: Weak<Dispatch<Id, D>>,
+}
+
+impl<Id: Ord, D> Registry<Id, D> {
+    pub fn new(dispatch: Weak<Dispatch<Id, D>>) -> Self {
+        Self { dispatch }
+    }
+}
+
+impl<Id: Ord, D> Clone for Registry<Id, D> {
+    fn clone(&self) -> Self {
+        Self { dispatch: self.dispatch.clone() }
+    }
+}
+
+#[async_trait]
+impl<Id, D> Service for Registry<Id, D>
+    where Id: 'static+Ord+Clone+Send+Sync+Unpin,
+          D: 'static+Send+Sync
+{
+    type Request = Request;
+    type Response = Response<Id>;
+
+    fn is_alive(&self) -> bool {
+        self.dispatch.strong_count() > 0
+    }
+
+    fn metas() -> &'static [(&'static str, &'static str)] {
+        &[("name", "registry")]
+    }
+
+    fn request_frame(_request: &Self::Request) -> Option<Frame> {
+        Some(Frame::Request(Call::UNARY))
+    }
+
+    fn response_frame(_response: &Self::Response) -> Option<Frame> {
+        Some(Frame::Response)
+    }
+
+    async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
+        let dispatch = self.dispatch.upgrade()?;
+        match request {
+            Request::Services => Some(Response::Services(
+                dispatch.services().into_iter().map(ServiceEntry::from).collect())),
+        }
+    }
+}
+
+
+/// Client of the registry service.
+pub struct Client<T, Id> {
+    transport: T,
+    options: ClientOptions,
+    phantom: PhantomData<fn() -> Id>,
+}
+
+impl<T, Id> Client<T, Id>
+    where T: Stream<Item=Response<Id>>+Sink<Request>+Unpin
+{
+    pub fn new(transport: T) -> Self {
+        Self::with_options(transport, ClientOptions::default())
+    }
+
+    pub fn with_options(transport: T, options: ClientOptions) -> Self {
+        Self { transport, options, phantom: PhantomData }
+    }
+
+    /// Return services registered on the server.
+    pub async fn services(&mut self) -> Result<Vec<ServiceEntry<Id>>, CallError> {
+        self.transport.send(Request::Services).await.or(Err(CallError::Failed))?;
+        match with_timeout(self.options.request_timeout, self.transport.next()).await? {
+            Some(Response::Services(services)) => Ok(services),
+            None => Err(CallError::Failed),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use futures::executor::LocalPool;
+    use futures::future::join;
+
+    use super::*;
+    use crate::rpc::dispatch::HandlerOptions;
+    use crate::rpc::dispatch::tests::SharedWriter;
+    use crate::rpc::service::tests::simple_service;
+    use crate::rpc::transport::{MPSCTransport, Transport};
+
+    type TestDispatch = Dispatch<u32, (SharedWriter, futures::io::Cursor<Vec<u8>>, ())>;
+
+    #[test]
+    fn test_registry() {
+        let dispatch = Arc::new(TestDispatch::new(None));
+        let registry = Registry::new(Arc::downgrade(&dispatch));
+        dispatch.add_builder(u32::MAX, Box::new({
+            let registry = registry.clone();
+            move |_| registry.clone()
+        }), HandlerOptions::default()).unwrap();
+        dispatch.add_builder(1, Box::new(|_| simple_service::Service::new()),
+                             HandlerOptions::default()).unwrap();
+        // raw handlers are not listed
+        dispatch.add(2, Box::new(|_| Box::pin(future::ready(()))), false).unwrap();
+
+        let (server_transport, client_transport) =
+            MPSCTransport::<Response<u32>, Request>::bi(8);
+        let client_fut = async move {
+            Client::new(client_transport).services().await.unwrap()
+        };
+        let server_fut = {
+            let mut registry = registry.clone();
+            async move {
+                let (s,r) = server_transport.split();
+                registry.serve(Transport::new(s, r)).await;
+            }
+        };
+        let (services, _) = LocalPool::new().run_until(join(client_fut, server_fut));
+
+        assert_eq!(services.iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![1, u32::MAX]);
+        let simple = &services[0];
+        assert_eq!(simple.methods.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(),
+                   simple_service::Service::methods().iter().map(|(name, _)| *name).collect::<Vec<_>>());
+        assert_eq!(simple.schema, simple_service::Service::schema());
+        assert!(services[1].schema.is_none());
+        assert!(!simple.supports(features::STREAMING));
+        assert_eq!(services[1].metas, vec![("name".to_string(), "registry".to_string())]);
+
+        // registry does not keep the dispatcher alive
+        drop(dispatch);
+        assert!(!registry.is_alive());
+    }
+}
@@ -1,5 +1,4 @@
 use proc_macro::TokenStream;
-use syn;
 
 
 // mod client;
@@ -10,20 +9,106 @@ mod utils;
 
 /// Generates RPC service and related classes around a server-side `impl` block of RPC methods.
 ///
-/// The code is generated inside the `service` module:
+/// The code is generated inside the `service` module. It only refers to the `rpccaps` crate,
+/// which must be in scope under this name: services' crates do not need to depend on the
+/// crates it uses (e.g. `serde` or `futures`).
 /// - `Client` trait: client implementation to call RPC, mapping service's RPC methods. Only
-///     `send_request(&mut self, request: Request)` must be implemented by user.
+///   `send_request(&mut self, request: Request)` must be implemented by user.
+///   Calls fail with an `rpc::call::CallError`, `Timeout` when no response is received before
+///   the request timeout of `rpc::call::ClientOptions` given to `Client::with_options`.
+///   Clients are also built from their transport with `From` (e.g. by `Service::serve_local()`).
 /// - `Request`, `Response` enums: a variant for each RPC method. They have same generics as
-/// Service.
+///   Service, and are `Clone`, which methods' arguments and outputs must then be.
+/// - Implementation of `rpc::codec::EncodedSize` for `Request` and `Response`: the encoded
+///   size of variants whose fields have a fixed size is known without serializing them;
 /// - Implementaton of `Service` trait for the struct implementing RPC methods;
+///   requests and responses are classified into `rpc::protocol::Frame`s, so that streams
+///   are checked against the protocol;
+/// - An implicit `__capabilities()` RPC method returning methods' actions and caller's
+///   effective capability;
+/// - An implicit `__schema()` RPC method returning methods' signatures, by index; clients
+///   compare it to theirs when opening the stream, or with `validate_schema()` (see
+///   `rpc::schema`);
+/// - A `Response::__Denied(call_id, reason)` variant, sent instead of the response of a request
+///   denied by `rpc::enforce::Enforced`, with the call id of unordered requests;
+/// - A `Response::__SlowDown(slow_down)` variant, sent instead of the response of a request
+///   exceeding service's rate (see `rpc::throttle`). Client returns it as a
+///   `CallError::SlowDown`, and waits for its `retry_after` before calling the method again;
+///   Peers of protocol versions prior to it get a `__Denied` response instead (see
+///   `rpc::version`);
+/// - A `Response::__Error(call_id, error)` variant, sent when the server fails to handle a
+///   request (`rpc::message::Error`). Client returns it as a `CallError::Server`. Methods
+///   returning a `Result` only send their Ok value in their response, while their errors
+///   are sent as an `Error::Method`, that the client returns as the `Err` of the call;
+/// - A `Request::__Trace(context)` variant, sent by clients whose options enable `trace`
+///   ahead of each call with its `rpc::trace::TraceContext`. Bodies of unary methods run
+///   in an `rpc::trace::method` span recording it;
+///
+/// Arguments of the attribute:
+/// - `#[service(wire_tests)]`: generate a test checking the encoding of a sample of each
+///   `Request` and `Response` variant against the snapshot saved in the crate's `wire/`
+///   directory (see `rpc::wire`). Methods' arguments and outputs must implement
+///   `rpc::wire::Sample`, and the service can't be generic. A missing snapshot fails the
+///   test: run it once with `RPCCAPS_UPDATE_WIRE` set to create it.
+/// - `#[service(cancellation_safe)]`: the service's calls can be aborted, its methods being
+///   left in a consistent state at each of their `.await` (see `rpc::cancel`).
+/// - `#[service(stable_ids)]`: requests and responses are encoded with methods' ids, set
+///   by `#[rpc(id=N)]`, instead of their position; messages of unknown ids are decoded as
+///   `__Unknown(id)` variants, to which the service responds with an
+///   `message::Error::ActionNotFound` (see `rpc::stable`). The service can't be generic.
+///
+/// Attributes on the `impl` block:
+/// - `#[rpc(capability="expr")]`: expression returning caller's `Capability`
+///   (e.g. `self.capability`);
+/// - `#[rpc(capability_type="Type")]`: type of callers' capability (`Service::Capability`),
+///   `Capability` by default. Services of more than 64 actions use a `WideCapability<N>`,
+///   whose actions' count is checked against their capability bits at compile time;
+/// - `#[rpc(error="MyError")]`: methods returning `Result<T, MyError>` send errors on the
+///   wire as `rpc::message::RemoteError`. It requires `From<MyError> for RemoteError`,
+///   and `From<RemoteError> for MyError` for the client to convert them back.
+///
+/// Attributes on methods:
+/// - `#[rpc(unordered)]`: when served concurrently, response is sent as soon as it is
+///   ready instead of in requests' order. Request and response are tagged with a call id.
+///   Client's `start_<method>()` returns the pending call (`rpc::call::Pending`), which
+///   can be cancelled with a `Request::__Cancel(call_id)`: server aborts its dispatch
+///   and answers with a `message::Error::Cancelled` error;
+/// - `#[rpc(idempotent)]`: calling the method again has no further effect. Failed calls
+///   are only re-issued by `rpc::client::Pool::idempotent()` when all of their requests
+///   are idempotent;
+/// - `#[rpc(id=N)]`: stable id of the method, required by `#[service(stable_ids)]`;
+/// - `#[rpc(cap="name")]`: methods of the same capability name share a capability bit, the
+///   one of the first of them, named after it by `Service::action_names()`;
+/// - `#[rpc(cap_bit=N)]`: capability bit of the method, i.e. the index of its action (lower
+///   than 64 for a `Capability`). Other methods get the bit of their index: a bit used by
+///   distinct methods or names fails the compilation. `Request::required_capability()`
+///   returns the capability required to call a method, whose action is listed by
+///   `Service::methods()`.
+///
+/// Attributes on methods' arguments:
+/// - `#[rpc(max_len=N)]`: collection (`Vec`, `HashMap`, `BTreeMap`, `String`, or an `Option`
+///   of them) longer than `N` items is rejected at deserialization, before its allocation
+///   (see `rpc::bounded`).
+///
+/// Methods returning `rpc::stream::Streaming<T>` are server-streaming: each item is sent
+/// as a `Response::{Method}Chunk(T)`, followed by a `Response::{Method}End`. Their client
+/// method returns a stream of the items.
+///
+/// Methods taking an `rpc::stream::Incoming<'_, T>` argument are client-streaming: items
+/// are sent as `Request::{Method}Chunk(T)` followed by `Request::{Method}End`. Their client
+/// method returns an `rpc::stream::ClientSink` whose `finish()` returns method's output.
+/// They can also return `Streaming<T>`.
 ///
 ///
 /// # Example
 ///
 #[proc_macro_attribute]
-pub fn service(_attrs: TokenStream, input: TokenStream) -> TokenStream {
+pub fn service(attrs: TokenStream, input: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(attrs as syn::AttributeArgs);
     let mut ast = syn::parse::<syn::ItemImpl>(input).unwrap();
-    let service = crate::service::Service::new(&mut ast);
-    service.generate()
+    match crate::service::Service::new(&mut ast, utils::Attributes::from_args(&args)) {
+        Ok(service) => service.generate(),
+        Err(err) => err.to_compile_error().into(),
+    }
 }
 
@@ -1,58 +1,254 @@
 extern crate proc_macro;
 
-use syn;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote,ToTokens};
 
 use super::utils::*;
 
 
+/// Encoded size of enums' variant index.
+pub const VARIANT_SIZE: usize = 4;
+
+
 pub struct Method {
     pub index: u32,
-    pub method: syn::ImplItemMethod,
 
     pub ident: syn::Ident,
     pub ident_cap: syn::Ident,
     pub args: Vec<syn::Pat>,
     pub args_ty: Vec<syn::Type>,
+    /// Maximum length of arguments' collections (`#[rpc(max_len=N)]`).
+    pub args_max_len: Vec<Option<usize>>,
     pub output: Option<syn::Type>,
     pub is_async: bool,
+    pub attrs: Attributes,
+    /// Ok and error types of fallible methods (returning `Result<T, E>`),
+    /// whose errors are sent through the `__Error` response.
+    pub result: Option<(syn::Type, syn::Type)>,
+    /// True when the error type is the service's one, sent on the wire as a
+    /// `RemoteError`.
+    pub remote_error: bool,
+    /// Item type of server-streaming methods (returning `Streaming<T>`).
+    pub stream_item: Option<syn::Type>,
+    /// Position, pattern and item type of client-streaming methods' argument
+    /// (of type `Incoming<'_, T>`). It is not part of `args`.
+    pub incoming: Option<(usize, syn::Pat, syn::Type)>,
+    /// Stable id of the method on the wire (`#[rpc(id=N)]`), used by
+    /// services declared with `#[service(stable_ids)]`.
+    pub id: Option<u32>,
+    /// Capability bit of the method (`#[rpc(cap_bit=N)]`).
+    pub cap_bit: Option<u32>,
+    /// Index of the action required to call the method, assigned by the
+    /// service.
+    pub action: u32,
 }
 
 impl Method {
-    pub fn new(index: u32, method: &mut syn::ImplItemMethod) -> Option<Self> {
-        let sig = &method.sig;
+    pub fn new(index: u32, method: &mut syn::ImplItemMethod) -> syn::Result<Option<Self>> {
+        let attrs = Attributes::from_attrs("rpc", &mut method.attrs);
         // arguments
-        let mut iter = sig.inputs.iter();
+        let mut iter = method.sig.inputs.iter_mut();
         match iter.next() {
             Some(syn::FnArg::Receiver(_)) => (),
-            _ => return None,
+            _ => return Ok(None),
         }
 
-        let (mut args, mut args_ty) = (Vec::new(), Vec::new());
+        let (mut args, mut args_ty, mut incoming) = (Vec::new(), Vec::new(), None);
+        let mut args_max_len = Vec::new();
         for arg in iter {
-            match arg {
-                syn::FnArg::Typed(arg) => {
-                    args.push((*arg.pat).clone());
-                    args_ty.push((*arg.ty).clone());
+            if let syn::FnArg::Typed(arg) = arg {
+                let arg_attrs = Attributes::from_attrs("rpc", &mut arg.attrs);
+                match Self::generic_item(&arg.ty, "Incoming") {
+                    Some(item) if incoming.is_none() =>
+                        incoming = Some((args.len(), (*arg.pat).clone(), item)),
+                    _ => {
+                        args.push((*arg.pat).clone());
+                        args_ty.push((*arg.ty).clone());
+                        args_max_len.push(arg_attrs.get_int("max_len", &arg.pat)?);
+                    }
                 }
-                _ => (),
             }
         }
-
-        // metadata
-        // let attrs = Attributes::from_attrs("rpc", &mut method.attrs).to_map();
+        let sig = &method.sig;
 
         let ident = sig.ident.clone();
-        Some(Self {
-            index, args, args_ty, ident,
-            method: method.clone(),
+        let output = match sig.output.clone() {
+            syn::ReturnType::Default => None,
+            syn::ReturnType::Type(_, ty) => Some(*ty)
+        };
+        Ok(Some(Self {
+            index, args, args_ty, args_max_len, ident,
             ident_cap: to_camel_ident(&sig.ident),
-            output: match sig.output.clone() {
-                syn::ReturnType::Default => None,
-                syn::ReturnType::Type(_, ty) => Some(*ty)
-            },
+            stream_item: output.as_ref().and_then(|ty| Self::generic_item(ty, "Streaming")),
+            result: output.as_ref().and_then(Self::result_types),
+            output, incoming,
+            id: attrs.get_int("id", &sig.ident)?,
+            cap_bit: attrs.get_int("cap_bit", &sig.ident)?,
+            action: 0,
 
             is_async: sig.asyncness.is_some(),
-        })
+            attrs,
+            remote_error: false,
+        }))
+    }
+
+    /// Return item type if `ty` is `name<T>` (lifetimes are skipped).
+    fn generic_item(ty: &syn::Type, name: &str) -> Option<syn::Type> {
+        let segment = match ty {
+            syn::Type::Path(path) => path.path.segments.last()?,
+            _ => return None,
+        };
+        match (&segment.arguments, segment.ident == name) {
+            (syn::PathArguments::AngleBracketed(args), true) =>
+                args.args.iter().find_map(|arg| match arg {
+                    syn::GenericArgument::Type(item) => Some(item.clone()),
+                    _ => None,
+                }),
+            _ => None,
+        }
+    }
+
+    /// Return method's signature, as listed in the service's schema.
+    pub fn signature(&self) -> String {
+        let mut args = self.args_ty.iter().map(|ty| ty.to_token_stream().to_string()).collect::<Vec<_>>();
+        if let Some((index, _, item)) = &self.incoming {
+            args.insert(*index, format!("Incoming < {} >", item.to_token_stream()));
+        }
+        let unordered = match self.is_unordered() {
+            true => "unordered ",
+            false => "",
+        };
+        match &self.output {
+            Some(output) => format!("{}fn {}({}) -> {}", unordered, self.ident, args.join(", "),
+                                    output.to_token_stream()),
+            None => format!("fn {}({})", self.ident, args.join(", ")),
+        }
+    }
+
+    /// Name of the capability grouping the method with others (`#[rpc(cap="name")]`).
+    pub fn cap_name(&self) -> Option<&str> {
+        match self.attrs.get("cap") {
+            Some(Some(name)) => Some(name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Return true if method is a server-streaming one.
+    pub fn is_streaming(&self) -> bool {
+        self.stream_item.is_some()
+    }
+
+    /// Return true if method is a client-streaming one.
+    pub fn is_incoming(&self) -> bool {
+        self.incoming.is_some()
+    }
+
+    /// Return arguments' types of request variant, with their serde
+    /// attributes.
+    pub fn variant_args(&self) -> Vec<TokenStream2> {
+        self.args_ty.iter().zip(self.args_max_len.iter()).map(|(ty, max_len)| match max_len {
+            Some(max_len) => {
+                let path = format!("rpccaps::rpc::bounded::max_len::<_, _, {}>", max_len);
+                quote! { #[serde(deserialize_with=#path)] #ty }
+            },
+            None => quote! { #ty },
+        }).collect()
+    }
+
+    /// Return arguments to call method with, `incoming` being inserted for
+    /// client-streaming methods.
+    pub fn call_args(&self, incoming: &TokenStream2) -> Vec<TokenStream2> {
+        let mut args = self.args.iter().map(|arg| arg.to_token_stream()).collect::<Vec<_>>();
+        if let Some((index, _, _)) = self.incoming {
+            args.insert(index, incoming.clone());
+        }
+        args
+    }
+
+    /// Return identifiers of streaming method's chunk and end variants, for
+    /// both requests and responses.
+    pub fn stream_idents(&self) -> (syn::Ident, syn::Ident) {
+        (quote::format_ident!("{}Chunk", self.ident_cap),
+         quote::format_ident!("{}End", self.ident_cap))
+    }
+
+    /// Return Ok and error types if `ty` is `Result<T, E>`.
+    fn result_types(ty: &syn::Type) -> Option<(syn::Type, syn::Type)> {
+        let segment = match ty {
+            syn::Type::Path(path) => path.path.segments.last()?,
+            _ => return None,
+        };
+        let args = match (&segment.arguments, segment.ident == "Result") {
+            (syn::PathArguments::AngleBracketed(args), true) => &args.args,
+            _ => return None,
+        };
+        match (args.len(), args.first(), args.last()) {
+            (2, Some(syn::GenericArgument::Type(ok)), Some(syn::GenericArgument::Type(err))) =>
+                Some((ok.clone(), err.clone())),
+            _ => None,
+        }
+    }
+
+    /// When output is `Result<T, error>`, mark method such as error is converted
+    /// into a `RemoteError` on the wire.
+    pub fn set_error(&mut self, error: &syn::Type) {
+        let error = error.to_token_stream().to_string();
+        self.remote_error = matches!(self.result, Some((_, ref err)) if err.to_token_stream().to_string() == error);
+    }
+
+    /// Output type as sent on the wire: fallible methods only send their Ok
+    /// value in their response variant.
+    pub fn wire_output(&self) -> Option<TokenStream2> {
+        match (&self.output, &self.result) {
+            (_, Some((ok, _))) => Some(ok.to_token_stream()),
+            (Some(output), None) => Some(output.to_token_stream()),
+            (None, _) => None,
+        }
+    }
+
+    /// Error type of fallible methods as sent on the wire.
+    pub fn wire_error(&self) -> Option<TokenStream2> {
+        match (&self.result, self.remote_error) {
+            (Some(_), true) => Some(quote! { rpccaps::rpc::message::RemoteError }),
+            (Some((_, err)), false) => Some(err.to_token_stream()),
+            (None, _) => None,
+        }
+    }
+
+    /// Encoded size of method's request variant, when it is fixed.
+    pub fn request_size(&self) -> Option<usize> {
+        let args = self.args_ty.iter().map(fixed_size).sum::<Option<usize>>()?;
+        Some(VARIANT_SIZE + args + self.call_id_size())
+    }
+
+    /// Encoded size of method's response variant, when it is fixed.
+    pub fn response_size(&self) -> Option<usize> {
+        match (&self.output, &self.result, &self.stream_item) {
+            (_, _, Some(_)) => None,
+            (_, Some((ok, _)), None) => Some(VARIANT_SIZE + fixed_size(ok)? + self.call_id_size()),
+            (Some(output), None, None) => Some(VARIANT_SIZE + fixed_size(output)? + self.call_id_size()),
+            (None, None, None) => Some(VARIANT_SIZE),
+        }
+    }
+
+    fn call_id_size(&self) -> usize {
+        match self.is_unordered() {
+            true => 8,
+            false => 0,
+        }
+    }
+
+    /// Return true if response can be sent before previous requests'
+    /// ones. Such requests and responses are tagged with a call id.
+    pub fn is_unordered(&self) -> bool {
+        self.output.is_some() && !self.is_streaming() && !self.is_incoming()
+            && self.attrs.contains_key("unordered")
+    }
+
+    /// Return true if calling the method again has no further effect, so
+    /// that a failed call can be re-issued (see `rpc::client::Pool`).
+    pub fn is_idempotent(&self) -> bool {
+        self.attrs.contains_key("idempotent")
     }
 }
 
@@ -1,42 +1,125 @@
 extern crate proc_macro;
 
-use syn;
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
-use quote::quote;
+use quote::{quote, ToTokens};
 
 
-use super::method::Method;
+use std::collections::BTreeMap;
+
+use super::method::{Method, VARIANT_SIZE};
 use super::utils::*;
 
 
+/// Maximum stable id of methods (see `rpc::stable::MAX_ID`).
+const STABLE_MAX_ID: u32 = 1 << 24;
+
+
 
 
 pub struct Service<'a> {
     pub ast: &'a mut syn::ItemImpl,
     pub methods: Vec<Method>,
     pub meta: Attributes,
+    pub attrs: Attributes,
+    /// Arguments of the `service` attribute.
+    pub args: Attributes,
 }
 
 impl<'a> Service<'a> {
-    pub fn new(ast: &'a mut syn::ItemImpl) -> Self {
-        let methods = ast.items.iter_mut().enumerate()
-            .filter_map(|(index, mut item)| match &mut item {
-                syn::ImplItem::Method(ref mut method) => Method::new(index as u32, method),
-                _ => None
-            }).collect::<Vec<_>>();
+    pub fn new(ast: &'a mut syn::ItemImpl, args: Attributes) -> syn::Result<Self> {
+        let attrs = Attributes::from_attrs("rpc", &mut ast.attrs);
+        let error = attrs.get_as::<_,syn::Type>("error");
+
+        let mut methods = Vec::new();
+        for item in ast.items.iter_mut() {
+            if let syn::ImplItem::Method(ref mut method) = item {
+                if let Some(mut method) = Method::new(methods.len() as u32, method)? {
+                    if let Some(ref error) = error {
+                        method.set_error(error);
+                    }
+                    methods.push(method);
+                }
+            }
+        }
 
-        assert!(methods.len() <= 64, "a maximum 64 rpc methods is allowed");
+        // actions of custom capability types are checked by the generated
+        // code, their count being unknown here
+        let max_actions = match attrs.contains_key("capability_type") {
+            true => None,
+            false => Some(64),
+        };
+        Self::assign_capabilities(&mut methods, max_actions)?;
 
         let mut meta = Attributes::from_attrs("service", &mut ast.attrs);
         meta.read_attrs("meta", &mut ast.attrs);
 
-        Self { ast, methods, meta }
+        for arg in ["wire_tests", "stable_ids"].iter() {
+            if args.contains_key(*arg) && !ast.generics.params.is_empty() {
+                return Err(syn::Error::new_spanned(&ast.generics,
+                                                   format!("{} require a service without generics", arg)));
+            }
+        }
+        if args.contains_key("stable_ids") {
+            let mut ids = std::collections::BTreeSet::new();
+            for method in methods.iter() {
+                let error = |message: String| Err(syn::Error::new_spanned(&method.ident, message));
+                match method.id {
+                    None => return error(format!("method `{}` has no `#[rpc(id=N)]`", method.ident)),
+                    Some(id) if id >= STABLE_MAX_ID =>
+                        return error(format!("method `{}` id must be lower than {}", method.ident, STABLE_MAX_ID)),
+                    Some(id) if !ids.insert(id) =>
+                        return error(format!("method `{}` id {} is already used", method.ident, id)),
+                    Some(_) => (),
+                }
+            }
+        }
+        Ok(Self { ast, methods, meta, attrs, args })
+    }
+
+    /// Assign capability bits (actions' index) to methods: the one set by
+    /// `cap_bit`, the one of the first method of the same `cap` name,
+    /// otherwise the one of their index. A bit assigned to distinct methods
+    /// or names, or not lower than `max_actions`, fails the compilation.
+    fn assign_capabilities(methods: &mut [Method], max_actions: Option<u32>) -> syn::Result<()> {
+        let (mut owners, mut names) = (BTreeMap::new(), BTreeMap::new());
+        for method in methods.iter_mut() {
+            let error = |message: String| Err(syn::Error::new_spanned(&method.ident, message));
+            let (bit, owner) = match (method.cap_bit, method.cap_name()) {
+                (Some(_), Some(_)) => return error(format!("method `{}` can't have both cap and cap_bit", method.ident)),
+                (Some(bit), None) => (bit, format!("method `{}`", method.ident)),
+                (None, Some(name)) => match names.get(name) {
+                    Some(&bit) => {
+                        method.action = bit;
+                        continue
+                    },
+                    None => (method.index, format!("capability `{}`", name)),
+                },
+                (None, None) => (method.index, format!("method `{}`", method.ident)),
+            };
+            match max_actions {
+                Some(max) if bit >= max =>
+                    return error(format!("capability bit {} of method `{}` must be lower than {}", bit, method.ident, max)),
+                _ => (),
+            }
+            if let Some(other) = owners.insert(bit, owner.clone()) {
+                return error(format!("capability bit {} of {} is already used by {}", bit, owner, other))
+            }
+            if let Some(name) = method.cap_name() {
+                names.insert(name.to_string(), bit);
+            }
+            method.action = bit;
+        }
+        Ok(())
     }
 
     pub fn generate(&self) -> TokenStream {
         let ast = &self.ast;
         let (types, service, client) = (self.types(), self.service(), self.client());
+        let wire_tests = match self.args.contains_key("wire_tests") {
+            true => Some(self.wire_tests()),
+            false => None,
+        };
 
         (quote!{
             #ast
@@ -44,11 +127,11 @@ impl<'a> Service<'a> {
             use super::*;
             use std::collections::BTreeMap;
             use std::marker::PhantomData;
-            use futures::prelude::*;
-            use futures::future::{Future,FutureExt,ok,err};
+            use rpccaps::__private::futures::{self, prelude::*};
+            use rpccaps::__private::futures::future::{Future,FutureExt,ok,err};
 
-            use async_trait::async_trait;
-            use serde::{Deserialize,Serialize};
+            use rpccaps::__private::async_trait::async_trait;
+            use rpccaps::__private::serde::{self, Deserialize, Serialize};
 
             use rpccaps::data::Capability;
             use rpccaps::rpc::service::{Service as RPCService_};
@@ -57,80 +140,652 @@ impl<'a> Service<'a> {
             #types
             #service
             #client
+            #wire_tests
         }).into()
     }
 
+    /// Test snapshotting the encoding of requests and responses' variants.
+    fn wire_tests(&self) -> TokenStream2 {
+        let name = self.ast.self_ty.to_token_stream().to_string();
+        // one test per service, as several may be declared in a module
+        let test = name.chars().filter(|c| !c.is_whitespace())
+                       .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+                       .collect::<String>();
+        let test = quote::format_ident!("__wire_snapshot_{}", test);
+        let samples = |count: usize| (0..count).map(|_| quote! { sample() }).collect::<Vec<_>>();
+        let variant = |ty: &str, ident: &syn::Ident, fields: Option<usize>| {
+            let (label, ty) = (format!("{}::{}", ty, ident), quote::format_ident!("{}", ty));
+            match fields.map(samples) {
+                Some(fields) => quote! { snapshot.add(#label, &#ty::#ident(#(#fields),*)); },
+                None => quote! { snapshot.add(#label, &#ty::#ident); },
+            }
+        };
+
+        let call_id = |method: &Method| method.is_unordered() as usize;
+        let requests = self.methods.iter().map(|method| {
+            let request = variant("Request", &method.ident_cap, Some(call_id(method) + method.args_ty.len()));
+            match method.is_incoming() {
+                true => {
+                    let (chunk, end) = method.stream_idents();
+                    let (chunk, end) = (variant("Request", &chunk, Some(1)), variant("Request", &end, None));
+                    quote! { #request #chunk #end }
+                },
+                false => request,
+            }
+        });
+        let responses = self.methods.iter().map(|method| {
+            match (method.stream_item.is_some(), method.output.is_some()) {
+                (true, _) => {
+                    let (chunk, end) = method.stream_idents();
+                    let (chunk, end) = (variant("Response", &chunk, Some(1)), variant("Response", &end, None));
+                    quote! { #chunk #end }
+                },
+                (false, true) => variant("Response", &method.ident_cap, Some(call_id(method) + 1)),
+                (false, false) => variant("Response", &method.ident_cap, None),
+            }
+        });
+
+        quote! {
+            #[cfg(test)]
+            #[test]
+            fn #test() {
+                use rpccaps::rpc::wire::{sample, Snapshot};
+
+                let mut snapshot = Snapshot::new(env!("CARGO_MANIFEST_DIR"), module_path!(), #name);
+                #(#requests)*
+                snapshot.add("Request::__Capabilities", &Request::__Capabilities);
+                snapshot.add("Request::__Cancel", &Request::__Cancel(sample()));
+                snapshot.add("Request::__Schema", &Request::__Schema);
+                snapshot.add("Request::__Trace", &Request::__Trace(sample()));
+                #(#responses)*
+                snapshot.add("Response::__Capabilities", &Response::__Capabilities(sample()));
+                snapshot.add("Response::__Denied", &Response::__Denied(sample(), sample()));
+                snapshot.add("Response::__SlowDown", &Response::__SlowDown(sample()));
+                snapshot.add("Response::__Error", &Response::__Error(sample(), sample()));
+                snapshot.add("Response::__Schema", &Response::__Schema(sample()));
+                snapshot.check();
+            }
+        }
+    }
+
     fn types(&self) -> TokenStream2 {
-        // let ty = &*self.ast.self_ty;
-        let (_impl_generics, ty_generics, where_clause) = self.ast.generics.split_for_impl();
+        let self_ty = &*self.ast.self_ty;
+        let capability_type = self.capability_type();
+        let (impl_generics, ty_generics, where_clause) = self.ast.generics.split_for_impl();
 
-        let requests = self.methods.iter().map(|Method { ident_cap, args_ty, .. }| {
-            quote! { #ident_cap(#(#args_ty),*) }
+        let requests = self.methods.iter().map(|method| {
+            // fields' serde attributes are only used by derived deserialization
+            let args_ty = match self.is_stable() {
+                true => method.args_ty.iter().map(ToTokens::to_token_stream).collect(),
+                false => method.variant_args(),
+            };
+            let ident_cap = &method.ident_cap;
+            if let Some((_, _, ref item)) = method.incoming {
+                let (chunk, end) = method.stream_idents();
+                return quote! { #ident_cap(#(#args_ty),*), #chunk(#item), #end };
+            }
+            match method.is_unordered() {
+                true => quote! { #ident_cap(u64, #(#args_ty),*) },
+                false => quote! { #ident_cap(#(#args_ty),*) },
+            }
         });
-        let responses = self.methods.iter().map(|Method { ident_cap, output, .. }| {
-            match output {
-                Some(output) => quote! { #ident_cap(#output) },
-                None => quote! { #ident_cap },
+        let responses = self.methods.iter().map(|method| {
+            let (ident_cap, output) = (&method.ident_cap, method.wire_output());
+            if let Some(ref item) = method.stream_item {
+                let (chunk, end) = method.stream_idents();
+                return quote! { #chunk(#item), #end };
+            }
+            match (output, method.is_unordered()) {
+                (Some(output), true) => quote! { #ident_cap(u64, #output) },
+                (Some(output), false) => quote! { #ident_cap(#output) },
+                (None, _) => quote! { #ident_cap },
             }
         });
-        /*let cap_ops = self.methods.iter().map(|Method { ident_cap, index, args_ty, .. }| {
-            let args_ty = args_ty.iter().map(|_| quote!{ _ });
-            let ops = 1u64.rotate_left(*index);
-            quote!{ Request::#ident_cap(#(#args_ty),*) => Capability::new(#ops, 0u64) }
-        });*/
 
         // we need phantom variant for handling generics cases: R, R<A>, R<A,B>.
         let phantom = quote! { _Phantom(PhantomData<Request #ty_generics>) };
 
+        // variants of fixed size don't need to be serialized to get it
+        let request_sizes = self.methods.iter().filter_map(|method| {
+            let (ident_cap, size) = (&method.ident_cap, method.request_size()?);
+            Some(quote! { Request::#ident_cap(..) => Some(#size) })
+        });
+        let response_sizes = self.methods.iter().filter_map(|method| {
+            let (ident_cap, size) = (&method.ident_cap, method.response_size()?);
+            Some(match method.output {
+                Some(_) => quote! { Response::#ident_cap(..) => Some(#size) },
+                None => quote! { Response::#ident_cap => Some(#size) },
+            })
+        });
+        let variant_size = VARIANT_SIZE;
+        let mut size_generics = self.ast.generics.clone();
+        size_generics.make_where_clause().predicates.push(syn::parse_quote! { Self: Serialize });
+        let (size_impl_generics, _, size_where_clause) = size_generics.split_for_impl();
+
+        // messages of services with stable ids are (de)serialized by id
+        let (derive, unknown, stable_serde) = match self.is_stable() {
+            true => (None, Some(quote! { __Unknown(u32), }), Some(self.stable_serde())),
+            false => (Some(quote! {
+                #[derive(Serialize,Deserialize)]
+                #[serde(crate="rpccaps::__private::serde")]
+            }), None, None),
+        };
+
+        // implicit variants come first, so that their index does not depend
+        // on the service's methods: clients can fetch the schema of servers
+        // whose methods differ
         quote! {
-            #[derive(Serialize,Deserialize)]
+            #[derive(Clone)]
+            #derive
             pub enum Request #ty_generics #where_clause {
+                __Capabilities,
+                __Cancel(u64),
+                __Schema,
+                __Trace(rpccaps::rpc::trace::TraceContext),
                 #(#requests,)*
+                #unknown
                 #phantom
             }
 
-            #[derive(Clone,Serialize,Deserialize)]
+            #[derive(Clone)]
+            #derive
             pub enum Response #ty_generics #where_clause {
+                __Capabilities(rpccaps::rpc::service::Capabilities<#capability_type>),
+                __Denied(Option<u64>, String),
+                __SlowDown(rpccaps::rpc::message::SlowDown),
+                __Error(Option<u64>, rpccaps::rpc::message::Error),
+                __Schema(rpccaps::rpc::schema::Schema),
                 #(#responses,)*
+                #unknown
                 #phantom
             }
-        }
-            /*
-            impl #impl_generics Into<Capability> for &Request #ty_generics #where_clause {
-                /// Get the index of the Request method.
-                fn into(self) -> Capability {
+
+            #stable_serde
+
+            impl #size_impl_generics rpccaps::rpc::codec::EncodedSize for Request #ty_generics #size_where_clause {
+                fn encoded_size_hint(&self) -> Option<usize> {
                     match self {
-                        #(#cap_ops,)*
-                        _ => Capability::empty(),
+                        #(#request_sizes,)*
+                        Request::__Capabilities => Some(#variant_size),
+                        Request::__Cancel(_) => Some(#variant_size + 8),
+                        Request::__Schema => Some(#variant_size),
+                        Request::__Trace(_) => Some(#variant_size + 24),
+                        _ => rpccaps::rpc::codec::serialized_size(self),
                     }
                 }
             }
-        }*/
+
+            impl #size_impl_generics rpccaps::rpc::codec::EncodedSize for Response #ty_generics #size_where_clause {
+                fn encoded_size_hint(&self) -> Option<usize> {
+                    match self {
+                        #(#response_sizes,)*
+                        _ => rpccaps::rpc::codec::serialized_size(self),
+                    }
+                }
+            }
+
+            impl #impl_generics Request #ty_generics #where_clause {
+                /// Capability required to send the request. Implicit requests
+                /// require none.
+                pub fn required_capability(&self) -> #capability_type {
+                    use rpccaps::data::CapabilitySet;
+                    <#self_ty as RPCService_>::method_of(self)
+                        .and_then(|index| <#self_ty as RPCService_>::methods().get(index))
+                        .map_or_else(<#capability_type>::empty, |(_, action)| <#capability_type>::of_action(*action))
+                }
+            }
+        }
+    }
+
+    /// Return true if messages are encoded with methods' stable ids.
+    fn is_stable(&self) -> bool {
+        self.args.contains_key("stable_ids")
+    }
+
+    /// Variants of requests and responses with their stable id and fields'
+    /// types (none for unit variants).
+    fn stable_variants(&self) -> (Vec<StableVariant>, Vec<StableVariant>) {
+        let capability_type = self.capability_type();
+        let variant = |ident: syn::Ident, id: TokenStream2, fields: Option<Vec<(TokenStream2, bool)>>| {
+            StableVariant { ident, id, fields }
+        };
+        let ident = |name: &str| quote::format_ident!("{}", name);
+        let (mut requests, mut responses) = (Vec::new(), Vec::new());
+        for method in self.methods.iter() {
+            let id = method.id.unwrap();
+            let call_id = match method.is_unordered() {
+                true => Some((quote! { u64 }, false)),
+                false => None,
+            };
+            let (chunk, end) = method.stream_idents();
+            let (chunk_id, end_id) = (quote! { #id | rpccaps::rpc::stable::CHUNK },
+                                      quote! { #id | rpccaps::rpc::stable::END });
+
+            let args = method.args_ty.iter().zip(method.args_max_len.iter()).map(|(ty, max_len)| match max_len {
+                Some(max_len) => (quote! { rpccaps::rpc::bounded::Limited<#ty, #max_len> }, true),
+                None => (quote! { #ty }, false),
+            });
+            requests.push(variant(method.ident_cap.clone(), quote! { #id },
+                                  Some(call_id.clone().into_iter().chain(args).collect())));
+            if let Some((_, _, item)) = &method.incoming {
+                requests.push(variant(chunk.clone(), chunk_id.clone(), Some(vec![(quote! { #item }, false)])));
+                requests.push(variant(end.clone(), end_id.clone(), None));
+            }
+
+            match (&method.stream_item, method.wire_output()) {
+                (Some(item), _) => {
+                    responses.push(variant(chunk, chunk_id, Some(vec![(quote! { #item }, false)])));
+                    responses.push(variant(end, end_id, None));
+                },
+                (None, Some(output)) => responses.push(variant(
+                    method.ident_cap.clone(), quote! { #id }, Some(call_id.into_iter().chain(Some((output, false))).collect()))),
+                (None, None) => responses.push(variant(method.ident_cap.clone(), quote! { #id }, None)),
+            }
+        }
+
+        let implicit = |name: &str| { let id = ident(name); quote! { rpccaps::rpc::stable::#id } };
+        requests.push(variant(ident("__Capabilities"), implicit("CAPABILITIES"), None));
+        requests.push(variant(ident("__Cancel"), implicit("CANCEL"), Some(vec![(quote! { u64 }, false)])));
+        requests.push(variant(ident("__Schema"), implicit("SCHEMA"), None));
+        requests.push(variant(ident("__Trace"), implicit("TRACE"),
+                              Some(vec![(quote! { rpccaps::rpc::trace::TraceContext }, false)])));
+        responses.push(variant(ident("__Capabilities"), implicit("CAPABILITIES"),
+                               Some(vec![(quote! { rpccaps::rpc::service::Capabilities<#capability_type> }, false)])));
+        responses.push(variant(ident("__Denied"), implicit("DENIED"),
+                               Some(vec![(quote! { Option<u64> }, false), (quote! { String }, false)])));
+        responses.push(variant(ident("__SlowDown"), implicit("SLOW_DOWN"),
+                               Some(vec![(quote! { rpccaps::rpc::message::SlowDown }, false)])));
+        responses.push(variant(ident("__Error"), implicit("ERROR"),
+                               Some(vec![(quote! { Option<u64> }, false),
+                                         (quote! { rpccaps::rpc::message::Error }, false)])));
+        responses.push(variant(ident("__Schema"), implicit("SCHEMA"),
+                               Some(vec![(quote! { rpccaps::rpc::schema::Schema }, false)])));
+        (requests, responses)
+    }
+
+    /// Serialization of requests and responses by stable ids.
+    fn stable_serde(&self) -> TokenStream2 {
+        let (requests, responses) = self.stable_variants();
+        let impls = vec![("Request", requests), ("Response", responses)].into_iter().map(|(name, variants)| {
+            let ty = quote::format_ident!("{}", name);
+            let visitor = quote::format_ident!("__{}Visitor", name);
+            let expecting = format!("a {}", name.to_lowercase());
+            let ser = variants.iter().map(|StableVariant { ident, id, fields }| match fields {
+                Some(fields) => {
+                    let names = (0..fields.len()).map(|i| quote::format_ident!("__{}", i)).collect::<Vec<_>>();
+                    quote! { #ty::#ident(#(#names),*) => rpccaps::rpc::stable::serialize(serializer, #id, &(#(#names,)*)) }
+                },
+                None => quote! { #ty::#ident => rpccaps::rpc::stable::serialize(serializer, #id, &()) },
+            });
+            let de = variants.iter().map(|StableVariant { ident, id, fields }| match fields {
+                Some(fields) => {
+                    let names = (0..fields.len()).map(|i| quote::format_ident!("__{}", i)).collect::<Vec<_>>();
+                    let values = fields.iter().zip(names.iter()).map(|((_, limited), name)| match limited {
+                        true => quote! { #name.0 },
+                        false => quote! { #name },
+                    });
+                    let fields = fields.iter().map(|(ty, _)| ty);
+                    quote! {
+                        id if id == #id => {
+                            let (#(#names,)*): (#(#fields,)*) = rpccaps::rpc::stable::next(&mut seq, 1)?;
+                            #ty::#ident(#(#values),*)
+                        }
+                    }
+                },
+                None => quote! {
+                    id if id == #id => {
+                        rpccaps::rpc::stable::next::<_, ()>(&mut seq, 1)?;
+                        #ty::#ident
+                    }
+                },
+            });
+            quote! {
+                impl Serialize for #ty {
+                    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                        match self {
+                            #(#ser,)*
+                            #ty::__Unknown(id) => rpccaps::rpc::stable::serialize(serializer, *id, &()),
+                            #ty::_Phantom(_) => Err(serde::ser::Error::custom("phantom variant")),
+                        }
+                    }
+                }
+
+                struct #visitor;
+
+                impl<'de> serde::de::Visitor<'de> for #visitor {
+                    type Value = #ty;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str(#expecting)
+                    }
+
+                    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<#ty, A::Error> {
+                        let id: u32 = rpccaps::rpc::stable::next(&mut seq, 0)?;
+                        Ok(match id {
+                            #(#de,)*
+                            id => {
+                                rpccaps::rpc::stable::skip(&mut seq);
+                                #ty::__Unknown(id)
+                            },
+                        })
+                    }
+                }
+
+                impl<'de> Deserialize<'de> for #ty {
+                    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                        deserializer.deserialize_tuple(2, #visitor)
+                    }
+                }
+            }
+        });
+        quote! { #(#impls)* }
     }
 
     fn service(&self) -> TokenStream2 {
         let ty = &*self.ast.self_ty;
         let (impl_generics, ty_generics, where_clause) = self.ast.generics.split_for_impl();
 
-        let metas = self.meta.iter().map(|(k,v)| match v {
+        // features flags are advertised in metas, along with the custom ones
+        let mut meta = self.meta.attrs.clone();
+        let mut features = match meta.get("features") {
+            Some(Some(features)) => features.split(',').map(|f| f.trim().to_string())
+                                            .filter(|f| !f.is_empty()).collect(),
+            _ => Vec::new(),
+        };
+        if self.methods.iter().any(|m| m.is_streaming() || m.is_incoming()) {
+            features.push("streaming".to_string());
+        }
+        if self.methods.iter().any(|m| m.is_unordered()) {
+            features.push("cancellation".to_string());
+        }
+        if !features.is_empty() {
+            meta.insert("features".to_string(), Some(features.join(",")));
+        }
+
+        let metas = meta.iter().map(|(k,v)| match v {
             None => quote! { (#k, "") },
             Some(v) => quote! { (#k, #v) },
         }).collect::<Vec<_>>();
         let metas_len = metas.len();
 
-        let variants = self.methods.iter().map(|method| self.service_dispatch_variant(method));
+        let methods = self.methods.iter().map(|Method { ident, action, .. }| {
+            let (name, action) = (ident.to_string(), *action as usize);
+            quote! { (#name, #action) }
+        }).collect::<Vec<_>>();
+        let methods_len = methods.len();
+        let mut action_names = Vec::new();
+        for method in self.methods.iter() {
+            let bit = method.action as usize;
+            if action_names.len() <= bit {
+                action_names.resize(bit + 1, String::new());
+            }
+            if action_names[bit].is_empty() {
+                action_names[bit] = method.cap_name().map_or_else(|| method.ident.to_string(), str::to_string);
+            }
+        }
+        let action_names_len = action_names.len();
+        let capability_type = self.capability_type();
+        let capability = self.attrs.get_as::<_,syn::Expr>("capability").map(|expr| quote! {
+            fn capability(&self) -> #capability_type {
+                (#expr).clone()
+            }
+        });
+        // bits of custom capability types are checked against their actions
+        let max_action = self.methods.iter().map(|method| method.action as usize).max();
+        let actions_check = match (self.attrs.contains_key("capability_type"), max_action) {
+            (true, Some(max)) => {
+                let message = format!("capability bit {} is not lower than the actions of {}", max,
+                                      capability_type.to_string().replace(' ', ""));
+                Some(quote! {
+                    const _: () = assert!(#max < <#capability_type as rpccaps::data::CapabilitySet>::ACTIONS, #message);
+                })
+            },
+            _ => None,
+        };
+
+        let cancellation_safe = match self.args.contains_key("cancellation_safe") {
+            true => Some(quote! {
+                fn is_cancellation_safe() -> bool {
+                    true
+                }
+            }),
+            false => None,
+        };
+
+        let unordered = self.methods.iter().filter(|m| m.is_unordered())
+            .map(|Method { ident_cap, args, .. }| {
+                let args = args.iter().map(|_| quote! { _ });
+                quote! { Request::#ident_cap(_, #(#args),*) }
+            }).collect::<Vec<_>>();
+        let is_ordered = match unordered.len() {
+            0 => None,
+            _ => Some(quote! {
+                fn is_ordered(request: &Self::Request) -> bool {
+                    match request {
+                        #(#unordered)|* => false,
+                        _ => true,
+                    }
+                }
+            }),
+        };
+
+        // streamed items are part of the call
+        let idempotent = self.methods.iter().filter(|m| m.is_idempotent()).map(|method| {
+            let ident_cap = &method.ident_cap;
+            match method.is_incoming() {
+                true => {
+                    let (chunk, end) = method.stream_idents();
+                    quote! { Request::#ident_cap(..) | Request::#chunk(_) | Request::#end }
+                },
+                false => quote! { Request::#ident_cap(..) },
+            }
+        });
+
+        let call_ids = self.methods.iter().filter(|m| m.is_unordered())
+            .map(|Method { ident_cap, .. }| quote! { Request::#ident_cap(call_id, ..) => Some(*call_id) });
+
+        let indexes = self.methods.iter().map(|Method { ident_cap, index, .. }| {
+            let index = *index as usize;
+            quote! { Request::#ident_cap(..) => Some(#index) }
+        });
+        let methods_of = self.methods.iter().map(|method| {
+            let (ident_cap, index) = (&method.ident_cap, method.index as usize);
+            match method.is_incoming() {
+                true => {
+                    let (chunk, end) = method.stream_idents();
+                    quote! { Request::#ident_cap(..) | Request::#chunk(..) | Request::#end => Some(#index) }
+                },
+                false => quote! { Request::#ident_cap(..) => Some(#index) },
+            }
+        });
+        // errors of fallible methods are sent through the error response
+        let is_error = match self.methods.iter().any(|m| m.result.is_some() && !m.is_streaming()) {
+            false => None,
+            true => Some(quote! {
+                fn is_error(response: &Self::Response) -> bool {
+                    matches!(response, Response::__Error(_, rpccaps::rpc::message::Error::Method(_)))
+                }
+            }),
+        };
+
+        let schema = self.schema();
+        let (unknown_frame, unknown_dispatch) = match self.is_stable() {
+            true => (Some(quote! { Request::__Unknown(_) => Some(Frame::Request(Call::UNARY)), }),
+                     Some(quote! {
+                         Request::__Unknown(_) => Some(Response::__Error(None, rpccaps::rpc::message::Error::ActionNotFound)),
+                     })),
+            false => (None, None),
+        };
+        let request_frames = self.methods.iter().map(|method| self.request_frames(method));
+        let response_frames = self.methods.iter().map(|method| self.response_frames(method));
+
+        let variants = self.methods.iter().filter(|m| !m.is_streaming() && !m.is_incoming())
+                                          .map(|method| self.service_dispatch_variant(method));
+        let streaming = self.methods.iter().filter(|m| m.is_streaming() && !m.is_incoming())
+                                           .map(|method| self.service_streaming_variant(method))
+                                           .collect::<Vec<_>>();
+        let dispatch_streaming = match streaming.len() {
+            0 => None,
+            _ => Some(quote! {
+                async fn dispatch_streaming(&mut self, request: Self::Request)
+                    -> std::result::Result<futures::stream::BoxStream<'static, Self::Response>, Self::Request>
+                {
+                    match request {
+                        #(#streaming,)*
+                        request => Err(request),
+                    }
+                }
+            }),
+        };
+        let incoming = self.methods.iter().filter(|m| m.is_incoming())
+                                          .map(|method| self.service_incoming_variant(method))
+                                          .collect::<Vec<_>>();
+        let dispatch_incoming = match incoming.len() {
+            0 => None,
+            _ => Some(quote! {
+                async fn dispatch_incoming(&mut self, request: Self::Request,
+                                           requests: &mut (dyn Stream<Item=Self::Request>+Send+Unpin))
+                    -> std::result::Result<futures::stream::BoxStream<'static, Self::Response>, Self::Request>
+                {
+                    match request {
+                        #(#incoming,)*
+                        request => Err(request),
+                    }
+                }
+            }),
+        };
 
         quote! {
+            #actions_check
+
             #[async_trait]
             impl #impl_generics RPCService_ for #ty #ty_generics #where_clause {
                 type Request = Request<#ty_generics>;
                 type Response = Response<#ty_generics>;
+                type Capability = #capability_type;
 
                 fn metas() -> &'static [(&'static str, &'static str)] {
                     static metas : [(&'static str, &'static str); #metas_len] = [#(#metas),*];
                     &metas
                 }
 
+                fn methods() -> &'static [(&'static str, usize)] {
+                    static methods : [(&'static str, usize); #methods_len] = [#(#methods),*];
+                    &methods
+                }
+
+                fn action_names() -> &'static [&'static str] {
+                    static names : [&'static str; #action_names_len] = [#(#action_names),*];
+                    &names
+                }
+
+                fn schema() -> Option<rpccaps::rpc::schema::Schema> {
+                    Some(#schema)
+                }
+
+                #capability
+                #cancellation_safe
+                #is_ordered
+                #is_error
+
+                fn denied(call_id: Option<u64>, reason: String) -> Option<Self::Response> {
+                    Some(Response::__Denied(call_id, reason))
+                }
+
+                fn slow_down(retry_after: std::time::Duration) -> Option<Self::Response> {
+                    Some(Response::__SlowDown(rpccaps::rpc::message::SlowDown { retry_after }))
+                }
+
+                fn error(call_id: Option<u64>, error: rpccaps::rpc::message::Error) -> Option<Self::Response> {
+                    Some(Response::__Error(call_id, error))
+                }
+
+                fn downgrade(response: Self::Response, version: u16) -> Option<Self::Response> {
+                    match response {
+                        Response::__SlowDown(slow_down) if version < rpccaps::rpc::version::SLOW_DOWN =>
+                            Some(Response::__Denied(None, format!("slow down, retry after {:?}", slow_down.retry_after))),
+                        Response::__Error(..) if version < rpccaps::rpc::version::ERROR => None,
+                        response => Some(response),
+                    }
+                }
+
+                fn method_index(request: &Self::Request) -> Option<usize> {
+                    match request {
+                        #(#indexes,)*
+                        _ => None,
+                    }
+                }
+
+                fn method_of(request: &Self::Request) -> Option<usize> {
+                    match request {
+                        #(#methods_of,)*
+                        _ => None,
+                    }
+                }
+
+                fn call_id(request: &Self::Request) -> Option<u64> {
+                    match request {
+                        #(#call_ids,)*
+                        _ => None,
+                    }
+                }
+
+                fn schema_request() -> Option<Self::Request> {
+                    Some(Request::__Schema)
+                }
+
+                fn into_schema(response: Self::Response) -> Option<rpccaps::rpc::schema::Schema> {
+                    match response {
+                        Response::__Schema(schema) => Some(schema),
+                        _ => None,
+                    }
+                }
+
+                fn is_idempotent(request: &Self::Request) -> bool {
+                    matches!(request, #(#idempotent |)* Request::__Capabilities | Request::__Schema
+                                      | Request::__Trace(_))
+                }
+
+                fn cancelled(request: &Self::Request) -> Option<u64> {
+                    match request {
+                        Request::__Cancel(call_id) => Some(*call_id),
+                        _ => None,
+                    }
+                }
+
+                fn trace_context(request: &Self::Request) -> Option<rpccaps::rpc::trace::TraceContext> {
+                    match request {
+                        Request::__Trace(context) => Some(*context),
+                        _ => None,
+                    }
+                }
+
+                fn request_frame(request: &Self::Request) -> Option<rpccaps::rpc::protocol::Frame> {
+                    use rpccaps::rpc::protocol::{Call, Frame, Reply};
+                    match request {
+                        #(#request_frames,)*
+                        Request::__Capabilities => Some(Frame::Request(Call::UNARY)),
+                        Request::__Cancel(_) | Request::__Trace(_) => Some(Frame::Request(Call::NOTIFY)),
+                        Request::__Schema => Some(Frame::Request(Call::UNARY)),
+                        #unknown_frame
+                        _ => None,
+                    }
+                }
+
+                fn response_frame(response: &Self::Response) -> Option<rpccaps::rpc::protocol::Frame> {
+                    use rpccaps::rpc::protocol::Frame;
+                    match response {
+                        #(#response_frames,)*
+                        Response::__Capabilities(_) | Response::__Schema(_) => Some(Frame::Response),
+                        Response::__Denied(..) | Response::__SlowDown(_) | Response::__Error(..) => Some(Frame::Denied),
+                        _ => None,
+                    }
+                }
+
+                fn is_refused(response: &Self::Response) -> bool {
+                    matches!(response, Response::__Denied(..) | Response::__SlowDown(_))
+                }
+
                 fn is_alive(&self) -> bool {
                     true
                 }
@@ -138,67 +793,415 @@ impl<'a> Service<'a> {
                 async fn dispatch(&mut self, request: Self::Request) -> Option<Self::Response> {
                     match request {
                         #(#variants,)*
+                        Request::__Capabilities => Some(Response::__Capabilities(self.capabilities())),
+                        Request::__Schema => Some(Response::__Schema(#schema)),
+                        #unknown_dispatch
                         _ => None,
                     }
                 }
+
+                #dispatch_streaming
+                #dispatch_incoming
             }
         }
     }
 
-    fn service_dispatch_variant(&self, method: &Method) -> TokenStream2 {
+    /// Expression of the service's schema.
+    fn schema(&self) -> TokenStream2 {
+        let signatures = self.methods.iter().map(Method::signature);
+        match self.is_stable() {
+            true => {
+                let ids = self.methods.iter().map(|method| method.id.unwrap());
+                quote! { rpccaps::rpc::schema::Schema::with_ids(&[#((#ids, #signatures)),*]) }
+            },
+            false => quote! { rpccaps::rpc::schema::Schema::new(&[#(#signatures),*]) },
+        }
+    }
+
+    /// Match arms of `request_frame()` for method's requests.
+    fn request_frames(&self, method: &Method) -> TokenStream2 {
+        let ident_cap = &method.ident_cap;
+        let reply = match (&method.output, method.is_streaming()) {
+            (_, true) => quote! { Reply::Stream },
+            (Some(_), false) => quote! { Reply::Single },
+            (None, false) => quote! { Reply::None },
+        };
+        match method.is_incoming() {
+            true => {
+                let (chunk, end) = method.stream_idents();
+                quote! {
+                    Request::#ident_cap(..) => Some(Frame::Request(Call::incoming(#reply))),
+                    Request::#chunk(_) => Some(Frame::RequestChunk),
+                    Request::#end => Some(Frame::RequestEnd)
+                }
+            },
+            false => quote! {
+                Request::#ident_cap(..) => Some(Frame::Request(Call { incoming: false, reply: #reply }))
+            },
+        }
+    }
+
+    /// Match arms of `response_frame()` for method's responses.
+    fn response_frames(&self, method: &Method) -> TokenStream2 {
+        let ident_cap = &method.ident_cap;
+        match (&method.output, method.is_streaming()) {
+            (_, true) => {
+                let (chunk, end) = method.stream_idents();
+                quote! {
+                    Response::#chunk(_) => Some(Frame::ResponseChunk),
+                    Response::#end => Some(Frame::ResponseEnd)
+                }
+            },
+            (Some(_), false) => quote! { Response::#ident_cap(..) => Some(Frame::Response) },
+            (None, false) => quote! { Response::#ident_cap => Some(Frame::Response) },
+        }
+    }
+
+    fn service_incoming_variant(&self, method: &Method) -> TokenStream2 {
         let Method { ident_cap, ident, args, is_async, output, .. } = method;
+        let (chunk, end) = method.stream_idents();
+        let call_args = method.call_args(&quote! { __incoming });
+        let invoke = match is_async {
+            false => quote! { self.#ident(#(#call_args),*) },
+            true => quote! { self.#ident(#(#call_args),*).await },
+        };
+        let respond = match (output, &method.stream_item, &method.result) {
+            (_, Some(_), _) => quote! { rpccaps::rpc::stream::respond(#invoke, Response::#chunk, Response::#end) },
+            (None, _, _) => quote! { { #invoke; futures::stream::empty().boxed() } },
+            (Some(_), None, Some(_)) => {
+                let respond = self.respond(method, &quote! { #invoke }, None);
+                quote! { futures::stream::once(futures::future::ready(#respond)).boxed() }
+            },
+            (Some(_), None, None) => quote! {
+                futures::stream::once(futures::future::ready(Response::#ident_cap(#invoke))).boxed()
+            },
+        };
+        quote! {
+            Request::#ident_cap(#(#args),*) => {
+                let __incoming = rpccaps::rpc::stream::receive(requests, |request| match request {
+                    Request::#chunk(item) => Some(item),
+                    _ => None,
+                });
+                Ok(#respond)
+            }
+        }
+    }
+
+    fn service_streaming_variant(&self, method: &Method) -> TokenStream2 {
+        let Method { ident_cap, ident, args, is_async, .. } = method;
+        let (chunk, end) = method.stream_idents();
         let invoke = match is_async {
             false => quote! { self.#ident(#(#args),*) },
             true => quote! { self.#ident(#(#args),*).await },
         };
-        let invoke = match output {
-            None => quote! { { #invoke; None } },
-            Some(_) => quote! { Some(Response::#ident_cap(#invoke)) }
+        quote! {
+            Request::#ident_cap(#(#args),*) =>
+                Ok(rpccaps::rpc::stream::respond(#invoke, Response::#chunk, Response::#end))
+        }
+    }
+
+    fn service_dispatch_variant(&self, method: &Method) -> TokenStream2 {
+        let Method { ident_cap, ident, args, is_async, output, .. } = method;
+        // method's body runs in its span
+        let name = ident.to_string();
+        let invoke = match is_async {
+            false => quote! {{
+                let __span = rpccaps::rpc::trace::method(#name);
+                let _enter = __span.enter();
+                self.#ident(#(#args),*)
+            }},
+            true => quote! {
+                rpccaps::rpc::trace::Instrument::instrument(self.#ident(#(#args),*),
+                                                             rpccaps::rpc::trace::method(#name)).await
+            },
         };
-        quote! { Request::#ident_cap(#(#args),*) => #invoke }
+        match (output, method.is_unordered(), &method.result) {
+            (None, _, _) => quote! { Request::#ident_cap(#(#args),*) => { #invoke; None } },
+            (Some(_), false, Some(_)) => {
+                let respond = self.respond(method, &invoke, None);
+                quote! { Request::#ident_cap(#(#args),*) => Some(#respond) }
+            },
+            (Some(_), true, Some(_)) => {
+                let respond = self.respond(method, &invoke, Some(quote! { __call_id }));
+                quote! { Request::#ident_cap(__call_id, #(#args),*) => Some(#respond) }
+            },
+            (Some(_), false, None) => quote! {
+                Request::#ident_cap(#(#args),*) => Some(Response::#ident_cap(#invoke))
+            },
+            (Some(_), true, None) => quote! {
+                Request::#ident_cap(__call_id, #(#args),*) =>
+                    Some(Response::#ident_cap(__call_id, #invoke))
+            },
+        }
+    }
+
+    /// Response of fallible method to the result of `invoke`: its error is
+    /// sent through the error response.
+    fn respond(&self, method: &Method, invoke: &TokenStream2, call_id: Option<TokenStream2>) -> TokenStream2 {
+        let ident_cap = &method.ident_cap;
+        let error = match method.remote_error {
+            true => quote! { rpccaps::rpc::message::RemoteError::from(error) },
+            false => quote! { error },
+        };
+        let (ok, err_id) = match call_id {
+            Some(call_id) => (quote! { Response::#ident_cap(#call_id, out) }, quote! { Some(#call_id) }),
+            None => (quote! { Response::#ident_cap(out) }, quote! { None }),
+        };
+        quote! {
+            match #invoke {
+                Ok(out) => #ok,
+                Err(error) => Response::__Error(#err_id, rpccaps::rpc::message::Error::method(&#error)),
+            }
+        }
     }
 
     fn client(&self) -> TokenStream2 {
-        let ty = &*self.ast.self_ty;
         let mut generics = self.ast.generics.clone();
         generics.params.push(syn::parse_str::<syn::GenericParam>(r"SinkError: Unpin+Send").unwrap());
-        generics.params.push(syn::parse_str::<syn::GenericParam>(&format!(
+        generics.params.push(syn::parse_str::<syn::GenericParam>(
             r"Transport: Stream<Item=Response>+Sink<Request,Error=SinkError>+Unpin+Send"
-        )).unwrap());
+        ).unwrap());
 
         let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
         let methods = self.methods.iter().map(|m| self.client_method(m));
+        let schema = self.schema();
+        let capability_type = self.capability_type();
 
         quote! {
             pub struct Client #impl_generics #where_clause {
                 transport: Transport,
+                call_id: u64,
+                options: rpccaps::rpc::call::ClientOptions,
+                backoff: rpccaps::rpc::call::Backoff,
+                /// Unset while a streaming call is in progress, and left so when it is
+                /// abandoned before its end.
+                synced: bool,
             }
 
             impl #impl_generics Client #ty_generics #where_clause {
                 pub fn new(transport: Transport) -> Self {
-                    Self { transport }
+                    Self::with_options(transport, Default::default())
+                }
+
+                pub fn with_options(transport: Transport, options: rpccaps::rpc::call::ClientOptions) -> Self {
+                    Self { transport, call_id: 0, options, backoff: Default::default(), synced: true }
+                }
+
+                /// Client's options.
+                pub fn options(&self) -> &rpccaps::rpc::call::ClientOptions {
+                    &self.options
+                }
+
+                /// Backoff of methods slowed down by the server.
+                pub fn backoff(&self) -> &rpccaps::rpc::call::Backoff {
+                    &self.backoff
+                }
+
+                /// Return an error if a previous call has been abandoned before its end.
+                fn check_synced(&self) -> Result<(), rpccaps::rpc::call::CallError> {
+                    match self.synced {
+                        true => Ok(()),
+                        false => Err(rpccaps::rpc::call::CallError::Desynced),
+                    }
+                }
+
+                /// Wait for the next response, until options' request timeout. On
+                /// timeout, the response may still come later: the client is out of
+                /// sync and further calls fail.
+                async fn next_response(&mut self) -> Result<Response, rpccaps::rpc::call::CallError> {
+                    self.synced = false;
+                    let response = rpccaps::rpc::call::with_timeout(self.options.request_timeout,
+                                                                    self.transport.next()).await?;
+                    self.synced = true;
+                    response.ok_or(rpccaps::rpc::call::CallError::Failed)
+                }
+
+                /// Send trace context of the call about to be made, when enabled by options.
+                async fn send_trace(&mut self) -> Result<(), rpccaps::rpc::call::CallError> {
+                    if self.options.trace {
+                        let context = rpccaps::rpc::trace::TraceContext::current();
+                        self.transport.send(Request::__Trace(context)).await
+                            .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    }
+                    Ok(())
+                }
+
+                /// Return a new call id for unordered methods.
+                fn next_call_id(&mut self) -> u64 {
+                    self.call_id = self.call_id.wrapping_add(1);
+                    self.call_id
                 }
 
                 #(#methods)*
+
+                /// Return service's methods and caller's effective capability.
+                pub async fn __capabilities(&mut self)
+                    -> Result<rpccaps::rpc::service::Capabilities<#capability_type>, rpccaps::rpc::call::CallError>
+                {
+                    self.check_synced()?;
+                    self.transport.send(Request::__Capabilities).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    match self.next_response().await? {
+                        Response::__Capabilities(out) => Ok(out),
+                        _ => Err(rpccaps::rpc::call::CallError::Failed),
+                    }
+                }
+
+                /// Return server's schema of the service.
+                pub async fn __schema(&mut self)
+                    -> Result<rpccaps::rpc::schema::Schema, rpccaps::rpc::call::CallError>
+                {
+                    self.check_synced()?;
+                    self.transport.send(Request::__Schema).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    match self.next_response().await? {
+                        Response::__Schema(out) => Ok(out),
+                        _ => Err(rpccaps::rpc::call::CallError::Failed),
+                    }
+                }
+
+                /// Validate server's schema of the service against the client's one.
+                pub async fn validate_schema(&mut self) -> Result<(), rpccaps::rpc::schema::SchemaError> {
+                    let server = self.__schema().await?;
+                    Ok(server.validate(&#schema)?)
+                }
+            }
+
+            impl #impl_generics From<Transport> for Client #ty_generics #where_clause {
+                fn from(transport: Transport) -> Self {
+                    Self::new(transport)
+                }
             }
         }
     }
 
     fn client_method(&self, method: &Method) -> TokenStream2 {
         let Method { ident, ident_cap, args, args_ty, output, .. } = method;
+        if method.is_incoming() {
+            return self.client_incoming_method(method)
+        }
+        let name = ident.to_string();
+        if let Some(ref item) = method.stream_item {
+            let chunk = method.stream_idents().0;
+            return quote! {
+                pub async fn #ident(&mut self, #(#args: #args_ty),*)
+                    -> Result<rpccaps::rpc::stream::ClientStream<'_, #item>, rpccaps::rpc::call::CallError>
+                {
+                    self.check_synced()?;
+                    self.backoff.wait(#name).await;
+                    self.send_trace().await?;
+                    self.transport.send(Request::#ident_cap(#(#args),*)).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    Ok(rpccaps::rpc::stream::receive_call(&mut self.transport, &mut self.synced, |resp| match resp {
+                        Response::#chunk(item) => Some(item),
+                        _ => None,
+                    }))
+                }
+            }
+        }
+        let (out_value, method_error) = self.method_error(method);
+        if let (Some(out), true) = (output, method.is_unordered()) {
+            // responses of other calls, e.g. cancelled ones
+            let others = self.methods.iter().filter(|m| m.is_unordered()).map(|m| {
+                let ident_cap = &m.ident_cap;
+                quote! { Response::#ident_cap(id, _) }
+            });
+            let stale = quote! {
+                #(#others)|* | Response::__Error(Some(id), _) | Response::__Denied(Some(id), _)
+            };
+            let (failed, pending_failed) = match method_error {
+                Some(method_error) => (
+                    Some(quote! {
+                        Response::__Error(Some(id), error @ rpccaps::rpc::message::Error::Method(_))
+                            if id == call_id => return #method_error,
+                    }),
+                    Some(quote! {
+                        Response::__Error(Some(id), error @ rpccaps::rpc::message::Error::Method(_))
+                            if id == call_id => Some(#method_error),
+                    }),
+                ),
+                None => (None, None),
+            };
+            let start = quote::format_ident!("start_{}", ident);
+            return quote! {
+                pub async fn #ident(&mut self, #(#args: #args_ty),*)
+                    -> Result<#out, rpccaps::rpc::call::CallError>
+                {
+                    let call_id = self.next_call_id();
+                    self.check_synced()?;
+                    self.backoff.wait(#name).await;
+                    self.send_trace().await?;
+                    self.transport.send(Request::#ident_cap(call_id, #(#args),*)).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    loop {
+                        match self.next_response().await? {
+                            Response::#ident_cap(id, out) if id == call_id => return Ok(#out_value),
+                            #failed
+                            #stale if id != call_id => continue,
+                            Response::__SlowDown(slow_down) => return Err(self.backoff.slow_down(#name, slow_down)),
+                            Response::__Error(_, error) => return Err(rpccaps::rpc::call::CallError::Server(error)),
+                            _ => return Err(rpccaps::rpc::call::CallError::Failed),
+                        }
+                    }
+                }
+
+                /// Start the call, returning its pending response. The call is cancelled
+                /// when it is dropped before.
+                pub async fn #start(&mut self, #(#args: #args_ty),*)
+                    -> Result<rpccaps::rpc::call::Pending<'_, Transport, Request, Response, #out>,
+                              rpccaps::rpc::call::CallError>
+                {
+                    let call_id = self.next_call_id();
+                    self.check_synced()?;
+                    self.backoff.wait(#name).await;
+                    self.send_trace().await?;
+                    self.transport.send(Request::#ident_cap(call_id, #(#args),*)).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                    Ok(rpccaps::rpc::call::Pending::new(
+                        &mut self.transport, call_id, self.options.request_timeout, Request::__Cancel,
+                        |call_id, response| match response {
+                            Response::#ident_cap(id, out) if id == call_id => Some(Ok(#out_value)),
+                            #pending_failed
+                            #stale if id != call_id => None,
+                            Response::__SlowDown(rpccaps::rpc::message::SlowDown { retry_after }) =>
+                                Some(Err(rpccaps::rpc::call::CallError::SlowDown { retry_after })),
+                            Response::__Error(_, error) => Some(Err(rpccaps::rpc::call::CallError::Server(error))),
+                            _ => Some(Err(rpccaps::rpc::call::CallError::Failed)),
+                        }))
+                }
+            }
+        }
+
         match output {
             None => quote! {
-                pub async fn #ident(&mut self, #(#args: #args_ty),*) {
-                    self.transport.send(Request::#ident_cap(#(#args),*)).await;
+                pub async fn #ident(&mut self, #(#args: #args_ty),*)
+                    -> Result<(), rpccaps::rpc::call::CallError>
+                {
+                    self.backoff.wait(#name).await;
+                    self.send_trace().await?;
+                    self.transport.send(Request::#ident_cap(#(#args),*)).await
+                        .or(Err(rpccaps::rpc::call::CallError::Failed))
                 }
             },
             Some(out) => {
+                let failed = method_error.map(|method_error| quote! {
+                    Response::__Error(_, error @ rpccaps::rpc::message::Error::Method(_)) => #method_error,
+                });
                 quote! {
-                    pub async fn #ident(&mut self, #(#args: #args_ty),*) -> Result<#out,()> {
-                        self.transport.send(Request::#ident_cap(#(#args),*)).await;
-                        match self.transport.next().await {
-                            Some(Response::#ident_cap(out)) => Ok(out),
-                            _ => Err(()),
+                    pub async fn #ident(&mut self, #(#args: #args_ty),*)
+                        -> Result<#out, rpccaps::rpc::call::CallError>
+                    {
+                        self.check_synced()?;
+                        self.backoff.wait(#name).await;
+                        self.send_trace().await?;
+                        self.transport.send(Request::#ident_cap(#(#args),*)).await
+                            .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                        match self.next_response().await? {
+                            Response::#ident_cap(out) => Ok(#out_value),
+                            #failed
+                            Response::__SlowDown(slow_down) => Err(self.backoff.slow_down(#name, slow_down)),
+                            Response::__Error(_, error) => Err(rpccaps::rpc::call::CallError::Server(error)),
+                            _ => Err(rpccaps::rpc::call::CallError::Failed),
                         }
                     }
                 }
@@ -206,6 +1209,104 @@ impl<'a> Service<'a> {
         }
     }
 
+    fn client_incoming_method(&self, method: &Method) -> TokenStream2 {
+        let Method { ident, ident_cap, args, args_ty, output, .. } = method;
+        let item = &method.incoming.as_ref().unwrap().2;
+        let (chunk, end) = method.stream_idents();
+        let name = ident.to_string();
+        let (out_value, method_error) = self.method_error(method);
+        let failed = method_error.map(|method_error| quote! {
+            Some(Response::__Error(_, error @ rpccaps::rpc::message::Error::Method(_))) => #method_error,
+        });
+        let (out, finish) = match (output, &method.stream_item) {
+            (_, Some(stream_item)) => (
+                quote! { rpccaps::rpc::stream::ClientStream<'_, #stream_item> },
+                quote! { |transport, synced, _| futures::future::ready(Ok(
+                    rpccaps::rpc::stream::receive_call(transport, synced, |resp| match resp {
+                        Response::#chunk(item) => Some(item),
+                        _ => None,
+                    })
+                )).boxed() },
+            ),
+            (Some(out), None) => (
+                quote! { #out },
+                quote! { |transport, synced, timeout| async move {
+                    let response = rpccaps::rpc::call::with_timeout(timeout, transport.next()).await?;
+                    *synced = true;
+                    match response {
+                        Some(Response::#ident_cap(out)) => Ok(#out_value),
+                        #failed
+                        Some(Response::__SlowDown(rpccaps::rpc::message::SlowDown { retry_after })) =>
+                            Err(rpccaps::rpc::call::CallError::SlowDown { retry_after }),
+                        Some(Response::__Error(_, error)) => Err(rpccaps::rpc::call::CallError::Server(error)),
+                        _ => Err(rpccaps::rpc::call::CallError::Failed),
+                    }
+                }.boxed() },
+            ),
+            (None, None) => (quote! { () }, quote! { |_, synced, _| {
+                *synced = true;
+                futures::future::ready(Ok(())).boxed()
+            } }),
+        };
+        quote! {
+            pub async fn #ident(&mut self, #(#args: #args_ty),*)
+                -> Result<rpccaps::rpc::stream::ClientSink<'_, Transport, Request, #item, #out>,
+                          rpccaps::rpc::call::CallError>
+            {
+                self.check_synced()?;
+                self.backoff.wait(#name).await;
+                self.send_trace().await?;
+                self.transport.send(Request::#ident_cap(#(#args),*)).await
+                    .or(Err(rpccaps::rpc::call::CallError::Failed))?;
+                Ok(rpccaps::rpc::stream::ClientSink::new(&mut self.transport, &mut self.synced,
+                                                         Request::#chunk, Request::#end, #finish)
+                       .with_timeout(self.options.request_timeout))
+            }
+        }
+    }
+
+    /// Return client's output value of method's response `out`, and for
+    /// fallible methods, the expression converting back `error`, its error
+    /// response, into the method's output.
+    fn method_error(&self, method: &Method) -> (TokenStream2, Option<TokenStream2>) {
+        let wire_error = match method.wire_error() {
+            Some(wire_error) => wire_error,
+            None => return (quote! { out }, None),
+        };
+        let error = match (method.remote_error, self.error()) {
+            (true, Some(error)) => quote! { <#error>::from(error) },
+            _ => quote! { error },
+        };
+        (quote! { Ok(out) }, Some(quote! {
+            error.method_error::<#wire_error>().map(|error| Ok(Err(#error)))
+                 .unwrap_or(Err(rpccaps::rpc::call::CallError::Failed))
+        }))
+    }
+
+    /// Service's error type, as declared by `#[rpc(error="...")]`.
+    fn error(&self) -> Option<syn::Type> {
+        self.attrs.get_as::<_,syn::Type>("error")
+    }
+
+    /// Type of callers' capability, `Capability` unless set by
+    /// `#[rpc(capability_type="...")]`.
+    fn capability_type(&self) -> TokenStream2 {
+        match self.attrs.get_as::<_,syn::Type>("capability_type") {
+            Some(ty) => quote! { #ty },
+            None => quote! { rpccaps::data::Capability },
+        }
+    }
+
 }
 
 
+
+
+/// Variant of a message with its stable id, and its fields' types when it
+/// is not a unit variant. Fields flagged true are wrapped into a
+/// `rpc::bounded::Limited`.
+struct StableVariant {
+    ident: syn::Ident,
+    id: TokenStream2,
+    fields: Option<Vec<(TokenStream2, bool)>>,
+}
@@ -2,7 +2,6 @@ use std::collections::BTreeMap;
 use std::ops::{Deref,DerefMut};
 
 use quote::ToTokens;
-use syn;
 
 
 /// Return camel-cased version of provided ident.
@@ -28,6 +27,32 @@ pub fn to_camel_ident(ident: &syn::Ident) -> syn::Ident {
 }
 
 
+/// Size of type's values once encoded with bincode, when it is fixed.
+pub fn fixed_size(ty: &syn::Type) -> Option<usize> {
+    match ty {
+        syn::Type::Path(path) if path.qself.is_none() => {
+            let ident = path.path.get_ident()?.to_string();
+            match ident.as_str() {
+                "u8" | "i8" | "bool" => Some(1),
+                "u16" | "i16" => Some(2),
+                "u32" | "i32" | "f32" => Some(4),
+                "u64" | "i64" | "f64" | "usize" | "isize" => Some(8),
+                "u128" | "i128" => Some(16),
+                _ => None,
+            }
+        },
+        syn::Type::Tuple(tuple) => tuple.elems.iter().map(fixed_size).sum(),
+        syn::Type::Array(array) => match array.len {
+            syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(ref len), .. }) =>
+                Some(len.base10_parse::<usize>().ok()? * fixed_size(&array.elem)?),
+            _ => None,
+        },
+        syn::Type::Paren(paren) => fixed_size(&paren.elem),
+        _ => None,
+    }
+}
+
+
 /// Run over attributes with the provided function, removing attribute when `func` returns `true`.
 /// Return the count of removed attributes.
 pub fn drain_attrs(attrs: &mut Vec<syn::Attribute>, mut func: impl FnMut(&syn::Attribute) -> bool) -> usize
@@ -63,24 +88,28 @@ impl Attributes {
         Self { attrs: AttributesMap::new() }
     }
 
-    /// Set `default` value for attribute key when not declared or None.
-    pub fn set_default<K: Into<String>, D: Into<String>>(&mut self, key: K, default: D) -> String {
-        let key = key.into();
-        if let Some(Some(v)) = self.attrs.get(&key) {
-            return v.clone();
-        }
-        self.attrs.insert(key.clone(), Some(default.into()));
-        self.attrs.get(&key).unwrap().as_ref().unwrap().clone()
-    }
-
     /// Parse attribute into syn entity.
     pub fn get_as<K: Into<String>,T: syn::parse::Parse>(&self, key: K) -> Option<T> {
         match self.attrs.get(&key.into()) {
-            Some(Some(v)) => syn::parse_str(&v).ok(),
+            Some(Some(v)) => syn::parse_str(v).ok(),
             _ => None
         }
     }
 
+    /// Parse attribute into an integer, returning an error spanned at
+    /// `tokens` when it is not one.
+    pub fn get_int<T>(&self, key: &str, tokens: &impl ToTokens) -> syn::Result<Option<T>>
+        where T: std::str::FromStr, T::Err: std::fmt::Display
+    {
+        match self.attrs.get(key) {
+            None => Ok(None),
+            Some(value) => value.as_ref().and_then(|v| syn::parse_str::<syn::LitInt>(v).ok())
+                .and_then(|lit| lit.base10_parse().ok())
+                .map(Some)
+                .ok_or_else(|| syn::Error::new_spanned(tokens, format!("`{}` must be an integer", key))),
+        }
+    }
+
     /// Create new Attributes reading from provided `syn::Attribute`s
     pub fn from_attrs(prefix: &str, attrs: &mut Vec<syn::Attribute>) -> Self {
         let mut this = Self::new();
@@ -88,6 +117,15 @@ impl Attributes {
         this
     }
 
+    /// Create new Attributes from the arguments of an attribute macro.
+    pub fn from_args(args: &syn::AttributeArgs) -> Self {
+        let mut this = Self::new();
+        for nested in args.iter() {
+            this.insert_nested(nested)
+        }
+        this
+    }
+
     /// Read attributes draining them when attribute has provided prefix.
     pub fn read_attrs(&mut self, prefix: &str, attrs: &mut Vec<syn::Attribute>) {
         drain_attrs(attrs, |attr| {